@@ -576,6 +576,7 @@ impl BankingStage {
                 Builder::new()
                     .name(format!("solCoWorker{id:02}"))
                     .spawn(|| {
+                        pin_banking_thread();
                         let _ = consume_worker.run();
                     })
                     .unwrap(),
@@ -593,6 +594,7 @@ impl BankingStage {
                     Builder::new()
                         .name("solBnkTxSched".to_string())
                         .spawn(move || {
+                            pin_banking_thread();
                             let scheduler_controller = SchedulerController::new(
                                 exit,
                                 scheduler_config,
@@ -653,6 +655,7 @@ impl BankingStage {
         Builder::new()
             .name("solBanknStgVote".to_string())
             .spawn(move || {
+                pin_banking_thread();
                 VoteWorker::new(
                     worker_exit_signal,
                     decision_maker,
@@ -760,6 +763,7 @@ mod external {
                     Builder::new()
                         .name(format!("solECoWorker{id:02}"))
                         .spawn(move || {
+                            pin_banking_thread();
                             let _ = consume_worker.run();
                         })
                         .unwrap(),
@@ -828,6 +832,14 @@ pub(crate) fn update_bank_forks_and_poh_recorder_for_new_tpu_bank(
     }
 }
 
+/// Pins the calling banking thread to the banking cpus of the installed cpu profile, if any.
+fn pin_banking_thread() {
+    if let Err(err) = agave_cpu_utils::pin_thread_to_profile(agave_cpu_utils::CpuProfile::BANKING)
+    {
+        warn!("Failed to pin banking thread to the cpu profile: {err}");
+    }
+}
+
 #[derive(Debug)]
 struct NamedTask<Ret = (), Name = String>
 where
@@ -4,7 +4,7 @@ use {
     crate::repair::{repair_service::OutstandingShredRepairs, serve_repair::ServeRepair},
     agave_feature_set::FeatureSet,
     bytes::Bytes,
-    crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender},
+    crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender, TrySendError},
     itertools::Itertools,
     solana_clock::{Slot, DEFAULT_MS_PER_SLOT},
     solana_epoch_schedule::EpochSchedule,
@@ -200,6 +200,7 @@ impl ShredFetchStage {
         receiver_thread_name: &'static str,
         modifier_thread_name: &'static str,
         sockets: Vec<Arc<UdpSocket>>,
+        xdp_receiver: Option<Receiver<PacketBatch>>,
         exit: Arc<AtomicBool>,
         sender: EvictingSender<PacketBatch>,
         recycler: PacketBatchRecycler,
@@ -215,7 +216,7 @@ impl ShredFetchStage {
         let (packet_sender, packet_receiver) =
             EvictingSender::new_bounded(SHRED_FETCH_CHANNEL_SIZE);
         let receiver_stats = Arc::new(StreamerReceiveStats::new(receiver_name));
-        let streamers = sockets
+        let mut streamers: Vec<_> = sockets
             .into_iter()
             .enumerate()
             .map(|(i, socket)| {
@@ -233,6 +234,39 @@ impl ShredFetchStage {
                 )
             })
             .collect();
+        if let Some(xdp_receiver) = xdp_receiver {
+            let packet_sender = packet_sender.clone();
+            let receiver_stats = receiver_stats.clone();
+            streamers.push(
+                Builder::new()
+                    .name(format!("{receiver_thread_name}Xdp"))
+                    .spawn(move || {
+                        // the rx loops stop sending once exit is set
+                        for packet_batch in xdp_receiver {
+                            let len = packet_batch.len();
+                            receiver_stats
+                                .packets_count
+                                .fetch_add(len, Ordering::Relaxed);
+                            receiver_stats
+                                .packet_batches_count
+                                .fetch_add(1, Ordering::Relaxed);
+                            receiver_stats
+                                .max_channel_len
+                                .fetch_max(packet_sender.len(), Ordering::Relaxed);
+                            match packet_sender.try_send(packet_batch) {
+                                Ok(()) => {}
+                                Err(TrySendError::Full(_)) => {
+                                    receiver_stats
+                                        .num_packets_dropped
+                                        .fetch_add(len, Ordering::Relaxed);
+                                }
+                                Err(TrySendError::Disconnected(_)) => break,
+                            }
+                        }
+                    })
+                    .unwrap(),
+            );
+        }
         let modifier_hdl = Builder::new()
             .name(modifier_thread_name.to_string())
             .spawn(move || {
@@ -255,6 +289,7 @@ impl ShredFetchStage {
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         sockets: Vec<Arc<UdpSocket>>,
+        xdp_receiver: Option<Receiver<PacketBatch>>,
         turbine_quic_endpoint_receiver: Receiver<(Pubkey, SocketAddr, Bytes)>,
         repair_response_quic_receiver: Receiver<(Pubkey, SocketAddr, Bytes)>,
         repair_socket: Arc<UdpSocket>,
@@ -277,6 +312,7 @@ impl ShredFetchStage {
             "solRcvrShred",
             "solTvuPktMod",
             sockets,
+            xdp_receiver,
             exit.clone(),
             sender.clone(),
             recycler.clone(),
@@ -293,6 +329,7 @@ impl ShredFetchStage {
             "solRcvrShredRep",
             "solTvuRepPktMod",
             vec![repair_socket],
+            None, // xdp_receiver
             exit.clone(),
             sender.clone(),
             recycler.clone(),
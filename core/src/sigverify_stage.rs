@@ -396,6 +396,11 @@ impl SigVerifyStage {
         Builder::new()
             .name(thread_name.to_string())
             .spawn(move || {
+                if let Err(err) = agave_cpu_utils::pin_thread_to_profile(
+                    agave_cpu_utils::CpuProfile::SIGVERIFY,
+                ) {
+                    warn!("Failed to pin {thread_name} to the cpu profile: {err}");
+                }
                 let mut rng = rand::thread_rng();
                 let mut deduper = Deduper::<2, [u8]>::new(&mut rng, DEDUPER_NUM_BITS);
                 loop {
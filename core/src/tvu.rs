@@ -35,6 +35,7 @@ use {
         blockstore_processor::TransactionStatusSender, entry_notifier_service::EntryNotifierSender,
         leader_schedule_cache::LeaderScheduleCache,
     },
+    solana_perf::packet::PacketBatch,
     solana_poh::{poh_controller::PohController, poh_recorder::PohRecorder},
     solana_pubkey::Pubkey,
     solana_rpc::{
@@ -101,6 +102,9 @@ pub struct TvuConfig {
     pub replay_transactions_threads: NonZeroUsize,
     pub shred_sigverify_threads: NonZeroUsize,
     pub xdp_sender: Option<XdpSender>,
+    /// Shreds received on the TVU port through XDP, see
+    /// [`XdpShredReceiver`](solana_turbine::xdp::XdpShredReceiver).
+    pub xdp_fetch_receiver: Option<Receiver<PacketBatch>>,
 }
 
 impl Default for TvuConfig {
@@ -115,6 +119,7 @@ impl Default for TvuConfig {
             replay_transactions_threads: NonZeroUsize::new(1).expect("1 is non-zero"),
             shred_sigverify_threads: NonZeroUsize::new(1).expect("1 is non-zero"),
             xdp_sender: None,
+            xdp_fetch_receiver: None,
         }
     }
 }
@@ -192,6 +197,7 @@ impl Tvu {
         let fetch_sockets: Vec<Arc<UdpSocket>> = fetch_sockets.into_iter().map(Arc::new).collect();
         let fetch_stage = ShredFetchStage::new(
             fetch_sockets,
+            tvu_config.xdp_fetch_receiver,
             turbine_quic_endpoint_receiver,
             repair_response_quic_receiver,
             repair_socket.clone(),
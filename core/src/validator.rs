@@ -147,7 +147,7 @@ use {
     solana_turbine::{
         self,
         broadcast_stage::BroadcastStageType,
-        xdp::{XdpConfig, XdpRetransmitter},
+        xdp::{XdpConfig, XdpRetransmitter, XdpShredReceiver},
     },
     solana_unified_scheduler_pool::DefaultSchedulerPool,
     solana_validator_exit::Exit,
@@ -382,6 +382,8 @@ pub struct ValidatorConfig {
     pub delay_leader_block_for_pending_fork: bool,
     pub use_tpu_client_next: bool,
     pub retransmit_xdp: Option<XdpConfig>,
+    /// Receive the shreds sent to the TVU port through XDP.
+    pub tvu_xdp: Option<XdpConfig>,
     pub repair_handler_type: RepairHandlerType,
 }
 
@@ -465,6 +467,7 @@ impl ValidatorConfig {
             delay_leader_block_for_pending_fork: false,
             use_tpu_client_next: true,
             retransmit_xdp: None,
+            tvu_xdp: None,
             repair_handler_type: RepairHandlerType::default(),
         }
     }
@@ -647,6 +650,7 @@ pub struct Validator {
     repair_quic_endpoints_runtime: Option<TokioRuntime>,
     repair_quic_endpoints_join_handle: Option<repair::quic_endpoint::AsyncTryJoinHandle>,
     xdp_retransmitter: Option<XdpRetransmitter>,
+    xdp_shred_receiver: Option<XdpShredReceiver>,
     // This runtime is used to run the client owned by SendTransactionService.
     // We don't wait for its JoinHandle here because ownership and shutdown
     // are managed elsewhere. This variable is intentionally unused.
@@ -1584,6 +1588,19 @@ impl Validator {
             } else {
                 (None, None)
             };
+        let (xdp_shred_receiver, xdp_fetch_receiver) =
+            if let Some(xdp_config) = config.tvu_xdp.clone() {
+                let tvu_port = node.sockets.tvu[0]
+                    .local_addr()
+                    .expect("failed to get local address")
+                    .port();
+                let (receiver, fetch_receiver) =
+                    XdpShredReceiver::new(xdp_config, tvu_port, exit.clone())
+                        .expect("failed to create xdp shred receiver");
+                (Some(receiver), Some(fetch_receiver))
+            } else {
+                (None, None)
+            };
 
         // disable all2all tests if not allowed for a given cluster type
         let alpenglow_socket = if genesis_config.cluster_type == ClusterType::Testnet
@@ -1637,6 +1654,7 @@ impl Validator {
                 replay_transactions_threads: config.replay_transactions_threads,
                 shred_sigverify_threads: config.tvu_shred_sigverify_threads,
                 xdp_sender: xdp_sender.clone(),
+                xdp_fetch_receiver,
             },
             &max_slots,
             block_metadata_notifier,
@@ -1827,6 +1845,7 @@ impl Validator {
             repair_quic_endpoints_runtime,
             repair_quic_endpoints_join_handle,
             xdp_retransmitter,
+            xdp_shred_receiver,
             _tpu_client_next_runtime: tpu_client_next_runtime,
         })
     }
@@ -1955,6 +1974,9 @@ impl Validator {
         if let Some(xdp_retransmitter) = self.xdp_retransmitter {
             xdp_retransmitter.join().expect("xdp_retransmitter");
         }
+        if let Some(xdp_shred_receiver) = self.xdp_shred_receiver {
+            xdp_shred_receiver.join().expect("xdp_shred_receiver");
+        }
         self.tpu.join().expect("tpu");
         self.tvu.join().expect("tvu");
         if let Some(turbine_quic_endpoint_join_handle) = self.turbine_quic_endpoint_join_handle {
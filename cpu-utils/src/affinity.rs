@@ -2,8 +2,10 @@
 
 use {
     crate::error::CpuAffinityError,
-    std::{collections::HashSet, fs, io},
+    std::{collections::HashSet, fs, io, path::Path},
 };
+#[cfg(target_os = "linux")]
+use {crate::numa::numa_nodes, std::sync::OnceLock};
 
 /// Maximum CPU ID that can be used with CPU_SET.
 ///
@@ -14,6 +16,17 @@ use {
 #[cfg(target_os = "linux")]
 const CPU_SETSIZE: usize = 1024;
 
+/// The sysfs directory holding the `cpu` and `node` directories.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) const SYSTEM_DIR: &str = "/sys/devices/system";
+
+/// The distance of a node to itself in the ACPI SLIT table, remote nodes are further.
+pub const LOCAL_NUMA_DISTANCE: u32 = 10;
+
+// Nodes are only onlined by memory hotplug, which validators don't use.
+#[cfg(target_os = "linux")]
+static NUMA_DISTANCES: OnceLock<Vec<Vec<u32>>> = OnceLock::new();
+
 /// Set CPU affinity for the calling thread.
 ///
 /// Restricts the thread to run only on the specified CPUs. Duplicate CPU IDs are
@@ -40,15 +53,26 @@ const CPU_SETSIZE: usize = 1024;
 ///
 /// Returns [`CpuAffinityError::EmptyCpuList`] if the CPU list is empty.
 /// Returns [`CpuAffinityError::InvalidCpu`] if any CPU ID exceeds the system maximum.
+/// Returns [`CpuAffinityError::OfflineCpu`] if any CPU is offline.
 /// Returns [`CpuAffinityError::Io`] if the system call fails (e.g., permission denied).
 /// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
 ///
 #[cfg(target_os = "linux")]
 pub fn set_cpu_affinity(cpus: impl IntoIterator<Item = usize>) -> Result<(), CpuAffinityError> {
+    set_thread_cpu_affinity(0, cpus) // 0 means current thread
+}
+
+/// Set the CPU affinity of the thread `tid` of any process.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_thread_cpu_affinity(
+    tid: libc::pid_t,
+    cpus: impl IntoIterator<Item = usize>,
+) -> Result<(), CpuAffinityError> {
     // Initialize CPU set
     // safety: cpu_set_t is a POD type, zero-initialization is standard
     let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
-    let max_cpu = max_cpu_id()?;
+    let online = online_cpus()?;
+    let max_cpu = online.last().copied().unwrap_or(0);
     let mut has_cpus = false;
 
     // validate, deduplicate via CPU_ISSET, and set CPUs
@@ -57,6 +81,10 @@ pub fn set_cpu_affinity(cpus: impl IntoIterator<Item = usize>) -> Result<(), Cpu
         if cpu > max_cpu {
             return Err(CpuAffinityError::InvalidCpu { cpu, max: max_cpu });
         }
+        // sched_setaffinity ignores offline CPUs, or fails with EINVAL if all are
+        if online.binary_search(&cpu).is_err() {
+            return Err(CpuAffinityError::OfflineCpu { cpu });
+        }
         // Also validate against CPU_SETSIZE to prevent undefined behavior
         if cpu >= CPU_SETSIZE {
             return Err(CpuAffinityError::InvalidCpu {
@@ -84,13 +112,8 @@ pub fn set_cpu_affinity(cpus: impl IntoIterator<Item = usize>) -> Result<(), Cpu
 
     // Apply the affinity
     // safety: sched_setaffinity is safe with valid parameters
-    let result = unsafe {
-        libc::sched_setaffinity(
-            0, // 0 means current thread
-            std::mem::size_of::<libc::cpu_set_t>(),
-            &cpu_set,
-        )
-    };
+    let result =
+        unsafe { libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &cpu_set) };
 
     if result != 0 {
         return Err(CpuAffinityError::Io(io::Error::last_os_error()));
@@ -125,17 +148,19 @@ pub fn set_cpu_affinity(_cpus: impl IntoIterator<Item = usize>) -> Result<(), Cp
 /// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
 #[cfg(target_os = "linux")]
 pub fn cpu_affinity() -> Result<Vec<usize>, CpuAffinityError> {
+    thread_cpu_affinity(0) // 0 means current thread
+}
+
+/// Get the CPU affinity mask of the thread `tid` of any process.
+#[cfg(target_os = "linux")]
+pub(crate) fn thread_cpu_affinity(tid: libc::pid_t) -> Result<Vec<usize>, CpuAffinityError> {
     // safety: cpu_set_t is a POD type, zero-initialization is standard
     let mut cpu_set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
 
     // Get current affinity
     // safety: sched_getaffinity is safe with valid parameters
     let result = unsafe {
-        libc::sched_getaffinity(
-            0, // 0 means current thread
-            std::mem::size_of::<libc::cpu_set_t>(),
-            &mut cpu_set,
-        )
+        libc::sched_getaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &mut cpu_set)
     };
 
     if result != 0 {
@@ -165,6 +190,7 @@ pub fn cpu_affinity() -> Result<Vec<usize>, CpuAffinityError> {
 /// Get the maximum CPU ID on the system (online CPUs only).
 ///
 /// Reads from `/sys/devices/system/cpu/online` or falls back to `sysconf(_SC_NPROCESSORS_ONLN)`.
+/// CPUs below the maximum can be offline, like SMT siblings taken offline, see [`online_cpus`].
 ///
 /// # Examples
 ///
@@ -183,17 +209,44 @@ pub fn cpu_affinity() -> Result<Vec<usize>, CpuAffinityError> {
 /// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
 #[cfg(target_os = "linux")]
 pub fn max_cpu_id() -> Result<usize, CpuAffinityError> {
-    // Try to read from sysfs first
-    if let Ok(content) = fs::read_to_string("/sys/devices/system/cpu/online") {
-        let content = content.trim();
+    Ok(online_cpus()?.last().copied().unwrap_or(0))
+}
 
-        // Parse range (e.g., "0-127" or just "0")
-        if let Some(range) = content.split('-').nth(1) {
-            if let Ok(max) = range.parse::<usize>() {
-                return Ok(max);
-            }
-        } else if let Ok(max) = content.parse::<usize>() {
-            return Ok(max);
+#[cfg(not(target_os = "linux"))]
+pub fn max_cpu_id() -> Result<usize, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Get the online CPUs, sorted.
+///
+/// Reads from `/sys/devices/system/cpu/online`, which can have holes (e.g., "0-3,8-11" with the
+/// SMT siblings of a 4 core CPU taken offline), or falls back to `sysconf(_SC_NPROCESSORS_ONLN)`,
+/// which assumes there are none.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use agave_cpu_utils::*;
+/// # fn main() -> Result<(), CpuAffinityError> {
+/// let online = online_cpus()?;
+/// set_cpu_affinity(online)?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::ParseError`] if the sysfs data is malformed.
+/// Returns [`CpuAffinityError::Io`] if unable to determine CPU count.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn online_cpus() -> Result<Vec<usize>, CpuAffinityError> {
+    // Try to read from sysfs first
+    if let Ok(content) = fs::read_to_string(format!("{SYSTEM_DIR}/cpu/online")) {
+        let mut online = parse_cpu_range_list(content.trim())?;
+        if !online.is_empty() {
+            online.sort_unstable();
+            return Ok(online);
         }
     }
 
@@ -211,18 +264,19 @@ pub fn max_cpu_id() -> Result<usize, CpuAffinityError> {
         return Err(CpuAffinityError::Io(io::Error::last_os_error()));
     }
 
-    Ok((count as usize).saturating_sub(1))
+    Ok((0..count as usize).collect())
 }
 
 #[cfg(not(target_os = "linux"))]
-pub fn max_cpu_id() -> Result<usize, CpuAffinityError> {
+pub fn online_cpus() -> Result<Vec<usize>, CpuAffinityError> {
     Err(CpuAffinityError::NotSupported)
 }
 
 /// Get the total number of online CPUs on the system.
 ///
 /// Returns the count of online logical CPUs (includes hyperthreads). Equivalent to `max_cpu_id() + 1`.
-/// Note: This returns only online CPUs, not all present CPUs.
+/// Note: This returns only online CPUs, not all present CPUs, but counts the offline CPUs below the
+/// maximum. Use [`online_cpus`] on systems with offline CPUs.
 ///
 /// # Examples
 ///
@@ -292,9 +346,92 @@ pub fn isolated_cpus() -> Result<Vec<usize>, CpuAffinityError> {
     Err(CpuAffinityError::NotSupported)
 }
 
-/// Parse a CPU range list string (e.g., "0-3,5,7-9") into a vector of CPU IDs.
+/// Returns the distances between the online NUMA nodes, as reported by the firmware.
+///
+/// `distances[i][j]` is the cost of an access from the `i`th node of [`numa_nodes`] to the memory
+/// of the `j`th one, relative to [`LOCAL_NUMA_DISTANCE`] for local memory. The matrix is read once
+/// and cached.
+///
+/// [`numa_nodes`]: crate::numa_nodes
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the distances can't be
+/// read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
 #[cfg(target_os = "linux")]
-fn parse_cpu_range_list(s: &str) -> Result<Vec<usize>, CpuAffinityError> {
+pub fn numa_distances() -> Result<Vec<Vec<u32>>, CpuAffinityError> {
+    if let Some(distances) = NUMA_DISTANCES.get() {
+        return Ok(distances.clone());
+    }
+    let distances = read_numa_distances(Path::new(SYSTEM_DIR), &numa_nodes()?)?;
+    Ok(NUMA_DISTANCES.get_or_init(|| distances).clone())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn numa_distances() -> Result<Vec<Vec<u32>>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Reads the distances between `nodes` from `system_dir`, usually `/sys/devices/system`.
+///
+/// Without NUMA support, the only node is at the local distance of itself.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn read_numa_distances(
+    system_dir: &Path,
+    nodes: &[usize],
+) -> Result<Vec<Vec<u32>>, CpuAffinityError> {
+    let node_dir = system_dir.join("node");
+    if !fs::exists(&node_dir)? {
+        return Ok(vec![vec![LOCAL_NUMA_DISTANCE]]);
+    }
+    let rows = nodes
+        .iter()
+        .map(|node| fs::read_to_string(node_dir.join(format!("node{node}/distance"))))
+        .collect::<Result<Vec<_>, _>>()?;
+    parse_distances(&rows)
+}
+
+// Parses the `distance` files of the nodes, which list the distances to every online node.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_distances(rows: &[String]) -> Result<Vec<Vec<u32>>, CpuAffinityError> {
+    rows.iter()
+        .map(|row| {
+            let distances = row
+                .split_whitespace()
+                .map(|distance| {
+                    distance.parse().map_err(|_| {
+                        CpuAffinityError::ParseError(format!("Invalid NUMA distance: {distance}"))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if distances.len() != rows.len() {
+                return Err(CpuAffinityError::ParseError(format!(
+                    "Expected {} NUMA distances, got {row:?}",
+                    rows.len()
+                )));
+            }
+            Ok(distances)
+        })
+        .collect()
+}
+
+/// Parse a CPU range list string (e.g., "0-3,5,7-9") into a vector of CPU IDs.
+///
+/// This is the format the kernel uses for CPU lists in sysfs and procfs, like
+/// `/sys/devices/system/cpu/isolated` or `/proc/irq/*/smp_affinity_list`.
+///
+/// # Examples
+///
+/// ```
+/// # use agave_cpu_utils::*;
+/// assert_eq!(parse_cpu_range_list("0-2,8").unwrap(), vec![0, 1, 2, 8]);
+/// ```
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::ParseError`] if the list is malformed.
+pub fn parse_cpu_range_list(s: &str) -> Result<Vec<usize>, CpuAffinityError> {
     let mut cpus = HashSet::new();
 
     for part in s.split(',') {
@@ -413,6 +550,21 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_online_cpus() {
+        let online = online_cpus().unwrap();
+        assert!(online.is_sorted());
+        assert_eq!(online.last().copied(), Some(max_cpu_id().unwrap()));
+        // a hole left by an offline cpu
+        if let Some(cpu) = (0..max_cpu_id().unwrap()).find(|cpu| !online.contains(cpu)) {
+            assert!(matches!(
+                set_cpu_affinity([cpu]).unwrap_err(),
+                CpuAffinityError::OfflineCpu { cpu: c } if c == cpu
+            ));
+        }
+    }
+
     #[test]
     #[cfg(not(target_os = "linux"))]
     fn test_not_supported_on_non_linux() {
@@ -428,6 +580,10 @@ mod tests {
             max_cpu_id().unwrap_err(),
             CpuAffinityError::NotSupported
         ));
+        assert!(matches!(
+            online_cpus().unwrap_err(),
+            CpuAffinityError::NotSupported
+        ));
         assert!(matches!(
             isolated_cpus().unwrap_err(),
             CpuAffinityError::NotSupported
@@ -472,4 +628,47 @@ mod tests {
             assert_eq!(cpus, sorted, "isolated_cpus should return sorted CPU list");
         }
     }
+
+    #[test]
+    fn test_parse_distances() {
+        let rows = ["10 21\n", "21 10\n"].map(str::to_string);
+        assert_eq!(parse_distances(&rows).unwrap(), [[10, 21], [21, 10]]);
+        assert!(parse_distances(&["10 21".to_string()]).is_err());
+        assert!(parse_distances(&["ten".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_read_numa_distances() {
+        let system_dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            read_numa_distances(system_dir.path(), &[0]).unwrap(),
+            [[LOCAL_NUMA_DISTANCE]]
+        );
+
+        for (node, distances) in [(0, "10 32\n"), (2, "32 10\n")] {
+            let node_dir = system_dir.path().join(format!("node/node{node}"));
+            fs::create_dir_all(&node_dir).unwrap();
+            fs::write(node_dir.join("distance"), distances).unwrap();
+        }
+        assert_eq!(
+            read_numa_distances(system_dir.path(), &[0, 2]).unwrap(),
+            [[10, 32], [32, 10]]
+        );
+        assert!(matches!(
+            read_numa_distances(system_dir.path(), &[0, 1]),
+            Err(CpuAffinityError::Io(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_numa_distances() {
+        let nodes = numa_nodes().unwrap();
+        let distances = numa_distances().unwrap();
+        assert_eq!(distances.len(), nodes.len());
+        for (i, row) in distances.iter().enumerate() {
+            assert_eq!(row.len(), nodes.len());
+            assert!(row.iter().all(|distance| *distance >= row[i]));
+        }
+    }
 }
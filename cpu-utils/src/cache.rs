@@ -0,0 +1,183 @@
+//! CPU cache hierarchy.
+//!
+//! Batch and buffer sizes are best picked so that the working set of a thread fits in its caches.
+//! [`cache_info`] reads the caches of a CPU from `/sys/devices/system/cpu/cpuN/cache`.
+
+#[cfg(target_os = "linux")]
+use crate::affinity::{max_cpu_id, SYSTEM_DIR};
+use {
+    crate::{affinity::parse_cpu_range_list, error::CpuAffinityError},
+    std::{fs, io, path::Path},
+};
+
+/// What a cache holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    /// Both data and instructions.
+    Unified,
+}
+
+/// A cache of a CPU.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheInfo {
+    pub level: u32,
+    pub cache_type: CacheType,
+    /// The size in bytes, `None` if not reported, like on some virtual machines.
+    pub size: Option<usize>,
+    /// The size of a cache line in bytes.
+    pub line_size: Option<usize>,
+    /// The associativity, how many lines of a set a memory line can go to.
+    pub ways: Option<usize>,
+    /// The CPUs sharing the cache, including the CPU itself, sorted.
+    pub shared_cpus: Vec<usize>,
+}
+
+/// Returns the caches of `cpu`, sorted by level then type.
+///
+/// Systems that don't report their caches have none.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidCpu`] if `cpu` doesn't exist.
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the caches can't be
+/// read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn cache_info(cpu: usize) -> Result<Vec<CacheInfo>, CpuAffinityError> {
+    let max = max_cpu_id()?;
+    if cpu > max {
+        return Err(CpuAffinityError::InvalidCpu { cpu, max });
+    }
+    read_cache_info(Path::new(SYSTEM_DIR), cpu)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cache_info(_cpu: usize) -> Result<Vec<CacheInfo>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Reads the caches of `cpu` from `system_dir`, usually `/sys/devices/system`.
+pub(crate) fn read_cache_info(
+    system_dir: &Path,
+    cpu: usize,
+) -> Result<Vec<CacheInfo>, CpuAffinityError> {
+    let caches = match fs::read_dir(system_dir.join(format!("cpu/cpu{cpu}/cache"))) {
+        Ok(caches) => caches,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut info = Vec::new();
+    for cache in caches {
+        let path = cache?.path();
+        // uevent and the like, the caches are the index directories
+        let Ok(level) = fs::read_to_string(path.join("level")) else {
+            continue;
+        };
+        let read = |name| fs::read_to_string(path.join(name)).ok();
+        let number = |name| -> Result<Option<usize>, CpuAffinityError> {
+            read(name)
+                .map(|value| {
+                    value.trim().parse().map_err(|_| {
+                        CpuAffinityError::ParseError(format!("Invalid cache {name}: {value:?}"))
+                    })
+                })
+                .transpose()
+        };
+        let cache_type = fs::read_to_string(path.join("type"))?;
+        let shared_cpus = fs::read_to_string(path.join("shared_cpu_list"))?;
+        info.push(CacheInfo {
+            level: level.trim().parse().map_err(|_| {
+                CpuAffinityError::ParseError(format!("Invalid cache level: {level:?}"))
+            })?,
+            cache_type: parse_cache_type(&cache_type)?,
+            size: read("size")
+                .map(|size| parse_cache_size(&size))
+                .transpose()?,
+            line_size: number("coherency_line_size")?,
+            ways: number("ways_of_associativity")?,
+            shared_cpus: parse_cpu_range_list(shared_cpus.trim())?,
+        });
+    }
+    info.sort_by_key(|cache| (cache.level, cache.cache_type));
+    Ok(info)
+}
+
+/// Returns the cache of `level` in `caches`, the last level cache when `None`.
+///
+/// Instruction caches are skipped for the last level, they never are.
+pub(crate) fn shared_cache(caches: &[CacheInfo], level: Option<u32>) -> Option<&CacheInfo> {
+    match level {
+        Some(level) => caches.iter().find(|cache| cache.level == level),
+        None => caches
+            .iter()
+            .filter(|cache| cache.cache_type != CacheType::Instruction)
+            .max_by_key(|cache| cache.level),
+    }
+}
+
+fn parse_cache_type(cache_type: &str) -> Result<CacheType, CpuAffinityError> {
+    match cache_type.trim() {
+        "Data" => Ok(CacheType::Data),
+        "Instruction" => Ok(CacheType::Instruction),
+        "Unified" => Ok(CacheType::Unified),
+        other => Err(CpuAffinityError::ParseError(format!(
+            "Invalid cache type: {other}"
+        ))),
+    }
+}
+
+// Parses a cache size like `48K` into bytes.
+fn parse_cache_size(size: &str) -> Result<usize, CpuAffinityError> {
+    let size = size.trim();
+    let invalid = || CpuAffinityError::ParseError(format!("Invalid cache size: {size}"));
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let multiplier: usize = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cache_size() {
+        assert_eq!(parse_cache_size("48K\n").unwrap(), 48 * 1024);
+        assert_eq!(parse_cache_size("32M").unwrap(), 32 << 20);
+        assert_eq!(parse_cache_size("512").unwrap(), 512);
+        assert!(parse_cache_size("K").is_err());
+        assert!(parse_cache_size("12T").is_err());
+        assert_eq!(parse_cache_type("Unified\n").unwrap(), CacheType::Unified);
+        assert!(parse_cache_type("Trace").is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_cache_info() {
+        let caches = cache_info(0).unwrap();
+        assert!(caches.is_sorted_by_key(|cache| (cache.level, cache.cache_type)));
+        for cache in &caches {
+            assert!(cache.shared_cpus.contains(&0));
+            assert!(cache.level >= 1);
+        }
+        let cpu = max_cpu_id().unwrap().saturating_add(1);
+        assert!(matches!(
+            cache_info(cpu),
+            Err(CpuAffinityError::InvalidCpu { .. })
+        ));
+    }
+}
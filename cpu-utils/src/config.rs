@@ -0,0 +1,102 @@
+//! The CPU settings of the tuning configuration.
+//!
+//! A [`CpuConfig`] holds the profile the threads of a node are pinned with and the CPUs the kernel
+//! is expected to isolate from the scheduler. It's deserialized as part of the configuration that
+//! also covers the XDP datapath, so that the network and CPU settings are written down, and
+//! validated, in one place.
+
+use {
+    crate::{
+        affinity::{isolated_cpus, parse_cpu_range_list},
+        error::CpuAffinityError,
+        profile::CpuProfile,
+    },
+    serde::{Deserialize, Serialize},
+    std::collections::BTreeMap,
+};
+
+/// The CPU settings of a node. Everything is optional, the default pins nothing and expects
+/// nothing to be isolated.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CpuConfig {
+    /// The CPU range list of each thread role, see [`CpuProfile`].
+    pub profile: BTreeMap<String, String>,
+    /// The CPU range list the kernel is expected to isolate, e.g. with `isolcpus`.
+    pub isolated: String,
+}
+
+impl CpuConfig {
+    /// Returns the profile to [install](crate::install_cpu_profile).
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`CpuProfile::add_role`].
+    pub fn profile(&self) -> Result<CpuProfile, CpuAffinityError> {
+        let mut profile = CpuProfile::default();
+        for (role, cpus) in &self.profile {
+            profile.add_role(role, cpus)?;
+        }
+        Ok(profile)
+    }
+
+    /// Returns the CPUs expected to be isolated.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CpuAffinityError::ParseError`] if the list is malformed.
+    pub fn isolated(&self) -> Result<Vec<usize>, CpuAffinityError> {
+        parse_cpu_range_list(&self.isolated)
+    }
+
+    /// Checks that the settings are well formed, without looking at the host.
+    pub fn validate(&self) -> Result<(), CpuAffinityError> {
+        self.profile()?;
+        self.isolated()?;
+        Ok(())
+    }
+
+    /// Returns the CPUs expected to be isolated that the kernel doesn't isolate.
+    pub fn missing_isolated_cpus(&self) -> Result<Vec<usize>, CpuAffinityError> {
+        let isolated = isolated_cpus()?;
+        Ok(self
+            .isolated()?
+            .into_iter()
+            .filter(|cpu| !isolated.contains(cpu))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_config() {
+        let mut config = CpuConfig {
+            profile: [("poh", "2"), ("banking", "8-11")]
+                .into_iter()
+                .map(|(role, cpus)| (role.to_owned(), cpus.to_owned()))
+                .collect(),
+            isolated: "2,8-11".to_owned(),
+        };
+        config.validate().unwrap();
+        assert_eq!(
+            config.profile().unwrap().cpus(CpuProfile::BANKING),
+            Some(&[8, 9, 10, 11][..])
+        );
+        assert_eq!(config.isolated().unwrap(), [2, 8, 9, 10, 11]);
+        assert_eq!(
+            CpuConfig::default().profile().unwrap(),
+            CpuProfile::default()
+        );
+
+        config
+            .profile
+            .insert(CpuProfile::NET.to_owned(), "".to_owned());
+        assert!(matches!(
+            config.validate(),
+            Err(CpuAffinityError::EmptyCpuList)
+        ));
+    }
+}
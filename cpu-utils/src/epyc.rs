@@ -0,0 +1,255 @@
+//! Placement recommendations for AMD EPYC.
+//!
+//! An EPYC socket is made of CCDs, chiplets of cores sharing an L3 cache, around an I/O die that
+//! holds the memory controllers and the PCIe lanes. The BIOS setting NPS (NUMA nodes per socket)
+//! splits the socket in 1, 2 or 4 NUMA nodes, each with the CCDs, memory channels and PCIe devices
+//! closest to each other. [`epyc_split`] detects the layout and splits the CCDs between the
+//! network threads, on the node of the NIC, and the compute threads, on the rest of the socket.
+
+use crate::error::CpuAffinityError;
+#[cfg(target_os = "linux")]
+use {
+    crate::{
+        hint::interface_numa_node, numa::cpu_to_node, placement::llc_domains, topology::package_of,
+    },
+    std::fs,
+};
+
+/// The number of NUMA nodes per socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NpsMode {
+    /// One node for all the sockets.
+    Nps0,
+    Nps1,
+    Nps2,
+    Nps4,
+    /// Another number of nodes, like one per CCD with the "L3 as NUMA" setting.
+    Other(usize),
+}
+
+/// A CCD, or a CCX on the CCDs with several L3 caches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ccd {
+    pub package: usize,
+    pub node: usize,
+    /// The CPUs sharing the L3 cache, sorted.
+    pub cpus: Vec<usize>,
+}
+
+/// The CPUs recommended for the network and the compute threads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EpycSplit {
+    pub nps: NpsMode,
+    pub ccds: Vec<Ccd>,
+    /// The CPUs of the network threads, like XDP and the QUIC servers, sorted.
+    pub network: Vec<usize>,
+    /// The CPUs of the compute threads, like sigverify and banking, sorted.
+    pub compute: Vec<usize>,
+}
+
+/// Splits the CCDs of an AMD EPYC between network and compute threads.
+///
+/// The network threads get the CCDs of the NUMA node of `interface`, or of the node of CPU 0 if
+/// `None` or the node isn't known. With NPS1 and NPS0 the node spans the whole socket, so they only
+/// get its first CCD. The compute threads get the other CCDs of the same socket: the other sockets
+/// are left out, crossing them costs more than sharing a node.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::NotEnoughCpus`] if the socket has no CCD left for compute.
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the topology or the
+/// node of the interface can't be read.
+/// Returns [`CpuAffinityError::NotSupported`] on CPUs other than AMD EPYC and non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn epyc_split(interface: Option<&str>) -> Result<EpycSplit, CpuAffinityError> {
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo")?;
+    if !is_epyc(&cpuinfo) {
+        return Err(CpuAffinityError::NotSupported);
+    }
+    let ccds = llc_domains()?
+        .into_iter()
+        .map(|cpus| {
+            Ok(Ccd {
+                package: package_of(cpus[0])?,
+                node: cpu_to_node(cpus[0])?,
+                cpus,
+            })
+        })
+        .collect::<Result<Vec<_>, CpuAffinityError>>()?;
+    let node = match interface {
+        Some(interface) => interface_numa_node(interface)?,
+        None => None,
+    };
+    split(ccds, node)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn epyc_split(_interface: Option<&str>) -> Result<EpycSplit, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn is_epyc(cpuinfo: &str) -> bool {
+    let field = |name| {
+        cpuinfo.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == name).then_some(value.trim())
+        })
+    };
+    field("vendor_id") == Some("AuthenticAMD")
+        && field("model name").is_some_and(|model| model.contains("EPYC"))
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn nps_mode(ccds: &[Ccd]) -> NpsMode {
+    let mut nodes_per_package = Vec::<(usize, Vec<usize>)>::new();
+    for ccd in ccds {
+        match nodes_per_package
+            .iter_mut()
+            .find(|(package, _)| *package == ccd.package)
+        {
+            Some((_, nodes)) if !nodes.contains(&ccd.node) => nodes.push(ccd.node),
+            Some(_) => {}
+            None => nodes_per_package.push((ccd.package, vec![ccd.node])),
+        }
+    }
+    let shared = nodes_per_package.iter().enumerate().any(|(i, (_, nodes))| {
+        nodes_per_package[i.saturating_add(1)..]
+            .iter()
+            .any(|(_, other)| other.iter().any(|node| nodes.contains(node)))
+    });
+    if shared {
+        return NpsMode::Nps0;
+    }
+    match nodes_per_package.iter().map(|(_, nodes)| nodes.len()).max() {
+        Some(1) | None => NpsMode::Nps1,
+        Some(2) => NpsMode::Nps2,
+        Some(4) => NpsMode::Nps4,
+        Some(nodes) => NpsMode::Other(nodes),
+    }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn split(ccds: Vec<Ccd>, node: Option<usize>) -> Result<EpycSplit, CpuAffinityError> {
+    let nps = nps_mode(&ccds);
+    let node = node
+        .filter(|node| ccds.iter().any(|ccd| ccd.node == *node))
+        .or_else(|| {
+            ccds.iter()
+                .find(|ccd| ccd.cpus.contains(&0))
+                .or(ccds.first())
+                .map(|ccd| ccd.node)
+        })
+        .ok_or(CpuAffinityError::NotEnoughCpus {
+            requested: 2,
+            available: 0,
+        })?;
+    let network_ccds = ccds
+        .iter()
+        .filter(|ccd| ccd.node == node)
+        .take(match nps {
+            NpsMode::Nps0 | NpsMode::Nps1 => 1,
+            _ => usize::MAX,
+        })
+        .collect::<Vec<_>>();
+    let package = network_ccds[0].package;
+
+    let mut network = network_ccds
+        .iter()
+        .flat_map(|ccd| ccd.cpus.iter().copied())
+        .collect::<Vec<_>>();
+    let mut compute = ccds
+        .iter()
+        .filter(|ccd| ccd.package == package && !network_ccds.contains(ccd))
+        .flat_map(|ccd| ccd.cpus.iter().copied())
+        .collect::<Vec<_>>();
+    if compute.is_empty() {
+        return Err(CpuAffinityError::NotEnoughCpus {
+            requested: network.len().saturating_add(1),
+            available: network.len(),
+        });
+    }
+    network.sort_unstable();
+    compute.sort_unstable();
+    Ok(EpycSplit {
+        nps,
+        ccds,
+        network,
+        compute,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ccds(layout: &[(usize, usize)]) -> Vec<Ccd> {
+        layout
+            .iter()
+            .enumerate()
+            .map(|(i, &(package, node))| Ccd {
+                package,
+                node,
+                cpus: (i * 4..(i + 1) * 4).collect(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split() {
+        // one socket of 8 CCDs in NPS4
+        let layout = [
+            (0, 0),
+            (0, 0),
+            (0, 1),
+            (0, 1),
+            (0, 2),
+            (0, 2),
+            (0, 3),
+            (0, 3),
+        ];
+        let recommended = split(ccds(&layout), Some(2)).unwrap();
+        assert_eq!(recommended.nps, NpsMode::Nps4);
+        assert_eq!(recommended.network, (16..24).collect::<Vec<_>>());
+        assert_eq!(recommended.compute.len(), 24);
+        assert!(!recommended.compute.contains(&16));
+
+        // two sockets in NPS1, the other socket is left out
+        let layout = [(0, 0), (0, 0), (0, 0), (1, 1), (1, 1)];
+        let recommended = split(ccds(&layout), None).unwrap();
+        assert_eq!(recommended.nps, NpsMode::Nps1);
+        assert_eq!(recommended.network, [0, 1, 2, 3]);
+        assert_eq!(recommended.compute, (4..12).collect::<Vec<_>>());
+        // a node without CCDs is ignored
+        assert_eq!(split(ccds(&layout), Some(5)).unwrap(), recommended);
+
+        // two sockets in NPS0
+        assert_eq!(nps_mode(&ccds(&[(0, 0), (1, 0)])), NpsMode::Nps0);
+        assert_eq!(
+            nps_mode(&ccds(&[(0, 0), (0, 1), (0, 2)])),
+            NpsMode::Other(3)
+        );
+
+        assert!(matches!(
+            split(ccds(&[(0, 0)]), None),
+            Err(CpuAffinityError::NotEnoughCpus {
+                requested: 5,
+                available: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn test_is_epyc() {
+        assert!(is_epyc(
+            "processor\t: 0\nvendor_id\t: AuthenticAMD\nmodel name\t: AMD EPYC 9254 24-Core \
+             Processor\n"
+        ));
+        assert!(!is_epyc(
+            "vendor_id\t: AuthenticAMD\nmodel name\t: AMD Ryzen 9 7950X\n"
+        ));
+        assert!(!is_epyc(
+            "vendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Xeon(R) Processor\n"
+        ));
+    }
+}
@@ -18,17 +18,44 @@ pub enum CpuAffinityError {
     #[error("CPU {cpu} is invalid (max CPU is {max})")]
     InvalidCpu { cpu: usize, max: usize },
 
+    /// CPU that isn't online
+    #[error("CPU {cpu} is offline")]
+    OfflineCpu { cpu: usize },
+
     /// Invalid physical core ID
     #[error("Physical core {core} is invalid (max core is {max})")]
     InvalidPhysicalCore { core: usize, max: usize },
 
+    /// NUMA node that isn't online
+    #[error("NUMA node {node} is not online")]
+    InvalidNode { node: usize },
+
     /// CPU list is empty
     #[error("CPU list cannot be empty")]
     EmptyCpuList,
 
+    /// Huge page size the system doesn't have
+    #[error("Huge pages of {page_size} bytes are not supported")]
+    InvalidHugepageSize { page_size: usize },
+
+    /// Locking memory would go over RLIMIT_MEMLOCK
+    #[error(
+        "Locking {requested} bytes exceeds the RLIMIT_MEMLOCK of {limit} bytes, raise it or grant \
+         CAP_IPC_LOCK"
+    )]
+    MemlockLimit { requested: u64, limit: u64 },
+
+    /// NUMA node list is empty
+    #[error("NUMA node list cannot be empty")]
+    EmptyNodeList,
+
     /// Failed to parse CPU range or ID
     #[error("Failed to parse CPU specification: {0}")]
     ParseError(String),
+
+    /// Fewer CPUs are available than requested
+    #[error("{requested} CPUs requested but only {available} are available")]
+    NotEnoughCpus { requested: usize, available: usize },
 }
 
 #[cfg(test)]
@@ -40,6 +67,9 @@ mod tests {
         let err = CpuAffinityError::InvalidCpu { cpu: 10, max: 7 };
         assert_eq!(err.to_string(), "CPU 10 is invalid (max CPU is 7)");
 
+        let err = CpuAffinityError::OfflineCpu { cpu: 3 };
+        assert_eq!(err.to_string(), "CPU 3 is offline");
+
         let err = CpuAffinityError::InvalidPhysicalCore { core: 5, max: 3 };
         assert_eq!(
             err.to_string(),
@@ -0,0 +1,219 @@
+//! Placement hints for memory heavy subsystems.
+//!
+//! Subsystems with large buffers, like the accounts index, the XDP UMEM or the packet buffers,
+//! describe where their memory should live with a [`PlacementHint`] and get back a [`Placement`]:
+//! the NUMA nodes to allocate from and the CPUs the threads touching the memory should run on, so
+//! that both end up on the same node.
+
+use {crate::error::CpuAffinityError, std::collections::BTreeMap};
+#[cfg(target_os = "linux")]
+use {
+    crate::numa::node_cpus,
+    std::{fs, io},
+};
+
+/// Which NUMA node the memory should be on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum NodeSelection {
+    /// No preference, the memory is allocated on whichever node touches it first.
+    #[default]
+    Any,
+    Node(usize),
+    /// The node most of these CPUs are on.
+    LocalToCpus(Vec<usize>),
+    /// The node the network interface is attached to.
+    LocalToInterface(String),
+}
+
+/// Whether the memory should be made of huge pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HugepagePreference {
+    #[default]
+    Never,
+    /// Huge pages if available, regular pages otherwise.
+    Prefer,
+    /// Huge pages only, the allocation fails otherwise.
+    Require,
+}
+
+/// How a subsystem would like its memory placed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PlacementHint {
+    pub node: NodeSelection,
+    /// Spread the pages over all the nodes rather than keeping them on one. Meant for memory
+    /// touched from every node, like the accounts index.
+    pub interleave: bool,
+    pub hugepages: HugepagePreference,
+}
+
+/// Where the memory of a [`PlacementHint`] goes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    /// The node the memory is bound to, `None` if any node will do.
+    pub node: Option<usize>,
+    /// The nodes to allocate from, all of them when interleaving.
+    pub memory_nodes: Vec<usize>,
+    pub interleave: bool,
+    /// The CPUs of the memory nodes, for the threads touching the memory.
+    pub cpus: Vec<usize>,
+    pub hugepages: HugepagePreference,
+}
+
+/// Resolves `hint` against the NUMA topology of the system.
+///
+/// Systems without NUMA support are a single node 0 with all the online CPUs.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the topology or the
+/// node of the interface can't be read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn resolve_placement(hint: &PlacementHint) -> Result<Placement, CpuAffinityError> {
+    let interface_node = match &hint.node {
+        NodeSelection::LocalToInterface(interface) => interface_numa_node(interface)?,
+        _ => None,
+    };
+    resolve(hint, &node_cpus()?, interface_node)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn resolve_placement(_hint: &PlacementHint) -> Result<Placement, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the node `interface` is attached to, `None` for virtual interfaces and systems that
+/// don't report it.
+#[cfg(target_os = "linux")]
+pub(crate) fn interface_numa_node(interface: &str) -> Result<Option<usize>, CpuAffinityError> {
+    match fs::read_to_string(format!("/sys/class/net/{interface}/device/numa_node")) {
+        // -1 if the platform doesn't know
+        Ok(node) => Ok(node.trim().parse().ok()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if fs::exists(format!("/sys/class/net/{interface}"))? {
+                Ok(None)
+            } else {
+                Err(e.into())
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn resolve(
+    hint: &PlacementHint,
+    nodes: &BTreeMap<usize, Vec<usize>>,
+    interface_node: Option<usize>,
+) -> Result<Placement, CpuAffinityError> {
+    let node = match &hint.node {
+        NodeSelection::Any => None,
+        NodeSelection::Node(node) => {
+            if !nodes.contains_key(node) {
+                return Err(CpuAffinityError::InvalidNode { node: *node });
+            }
+            Some(*node)
+        }
+        NodeSelection::LocalToCpus(cpus) => nodes
+            .iter()
+            .map(|(node, node_cpus)| {
+                let local = cpus.iter().filter(|cpu| node_cpus.contains(cpu)).count();
+                (local, *node)
+            })
+            // ties go to the lowest node
+            .max_by(|(a_local, a_node), (b_local, b_node)| {
+                a_local.cmp(b_local).then(b_node.cmp(a_node))
+            })
+            .filter(|(local, _)| *local > 0)
+            .map(|(_, node)| node),
+        NodeSelection::LocalToInterface(_) => {
+            interface_node.filter(|node| nodes.contains_key(node))
+        }
+    };
+
+    let memory_nodes = match node {
+        Some(node) if !hint.interleave => vec![node],
+        _ => nodes.keys().copied().collect(),
+    };
+    let mut cpus = match node {
+        Some(node) => nodes[&node].clone(),
+        None => nodes.values().flatten().copied().collect(),
+    };
+    cpus.sort_unstable();
+    Ok(Placement {
+        node,
+        memory_nodes,
+        interleave: hint.interleave,
+        cpus,
+        hugepages: hint.hugepages,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_placement() {
+        let nodes = BTreeMap::from([(0, vec![0, 1, 4, 5]), (1, vec![2, 3, 6, 7])]);
+
+        let placement = resolve(&PlacementHint::default(), &nodes, None).unwrap();
+        assert_eq!(placement.node, None);
+        assert_eq!(placement.memory_nodes, [0, 1]);
+        assert_eq!(placement.cpus, (0..8).collect::<Vec<_>>());
+
+        let hint = PlacementHint {
+            node: NodeSelection::LocalToCpus(vec![1, 2, 3]),
+            hugepages: HugepagePreference::Prefer,
+            ..PlacementHint::default()
+        };
+        let placement = resolve(&hint, &nodes, None).unwrap();
+        assert_eq!(placement.node, Some(1));
+        assert_eq!(placement.memory_nodes, [1]);
+        assert_eq!(placement.cpus, [2, 3, 6, 7]);
+        assert_eq!(placement.hugepages, HugepagePreference::Prefer);
+
+        // interleaving keeps the threads on the node but spreads the memory
+        let hint = PlacementHint {
+            node: NodeSelection::LocalToInterface("eth0".to_string()),
+            interleave: true,
+            ..PlacementHint::default()
+        };
+        let placement = resolve(&hint, &nodes, Some(0)).unwrap();
+        assert_eq!(placement.node, Some(0));
+        assert_eq!(placement.memory_nodes, [0, 1]);
+        assert_eq!(placement.cpus, [0, 1, 4, 5]);
+        // an interface without a node places like any
+        assert_eq!(resolve(&hint, &nodes, None).unwrap().node, None);
+
+        assert!(resolve(
+            &PlacementHint {
+                node: NodeSelection::Node(2),
+                ..PlacementHint::default()
+            },
+            &nodes,
+            None
+        )
+        .is_err_and(|e| matches!(e, CpuAffinityError::InvalidNode { node: 2 })));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_resolve_placement_local() {
+        let placement = resolve_placement(&PlacementHint {
+            node: NodeSelection::LocalToCpus(vec![0]),
+            ..PlacementHint::default()
+        })
+        .unwrap();
+        assert!(placement.cpus.contains(&0));
+        assert_eq!(
+            placement.memory_nodes,
+            placement.node.into_iter().collect::<Vec<_>>()
+        );
+        assert!(resolve_placement(&PlacementHint {
+            node: NodeSelection::LocalToInterface("doesnotexist0".to_string()),
+            ..PlacementHint::default()
+        })
+        .is_err());
+    }
+}
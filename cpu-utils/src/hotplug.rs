@@ -0,0 +1,164 @@
+//! Watching for CPUs going online and offline.
+//!
+//! Resizing a cloud instance or a VM, or taking SMT siblings offline, changes the online CPUs
+//! under a running validator, and threads pinned to CPUs that went away silently run elsewhere.
+//! A [`HotplugWatcher`] polls `/sys/devices/system/cpu/online`, which doesn't support inotify, and
+//! calls back with what changed so that the threads can be placed again.
+
+#[cfg(target_os = "linux")]
+use crate::affinity::online_cpus;
+use {
+    crate::error::CpuAffinityError,
+    std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread::{self, Builder},
+        time::Duration,
+    },
+};
+
+/// A change of the online CPUs.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HotplugEvent {
+    /// The CPUs that went online, sorted.
+    pub added: Vec<usize>,
+    /// The CPUs that went offline, sorted.
+    pub removed: Vec<usize>,
+    /// The online CPUs after the change, sorted.
+    pub online: Vec<usize>,
+}
+
+/// Calls back when CPUs go online or offline, see the [module documentation](self).
+pub struct HotplugWatcher {
+    thread: thread::JoinHandle<()>,
+}
+
+impl HotplugWatcher {
+    /// Polls the online CPUs every `interval` and calls `callback` when they change, until `exit`
+    /// is set.
+    ///
+    /// Polls that fail to read the online CPUs are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CpuAffinityError::Io`] if the online CPUs can't be read or the thread can't be
+    /// spawned.
+    /// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+    #[cfg(target_os = "linux")]
+    pub fn new(
+        interval: Duration,
+        exit: Arc<AtomicBool>,
+        callback: impl FnMut(&HotplugEvent) + Send + 'static,
+    ) -> Result<Self, CpuAffinityError> {
+        Self::spawn(online_cpus, interval, exit, callback)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(
+        _interval: Duration,
+        _exit: Arc<AtomicBool>,
+        _callback: impl FnMut(&HotplugEvent) + Send + 'static,
+    ) -> Result<Self, CpuAffinityError> {
+        Err(CpuAffinityError::NotSupported)
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn spawn(
+        mut read_online: impl FnMut() -> Result<Vec<usize>, CpuAffinityError> + Send + 'static,
+        interval: Duration,
+        exit: Arc<AtomicBool>,
+        mut callback: impl FnMut(&HotplugEvent) + Send + 'static,
+    ) -> Result<Self, CpuAffinityError> {
+        let mut online = read_online()?;
+        let thread = Builder::new()
+            .name("solCpuHotplug".to_string())
+            .spawn(move || {
+                // unparked by join to notice exit early
+                thread::park_timeout(interval);
+                while !exit.load(Ordering::Relaxed) {
+                    if let Ok(new_online) = read_online() {
+                        if let Some(event) = diff(&online, new_online) {
+                            callback(&event);
+                            online = event.online;
+                        }
+                    }
+                    thread::park_timeout(interval);
+                }
+            })?;
+        Ok(Self { thread })
+    }
+
+    /// Waits for the watcher to stop, once `exit` is set.
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.thread().unpark();
+        self.thread.join()
+    }
+}
+
+// Returns what changed from `old` to `new`, both sorted, `None` if nothing did.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn diff(old: &[usize], new: Vec<usize>) -> Option<HotplugEvent> {
+    if old == new {
+        return None;
+    }
+    Some(HotplugEvent {
+        added: new
+            .iter()
+            .copied()
+            .filter(|cpu| old.binary_search(cpu).is_err())
+            .collect(),
+        removed: old
+            .iter()
+            .copied()
+            .filter(|cpu| new.binary_search(cpu).is_err())
+            .collect(),
+        online: new,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::sync::{mpsc, Mutex},
+    };
+
+    #[test]
+    fn test_hotplug_watcher() {
+        let online = Arc::new(Mutex::new(vec![0, 1, 2, 3]));
+        let exit = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = mpsc::channel();
+        let watcher = {
+            let online = Arc::clone(&online);
+            HotplugWatcher::spawn(
+                move || Ok(online.lock().unwrap().clone()),
+                Duration::from_millis(1),
+                Arc::clone(&exit),
+                move |event| sender.send(event.clone()).unwrap(),
+            )
+            .unwrap()
+        };
+
+        *online.lock().unwrap() = vec![0, 1, 3];
+        let event = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            event,
+            HotplugEvent {
+                added: vec![],
+                removed: vec![2],
+                online: vec![0, 1, 3],
+            }
+        );
+        *online.lock().unwrap() = vec![0, 1, 2, 3, 4];
+        let event = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(event.added, [2, 4]);
+        assert!(event.removed.is_empty());
+
+        exit.store(true, Ordering::Relaxed);
+        watcher.join().unwrap();
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(diff(&[0, 1], vec![0, 1]), None);
+    }
+}
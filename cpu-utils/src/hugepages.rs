@@ -0,0 +1,375 @@
+//! Huge pages of each NUMA node.
+//!
+//! Huge pages have to be reserved in the pool of a node before they can be mapped, and a mapping
+//! asking for huge pages on a node with an empty pool fails. [`hugepages`] reports the pools of
+//! each node and [`reserve_hugepages`] grows one, so that the XDP UMEM or the accounts mmaps can
+//! check for, or make room for, their pages before allocating.
+//!
+//! Transparent huge pages need no reservation, the kernel backs memory with them when it can.
+//! [`thp_status`] tells when it does, and [`advise_hugepage`] and [`advise_nohugepage`] opt a
+//! buffer in or out.
+
+use crate::error::CpuAffinityError;
+#[cfg(target_os = "linux")]
+use {
+    crate::numa::node_cpus,
+    std::{fs, io, path::PathBuf},
+};
+
+#[cfg(target_os = "linux")]
+const THP_DIR: &str = "/sys/kernel/mm/transparent_hugepage";
+
+/// When the kernel backs memory with transparent huge pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThpMode {
+    Always,
+    /// Only in regions advised with [`advise_hugepage`].
+    Madvise,
+    Never,
+}
+
+/// What a page fault does when no huge page is free, from the `defrag` setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThpDefrag {
+    /// Compacts memory right away.
+    Always,
+    /// Falls back to regular pages and wakes kcompactd.
+    Defer,
+    /// Compacts right away in advised regions, defers in the others.
+    DeferMadvise,
+    /// Compacts right away in advised regions, falls back to regular pages in the others.
+    Madvise,
+    /// Falls back to regular pages.
+    Never,
+}
+
+/// The transparent huge page settings of the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThpStatus {
+    pub enabled: ThpMode,
+    pub defrag: ThpDefrag,
+    /// Whether khugepaged compacts memory to collapse regular pages into huge ones in the
+    /// background, which takes locks the pinned threads may wait on.
+    pub khugepaged_defrag: bool,
+}
+
+impl ThpStatus {
+    /// Whether a page fault in an advised region can stall compacting memory, which shows in the
+    /// tail latency of the threads touching new memory.
+    pub fn compacts_on_fault(&self) -> bool {
+        self.enabled != ThpMode::Never
+            && self.defrag != ThpDefrag::Defer
+            && self.defrag != ThpDefrag::Never
+    }
+}
+
+/// The huge pages of one size on a node.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HugepagePool {
+    pub node: usize,
+    /// The size of the pages in bytes, e.g. 2MB or 1GB.
+    pub page_size: usize,
+    /// The pages in the pool, free or not.
+    pub total: usize,
+    pub free: usize,
+    /// The pages allocated over `total`, on systems allowing overcommit.
+    pub surplus: usize,
+}
+
+/// Returns the huge page pools of the online nodes, sorted by node then page size.
+///
+/// Systems without NUMA support report the pools of the system as node 0.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the pools can't be
+/// read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn hugepages() -> Result<Vec<HugepagePool>, CpuAffinityError> {
+    let mut pools = Vec::new();
+    for node in node_cpus()?.into_keys() {
+        // no hugetlbfs support if there's no directory
+        let Some(Ok(entries)) = node_dir(node).map(fs::read_dir) else {
+            continue;
+        };
+        for entry in entries {
+            let entry = entry?;
+            let Some(page_size) = entry.file_name().to_str().and_then(parse_page_size) else {
+                continue;
+            };
+            let read = |name: &str| -> Result<usize, CpuAffinityError> {
+                let value = fs::read_to_string(entry.path().join(name))?;
+                value.trim().parse().map_err(|_| {
+                    CpuAffinityError::ParseError(format!("Invalid {name}: {}", value.trim()))
+                })
+            };
+            pools.push(HugepagePool {
+                node,
+                page_size,
+                total: read("nr_hugepages")?,
+                free: read("free_hugepages")?,
+                surplus: read("surplus_hugepages")?,
+            });
+        }
+    }
+    pools.sort_unstable_by_key(|pool| (pool.node, pool.page_size));
+    Ok(pools)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn hugepages() -> Result<Vec<HugepagePool>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Grows the pool of `page_size` huge pages of `node` until `count` of them are free, and returns
+/// the number of free pages.
+///
+/// Needs root. The kernel may not find enough contiguous memory for all the pages, fewer than
+/// `count` pages are free then. Reserving 1GB pages usually only works early after boot.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidNode`] if `node` isn't online.
+/// Returns [`CpuAffinityError::InvalidHugepageSize`] if the system has no pages of `page_size`.
+/// Returns [`CpuAffinityError::Io`] if the pool can't be grown.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn reserve_hugepages(
+    node: usize,
+    page_size: usize,
+    count: usize,
+) -> Result<usize, CpuAffinityError> {
+    let pools = hugepages()?;
+    if !pools.iter().any(|pool| pool.node == node) && !node_cpus()?.contains_key(&node) {
+        return Err(CpuAffinityError::InvalidNode { node });
+    }
+    let pool = pools
+        .into_iter()
+        .find(|pool| pool.node == node && pool.page_size == page_size)
+        .ok_or(CpuAffinityError::InvalidHugepageSize { page_size })?;
+    if pool.free >= count {
+        return Ok(pool.free);
+    }
+
+    let dir = node_dir(node)
+        .expect("node is online")
+        .join(format!("hugepages-{}kB", page_size / 1024));
+    let total = pool.total.saturating_add(count.saturating_sub(pool.free));
+    fs::write(dir.join("nr_hugepages"), total.to_string())?;
+    let free = fs::read_to_string(dir.join("free_hugepages"))?;
+    free.trim()
+        .parse()
+        .map_err(|_| CpuAffinityError::ParseError(format!("Invalid free_hugepages: {free}")))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reserve_hugepages(
+    _node: usize,
+    _page_size: usize,
+    _count: usize,
+) -> Result<usize, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the transparent huge page settings.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] if the kernel has no transparent huge page support.
+/// Returns [`CpuAffinityError::ParseError`] if a setting can't be parsed.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn thp_status() -> Result<ThpStatus, CpuAffinityError> {
+    let enabled = fs::read_to_string(format!("{THP_DIR}/enabled"))?;
+    let defrag = fs::read_to_string(format!("{THP_DIR}/defrag"))?;
+    let khugepaged_defrag = fs::read_to_string(format!("{THP_DIR}/khugepaged/defrag"))?;
+    parse_thp_status(&enabled, &defrag, &khugepaged_defrag)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn thp_status() -> Result<ThpStatus, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Asks the kernel to back the pages overlapping the `len` bytes at `ptr` with transparent huge
+/// pages, when [`ThpMode::Madvise`] is enabled.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] if the range isn't mapped or the kernel has no transparent
+/// huge page support.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn advise_hugepage(ptr: *const u8, len: usize) -> Result<(), CpuAffinityError> {
+    advise(ptr, len, libc::MADV_HUGEPAGE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn advise_hugepage(_ptr: *const u8, _len: usize) -> Result<(), CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Keeps the pages overlapping the `len` bytes at `ptr` off transparent huge pages, even when
+/// [`ThpMode::Always`] is enabled, e.g. for sparse buffers.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] if the range isn't mapped or the kernel has no transparent
+/// huge page support.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn advise_nohugepage(ptr: *const u8, len: usize) -> Result<(), CpuAffinityError> {
+    advise(ptr, len, libc::MADV_NOHUGEPAGE)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn advise_nohugepage(_ptr: *const u8, _len: usize) -> Result<(), CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+#[cfg(target_os = "linux")]
+fn advise(ptr: *const u8, len: usize, advice: libc::c_int) -> Result<(), CpuAffinityError> {
+    // Safety: just a libc wrapper
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = (ptr as usize) & !(page_size - 1);
+    let len = (ptr as usize).saturating_add(len).saturating_sub(start);
+    // Safety: these advices only change how the range is backed, not its contents
+    if unsafe { libc::madvise(start as *mut libc::c_void, len, advice) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// Parses the settings of /sys/kernel/mm/transparent_hugepage, where the selected value of
+// `enabled` and `defrag` is in brackets.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_thp_status(
+    enabled: &str,
+    defrag: &str,
+    khugepaged_defrag: &str,
+) -> Result<ThpStatus, CpuAffinityError> {
+    let selected = |setting: &str| {
+        setting
+            .split_whitespace()
+            .find_map(|value| value.strip_prefix('[')?.strip_suffix(']'))
+            .map(str::to_string)
+            .ok_or_else(|| {
+                CpuAffinityError::ParseError(format!("Invalid THP setting: {}", setting.trim()))
+            })
+    };
+    let enabled = match selected(enabled)?.as_str() {
+        "always" => ThpMode::Always,
+        "madvise" => ThpMode::Madvise,
+        "never" => ThpMode::Never,
+        mode => {
+            return Err(CpuAffinityError::ParseError(format!(
+                "Unknown THP mode: {mode}"
+            )))
+        }
+    };
+    let defrag = match selected(defrag)?.as_str() {
+        "always" => ThpDefrag::Always,
+        "defer" => ThpDefrag::Defer,
+        "defer+madvise" => ThpDefrag::DeferMadvise,
+        "madvise" => ThpDefrag::Madvise,
+        "never" => ThpDefrag::Never,
+        defrag => {
+            return Err(CpuAffinityError::ParseError(format!(
+                "Unknown THP defrag: {defrag}"
+            )))
+        }
+    };
+    Ok(ThpStatus {
+        enabled,
+        defrag,
+        khugepaged_defrag: khugepaged_defrag.trim() != "0",
+    })
+}
+
+// Returns the directory of the huge page pools of `node`, those of the system if there's no NUMA
+// support.
+#[cfg(target_os = "linux")]
+fn node_dir(node: usize) -> Option<PathBuf> {
+    let dir = PathBuf::from(format!("/sys/devices/system/node/node{node}/hugepages"));
+    if fs::exists("/sys/devices/system/node").unwrap_or(false) {
+        Some(dir)
+    } else {
+        (node == 0).then(|| PathBuf::from("/sys/kernel/mm/hugepages"))
+    }
+}
+
+// Parses the page size in bytes of a pool directory, like `hugepages-2048kB`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_page_size(name: &str) -> Option<usize> {
+    name.strip_prefix("hugepages-")?
+        .strip_suffix("kB")?
+        .parse::<usize>()
+        .ok()?
+        .checked_mul(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_page_size() {
+        assert_eq!(parse_page_size("hugepages-2048kB"), Some(2 << 20));
+        assert_eq!(parse_page_size("hugepages-1048576kB"), Some(1 << 30));
+        assert_eq!(parse_page_size("hugepages-2MB"), None);
+        assert_eq!(parse_page_size("power"), None);
+    }
+
+    #[test]
+    fn test_parse_thp_status() {
+        let status = parse_thp_status(
+            "always [madvise] never\n",
+            "always defer [defer+madvise] madvise never\n",
+            "1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            status,
+            ThpStatus {
+                enabled: ThpMode::Madvise,
+                defrag: ThpDefrag::DeferMadvise,
+                khugepaged_defrag: true,
+            }
+        );
+        assert!(status.compacts_on_fault());
+        let status =
+            parse_thp_status("[always] madvise never", "always [defer] never", "0").unwrap();
+        assert!(!status.compacts_on_fault());
+        assert!(!status.khugepaged_defrag);
+        assert!(parse_thp_status("always madvise never", "[never]", "0").is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_hugepages() {
+        let pools = hugepages().unwrap();
+        for pool in &pools {
+            assert!(pool.free <= pool.total.saturating_add(pool.surplus));
+            assert!(pool.page_size.is_power_of_two());
+        }
+        // nothing to reserve
+        if let Some(pool) = pools.first() {
+            assert_eq!(
+                reserve_hugepages(pool.node, pool.page_size, 0).unwrap(),
+                pool.free
+            );
+            assert!(matches!(
+                reserve_hugepages(pool.node, 3, 0),
+                Err(CpuAffinityError::InvalidHugepageSize { page_size: 3 })
+            ));
+        }
+
+        if thp_status().is_ok() {
+            let node = crate::numa::cpu_to_node(0).unwrap();
+            let memory = crate::memory::alloc_on_node(4 << 20, node).unwrap();
+            advise_hugepage(memory[1..].as_ptr(), 1 << 20).unwrap();
+            advise_nohugepage(memory.as_ptr(), memory.len()).unwrap();
+        }
+    }
+}
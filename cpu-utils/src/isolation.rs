@@ -0,0 +1,288 @@
+//! Isolating a core for the PoH thread.
+//!
+//! The PoH thread hashes in a tight loop and its rate depends on having a core to itself, running
+//! at full clock and never going idle. [`setup_poh_core`] does the tuning an operator would
+//! otherwise do by hand and reports each step in a [`PohCoreSetup`], since most of them need
+//! privileges the validator may not have.
+
+#[cfg(not(target_os = "linux"))]
+use crate::error::CpuAffinityError;
+use std::fmt;
+#[cfg(target_os = "linux")]
+use {
+    crate::{
+        affinity::SYSTEM_DIR, memlock::lock_all_memory, registry::pin_thread,
+        topology::read_thread_siblings, CpuProfile,
+    },
+    std::{fs, io, path::Path},
+};
+
+/// The outcome of one step of [`setup_poh_core`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SetupStep {
+    Applied,
+    /// The step doesn't apply to this system, e.g. the core has no SMT sibling.
+    Skipped(String),
+    Failed(String),
+}
+
+impl SetupStep {
+    #[cfg(target_os = "linux")]
+    fn from_result(result: Result<(), impl fmt::Display>) -> Self {
+        match result {
+            Ok(()) => Self::Applied,
+            Err(e) => Self::Failed(e.to_string()),
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::Failed(_))
+    }
+}
+
+impl fmt::Display for SetupStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Applied => write!(f, "applied"),
+            Self::Skipped(reason) => write!(f, "skipped, {reason}"),
+            Self::Failed(reason) => write!(f, "failed, {reason}"),
+        }
+    }
+}
+
+/// What [`setup_poh_core`] did to the core.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PohCoreSetup {
+    pub cpu: usize,
+    /// The thread was pinned to the CPU and recorded in the registry of pinned threads, see
+    /// [`pinned_threads`](crate::pinned_threads).
+    pub pinned: SetupStep,
+    /// The SMT siblings of the CPU were taken offline.
+    pub siblings_parked: SetupStep,
+    /// The CPU's frequency governor was set to `performance`.
+    pub governor: SetupStep,
+    /// The CPU was kept out of idle states with a zero resume latency limit.
+    pub cstate_latch: SetupStep,
+    /// The thread's nice value was raised to the highest priority.
+    pub priority: SetupStep,
+    /// The process memory was locked so that the thread never waits on a page fault to swap.
+    pub memory_locked: SetupStep,
+}
+
+impl PohCoreSetup {
+    fn steps(&self) -> [(&'static str, &SetupStep); 6] {
+        [
+            ("pinned", &self.pinned),
+            ("siblings parked", &self.siblings_parked),
+            ("governor", &self.governor),
+            ("c-state latch", &self.cstate_latch),
+            ("priority", &self.priority),
+            ("memory locked", &self.memory_locked),
+        ]
+    }
+
+    /// Returns whether every step that applies to this system was applied.
+    pub fn is_complete(&self) -> bool {
+        self.steps().iter().all(|(_, step)| !step.is_failed())
+    }
+}
+
+impl fmt::Display for PohCoreSetup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "poh core {}:", self.cpu)?;
+        for (name, step) in self.steps() {
+            write!(f, " {name}: {step};")?;
+        }
+        Ok(())
+    }
+}
+
+/// Dedicates `cpu` to the calling thread.
+///
+/// Pins the thread to `cpu` as [`CpuProfile::POH`], takes the SMT siblings of `cpu` offline, sets
+/// its frequency governor to `performance`, keeps it out of idle states, raises the priority of
+/// the thread and locks the memory of the process. Every step is attempted even if the previous
+/// ones failed, except that nothing is changed if the thread can't be pinned.
+///
+/// Offlining CPUs and changing their power settings affect the whole system and outlive the
+/// process, they aren't undone when the thread exits.
+#[cfg(target_os = "linux")]
+pub fn setup_poh_core(cpu: usize) -> PohCoreSetup {
+    if let Err(e) = pin_thread(CpuProfile::POH, [cpu]) {
+        let skipped = || SetupStep::Skipped("the thread isn't pinned".to_string());
+        return PohCoreSetup {
+            cpu,
+            pinned: SetupStep::Failed(e.to_string()),
+            siblings_parked: skipped(),
+            governor: skipped(),
+            cstate_latch: skipped(),
+            priority: skipped(),
+            memory_locked: skipped(),
+        };
+    }
+    let system_dir = Path::new(SYSTEM_DIR);
+    PohCoreSetup {
+        cpu,
+        pinned: SetupStep::Applied,
+        siblings_parked: park_siblings(system_dir, cpu),
+        governor: set_performance_governor(system_dir, cpu),
+        cstate_latch: SetupStep::from_result(latch_cstate(system_dir, cpu)),
+        priority: SetupStep::from_result(raise_priority()),
+        memory_locked: SetupStep::from_result(lock_all_memory()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn setup_poh_core(cpu: usize) -> PohCoreSetup {
+    let skipped = || SetupStep::Skipped(CpuAffinityError::NotSupported.to_string());
+    PohCoreSetup {
+        cpu,
+        pinned: SetupStep::Failed(CpuAffinityError::NotSupported.to_string()),
+        siblings_parked: skipped(),
+        governor: skipped(),
+        cstate_latch: skipped(),
+        priority: skipped(),
+        memory_locked: skipped(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn park_siblings(system_dir: &Path, cpu: usize) -> SetupStep {
+    let siblings = match read_thread_siblings(system_dir, cpu) {
+        Ok(siblings) => siblings,
+        Err(e) => return SetupStep::Failed(e.to_string()),
+    };
+    if siblings.iter().all(|&sibling| sibling == cpu) {
+        return SetupStep::Skipped("no smt siblings".to_string());
+    }
+    for sibling in siblings.into_iter().filter(|&sibling| sibling != cpu) {
+        if let Err(e) = fs::write(system_dir.join(format!("cpu/cpu{sibling}/online")), "0") {
+            return SetupStep::Failed(format!("cpu {sibling}: {e}"));
+        }
+    }
+    SetupStep::Applied
+}
+
+#[cfg(target_os = "linux")]
+fn set_performance_governor(system_dir: &Path, cpu: usize) -> SetupStep {
+    let path = system_dir.join(format!("cpu/cpu{cpu}/cpufreq/scaling_governor"));
+    match fs::read_to_string(&path) {
+        Ok(governor) if governor.trim() == "performance" => SetupStep::Applied,
+        Ok(_) => SetupStep::from_result(fs::write(&path, "performance")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            SetupStep::Skipped("no cpufreq driver".to_string())
+        }
+        Err(e) => SetupStep::Failed(e.to_string()),
+    }
+}
+
+/// Sets the resume latency limit of `cpu` to zero so that cpuidle only polls on it. Unlike
+/// holding `/dev/cpu_dma_latency` open this only affects the one CPU.
+#[cfg(target_os = "linux")]
+fn latch_cstate(system_dir: &Path, cpu: usize) -> io::Result<()> {
+    fs::write(
+        system_dir.join(format!("cpu/cpu{cpu}/power/pm_qos_resume_latency_us")),
+        "n/a",
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn raise_priority() -> io::Result<()> {
+    // safety: gettid has no preconditions
+    let tid = unsafe { libc::gettid() };
+    // safety: setpriority only reads its arguments
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, -20) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use {super::*, crate::max_cpu_id};
+
+    #[test]
+    fn test_setup_poh_core_unpinned() {
+        // the steps change the system, only check that nothing is done without pinning
+        let cpu = max_cpu_id().unwrap().saturating_add(1);
+        let setup = std::thread::spawn(move || setup_poh_core(cpu))
+            .join()
+            .unwrap();
+        assert!(setup.pinned.is_failed());
+        assert!(!setup.is_complete());
+        assert!(setup
+            .steps()
+            .iter()
+            .skip(1)
+            .all(|(_, step)| matches!(step, SetupStep::Skipped(_))));
+        assert!(setup.to_string().starts_with(&format!(
+            "poh core {cpu}: pinned: failed, CPU {cpu} is invalid"
+        )));
+    }
+
+    #[test]
+    fn test_park_siblings() {
+        let system_dir = tempfile::tempdir().unwrap();
+        let cpu_dir = system_dir.path().join("cpu");
+        for (cpu, siblings) in [(2, "2,6\n"), (3, "3\n")] {
+            fs::create_dir_all(cpu_dir.join(format!("cpu{cpu}/topology"))).unwrap();
+            fs::write(
+                cpu_dir.join(format!("cpu{cpu}/topology/thread_siblings_list")),
+                siblings,
+            )
+            .unwrap();
+        }
+        fs::create_dir(cpu_dir.join("cpu6")).unwrap();
+        fs::write(cpu_dir.join("cpu6/online"), "1\n").unwrap();
+
+        assert_eq!(park_siblings(system_dir.path(), 2), SetupStep::Applied);
+        assert_eq!(
+            fs::read_to_string(cpu_dir.join("cpu6/online")).unwrap(),
+            "0"
+        );
+        assert!(matches!(
+            park_siblings(system_dir.path(), 3),
+            SetupStep::Skipped(_)
+        ));
+        assert!(park_siblings(system_dir.path(), 4).is_failed());
+    }
+
+    #[test]
+    fn test_set_performance_governor() {
+        let system_dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            set_performance_governor(system_dir.path(), 2),
+            SetupStep::Skipped(_)
+        ));
+
+        let cpufreq_dir = system_dir.path().join("cpu/cpu2/cpufreq");
+        fs::create_dir_all(&cpufreq_dir).unwrap();
+        fs::write(cpufreq_dir.join("scaling_governor"), "powersave\n").unwrap();
+        assert_eq!(
+            set_performance_governor(system_dir.path(), 2),
+            SetupStep::Applied
+        );
+        assert_eq!(
+            fs::read_to_string(cpufreq_dir.join("scaling_governor")).unwrap(),
+            "performance"
+        );
+        assert_eq!(
+            set_performance_governor(system_dir.path(), 2),
+            SetupStep::Applied
+        );
+    }
+
+    #[test]
+    fn test_latch_cstate() {
+        let system_dir = tempfile::tempdir().unwrap();
+        assert!(latch_cstate(system_dir.path(), 2).is_err());
+
+        let power_dir = system_dir.path().join("cpu/cpu2/power");
+        fs::create_dir_all(&power_dir).unwrap();
+        latch_cstate(system_dir.path(), 2).unwrap();
+        assert_eq!(
+            fs::read_to_string(power_dir.join("pm_qos_resume_latency_us")).unwrap(),
+            "n/a"
+        );
+    }
+}
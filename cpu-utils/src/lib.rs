@@ -50,11 +50,55 @@
 //!
 
 mod affinity;
+mod cache;
+mod config;
+mod epyc;
 mod error;
+mod hint;
+mod hotplug;
+mod hugepages;
+mod isolation;
+mod memlock;
+mod memory;
+mod numa;
+mod placement;
+mod profile;
+mod readiness;
+mod registry;
+mod snapshot;
 mod topology;
 
 pub use {
-    affinity::{cpu_affinity, cpu_count, isolated_cpus, max_cpu_id, set_cpu_affinity},
+    affinity::{
+        cpu_affinity, cpu_count, isolated_cpus, max_cpu_id, numa_distances, online_cpus,
+        parse_cpu_range_list, set_cpu_affinity, LOCAL_NUMA_DISTANCE,
+    },
+    cache::{cache_info, CacheInfo, CacheType},
+    config::CpuConfig,
+    epyc::{epyc_split, Ccd, EpycSplit, NpsMode},
     error::CpuAffinityError,
-    topology::{core_to_cpus_mapping, physical_core_count, set_affinity_physical_cores_only},
+    hint::{resolve_placement, HugepagePreference, NodeSelection, Placement, PlacementHint},
+    hotplug::{HotplugEvent, HotplugWatcher},
+    hugepages::{
+        advise_hugepage, advise_nohugepage, hugepages, reserve_hugepages, thp_status, HugepagePool,
+        ThpDefrag, ThpMode, ThpStatus,
+    },
+    isolation::{setup_poh_core, PohCoreSetup, SetupStep},
+    memlock::{lock_all_memory, lock_memory, memlock_limit, raise_memlock_limit, MemlockLimit},
+    memory::{
+        alloc_on_node, bind_memory_to_node, memory_policy, migrate_thread_memory, numa_node_of,
+        set_memory_policy, MemoryPolicy, MigratedPages, NodeMemory, PageNodes,
+    },
+    numa::{cpu_to_node, node_to_cpus, numa_nodes},
+    placement::{l3_domains, llc_domains, place_workers},
+    profile::{
+        cpu_profile, install_cpu_profile, pin_thread_to_profile, reload_cpu_profile, CpuProfile,
+    },
+    readiness::{realtime_readiness, ReadinessCheck, ReadinessReport, ReadinessStatus},
+    registry::{pin_thread, pinned_threads, PinnedThread, RepinnedThread},
+    snapshot::Topology,
+    topology::{
+        core_of, core_to_cpus_mapping, one_cpu_per_core, package_core_cpus_mapping, package_of,
+        packages, physical_core_count, set_affinity_physical_cores_only, smt_siblings_of, CoreMap,
+    },
 };
@@ -0,0 +1,232 @@
+//! Locking memory.
+//!
+//! Locked memory is never swapped out, so touching it never waits on a page fault, which the AF_XDP
+//! UMEM requires and the heaps of latency sensitive threads want. The kernel caps the memory a
+//! process can lock at `RLIMIT_MEMLOCK`, unless it has `CAP_IPC_LOCK`. [`lock_memory`] and
+//! [`lock_all_memory`] raise the limit as far as the process is allowed to and fail with
+//! [`CpuAffinityError::MemlockLimit`] when that isn't enough.
+
+use crate::error::CpuAffinityError;
+#[cfg(target_os = "linux")]
+use std::{fs, io};
+
+// The bit of CAP_IPC_LOCK in the capability sets.
+#[cfg(target_os = "linux")]
+const CAP_IPC_LOCK: u32 = 14;
+
+/// `RLIMIT_MEMLOCK` in bytes, `None` when unlimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemlockLimit {
+    pub soft: Option<u64>,
+    /// The most the soft limit can be raised to without `CAP_SYS_RESOURCE`.
+    pub hard: Option<u64>,
+}
+
+/// Returns `RLIMIT_MEMLOCK`.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] if the limit can't be read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn memlock_limit() -> Result<MemlockLimit, CpuAffinityError> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // safety: getrlimit only writes to limit
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let bytes = |limit| (limit != libc::RLIM_INFINITY).then_some(limit);
+    Ok(MemlockLimit {
+        soft: bytes(limit.rlim_cur),
+        hard: bytes(limit.rlim_max),
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn memlock_limit() -> Result<MemlockLimit, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Raises `RLIMIT_MEMLOCK` to at least `bytes` and returns the new limit.
+///
+/// The hard limit is raised too if needed, which needs `CAP_SYS_RESOURCE`. Nothing is changed if
+/// the limit is high enough already or the process has `CAP_IPC_LOCK`.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::MemlockLimit`] if the limit can't be raised that far.
+/// Returns [`CpuAffinityError::Io`] if the limit can't be read or set.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn raise_memlock_limit(bytes: u64) -> Result<MemlockLimit, CpuAffinityError> {
+    let limit = memlock_limit()?;
+    if limit.soft.is_none_or(|soft| soft >= bytes) || has_cap_ipc_lock() {
+        return Ok(limit);
+    }
+    let hard = limit
+        .hard
+        .map_or(libc::RLIM_INFINITY, |hard| hard.max(bytes));
+    let new_limit = libc::rlimit {
+        rlim_cur: bytes,
+        rlim_max: hard,
+    };
+    // safety: setrlimit only reads new_limit
+    if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &new_limit) } != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EPERM) => Err(CpuAffinityError::MemlockLimit {
+                requested: bytes,
+                limit: limit.hard.unwrap_or(bytes),
+            }),
+            _ => Err(e.into()),
+        };
+    }
+    memlock_limit()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn raise_memlock_limit(_bytes: u64) -> Result<MemlockLimit, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Locks the pages overlapping the `len` bytes at `ptr` in memory, raising `RLIMIT_MEMLOCK` if
+/// needed.
+///
+/// The pages are allocated now if they weren't yet. They're unlocked when unmapped.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::MemlockLimit`] if the limit is too low and can't be raised.
+/// Returns [`CpuAffinityError::Io`] if the range isn't mapped or memory is short.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn lock_memory(ptr: *const u8, len: usize) -> Result<(), CpuAffinityError> {
+    // Safety: just a libc wrapper
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = (ptr as usize) & !(page_size - 1);
+    let len = (ptr as usize).saturating_add(len).saturating_sub(start);
+    let status = fs::read_to_string("/proc/self/status")?;
+    let locked = status_bytes(&status, "VmLck").unwrap_or(0);
+    raise_memlock_limit(locked.saturating_add(len as u64))?;
+    // safety: mlock doesn't change the contents of the range, the kernel checks it's mapped
+    if unsafe { libc::mlock(start as *const libc::c_void, len) } != 0 {
+        return Err(memlock_error(len as u64));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lock_memory(_ptr: *const u8, _len: usize) -> Result<(), CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Locks all the memory of the process, mapped now or later, raising `RLIMIT_MEMLOCK` to the size
+/// of the mappings if needed.
+///
+/// Later mappings fail once they'd go over the limit, so the limit should leave room for them.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::MemlockLimit`] if the limit is too low and can't be raised.
+/// Returns [`CpuAffinityError::Io`] if memory is short.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn lock_all_memory() -> Result<(), CpuAffinityError> {
+    let status = fs::read_to_string("/proc/self/status")?;
+    let mapped = status_bytes(&status, "VmSize").unwrap_or(0);
+    raise_memlock_limit(mapped)?;
+    // safety: mlockall only reads its arguments
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(memlock_error(mapped));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn lock_all_memory() -> Result<(), CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+// Maps the errno of a failed mlock of `requested` bytes, ENOMEM and EPERM are the limit.
+#[cfg(target_os = "linux")]
+fn memlock_error(requested: u64) -> CpuAffinityError {
+    let e = io::Error::last_os_error();
+    match (e.raw_os_error(), memlock_limit()) {
+        (
+            Some(libc::ENOMEM | libc::EPERM),
+            Ok(MemlockLimit {
+                soft: Some(limit), ..
+            }),
+        ) => CpuAffinityError::MemlockLimit { requested, limit },
+        _ => e.into(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn has_cap_ipc_lock() -> bool {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| effective_caps(&status))
+        .is_some_and(|caps| caps & (1 << CAP_IPC_LOCK) != 0)
+}
+
+// Returns the effective capabilities in /proc/<pid>/status.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn effective_caps(status: &str) -> Option<u64> {
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
+
+// Returns a field of /proc/<pid>/status in bytes, like `VmLck:   4 kB`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn status_bytes(status: &str, field: &str) -> Option<u64> {
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))?;
+    let kb = value
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    kb.checked_mul(1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        let status =
+            "Name:\tagave\nVmSize:\t  123456 kB\nVmLck:\t       8 kB\nCapEff:\t000001fffeffffff\n";
+        assert_eq!(status_bytes(status, "VmSize"), Some(123456 * 1024));
+        assert_eq!(status_bytes(status, "VmLck"), Some(8 * 1024));
+        assert_eq!(status_bytes(status, "VmPin"), None);
+        assert_eq!(status_bytes(status, "Name"), None);
+        let caps = effective_caps(status).unwrap();
+        assert_ne!(caps & (1 << 14), 0);
+        assert_eq!(effective_caps("CapEff:\t0000000000000000\n"), Some(0));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_lock_memory() {
+        let limit = memlock_limit().unwrap();
+        // nothing to raise
+        assert_eq!(raise_memlock_limit(0).unwrap(), limit);
+
+        let memory = vec![1u8; 8192];
+        match lock_memory(memory[1..].as_ptr(), 4096) {
+            Ok(()) => {}
+            // e.g. a limit of 0 without the permission to raise it
+            Err(CpuAffinityError::MemlockLimit { .. }) => assert!(!has_cap_ipc_lock()),
+            Err(e) => panic!("{e}"),
+        }
+    }
+}
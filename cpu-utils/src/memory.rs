@@ -0,0 +1,636 @@
+//! NUMA placement of memory.
+//!
+//! The kernel allocates a page on the node of the thread that touches it first, which for a
+//! buffer allocated at startup, like the XDP UMEM or the packet batches, is rarely the node of the
+//! threads using it later. [`alloc_on_node`] maps memory that stays on a node whichever thread
+//! touches it, and [`bind_memory_to_node`] does the same for an existing mapping.
+//! [`numa_node_of`] tells where the pages of a buffer actually are, and [`migrate_thread_memory`]
+//! moves them after the threads using them were moved to another node.
+
+use {
+    crate::error::CpuAffinityError,
+    std::{
+        collections::BTreeMap,
+        ops::{Deref, DerefMut},
+        ptr::NonNull,
+        slice,
+    },
+};
+#[cfg(target_os = "linux")]
+use {
+    crate::numa::node_cpus,
+    std::{fs, io, ptr},
+};
+
+// Flags of mbind(2) and move_pages(2), which libc doesn't have.
+#[cfg(target_os = "linux")]
+const MPOL_MF_STRICT: libc::c_uint = 1 << 0;
+#[cfg(target_os = "linux")]
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+// MAX_NUMNODES of the kernel with the largest NODES_SHIFT.
+#[cfg(target_os = "linux")]
+const MAX_NODES: usize = 1024;
+
+// The pages queried with one move_pages(2) call.
+#[cfg(target_os = "linux")]
+const PAGES_PER_QUERY: usize = 1024;
+
+/// The NUMA nodes of the pages of a memory region, see [`numa_node_of`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PageNodes {
+    /// The number of pages on each node.
+    pub nodes: BTreeMap<usize, usize>,
+    /// The pages not allocated yet, which go to the node of the memory policy on first touch.
+    pub unallocated: usize,
+}
+
+impl PageNodes {
+    /// Returns the node with the most pages, the lowest one on ties.
+    pub fn dominant_node(&self) -> Option<usize> {
+        self.nodes
+            .iter()
+            .max_by(|(a_node, a_pages), (b_node, b_pages)| {
+                a_pages.cmp(b_pages).then(b_node.cmp(a_node))
+            })
+            .map(|(node, _)| *node)
+    }
+}
+
+/// Where the pages of the new allocations of a thread go.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    /// No policy of its own, the pages go where the system default puts them, the local node.
+    Default,
+    /// Only these nodes, the allocation fails once they're full.
+    Bind(Vec<usize>),
+    /// This node, falling back to the others once it's full.
+    Preferred(usize),
+    /// Round robin over these nodes, page by page.
+    Interleave(Vec<usize>),
+    /// The node of the CPU the thread runs on when the page is allocated.
+    Local,
+}
+
+/// The outcome of [`migrate_thread_memory`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MigratedPages {
+    /// The pages that left the source node.
+    pub migrated: usize,
+    /// The pages that couldn't be moved, e.g. because they're shared or locked by the kernel.
+    pub failed: usize,
+}
+
+/// Anonymous memory bound to a NUMA node, unmapped on drop.
+///
+/// The pages are allocated zeroed on first touch, on the node the memory is bound to.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct NodeMemory {
+    ptr: NonNull<u8>,
+    len: usize,
+    node: usize,
+}
+
+// Safety: the memory is owned, like a Box<[u8]>
+unsafe impl Send for NodeMemory {}
+// Safety: shared access only reads
+unsafe impl Sync for NodeMemory {}
+
+impl NodeMemory {
+    /// Returns the node the memory is bound to.
+    pub fn node(&self) -> usize {
+        self.node
+    }
+
+    /// Returns the node the first page is actually on, `None` if it wasn't touched yet.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CpuAffinityError::Io`] if the kernel can't tell.
+    /// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+    pub fn resident_node(&self) -> Result<Option<usize>, CpuAffinityError> {
+        Ok(numa_node_of(self.ptr.as_ptr(), 1)?.dominant_node())
+    }
+
+    /// Returns the nodes all the pages are on.
+    pub fn page_nodes(&self) -> Result<PageNodes, CpuAffinityError> {
+        numa_node_of(self.ptr.as_ptr(), self.len)
+    }
+}
+
+impl Deref for NodeMemory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // Safety: ptr is a mapping of len bytes owned by self
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for NodeMemory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // Safety: ptr is a mapping of len bytes owned by self
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for NodeMemory {
+    fn drop(&mut self) {
+        // Safety: ptr is a mapping of len bytes owned by self
+        unsafe {
+            libc::munmap(self.ptr.as_ptr().cast(), self.len);
+        }
+    }
+}
+
+/// Maps `size` bytes, rounded up to the page size, bound to `node`.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidNode`] if `node` isn't online.
+/// Returns [`CpuAffinityError::Io`] if the memory can't be mapped or bound.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn alloc_on_node(size: usize, node: usize) -> Result<NodeMemory, CpuAffinityError> {
+    if !node_cpus()?.contains_key(&node) {
+        return Err(CpuAffinityError::InvalidNode { node });
+    }
+    // Safety: just a libc wrapper
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let len = size.div_ceil(page_size).saturating_mul(page_size);
+    // Safety: a new anonymous mapping
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error().into());
+    }
+    let memory = NodeMemory {
+        ptr: NonNull::new(ptr.cast()).expect("mmap doesn't map address 0"),
+        len,
+        node,
+    };
+    // Safety: memory owns the mapping, which is unmapped on error by its drop
+    unsafe { bind_memory_to_node(memory.ptr.as_ptr(), len, node)? };
+    Ok(memory)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn alloc_on_node(_size: usize, _node: usize) -> Result<NodeMemory, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Binds the `len` bytes mapped at `ptr` to `node`, moving the pages already allocated elsewhere.
+///
+/// `ptr` must be page aligned. Pages shared with other processes aren't moved.
+///
+/// # Safety
+///
+/// The range must be a mapping owned by the caller. Binding memory handed out by an allocator
+/// changes the node of whatever else it allocates there.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidNode`] if `node` isn't online.
+/// Returns [`CpuAffinityError::Io`] if the range isn't mapped or some pages couldn't be moved.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub unsafe fn bind_memory_to_node(
+    ptr: *mut u8,
+    len: usize,
+    node: usize,
+) -> Result<(), CpuAffinityError> {
+    let mask = node_mask(&[node], node);
+    // Safety: mask has the number of bits passed, the kernel checks the range
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr,
+            len,
+            libc::MPOL_BIND,
+            mask.as_ptr(),
+            mask_bits(&mask),
+            MPOL_MF_MOVE | MPOL_MF_STRICT,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            // kernels without NUMA support only have node 0
+            Some(libc::ENOSYS) if node == 0 => Ok(()),
+            Some(libc::EINVAL) if !node_cpus()?.contains_key(&node) => {
+                Err(CpuAffinityError::InvalidNode { node })
+            }
+            _ => Err(e.into()),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub unsafe fn bind_memory_to_node(
+    _ptr: *mut u8,
+    _len: usize,
+    _node: usize,
+) -> Result<(), CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the NUMA nodes of the pages of the `len` bytes at `ptr`.
+///
+/// The pages aren't touched, those not allocated yet are counted as such. On kernels without NUMA
+/// support all the pages are reported on node 0.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] if the range isn't mapped.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn numa_node_of(ptr: *const u8, len: usize) -> Result<PageNodes, CpuAffinityError> {
+    // Safety: just a libc wrapper
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    let start = (ptr as usize) & !(page_size - 1);
+    let end = (ptr as usize).saturating_add(len);
+    let pages = (start..end)
+        .step_by(page_size)
+        .map(|page| page as *const libc::c_void)
+        .collect::<Vec<_>>();
+
+    let mut page_nodes = PageNodes::default();
+    let mut status = vec![0 as libc::c_int; PAGES_PER_QUERY];
+    for pages in pages.chunks(PAGES_PER_QUERY) {
+        // Safety: as many statuses as pages, nodes NULL only queries where the pages are
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_move_pages,
+                0,
+                pages.len() as libc::c_ulong,
+                pages.as_ptr(),
+                ptr::null::<libc::c_int>(),
+                status.as_mut_ptr(),
+                0,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::ENOSYS) {
+                return Err(e.into());
+            }
+            status.fill(0);
+        }
+        for status in &status[..pages.len()] {
+            match *status {
+                node if node >= 0 => {
+                    let pages = page_nodes.nodes.entry(node as usize).or_default();
+                    *pages = pages.saturating_add(1);
+                }
+                errno if errno == -libc::ENOENT => {
+                    page_nodes.unallocated = page_nodes.unallocated.saturating_add(1);
+                }
+                errno => return Err(io::Error::from_raw_os_error(-errno).into()),
+            }
+        }
+    }
+    Ok(page_nodes)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn numa_node_of(_ptr: *const u8, _len: usize) -> Result<PageNodes, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Moves the pages of the process on `from_node` to `to_node`, to follow threads repinned there.
+///
+/// Threads share the memory of the process, so this moves the pages of all of them. It's meant
+/// for when most of the threads touching the memory on `from_node`, like the pinned ones of a role,
+/// moved. Pages bound to `from_node` with [`bind_memory_to_node`] stay there.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidNode`] if a node isn't online.
+/// Returns [`CpuAffinityError::Io`] if the pages can't be moved.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn migrate_thread_memory(
+    from_node: usize,
+    to_node: usize,
+) -> Result<MigratedPages, CpuAffinityError> {
+    let nodes = node_cpus()?;
+    for node in [from_node, to_node] {
+        if !nodes.contains_key(&node) {
+            return Err(CpuAffinityError::InvalidNode { node });
+        }
+    }
+    if from_node == to_node {
+        return Ok(MigratedPages::default());
+    }
+
+    let before = process_node_pages()?;
+    let max_node = from_node.max(to_node);
+    let from = node_mask(&[from_node], max_node);
+    let to = node_mask(&[to_node], max_node);
+    // Safety: both masks have the number of bits passed
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_migrate_pages,
+            0,
+            mask_bits(&from),
+            from.as_ptr(),
+            to.as_ptr(),
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let after = process_node_pages()?;
+    let pages_on = |pages: &BTreeMap<usize, usize>| pages.get(&from_node).copied().unwrap_or(0);
+    Ok(MigratedPages {
+        migrated: pages_on(&before).saturating_sub(pages_on(&after)),
+        failed: ret as usize,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn migrate_thread_memory(
+    _from_node: usize,
+    _to_node: usize,
+) -> Result<MigratedPages, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Sets the memory policy of the calling thread, e.g. before allocating large buffers.
+///
+/// The policy applies to the pages allocated by the thread from then on, including those of memory
+/// allocated before but touched first afterwards. Threads spawned by the thread inherit it.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::EmptyNodeList`] if the policy has no node.
+/// Returns [`CpuAffinityError::InvalidNode`] if a node isn't online.
+/// Returns [`CpuAffinityError::Io`] if the kernel rejects the policy.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn set_memory_policy(policy: &MemoryPolicy) -> Result<(), CpuAffinityError> {
+    let (mode, nodes) = match policy {
+        MemoryPolicy::Default => (libc::MPOL_DEFAULT, &[][..]),
+        MemoryPolicy::Bind(nodes) => (libc::MPOL_BIND, &nodes[..]),
+        MemoryPolicy::Preferred(node) => (libc::MPOL_PREFERRED, slice::from_ref(node)),
+        MemoryPolicy::Interleave(nodes) => (libc::MPOL_INTERLEAVE, &nodes[..]),
+        MemoryPolicy::Local => (libc::MPOL_LOCAL, &[][..]),
+    };
+    let online = node_cpus()?;
+    if let Some(node) = nodes.iter().find(|node| !online.contains_key(node)) {
+        return Err(CpuAffinityError::InvalidNode { node: *node });
+    }
+    let mask = match policy {
+        MemoryPolicy::Default | MemoryPolicy::Local => None,
+        _ if nodes.is_empty() => return Err(CpuAffinityError::EmptyNodeList),
+        _ => Some(node_mask(nodes, nodes.iter().copied().max().unwrap_or(0))),
+    };
+    // Safety: mask, if any, has the number of bits passed
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_set_mempolicy,
+            mode,
+            mask.as_ref().map_or(ptr::null(), |mask| mask.as_ptr()),
+            mask.as_deref().map_or(0, mask_bits),
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        // kernels without NUMA support only have node 0, where everything goes anyway
+        if e.raw_os_error() == Some(libc::ENOSYS) && nodes.iter().all(|node| *node == 0) {
+            return Ok(());
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_memory_policy(_policy: &MemoryPolicy) -> Result<(), CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the memory policy of the calling thread.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] if the policy can't be read.
+/// Returns [`CpuAffinityError::ParseError`] if the kernel returns a policy this crate doesn't know.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn memory_policy() -> Result<MemoryPolicy, CpuAffinityError> {
+    let mut mode: libc::c_int = 0;
+    // enough for MAX_NUMNODES, the kernel fails if the mask is shorter than the possible nodes
+    let mut mask = node_mask(&[], MAX_NODES - 1);
+    // Safety: mask has the number of bits passed, the address is only read with MPOL_F_ADDR
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_get_mempolicy,
+            &mut mode,
+            mask.as_mut_ptr(),
+            mask_bits(&mask),
+            ptr::null::<libc::c_void>(),
+            0,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() == Some(libc::ENOSYS) {
+            return Ok(MemoryPolicy::Default);
+        }
+        return Err(e.into());
+    }
+    let bits = libc::c_ulong::BITS as usize;
+    let nodes = (0..MAX_NODES)
+        .filter(|node| mask[node / bits] & (1 << (node % bits)) != 0)
+        .collect::<Vec<_>>();
+    // the mode flags, like MPOL_F_STATIC_NODES, are in the upper bits
+    match mode
+        & !(libc::MPOL_F_STATIC_NODES | libc::MPOL_F_RELATIVE_NODES | libc::MPOL_F_NUMA_BALANCING)
+    {
+        libc::MPOL_DEFAULT => Ok(MemoryPolicy::Default),
+        libc::MPOL_BIND => Ok(MemoryPolicy::Bind(nodes)),
+        // preferred without a node is local
+        libc::MPOL_PREFERRED => Ok(nodes
+            .first()
+            .map_or(MemoryPolicy::Local, |node| MemoryPolicy::Preferred(*node))),
+        libc::MPOL_INTERLEAVE => Ok(MemoryPolicy::Interleave(nodes)),
+        libc::MPOL_LOCAL => Ok(MemoryPolicy::Local),
+        mode => Err(CpuAffinityError::ParseError(format!(
+            "Unknown memory policy mode: {mode}"
+        ))),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn memory_policy() -> Result<MemoryPolicy, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+// Returns the node mask of `nodes` for the syscalls, long enough for `max_node`.
+#[cfg(target_os = "linux")]
+fn node_mask(nodes: &[usize], max_node: usize) -> Vec<libc::c_ulong> {
+    let bits = libc::c_ulong::BITS as usize;
+    let mut mask = vec![0; max_node / bits + 1];
+    for node in nodes {
+        mask[node / bits] |= 1 << (node % bits);
+    }
+    mask
+}
+
+// Returns the maxnode argument of the syscalls for `mask`, the kernel reads one bit less.
+#[cfg(target_os = "linux")]
+fn mask_bits(mask: &[libc::c_ulong]) -> libc::c_ulong {
+    (mask.len() as libc::c_ulong)
+        .saturating_mul(libc::c_ulong::BITS.into())
+        .saturating_add(1)
+}
+
+// Returns the number of pages of the process on each node.
+#[cfg(target_os = "linux")]
+fn process_node_pages() -> Result<BTreeMap<usize, usize>, CpuAffinityError> {
+    Ok(parse_numa_maps(&fs::read_to_string(
+        "/proc/self/numa_maps",
+    )?))
+}
+
+// Sums the `N<node>=<pages>` fields of each mapping in /proc/<pid>/numa_maps.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_numa_maps(numa_maps: &str) -> BTreeMap<usize, usize> {
+    let mut pages = BTreeMap::new();
+    for field in numa_maps.split_whitespace() {
+        let Some((node, count)) = field
+            .strip_prefix('N')
+            .and_then(|field| field.split_once('='))
+        else {
+            continue;
+        };
+        if let (Ok(node), Ok(count)) = (node.parse::<usize>(), count.parse::<usize>()) {
+            let node_pages: &mut usize = pages.entry(node).or_default();
+            *node_pages = node_pages.saturating_add(count);
+        }
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(target_os = "linux")]
+    use crate::numa::{cpu_to_node, numa_nodes};
+
+    #[test]
+    fn test_parse_numa_maps() {
+        let numa_maps = "5625c292a000 default file=/usr/bin/head mapped=2 N0=2 \
+                         kernelpagesize_kB=4\n7f0000000000 bind:1 anon=3 dirty=3 N0=1 N1=2 \
+                         kernelpagesize_kB=4\n7f0000200000 default\n";
+        assert_eq!(parse_numa_maps(numa_maps), BTreeMap::from([(0, 3), (1, 2)]));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_alloc_on_node() {
+        let node = cpu_to_node(0).unwrap();
+        let mut memory = alloc_on_node(5000, node).unwrap();
+        assert_eq!(memory.node(), node);
+        assert_eq!(memory.as_ptr() as usize % 4096, 0);
+        assert!(memory.len() >= 5000);
+        assert_eq!(memory.resident_node().unwrap(), None);
+        memory[0] = 1;
+        assert_eq!(memory.resident_node().unwrap(), Some(node));
+        assert!(memory[1..].iter().all(|byte| *byte == 0));
+
+        let node = numa_nodes().unwrap().last().unwrap().saturating_add(1);
+        assert!(matches!(
+            alloc_on_node(4096, node),
+            Err(CpuAffinityError::InvalidNode { node: n }) if n == node
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_numa_node_of() {
+        let node = cpu_to_node(0).unwrap();
+        let mut memory = alloc_on_node(4 * 4096, node).unwrap();
+        let pages = memory.len() / 4096;
+        memory[4096] = 1;
+        memory[3 * 4096 + 1] = 1;
+        assert_eq!(
+            memory.page_nodes().unwrap(),
+            PageNodes {
+                nodes: BTreeMap::from([(node, 2)]),
+                unallocated: pages - 2,
+            }
+        );
+        // the pages overlapped by the range
+        let page_nodes = numa_node_of(memory[4095..].as_ptr(), 2).unwrap();
+        assert_eq!(page_nodes.nodes, BTreeMap::from([(node, 1)]));
+        assert_eq!(page_nodes.unallocated, 1);
+        assert_eq!(page_nodes.dominant_node(), Some(node));
+
+        let page_nodes = PageNodes {
+            nodes: BTreeMap::from([(0, 3), (1, 5), (2, 5)]),
+            unallocated: 0,
+        };
+        assert_eq!(page_nodes.dominant_node(), Some(1));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_migrate_thread_memory() {
+        let nodes = numa_nodes().unwrap();
+        let node = nodes[0];
+        assert_eq!(
+            migrate_thread_memory(node, node).unwrap(),
+            MigratedPages::default()
+        );
+        if let Some(&other) = nodes.get(1) {
+            assert!(migrate_thread_memory(node, other).is_ok());
+        }
+        let invalid = nodes.last().unwrap().saturating_add(1);
+        assert!(matches!(
+            migrate_thread_memory(node, invalid),
+            Err(CpuAffinityError::InvalidNode { node: n }) if n == invalid
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_memory_policy() {
+        // a thread of its own, the policy is per thread
+        std::thread::spawn(|| {
+            assert_eq!(memory_policy().unwrap(), MemoryPolicy::Default);
+            let node = cpu_to_node(0).unwrap();
+            for policy in [
+                MemoryPolicy::Preferred(node),
+                MemoryPolicy::Bind(vec![node]),
+                MemoryPolicy::Interleave(vec![node]),
+                MemoryPolicy::Local,
+                MemoryPolicy::Default,
+            ] {
+                set_memory_policy(&policy).unwrap();
+                assert_eq!(memory_policy().unwrap(), policy);
+            }
+            assert!(matches!(
+                set_memory_policy(&MemoryPolicy::Bind(vec![])),
+                Err(CpuAffinityError::EmptyNodeList)
+            ));
+            assert!(matches!(
+                set_memory_policy(&MemoryPolicy::Preferred(MAX_NODES)),
+                Err(CpuAffinityError::InvalidNode { .. })
+            ));
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+//! NUMA node topology.
+//!
+//! Memory and PCIe devices are attached to a NUMA node, and a thread running on another node pays
+//! for every access to them. The functions here read the nodes and their CPUs from
+//! `/sys/devices/system/node`, so that threads can be placed on the node of the memory and the NIC
+//! they touch.
+//!
+//! Systems without NUMA support are reported as a single node 0 with all the online CPUs.
+
+#[cfg(target_os = "linux")]
+use crate::affinity::{max_cpu_id, online_cpus, SYSTEM_DIR};
+use {
+    crate::{affinity::parse_cpu_range_list, error::CpuAffinityError},
+    std::{collections::BTreeMap, fs, io, path::Path},
+};
+
+/// Returns the online NUMA nodes, sorted.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the nodes can't be
+/// read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn numa_nodes() -> Result<Vec<usize>, CpuAffinityError> {
+    Ok(node_cpus()?.into_keys().collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn numa_nodes() -> Result<Vec<usize>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the online CPUs of `node`, sorted.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidNode`] if `node` isn't online.
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the nodes can't be
+/// read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn node_to_cpus(node: usize) -> Result<Vec<usize>, CpuAffinityError> {
+    node_cpus()?
+        .remove(&node)
+        .ok_or(CpuAffinityError::InvalidNode { node })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn node_to_cpus(_node: usize) -> Result<Vec<usize>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the NUMA node of `cpu`.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidCpu`] if `cpu` doesn't exist.
+/// Returns [`CpuAffinityError::Io`] if the CPUs can't be read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn cpu_to_node(cpu: usize) -> Result<usize, CpuAffinityError> {
+    let max = max_cpu_id()?;
+    if cpu > max {
+        return Err(CpuAffinityError::InvalidCpu { cpu, max });
+    }
+    let system_dir = Path::new(SYSTEM_DIR);
+    if !fs::exists(system_dir.join("node"))? {
+        return Ok(0);
+    }
+    read_cpu_node(system_dir, cpu).ok_or(CpuAffinityError::InvalidCpu { cpu, max })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn cpu_to_node(_cpu: usize) -> Result<usize, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the online NUMA nodes with their CPUs.
+#[cfg(target_os = "linux")]
+pub(crate) fn node_cpus() -> Result<BTreeMap<usize, Vec<usize>>, CpuAffinityError> {
+    read_node_cpus(Path::new(SYSTEM_DIR), &online_cpus()?)
+}
+
+/// Reads the online NUMA nodes with their CPUs from `system_dir`, usually `/sys/devices/system`.
+///
+/// Without NUMA support, `online` are the CPUs of node 0.
+pub(crate) fn read_node_cpus(
+    system_dir: &Path,
+    online: &[usize],
+) -> Result<BTreeMap<usize, Vec<usize>>, CpuAffinityError> {
+    let node_dir = system_dir.join("node");
+    let nodes = match fs::read_to_string(node_dir.join("online")) {
+        Ok(nodes) => parse_cpu_range_list(nodes.trim())?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(BTreeMap::from([(0, online.to_vec())]));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    nodes
+        .into_iter()
+        .map(|node| {
+            let cpus = fs::read_to_string(node_dir.join(format!("node{node}/cpulist")))?;
+            Ok((node, parse_cpu_range_list(cpus.trim())?))
+        })
+        .collect()
+}
+
+/// Returns the node of `cpu`, `None` on systems without NUMA support.
+///
+/// The directory of a CPU links to the directory of its node.
+#[cfg(target_os = "linux")]
+pub(crate) fn cpu_node(cpu: usize) -> Option<usize> {
+    read_cpu_node(Path::new(SYSTEM_DIR), cpu)
+}
+
+/// Reads the node of `cpu` from `system_dir`, usually `/sys/devices/system`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn read_cpu_node(system_dir: &Path, cpu: usize) -> Option<usize> {
+    fs::read_dir(system_dir.join(format!("cpu/cpu{cpu}")))
+        .ok()?
+        .filter_map(Result::ok)
+        .find_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_node_cpus() {
+        let system_dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            read_node_cpus(system_dir.path(), &[0, 1]).unwrap(),
+            BTreeMap::from([(0, vec![0, 1])])
+        );
+
+        let node_dir = system_dir.path().join("node");
+        for (node, cpus) in [(0, "0-1\n"), (2, "2,3\n")] {
+            fs::create_dir_all(node_dir.join(format!("node{node}"))).unwrap();
+            fs::write(node_dir.join(format!("node{node}/cpulist")), cpus).unwrap();
+        }
+        fs::write(node_dir.join("online"), "0,2\n").unwrap();
+        assert_eq!(
+            read_node_cpus(system_dir.path(), &[0, 1, 2, 3]).unwrap(),
+            BTreeMap::from([(0, vec![0, 1]), (2, vec![2, 3])])
+        );
+    }
+
+    #[test]
+    fn test_read_cpu_node() {
+        let system_dir = tempfile::tempdir().unwrap();
+        let cpu_dir = system_dir.path().join("cpu/cpu3");
+        fs::create_dir_all(cpu_dir.join("topology")).unwrap();
+        assert_eq!(read_cpu_node(system_dir.path(), 3), None);
+        assert_eq!(read_cpu_node(system_dir.path(), 4), None);
+
+        fs::create_dir(cpu_dir.join("node2")).unwrap();
+        assert_eq!(read_cpu_node(system_dir.path(), 3), Some(2));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_numa_nodes() {
+        let nodes = numa_nodes().unwrap();
+        let node = cpu_to_node(0).unwrap();
+        assert!(nodes.contains(&node));
+        assert!(node_to_cpus(node).unwrap().contains(&0));
+        let cpus = nodes
+            .iter()
+            .map(|node| node_to_cpus(*node).unwrap().len())
+            .sum::<usize>();
+        assert!(cpus > 0);
+
+        let node = nodes.last().unwrap().saturating_add(1);
+        assert!(matches!(
+            node_to_cpus(node),
+            Err(CpuAffinityError::InvalidNode { node: n }) if n == node
+        ));
+        let cpu = max_cpu_id().unwrap().saturating_add(1);
+        assert!(matches!(
+            cpu_to_node(cpu),
+            Err(CpuAffinityError::InvalidCpu { .. })
+        ));
+    }
+}
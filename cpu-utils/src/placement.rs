@@ -0,0 +1,253 @@
+//! Placing worker pools by shared L3 cache.
+//!
+//! Workers of a pool like banking or sigverify hand data to each other, which is cheap between
+//! CPUs sharing an L3 cache (a CCX on AMD) and expensive across. [`place_workers`] fills as few L3
+//! domains as possible, prefers domains that don't hold the PoH or network threads, and uses one
+//! CPU per physical core before using SMT siblings.
+
+#[cfg(target_os = "linux")]
+use crate::{
+    affinity::online_cpus, cache::cache_info, profile::cpu_profile, topology::thread_siblings,
+    CpuProfile,
+};
+use {
+    crate::{
+        cache::{shared_cache, CacheInfo},
+        error::CpuAffinityError,
+    },
+    std::collections::{BTreeMap, BTreeSet, HashSet},
+};
+
+/// Returns the groups of online CPUs sharing an L3 cache, sorted by their first CPU.
+///
+/// Within a group the first CPU of every physical core comes before the SMT siblings. Systems that
+/// don't report their caches are a single group.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the topology can't be
+/// read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn l3_domains() -> Result<Vec<Vec<usize>>, CpuAffinityError> {
+    cache_domains(Some(3))?
+        .into_iter()
+        .map(|domain| {
+            // rank each cpu by its position among its siblings, first threads first
+            let mut ranked = Vec::with_capacity(domain.len());
+            for cpu in domain {
+                let siblings = thread_siblings(cpu).unwrap_or_else(|_| vec![cpu]);
+                let rank = siblings.iter().position(|&sibling| sibling == cpu);
+                ranked.push((rank.unwrap_or(0), cpu));
+            }
+            ranked.sort_unstable();
+            Ok(ranked.into_iter().map(|(_, cpu)| cpu).collect())
+        })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn l3_domains() -> Result<Vec<Vec<usize>>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the groups of online CPUs sharing the last level cache, each sorted, sorted by their
+/// first CPU.
+///
+/// The last level cache is the L3 on x86, a CCX on AMD, but the L2 of some ARM and low power
+/// parts, where [`l3_domains`] only finds a single group. Systems that don't report their caches
+/// are a single group.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the topology can't be
+/// read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn llc_domains() -> Result<Vec<Vec<usize>>, CpuAffinityError> {
+    cache_domains(None)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn llc_domains() -> Result<Vec<Vec<usize>>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+// Groups the online cpus by the cache of `level` they share, the last level when `None`.
+#[cfg(target_os = "linux")]
+fn cache_domains(level: Option<u32>) -> Result<Vec<Vec<usize>>, CpuAffinityError> {
+    let online = online_cpus()?;
+    let caches = online
+        .iter()
+        .map(|&cpu| Ok((cpu, cache_info(cpu)?)))
+        .collect::<Result<BTreeMap<_, _>, CpuAffinityError>>()?;
+    Ok(group_by_cache(&online, &caches, level))
+}
+
+/// Groups `online` by the cache of `level` they share, the last level when `None`, sorted by their
+/// first CPU. CPUs without such a cache share it with all of `online`.
+pub(crate) fn group_by_cache(
+    online: &[usize],
+    caches: &BTreeMap<usize, Vec<CacheInfo>>,
+    level: Option<u32>,
+) -> Vec<Vec<usize>> {
+    let online_set = online.iter().copied().collect::<HashSet<_>>();
+    let mut domains = BTreeSet::new();
+    for cpu in online {
+        let cache = caches
+            .get(cpu)
+            .and_then(|caches| shared_cache(caches, level));
+        let domain = match cache {
+            Some(cache) => cache
+                .shared_cpus
+                .iter()
+                .copied()
+                .filter(|cpu| online_set.contains(cpu))
+                .collect::<Vec<_>>(),
+            None => online.to_vec(),
+        };
+        domains.insert(domain);
+    }
+    domains.into_iter().collect()
+}
+
+/// Returns the CPUs for `count` workers, placed by shared L3 cache.
+///
+/// The workers placed in the same L3 domain share the CPUs picked there, so that the scheduler
+/// balances them within the domain: each element of the result is the CPU set to pin one worker
+/// to, and workers next to each other share a set. The CPUs in `exclude`, and those of the
+/// [`CpuProfile::POH`] and [`CpuProfile::NET`] roles of the installed profile, aren't used, nor are
+/// their SMT siblings.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::NotEnoughCpus`] if fewer than `count` CPUs are left.
+/// Returns the errors of [`l3_domains`].
+#[cfg(target_os = "linux")]
+pub fn place_workers(count: usize, exclude: &[usize]) -> Result<Vec<Vec<usize>>, CpuAffinityError> {
+    let profile = cpu_profile();
+    let profile_reserved = profile.iter().flat_map(|profile| {
+        [CpuProfile::POH, CpuProfile::NET]
+            .into_iter()
+            .flat_map(|role| profile.cpus(role).unwrap_or_default())
+    });
+    let mut reserved = HashSet::new();
+    for &cpu in exclude.iter().chain(profile_reserved) {
+        reserved.insert(cpu);
+        reserved.extend(thread_siblings(cpu).unwrap_or_default());
+    }
+    plan_placement(&l3_domains()?, &reserved, count)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn place_workers(
+    _count: usize,
+    _exclude: &[usize],
+) -> Result<Vec<Vec<usize>>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn plan_placement(
+    domains: &[Vec<usize>],
+    reserved: &HashSet<usize>,
+    count: usize,
+) -> Result<Vec<Vec<usize>>, CpuAffinityError> {
+    let mut candidates = domains
+        .iter()
+        .map(|domain| {
+            let shared = domain.iter().any(|cpu| reserved.contains(cpu));
+            let free = domain
+                .iter()
+                .copied()
+                .filter(|cpu| !reserved.contains(cpu))
+                .collect::<Vec<_>>();
+            (shared, free)
+        })
+        .filter(|(_, free)| !free.is_empty())
+        .collect::<Vec<_>>();
+
+    let available = candidates.iter().map(|(_, free)| free.len()).sum::<usize>();
+    if count > available {
+        return Err(CpuAffinityError::NotEnoughCpus {
+            requested: count,
+            available,
+        });
+    }
+
+    // domains without reserved cpus first, then the largest ones so the pool spans fewer domains
+    candidates.sort_by(|(a_shared, a), (b_shared, b)| {
+        a_shared
+            .cmp(b_shared)
+            .then(b.len().cmp(&a.len()))
+            .then(a.cmp(b))
+    });
+
+    let mut workers = Vec::with_capacity(count);
+    for (_, free) in candidates {
+        let remaining = count.saturating_sub(workers.len());
+        if remaining == 0 {
+            break;
+        }
+        let mut cpus = free[..remaining.min(free.len())].to_vec();
+        cpus.sort_unstable();
+        workers.extend(std::iter::repeat_n(cpus, remaining.min(free.len())));
+    }
+    Ok(workers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_placement() {
+        // two 4 core CCXs with SMT, first threads first
+        let domains = vec![
+            vec![0, 1, 2, 3, 8, 9, 10, 11],
+            vec![4, 5, 6, 7, 12, 13, 14, 15],
+        ];
+        let no_reserved = HashSet::new();
+
+        let workers = plan_placement(&domains, &no_reserved, 3).unwrap();
+        assert_eq!(workers, vec![vec![0, 1, 2]; 3]);
+
+        // the poh core and its sibling push the pool to the other ccx
+        let reserved = HashSet::from([0, 8]);
+        let workers = plan_placement(&domains, &reserved, 3).unwrap();
+        assert_eq!(workers, vec![vec![4, 5, 6]; 3]);
+
+        // spills over into the shared ccx once the other one is full
+        let workers = plan_placement(&domains, &reserved, 10).unwrap();
+        assert_eq!(workers[..8], vec![domains[1].clone(); 8]);
+        assert_eq!(workers[8..], vec![vec![1, 2]; 2]);
+
+        assert!(matches!(
+            plan_placement(&domains, &reserved, 15),
+            Err(CpuAffinityError::NotEnoughCpus {
+                requested: 15,
+                available: 14
+            })
+        ));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_l3_domains() {
+        let domains = l3_domains().unwrap();
+        let cpus = domains.iter().flatten().collect::<HashSet<_>>();
+        assert!(!cpus.is_empty());
+        assert_eq!(cpus.len(), domains.iter().map(Vec::len).sum::<usize>());
+
+        let llc_domains = llc_domains().unwrap();
+        let llc_cpus = llc_domains.iter().flatten().collect::<HashSet<_>>();
+        assert_eq!(llc_cpus, cpus);
+        assert_eq!(
+            llc_cpus.len(),
+            llc_domains.iter().map(Vec::len).sum::<usize>()
+        );
+        assert!(llc_domains.iter().all(|domain| domain.is_sorted()));
+        // at least as fine as the L3 domains
+        assert!(llc_domains.len() >= domains.len());
+    }
+}
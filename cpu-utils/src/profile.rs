@@ -0,0 +1,218 @@
+//! Thread pinning profiles.
+//!
+//! A profile maps the roles of the threads an application spawns, like `poh` or `banking`, to the
+//! CPUs they may run on. It's read from a file with one `role = cpus` line per role, where `cpus`
+//! is a CPU range list as accepted by [`parse_cpu_range_list`]:
+//!
+//! ```text
+//! # dedicated core for PoH
+//! poh = 2
+//! sigverify = 4-7
+//! banking = 8-15
+//! net = 16-19,48-51
+//! ```
+//!
+//! The application [installs](install_cpu_profile) the profile once at startup, and each thread
+//! calls [`pin_thread_to_profile`] with its role when it starts. Threads whose role isn't in the
+//! profile keep the affinity they inherited. The profile can be changed while the threads run
+//! with [`reload_cpu_profile`].
+
+#[cfg(target_os = "linux")]
+use crate::registry::repin_role;
+use {
+    crate::{
+        affinity::parse_cpu_range_list,
+        error::CpuAffinityError,
+        registry::{pin_thread, RepinnedThread},
+    },
+    std::{
+        collections::BTreeMap,
+        fs,
+        path::Path,
+        sync::{Arc, RwLock},
+    },
+};
+
+static CPU_PROFILE: RwLock<Option<Arc<CpuProfile>>> = RwLock::new(None);
+
+/// The CPUs each thread role may run on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpuProfile {
+    roles: BTreeMap<String, Vec<usize>>,
+}
+
+impl CpuProfile {
+    /// The PoH tick producer.
+    pub const POH: &'static str = "poh";
+    /// The signature verification threads.
+    pub const SIGVERIFY: &'static str = "sigverify";
+    /// The banking stage threads.
+    pub const BANKING: &'static str = "banking";
+    /// The threads receiving packets from the network.
+    pub const NET: &'static str = "net";
+
+    /// Parses a profile, see the [module documentation](self) for the format.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CpuAffinityError::ParseError`] if a line is malformed or a role is listed twice.
+    /// Returns [`CpuAffinityError::EmptyCpuList`] if a role has no CPUs.
+    pub fn parse(s: &str) -> Result<Self, CpuAffinityError> {
+        let mut profile = Self::default();
+        for line in s.lines() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((role, cpus)) = line.split_once('=') else {
+                return Err(CpuAffinityError::ParseError(format!(
+                    "Expected role = cpus: {line}"
+                )));
+            };
+            profile.add_role(role.trim(), cpus)?;
+        }
+        Ok(profile)
+    }
+
+    /// Adds `role` with the CPUs in the CPU range list `cpus`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CpuAffinityError::ParseError`] if the role is empty or already in the profile,
+    /// or if `cpus` is malformed.
+    /// Returns [`CpuAffinityError::EmptyCpuList`] if `cpus` is empty.
+    pub fn add_role(&mut self, role: &str, cpus: &str) -> Result<(), CpuAffinityError> {
+        if role.is_empty() {
+            return Err(CpuAffinityError::ParseError(format!(
+                "Missing role for cpus {cpus}"
+            )));
+        }
+        if self.roles.contains_key(role) {
+            return Err(CpuAffinityError::ParseError(format!(
+                "Duplicate role: {role}"
+            )));
+        }
+        let cpus = parse_cpu_range_list(cpus)?;
+        if cpus.is_empty() {
+            return Err(CpuAffinityError::EmptyCpuList);
+        }
+        self.roles.insert(role.to_owned(), cpus);
+        Ok(())
+    }
+
+    /// Reads and parses the profile at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CpuAffinityError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Returns the CPUs of `role`, if the profile has it.
+    pub fn cpus(&self, role: &str) -> Option<&[usize]> {
+        self.roles.get(role).map(Vec::as_slice)
+    }
+
+    /// Returns the roles of the profile and their CPUs, sorted by role.
+    pub fn roles(&self) -> impl Iterator<Item = (&str, &[usize])> {
+        self.roles
+            .iter()
+            .map(|(role, cpus)| (role.as_str(), cpus.as_slice()))
+    }
+}
+
+/// Installs the profile the threads spawned from now on consult through
+/// [`pin_thread_to_profile`].
+///
+/// A profile can only be installed once, installing another one returns it back.
+pub fn install_cpu_profile(profile: CpuProfile) -> Result<(), CpuProfile> {
+    let mut installed = CPU_PROFILE.write().unwrap();
+    if installed.is_some() {
+        return Err(profile);
+    }
+    *installed = Some(Arc::new(profile));
+    Ok(())
+}
+
+/// Returns the installed profile.
+pub fn cpu_profile() -> Option<Arc<CpuProfile>> {
+    CPU_PROFILE.read().unwrap().clone()
+}
+
+/// Installs `profile` in place of the installed one, if any, and moves the running threads of its
+/// roles to their new CPUs.
+///
+/// Only the threads pinned to a role of `profile`, with [`pin_thread_to_profile`] or
+/// [`pin_thread`], are moved. Threads of the roles that aren't in `profile` anymore stay where
+/// they are, as do threads pinned to CPUs of their own like the XDP loops, whose CPUs are tied to
+/// the NIC queues they serve. Returns the threads that were moved, or failed to be.
+pub fn reload_cpu_profile(profile: CpuProfile) -> Vec<RepinnedThread> {
+    let profile = Arc::new(profile);
+    // threads starting from now on pin themselves to the new cpus
+    *CPU_PROFILE.write().unwrap() = Some(Arc::clone(&profile));
+    #[cfg(target_os = "linux")]
+    return profile
+        .roles()
+        .flat_map(|(role, cpus)| repin_role(role, cpus))
+        .collect();
+    #[cfg(not(target_os = "linux"))]
+    Vec::new()
+}
+
+/// Pins the calling thread to the CPUs of `role` in the installed profile, see [`pin_thread`].
+///
+/// Returns `false`, leaving the affinity of the thread as is, if no profile is installed or the
+/// profile doesn't have `role`.
+///
+/// # Errors
+///
+/// Returns the errors of [`pin_thread`].
+pub fn pin_thread_to_profile(role: &str) -> Result<bool, CpuAffinityError> {
+    let profile = cpu_profile();
+    let Some(cpus) = profile.as_deref().and_then(|profile| profile.cpus(role)) else {
+        return Ok(false);
+    };
+    pin_thread(role, cpus.iter().copied())?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let profile = CpuProfile::parse(
+            "# pinning\npoh = 2\n\nbanking = 8-10, 12 # the rest is sigverify\nsigverify=4-7\n",
+        )
+        .unwrap();
+        assert_eq!(profile.cpus(CpuProfile::POH), Some(&[2][..]));
+        assert_eq!(profile.cpus(CpuProfile::BANKING), Some(&[8, 9, 10, 12][..]));
+        assert_eq!(profile.cpus(CpuProfile::NET), None);
+        assert_eq!(
+            profile.roles().map(|(role, _)| role).collect::<Vec<_>>(),
+            ["banking", "poh", "sigverify"]
+        );
+
+        assert_eq!(CpuProfile::parse("").unwrap(), CpuProfile::default());
+        assert!(matches!(
+            CpuProfile::parse("poh = 1\npoh = 2"),
+            Err(CpuAffinityError::ParseError(_))
+        ));
+        assert!(matches!(
+            CpuProfile::parse("poh 1"),
+            Err(CpuAffinityError::ParseError(_))
+        ));
+        assert!(matches!(
+            CpuProfile::parse("= 1"),
+            Err(CpuAffinityError::ParseError(_))
+        ));
+        assert!(matches!(
+            CpuProfile::parse("poh = "),
+            Err(CpuAffinityError::EmptyCpuList)
+        ));
+    }
+
+    #[test]
+    fn test_pin_thread_without_profile() {
+        // nothing is installed in tests, so nothing is pinned
+        assert!(!pin_thread_to_profile(CpuProfile::POH).unwrap());
+    }
+}
@@ -0,0 +1,327 @@
+//! Checks of how ready CPUs are for realtime threads.
+//!
+//! A thread pinned to a CPU still shares it with the kernel: the scheduler tick, RCU callbacks,
+//! interrupts, an SMT sibling, and the frequency and idle state drivers. [`realtime_readiness`]
+//! looks at how the kernel is set up for the CPUs a latency sensitive thread is pinned to, like
+//! PoH, and returns a report that can be logged or shown to the operator. Nothing is changed.
+
+use {crate::error::CpuAffinityError, std::fmt};
+#[cfg(target_os = "linux")]
+use {
+    crate::{
+        affinity::{isolated_cpus, online_cpus, parse_cpu_range_list},
+        topology::thread_siblings,
+    },
+    std::{collections::BTreeSet, fs},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReadinessStatus {
+    Pass,
+    /// The thread works but will be interrupted or slowed down at times.
+    Warn,
+    /// The thread can't run on the CPU.
+    Fail,
+}
+
+impl fmt::Display for ReadinessStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        })
+    }
+}
+
+/// The outcome of one of the checks of [`realtime_readiness`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReadinessCheck {
+    pub name: &'static str,
+    pub status: ReadinessStatus,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReadinessReport {
+    pub checks: Vec<ReadinessCheck>,
+}
+
+impl ReadinessReport {
+    /// Returns the worst status of all the checks.
+    pub fn status(&self) -> ReadinessStatus {
+        self.checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(ReadinessStatus::Pass)
+    }
+
+    fn push(&mut self, name: &'static str, status: ReadinessStatus, detail: impl Into<String>) {
+        self.checks.push(ReadinessCheck {
+            name,
+            status,
+            detail: detail.into(),
+        });
+    }
+
+    // Passes if `missing` is empty, warns with `warning` otherwise.
+    #[cfg(target_os = "linux")]
+    fn push_missing(
+        &mut self,
+        name: &'static str,
+        missing: &[usize],
+        pass: impl Into<String>,
+        warning: &str,
+    ) {
+        if missing.is_empty() {
+            self.push(name, ReadinessStatus::Pass, pass);
+        } else {
+            self.push(
+                name,
+                ReadinessStatus::Warn,
+                format!("cpus {missing:?} {warning}"),
+            );
+        }
+    }
+}
+
+impl fmt::Display for ReadinessReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "[{}] {}: {}", check.status, check.name, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether the kernel keeps itself off `cpus`.
+///
+/// The checks are that the CPUs are online, isolated from the scheduler (`isolcpus`), without
+/// scheduler tick (`nohz_full`) or RCU callbacks (`rcu_nocbs`), excluded from the default
+/// interrupt affinity, running the `performance` governor, kept out of idle states, and that
+/// their SMT siblings are offline or isolated too.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::EmptyCpuList`] if `cpus` is empty.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn realtime_readiness(cpus: &[usize]) -> Result<ReadinessReport, CpuAffinityError> {
+    let cpus = cpus.iter().copied().collect::<BTreeSet<_>>();
+    if cpus.is_empty() {
+        return Err(CpuAffinityError::EmptyCpuList);
+    }
+    let mut report = ReadinessReport::default();
+
+    let online = online_cpus()?;
+    let offline = cpus
+        .iter()
+        .copied()
+        .filter(|cpu| !online.contains(cpu))
+        .collect::<Vec<_>>();
+    if offline.is_empty() {
+        report.push("online", ReadinessStatus::Pass, format!("cpus {cpus:?}"));
+    } else {
+        report.push(
+            "online",
+            ReadinessStatus::Fail,
+            format!(
+                "cpus {offline:?} are offline or don't exist (max cpu is {})",
+                online.last().copied().unwrap_or(0)
+            ),
+        );
+    }
+    let cpus = cpus
+        .into_iter()
+        .filter(|cpu| online.contains(cpu))
+        .collect::<BTreeSet<_>>();
+
+    let isolated = isolated_cpus()?;
+    report.push_missing(
+        "isolcpus",
+        &missing(&cpus, &isolated),
+        "isolated from the scheduler",
+        "aren't isolated, other threads can be scheduled on them",
+    );
+
+    let nohz_full = fs::read_to_string("/sys/devices/system/cpu/nohz_full")
+        .ok()
+        .and_then(|cpus| parse_cpu_range_list(cpus.trim()).ok())
+        .unwrap_or_default();
+    report.push_missing(
+        "nohz_full",
+        &missing(&cpus, &nohz_full),
+        "no scheduler tick",
+        "get the scheduler tick",
+    );
+
+    let cmdline = fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    let rcu_nocbs = kernel_param(&cmdline, "rcu_nocbs")
+        .and_then(|cpus| parse_cpu_range_list(cpus).ok())
+        .unwrap_or_default();
+    report.push_missing(
+        "rcu_nocbs",
+        &missing(&cpus, &rcu_nocbs),
+        "rcu callbacks offloaded",
+        "run rcu callbacks",
+    );
+
+    match fs::read_to_string("/proc/irq/default_smp_affinity")
+        .map_err(CpuAffinityError::from)
+        .and_then(|mask| parse_cpu_mask(mask.trim()))
+    {
+        Ok(irq_cpus) => {
+            let targeted = cpus
+                .iter()
+                .copied()
+                .filter(|cpu| irq_cpus.contains(cpu))
+                .collect::<Vec<_>>();
+            report.push_missing(
+                "irq affinity",
+                &targeted,
+                "excluded from the default irq affinity",
+                "get the interrupts of new irqs",
+            );
+        }
+        Err(e) => report.push(
+            "irq affinity",
+            ReadinessStatus::Warn,
+            format!("can't read the default irq affinity: {e}"),
+        ),
+    }
+
+    let slow_governor = cpus
+        .iter()
+        .copied()
+        .filter(|cpu| {
+            fs::read_to_string(format!(
+                "/sys/devices/system/cpu/cpu{cpu}/cpufreq/scaling_governor"
+            ))
+            .is_ok_and(|governor| governor.trim() != "performance")
+        })
+        .collect::<Vec<_>>();
+    report.push_missing(
+        "governor",
+        &slow_governor,
+        "performance governor or no cpufreq driver",
+        "don't run the performance governor",
+    );
+
+    let idling = cpus
+        .iter()
+        .copied()
+        .filter(|cpu| {
+            fs::exists(format!("/sys/devices/system/cpu/cpu{cpu}/cpuidle")).unwrap_or(false)
+                && fs::read_to_string(format!(
+                    "/sys/devices/system/cpu/cpu{cpu}/power/pm_qos_resume_latency_us"
+                ))
+                .is_ok_and(|latency| latency.trim() != "n/a")
+        })
+        .collect::<Vec<_>>();
+    report.push_missing(
+        "c-states",
+        &idling,
+        "kept out of idle states or no cpuidle driver",
+        "can enter idle states, waking up adds latency",
+    );
+
+    let shared = cpus
+        .iter()
+        .copied()
+        .filter(|&cpu| {
+            thread_siblings(cpu).is_ok_and(|siblings| {
+                siblings.into_iter().any(|sibling| {
+                    sibling != cpu && online.contains(&sibling) && !isolated.contains(&sibling)
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+    report.push_missing(
+        "smt",
+        &shared,
+        "no smt sibling running other threads",
+        "share their core with an online sibling that isn't isolated",
+    );
+
+    Ok(report)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn realtime_readiness(_cpus: &[usize]) -> Result<ReadinessReport, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+#[cfg(target_os = "linux")]
+fn missing(cpus: &BTreeSet<usize>, set: &[usize]) -> Vec<usize> {
+    cpus.iter()
+        .copied()
+        .filter(|cpu| !set.contains(cpu))
+        .collect()
+}
+
+// Returns the value of `name=value` on the kernel command line, the last one if repeated.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn kernel_param<'a>(cmdline: &'a str, name: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|param| param.split_once('='))
+        .filter(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+        .next_back()
+}
+
+// Parses a hex CPU mask as in /proc/irq/*/smp_affinity, 32 bit groups separated by commas with
+// the highest CPUs first.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_cpu_mask(mask: &str) -> Result<Vec<usize>, CpuAffinityError> {
+    let mut cpus = Vec::new();
+    for (group, word) in mask.rsplit(',').enumerate() {
+        let word = u32::from_str_radix(word.trim(), 16)
+            .map_err(|_| CpuAffinityError::ParseError(format!("Invalid CPU mask: {mask}")))?;
+        cpus.extend(
+            (0..32)
+                .filter(|bit| word & (1 << bit) != 0)
+                .map(|bit| group.saturating_mul(32).saturating_add(bit)),
+        );
+    }
+    cpus.sort_unstable();
+    Ok(cpus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kernel_config() {
+        let cmdline = "BOOT_IMAGE=/vmlinuz ro isolcpus=2-5 rcu_nocbs=2-3 quiet rcu_nocbs=2-5,8";
+        assert_eq!(kernel_param(cmdline, "rcu_nocbs"), Some("2-5,8"));
+        assert_eq!(kernel_param(cmdline, "nohz_full"), None);
+
+        assert_eq!(parse_cpu_mask("ff").unwrap(), (0..8).collect::<Vec<_>>());
+        assert_eq!(
+            parse_cpu_mask("00000001,00000000,fffffff3").unwrap()[..3],
+            [0, 1, 4]
+        );
+        assert_eq!(*parse_cpu_mask("00000001,00000000,00000000").unwrap(), [64]);
+        assert!(parse_cpu_mask("xyz").is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_realtime_readiness() {
+        let report = realtime_readiness(&[0]).unwrap();
+        assert_eq!(report.checks[0].name, "online");
+        assert_eq!(report.checks[0].status, ReadinessStatus::Pass);
+        assert!(report.to_string().contains("] smt: "));
+
+        let cpu = crate::max_cpu_id().unwrap().saturating_add(1);
+        let report = realtime_readiness(&[0, cpu]).unwrap();
+        assert_eq!(report.status(), ReadinessStatus::Fail);
+        assert!(matches!(
+            realtime_readiness(&[]),
+            Err(CpuAffinityError::EmptyCpuList)
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+//! The threads pinned to CPUs.
+//!
+//! Threads pinned with [`pin_thread`], directly or through
+//! [`pin_thread_to_profile`](crate::pin_thread_to_profile), are recorded with their role and the
+//! CPUs they were pinned to, so that a running process can report where its threads are supposed
+//! to run and whether they still do. See [`pinned_threads`].
+
+use crate::error::CpuAffinityError;
+#[cfg(target_os = "linux")]
+use {
+    crate::{
+        affinity::{set_cpu_affinity, set_thread_cpu_affinity, thread_cpu_affinity},
+        numa::cpu_node,
+    },
+    std::{path::Path, sync::Mutex, thread},
+};
+
+#[cfg(target_os = "linux")]
+struct Registration {
+    tid: libc::pid_t,
+    name: String,
+    role: String,
+    cpus: Vec<usize>,
+}
+
+#[cfg(target_os = "linux")]
+static PINNED_THREADS: Mutex<Vec<Registration>> = Mutex::new(Vec::new());
+
+/// A thread pinned with [`pin_thread`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PinnedThread {
+    pub tid: i32,
+    pub name: String,
+    pub role: String,
+    /// The CPUs the thread was pinned to, sorted.
+    pub cpus: Vec<usize>,
+    /// The CPUs the thread may run on now, sorted.
+    pub affinity: Vec<usize>,
+    /// The NUMA nodes of `cpus`, sorted. Empty on systems without NUMA support.
+    pub numa_nodes: Vec<usize>,
+}
+
+impl PinnedThread {
+    /// Returns whether the affinity of the thread was changed since it was pinned, e.g. with
+    /// `taskset`.
+    pub fn is_violated(&self) -> bool {
+        self.affinity != self.cpus
+    }
+}
+
+/// A thread moved to other CPUs by [`reload_cpu_profile`](crate::reload_cpu_profile).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepinnedThread {
+    pub tid: i32,
+    pub name: String,
+    pub role: String,
+    /// The CPUs the thread was pinned to before.
+    pub from: Vec<usize>,
+    /// The CPUs the thread is pinned to now, unless `error` is set.
+    pub to: Vec<usize>,
+    /// Why the thread couldn't be moved, in which case it stays on `from`.
+    pub error: Option<String>,
+}
+
+/// Pins the calling thread to `cpus` and records it as a thread of `role`.
+///
+/// Pinning a thread again replaces its previous record, and the records of the threads that
+/// exited are dropped.
+///
+/// # Errors
+///
+/// Returns the errors of [`set_cpu_affinity`](crate::set_cpu_affinity), in which case nothing is
+/// recorded.
+#[cfg(target_os = "linux")]
+pub fn pin_thread(
+    role: &str,
+    cpus: impl IntoIterator<Item = usize>,
+) -> Result<(), CpuAffinityError> {
+    let mut cpus = cpus.into_iter().collect::<Vec<_>>();
+    cpus.sort_unstable();
+    cpus.dedup();
+    set_cpu_affinity(cpus.iter().copied())?;
+
+    // safety: gettid has no preconditions
+    let tid = unsafe { libc::gettid() };
+    let mut threads = PINNED_THREADS.lock().unwrap();
+    threads.retain(|thread| thread.tid != tid && is_running(thread.tid));
+    threads.push(Registration {
+        tid,
+        name: thread::current().name().unwrap_or_default().to_owned(),
+        role: role.to_owned(),
+        cpus,
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_thread(
+    _role: &str,
+    _cpus: impl IntoIterator<Item = usize>,
+) -> Result<(), CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the pinned threads that are still running, with their current affinity.
+///
+/// Threads that exited are forgotten.
+#[cfg(target_os = "linux")]
+pub fn pinned_threads() -> Vec<PinnedThread> {
+    let mut pinned = Vec::new();
+    PINNED_THREADS.lock().unwrap().retain(|thread| {
+        if !is_running(thread.tid) {
+            return false;
+        }
+        let Ok(affinity) = thread_cpu_affinity(thread.tid) else {
+            return false;
+        };
+        let mut numa_nodes = thread
+            .cpus
+            .iter()
+            .filter_map(|&cpu| cpu_node(cpu))
+            .collect::<Vec<_>>();
+        numa_nodes.sort_unstable();
+        numa_nodes.dedup();
+        pinned.push(PinnedThread {
+            tid: thread.tid,
+            name: thread.name.clone(),
+            role: thread.role.clone(),
+            cpus: thread.cpus.clone(),
+            affinity,
+            numa_nodes,
+        });
+        true
+    });
+    pinned
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pinned_threads() -> Vec<PinnedThread> {
+    Vec::new()
+}
+
+/// Moves the running threads of `role` that aren't pinned to `cpus` there.
+#[cfg(target_os = "linux")]
+pub(crate) fn repin_role(role: &str, cpus: &[usize]) -> Vec<RepinnedThread> {
+    let mut repinned = Vec::new();
+    for thread in PINNED_THREADS.lock().unwrap().iter_mut() {
+        // an exited thread is forgotten by pinned_threads, and its tid may be someone else's
+        if thread.role != role || thread.cpus == cpus || !is_running(thread.tid) {
+            continue;
+        }
+        let error = set_thread_cpu_affinity(thread.tid, cpus.iter().copied())
+            .err()
+            .map(|e| e.to_string());
+        repinned.push(RepinnedThread {
+            tid: thread.tid,
+            name: thread.name.clone(),
+            role: thread.role.clone(),
+            from: thread.cpus.clone(),
+            to: cpus.to_vec(),
+            error: error.clone(),
+        });
+        if error.is_none() {
+            thread.cpus = cpus.to_vec();
+        }
+    }
+    repinned
+}
+
+// Returns whether `tid` is a thread of this process. The tid of an exited thread can be reused by
+// another process.
+#[cfg(target_os = "linux")]
+fn is_running(tid: libc::pid_t) -> bool {
+    Path::new(&format!("/proc/self/task/{tid}")).exists()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use {super::*, crate::cpu_affinity};
+
+    #[test]
+    fn test_pinned_threads() {
+        let tid = std::thread::Builder::new()
+            .name("solTestPinned".to_string())
+            .spawn(|| {
+                let cpus = cpu_affinity().unwrap();
+                let cpu = cpus[0];
+                pin_thread("test", [cpu, cpu]).unwrap();
+                // safety: gettid has no preconditions
+                let tid = unsafe { libc::gettid() };
+                let thread = pinned_threads()
+                    .into_iter()
+                    .find(|thread| thread.tid == tid)
+                    .unwrap();
+                assert_eq!(thread.name, "solTestPinned");
+                assert_eq!(thread.role, "test");
+                assert_eq!(thread.cpus, [cpu]);
+                assert!(!thread.is_violated());
+                if Path::new("/sys/devices/system/node").exists() {
+                    assert_eq!(thread.numa_nodes.len(), 1);
+                }
+
+                // moving the thread elsewhere is a violation
+                if cpus.len() > 1 {
+                    set_cpu_affinity(cpus.iter().copied()).unwrap();
+                    let thread = pinned_threads()
+                        .into_iter()
+                        .find(|thread| thread.tid == tid)
+                        .unwrap();
+                    assert_eq!(thread.affinity, cpus);
+                    assert!(thread.is_violated());
+                }
+                tid
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        // the exited thread is dropped when the next one is pinned
+        let cpu = cpu_affinity().unwrap()[0];
+        std::thread::spawn(move || pin_thread("test", [cpu]).unwrap())
+            .join()
+            .unwrap();
+        assert!(PINNED_THREADS
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|thread| thread.tid != tid));
+        assert!(pinned_threads().iter().all(|thread| thread.tid != tid));
+    }
+
+    #[test]
+    fn test_repin_role() {
+        let cpus = cpu_affinity().unwrap();
+        let (first, last) = (cpus[0], cpus[cpus.len() - 1]);
+        std::thread::spawn(move || {
+            pin_thread("test-repin", [first]).unwrap();
+            // safety: gettid has no preconditions
+            let tid = unsafe { libc::gettid() };
+            assert!(repin_role("test-repin", &[first]).is_empty());
+            if first == last {
+                return;
+            }
+
+            let repinned = repin_role("test-repin", &[last]);
+            assert_eq!(repinned.len(), 1);
+            assert_eq!(repinned[0].tid, tid);
+            assert_eq!(
+                (repinned[0].from.clone(), repinned[0].to.clone()),
+                (vec![first], vec![last])
+            );
+            assert_eq!(repinned[0].error, None);
+            assert_eq!(cpu_affinity().unwrap(), [last]);
+            let thread = pinned_threads()
+                .into_iter()
+                .find(|thread| thread.tid == tid)
+                .unwrap();
+            assert!(!thread.is_violated());
+        })
+        .join()
+        .unwrap();
+    }
+}
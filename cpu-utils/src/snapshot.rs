@@ -0,0 +1,365 @@
+//! A snapshot of the CPU topology.
+//!
+//! The free functions of this crate read sysfs on every call, which adds up when startup places
+//! dozens of threads. A [`Topology`] reads the CPUs, cores, NUMA nodes and caches once and answers
+//! from memory until [`Topology::refresh`] reads them again, like after CPUs were hotplugged.
+//!
+//! [`Topology::from_sysfs`] reads a copy of the sysfs tree instead, so that the topology of other
+//! machines can be inspected and tested against.
+
+#[cfg(target_os = "linux")]
+use crate::affinity::online_cpus;
+use {
+    crate::{
+        affinity::{parse_cpu_range_list, SYSTEM_DIR},
+        cache::{read_cache_info, CacheInfo},
+        error::CpuAffinityError,
+        numa::read_node_cpus,
+        placement::group_by_cache,
+        topology::{read_package_core_cpus, CoreMap},
+    },
+    std::{
+        collections::BTreeMap,
+        fs,
+        path::{Path, PathBuf},
+    },
+};
+
+/// The CPUs, cores, NUMA nodes and caches of the system, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Topology {
+    system_dir: PathBuf,
+    online: Vec<usize>,
+    isolated: Vec<usize>,
+    cores: CoreMap,
+    nodes: BTreeMap<usize, Vec<usize>>,
+    caches: BTreeMap<usize, Vec<CacheInfo>>,
+}
+
+impl Topology {
+    /// Reads the topology of the system.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the topology can't
+    /// be read.
+    /// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+    #[cfg(target_os = "linux")]
+    pub fn new() -> Result<Self, CpuAffinityError> {
+        Self::read(PathBuf::from(SYSTEM_DIR), online_cpus()?)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new() -> Result<Self, CpuAffinityError> {
+        Err(CpuAffinityError::NotSupported)
+    }
+
+    /// Reads the topology from a copy of `/sys/devices/system` at `system_dir`.
+    ///
+    /// Only the files read are needed: `cpu/online`, `cpu/isolated`, the `topology` and `cache`
+    /// directories of the CPUs and the `node` directory, the last ones being optional like on
+    /// systems that don't report them. Works on any platform.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`CpuAffinityError::Io`] or [`CpuAffinityError::ParseError`] if the files can't be
+    /// read.
+    pub fn from_sysfs(system_dir: impl AsRef<Path>) -> Result<Self, CpuAffinityError> {
+        let system_dir = system_dir.as_ref().to_path_buf();
+        let online = fs::read_to_string(system_dir.join("cpu/online"))?;
+        let online = parse_cpu_range_list(online.trim())?;
+        Self::read(system_dir, online)
+    }
+
+    fn read(system_dir: PathBuf, online: Vec<usize>) -> Result<Self, CpuAffinityError> {
+        let isolated = match fs::read_to_string(system_dir.join("cpu/isolated")) {
+            Ok(isolated) => parse_cpu_range_list(isolated.trim())?,
+            Err(_) => Vec::new(),
+        };
+        let caches = online
+            .iter()
+            .map(|&cpu| Ok((cpu, read_cache_info(&system_dir, cpu)?)))
+            .collect::<Result<_, CpuAffinityError>>()?;
+        Ok(Self {
+            isolated,
+            cores: CoreMap::from_mapping(read_package_core_cpus(
+                &system_dir,
+                online.iter().copied(),
+            )?),
+            nodes: read_node_cpus(&system_dir, &online)?,
+            caches,
+            online,
+            system_dir,
+        })
+    }
+
+    /// Reads the topology again, from where it was read first, leaving it unchanged on error.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`Topology::new`] or [`Topology::from_sysfs`].
+    pub fn refresh(&mut self) -> Result<(), CpuAffinityError> {
+        *self = if self.system_dir == Path::new(SYSTEM_DIR) {
+            Self::new()?
+        } else {
+            Self::from_sysfs(&self.system_dir)?
+        };
+        Ok(())
+    }
+
+    /// Returns the online CPUs, sorted.
+    pub fn online_cpus(&self) -> &[usize] {
+        &self.online
+    }
+
+    /// Returns the highest online CPU.
+    pub fn max_cpu_id(&self) -> usize {
+        self.online.last().copied().unwrap_or(0)
+    }
+
+    /// Returns the CPUs isolated from the scheduler with `isolcpus`, sorted.
+    pub fn isolated_cpus(&self) -> &[usize] {
+        &self.isolated
+    }
+
+    /// Returns the physical cores of the online CPUs.
+    pub fn cores(&self) -> &CoreMap {
+        &self.cores
+    }
+
+    /// Returns the number of physical cores with an online CPU.
+    pub fn physical_core_count(&self) -> usize {
+        self.cores.cores().count()
+    }
+
+    /// Returns the packages, or sockets, with their online CPUs, sorted.
+    pub fn packages(&self) -> BTreeMap<usize, Vec<usize>> {
+        let mut packages: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (package, _, cpus) in self.cores.cores() {
+            packages.entry(package).or_default().extend(cpus);
+        }
+        for cpus in packages.values_mut() {
+            cpus.sort_unstable();
+        }
+        packages
+    }
+
+    /// Returns the online NUMA nodes, sorted.
+    pub fn numa_nodes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.nodes.keys().copied()
+    }
+
+    /// Returns the online CPUs of `node`, sorted, `None` if `node` isn't online.
+    pub fn node_cpus(&self, node: usize) -> Option<&[usize]> {
+        self.nodes.get(&node).map(Vec::as_slice)
+    }
+
+    /// Returns the NUMA node of `cpu`, `None` if it isn't online.
+    pub fn node_of(&self, cpu: usize) -> Option<usize> {
+        self.nodes
+            .iter()
+            .find(|(_, cpus)| cpus.binary_search(&cpu).is_ok())
+            .map(|(node, _)| *node)
+    }
+
+    /// Returns the caches of `cpu`, sorted by level then type, empty if it isn't online.
+    pub fn caches(&self, cpu: usize) -> &[CacheInfo] {
+        self.caches.get(&cpu).map_or(&[], Vec::as_slice)
+    }
+
+    /// Returns the groups of online CPUs sharing the last level cache, like
+    /// [`llc_domains`](crate::llc_domains).
+    pub fn llc_domains(&self) -> Vec<Vec<usize>> {
+        group_by_cache(&self.online, &self.caches, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::cache::CacheType};
+
+    // Writes a sysfs tree of `files` under a new temporary directory.
+    fn sysfs_fixture(name: &str, files: &[(String, String)]) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("agave-cpu-utils-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, format!("{content}\n")).unwrap();
+        }
+        dir
+    }
+
+    fn cache_files(
+        cpu: usize,
+        index: usize,
+        level: u32,
+        kind: &str,
+        size: &str,
+        shared: &str,
+    ) -> Vec<(String, String)> {
+        let dir = format!("cpu/cpu{cpu}/cache/index{index}");
+        [
+            ("level", level.to_string()),
+            ("type", kind.to_string()),
+            ("size", size.to_string()),
+            ("coherency_line_size", "64".to_string()),
+            ("ways_of_associativity", "8".to_string()),
+            ("shared_cpu_list", shared.to_string()),
+        ]
+        .into_iter()
+        .map(|(name, content)| (format!("{dir}/{name}"), content))
+        .collect()
+    }
+
+    #[test]
+    fn test_dual_socket_fixture() {
+        // two sockets of two CCDs of two cores with SMT, in NPS1: cpu 0-7 are the first threads
+        // and 8-15 their siblings, core ids repeat on each socket
+        let mut files = vec![
+            ("cpu/online".to_string(), "0-15".to_string()),
+            ("cpu/isolated".to_string(), "2-3,10-11".to_string()),
+            ("node/online".to_string(), "0-1".to_string()),
+            ("node/node0/cpulist".to_string(), "0-3,8-11".to_string()),
+            ("node/node1/cpulist".to_string(), "4-7,12-15".to_string()),
+        ];
+        for cpu in 0..16 {
+            let thread = cpu % 8;
+            let (package, core, ccd) = (thread / 4, thread % 4, thread / 2);
+            let topology = format!("cpu/cpu{cpu}/topology");
+            files.push((
+                format!("{topology}/physical_package_id"),
+                package.to_string(),
+            ));
+            files.push((format!("{topology}/core_id"), core.to_string()));
+            files.extend(cache_files(
+                cpu,
+                0,
+                1,
+                "Data",
+                "32K",
+                &format!("{thread},{}", thread + 8),
+            ));
+            files.extend(cache_files(
+                cpu,
+                1,
+                1,
+                "Instruction",
+                "32K",
+                &format!("{thread},{}", thread + 8),
+            ));
+            files.extend(cache_files(
+                cpu,
+                2,
+                2,
+                "Unified",
+                "1024K",
+                &format!("{thread},{}", thread + 8),
+            ));
+            let first = ccd * 2;
+            files.extend(cache_files(
+                cpu,
+                3,
+                3,
+                "Unified",
+                "32M",
+                &format!("{first}-{},{}-{}", first + 1, first + 8, first + 9),
+            ));
+        }
+        let dir = sysfs_fixture("dual-socket", &files);
+        let mut topology = Topology::from_sysfs(&dir).unwrap();
+
+        assert_eq!(topology.online_cpus(), (0..16).collect::<Vec<_>>());
+        assert_eq!(topology.max_cpu_id(), 15);
+        assert_eq!(topology.isolated_cpus(), [2, 3, 10, 11]);
+        // the cores of both sockets are told apart
+        assert_eq!(topology.physical_core_count(), 8);
+        assert_eq!(topology.cores().core_of(12), Some((1, 0)));
+        assert_eq!(topology.cores().siblings_of(12), Some(&[4, 12][..]));
+        assert_eq!(
+            topology.packages(),
+            BTreeMap::from([
+                (0, vec![0, 1, 2, 3, 8, 9, 10, 11]),
+                (1, vec![4, 5, 6, 7, 12, 13, 14, 15]),
+            ])
+        );
+        assert_eq!(topology.numa_nodes().collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(topology.node_of(13), Some(1));
+        assert_eq!(topology.node_of(16), None);
+        assert_eq!(
+            topology.llc_domains(),
+            [[0, 1, 8, 9], [2, 3, 10, 11], [4, 5, 12, 13], [6, 7, 14, 15]]
+        );
+        let caches = topology.caches(0);
+        assert_eq!(caches.len(), 4);
+        assert_eq!(caches[1].cache_type, CacheType::Instruction);
+        assert_eq!(caches[3].size, Some(32 << 20));
+        assert_eq!(caches[3].ways, Some(8));
+
+        // cpus 14 and 15 go offline
+        fs::write(dir.join("cpu/online"), "0-13\n").unwrap();
+        fs::remove_dir_all(dir.join("cpu/cpu14")).unwrap();
+        topology.refresh().unwrap();
+        assert_eq!(topology.max_cpu_id(), 13);
+        assert_eq!(topology.llc_domains()[3], [6, 7]);
+        assert_eq!(topology.cores().siblings_of(7), Some(&[7][..]));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_arm_fixture() {
+        // 8 cores without SMT in clusters of 4 sharing an L2, no NUMA and no package id
+        let mut files = vec![("cpu/online".to_string(), "0-7".to_string())];
+        for cpu in 0..8 {
+            let topology = format!("cpu/cpu{cpu}/topology");
+            files.push((format!("{topology}/physical_package_id"), "-1".to_string()));
+            files.push((format!("{topology}/core_id"), cpu.to_string()));
+            files.extend(cache_files(cpu, 0, 1, "Data", "64K", &cpu.to_string()));
+            let first = cpu / 4 * 4;
+            files.extend(cache_files(
+                cpu,
+                1,
+                2,
+                "Unified",
+                "2048K",
+                &format!("{first}-{}", first + 3),
+            ));
+        }
+        let dir = sysfs_fixture("arm", &files);
+        let topology = Topology::from_sysfs(&dir).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(topology.isolated_cpus().is_empty());
+        assert_eq!(topology.physical_core_count(), 8);
+        assert_eq!(topology.packages(), BTreeMap::from([(0, (0..8).collect())]));
+        assert_eq!(topology.numa_nodes().collect::<Vec<_>>(), [0]);
+        assert_eq!(topology.node_cpus(0), Some(&[0, 1, 2, 3, 4, 5, 6, 7][..]));
+        assert_eq!(topology.llc_domains(), [[0, 1, 2, 3], [4, 5, 6, 7]]);
+        assert!(Topology::from_sysfs("/nonexistent").is_err());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_topology() {
+        use crate::{cache_info, isolated_cpus, max_cpu_id};
+
+        let mut topology = Topology::new().unwrap();
+        assert!(topology.online_cpus().contains(&0));
+        assert_eq!(topology.max_cpu_id(), max_cpu_id().unwrap());
+        assert_eq!(topology.isolated_cpus(), isolated_cpus().unwrap());
+        assert_eq!(topology.packages(), crate::packages().unwrap());
+        assert_eq!(topology.llc_domains(), crate::llc_domains().unwrap());
+        assert_eq!(topology.caches(0), cache_info(0).unwrap());
+        let node = topology.node_of(0).unwrap();
+        assert_eq!(node, crate::cpu_to_node(0).unwrap());
+        assert!(topology.node_cpus(node).unwrap().contains(&0));
+        assert!(topology.numa_nodes().any(|n| n == node));
+        assert!(topology.physical_core_count() > 0);
+
+        let snapshot = topology.clone();
+        topology.refresh().unwrap();
+        assert_eq!(topology, snapshot);
+    }
+}
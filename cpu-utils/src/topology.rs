@@ -2,12 +2,13 @@
 
 use {
     crate::{
-        affinity::{cpu_count, max_cpu_id, set_cpu_affinity},
+        affinity::{cpu_count, max_cpu_id, parse_cpu_range_list, set_cpu_affinity, SYSTEM_DIR},
         error::CpuAffinityError,
     },
     std::{
-        collections::{BTreeMap, HashSet},
-        fs,
+        collections::{BTreeMap, HashMap, HashSet},
+        fs, io,
+        path::Path,
     },
 };
 
@@ -133,6 +134,301 @@ pub fn core_to_cpus_mapping() -> Result<BTreeMap<usize, Vec<usize>>, CpuAffinity
     Err(CpuAffinityError::NotSupported)
 }
 
+/// Returns the package, or socket, of `cpu`.
+///
+/// CPUs without topology information are reported on package 0.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidCpu`] if `cpu` doesn't exist.
+/// Returns [`CpuAffinityError::Io`] if the CPU is offline or its package can't be read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn package_of(cpu: usize) -> Result<usize, CpuAffinityError> {
+    Ok(cpu_topology_id(cpu, "physical_package_id")?.unwrap_or(0))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn package_of(_cpu: usize) -> Result<usize, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the physical core of `cpu`, as keyed in [`core_to_cpus_mapping`].
+///
+/// Reads a single sysfs file, use a [`CoreMap`] to look up many CPUs. CPUs without topology
+/// information are their own core.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidCpu`] if `cpu` doesn't exist.
+/// Returns [`CpuAffinityError::Io`] if the CPU is offline or its core can't be read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn core_of(cpu: usize) -> Result<usize, CpuAffinityError> {
+    Ok(cpu_topology_id(cpu, "core_id")?.unwrap_or(cpu))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn core_of(_cpu: usize) -> Result<usize, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// The physical cores of the online CPUs, looked up in constant time both ways.
+///
+/// Cores are identified by their package and core ID, since core IDs repeat across packages.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use agave_cpu_utils::*;
+/// # fn main() -> Result<(), CpuAffinityError> {
+/// let cores = CoreMap::new()?;
+/// // the CPU the thread runs on, from sched_getcpu()
+/// let cpu = 3;
+/// if let Some(siblings) = cores.siblings_of(cpu) {
+///     println!("CPU {cpu} shares its core with CPUs {siblings:?}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoreMap {
+    // the index in cores of each cpu
+    cpu_cores: Vec<Option<usize>>,
+    cores: Vec<(usize, usize, Vec<usize>)>,
+    core_index: HashMap<(usize, usize), usize>,
+}
+
+impl CoreMap {
+    /// Reads the cores of the online CPUs.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of [`package_core_cpus_mapping`].
+    pub fn new() -> Result<Self, CpuAffinityError> {
+        Ok(Self::from_mapping(package_core_cpus_mapping()?))
+    }
+
+    pub(crate) fn from_mapping(cores: Vec<(usize, usize, Vec<usize>)>) -> Self {
+        let max_cpu = cores.iter().flat_map(|(_, _, cpus)| cpus).max();
+        let mut cpu_cores = vec![None; max_cpu.map_or(0, |max| max.saturating_add(1))];
+        let mut core_index = HashMap::with_capacity(cores.len());
+        for (i, (package, core, cpus)) in cores.iter().enumerate() {
+            core_index.insert((*package, *core), i);
+            for &cpu in cpus {
+                cpu_cores[cpu] = Some(i);
+            }
+        }
+        Self {
+            cpu_cores,
+            cores,
+            core_index,
+        }
+    }
+
+    /// Returns the `(package, core)` of `cpu`, `None` if it isn't online.
+    pub fn core_of(&self, cpu: usize) -> Option<(usize, usize)> {
+        let (package, core, _) = &self.cores[(*self.cpu_cores.get(cpu)?)?];
+        Some((*package, *core))
+    }
+
+    /// Returns the CPUs of the core `core` of `package`, sorted.
+    pub fn cpus_of(&self, package: usize, core: usize) -> Option<&[usize]> {
+        let i = self.core_index.get(&(package, core))?;
+        Some(&self.cores[*i].2)
+    }
+
+    /// Returns the CPUs sharing the physical core of `cpu`, including `cpu`, sorted.
+    pub fn siblings_of(&self, cpu: usize) -> Option<&[usize]> {
+        Some(&self.cores[(*self.cpu_cores.get(cpu)?)?].2)
+    }
+
+    /// Returns the CPUs of `cpus` on distinct physical cores, keeping the first CPU of each core
+    /// in the order of `cpus`.
+    ///
+    /// CPUs that aren't online are kept, as their own core.
+    pub fn one_cpu_per_core(&self, cpus: &[usize]) -> Vec<usize> {
+        let mut seen = HashSet::new();
+        cpus.iter()
+            .copied()
+            .filter(|&cpu| match self.core_of(cpu) {
+                Some(core) => seen.insert(Ok(core)),
+                None => seen.insert(Err(cpu)),
+            })
+            .collect()
+    }
+
+    /// Returns the `(package, core, cpus)` of each physical core, sorted by package then core.
+    pub fn cores(&self) -> impl Iterator<Item = (usize, usize, &[usize])> {
+        self.cores
+            .iter()
+            .map(|(package, core, cpus)| (*package, *core, cpus.as_slice()))
+    }
+}
+
+/// Returns the CPUs sharing the physical core of `cpu`, including `cpu`, sorted.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::InvalidCpu`] if `cpu` doesn't exist.
+/// Returns [`CpuAffinityError::Io`] if the CPU is offline or its siblings can't be read.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn smt_siblings_of(cpu: usize) -> Result<Vec<usize>, CpuAffinityError> {
+    let max = max_cpu_id()?;
+    if cpu > max {
+        return Err(CpuAffinityError::InvalidCpu { cpu, max });
+    }
+    thread_siblings(cpu)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn smt_siblings_of(_cpu: usize) -> Result<Vec<usize>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the CPUs of `cpus` on distinct physical cores, keeping the first CPU of each core in
+/// the order of `cpus`.
+///
+/// Takes as many CPUs as wanted from the result to get CPUs that don't share a core. If the
+/// topology can't be read, every CPU is assumed to be its own core and only duplicates are removed.
+///
+/// # Examples
+///
+/// ```no_run
+/// # use agave_cpu_utils::*;
+/// # fn main() -> Result<(), CpuAffinityError> {
+/// // 8 CPUs, no two sharing a core
+/// let cpus = one_cpu_per_core(&cpu_affinity()?);
+/// set_cpu_affinity(cpus.into_iter().take(8))?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn one_cpu_per_core(cpus: &[usize]) -> Vec<usize> {
+    CoreMap::new().unwrap_or_default().one_cpu_per_core(cpus)
+}
+
+/// Returns the packages, or sockets, with their online CPUs, sorted.
+///
+/// Useful on multi-socket systems to keep the threads of the validator on one socket.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] if unable to read topology information.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn packages() -> Result<BTreeMap<usize, Vec<usize>>, CpuAffinityError> {
+    let mut packages: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (package, _, cpus) in package_core_cpus_mapping()? {
+        packages.entry(package).or_default().extend(cpus);
+    }
+    for cpus in packages.values_mut() {
+        cpus.sort_unstable();
+    }
+    Ok(packages)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn packages() -> Result<BTreeMap<usize, Vec<usize>>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Returns the `(package, core, cpus)` of each physical core, sorted by package then core.
+///
+/// Like [`core_to_cpus_mapping`], but core IDs are only unique within a package, so the cores of
+/// multi-socket systems are told apart by their package. Only online CPUs are included.
+///
+/// # Errors
+///
+/// Returns [`CpuAffinityError::Io`] if unable to read topology information.
+/// Returns [`CpuAffinityError::NotSupported`] on non-Linux platforms.
+#[cfg(target_os = "linux")]
+pub fn package_core_cpus_mapping() -> Result<Vec<(usize, usize, Vec<usize>)>, CpuAffinityError> {
+    read_package_core_cpus(Path::new(SYSTEM_DIR), 0..=max_cpu_id()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn package_core_cpus_mapping() -> Result<Vec<(usize, usize, Vec<usize>)>, CpuAffinityError> {
+    Err(CpuAffinityError::NotSupported)
+}
+
+/// Reads the `(package, core, cpus)` of the physical cores of `cpus` from `system_dir`, usually
+/// `/sys/devices/system`, skipping the CPUs that are offline.
+pub(crate) fn read_package_core_cpus(
+    system_dir: &Path,
+    cpus: impl IntoIterator<Item = usize>,
+) -> Result<Vec<(usize, usize, Vec<usize>)>, CpuAffinityError> {
+    let mut mapping: BTreeMap<(usize, usize), Vec<usize>> = BTreeMap::new();
+    for cpu in cpus {
+        let topology_dir = system_dir.join(format!("cpu/cpu{cpu}/topology"));
+        let core = match read_id(&topology_dir.join("core_id")) {
+            Ok(core) => core.unwrap_or(cpu),
+            // offline
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let package = read_id(&topology_dir.join("physical_package_id"))?.unwrap_or(0);
+        mapping.entry((package, core)).or_default().push(cpu);
+    }
+    Ok(mapping
+        .into_iter()
+        .map(|((package, core), cpus)| (package, core, cpus))
+        .collect())
+}
+
+// Reads an ID of `cpu`, checking that it exists and is online.
+#[cfg(target_os = "linux")]
+fn cpu_topology_id(cpu: usize, name: &str) -> Result<Option<usize>, CpuAffinityError> {
+    let max = max_cpu_id()?;
+    if cpu > max {
+        return Err(CpuAffinityError::InvalidCpu { cpu, max });
+    }
+    match read_topology_id(cpu, name) {
+        Ok(id) => Ok(id),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // no topology directory when the CPU is offline
+            if fs::exists(format!("/sys/devices/system/cpu/cpu{cpu}/topology"))? {
+                Ok(None)
+            } else {
+                Err(e.into())
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Reads an ID in the topology directory of `cpu`, `None` if the platform doesn't know it (-1).
+#[cfg(target_os = "linux")]
+fn read_topology_id(cpu: usize, name: &str) -> io::Result<Option<usize>> {
+    read_id(Path::new(&format!(
+        "{SYSTEM_DIR}/cpu/cpu{cpu}/topology/{name}"
+    )))
+}
+
+fn read_id(path: &Path) -> io::Result<Option<usize>> {
+    Ok(fs::read_to_string(path)?.trim().parse().ok())
+}
+
+/// Returns the CPUs sharing the physical core of `cpu`, including `cpu`, sorted.
+#[cfg(target_os = "linux")]
+pub(crate) fn thread_siblings(cpu: usize) -> Result<Vec<usize>, CpuAffinityError> {
+    read_thread_siblings(Path::new(SYSTEM_DIR), cpu)
+}
+
+/// Reads the CPUs sharing the physical core of `cpu` from `system_dir`, usually
+/// `/sys/devices/system`.
+#[cfg(target_os = "linux")]
+pub(crate) fn read_thread_siblings(
+    system_dir: &Path,
+    cpu: usize,
+) -> Result<Vec<usize>, CpuAffinityError> {
+    let siblings =
+        fs::read_to_string(system_dir.join(format!("cpu/cpu{cpu}/topology/thread_siblings_list")))?;
+    let mut siblings = parse_cpu_range_list(siblings.trim())?;
+    siblings.sort_unstable();
+    Ok(siblings)
+}
+
 /// Set CPU affinity using only physical cores (avoiding hyperthreads).
 ///
 /// Pins the thread to the first logical CPU of each specified physical core,
@@ -311,6 +607,83 @@ mod tests {
         ));
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_packages() {
+        let packages = packages().unwrap();
+        assert!(packages.values().any(|cpus| cpus.contains(&0)));
+        for (package, cpus) in &packages {
+            for cpu in cpus {
+                assert_eq!(package_of(*cpu).unwrap(), *package);
+            }
+        }
+        let mapping = package_core_cpus_mapping().unwrap();
+        let cpus = mapping
+            .iter()
+            .flat_map(|(_, _, cpus)| cpus.iter().copied())
+            .collect::<HashSet<_>>();
+        assert_eq!(cpus.len(), packages.values().map(Vec::len).sum::<usize>());
+        // the cores of a package are the cores of the mapping, when core ids are unique
+        if packages.len() == 1 {
+            assert_eq!(mapping.len(), core_to_cpus_mapping().unwrap().len());
+        }
+
+        let cpu = max_cpu_id().unwrap().saturating_add(1);
+        assert!(matches!(
+            package_of(cpu),
+            Err(CpuAffinityError::InvalidCpu { .. })
+        ));
+    }
+
+    #[test]
+    fn test_core_map() {
+        // two packages of two cores with SMT, cpu 3 offline
+        let cores = CoreMap::from_mapping(vec![
+            (0, 0, vec![0, 4]),
+            (0, 1, vec![1, 5]),
+            (1, 0, vec![2, 6]),
+            (1, 1, vec![7]),
+        ]);
+        assert_eq!(cores.core_of(6), Some((1, 0)));
+        assert_eq!(cores.core_of(3), None);
+        assert_eq!(cores.core_of(8), None);
+        assert_eq!(cores.siblings_of(4), Some(&[0, 4][..]));
+        assert_eq!(cores.cpus_of(1, 0), Some(&[2, 6][..]));
+        assert_eq!(cores.cpus_of(2, 0), None);
+        assert_eq!(cores.cores().count(), 4);
+        assert_eq!(
+            cores.one_cpu_per_core(&[4, 0, 1, 3, 5, 6, 3, 7]),
+            [4, 1, 3, 6, 7]
+        );
+        // without topology, duplicates only
+        assert_eq!(CoreMap::default().one_cpu_per_core(&[0, 4, 0]), [0, 4]);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_core_of() {
+        let cores = CoreMap::new().unwrap();
+        let (package, core) = cores.core_of(0).unwrap();
+        assert_eq!(core_of(0).unwrap(), core);
+        assert_eq!(package_of(0).unwrap(), package);
+        assert!(cores.cpus_of(package, core).unwrap().contains(&0));
+        assert_eq!(
+            cores.siblings_of(0).unwrap(),
+            smt_siblings_of(0).unwrap().as_slice()
+        );
+        let cpus = (0..=max_cpu_id().unwrap()).collect::<Vec<_>>();
+        assert_eq!(one_cpu_per_core(&cpus).len(), cores.cores().count());
+        let cpu = max_cpu_id().unwrap().saturating_add(1);
+        assert!(matches!(
+            core_of(cpu),
+            Err(CpuAffinityError::InvalidCpu { .. })
+        ));
+        assert!(matches!(
+            smt_siblings_of(cpu),
+            Err(CpuAffinityError::InvalidCpu { .. })
+        ));
+    }
+
     #[test]
     #[cfg(target_os = "linux")]
     fn test_physical_vs_logical_consistency() {
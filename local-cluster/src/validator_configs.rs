@@ -81,6 +81,7 @@ pub fn safe_clone_config(config: &ValidatorConfig) -> ValidatorConfig {
         delay_leader_block_for_pending_fork: config.delay_leader_block_for_pending_fork,
         use_tpu_client_next: config.use_tpu_client_next,
         retransmit_xdp: config.retransmit_xdp.clone(),
+        tvu_xdp: config.tvu_xdp.clone(),
         repair_handler_type: config.repair_handler_type.clone(),
     }
 }
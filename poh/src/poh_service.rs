@@ -129,22 +129,34 @@ impl PohService {
                 } else {
                     // PoH service runs in a tight loop, generating hashes as fast as possible.
                     // Let's dedicate one of the CPU cores to this thread so that it can gain
-                    // from cache performance.
-                    match agave_cpu_utils::cpu_count() {
-                        Ok(cpu_count) => {
-                            if pinned_cpu_core >= cpu_count {
+                    // from cache performance. A cpu profile with a poh role takes precedence
+                    // over the pinned core.
+                    let pinned_by_profile =
+                        agave_cpu_utils::pin_thread_to_profile(agave_cpu_utils::CpuProfile::POH)
+                            .expect("Failed to set CPU affinity for POH service from the profile");
+                    if !pinned_by_profile {
+                        match agave_cpu_utils::cpu_count() {
+                            Ok(cpu_count) => {
+                                if pinned_cpu_core >= cpu_count {
+                                    panic!(
+                                        "POH service requested CPU {pinned_cpu_core} but only \
+                                         {cpu_count} CPUs available"
+                                    );
+                                }
+                                agave_cpu_utils::pin_thread(
+                                    agave_cpu_utils::CpuProfile::POH,
+                                    [pinned_cpu_core],
+                                )
+                                .expect(
+                                    "Failed to set CPU affinity for POH service. This is critical \
+                                     for performance.",
+                                );
+                            }
+                            Err(e) => {
                                 panic!(
-                                    "POH service requested CPU {pinned_cpu_core} but only \
-                                     {cpu_count} CPUs available"
+                                    "Failed to determine CPU count for POH service affinity: {e:?}"
                                 );
                             }
-                            agave_cpu_utils::set_cpu_affinity([pinned_cpu_core]).expect(
-                                "Failed to set CPU affinity for POH service. This is critical for \
-                                 performance.",
-                            );
-                        }
-                        Err(e) => {
-                            panic!("Failed to determine CPU count for POH service affinity: {e:?}");
                         }
                     }
                     Self::tick_producer(
@@ -257,6 +257,13 @@ fn recv_loop<P: SocketProvider>(
     }
 }
 
+/// Pins the calling receiver thread to the net cpus of the installed cpu profile, if any.
+fn pin_receiver_thread() {
+    if let Err(err) = agave_cpu_utils::pin_thread_to_profile(agave_cpu_utils::CpuProfile::NET) {
+        warn!("Failed to pin receiver thread to the cpu profile: {err}");
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn receiver(
     thread_name: String,
@@ -273,6 +280,7 @@ pub fn receiver(
     Builder::new()
         .name(thread_name)
         .spawn(move || {
+            pin_receiver_thread();
             let mut provider = FixedSocketProvider::new(socket);
             let _ = recv_loop(
                 &mut provider,
@@ -306,6 +314,7 @@ pub fn receiver_atomic(
     Builder::new()
         .name(thread_name)
         .spawn(move || {
+            pin_receiver_thread();
             let mut provider = MultihomedSocketProvider::new(sockets, bind_ip_addrs);
             let _ = recv_loop(
                 &mut provider,
@@ -1,17 +1,29 @@
 #[cfg(target_os = "linux")]
 use {
     agave_xdp::{
+        capture::capture_ring,
         device::{NetworkDevice, QueueId},
         load_xdp_program,
-        tx_loop::tx_loop,
+        metrics::XdpMetrics,
+        rx_filter::RxFlow,
+        rx_loop::{RxService, RxServiceConfig},
+        shaping::{traffic_shaper, TrafficClass},
+        tx_loop::{tx_loop, TxLoopConfig, TxLoopStats},
+        xdp_program_id,
     },
     crossbeam_channel::TryRecvError,
-    std::{sync::Arc, thread::Builder, time::Duration},
+    std::{thread::Builder, time::Duration},
 };
 use {
-    crossbeam_channel::{Sender, TrySendError},
+    crossbeam_channel::{Receiver, Sender, TrySendError},
     solana_ledger::shred,
-    std::{error::Error, net::SocketAddr, thread},
+    solana_perf::packet::PacketBatch,
+    std::{
+        error::Error,
+        net::SocketAddr,
+        sync::{atomic::AtomicBool, Arc},
+        thread,
+    },
 };
 
 #[derive(Clone, Debug)]
@@ -138,6 +150,13 @@ impl XdpRetransmitter {
             caps::drop(None, CapSet::Effective, cap).unwrap();
         }
 
+        // report the loops through the admin interface
+        let metrics = XdpMetrics::global();
+        metrics.add_device(Arc::clone(&dev));
+        if let Some(id) = ebpf.as_ref().and_then(xdp_program_id) {
+            metrics.add_program(dev.name(), id);
+        }
+
         let (senders, receivers) = (0..config.cpus.len())
             .map(|_| crossbeam_channel::bounded(config.rtx_channel_cap))
             .unzip::<_, _, Vec<_>, Vec<_>>();
@@ -175,6 +194,8 @@ impl XdpRetransmitter {
         {
             let dev = Arc::clone(&dev);
             let drop_sender = drop_sender.clone();
+            let stats = Arc::new(TxLoopStats::default());
+            metrics.add_tx(dev.name(), Some(QueueId(i as u64)), Arc::clone(&stats));
             threads.push(
                 Builder::new()
                     .name(format!("solRetransmIO{i:02}"))
@@ -189,7 +210,15 @@ impl XdpRetransmitter {
                             src_port,
                             None,
                             receiver,
+                            None,
                             drop_sender,
+                            TxLoopConfig {
+                                stats: Some(stats),
+                                capture: capture_ring(),
+                                shaping: traffic_shaper()
+                                    .map(|shaper| (shaper, TrafficClass::Turbine)),
+                                ..TxLoopConfig::default()
+                            },
                         )
                     })
                     .unwrap(),
@@ -206,3 +235,54 @@ impl XdpRetransmitter {
         Ok(())
     }
 }
+
+/// Receives the shreds sent to the TVU port through AF_XDP, with one rx loop pinned to each of the
+/// configured CPUs.
+///
+/// Only the TVU port is steered to the rx loops, the rest of the traffic still reaches the kernel.
+/// Packets received on the queues past the number of CPUs reach the kernel too, so the TVU sockets
+/// must still be read.
+pub struct XdpShredReceiver {
+    #[cfg(target_os = "linux")]
+    service: RxService,
+}
+
+impl XdpShredReceiver {
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(
+        _config: XdpConfig,
+        _tvu_port: u16,
+        _exit: Arc<AtomicBool>,
+    ) -> Result<(Self, Receiver<PacketBatch>), Box<dyn Error>> {
+        Err("XDP is only supported on Linux".into())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn new(
+        config: XdpConfig,
+        tvu_port: u16,
+        exit: Arc<AtomicBool>,
+    ) -> Result<(Self, Receiver<PacketBatch>), Box<dyn Error>> {
+        let interface = match config.interface {
+            Some(interface) => interface,
+            None => NetworkDevice::new_from_default_route()?.name().to_string(),
+        };
+        let mut rx_config = RxServiceConfig::new(config.cpus);
+        rx_config.interface = Some(interface.clone());
+        rx_config.zero_copy = config.zero_copy;
+        rx_config.flows = vec![RxFlow::port(tvu_port)];
+        rx_config.rx_loop.capture = capture_ring();
+        let (service, receiver) = RxService::new(rx_config, exit)?;
+
+        // report the loops through the admin interface
+        XdpMetrics::global().add_rx_service(&interface, &service);
+
+        Ok((Self { service }, receiver))
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        #[cfg(target_os = "linux")]
+        self.service.join()?;
+        Ok(())
+    }
+}
@@ -35,7 +35,7 @@ use {
         fmt::{self, Display},
         net::{IpAddr, SocketAddr},
         num::NonZeroUsize,
-        path::{Path, PathBuf},
+        path::{Component, Path, PathBuf},
         sync::{
             atomic::{AtomicBool, Ordering},
             Arc, RwLock,
@@ -58,6 +58,7 @@ pub struct AdminRpcRequestMetadata {
     pub staked_nodes_overrides: Arc<RwLock<HashMap<Pubkey, u64>>>,
     pub post_init: Arc<RwLock<Option<AdminRpcRequestMetadataPostInit>>>,
     pub rpc_to_plugin_manager_sender: Option<Sender<GeyserPluginManagerRequest>>,
+    pub ledger_path: PathBuf,
 }
 
 impl Metadata for AdminRpcRequestMetadata {}
@@ -154,6 +155,119 @@ impl Display for AdminRpcRepairWhitelist {
 impl solana_cli_output::VerboseDisplay for AdminRpcRepairWhitelist {}
 impl solana_cli_output::QuietDisplay for AdminRpcRepairWhitelist {}
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminRpcDatapathStats {
+    pub threads: Vec<AdminRpcPinnedThread>,
+    pub xdp: Vec<AdminRpcXdpStats>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminRpcPinnedThread {
+    pub tid: i32,
+    pub name: String,
+    pub role: String,
+    pub cpus: Vec<usize>,
+    pub affinity: Vec<usize>,
+    /// The NUMA nodes of `cpus`.
+    #[serde(default)]
+    pub numa_nodes: Vec<usize>,
+    /// The affinity of the thread was changed after it was pinned.
+    pub violated: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminRpcXdpStats {
+    pub measurement: String,
+    pub interface: String,
+    pub queue: Option<u64>,
+    /// The CPUs the threads of the loop are pinned to.
+    #[serde(default)]
+    pub cpus: Vec<usize>,
+    #[serde(default)]
+    pub numa_nodes: Vec<usize>,
+    pub values: Vec<(String, u64)>,
+}
+
+impl AdminRpcDatapathStats {
+    fn collect() -> Self {
+        let threads = agave_cpu_utils::pinned_threads()
+            .into_iter()
+            .map(|thread| AdminRpcPinnedThread {
+                violated: thread.is_violated(),
+                tid: thread.tid,
+                name: thread.name,
+                role: thread.role,
+                cpus: thread.cpus,
+                affinity: thread.affinity,
+                numa_nodes: thread.numa_nodes,
+            })
+            .collect();
+        #[cfg(target_os = "linux")]
+        let xdp = agave_xdp::metrics::XdpMetrics::global()
+            .samples()
+            .into_iter()
+            .map(|sample| AdminRpcXdpStats {
+                measurement: sample.measurement.to_string(),
+                interface: sample.interface,
+                queue: sample.queue.map(|queue| queue.0),
+                cpus: sample.cpus,
+                numa_nodes: sample.numa_nodes,
+                values: sample
+                    .values
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value))
+                    .collect(),
+            })
+            .collect();
+        #[cfg(not(target_os = "linux"))]
+        let xdp = Vec::new();
+        Self { threads, xdp }
+    }
+}
+
+impl Display for AdminRpcDatapathStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Pinned threads:")?;
+        for thread in &self.threads {
+            write!(
+                f,
+                "  {} ({}): {} on cpus {:?}",
+                thread.name, thread.tid, thread.role, thread.cpus
+            )?;
+            if !thread.numa_nodes.is_empty() {
+                write!(f, " node {:?}", thread.numa_nodes)?;
+            }
+            if thread.violated {
+                write!(f, ", VIOLATED: may run on cpus {:?}", thread.affinity)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "XDP:")?;
+        for stats in &self.xdp {
+            write!(f, "  {}", stats.measurement)?;
+            if !stats.interface.is_empty() {
+                write!(f, " {}", stats.interface)?;
+            }
+            if let Some(queue) = stats.queue {
+                write!(f, " queue {queue}")?;
+            }
+            if !stats.cpus.is_empty() {
+                write!(f, " on cpus {:?}", stats.cpus)?;
+            }
+            if !stats.numa_nodes.is_empty() {
+                write!(f, " node {:?}", stats.numa_nodes)?;
+            }
+            writeln!(f, ":")?;
+            for (name, value) in &stats.values {
+                writeln!(f, "    {name}: {value}")?;
+            }
+        }
+        Ok(())
+    }
+}
+impl solana_cli_output::VerboseDisplay for AdminRpcDatapathStats {}
+impl solana_cli_output::QuietDisplay for AdminRpcDatapathStats {}
+
 #[rpc]
 pub trait AdminRpc {
     type Metadata;
@@ -228,6 +342,20 @@ pub trait AdminRpc {
     #[rpc(meta, name = "contactInfo")]
     fn contact_info(&self, meta: Self::Metadata) -> Result<AdminRpcContactInfo>;
 
+    /// Return the CPUs the pinned threads run on and the statistics of the XDP datapath
+    #[rpc(name = "datapathStats")]
+    fn datapath_stats(&self) -> Result<AdminRpcDatapathStats>;
+
+    /// Write the frames kept by the XDP capture ring to a new pcap file at `path` in the ledger
+    /// directory, returning the number of frames written
+    #[rpc(meta, name = "xdpCaptureDump")]
+    fn xdp_capture_dump(&self, meta: Self::Metadata, path: String) -> Result<usize>;
+
+    /// Reload the file given with --experimental-tuning-config, moving the pinned threads to the
+    /// cpus of the new profile and applying its pacing, returning what changed
+    #[rpc(name = "reloadTuningConfig")]
+    fn reload_tuning_config(&self) -> Result<String>;
+
     #[rpc(meta, name = "selectActiveInterface")]
     fn select_active_interface(&self, meta: Self::Metadata, interface: IpAddr) -> Result<()>;
 
@@ -564,6 +692,57 @@ impl AdminRpc for AdminRpcImpl {
         meta.with_post_init(|post_init| Ok(post_init.cluster_info.my_contact_info().into()))
     }
 
+    fn datapath_stats(&self) -> Result<AdminRpcDatapathStats> {
+        debug!("datapath_stats request received");
+        Ok(AdminRpcDatapathStats::collect())
+    }
+
+    fn xdp_capture_dump(&self, meta: Self::Metadata, path: String) -> Result<usize> {
+        debug!("xdp_capture_dump request received: {path}");
+        let path = capture_dump_path(&meta.ledger_path, &path)?;
+        #[cfg(target_os = "linux")]
+        {
+            let ring = agave_xdp::capture::capture_ring().ok_or_else(|| {
+                jsonrpc_core::error::Error::invalid_params(
+                    "the XDP capture ring isn't enabled, see --experimental-xdp-capture-frames",
+                )
+            })?;
+            ring.dump(&path).map_err(|err| {
+                jsonrpc_core::error::Error::invalid_params(format!(
+                    "failed to write {}: {err}",
+                    path.display()
+                ))
+            })
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(jsonrpc_core::error::Error::invalid_params(
+            "XDP is only supported on Linux",
+        ))
+    }
+
+    fn reload_tuning_config(&self) -> Result<String> {
+        debug!("reload_tuning_config request received");
+        #[cfg(target_os = "linux")]
+        {
+            let reloader = agave_xdp::reload::tuning_reloader().ok_or_else(|| {
+                jsonrpc_core::error::Error::invalid_params(
+                    "no tuning config to reload, see --experimental-tuning-config",
+                )
+            })?;
+            let report = reloader.reload().map_err(|err| {
+                jsonrpc_core::error::Error::invalid_params(format!(
+                    "failed to reload the tuning config: {err}"
+                ))
+            })?;
+            info!("reloaded the tuning config: {report}");
+            Ok(report.to_string())
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(jsonrpc_core::error::Error::invalid_params(
+            "the tuning config is only supported on Linux",
+        ))
+    }
+
     fn select_active_interface(&self, meta: Self::Metadata, interface: IpAddr) -> Result<()> {
         debug!("select_active_interface received: {interface}");
         meta.with_post_init(|post_init| {
@@ -861,6 +1040,28 @@ fn rpc_account_index_from_account_index(account_index: &AccountIndex) -> RpcAcco
 }
 
 // Start the Admin RPC interface
+// Returns where xdpCaptureDump writes to. The admin RPC only writes to the ledger directory, so
+// `path` must be in it or relative to it, and can't go up.
+fn capture_dump_path(ledger_path: &Path, path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    let relative = if path.is_absolute() {
+        path.strip_prefix(ledger_path).unwrap_or(path)
+    } else {
+        path
+    };
+    if relative.file_name().is_none()
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(jsonrpc_core::error::Error::invalid_params(format!(
+            "{} isn't a file in the ledger directory",
+            path.display()
+        )));
+    }
+    Ok(ledger_path.join(relative))
+}
+
 pub fn run(ledger_path: &Path, metadata: AdminRpcRequestMetadata) {
     let admin_rpc_path = admin_rpc_path(ledger_path);
 
@@ -1088,6 +1289,7 @@ mod tests {
                 }))),
                 staked_nodes_overrides: Arc::new(RwLock::new(HashMap::new())),
                 rpc_to_plugin_manager_sender: None,
+                ledger_path: PathBuf::new(),
             };
             let mut io = MetaIoHandler::default();
             io.extend_with(AdminRpcImpl.to_delegate());
@@ -1508,6 +1710,7 @@ mod tests {
                 post_init: post_init.clone(),
                 staked_nodes_overrides: Arc::new(RwLock::new(HashMap::new())),
                 rpc_to_plugin_manager_sender: None,
+                ledger_path: validator_ledger_path.clone(),
             };
 
             let _validator = Validator::new(
@@ -1613,4 +1816,26 @@ mod tests {
                 .expect("actual response deserialization");
         assert_eq!(actual_parsed_response, expected_parsed_response);
     }
+
+    #[test]
+    fn test_capture_dump_path() {
+        let ledger_path = Path::new("/ledger");
+        for (path, expected) in [
+            ("capture.pcap", Some("/ledger/capture.pcap")),
+            ("captures/q0.pcap", Some("/ledger/captures/q0.pcap")),
+            ("/ledger/capture.pcap", Some("/ledger/capture.pcap")),
+            ("/tmp/capture.pcap", None),
+            ("../capture.pcap", None),
+            ("captures/../../capture.pcap", None),
+            ("/ledger/../capture.pcap", None),
+            ("", None),
+            (".", None),
+        ] {
+            assert_eq!(
+                capture_dump_path(ledger_path, path).ok(),
+                expected.map(PathBuf::from),
+                "{path}"
+            );
+        }
+    }
 }
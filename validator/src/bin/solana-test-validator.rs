@@ -432,6 +432,7 @@ fn main() {
             post_init: admin_service_post_init,
             tower_storage: tower_storage.clone(),
             rpc_to_plugin_manager_sender,
+            ledger_path: ledger_path.clone(),
         },
     );
     let dashboard = if output == Output::Dashboard {
@@ -64,6 +64,8 @@ pub fn app<'a>(version: &'a str, default_args: &'a DefaultArgs) -> App<'a, 'a> {
         .subcommand(commands::exit::command())
         .subcommand(commands::authorized_voter::command())
         .subcommand(commands::contact_info::command())
+        .subcommand(commands::datapath_stats::command())
+        .subcommand(commands::doctor::command())
         .subcommand(commands::repair_shred_from_peer::command())
         .subcommand(commands::repair_whitelist::command())
         .subcommand(
@@ -72,12 +74,14 @@ pub fn app<'a>(version: &'a str, default_args: &'a DefaultArgs) -> App<'a, 'a> {
         .subcommand(commands::monitor::command())
         .subcommand(SubCommand::with_name("run").about("Run the validator"))
         .subcommand(commands::plugin::command())
+        .subcommand(commands::reload_tuning_config::command())
         .subcommand(commands::set_identity::command())
         .subcommand(commands::set_log_filter::command())
         .subcommand(commands::staked_nodes_overrides::command())
         .subcommand(commands::wait_for_restart_window::command())
         .subcommand(commands::set_public_address::command())
-        .subcommand(commands::manage_block_production::command(default_args));
+        .subcommand(commands::manage_block_production::command(default_args))
+        .subcommand(commands::xdp_capture::command());
 
     commands::run::add_args(app, default_args)
         .args(&thread_args(&default_args.thread_args))
@@ -0,0 +1,97 @@
+use {
+    crate::{
+        admin_rpc_service,
+        commands::{FromClapArgMatches, Result},
+    },
+    clap::{App, Arg, ArgMatches, SubCommand},
+    solana_cli_output::OutputFormat,
+    std::path::Path,
+};
+
+const COMMAND: &str = "datapath-stats";
+
+#[derive(Debug, PartialEq)]
+pub struct DatapathStatsArgs {
+    pub output: OutputFormat,
+}
+
+impl FromClapArgMatches for DatapathStatsArgs {
+    fn from_clap_arg_match(matches: &ArgMatches) -> Result<Self> {
+        Ok(DatapathStatsArgs {
+            output: OutputFormat::from_matches(matches, "output", false),
+        })
+    }
+}
+
+pub fn command<'a>() -> App<'a, 'a> {
+    SubCommand::with_name(COMMAND)
+        .about(
+            "Display the CPUs the validator's threads are pinned to and the statistics of its \
+             XDP sockets and programs",
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["json", "json-compact"])
+                .help("Output display mode"),
+        )
+}
+
+pub fn execute(matches: &ArgMatches, ledger_path: &Path) -> Result<()> {
+    let datapath_stats_args = DatapathStatsArgs::from_clap_arg_match(matches)?;
+
+    let admin_client = admin_rpc_service::connect(ledger_path);
+    let datapath_stats = admin_rpc_service::runtime()
+        .block_on(async move { admin_client.await?.datapath_stats().await })?;
+
+    println!(
+        "{}",
+        datapath_stats_args
+            .output
+            .formatted_string(&datapath_stats)
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::commands::tests::{
+            verify_args_struct_by_command, verify_args_struct_by_command_is_error,
+        },
+    };
+
+    #[test]
+    fn verify_args_struct_by_command_datapath_stats_output_json() {
+        verify_args_struct_by_command(
+            command(),
+            vec![COMMAND, "--output", "json"],
+            DatapathStatsArgs {
+                output: OutputFormat::Json,
+            },
+        );
+    }
+
+    #[test]
+    fn verify_args_struct_by_command_datapath_stats_output_default() {
+        verify_args_struct_by_command(
+            command(),
+            vec![COMMAND],
+            DatapathStatsArgs {
+                output: OutputFormat::Display,
+            },
+        );
+    }
+
+    #[test]
+    fn verify_args_struct_by_command_datapath_stats_output_invalid() {
+        verify_args_struct_by_command_is_error::<DatapathStatsArgs>(
+            command(),
+            vec![COMMAND, "--output", "invalid_output_type"],
+        );
+    }
+}
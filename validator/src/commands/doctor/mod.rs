@@ -0,0 +1,336 @@
+use {
+    crate::commands::{FromClapArgMatches, Result},
+    agave_cpu_utils::{realtime_readiness, ReadinessStatus},
+    clap::{value_t, values_t, App, Arg, ArgMatches, SubCommand},
+    solana_clap_utils::input_validators::is_parsable,
+    solana_clock::{DEFAULT_HASHES_PER_TICK, DEFAULT_MS_PER_SLOT, DEFAULT_TICKS_PER_SLOT},
+    solana_entry::poh::compute_hash_time,
+    solana_poh::poh_service::DEFAULT_PINNED_CPU_CORE,
+    std::{fmt, sync::LazyLock, thread},
+};
+
+const COMMAND: &str = "doctor";
+
+static DEFAULT_POH_CORE: LazyLock<String> = LazyLock::new(|| DEFAULT_PINNED_CPU_CORE.to_string());
+// The hashes per tick of mainnet-beta.
+static DEFAULT_HASHES_PER_TICK_ARG: LazyLock<String> =
+    LazyLock::new(|| DEFAULT_HASHES_PER_TICK.to_string());
+// Below this much headroom over the target hash rate, a busy host may fall behind.
+const POH_SPEED_MARGIN_PERCENT: u64 = 10;
+
+#[derive(Debug, PartialEq)]
+pub struct DoctorArgs {
+    pub poh_core: usize,
+    pub realtime_cpus: Vec<usize>,
+    pub hashes_per_tick: u64,
+    pub xdp_interface: Option<String>,
+    pub xdp_ports: Vec<u16>,
+}
+
+impl FromClapArgMatches for DoctorArgs {
+    fn from_clap_arg_match(matches: &ArgMatches) -> Result<Self> {
+        let realtime_cpus = match matches.value_of("realtime_cpus") {
+            Some(cpus) => agave_cpu_utils::parse_cpu_range_list(cpus)
+                .map_err(|e| Box::new(e) as Box<dyn std::error::Error>)?,
+            None => Vec::new(),
+        };
+        Ok(DoctorArgs {
+            poh_core: value_t!(matches, "poh_core", usize)?,
+            realtime_cpus,
+            hashes_per_tick: value_t!(matches, "hashes_per_tick", u64)?,
+            xdp_interface: matches.value_of("xdp_interface").map(str::to_string),
+            xdp_ports: if matches.is_present("xdp_port") {
+                values_t!(matches, "xdp_port", u16)?
+            } else {
+                Vec::new()
+            },
+        })
+    }
+}
+
+pub fn command<'a>() -> App<'a, 'a> {
+    SubCommand::with_name(COMMAND)
+        .about(
+            "Check whether this host is set up to run the validator: the isolation of the CPUs of \
+             the realtime threads, the PoH speed on its core and the XDP environment",
+        )
+        .arg(
+            Arg::with_name("poh_core")
+                .long("poh-core")
+                .takes_value(true)
+                .value_name("CPU")
+                .default_value(&DEFAULT_POH_CORE)
+                .validator(is_parsable::<usize>)
+                .help("CPU the PoH thread will be pinned to"),
+        )
+        .arg(
+            Arg::with_name("realtime_cpus")
+                .long("realtime-cpus")
+                .takes_value(true)
+                .value_name("CPU_LIST")
+                .validator(|cpus| {
+                    agave_cpu_utils::parse_cpu_range_list(&cpus)
+                        .map(|_| ())
+                        .map_err(|e| e.to_string())
+                })
+                .help(
+                    "Other CPUs expected to be isolated besides the PoH core, e.g. those of the \
+                     XDP loops",
+                ),
+        )
+        .arg(
+            Arg::with_name("hashes_per_tick")
+                .long("hashes-per-tick")
+                .takes_value(true)
+                .value_name("COUNT")
+                .default_value(&DEFAULT_HASHES_PER_TICK_ARG)
+                .validator(is_parsable::<u64>)
+                .help("Hashes per tick of the cluster, the default is that of mainnet-beta"),
+        )
+        .arg(
+            Arg::with_name("xdp_interface")
+                .long("xdp-interface")
+                .takes_value(true)
+                .value_name("INTERFACE")
+                .help("Network interface XDP will be enabled on"),
+        )
+        .arg(
+            Arg::with_name("xdp_port")
+                .long("xdp-port")
+                .takes_value(true)
+                .value_name("PORT")
+                .multiple(true)
+                .requires("xdp_interface")
+                .validator(is_parsable::<u16>)
+                .help(
+                    "UDP port the validator receives on, checked against the firewall rules. May \
+                     be specified multiple times",
+                ),
+        )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        })
+    }
+}
+
+impl From<ReadinessStatus> for Status {
+    fn from(status: ReadinessStatus) -> Self {
+        match status {
+            ReadinessStatus::Pass => Self::Pass,
+            ReadinessStatus::Warn => Self::Warn,
+            ReadinessStatus::Fail => Self::Fail,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<agave_xdp::environment::CheckStatus> for Status {
+    fn from(status: agave_xdp::environment::CheckStatus) -> Self {
+        use agave_xdp::environment::CheckStatus;
+        match status {
+            CheckStatus::Pass => Self::Pass,
+            CheckStatus::Warn => Self::Warn,
+            CheckStatus::Fail => Self::Fail,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    checks: Vec<(&'static str, String, Status, String)>,
+}
+
+impl Report {
+    fn push(
+        &mut self,
+        section: &'static str,
+        name: impl Into<String>,
+        status: Status,
+        detail: String,
+    ) {
+        self.checks.push((section, name.into(), status, detail));
+    }
+
+    fn status(&self) -> Status {
+        self.checks
+            .iter()
+            .map(|(_, _, status, _)| *status)
+            .max()
+            .unwrap_or(Status::Pass)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (section, name, status, detail) in &self.checks {
+            writeln!(f, "[{status}] {section} {name}: {detail}")?;
+        }
+        write!(f, "overall: {}", self.status())
+    }
+}
+
+pub fn execute(matches: &ArgMatches) -> Result<()> {
+    let args = DoctorArgs::from_clap_arg_match(matches)?;
+    let mut report = Report::default();
+
+    let mut cpus = args.realtime_cpus.clone();
+    cpus.push(args.poh_core);
+    match realtime_readiness(&cpus) {
+        Ok(readiness) => {
+            for check in readiness.checks {
+                report.push("cpu", check.name, check.status.into(), check.detail);
+            }
+        }
+        Err(e) => report.push("cpu", "readiness", Status::Fail, e.to_string()),
+    }
+
+    let (status, detail) = check_poh_speed(args.poh_core, args.hashes_per_tick);
+    report.push("poh", "speed", status, detail);
+
+    match &args.xdp_interface {
+        #[cfg(target_os = "linux")]
+        Some(interface) => {
+            let environment =
+                agave_xdp::environment::validate_environment(interface, &args.xdp_ports);
+            for check in environment.checks {
+                report.push("xdp", check.name, check.status.into(), check.detail);
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => report.push(
+            "xdp",
+            "environment",
+            Status::Fail,
+            "XDP is only supported on Linux".to_string(),
+        ),
+        None => report.push(
+            "xdp",
+            "environment",
+            Status::Pass,
+            "not checked, no --xdp-interface".to_string(),
+        ),
+    }
+
+    println!("{report}");
+    if report.status() == Status::Fail {
+        return Err(Box::<dyn std::error::Error>::from("some checks failed").into());
+    }
+    Ok(())
+}
+
+// Hashes for a slot on the PoH core, like the speed check at startup but pinned where the PoH
+// thread will run.
+fn check_poh_speed(poh_core: usize, hashes_per_tick: u64) -> (Status, String) {
+    let hashes_per_slot = hashes_per_tick.saturating_mul(DEFAULT_TICKS_PER_SLOT);
+    let target_hashes_per_second = hashes_per_slot.saturating_mul(1_000) / DEFAULT_MS_PER_SLOT;
+    let hash_time = thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                agave_cpu_utils::set_cpu_affinity([poh_core])?;
+                Ok::<_, agave_cpu_utils::CpuAffinityError>(compute_hash_time(hashes_per_slot))
+            })
+            .join()
+            .unwrap()
+    });
+    let hash_time = match hash_time {
+        Ok(hash_time) => hash_time,
+        Err(e) => return (Status::Fail, format!("can't run on cpu {poh_core}: {e}")),
+    };
+    let hashes_per_second = (hashes_per_slot as f64 / hash_time.as_secs_f64()) as u64;
+    let status = poh_speed_status(hashes_per_second, target_hashes_per_second);
+    (
+        status,
+        format!(
+            "{hashes_per_second} hashes per second on cpu {poh_core}, the target is \
+             {target_hashes_per_second}"
+        ),
+    )
+}
+
+fn poh_speed_status(hashes_per_second: u64, target_hashes_per_second: u64) -> Status {
+    if hashes_per_second < target_hashes_per_second {
+        Status::Fail
+    } else if hashes_per_second.saturating_mul(100)
+        < target_hashes_per_second.saturating_mul(100 + POH_SPEED_MARGIN_PERCENT)
+    {
+        Status::Warn
+    } else {
+        Status::Pass
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::commands::tests::{
+            verify_args_struct_by_command, verify_args_struct_by_command_is_error,
+        },
+    };
+
+    #[test]
+    fn verify_args_struct_by_command_doctor_default() {
+        verify_args_struct_by_command(
+            command(),
+            vec![COMMAND],
+            DoctorArgs {
+                poh_core: DEFAULT_PINNED_CPU_CORE,
+                realtime_cpus: vec![],
+                hashes_per_tick: DEFAULT_HASHES_PER_TICK,
+                xdp_interface: None,
+                xdp_ports: vec![],
+            },
+        );
+    }
+
+    #[test]
+    fn verify_args_struct_by_command_doctor_with_args() {
+        verify_args_struct_by_command(
+            command(),
+            vec![
+                COMMAND,
+                "--poh-core",
+                "2",
+                "--realtime-cpus",
+                "4-5,8",
+                "--xdp-interface",
+                "eth0",
+                "--xdp-port",
+                "8001",
+                "--xdp-port",
+                "8002",
+            ],
+            DoctorArgs {
+                poh_core: 2,
+                realtime_cpus: vec![4, 5, 8],
+                hashes_per_tick: DEFAULT_HASHES_PER_TICK,
+                xdp_interface: Some("eth0".to_string()),
+                xdp_ports: vec![8001, 8002],
+            },
+        );
+        verify_args_struct_by_command_is_error::<DoctorArgs>(
+            command(),
+            vec![COMMAND, "--xdp-port", "8001"],
+        );
+    }
+
+    #[test]
+    fn test_poh_speed_status() {
+        assert_eq!(poh_speed_status(9_999_999, 10_000_000), Status::Fail);
+        assert_eq!(poh_speed_status(10_500_000, 10_000_000), Status::Warn);
+        assert_eq!(poh_speed_status(11_000_000, 10_000_000), Status::Pass);
+    }
+}
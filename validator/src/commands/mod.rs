@@ -1,9 +1,12 @@
 pub mod authorized_voter;
 pub mod contact_info;
+pub mod datapath_stats;
+pub mod doctor;
 pub mod exit;
 pub mod manage_block_production;
 pub mod monitor;
 pub mod plugin;
+pub mod reload_tuning_config;
 pub mod repair_shred_from_peer;
 pub mod repair_whitelist;
 pub mod run;
@@ -12,6 +15,7 @@ pub mod set_log_filter;
 pub mod set_public_address;
 pub mod staked_nodes_overrides;
 pub mod wait_for_restart_window;
+pub mod xdp_capture;
 
 use thiserror::Error;
 
@@ -0,0 +1,25 @@
+use {
+    crate::{admin_rpc_service, commands::Result},
+    clap::{App, ArgMatches, SubCommand},
+    std::path::Path,
+};
+
+const COMMAND: &str = "reload-tuning-config";
+
+pub fn command<'a>() -> App<'a, 'a> {
+    SubCommand::with_name(COMMAND)
+        .about("Reload the tuning config file of the validator, like sending it SIGHUP")
+        .after_help(
+            "Note: the validator must be running with --experimental-tuning-config. The pacing \
+             limits and the cpu profile are applied right away, the other settings on restart",
+        )
+}
+
+pub fn execute(_matches: &ArgMatches, ledger_path: &Path) -> Result<()> {
+    let admin_client = admin_rpc_service::connect(ledger_path);
+    let report = admin_rpc_service::runtime()
+        .block_on(async move { admin_client.await?.reload_tuning_config().await })?;
+    println!("{report}");
+
+    Ok(())
+}
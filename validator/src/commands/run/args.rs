@@ -973,6 +973,33 @@ pub fn add_args<'a>(app: App<'a, 'a>, default_args: &'a DefaultArgs) -> App<'a,
             })
             .help("EXPERIMENTAL: Specify which CPU core PoH is pinned to"),
     )
+    .arg(
+        Arg::with_name("cpu_profile")
+            .hidden(hidden_unless_forced())
+            .long("cpu-profile")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with("poh_pinned_cpu_core")
+            .help(
+                "EXPERIMENTAL: File pinning the PoH, sigverify, banking and network receiver \
+                 threads to CPUs, with one `role = cpus` line per role, where role is one of poh, \
+                 sigverify, banking or net and cpus is a list like 2-5,8",
+            ),
+    )
+    .arg(
+        Arg::with_name("tuning_config")
+            .hidden(hidden_unless_forced())
+            .long("experimental-tuning-config")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["cpu_profile", "poh_pinned_cpu_core"])
+            .help(
+                "EXPERIMENTAL: TOML or YAML file with the XDP and CPU tuning of the node. Its cpu \
+                 profile is used like --cpu-profile. The file is reloaded on SIGHUP or with \
+                 `agave-validator reload-tuning-config`, moving the pinned threads to the cpus of \
+                 the new profile and applying its pacing to the XDP retransmit traffic",
+            ),
+    )
     .arg(
         Arg::with_name("poh_hashes_per_batch")
             .hidden(hidden_unless_forced())
@@ -1354,6 +1381,51 @@ pub fn add_args<'a>(app: App<'a, 'a>, default_args: &'a DefaultArgs) -> App<'a,
             .requires("retransmit_xdp_cpu_cores")
             .help("EXPERIMENTAL: Enable XDP zero copy. Requires hardware support"),
     )
+    .arg(
+        Arg::with_name("tvu_xdp_interface")
+            .hidden(hidden_unless_forced())
+            .long("experimental-tvu-xdp-interface")
+            .takes_value(true)
+            .value_name("INTERFACE")
+            .requires("tvu_xdp_cpu_cores")
+            .help(
+                "EXPERIMENTAL: The network interface to receive shreds on with XDP. Must differ \
+                 from the XDP retransmit interface",
+            ),
+    )
+    .arg(
+        Arg::with_name("tvu_xdp_cpu_cores")
+            .hidden(hidden_unless_forced())
+            .long("experimental-tvu-xdp-cpu-cores")
+            .takes_value(true)
+            .value_name("CPU_LIST")
+            .validator(|value| validate_cpu_ranges(value, "--experimental-tvu-xdp-cpu-cores"))
+            .help(
+                "EXPERIMENTAL: Receive the shreds sent to the TVU port with XDP, one NIC queue \
+                 per CPU core",
+            ),
+    )
+    .arg(
+        Arg::with_name("tvu_xdp_zero_copy")
+            .hidden(hidden_unless_forced())
+            .long("experimental-tvu-xdp-zero-copy")
+            .takes_value(false)
+            .requires("tvu_xdp_cpu_cores")
+            .help("EXPERIMENTAL: Enable XDP zero copy for TVU. Requires hardware support"),
+    )
+    .arg(
+        Arg::with_name("xdp_capture_frames")
+            .hidden(hidden_unless_forced())
+            .long("experimental-xdp-capture-frames")
+            .takes_value(true)
+            .value_name("FRAMES")
+            .validator(is_parsable::<usize>)
+            .help(
+                "EXPERIMENTAL: Keep the headers of the last FRAMES frames sent and received by \
+                 each XDP queue in memory, to be written to a pcap file with `agave-validator \
+                 xdp-capture`",
+            ),
+    )
     .arg(
         Arg::with_name("use_connection_cache")
             .long("use-connection-cache")
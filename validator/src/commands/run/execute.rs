@@ -492,6 +492,37 @@ pub fn execute(
             xdp_zero_copy,
         )
     });
+    let tvu_xdp = matches.value_of("tvu_xdp_cpu_cores").map(|cpus| {
+        XdpConfig::new(
+            matches.value_of("tvu_xdp_interface"),
+            parse_cpu_ranges(cpus).unwrap(),
+            matches.is_present("tvu_xdp_zero_copy"),
+        )
+    });
+    // both bind the queues from 0 and attach their own program, they can't share an interface
+    #[cfg(target_os = "linux")]
+    if let (Some(retransmit_xdp), Some(tvu_xdp)) = (&retransmit_xdp, &tvu_xdp) {
+        let interface = |config: &XdpConfig| match &config.interface {
+            Some(interface) => Ok(interface.clone()),
+            None => agave_xdp::device::NetworkDevice::new_from_default_route()
+                .map(|dev| dev.name().to_string())
+                .map_err(|err| format!("unable to find the default route interface: {err}")),
+        };
+        let interface = interface(retransmit_xdp)?;
+        if interface == interface(tvu_xdp)? {
+            return Err(format!(
+                "--experimental-retransmit-xdp-cpu-cores and --experimental-tvu-xdp-cpu-cores \
+                 can't both use interface {interface}, set --experimental-tvu-xdp-interface to \
+                 another one"
+            )
+            .into());
+        }
+    }
+    #[cfg(target_os = "linux")]
+    if let Ok(frames) = value_t!(matches, "xdp_capture_frames", usize) {
+        use agave_xdp::capture::{install_capture_ring, CaptureRing, DEFAULT_CAPTURE_SNAPLEN};
+        install_capture_ring(CaptureRing::new(frames, DEFAULT_CAPTURE_SNAPLEN));
+    }
 
     let account_paths: Vec<PathBuf> =
         if let Ok(account_paths) = values_t!(matches, "account_paths", String) {
@@ -616,6 +647,7 @@ pub fn execute(
         wen_restart_coordinator: value_t!(matches, "wen_restart_coordinator", Pubkey).ok(),
         turbine_disabled: Arc::<AtomicBool>::default(),
         retransmit_xdp,
+        tvu_xdp,
         broadcast_stage_type: BroadcastStageType::Standard,
         use_tpu_client_next: !matches.is_present("use_connection_cache"),
         block_verification_method: value_t_or_exit!(
@@ -653,16 +685,55 @@ pub fn execute(
         .into(),
     };
 
-    let reserved = validator_config
-        .retransmit_xdp
-        .as_ref()
-        .map(|xdp| xdp.cpus.clone())
-        .unwrap_or_default()
-        .iter()
-        .cloned()
+    if let Some(path) = matches.value_of("cpu_profile") {
+        let profile = agave_cpu_utils::CpuProfile::load(path)
+            .map_err(|err| format!("failed to load cpu profile {path}: {err}"))?;
+        for (role, cpus) in profile.roles() {
+            info!("cpu profile: pinning {role} threads to cpus {cpus:?}");
+        }
+        // threads are spawned by Validator::new below, so nothing can have installed one yet
+        agave_cpu_utils::install_cpu_profile(profile).expect("cpu profile is installed once");
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(path) = matches.value_of("tuning_config") {
+        use agave_xdp::{
+            reload::{install_tuning_reloader, TuningReloader},
+            shaping::{install_traffic_shaper, TrafficShaper},
+        };
+        // the xdp retransmit loops spawned by Validator::new are limited by the shaper, so the
+        // pacing can be reloaded along with the rest
+        let shaper = TrafficShaper::new();
+        install_traffic_shaper(shaper.clone());
+        let reloader = TuningReloader::new(path, Some(shaper))
+            .map_err(|err| format!("failed to load tuning config {path}: {err}"))?;
+        let profile = reloader
+            .config()
+            .cpu_profile()
+            .map_err(|err| format!("invalid cpu profile in {path}: {err}"))?;
+        for (role, cpus) in profile.roles() {
+            info!("tuning config: pinning {role} threads to cpus {cpus:?}");
+        }
+        // --experimental-tuning-config conflicts with --cpu-profile and the pinned PoH core
+        agave_cpu_utils::install_cpu_profile(profile).expect("cpu profile is installed once");
+        install_tuning_reloader(reloader)
+            .unwrap_or_else(|_| panic!("tuning config is loaded once"));
+        reload_tuning_config_on_sighup()?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    if matches.is_present("tuning_config") {
+        return Err("--experimental-tuning-config is only supported on Linux".into());
+    }
+
+    let reserved = [&validator_config.retransmit_xdp, &validator_config.tvu_xdp]
+        .into_iter()
+        .flatten()
+        .flat_map(|xdp| xdp.cpus.iter().copied())
         .collect::<HashSet<_>>();
     if !reserved.is_empty() {
-        let available = (0..agave_cpu_utils::cpu_count().unwrap_or(0)).collect::<HashSet<_>>();
+        let available = agave_cpu_utils::online_cpus()
+            .unwrap_or_default()
+            .into_iter()
+            .collect::<HashSet<_>>();
         let available = available.difference(&reserved);
         agave_cpu_utils::set_cpu_affinity(available.into_iter().copied()).unwrap();
     }
@@ -740,6 +811,7 @@ pub fn execute(
             tower_storage: validator_config.tower_storage.clone(),
             staked_nodes_overrides,
             rpc_to_plugin_manager_sender,
+            ledger_path: ledger_path.clone(),
         },
     );
 
@@ -1058,6 +1130,28 @@ pub fn execute(
     Ok(())
 }
 
+// The validator keeps running if the new config is invalid, the reload is only logged.
+#[cfg(target_os = "linux")]
+fn reload_tuning_config_on_sighup() -> Result<(), Box<dyn std::error::Error>> {
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+    std::thread::Builder::new()
+        .name("solSigHup".into())
+        .spawn(move || {
+            for _ in signals.forever() {
+                let Some(reloader) = agave_xdp::reload::tuning_reloader() else {
+                    continue;
+                };
+                match reloader.reload() {
+                    Ok(report) => info!("received SIGHUP, reloaded the tuning config: {report}"),
+                    Err(err) => {
+                        warn!("received SIGHUP, failed to reload the tuning config: {err}")
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
 // This function is duplicated in ledger-tool/src/main.rs...
 fn hardforks_of(matches: &ArgMatches<'_>, name: &str) -> Option<Vec<Slot>> {
     if matches.is_present(name) {
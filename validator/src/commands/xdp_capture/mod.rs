@@ -0,0 +1,84 @@
+use {
+    crate::{
+        admin_rpc_service,
+        commands::{FromClapArgMatches, Result},
+    },
+    clap::{App, Arg, ArgMatches, SubCommand},
+    std::path::{Path, PathBuf},
+};
+
+const COMMAND: &str = "xdp-capture";
+
+#[derive(Debug, PartialEq)]
+pub struct XdpCaptureArgs {
+    pub path: PathBuf,
+}
+
+impl FromClapArgMatches for XdpCaptureArgs {
+    fn from_clap_arg_match(matches: &ArgMatches) -> Result<Self> {
+        Ok(XdpCaptureArgs {
+            path: PathBuf::from(matches.value_of("path").expect("path is required")),
+        })
+    }
+}
+
+pub fn command<'a>() -> App<'a, 'a> {
+    SubCommand::with_name(COMMAND)
+        .about("Write the frames recently sent and received through XDP to a pcap file")
+        .arg(
+            Arg::with_name("path")
+                .value_name("PATH")
+                .takes_value(true)
+                .required(true)
+                .help("The pcap file to create, relative to the ledger directory"),
+        )
+        .after_help(
+            "Note: the validator must be running with --experimental-xdp-capture-frames, only the \
+             headers of the last frames of each queue are kept",
+        )
+}
+
+pub fn execute(matches: &ArgMatches, ledger_path: &Path) -> Result<()> {
+    let xdp_capture_args = XdpCaptureArgs::from_clap_arg_match(matches)?;
+    let path = xdp_capture_args.path;
+
+    let admin_client = admin_rpc_service::connect(ledger_path);
+    let frames = admin_rpc_service::runtime().block_on(async {
+        admin_client
+            .await?
+            .xdp_capture_dump(path.display().to_string())
+            .await
+    })?;
+    println!(
+        "Wrote {frames} frames to {}",
+        ledger_path.join(path).display()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::commands::tests::{
+            verify_args_struct_by_command, verify_args_struct_by_command_is_error,
+        },
+    };
+
+    #[test]
+    fn verify_args_struct_by_command_xdp_capture_with_path() {
+        verify_args_struct_by_command(
+            command(),
+            vec![COMMAND, "capture.pcap"],
+            XdpCaptureArgs {
+                path: PathBuf::from("capture.pcap"),
+            },
+        );
+    }
+
+    #[test]
+    fn verify_args_struct_by_command_xdp_capture_without_path() {
+        verify_args_struct_by_command_is_error::<XdpCaptureArgs>(command(), vec![COMMAND]);
+    }
+}
@@ -47,6 +47,10 @@ pub fn main() {
         ("contact-info", Some(subcommand_matches)) => {
             commands::contact_info::execute(subcommand_matches, &ledger_path)
         }
+        ("datapath-stats", Some(subcommand_matches)) => {
+            commands::datapath_stats::execute(subcommand_matches, &ledger_path)
+        }
+        ("doctor", Some(subcommand_matches)) => commands::doctor::execute(subcommand_matches),
         ("exit", Some(subcommand_matches)) => {
             commands::exit::execute(subcommand_matches, &ledger_path)
         }
@@ -54,6 +58,9 @@ pub fn main() {
         ("staked-nodes-overrides", Some(subcommand_matches)) => {
             commands::staked_nodes_overrides::execute(subcommand_matches, &ledger_path)
         }
+        ("reload-tuning-config", Some(subcommand_matches)) => {
+            commands::reload_tuning_config::execute(subcommand_matches, &ledger_path)
+        }
         ("set-identity", Some(subcommand_matches)) => {
             commands::set_identity::execute(subcommand_matches, &ledger_path)
         }
@@ -75,6 +82,9 @@ pub fn main() {
         ("manage-block-production", Some(subcommand_matches)) => {
             commands::manage_block_production::execute(subcommand_matches, &ledger_path)
         }
+        ("xdp-capture", Some(subcommand_matches)) => {
+            commands::xdp_capture::execute(subcommand_matches, &ledger_path)
+        }
         _ => unreachable!(),
     }
     .unwrap_or_else(|err| {
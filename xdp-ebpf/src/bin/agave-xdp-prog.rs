@@ -2,28 +2,64 @@
 #![no_main]
 
 use {
+    agave_xdp_ebpf::{FlowKey, MAX_INTERFACES, QUEUES_PER_INTERFACE},
     aya_ebpf::{
         bindings::xdp_action::{XDP_DROP, XDP_PASS},
         helpers::gen::bpf_xdp_get_buff_len,
-        macros::xdp,
+        macros::{map, xdp},
+        maps::{HashMap, XskMap},
         programs::XdpContext,
     },
-    core::ptr,
+    core::{mem, ptr},
 };
 
 #[no_mangle]
 // Set to 1 from user space at load time to control whether we must drop multi-frags packets
 static AGAVE_XDP_DROP_MULTI_FRAGS: u8 = 0;
 
+// The flows to redirect, see agave_xdp_ebpf::FLOWS_MAP. Updated from user space at runtime.
+#[map]
+static AGAVE_XDP_FLOWS: HashMap<FlowKey, u8> = HashMap::with_max_entries(1024, 0);
+
+// The AF_XDP socket of each queue, see agave_xdp_ebpf::SOCKETS_MAP.
+#[map]
+static AGAVE_XDP_SOCKETS: XskMap =
+    XskMap::with_max_entries(MAX_INTERFACES * QUEUES_PER_INTERFACE, 0);
+
+// The slot of each interface in AGAVE_XDP_SOCKETS, see agave_xdp_ebpf::INTERFACES_MAP.
+#[map]
+static AGAVE_XDP_INTERFACES: HashMap<u32, u32> = HashMap::with_max_entries(MAX_INTERFACES, 0);
+
+const ETH_HEADER_SIZE: usize = 14;
+const ETH_P_IP: u16 = 0x0800;
+const IPPROTO_UDP: u8 = 17;
+
 #[xdp]
 pub fn agave_xdp(ctx: XdpContext) -> u32 {
     if drop_frags() && has_frags(&ctx) {
         // We're not actually dropping any valid frames here. See
         // https://lore.kernel.org/netdev/20251021173200.7908-2-alessandro.d@gmail.com
-        XDP_DROP
-    } else {
+        return XDP_DROP;
+    }
+    if !is_redirected_flow(&ctx) {
         // let the kernel handle the packet normally
-        XDP_PASS
+        return XDP_PASS;
+    }
+    // Safety: the verifier guarantees ctx.ctx is valid
+    let (if_index, queue) = unsafe { ((*ctx.ctx).ingress_ifindex, (*ctx.ctx).rx_queue_index) };
+    if queue >= QUEUES_PER_INTERFACE {
+        return XDP_PASS;
+    }
+    // Safety: the value is copied out
+    let slot = unsafe { AGAVE_XDP_INTERFACES.get(&if_index) }
+        .copied()
+        .unwrap_or(0);
+    let index = slot
+        .saturating_mul(QUEUES_PER_INTERFACE)
+        .saturating_add(queue);
+    // packets received on a queue without a socket go to the kernel
+    match AGAVE_XDP_SOCKETS.redirect(index, XDP_PASS as u64) {
+        Ok(action) | Err(action) => action,
     }
 }
 
@@ -43,6 +79,57 @@ fn has_frags(ctx: &XdpContext) -> bool {
     linear_len < buf_len
 }
 
+// Returns whether the packet is an unfragmented IPv4 UDP datagram sent to one of the flows in
+// AGAVE_XDP_FLOWS, either to its address and port or to its port on any address.
+#[inline(always)]
+#[allow(clippy::arithmetic_side_effects)]
+fn is_redirected_flow(ctx: &XdpContext) -> bool {
+    let Some(eth) = ptr_at::<[u8; ETH_HEADER_SIZE]>(ctx, 0) else {
+        return false;
+    };
+    // Safety: ptr_at checked the bounds
+    let eth = unsafe { &*eth };
+    if u16::from_be_bytes([eth[12], eth[13]]) != ETH_P_IP {
+        return false;
+    }
+
+    let Some(ip) = ptr_at::<[u8; 20]>(ctx, ETH_HEADER_SIZE) else {
+        return false;
+    };
+    // Safety: ptr_at checked the bounds
+    let ip = unsafe { &*ip };
+    let ihl = (ip[0] & 0x0f) as usize * 4;
+    // only the first fragment has the UDP header, let the kernel reassemble them
+    let fragmented = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0;
+    if ihl < 20 || ip[9] != IPPROTO_UDP || fragmented {
+        return false;
+    }
+
+    let Some(udp) = ptr_at::<[u8; 4]>(ctx, ETH_HEADER_SIZE + ihl) else {
+        return false;
+    };
+    // Safety: ptr_at checked the bounds
+    let udp = unsafe { &*udp };
+
+    let mut key = FlowKey::new([ip[16], ip[17], ip[18], ip[19]], [udp[2], udp[3]]);
+    // Safety: the values are never referenced after the lookup
+    if unsafe { AGAVE_XDP_FLOWS.get(&key) }.is_some() {
+        return true;
+    }
+    key.addr = [0; 4];
+    // Safety: as above
+    unsafe { AGAVE_XDP_FLOWS.get(&key) }.is_some()
+}
+
+#[inline(always)]
+fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Option<*const T> {
+    let ptr = ctx.data().checked_add(offset)?;
+    if ptr.checked_add(mem::size_of::<T>())? > ctx.data_end() {
+        return None;
+    }
+    Some(ptr as *const T)
+}
+
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
     // This is so that if we accidentally panic anywhere the verifier will refuse to load the
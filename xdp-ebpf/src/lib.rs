@@ -18,6 +18,48 @@
 #![warn(unsafe_op_in_unsafe_fn)]
 #![no_std]
 
+/// The map of the flows whose packets the program redirects to AF_XDP, keyed by [`FlowKey`].
+pub const FLOWS_MAP: &str = "AGAVE_XDP_FLOWS";
+/// The map of the AF_XDP sockets packets are redirected to, indexed by interface slot and queue
+/// id, see [`QUEUES_PER_INTERFACE`].
+pub const SOCKETS_MAP: &str = "AGAVE_XDP_SOCKETS";
+/// The map of the slot in [`SOCKETS_MAP`] of each interface the program is attached to, keyed by
+/// interface index. Interfaces missing from the map use slot 0.
+pub const INTERFACES_MAP: &str = "AGAVE_XDP_INTERFACES";
+/// The number of entries of [`SOCKETS_MAP`] in each interface slot. The socket of queue `q` of
+/// the interface in slot `s` is at index `s * QUEUES_PER_INTERFACE + q`.
+pub const QUEUES_PER_INTERFACE: u32 = 256;
+/// The number of interface slots, including the default slot 0.
+pub const MAX_INTERFACES: u32 = 16;
+
+/// A flow redirected to AF_XDP, identified by its destination. An address of `0.0.0.0` matches
+/// packets sent to the port on any address.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    /// IPv4 address in network byte order.
+    pub addr: [u8; 4],
+    /// UDP port in network byte order.
+    pub port: [u8; 2],
+    // keys are compared bytewise, so the padding must be explicit to be zeroed
+    _pad: [u8; 2],
+}
+
+impl FlowKey {
+    /// Creates a key from an address and a port in network byte order.
+    pub const fn new(addr: [u8; 4], port: [u8; 2]) -> Self {
+        Self {
+            addr,
+            port,
+            _pad: [0; 2],
+        }
+    }
+}
+
+// Safety: FlowKey is repr(C) and has no implicit padding
+#[cfg(all(target_os = "linux", not(target_arch = "bpf")))]
+unsafe impl aya::Pod for FlowKey {}
+
 #[cfg(all(target_os = "linux", not(target_arch = "bpf")))]
 #[unsafe(no_mangle)]
 pub static AGAVE_XDP_EBPF_PROGRAM: [u8; aya::include_bytes_aligned!(concat!(
@@ -0,0 +1,446 @@
+//! Compares the UDP, io_uring and AF_XDP transports across packet sizes and fan-outs.
+//!
+//! Each iteration sends one payload to `fanout` destinations and waits until all the packets have
+//! arrived, so the numbers include the whole way from the sender to the receiver:
+//!
+//! - `pps` is the time per send, with the packets per second as throughput.
+//! - `cpu` is the CPU time of the whole process per send, the sender, the receiver and, for
+//!   AF_XDP, the simulated kernel. Throughput is reported in packets per CPU second.
+//! - `latency` is the time from handing a single packet to the transport to receiving it.
+//!
+//! UDP and io_uring send over loopback. AF_XDP runs the real tx loop over the
+//! [simulation backend](agave_xdp::sim), which copies frames between threads instead of going
+//! through a driver, so it measures the loop itself rather than a NIC.
+//!
+//! ```text
+//! cargo bench -p agave-xdp --features test-utils --bench transports
+//! ```
+
+#[cfg(target_os = "linux")]
+criterion::criterion_main!(linux::benches);
+
+#[cfg(not(target_os = "linux"))]
+fn main() {}
+
+#[cfg(target_os = "linux")]
+#[allow(deprecated, clippy::arithmetic_side_effects)]
+mod linux {
+    use {
+        agave_io_uring::{Completion, Ring, RingOp},
+        agave_xdp::{
+            netlink::MacAddress,
+            sim::veth_pair,
+            transport::{DatagramTransport, XdpTransport},
+            tx_loop::{sim_tx_loop, TxLoopConfig},
+        },
+        criterion::{
+            measurement::{Measurement, ValueFormatter, WallTime},
+            BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+        },
+        io_uring::{opcode, squeue, types, IoUring},
+        solana_perf::packet::bytes::Bytes,
+        std::{
+            io, mem,
+            net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+            os::fd::AsRawFd,
+            sync::{
+                atomic::{AtomicBool, AtomicU64, Ordering},
+                Arc,
+            },
+            thread::{self, JoinHandle},
+            time::{Duration, Instant},
+        },
+    };
+
+    const PACKET_SIZES: [usize; 3] = [64, 512, 1232];
+    // the largest send must fit in the default socket receive buffer, or loopback drops packets
+    const FANOUTS: [usize; 3] = [1, 8, 64];
+    const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+    const TX_RING_SIZE: usize = 1024;
+
+    #[allow(clippy::disallowed_methods)]
+    fn bind_localhost() -> UdpSocket {
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap()
+    }
+
+    /// Counts the packets that arrive on the receiving end of a backend.
+    struct Sink {
+        received: Arc<AtomicU64>,
+        exit: Arc<AtomicBool>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl Sink {
+        fn spawn(mut recv: impl FnMut() -> bool + Send + 'static) -> Self {
+            let received = Arc::new(AtomicU64::new(0));
+            let exit = Arc::new(AtomicBool::new(false));
+            let thread = thread::Builder::new()
+                .name("solBenchSink".to_owned())
+                .spawn({
+                    let (received, exit) = (received.clone(), exit.clone());
+                    move || {
+                        while !exit.load(Ordering::Relaxed) {
+                            if recv() {
+                                received.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                    }
+                })
+                .unwrap();
+            Self {
+                received,
+                exit,
+                thread: Some(thread),
+            }
+        }
+
+        fn udp() -> (Self, SocketAddr) {
+            let socket = bind_localhost();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(10)))
+                .unwrap();
+            let addr = socket.local_addr().unwrap();
+            let mut buf = [0u8; 2048];
+            (Self::spawn(move || socket.recv(&mut buf).is_ok()), addr)
+        }
+
+        fn wait_for(&self, packets: u64) {
+            let deadline = Instant::now() + DELIVERY_TIMEOUT;
+            while self.received.load(Ordering::Relaxed) < packets {
+                assert!(
+                    Instant::now() < deadline,
+                    "packets were lost: {} of {packets}",
+                    self.received.load(Ordering::Relaxed)
+                );
+                thread::yield_now();
+            }
+        }
+    }
+
+    impl Drop for Sink {
+        fn drop(&mut self) {
+            self.exit.store(true, Ordering::Relaxed);
+            self.thread.take().unwrap().join().unwrap();
+        }
+    }
+
+    struct IoUringSend {
+        // boxed so that the pointers handed to the kernel stay put while the op moves around
+        msg: Box<(libc::msghdr, libc::iovec, libc::sockaddr_in)>,
+        _payload: Bytes,
+        fd: i32,
+    }
+
+    impl IoUringSend {
+        fn new(fd: i32, payload: Bytes, addr: SocketAddrV4) -> Self {
+            // safety: all zeroes is a valid msghdr, iovec and sockaddr_in
+            let mut msg: Box<(libc::msghdr, libc::iovec, libc::sockaddr_in)> =
+                Box::new(unsafe { mem::zeroed() });
+            let (hdr, iov, sockaddr) = &mut *msg;
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = addr.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+            iov.iov_base = payload.as_ptr() as *mut libc::c_void;
+            iov.iov_len = payload.len();
+            hdr.msg_name = sockaddr as *mut libc::sockaddr_in as *mut libc::c_void;
+            hdr.msg_namelen = mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+            hdr.msg_iov = iov;
+            hdr.msg_iovlen = 1;
+            Self {
+                msg,
+                _payload: payload,
+                fd,
+            }
+        }
+    }
+
+    impl RingOp<()> for IoUringSend {
+        fn entry(&mut self) -> squeue::Entry {
+            opcode::SendMsg::new(types::Fd(self.fd), &self.msg.0).build()
+        }
+
+        fn complete(
+            &mut self,
+            _completion: &mut Completion<(), Self>,
+            res: io::Result<i32>,
+        ) -> io::Result<()> {
+            res.map(|_| ())
+        }
+    }
+
+    /// Sends with `IORING_OP_SENDMSG`, reaping completions as the ring fills up.
+    struct IoUringTransport {
+        socket: UdpSocket,
+        ring: Ring<(), IoUringSend>,
+    }
+
+    impl IoUringTransport {
+        fn new() -> io::Result<Self> {
+            Ok(Self {
+                socket: bind_localhost(),
+                ring: Ring::new(IoUring::new(1024)?, ()),
+            })
+        }
+
+        fn send_to(&mut self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+            for addr in addrs {
+                let SocketAddr::V4(addr) = addr else {
+                    return Err(io::ErrorKind::Unsupported.into());
+                };
+                let op = IoUringSend::new(self.socket.as_raw_fd(), payload.clone(), *addr);
+                self.ring.push(op)?;
+            }
+            self.ring.submit()
+        }
+    }
+
+    impl Drop for IoUringTransport {
+        fn drop(&mut self) {
+            let _ = self.ring.drain();
+        }
+    }
+
+    enum Backend {
+        Udp {
+            socket: UdpSocket,
+        },
+        IoUring(Box<IoUringTransport>),
+        Xdp {
+            transport: Option<XdpTransport>,
+            tx_loop: Option<JoinHandle<()>>,
+        },
+    }
+
+    impl Backend {
+        const ALL: [&'static str; 3] = ["udp", "io_uring", "af_xdp"];
+
+        /// Creates the backend called `name` and a sink receiving what it sends to the returned
+        /// address.
+        fn new(name: &str) -> (Self, Sink, SocketAddr) {
+            match name {
+                "udp" => {
+                    let (sink, addr) = Sink::udp();
+                    let socket = bind_localhost();
+                    (Self::Udp { socket }, sink, addr)
+                }
+                "io_uring" => {
+                    let (sink, addr) = Sink::udp();
+                    let transport = IoUringTransport::new().unwrap();
+                    (Self::IoUring(Box::new(transport)), sink, addr)
+                }
+                "af_xdp" => {
+                    let (endpoint, peer) = veth_pair();
+                    let sink =
+                        Sink::spawn(move || peer.recv_timeout(Duration::from_millis(10)).is_some());
+                    let (transport, mut receivers) = XdpTransport::new(1, TX_RING_SIZE);
+                    let receiver = receivers.pop().unwrap();
+                    let tx_loop = thread::Builder::new()
+                        .name("solBenchTxLoop".to_owned())
+                        .spawn(move || {
+                            let (drop_sender, _drop_receiver) = crossbeam_channel::unbounded();
+                            sim_tx_loop(
+                                endpoint,
+                                SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8000),
+                                MacAddress([2, 0, 0, 0, 0, 2]),
+                                TX_RING_SIZE,
+                                receiver,
+                                drop_sender,
+                                &TxLoopConfig::default(),
+                            )
+                            .unwrap();
+                        })
+                        .unwrap();
+                    let backend = Self::Xdp {
+                        transport: Some(transport),
+                        tx_loop: Some(tx_loop),
+                    };
+                    (backend, sink, SocketAddr::from(([10, 0, 0, 2], 8000)))
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        fn send_to(&mut self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+            match self {
+                Self::Udp { socket } => DatagramTransport::send_to(socket, payload, addrs),
+                Self::IoUring(transport) => transport.send_to(payload, addrs),
+                Self::Xdp { transport, .. } => transport.as_ref().unwrap().send_to(payload, addrs),
+            }
+        }
+    }
+
+    impl Drop for Backend {
+        fn drop(&mut self) {
+            if let Self::Xdp { transport, tx_loop } = self {
+                // the tx loop exits once its channel is disconnected
+                drop(transport.take());
+                tx_loop.take().unwrap().join().unwrap();
+            }
+        }
+    }
+
+    /// Sends `payload` to `fanout` copies of the backend's address until criterion has enough
+    /// samples.
+    fn bench_sends<M: Measurement>(
+        group: &mut BenchmarkGroup<M>,
+        name: &str,
+        size: usize,
+        fanout: usize,
+        id: BenchmarkId,
+    ) {
+        let (mut backend, sink, addr) = Backend::new(name);
+        let payload = Bytes::from(vec![0xa5; size]);
+        let addrs = vec![addr; fanout];
+        let mut sent = 0;
+        group.bench_function(id, |b| {
+            b.iter(|| {
+                backend.send_to(payload.clone(), &addrs).unwrap();
+                sent += fanout as u64;
+                sink.wait_for(sent);
+            })
+        });
+    }
+
+    fn bench_pps(c: &mut Criterion) {
+        let mut group = c.benchmark_group("pps");
+        for name in Backend::ALL {
+            for size in PACKET_SIZES {
+                for fanout in FANOUTS {
+                    group.throughput(Throughput::Elements(fanout as u64));
+                    let id = BenchmarkId::new(name, format!("{size}B/x{fanout}"));
+                    bench_sends(&mut group, name, size, fanout, id);
+                }
+            }
+        }
+        group.finish();
+    }
+
+    fn bench_latency(c: &mut Criterion) {
+        let mut group = c.benchmark_group("latency");
+        for name in Backend::ALL {
+            for size in PACKET_SIZES {
+                bench_sends(&mut group, name, size, 1, BenchmarkId::new(name, size));
+            }
+        }
+        group.finish();
+    }
+
+    fn bench_cpu(c: &mut Criterion<ProcessCpuTime>) {
+        let mut group = c.benchmark_group("cpu");
+        for name in Backend::ALL {
+            for fanout in FANOUTS {
+                group.throughput(Throughput::Elements(fanout as u64));
+                let size = PACKET_SIZES[PACKET_SIZES.len() - 1];
+                let id = BenchmarkId::new(name, format!("{size}B/x{fanout}"));
+                bench_sends(&mut group, name, size, fanout, id);
+            }
+        }
+        group.finish();
+    }
+
+    /// The CPU time used by all the threads of the process.
+    struct ProcessCpuTime;
+
+    impl ProcessCpuTime {
+        fn now() -> Duration {
+            // safety: all zeroes is a valid timespec
+            let mut ts: libc::timespec = unsafe { mem::zeroed() };
+            // safety: ts is a valid timespec to write to
+            unsafe { libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut ts) };
+            Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+        }
+    }
+
+    impl Measurement for ProcessCpuTime {
+        type Intermediate = Duration;
+        type Value = Duration;
+
+        fn start(&self) -> Duration {
+            Self::now()
+        }
+
+        fn end(&self, start: Duration) -> Duration {
+            Self::now().saturating_sub(start)
+        }
+
+        fn add(&self, v1: &Duration, v2: &Duration) -> Duration {
+            *v1 + *v2
+        }
+
+        fn zero(&self) -> Duration {
+            Duration::ZERO
+        }
+
+        fn to_f64(&self, value: &Duration) -> f64 {
+            value.as_nanos() as f64
+        }
+
+        fn formatter(&self) -> &dyn ValueFormatter {
+            &CpuTimeFormatter
+        }
+    }
+
+    struct CpuTimeFormatter;
+
+    impl ValueFormatter for CpuTimeFormatter {
+        fn scale_values(&self, typical_value: f64, values: &mut [f64]) -> &'static str {
+            let (factor, unit) = if typical_value < 1e3 {
+                (1.0, "ns cpu")
+            } else if typical_value < 1e6 {
+                (1e-3, "µs cpu")
+            } else {
+                (1e-6, "ms cpu")
+            };
+            values.iter_mut().for_each(|value| *value *= factor);
+            unit
+        }
+
+        fn scale_throughputs(
+            &self,
+            _typical_value: f64,
+            throughput: &Throughput,
+            values: &mut [f64],
+        ) -> &'static str {
+            let elements = match throughput {
+                Throughput::Elements(elements) => *elements,
+                Throughput::Bytes(bytes) | Throughput::BytesDecimal(bytes) => *bytes,
+            } as f64;
+            // from cpu nanoseconds per iteration to packets per cpu second
+            values
+                .iter_mut()
+                .for_each(|value| *value = elements * 1e9 / *value);
+            "pkt/cpu-s"
+        }
+
+        fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+            "ns"
+        }
+    }
+
+    fn wall_time() -> Criterion<WallTime> {
+        Criterion::default().sample_size(20)
+    }
+
+    fn cpu_time() -> Criterion<ProcessCpuTime> {
+        Criterion::default()
+            .with_measurement(ProcessCpuTime)
+            .sample_size(20)
+    }
+
+    criterion::criterion_group! {
+        name = wall;
+        config = wall_time();
+        targets = bench_pps, bench_latency
+    }
+
+    criterion::criterion_group! {
+        name = cpu;
+        config = cpu_time();
+        targets = bench_cpu
+    }
+
+    pub fn benches() {
+        wall();
+        cpu();
+    }
+}
@@ -0,0 +1,369 @@
+//! tokio wrapper of an AF_XDP socket.
+//!
+//! [`AsyncSocket`] registers the socket with the tokio reactor, whose readiness stands in for the
+//! rings: the socket is readable when the rx ring has frames, and writable when the tx ring has
+//! room, which the kernel signals as it completes frames. [`send`](AsyncSocket::send) waits for
+//! room in the tx ring and the socket is a [`Stream`] of the frames received on its rx ring. The
+//! driver is woken up whenever the rings ask for it with `XDP_RING_NEED_WAKEUP`.
+//!
+//! Received frames are copied out of the UMEM and their frames go straight back to the fill ring.
+//! The loops in [`rx_loop`](crate::rx_loop) and [`tx_loop`](crate::tx_loop) remain the fast path,
+//! this is for code that already lives in a runtime.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        rx_batch::refill,
+        socket::{RingFull, Rx, Socket, Tx, TxQueue as _},
+        umem::Umem,
+    },
+    futures_util::Stream,
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        collections::VecDeque,
+        future::poll_fn,
+        io,
+        os::fd::{AsRawFd, RawFd},
+        pin::Pin,
+        slice,
+        task::{ready, Context, Poll},
+        time::Duration,
+    },
+    tokio::{
+        io::{unix::AsyncFd, Interest},
+        time::{self, Interval, MissedTickBehavior},
+    },
+};
+
+// How often the rings of sockets the reactor can't watch, e.g. simulated ones, are checked.
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+type FrameOf<S> = <<S as UmemOwner>::Umem as Umem>::Frame;
+
+/// A socket owning its UMEM.
+pub trait UmemOwner {
+    type Umem: Umem;
+
+    fn umem(&mut self) -> &mut Self::Umem;
+}
+
+impl<U: Umem> UmemOwner for Socket<U> {
+    type Umem = U;
+
+    fn umem(&mut self) -> &mut U {
+        Socket::umem(self)
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl<U: Umem> UmemOwner for crate::sim::SimSocket<U> {
+    type Umem = U;
+
+    fn umem(&mut self) -> &mut U {
+        crate::sim::SimSocket::umem(self)
+    }
+}
+
+// The fd of the socket, owned by the socket itself.
+struct SocketFd(RawFd);
+
+impl AsRawFd for SocketFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+enum Readiness {
+    Reactor(AsyncFd<SocketFd>),
+    // the rings of simulated sockets are memfds, which epoll rejects
+    Interval(Interval),
+}
+
+impl Readiness {
+    // Returns once `ready` returns true, checking it again whenever the socket becomes readable
+    // or writable.
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        interest: Interest,
+        mut ready: impl FnMut() -> bool,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if ready() {
+                return Poll::Ready(Ok(()));
+            }
+            match self {
+                Self::Reactor(fd) => {
+                    let mut guard = if interest.is_readable() {
+                        ready!(fd.poll_read_ready(cx))?
+                    } else {
+                        ready!(fd.poll_write_ready(cx))?
+                    };
+                    // the readiness is only cleared if no event came in since the guard was
+                    // returned, so nothing is missed between the check and the clear
+                    if ready() {
+                        return Poll::Ready(Ok(()));
+                    }
+                    guard.clear_ready();
+                }
+                Self::Interval(interval) => {
+                    ready!(interval.poll_tick(cx));
+                }
+            }
+        }
+    }
+}
+
+struct Rings<S: UmemOwner> {
+    socket: S,
+    rx: Rx<FrameOf<S>>,
+    tx: Tx<FrameOf<S>>,
+    received: VecDeque<Bytes>,
+}
+
+impl<S: UmemOwner> Rings<S> {
+    // Refills the fill ring and copies out the frames in the rx ring.
+    fn receive(&mut self) -> io::Result<()> {
+        let Self {
+            socket,
+            rx,
+            received,
+            ..
+        } = self;
+        let umem = socket.umem();
+        refill(&mut rx.fill, umem, usize::MAX);
+        if rx.fill.needs_wakeup() {
+            if let Err(e) = rx.fill.wake() {
+                if !matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EBUSY)) {
+                    log::debug!("failed to wake up the driver: {e}");
+                }
+            }
+        }
+
+        let Some(ring) = rx.ring.as_mut() else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the socket has no rx ring",
+            ));
+        };
+        ring.sync(true);
+        while let Some((offset, len)) = ring.read() {
+            // Safety: the kernel wrote the frame within the UMEM
+            let frame = unsafe { slice::from_raw_parts(umem.as_ptr().add(offset.0), len) };
+            received.push_back(Bytes::copy_from_slice(frame));
+            umem.release(offset);
+        }
+        ring.commit();
+        Ok(())
+    }
+
+    fn rx_ready(&self) -> bool {
+        self.rx
+            .ring
+            .as_ref()
+            .is_some_and(|ring| ring.occupancy() > 0)
+    }
+
+    // Releases the frames the driver is done with to the UMEM.
+    fn reap(&mut self) {
+        let umem = self.socket.umem();
+        while let Some(offset) = self.tx.complete() {
+            umem.release(offset);
+        }
+        self.tx.completion.commit();
+    }
+
+    fn tx_ready(&mut self) -> bool {
+        if self.tx.completion.occupancy() > 0 {
+            return true;
+        }
+        self.tx.ring.as_mut().is_some_and(|ring| {
+            ring.sync(false);
+            ring.available() > 0
+        })
+    }
+
+    fn kick(&mut self) -> io::Result<()> {
+        match self.tx.kick() {
+            Err(e)
+                if !matches!(
+                    e.raw_os_error(),
+                    Some(libc::EBUSY | libc::ENOBUFS | libc::EAGAIN)
+                ) =>
+            {
+                Err(e)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An AF_XDP socket driven by the tokio reactor.
+pub struct AsyncSocket<S: UmemOwner> {
+    // dropped first, to deregister the fd before the socket closes it
+    readiness: Readiness,
+    rings: Rings<S>,
+}
+
+impl<S: UmemOwner> AsyncSocket<S> {
+    /// Wraps `socket` and its rings, as returned by [`Socket::new`] or `SimSocket::new`.
+    ///
+    /// Must be called from within a tokio runtime with IO and time enabled.
+    pub fn new(socket: S, rx: Rx<FrameOf<S>>, tx: Tx<FrameOf<S>>) -> io::Result<Self> {
+        let fd = match (&rx.ring, &tx.ring) {
+            (Some(ring), _) => ring.fd(),
+            (None, Some(ring)) => ring.fd(),
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the socket has no rx or tx ring",
+                ))
+            }
+        };
+        let readiness =
+            match AsyncFd::with_interest(SocketFd(fd), Interest::READABLE | Interest::WRITABLE) {
+                Ok(fd) => Readiness::Reactor(fd),
+                Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                    log::debug!("can't register the socket with the reactor, polling it: {e}");
+                    let mut interval = time::interval(POLL_INTERVAL);
+                    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                    Readiness::Interval(interval)
+                }
+                Err(e) => return Err(e),
+            };
+        let mut rings = Rings {
+            socket,
+            rx,
+            tx,
+            received: VecDeque::new(),
+        };
+        if rings.rx.ring.is_some() {
+            rings.receive()?;
+        }
+        Ok(Self { readiness, rings })
+    }
+
+    pub fn socket(&mut self) -> &mut S {
+        &mut self.rings.socket
+    }
+
+    /// Returns the UMEM to write the frames to send into.
+    pub fn umem(&mut self) -> &mut S::Umem {
+        self.rings.socket.umem()
+    }
+
+    /// Reserves a frame to send, waiting for the driver to complete one if the UMEM is empty.
+    pub async fn reserve(&mut self) -> io::Result<FrameOf<S>> {
+        loop {
+            self.rings.reap();
+            if let Some(frame) = self.umem().reserve() {
+                return Ok(frame);
+            }
+            self.rings.kick()?;
+            let Self { readiness, rings } = self;
+            poll_fn(|cx| readiness.poll(cx, Interest::WRITABLE, || rings.tx_ready())).await?;
+        }
+    }
+
+    /// Queues `frame` on the tx ring, waiting for room if it's full, and wakes up the driver if
+    /// it needs it. The frame returns to the UMEM once the driver completes it.
+    pub async fn send(&mut self, frame: FrameOf<S>) -> io::Result<()> {
+        let mut frame = frame;
+        loop {
+            self.rings.reap();
+            match self.rings.tx.submit(frame, 0) {
+                Ok(()) => {
+                    self.rings.tx.commit();
+                    return self.rings.kick();
+                }
+                Err(RingFull(returned)) => frame = returned,
+            }
+            if self.rings.tx.ring.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "the socket has no tx ring",
+                ));
+            }
+            self.rings.kick()?;
+            let Self { readiness, rings } = self;
+            poll_fn(|cx| readiness.poll(cx, Interest::WRITABLE, || rings.tx_ready())).await?;
+        }
+    }
+
+    /// Returns the next received frame.
+    pub async fn recv(&mut self) -> io::Result<Bytes> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<Bytes>> {
+        loop {
+            if let Some(frame) = self.rings.received.pop_front() {
+                return Poll::Ready(Ok(frame));
+            }
+            self.rings.receive()?;
+            if !self.rings.received.is_empty() {
+                continue;
+            }
+            let Self { readiness, rings } = self;
+            ready!(readiness.poll(cx, Interest::READABLE, || rings.rx_ready()))?;
+        }
+    }
+}
+
+impl<S: UmemOwner + Unpin> Stream for AsyncSocket<S>
+where
+    FrameOf<S>: Unpin,
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            sim::{veth_pair, SimSocket},
+            umem::{Frame as _, PageAlignedMemory, SliceUmem},
+        },
+        futures_util::StreamExt as _,
+    };
+
+    #[tokio::test]
+    async fn test_send_recv() {
+        const FRAME_SIZE: usize = 2048;
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 64).unwrap();
+        let umem = SliceUmem::new(&mut memory, FRAME_SIZE as u32).unwrap();
+        let (endpoint, peer) = veth_pair();
+        // the tx ring fills up so send() has to wait for completions
+        let (socket, rx, tx) = SimSocket::new(umem, endpoint, 16, 16, 8, 8).unwrap();
+        let mut socket = AsyncSocket::new(socket, rx, tx).unwrap();
+
+        for i in 0..40u8 {
+            let mut frame = socket.reserve().await.unwrap();
+            frame.set_len(64);
+            socket.umem().map_frame_mut(&frame).fill(i);
+            socket.send(frame).await.unwrap();
+        }
+        for i in 0..40u8 {
+            let frame = peer.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(frame, [i; 64]);
+        }
+
+        // more frames than the fill ring holds, one at a time so the simulated kernel doesn't
+        // drop them
+        for i in 0..40u8 {
+            assert!(peer.send(vec![i; 100]));
+            let frame = time::timeout(Duration::from_secs(5), socket.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame.len(), 100);
+            assert!(frame.iter().all(|b| *b == i));
+        }
+    }
+}
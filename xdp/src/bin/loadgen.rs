@@ -0,0 +1,387 @@
+//! Sends synthetic UDP traffic through the full XDP tx stack and reports the achieved packet rate,
+//! drops and completion latency. Used to qualify a NIC, driver and host tuning before trusting the
+//! datapath with validator traffic.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    linux::main()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("agave-xdp-loadgen is only supported on Linux");
+    std::process::exit(1);
+}
+
+#[cfg(target_os = "linux")]
+#[allow(deprecated, clippy::arithmetic_side_effects)]
+mod linux {
+    use {
+        agave_xdp::{
+            device::{NetworkDevice, QueueId},
+            load_xdp_program,
+            netlink::MacAddress,
+            socket::XdpStatistics,
+            tx_loop::{tx_loop, TxLoopConfig, TxLoopStats},
+        },
+        clap::{
+            crate_description, crate_version, value_t, value_t_or_exit, values_t_or_exit, App, Arg,
+        },
+        crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError},
+        std::{
+            net::{Ipv4Addr, SocketAddr},
+            sync::{
+                atomic::{AtomicBool, AtomicU64, Ordering},
+                Arc,
+            },
+            thread::{self, Builder},
+            time::{Duration, Instant},
+        },
+    };
+
+    type Item = (Arc<[SocketAddr]>, Arc<[u8]>);
+
+    const CHANNEL_CAP: usize = 65_536;
+    const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+    #[derive(Default)]
+    struct GeneratorStats {
+        // packets queued for the tx loops, counting each destination
+        queued: AtomicU64,
+        // packets dropped because a tx loop couldn't keep up
+        channel_full: AtomicU64,
+    }
+
+    pub fn main() {
+        agave_logger::setup_with_default("info");
+
+        let matches = App::new("agave-xdp-loadgen")
+            .about(crate_description!())
+            .version(crate_version!())
+            .arg(
+                Arg::with_name("interface")
+                    .long("interface")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .help("Interface to send from [default: interface of the default route]"),
+            )
+            .arg(
+                Arg::with_name("cpus")
+                    .long("cpus")
+                    .value_name("CPU")
+                    .takes_value(true)
+                    .use_delimiter(true)
+                    .required(true)
+                    .help("Comma separated list of CPUs to run tx loops on, one per NIC queue"),
+            )
+            .arg(
+                Arg::with_name("zero_copy")
+                    .long("zero-copy")
+                    .help("Load the XDP program and bind the sockets in zero copy mode"),
+            )
+            .arg(
+                Arg::with_name("dest")
+                    .long("dest")
+                    .value_name("IP:PORT")
+                    .takes_value(true)
+                    .multiple(true)
+                    .required(true)
+                    .help("Destination address. May be specified multiple times"),
+            )
+            .arg(
+                Arg::with_name("fan_out")
+                    .long("fan-out")
+                    .value_name("COUNT")
+                    .takes_value(true)
+                    .default_value("1")
+                    .help("Number of destinations each payload is sent to"),
+            )
+            .arg(
+                Arg::with_name("packet_size")
+                    .long("packet-size")
+                    .value_name("BYTES")
+                    .takes_value(true)
+                    .default_value("1232")
+                    .help("UDP payload size"),
+            )
+            .arg(
+                Arg::with_name("rate")
+                    .long("rate")
+                    .value_name("PPS")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("Target packets per second across all queues, 0 for unlimited"),
+            )
+            .arg(
+                Arg::with_name("duration")
+                    .long("duration")
+                    .value_name("SECS")
+                    .takes_value(true)
+                    .default_value("10")
+                    .help("How long to send for"),
+            )
+            .arg(
+                Arg::with_name("src_ip")
+                    .long("src-ip")
+                    .value_name("IP")
+                    .takes_value(true)
+                    .help("Source address [default: address of the interface]"),
+            )
+            .arg(
+                Arg::with_name("src_port")
+                    .long("src-port")
+                    .value_name("PORT")
+                    .takes_value(true)
+                    .default_value("9000")
+                    .help("Source port"),
+            )
+            .arg(
+                Arg::with_name("dest_mac")
+                    .long("dest-mac")
+                    .value_name("MAC")
+                    .takes_value(true)
+                    .help(
+                        "Destination MAC address, for example the gateway's. Bypasses route and \
+                         neighbor lookups",
+                    ),
+            )
+            .get_matches();
+
+        let cpus = values_t_or_exit!(matches, "cpus", usize);
+        let dests = values_t_or_exit!(matches, "dest", SocketAddr);
+        let fan_out = value_t_or_exit!(matches, "fan_out", usize).clamp(1, dests.len());
+        let packet_size = value_t_or_exit!(matches, "packet_size", usize);
+        let rate = value_t_or_exit!(matches, "rate", u64);
+        let duration = Duration::from_secs(value_t_or_exit!(matches, "duration", u64));
+        let src_ip = value_t!(matches, "src_ip", Ipv4Addr).ok();
+        let src_port = value_t_or_exit!(matches, "src_port", u16);
+        let zero_copy = matches.is_present("zero_copy");
+        let dest_mac = matches.value_of("dest_mac").map(|mac| {
+            parse_mac(mac).unwrap_or_else(|| {
+                eprintln!("invalid MAC address: {mac}");
+                std::process::exit(1);
+            })
+        });
+
+        let dev = Arc::new(
+            match matches.value_of("interface") {
+                Some(interface) => NetworkDevice::new(interface),
+                None => NetworkDevice::new_from_default_route(),
+            }
+            .expect("failed to open network device"),
+        );
+
+        let ebpf = zero_copy.then(|| load_xdp_program(&dev).expect("failed to load XDP program"));
+
+        let tx_stats = Arc::new(TxLoopStats::default());
+        let gen_stats = Arc::new(GeneratorStats::default());
+        let exit = Arc::new(AtomicBool::new(false));
+        let (drop_sender, drop_receiver) = crossbeam_channel::bounded::<Item>(CHANNEL_CAP);
+
+        // the destination sets we cycle through
+        let addrs = (0..dests.len())
+            .map(|i| {
+                (0..fan_out)
+                    .map(|j| dests[(i + j) % dests.len()])
+                    .collect::<Arc<[SocketAddr]>>()
+            })
+            .collect::<Vec<_>>();
+        let payload: Arc<[u8]> = vec![0xa5; packet_size].into();
+        let queue_rate = rate / cpus.len() as u64;
+
+        let mut tx_threads = Vec::with_capacity(cpus.len());
+        let mut gen_threads = Vec::with_capacity(cpus.len());
+        for (i, cpu_id) in cpus.into_iter().enumerate() {
+            let (sender, receiver) = crossbeam_channel::bounded(CHANNEL_CAP);
+
+            let dev = Arc::clone(&dev);
+            let drop_sender = drop_sender.clone();
+            let config = TxLoopConfig {
+                stats: Some(Arc::clone(&tx_stats)),
+                ..TxLoopConfig::default()
+            };
+            tx_threads.push(
+                Builder::new()
+                    .name(format!("solXdpLoadTx{i:02}"))
+                    .spawn(move || {
+                        tx_loop(
+                            cpu_id,
+                            &dev,
+                            QueueId(i as u64),
+                            zero_copy,
+                            None,
+                            src_ip,
+                            src_port,
+                            dest_mac,
+                            receiver,
+                            None,
+                            drop_sender,
+                            config,
+                        )
+                    })
+                    .unwrap(),
+            );
+
+            let addrs = addrs.clone();
+            let payload = Arc::clone(&payload);
+            let gen_stats = Arc::clone(&gen_stats);
+            let exit = Arc::clone(&exit);
+            gen_threads.push(
+                Builder::new()
+                    .name(format!("solXdpLoadGen{i:02}"))
+                    .spawn(move || generate(sender, addrs, payload, queue_rate, &gen_stats, &exit))
+                    .unwrap(),
+            );
+        }
+        drop(drop_sender);
+
+        let drop_thread = Builder::new()
+            .name("solXdpLoadDrop".to_owned())
+            .spawn(move || drain(drop_receiver))
+            .unwrap();
+
+        let start = Instant::now();
+        let mut last = Snapshot::default();
+        let mut last_report = start;
+        while start.elapsed() < duration {
+            thread::sleep(REPORT_INTERVAL.min(duration.saturating_sub(start.elapsed())));
+            let now = Instant::now();
+            let snapshot = Snapshot::new(&tx_stats, &gen_stats);
+            snapshot.report(&last, now.duration_since(last_report));
+            last = snapshot;
+            last_report = now;
+        }
+
+        exit.store(true, Ordering::Relaxed);
+        for thread in gen_threads {
+            thread.join().unwrap();
+        }
+        // the tx loops exit once they've drained their channels
+        for thread in tx_threads {
+            thread.join().unwrap();
+        }
+        drop_thread.join().unwrap();
+        drop(ebpf);
+
+        let elapsed = start.elapsed();
+        let total = Snapshot::new(&tx_stats, &gen_stats);
+        println!("total over {:.1}s:", elapsed.as_secs_f64());
+        total.report(&Snapshot::default(), elapsed);
+        println!(
+            "max completion latency {}us",
+            tx_stats.max_completion_latency_us.load(Ordering::Relaxed)
+        );
+        println!("xdp socket statistics {:?}", tx_stats.socket.load());
+    }
+
+    fn generate(
+        sender: Sender<Item>,
+        addrs: Vec<Arc<[SocketAddr]>>,
+        payload: Arc<[u8]>,
+        rate: u64,
+        stats: &GeneratorStats,
+        exit: &AtomicBool,
+    ) {
+        let start = Instant::now();
+        let mut sent = 0u64;
+        let mut next = 0;
+        while !exit.load(Ordering::Relaxed) {
+            if rate > 0 {
+                let due = (start.elapsed().as_nanos() * rate as u128 / 1_000_000_000) as u64;
+                if sent >= due {
+                    thread::sleep(Duration::from_micros(50));
+                    continue;
+                }
+            }
+
+            let dests = Arc::clone(&addrs[next]);
+            next = (next + 1) % addrs.len();
+            let count = dests.len() as u64;
+            match sender.try_send((dests, Arc::clone(&payload))) {
+                Ok(()) => {
+                    stats.queued.fetch_add(count, Ordering::Relaxed);
+                }
+                Err(TrySendError::Full(_)) => {
+                    stats.channel_full.fetch_add(count, Ordering::Relaxed);
+                    if rate == 0 {
+                        // we're going as fast as we can, let the tx loop catch up
+                        thread::yield_now();
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => break,
+            }
+            sent += count;
+        }
+    }
+
+    fn drain(receiver: Receiver<Item>) {
+        loop {
+            match receiver.try_recv() {
+                Ok(item) => drop(item),
+                Err(TryRecvError::Empty) => thread::sleep(Duration::from_millis(1)),
+                Err(TryRecvError::Disconnected) => break,
+            }
+        }
+    }
+
+    fn parse_mac(s: &str) -> Option<MacAddress> {
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in mac.iter_mut() {
+            *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+        }
+        parts.next().is_none().then_some(MacAddress(mac))
+    }
+
+    #[derive(Default)]
+    struct Snapshot {
+        queued: u64,
+        channel_full: u64,
+        sent: u64,
+        unroutable: u64,
+        ring_full: u64,
+        completed: u64,
+        completion_latency_us: u64,
+        kicks: u64,
+        socket: XdpStatistics,
+    }
+
+    impl Snapshot {
+        fn new(tx: &TxLoopStats, generator: &GeneratorStats) -> Self {
+            Self {
+                queued: generator.queued.load(Ordering::Relaxed),
+                channel_full: generator.channel_full.load(Ordering::Relaxed),
+                sent: tx.packets_sent.load(Ordering::Relaxed),
+                unroutable: tx.packets_dropped.load(Ordering::Relaxed),
+                ring_full: tx.ring_full_drops.load(Ordering::Relaxed)
+                    + tx.priority_ring_full_drops.load(Ordering::Relaxed),
+                completed: tx.packets_completed.load(Ordering::Relaxed),
+                completion_latency_us: tx.completion_latency_us.load(Ordering::Relaxed),
+                kicks: tx.kicks.load(Ordering::Relaxed),
+                socket: tx.socket.load(),
+            }
+        }
+
+        fn report(&self, prev: &Snapshot, elapsed: Duration) {
+            let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+            let completed = self.completed - prev.completed;
+            let avg_latency_us = (self.completion_latency_us - prev.completion_latency_us)
+                .checked_div(completed)
+                .unwrap_or(0);
+            println!(
+                "queued {:.0} pps sent {:.0} pps completed {:.0} pps kicks {:.0}/s | dropped: \
+                 channel full {} unroutable {} ring full {} | avg completion latency \
+                 {avg_latency_us}us | socket: tx invalid descs {} tx ring empty {}",
+                (self.queued - prev.queued) as f64 / secs,
+                (self.sent - prev.sent) as f64 / secs,
+                completed as f64 / secs,
+                (self.kicks - prev.kicks) as f64 / secs,
+                self.channel_full - prev.channel_full,
+                self.unroutable - prev.unroutable,
+                self.ring_full - prev.ring_full,
+                self.socket.tx_invalid_descs - prev.socket.tx_invalid_descs,
+                self.socket.tx_ring_empty_descs - prev.socket.tx_ring_empty_descs,
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Configures a NIC and the interrupts of its queues for the XDP rx and tx loops, and prints the
+//! CPUs the loops must be pinned to. Given an interface and a CPU budget it sets the channel count,
+//! steers RSS and the given ports to the rx queues, and routes the interrupts of each queue to a
+//! CPU next to the queue's worker, see [`agave_xdp::tuner`].
+//!
+//! The settings can also be read from a tuning configuration file with `--config`, see
+//! [`agave_xdp::config`].
+//!
+//! With `--dry-run` the plan is printed and nothing is changed.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    linux::main()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("agave-net-tuner is only supported on Linux");
+    std::process::exit(1);
+}
+
+#[cfg(target_os = "linux")]
+#[allow(deprecated, clippy::arithmetic_side_effects)]
+mod linux {
+    use {
+        agave_cpu_utils::parse_cpu_range_list,
+        agave_xdp::{
+            config::TuningConfig,
+            device::NetworkDevice,
+            tuner::{apply_tuning, plan_tuning, TunerConfig},
+        },
+        clap::{crate_description, crate_version, value_t_or_exit, values_t_or_exit, App, Arg},
+        std::process::exit,
+    };
+
+    pub fn main() {
+        agave_logger::setup_with_default("info");
+
+        let matches = App::new("agave-net-tuner")
+            .about(crate_description!())
+            .version(crate_version!())
+            .arg(
+                Arg::with_name("interface")
+                    .long("interface")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .help("Interface to tune [default: interface of the default route]"),
+            )
+            .arg(
+                Arg::with_name("config")
+                    .long("config")
+                    .value_name("FILE")
+                    .takes_value(true)
+                    .conflicts_with_all(&["cpus", "rx_queues", "tx_queues", "rx_port"])
+                    .help("Tuning configuration file to read the settings from, TOML or YAML"),
+            )
+            .arg(
+                Arg::with_name("cpus")
+                    .long("cpus")
+                    .value_name("CPUS")
+                    .takes_value(true)
+                    .required_unless("config")
+                    .help(
+                        "CPUs the loops and the interrupts of their queues may use, two per \
+                         queue, e.g. 2-9,18-25",
+                    ),
+            )
+            .arg(
+                Arg::with_name("rx_queues")
+                    .long("rx-queues")
+                    .value_name("COUNT")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("Number of rx loops"),
+            )
+            .arg(
+                Arg::with_name("tx_queues")
+                    .long("tx-queues")
+                    .value_name("COUNT")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("Number of tx loops"),
+            )
+            .arg(
+                Arg::with_name("rx_port")
+                    .long("rx-port")
+                    .value_name("PORT")
+                    .takes_value(true)
+                    .multiple(true)
+                    .help(
+                        "UDP port to steer to the rx queues with an n-tuple filter. May be \
+                         specified multiple times",
+                    ),
+            )
+            .arg(
+                Arg::with_name("dry_run")
+                    .long("dry-run")
+                    .help("Print the plan without changing anything"),
+            )
+            .get_matches();
+
+        let (config, config_interface) = match matches.value_of("config") {
+            Some(path) => {
+                let tuning = TuningConfig::load(path).unwrap_or_else(|e| {
+                    eprintln!("invalid tuning configuration {path}: {e}");
+                    exit(1);
+                });
+                match tuning.cpu.missing_isolated_cpus() {
+                    Ok(missing) if !missing.is_empty() => {
+                        eprintln!(
+                            "warning: cpus {missing:?} are expected to be isolated but aren't"
+                        );
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("warning: can't check the isolated cpus: {e}"),
+                }
+                // validated by load
+                (tuning.xdp.tuner_config().unwrap(), tuning.xdp.interface)
+            }
+            None => {
+                let cpus = matches.value_of("cpus").unwrap();
+                let cpus = parse_cpu_range_list(cpus).unwrap_or_else(|e| {
+                    eprintln!("invalid CPU list {cpus}: {e}");
+                    exit(1);
+                });
+                let config = TunerConfig {
+                    rx_queues: value_t_or_exit!(matches, "rx_queues", usize),
+                    tx_queues: value_t_or_exit!(matches, "tx_queues", usize),
+                    cpus,
+                    rx_ports: if matches.is_present("rx_port") {
+                        values_t_or_exit!(matches, "rx_port", u16)
+                    } else {
+                        Vec::new()
+                    },
+                };
+                (config, None)
+            }
+        };
+        if config.rx_queues + config.tx_queues == 0 {
+            eprintln!("nothing to tune, pass --rx-queues and/or --tx-queues");
+            exit(1);
+        }
+
+        let dev = match matches
+            .value_of("interface")
+            .or(config_interface.as_deref())
+        {
+            Some(interface) => NetworkDevice::new(interface),
+            None => NetworkDevice::new_from_default_route(),
+        }
+        .expect("failed to open network device");
+
+        let plan = plan_tuning(&dev, &config).unwrap_or_else(|e| {
+            eprintln!("failed to plan the tuning of {}: {e}", dev.name());
+            exit(1);
+        });
+        println!("{plan}");
+        if matches.is_present("dry_run") {
+            return;
+        }
+
+        match apply_tuning(&dev, &plan) {
+            Ok(misalignments) if misalignments.is_empty() => {
+                println!("applied");
+            }
+            Ok(misalignments) => {
+                println!("applied, but some queues are still misaligned:");
+                for misalignment in misalignments {
+                    println!("  {misalignment:?}");
+                }
+                exit(2);
+            }
+            Err(e) => {
+                eprintln!("failed to tune {}: {e}", dev.name());
+                exit(1);
+            }
+        }
+    }
+}
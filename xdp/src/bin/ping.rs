@@ -0,0 +1,393 @@
+//! Measures the round trip time of the XDP datapath. Probes carrying a sequence number and a send
+//! timestamp are sent through the tx loop to a reflector, either a UDP echo server or another
+//! instance running with `--reflect`, and the echoes are timed as they come back. Used to measure
+//! the latency the datapath adds in isolation from the validator.
+//!
+//! Echoes are received through a regular socket, so the reported RTTs include one kernel receive
+//! path on each end, which is constant for a given host and can be measured with a plain UDP ping.
+
+#[cfg(target_os = "linux")]
+fn main() {
+    linux::main()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("agave-xdp-ping is only supported on Linux");
+    std::process::exit(1);
+}
+
+#[cfg(target_os = "linux")]
+#[allow(deprecated, clippy::arithmetic_side_effects)]
+mod linux {
+    use {
+        agave_xdp::{
+            device::{NetworkDevice, QueueId},
+            load_xdp_program,
+            netlink::MacAddress,
+            tx_loop::{tx_loop, TxLoopConfig, TxLoopStats},
+        },
+        clap::{crate_description, crate_version, value_t, value_t_or_exit, App, Arg},
+        crossbeam_channel::Sender,
+        std::{
+            collections::HashSet,
+            io::ErrorKind,
+            net::{Ipv4Addr, SocketAddr, UdpSocket},
+            sync::{
+                atomic::{AtomicBool, Ordering},
+                Arc,
+            },
+            thread::{self, Builder},
+            time::{Duration, Instant},
+        },
+    };
+
+    type Item = ([SocketAddr; 1], Vec<u8>);
+
+    const CHANNEL_CAP: usize = 1024;
+    // magic, sequence number, send time in nanoseconds
+    const PROBE_HEADER_SIZE: usize = 24;
+    const MAGIC: [u8; 8] = *b"agvping\0";
+    const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+    pub fn main() {
+        agave_logger::setup_with_default("info");
+
+        let matches = App::new("agave-xdp-ping")
+            .about(crate_description!())
+            .version(crate_version!())
+            .arg(
+                Arg::with_name("interface")
+                    .long("interface")
+                    .value_name("NAME")
+                    .takes_value(true)
+                    .help("Interface to send from [default: interface of the default route]"),
+            )
+            .arg(
+                Arg::with_name("cpu")
+                    .long("cpu")
+                    .value_name("CPU")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("CPU to run the tx loop on"),
+            )
+            .arg(
+                Arg::with_name("queue")
+                    .long("queue")
+                    .value_name("QUEUE")
+                    .takes_value(true)
+                    .default_value("0")
+                    .help("NIC queue to send on"),
+            )
+            .arg(
+                Arg::with_name("zero_copy")
+                    .long("zero-copy")
+                    .help("Load the XDP program and bind the socket in zero copy mode"),
+            )
+            .arg(
+                Arg::with_name("reflect")
+                    .long("reflect")
+                    .help("Echo the probes received on --src-port back through XDP"),
+            )
+            .arg(
+                Arg::with_name("dest")
+                    .long("dest")
+                    .value_name("IP:PORT")
+                    .takes_value(true)
+                    .required_unless("reflect")
+                    .conflicts_with("reflect")
+                    .help("Address of the reflector"),
+            )
+            .arg(
+                Arg::with_name("count")
+                    .long("count")
+                    .value_name("COUNT")
+                    .takes_value(true)
+                    .default_value("1000")
+                    .help("Number of probes to send"),
+            )
+            .arg(
+                Arg::with_name("interval")
+                    .long("interval")
+                    .value_name("MICROS")
+                    .takes_value(true)
+                    .default_value("1000")
+                    .help("Time between probes"),
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .value_name("MILLIS")
+                    .takes_value(true)
+                    .default_value("1000")
+                    .help("How long to wait for echoes after the last probe is sent"),
+            )
+            .arg(
+                Arg::with_name("packet_size")
+                    .long("packet-size")
+                    .value_name("BYTES")
+                    .takes_value(true)
+                    .default_value("64")
+                    .help("UDP payload size of the probes, at least 24"),
+            )
+            .arg(
+                Arg::with_name("src_ip")
+                    .long("src-ip")
+                    .value_name("IP")
+                    .takes_value(true)
+                    .help("Source address [default: address of the interface]"),
+            )
+            .arg(
+                Arg::with_name("src_port")
+                    .long("src-port")
+                    .value_name("PORT")
+                    .takes_value(true)
+                    .default_value("9001")
+                    .help("Port to send from and receive echoes on"),
+            )
+            .arg(
+                Arg::with_name("dest_mac")
+                    .long("dest-mac")
+                    .value_name("MAC")
+                    .takes_value(true)
+                    .help(
+                        "Destination MAC address, for example the gateway's. Bypasses route and \
+                         neighbor lookups",
+                    ),
+            )
+            .get_matches();
+
+        let cpu_id = value_t_or_exit!(matches, "cpu", usize);
+        let queue_id = QueueId(value_t_or_exit!(matches, "queue", u64));
+        let zero_copy = matches.is_present("zero_copy");
+        let src_ip = value_t!(matches, "src_ip", Ipv4Addr).ok();
+        let src_port = value_t_or_exit!(matches, "src_port", u16);
+        let dest_mac = matches.value_of("dest_mac").map(|mac| {
+            parse_mac(mac).unwrap_or_else(|| {
+                eprintln!("invalid MAC address: {mac}");
+                std::process::exit(1);
+            })
+        });
+        let packet_size = value_t_or_exit!(matches, "packet_size", usize).max(PROBE_HEADER_SIZE);
+
+        let dev = Arc::new(
+            match matches.value_of("interface") {
+                Some(interface) => NetworkDevice::new(interface),
+                None => NetworkDevice::new_from_default_route(),
+            }
+            .expect("failed to open network device"),
+        );
+        let ebpf = zero_copy.then(|| load_xdp_program(&dev).expect("failed to load XDP program"));
+
+        // echoes come back through the kernel, on the port the probes are sent from
+        let socket = bind(src_ip, src_port);
+        socket.set_read_timeout(Some(POLL_TIMEOUT)).unwrap();
+
+        let tx_stats = Arc::new(TxLoopStats::default());
+        let (sender, receiver) = crossbeam_channel::bounded::<Item>(CHANNEL_CAP);
+        // probes the tx loop can't send are lost, which shows up as loss
+        let (drop_sender, _drop_receiver) = crossbeam_channel::unbounded();
+        let config = TxLoopConfig {
+            stats: Some(Arc::clone(&tx_stats)),
+            ..TxLoopConfig::default()
+        };
+        let tx_thread = {
+            let dev = Arc::clone(&dev);
+            Builder::new()
+                .name("solXdpPingTx".to_owned())
+                .spawn(move || {
+                    tx_loop(
+                        cpu_id,
+                        &dev,
+                        queue_id,
+                        zero_copy,
+                        None,
+                        src_ip,
+                        src_port,
+                        dest_mac,
+                        receiver,
+                        None,
+                        drop_sender,
+                        config,
+                    )
+                })
+                .unwrap()
+        };
+
+        if matches.is_present("reflect") {
+            reflect(&socket, &sender);
+        } else {
+            let dest = value_t_or_exit!(matches, "dest", SocketAddr);
+            let count = value_t_or_exit!(matches, "count", u64);
+            let interval = Duration::from_micros(value_t_or_exit!(matches, "interval", u64));
+            let timeout = Duration::from_millis(value_t_or_exit!(matches, "timeout", u64));
+
+            let start = Instant::now();
+            let exit = Arc::new(AtomicBool::new(false));
+            let rx_thread = {
+                let exit = Arc::clone(&exit);
+                Builder::new()
+                    .name("solXdpPingRx".to_owned())
+                    .spawn(move || receive(&socket, start, &exit))
+                    .unwrap()
+            };
+            probe(&sender, dest, count, interval, packet_size, start);
+            thread::sleep(timeout);
+            exit.store(true, Ordering::Relaxed);
+            let echoes = rx_thread.join().unwrap();
+            echoes.report(count);
+        }
+
+        drop(sender);
+        tx_thread.join().unwrap();
+        drop(ebpf);
+        println!(
+            "tx loop: sent {} dropped {} ring full {} max completion latency {}us",
+            tx_stats.packets_sent.load(Ordering::Relaxed),
+            tx_stats.packets_dropped.load(Ordering::Relaxed),
+            tx_stats.ring_full_drops.load(Ordering::Relaxed),
+            tx_stats.max_completion_latency_us.load(Ordering::Relaxed),
+        );
+    }
+
+    // the probes are sent through XDP, the socket only receives
+    #[allow(clippy::disallowed_methods)]
+    fn bind(src_ip: Option<Ipv4Addr>, port: u16) -> UdpSocket {
+        UdpSocket::bind((src_ip.unwrap_or(Ipv4Addr::UNSPECIFIED), port))
+            .unwrap_or_else(|e| panic!("failed to bind port {port}: {e}"))
+    }
+
+    fn probe(
+        sender: &Sender<Item>,
+        dest: SocketAddr,
+        count: u64,
+        interval: Duration,
+        packet_size: usize,
+        start: Instant,
+    ) {
+        for seq in 0..count {
+            // pace against the start so that a late probe doesn't delay the following ones
+            let due = interval.saturating_mul(u32::try_from(seq).unwrap_or(u32::MAX));
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+            let mut payload = vec![0; packet_size];
+            payload[..8].copy_from_slice(&MAGIC);
+            payload[8..16].copy_from_slice(&seq.to_le_bytes());
+            let sent_ns = start.elapsed().as_nanos() as u64;
+            payload[16..24].copy_from_slice(&sent_ns.to_le_bytes());
+            if sender.send(([dest], payload)).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn receive(socket: &UdpSocket, start: Instant, exit: &AtomicBool) -> Echoes {
+        let mut echoes = Echoes::default();
+        let mut seen = HashSet::new();
+        let mut buf = [0u8; 2048];
+        while !exit.load(Ordering::Relaxed) {
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(e) => panic!("failed to receive echoes: {e}"),
+            };
+            let now_ns = start.elapsed().as_nanos() as u64;
+            let Some((seq, sent_ns)) = parse_probe(&buf[..len]) else {
+                echoes.invalid += 1;
+                continue;
+            };
+            if !seen.insert(seq) {
+                echoes.duplicates += 1;
+                continue;
+            }
+            echoes.rtts_ns.push(now_ns.saturating_sub(sent_ns));
+        }
+        echoes
+    }
+
+    // echoes the probes received on `socket` back to where they came from
+    fn reflect(socket: &UdpSocket, sender: &Sender<Item>) {
+        log::info!(
+            "reflecting probes received on {}",
+            socket.local_addr().unwrap()
+        );
+        let mut buf = [0u8; 2048];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue
+                }
+                Err(e) => panic!("failed to receive probes: {e}"),
+            };
+            if parse_probe(&buf[..len]).is_none() {
+                continue;
+            }
+            if sender.send(([from], buf[..len].to_vec())).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn parse_probe(payload: &[u8]) -> Option<(u64, u64)> {
+        if payload.len() < PROBE_HEADER_SIZE || payload[..8] != MAGIC {
+            return None;
+        }
+        let seq = u64::from_le_bytes(payload[8..16].try_into().unwrap());
+        let sent_ns = u64::from_le_bytes(payload[16..24].try_into().unwrap());
+        Some((seq, sent_ns))
+    }
+
+    fn parse_mac(s: &str) -> Option<MacAddress> {
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in mac.iter_mut() {
+            *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+        }
+        parts.next().is_none().then_some(MacAddress(mac))
+    }
+
+    #[derive(Default)]
+    struct Echoes {
+        rtts_ns: Vec<u64>,
+        duplicates: u64,
+        // packets received on the port that aren't probes
+        invalid: u64,
+    }
+
+    impl Echoes {
+        fn report(mut self, sent: u64) {
+            let received = self.rtts_ns.len() as u64;
+            let loss = sent.saturating_sub(received) as f64 * 100.0 / sent.max(1) as f64;
+            println!(
+                "{sent} probes sent, {received} echoes received, {loss:.2}% loss, {} duplicates, \
+                 {} invalid",
+                self.duplicates, self.invalid
+            );
+            if self.rtts_ns.is_empty() {
+                return;
+            }
+            self.rtts_ns.sort_unstable();
+            let rtts = &self.rtts_ns;
+            let us = |ns: u64| ns as f64 / 1_000.0;
+            println!(
+                "rtt min {:.1}us p50 {:.1}us p90 {:.1}us p99 {:.1}us p99.9 {:.1}us max {:.1}us",
+                us(rtts[0]),
+                us(percentile(rtts, 50.0)),
+                us(percentile(rtts, 90.0)),
+                us(percentile(rtts, 99.0)),
+                us(percentile(rtts, 99.9)),
+                us(rtts[rtts.len() - 1]),
+            );
+        }
+    }
+
+    // nearest rank percentile of a sorted, non empty slice
+    fn percentile(sorted: &[u64], p: f64) -> u64 {
+        let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+        sorted[rank.clamp(1, sorted.len()) - 1]
+    }
+}
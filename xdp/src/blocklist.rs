@@ -0,0 +1,242 @@
+//! Blocking destinations at runtime.
+//!
+//! A [`DestinationBlocklist`] given to the tx loops through
+//! [`TxLoopConfig::blocklist`](crate::tx_loop::TxLoopConfig::blocklist) makes them drop the
+//! packets to the blocked addresses and prefixes instead of sending them. Operators keep a clone
+//! to block a peer that's blackholing traffic, or a misconfigured address that's amplifying it,
+//! while the loops are running, and to see how many packets were suppressed.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    std::{
+        fmt,
+        net::{Ipv4Addr, SocketAddr},
+        str::FromStr,
+        sync::{
+            atomic::{AtomicU64, AtomicUsize, Ordering},
+            Arc, RwLock,
+        },
+    },
+    thiserror::Error,
+};
+
+/// A block of IPv4 addresses, e.g. `10.1.0.0/16`. A single address is a `/32`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Ipv4Prefix {
+    addr: Ipv4Addr,
+    len: u8,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("invalid IPv4 prefix {0}")]
+pub struct InvalidPrefix(String);
+
+impl Ipv4Prefix {
+    /// Returns the prefix of the first `len` bits of `addr`. The other bits are cleared.
+    pub fn new(addr: Ipv4Addr, len: u8) -> Result<Self, InvalidPrefix> {
+        if len > 32 {
+            return Err(InvalidPrefix(format!("{addr}/{len}")));
+        }
+        Ok(Self {
+            addr: Ipv4Addr::from(u32::from(addr) & Self::mask(len)),
+            len,
+        })
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & Self::mask(self.len) == u32::from(self.addr)
+    }
+
+    fn mask(len: u8) -> u32 {
+        u32::MAX.checked_shl(32 - u32::from(len)).unwrap_or(0)
+    }
+}
+
+impl From<Ipv4Addr> for Ipv4Prefix {
+    fn from(addr: Ipv4Addr) -> Self {
+        Self { addr, len: 32 }
+    }
+}
+
+impl FromStr for Ipv4Prefix {
+    type Err = InvalidPrefix;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidPrefix(s.to_owned());
+        match s.split_once('/') {
+            Some((addr, len)) => Self::new(
+                addr.parse().map_err(|_| invalid())?,
+                len.parse().map_err(|_| invalid())?,
+            ),
+            None => Ok(Self::from(s.parse::<Ipv4Addr>().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+struct Entry {
+    prefix: Ipv4Prefix,
+    suppressed: AtomicU64,
+}
+
+#[derive(Default)]
+struct BlocklistInner {
+    entries: RwLock<Vec<Entry>>,
+    // the length of entries, so that an empty list costs a single load
+    len: AtomicUsize,
+    suppressed: AtomicU64,
+}
+
+/// The destinations the tx loops must not send to, shared by all of them.
+///
+/// Cloning returns a handle to the same list. Changes take effect on the next packet.
+#[derive(Clone, Default)]
+pub struct DestinationBlocklist {
+    inner: Arc<BlocklistInner>,
+}
+
+impl DestinationBlocklist {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Blocks the destinations in `prefix`. Returns false if it was already blocked.
+    pub fn block(&self, prefix: impl Into<Ipv4Prefix>) -> bool {
+        let prefix = prefix.into();
+        let mut entries = self.inner.entries.write().unwrap();
+        if entries.iter().any(|entry| entry.prefix == prefix) {
+            return false;
+        }
+        log::warn!("blocking destinations {prefix}");
+        entries.push(Entry {
+            prefix,
+            suppressed: AtomicU64::new(0),
+        });
+        self.inner.len.store(entries.len(), Ordering::Relaxed);
+        true
+    }
+
+    /// Unblocks `prefix`, which must have been blocked as is. Returns false if it wasn't blocked.
+    pub fn unblock(&self, prefix: impl Into<Ipv4Prefix>) -> bool {
+        let prefix = prefix.into();
+        let mut entries = self.inner.entries.write().unwrap();
+        let Some(index) = entries.iter().position(|entry| entry.prefix == prefix) else {
+            return false;
+        };
+        let entry = entries.swap_remove(index);
+        log::warn!(
+            "unblocking destinations {prefix} after suppressing {} packets",
+            entry.suppressed.load(Ordering::Relaxed)
+        );
+        self.inner.len.store(entries.len(), Ordering::Relaxed);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.len.load(Ordering::Relaxed) == 0
+    }
+
+    /// Returns the blocked prefixes, with how many packets each suppressed.
+    pub fn blocked(&self) -> Vec<(Ipv4Prefix, u64)> {
+        self.inner
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .map(|entry| (entry.prefix, entry.suppressed.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Returns how many packets were suppressed since the list was created, including by prefixes
+    /// unblocked since.
+    pub fn suppressed(&self) -> u64 {
+        self.inner.suppressed.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether `addr` is blocked, counting a suppressed packet if it is.
+    #[inline]
+    pub fn suppress(&self, addr: &SocketAddr) -> bool {
+        !self.is_empty() && self.suppress_slow(addr)
+    }
+
+    #[inline(never)]
+    fn suppress_slow(&self, addr: &SocketAddr) -> bool {
+        let SocketAddr::V4(addr) = addr else {
+            return false;
+        };
+        let entries = self.inner.entries.read().unwrap();
+        // the most specific prefix gets the count
+        let Some(entry) = entries
+            .iter()
+            .filter(|entry| entry.prefix.contains(*addr.ip()))
+            .max_by_key(|entry| entry.prefix.len)
+        else {
+            return false;
+        };
+        entry.suppressed.fetch_add(1, Ordering::Relaxed);
+        self.inner.suppressed.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+}
+
+impl fmt::Debug for DestinationBlocklist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.blocked()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::net::SocketAddrV4};
+
+    #[test]
+    fn test_ipv4_prefix() {
+        let prefix = "10.1.2.3/16".parse::<Ipv4Prefix>().unwrap();
+        assert_eq!(prefix.to_string(), "10.1.0.0/16");
+        assert!(prefix.contains(Ipv4Addr::new(10, 1, 255, 1)));
+        assert!(!prefix.contains(Ipv4Addr::new(10, 2, 0, 1)));
+        assert!("0.0.0.0/0"
+            .parse::<Ipv4Prefix>()
+            .unwrap()
+            .contains(Ipv4Addr::BROADCAST));
+        assert_eq!(
+            "10.0.0.1".parse::<Ipv4Prefix>().unwrap(),
+            Ipv4Prefix::from(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert!("10.0.0.0/33".parse::<Ipv4Prefix>().is_err());
+        assert!("10.0.0/8".parse::<Ipv4Prefix>().is_err());
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let addr = |a, b| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 1, a, b), 8000));
+        let blocklist = DestinationBlocklist::new();
+        assert!(!blocklist.suppress(&addr(0, 1)));
+
+        let handle = blocklist.clone();
+        let subnet = "10.1.0.0/16".parse::<Ipv4Prefix>().unwrap();
+        assert!(handle.block(subnet));
+        assert!(!handle.block(subnet));
+        assert!(handle.block(Ipv4Addr::new(10, 1, 0, 1)));
+        assert!(blocklist.suppress(&addr(0, 1)));
+        assert!(blocklist.suppress(&addr(7, 7)));
+        assert!(!blocklist.suppress(&"10.2.0.1:8000".parse().unwrap()));
+        assert_eq!(
+            handle.blocked(),
+            [
+                (subnet, 1),
+                (Ipv4Prefix::from(Ipv4Addr::new(10, 1, 0, 1)), 1)
+            ]
+        );
+
+        assert!(handle.unblock(subnet));
+        assert!(!handle.unblock(subnet));
+        assert!(!blocklist.suppress(&addr(7, 7)));
+        assert_eq!(handle.suppressed(), 2);
+    }
+}
@@ -0,0 +1,268 @@
+//! In-memory capture of the most recent frames.
+//!
+//! The [pcap tap](crate::pcap::PcapTap) writes every captured frame to a file, which costs too
+//! much to leave on and is only turned on once something went wrong, after the frames of interest
+//! are gone. A [`CaptureRing`] instead keeps the start of the last frames sent and received by each
+//! loop in memory, overwriting the oldest ones, and writes them to a pcap file on demand with
+//! [`CaptureRing::dump`]. Recording a frame is a copy of its headers into a preallocated slot.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{device::QueueId, pcap::PcapWriter},
+    std::{
+        fmt,
+        fs::File,
+        io::{self, BufWriter, Write},
+        path::Path,
+        sync::{Arc, Mutex, RwLock},
+        time::SystemTime,
+    },
+};
+
+/// Default number of frames kept for each loop.
+pub const DEFAULT_CAPTURE_FRAMES: usize = 4096;
+/// Default number of bytes kept for each frame, enough for the ethernet, VLAN, IP and UDP headers
+/// and the start of the payload.
+pub const DEFAULT_CAPTURE_SNAPLEN: u32 = 128;
+
+/// Whether a frame was sent or received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+struct Slot {
+    timestamp: SystemTime,
+    orig_len: u32,
+    len: usize,
+    data: Box<[u8]>,
+}
+
+struct QueueRing {
+    direction: Direction,
+    queue_id: QueueId,
+    slots: Vec<Slot>,
+    // where the next frame goes
+    next: usize,
+    // the slots in use, which stays at the capacity once the ring wrapped around
+    len: usize,
+}
+
+struct CaptureRingInner {
+    frames: usize,
+    snaplen: usize,
+    queues: Mutex<Vec<Arc<Mutex<QueueRing>>>>,
+}
+
+/// The recent frames of all the loops given a [`recorder`](CaptureRing::recorder).
+///
+/// Clones share the same frames.
+#[derive(Clone)]
+pub struct CaptureRing {
+    inner: Arc<CaptureRingInner>,
+}
+
+impl fmt::Debug for CaptureRing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureRing")
+            .field("frames", &self.inner.frames)
+            .field("snaplen", &self.inner.snaplen)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CaptureRing {
+    /// Creates a ring keeping the first `snaplen` bytes of the last `frames` frames of each loop.
+    pub fn new(frames: usize, snaplen: u32) -> Self {
+        Self {
+            inner: Arc::new(CaptureRingInner {
+                frames: frames.max(1),
+                snaplen: snaplen as usize,
+                queues: Mutex::default(),
+            }),
+        }
+    }
+
+    /// Returns the recorder of a loop.
+    ///
+    /// Each loop records into its own slots, so loops never wait on each other. The memory for
+    /// the slots is allocated here.
+    pub fn recorder(&self, direction: Direction, queue_id: QueueId) -> CaptureRecorder {
+        let slots = (0..self.inner.frames)
+            .map(|_| Slot {
+                timestamp: SystemTime::UNIX_EPOCH,
+                orig_len: 0,
+                len: 0,
+                data: vec![0; self.inner.snaplen].into_boxed_slice(),
+            })
+            .collect();
+        let ring = Arc::new(Mutex::new(QueueRing {
+            direction,
+            queue_id,
+            slots,
+            next: 0,
+            len: 0,
+        }));
+        self.inner.queues.lock().unwrap().push(Arc::clone(&ring));
+        CaptureRecorder { ring }
+    }
+
+    /// Writes the frames of all the loops to a new pcap file at `path`, oldest first. Returns the
+    /// number of frames written. Fails if `path` already exists.
+    pub fn dump(&self, path: &Path) -> io::Result<usize> {
+        let mut file = BufWriter::new(File::create_new(path)?);
+        let frames = self.dump_to(&mut file)?;
+        file.flush()?;
+        Ok(frames)
+    }
+
+    /// Writes the frames of all the loops to `writer` in the pcap format, oldest first.
+    ///
+    /// The loops keep recording while the frames are written, so the frames of a loop are copied
+    /// out before writing them to keep it waiting as little as possible.
+    pub fn dump_to<W: Write>(&self, writer: W) -> io::Result<usize> {
+        let queues = self.inner.queues.lock().unwrap().clone();
+        let mut frames = Vec::new();
+        for ring in queues {
+            let ring = ring.lock().unwrap();
+            let oldest = (ring.next + ring.slots.len() - ring.len) % ring.slots.len();
+            frames.extend((0..ring.len).map(|i| {
+                let slot = &ring.slots[(oldest + i) % ring.slots.len()];
+                (
+                    slot.timestamp,
+                    slot.orig_len,
+                    slot.data[..slot.len].to_vec(),
+                )
+            }));
+        }
+        // stable, so frames recorded at the same time keep the order of their loop
+        frames.sort_by_key(|(timestamp, _, _)| *timestamp);
+
+        let mut writer = PcapWriter::new(writer, self.inner.snaplen as u32)?;
+        for (timestamp, orig_len, data) in &frames {
+            writer.write_frame_truncated(*timestamp, *orig_len, data)?;
+        }
+        writer.flush()?;
+        Ok(frames.len())
+    }
+
+    /// Returns the number of frames currently kept for each loop.
+    pub fn queues(&self) -> Vec<(Direction, QueueId, usize)> {
+        self.inner
+            .queues
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|ring| {
+                let ring = ring.lock().unwrap();
+                (ring.direction, ring.queue_id, ring.len)
+            })
+            .collect()
+    }
+}
+
+/// Records the frames of a loop into its [`CaptureRing`].
+pub struct CaptureRecorder {
+    ring: Arc<Mutex<QueueRing>>,
+}
+
+impl CaptureRecorder {
+    /// Keeps the start of `frame`, overwriting the oldest frame if the ring is full.
+    #[inline]
+    pub fn record(&mut self, frame: &[u8]) {
+        // only contended while the ring is dumped
+        let mut ring = self.ring.lock().unwrap();
+        let ring = &mut *ring;
+        let capacity = ring.slots.len();
+        let slot = &mut ring.slots[ring.next];
+        let len = frame.len().min(slot.data.len());
+        slot.data[..len].copy_from_slice(&frame[..len]);
+        slot.len = len;
+        slot.orig_len = frame.len() as u32;
+        slot.timestamp = SystemTime::now();
+        ring.next = (ring.next + 1) % capacity;
+        ring.len = (ring.len + 1).min(capacity);
+    }
+}
+
+static CAPTURE_RING: RwLock<Option<CaptureRing>> = RwLock::new(None);
+
+/// Makes `ring` the process wide ring, which the admin interface dumps.
+pub fn install_capture_ring(ring: CaptureRing) {
+    *CAPTURE_RING.write().unwrap() = Some(ring);
+}
+
+/// Returns the ring installed with [`install_capture_ring`], if any.
+pub fn capture_ring() -> Option<CaptureRing> {
+    CAPTURE_RING.read().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::pcap::PcapReader};
+
+    #[test]
+    fn test_capture_ring() {
+        let ring = CaptureRing::new(3, 4);
+        let mut tx = ring.recorder(Direction::Tx, QueueId(0));
+        let mut rx = ring.recorder(Direction::Rx, QueueId(1));
+        for i in 0..5u8 {
+            tx.record(&[i; 6]);
+        }
+        rx.record(&[9; 2]);
+        assert_eq!(
+            ring.queues(),
+            vec![
+                (Direction::Tx, QueueId(0), 3),
+                (Direction::Rx, QueueId(1), 1)
+            ]
+        );
+
+        // the oldest frames were overwritten and the others truncated to the snaplen
+        let mut buf = Vec::new();
+        assert_eq!(ring.dump_to(&mut buf).unwrap(), 4);
+        let records = PcapReader::new(&buf[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert!(records
+            .windows(2)
+            .all(|pair| pair[0].timestamp <= pair[1].timestamp));
+        let mut frames = records
+            .iter()
+            .map(|record| (record.orig_len, record.data.clone()))
+            .collect::<Vec<_>>();
+        frames.sort();
+        assert_eq!(
+            frames,
+            vec![
+                (2, vec![9; 2]),
+                (6, vec![2; 4]),
+                (6, vec![3; 4]),
+                (6, vec![4; 4])
+            ]
+        );
+
+        // dumping doesn't consume the frames
+        assert_eq!(ring.dump_to(io::sink()).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_capture_dump_new_file() {
+        let ring = CaptureRing::new(3, 4);
+        ring.recorder(Direction::Tx, QueueId(0)).record(&[1; 4]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("capture.pcap");
+        assert_eq!(ring.dump(&path).unwrap(), 1);
+
+        // an existing file is left alone
+        let err = ring.dump(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let records = PcapReader::new(File::open(&path).unwrap())
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 1);
+    }
+}
@@ -0,0 +1,339 @@
+//! One configuration file for the XDP datapath and the CPUs around it.
+//!
+//! The network settings, the queues, their CPUs, ring sizes and pacing, and the CPU settings, the
+//! thread profile and the CPUs expected to be isolated, depend on each other: the loops must not
+//! share CPUs with the pinned threads, and both usually sit on isolated CPUs. A [`TuningConfig`]
+//! holds them together, read from TOML or YAML, and is validated as a whole:
+//!
+//! ```toml
+//! [xdp]
+//! interface = "eth0"
+//! rx_queues = 2
+//! tx_queues = 2
+//! cpus = "2-5,34-37"
+//! rx_ports = [8001, 8002]
+//! tx_ring_size = 4096
+//!
+//! [xdp.pacing]
+//! global = { packets_per_second = 2000000, burst = 4096 }
+//! repair = { packets_per_second = 50000, burst = 512 }
+//!
+//! [cpu]
+//! isolated = "2-9,34-41"
+//!
+//! [cpu.profile]
+//! poh = "6"
+//! banking = "7-9"
+//! ```
+//!
+//! Every setting is optional. [`TunerConfig`], [`RingSizes`] and the limits of a
+//! [`TrafficShaper`] are derived from the `xdp` section, the [`CpuProfile`] of the `cpu` section is
+//! what threads are pinned with.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        device::RingSizes,
+        shaping::{RateLimit, TrafficShaper},
+        tuner::TunerConfig,
+    },
+    agave_cpu_utils::{parse_cpu_range_list, CpuAffinityError, CpuConfig, CpuProfile},
+    serde::{Deserialize, Serialize},
+    std::{collections::BTreeMap, fs, io, path::Path},
+    thiserror::Error,
+};
+
+/// The pacing key limiting all the traffic classes together.
+pub const GLOBAL_PACING: &str = "global";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("invalid TOML: {0}")]
+    Toml(#[from] toml::de::Error),
+
+    #[error("invalid YAML: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("unknown format of {0}, expected a .toml, .yaml or .yml file")]
+    UnknownFormat(String),
+
+    #[error("invalid cpu settings: {0}")]
+    Cpu(#[from] CpuAffinityError),
+
+    #[error("{0}")]
+    Invalid(String),
+}
+
+/// The XDP settings of a node.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct XdpSettings {
+    /// The interface to use, the interface of the default route if not set.
+    pub interface: Option<String>,
+    pub rx_queues: usize,
+    pub tx_queues: usize,
+    /// The CPU range list the loops and the interrupts of their queues may use, two per queue.
+    pub cpus: String,
+    /// UDP ports steered to the rx queues.
+    pub rx_ports: Vec<u16>,
+    /// The rx ring size, the driver's if not set. Must be a power of two.
+    pub rx_ring_size: Option<usize>,
+    /// The tx ring size, the driver's if not set. Must be a power of two.
+    pub tx_ring_size: Option<usize>,
+    pub zero_copy: bool,
+    /// The rate limit of each traffic class by label, and of all of them under
+    /// [`GLOBAL_PACING`].
+    pub pacing: BTreeMap<String, RateLimit>,
+}
+
+impl XdpSettings {
+    /// Returns the CPUs the loops may use.
+    pub fn cpus(&self) -> Result<Vec<usize>, ConfigError> {
+        Ok(parse_cpu_range_list(&self.cpus)?)
+    }
+
+    /// Returns the configuration of [`plan_tuning`](crate::tuner::plan_tuning).
+    pub fn tuner_config(&self) -> Result<TunerConfig, ConfigError> {
+        Ok(TunerConfig {
+            rx_queues: self.rx_queues,
+            tx_queues: self.tx_queues,
+            cpus: self.cpus()?,
+            rx_ports: self.rx_ports.clone(),
+        })
+    }
+
+    /// Returns the ring sizes, taking the ones that aren't set from `device`.
+    pub fn ring_sizes(&self, device: RingSizes) -> RingSizes {
+        RingSizes {
+            rx: self.rx_ring_size.unwrap_or(device.rx),
+            tx: self.tx_ring_size.unwrap_or(device.tx),
+        }
+    }
+
+    /// Sets the limits of `shaper` to the pacing settings.
+    ///
+    /// Fails, without changing anything, if a label isn't one of the classes of `shaper`.
+    pub fn apply_pacing(&self, shaper: &TrafficShaper) -> Result<(), ConfigError> {
+        let classes = shaper.classes();
+        let mut limits = Vec::with_capacity(self.pacing.len());
+        for (label, limit) in &self.pacing {
+            if label == GLOBAL_PACING {
+                limits.push((None, *limit));
+                continue;
+            }
+            let class = classes
+                .iter()
+                .find(|class| shaper.label(**class) == *label)
+                .ok_or_else(|| ConfigError::Invalid(format!("unknown traffic class {label}")))?;
+            limits.push((Some(*class), *limit));
+        }
+        for (class, limit) in limits {
+            match class {
+                Some(class) => shaper.set_limit(class, Some(limit)),
+                None => shaper.set_global_limit(Some(limit)),
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let cpus = self.cpus()?;
+        let queues = self.rx_queues + self.tx_queues;
+        if cpus.len() < 2 * queues {
+            return Err(ConfigError::Invalid(format!(
+                "{queues} queues need 2 cpus each, got {}",
+                cpus.len()
+            )));
+        }
+        if self.rx_queues == 0 && !self.rx_ports.is_empty() {
+            return Err(ConfigError::Invalid(format!(
+                "no rx queue to steer ports {:?} to",
+                self.rx_ports
+            )));
+        }
+        for (ring, size) in [("rx", self.rx_ring_size), ("tx", self.tx_ring_size)] {
+            if let Some(size) = size.filter(|size| !size.is_power_of_two()) {
+                return Err(ConfigError::Invalid(format!(
+                    "{ring} ring size {size} isn't a power of two"
+                )));
+            }
+        }
+        if let Some((label, _)) = self
+            .pacing
+            .iter()
+            .find(|(_, limit)| limit.packets_per_second == 0)
+        {
+            return Err(ConfigError::Invalid(format!(
+                "pacing of {label} must allow at least one packet per second"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The XDP and CPU settings of a node.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TuningConfig {
+    pub xdp: XdpSettings,
+    pub cpu: CpuConfig,
+}
+
+impl TuningConfig {
+    /// Reads and validates the configuration at `path`, in TOML or YAML depending on its
+    /// extension.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::from_toml(&s),
+            Some("yaml" | "yml") => Self::from_yaml(&s),
+            _ => Err(ConfigError::UnknownFormat(path.display().to_string())),
+        }
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, ConfigError> {
+        let config: Self = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_yaml(s: &str) -> Result<Self, ConfigError> {
+        let config: Self = serde_yaml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns the profile the threads are pinned with.
+    pub fn cpu_profile(&self) -> Result<CpuProfile, ConfigError> {
+        Ok(self.cpu.profile()?)
+    }
+
+    /// Checks the settings and that the loops and the pinned threads don't share CPUs.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.xdp.validate()?;
+        self.cpu.validate()?;
+        let cpus = self.xdp.cpus()?;
+        let profile = self.cpu_profile()?;
+        for (role, role_cpus) in profile.roles() {
+            if let Some(cpu) = role_cpus.iter().find(|cpu| cpus.contains(cpu)) {
+                return Err(ConfigError::Invalid(format!(
+                    "cpu {cpu} is used by both the xdp loops and the {role} threads"
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::shaping::TrafficClass};
+
+    const TOML: &str = r#"
+[xdp]
+interface = "eth0"
+rx_queues = 1
+tx_queues = 1
+cpus = "2-3,34-35"
+rx_ports = [8001]
+tx_ring_size = 4096
+
+[xdp.pacing]
+global = { packets_per_second = 2000000, burst = 4096 }
+repair = { packets_per_second = 50000, burst = 512 }
+
+[cpu]
+isolated = "2-9,34-41"
+
+[cpu.profile]
+poh = "6"
+banking = "7-9"
+"#;
+
+    #[test]
+    fn test_tuning_config() {
+        let config = TuningConfig::from_toml(TOML).unwrap();
+        assert_eq!(config.xdp.interface.as_deref(), Some("eth0"));
+        assert_eq!(config.xdp.tuner_config().unwrap().cpus, [2, 3, 34, 35]);
+        assert_eq!(
+            config.xdp.ring_sizes(RingSizes::default()),
+            RingSizes { rx: 1024, tx: 4096 }
+        );
+        assert_eq!(
+            config.cpu_profile().unwrap().cpus(CpuProfile::POH),
+            Some(&[6][..])
+        );
+
+        let shaper = TrafficShaper::new();
+        config.xdp.apply_pacing(&shaper).unwrap();
+        assert_eq!(
+            shaper.limit(TrafficClass::Repair),
+            Some(RateLimit {
+                packets_per_second: 50_000,
+                burst: 512
+            })
+        );
+        assert_eq!(shaper.global_limit().unwrap().burst, 4096);
+
+        // the same settings in YAML
+        let yaml = "
+xdp:
+  interface: eth0
+  rx_queues: 1
+  tx_queues: 1
+  cpus: 2-3,34-35
+  rx_ports: [8001]
+  tx_ring_size: 4096
+  pacing:
+    global: { packets_per_second: 2000000, burst: 4096 }
+    repair: { packets_per_second: 50000, burst: 512 }
+cpu:
+  isolated: 2-9,34-41
+  profile:
+    poh: '6'
+    banking: 7-9
+";
+        assert_eq!(TuningConfig::from_yaml(yaml).unwrap(), config);
+        assert_eq!(
+            TuningConfig::from_toml("").unwrap(),
+            TuningConfig::default()
+        );
+    }
+
+    #[test]
+    fn test_invalid_tuning_config() {
+        let invalid = |s: &str| matches!(TuningConfig::from_toml(s), Err(ConfigError::Invalid(_)));
+        // the loops and the banking threads both want cpu 3
+        assert!(invalid(
+            &TOML.replace("banking = \"7-9\"", "banking = \"3\"")
+        ));
+        assert!(invalid(
+            &TOML.replace("cpus = \"2-3,34-35\"", "cpus = \"2-3\"")
+        ));
+        assert!(invalid(
+            &TOML.replace("tx_ring_size = 4096", "tx_ring_size = 1000")
+        ));
+        assert!(invalid("[xdp]\nrx_ports = [8001]"));
+        assert!(matches!(
+            TuningConfig::from_toml("[xdp]\nqueues = 1"),
+            Err(ConfigError::Toml(_))
+        ));
+        assert!(matches!(
+            TuningConfig::from_toml("[cpu.profile]\npoh = \"x\""),
+            Err(ConfigError::Cpu(_))
+        ));
+
+        let shaper = TrafficShaper::new();
+        let config =
+            TuningConfig::from_toml("[xdp.pacing]\nbulk = { packets_per_second = 1, burst = 1 }")
+                .unwrap();
+        assert!(config.xdp.apply_pacing(&shaper).is_err());
+        let bulk = shaper.register_class("bulk");
+        config.xdp.apply_pacing(&shaper).unwrap();
+        assert_eq!(shaper.limit(bulk).unwrap().packets_per_second, 1);
+    }
+}
@@ -0,0 +1,233 @@
+//! Per-destination delivery accounting.
+//!
+//! The tx loops count the packets they submit, complete and drop for each destination in a local
+//! table and merge it into a shared [`DeliveryStats`] about once a second, so the hot path never
+//! takes a lock. Completion only means the NIC is done with a frame, not that the peer received
+//! it, but a destination whose packets keep getting dropped before reaching the NIC, e.g. because
+//! it's unroutable, is one we consistently fail to reach.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::umem::{FrameOffset, Umem},
+    std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::Mutex,
+        time::{Duration, Instant},
+    },
+};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Counters of the packets sent to a destination.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DestinationStats {
+    /// Packets written to the tx ring.
+    pub submitted: u64,
+    /// Packets the driver reported as completed.
+    pub completed: u64,
+    /// Packets dropped before reaching the ring, or abandoned in the ring when the socket was
+    /// recreated.
+    pub dropped: u64,
+}
+
+impl DestinationStats {
+    /// Returns the share of the packets sent to the destination that were dropped.
+    pub fn drop_rate(&self) -> f64 {
+        let total = self.submitted + self.dropped;
+        if total == 0 {
+            0.0
+        } else {
+            self.dropped as f64 / total as f64
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.submitted += other.submitted;
+        self.completed += other.completed;
+        self.dropped += other.dropped;
+    }
+}
+
+/// Delivery counters by destination, enabled with
+/// [`TxLoopConfig::delivery`](crate::tx_loop::TxLoopConfig::delivery).
+///
+/// The same instance can be shared by multiple tx loops. Destinations nothing was sent to for
+/// `expiry` are forgotten.
+#[derive(Debug)]
+pub struct DeliveryStats {
+    max_destinations: usize,
+    expiry: Duration,
+    destinations: Mutex<HashMap<SocketAddr, (DestinationStats, Instant)>>,
+}
+
+impl Default for DeliveryStats {
+    fn default() -> Self {
+        Self::new(65_536, Duration::from_secs(600))
+    }
+}
+
+impl DeliveryStats {
+    /// Creates an instance tracking up to `max_destinations` destinations. Once full, new
+    /// destinations are ignored until others expire.
+    pub fn new(max_destinations: usize, expiry: Duration) -> Self {
+        Self {
+            max_destinations,
+            expiry,
+            destinations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the counters of `addr`.
+    pub fn get(&self, addr: &SocketAddr) -> Option<DestinationStats> {
+        self.destinations
+            .lock()
+            .unwrap()
+            .get(addr)
+            .map(|(stats, _)| *stats)
+    }
+
+    /// Returns the number of destinations tracked.
+    pub fn len(&self) -> usize {
+        self.destinations.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns up to `n` destinations that had packets dropped, highest drop rate first and most
+    /// drops first among equal rates.
+    pub fn worst(&self, n: usize) -> Vec<(SocketAddr, DestinationStats)> {
+        let mut worst = self
+            .destinations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (stats, _))| stats.dropped > 0)
+            .map(|(addr, (stats, _))| (*addr, *stats))
+            .collect::<Vec<_>>();
+        worst.sort_unstable_by(|(_, a), (_, b)| {
+            b.drop_rate()
+                .total_cmp(&a.drop_rate())
+                .then(b.dropped.cmp(&a.dropped))
+        });
+        worst.truncate(n);
+        worst
+    }
+
+    fn merge(&self, counts: impl IntoIterator<Item = (SocketAddr, DestinationStats)>) {
+        let now = Instant::now();
+        let mut destinations = self.destinations.lock().unwrap();
+        destinations.retain(|_, (_, updated)| now.duration_since(*updated) < self.expiry);
+        for (addr, counts) in counts {
+            if destinations.len() < self.max_destinations || destinations.contains_key(&addr) {
+                let (stats, updated) = destinations
+                    .entry(addr)
+                    .or_insert((DestinationStats::default(), now));
+                stats.merge(&counts);
+                *updated = now;
+            }
+        }
+    }
+}
+
+// Counts the packets of a tx loop by destination and periodically merges them into a
+// DeliveryStats. Frames still in flight when it's dropped are counted as dropped.
+pub(crate) struct DeliveryRecorder<'a> {
+    stats: &'a DeliveryStats,
+    frame_size: usize,
+    // the destination of each frame in flight
+    in_flight: Vec<Option<SocketAddr>>,
+    counts: HashMap<SocketAddr, DestinationStats>,
+    last_flush: Instant,
+}
+
+impl<'a> DeliveryRecorder<'a> {
+    pub(crate) fn new(stats: &'a DeliveryStats, umem: &impl Umem) -> Self {
+        Self {
+            stats,
+            frame_size: umem.frame_size(),
+            in_flight: vec![None; umem.capacity()],
+            counts: HashMap::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn submitted(&mut self, offset: FrameOffset, addr: &SocketAddr) {
+        self.in_flight[offset.0 / self.frame_size] = Some(*addr);
+        self.counts.entry(*addr).or_default().submitted += 1;
+    }
+
+    #[inline]
+    pub(crate) fn completed(&mut self, offset: FrameOffset) {
+        if let Some(addr) = self.in_flight[offset.0 / self.frame_size].take() {
+            self.counts.entry(addr).or_default().completed += 1;
+        }
+    }
+
+    pub(crate) fn dropped(&mut self, addr: &SocketAddr) {
+        self.counts.entry(*addr).or_default().dropped += 1;
+    }
+
+    #[inline]
+    pub(crate) fn maybe_flush(&mut self) {
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        if !self.counts.is_empty() {
+            self.stats.merge(self.counts.drain());
+        }
+    }
+}
+
+impl Drop for DeliveryRecorder<'_> {
+    fn drop(&mut self) {
+        for addr in self.in_flight.iter_mut().filter_map(Option::take) {
+            self.counts.entry(addr).or_default().dropped += 1;
+        }
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worst() {
+        let addr = |port| SocketAddr::from(([10, 0, 0, 1], port));
+        let stats = DeliveryStats::new(3, Duration::from_secs(60));
+        let counts = |submitted, dropped| DestinationStats {
+            submitted,
+            completed: submitted,
+            dropped,
+        };
+        stats.merge([
+            (addr(1), counts(10, 0)),
+            (addr(2), counts(5, 5)),
+            (addr(3), counts(90, 10)),
+            // over capacity
+            (addr(4), counts(0, 10)),
+        ]);
+        stats.merge([(addr(3), counts(0, 90))]);
+        assert_eq!(stats.len(), 3);
+        assert_eq!(stats.get(&addr(3)), Some(counts(90, 100)));
+        assert_eq!(stats.get(&addr(4)), None);
+        assert_eq!(
+            stats.worst(5),
+            [(addr(3), counts(90, 100)), (addr(2), counts(5, 5))]
+        );
+        assert_eq!(stats.worst(1).len(), 1);
+
+        let stats = DeliveryStats::new(3, Duration::ZERO);
+        stats.merge([(addr(1), counts(1, 1))]);
+        stats.merge([(addr(2), counts(1, 1))]);
+        assert_eq!(stats.get(&addr(1)), None);
+    }
+}
@@ -1,27 +1,35 @@
 use {
     crate::{
-        netlink::MacAddress,
+        error::{XdpError, XdpErrorKind},
+        netlink::{
+            netlink_get_addresses, netlink_get_link, netlink_get_links, InterfaceAddress,
+            LinkEvent, LinkInfo, MacAddress,
+        },
         route::Router,
         umem::{Frame, FrameOffset},
     },
     libc::{
-        ifreq, mmap, munmap, socket, syscall, xdp_ring_offset, SYS_ioctl, AF_INET, IF_NAMESIZE,
-        SIOCETHTOOL, SIOCGIFADDR, SIOCGIFHWADDR, SOCK_DGRAM,
+        ifreq, mmap, munmap, recvfrom, socket, syscall, xdp_ring_offset, SYS_ioctl, AF_INET,
+        AF_UNSPEC, IFF_LOWER_UP, IFF_UP, IF_NAMESIZE, IF_OPER_DORMANT, IF_OPER_DOWN,
+        IF_OPER_LOWERLAYERDOWN, IF_OPER_NOTPRESENT, IF_OPER_TESTING, IF_OPER_UP, MSG_DONTWAIT,
+        RT_SCOPE_UNIVERSE, SIOCETHTOOL, SIOCGIFADDR, SIOCGIFHWADDR, SOCK_DGRAM,
+        XDP_RING_NEED_WAKEUP,
     },
     std::{
         ffi::{c_char, CStr, CString},
-        fs,
+        fmt, fs,
         io::{self, ErrorKind},
         marker::PhantomData,
         mem,
-        net::Ipv4Addr,
+        net::{IpAddr, Ipv4Addr},
+        ops::Range,
         os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd, RawFd},
         ptr, slice,
         sync::atomic::{AtomicU32, Ordering},
     },
 };
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub struct QueueId(pub u64);
 
 pub struct NetworkDevice {
@@ -57,6 +65,15 @@ impl NetworkDevice {
         Ok(Self { if_index, if_name })
     }
 
+    /// Opens the device identified by `id`, under whatever name it has now.
+    pub fn new_from_id(id: &DeviceId) -> Result<Self, XdpError> {
+        let link = id.locate()?;
+        Ok(Self {
+            if_index: link.if_index,
+            if_name: link.name,
+        })
+    }
+
     pub fn new_from_default_route() -> Result<Self, io::Error> {
         let router = Router::new()?;
         let default_route = router.default().unwrap();
@@ -71,6 +88,23 @@ impl NetworkDevice {
         self.if_index
     }
 
+    /// Returns the identity of the device, which unlike its name survives renames.
+    pub fn id(&self) -> Result<DeviceId, XdpError> {
+        Ok(DeviceId::from_link(&netlink_get_link(self.if_index)?))
+    }
+
+    /// Reads the name of the interface again, in case it was renamed since the device was
+    /// opened. Returns whether the name changed.
+    pub fn refresh_name(&mut self) -> Result<bool, XdpError> {
+        let name = netlink_get_link(self.if_index)?.name;
+        if name == self.if_name {
+            return Ok(false);
+        }
+        log::info!("interface {} was renamed to {name}", self.if_name);
+        self.if_name = name;
+        Ok(true)
+    }
+
     pub fn mac_addr(&self) -> Result<MacAddress, io::Error> {
         let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
         if fd < 0 {
@@ -139,19 +173,127 @@ impl NetworkDevice {
     }
 
     pub fn driver(&self) -> io::Result<String> {
-        let path = format!("/sys/class/net/{}/device/driver", self.if_name);
-
-        let path = fs::read_link(path).map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!(
-                    "Failed to read driver link for interface {}: {}",
-                    self.if_name, e
-                ),
-            )
-        })?;
+        read_driver(&self.if_name)
+    }
+
+    /// Returns all the IPv4 and IPv6 addresses assigned to the interface.
+    pub fn addresses(&self) -> Result<Vec<InterfaceAddress>, XdpError> {
+        let mut addresses = netlink_get_addresses(AF_UNSPEC as u8)?;
+        addresses.retain(|a| a.if_index == self.if_index);
+        Ok(addresses)
+    }
 
-        Ok(path.file_name().unwrap().to_str().unwrap().into())
+    /// Returns the address to send from when nothing more specific is known: a global IPv4
+    /// address of the interface, preferring primary addresses that aren't deprecated.
+    ///
+    /// Unlike [`ipv4_addr`](Self::ipv4_addr), this doesn't pick whichever address was added
+    /// first, which on hosts with multiple addresses may not be reachable from the outside.
+    pub fn preferred_ipv4_addr(&self) -> Result<Ipv4Addr, XdpError> {
+        self.addresses()?
+            .into_iter()
+            .filter(|a| a.address.is_ipv4() && a.is_usable() && a.scope == RT_SCOPE_UNIVERSE)
+            .min_by_key(|a| (a.is_deprecated(), a.secondary))
+            .and_then(|a| match a.address {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .ok_or_else(|| {
+                XdpError::other(
+                    XdpErrorKind::Misconfigured,
+                    "RTM_GETADDR",
+                    format!("{} has no global IPv4 address", self.if_name),
+                )
+            })
+    }
+
+    /// Checks that `ip` is assigned to the interface and can be sent from, e.g. before binding
+    /// to an address given by the user.
+    pub fn validate_addr(&self, ip: IpAddr) -> Result<InterfaceAddress, XdpError> {
+        let addresses = self.addresses()?;
+        let error = |msg: String| XdpError::other(XdpErrorKind::Misconfigured, "RTM_GETADDR", msg);
+        let Some(address) = addresses.iter().find(|a| a.address == ip) else {
+            let assigned = addresses
+                .iter()
+                .map(|a| a.address.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(error(format!(
+                "{ip} is not assigned to {}, its addresses are [{assigned}]",
+                self.if_name
+            )));
+        };
+        if !address.is_usable() {
+            return Err(error(format!(
+                "{ip} on {} is tentative or failed duplicate address detection",
+                self.if_name
+            )));
+        }
+        Ok(address.clone())
+    }
+
+    /// Queries the kernel for the current state of the interface.
+    pub fn info(&self) -> Result<DeviceInfo, XdpError> {
+        DeviceInfo::query(self.if_index)
+    }
+
+    /// Returns whether the interface is operationally up, as reported by sysfs.
+    pub fn is_up(&self) -> io::Result<bool> {
+        let path = format!("/sys/class/net/{}/operstate", self.if_name);
+        let state = fs::read_to_string(path)?;
+        Ok(state.trim() == "up")
+    }
+
+    /// Returns the NUMA node the device is attached to, `None` if it's not a PCI device or the
+    /// machine isn't NUMA.
+    pub fn numa_node(&self) -> io::Result<Option<usize>> {
+        let path = format!("/sys/class/net/{}/device/numa_node", self.if_name);
+        let node = match fs::read_to_string(path) {
+            Ok(node) => node,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // -1 when the platform doesn't report a node
+        Ok(node.trim().parse::<usize>().ok())
+    }
+
+    /// Returns the MSI interrupts of the device, `None` if it's not a PCI device.
+    pub fn msi_irqs(&self) -> io::Result<Option<Vec<u32>>> {
+        let path = format!("/sys/class/net/{}/device/msi_irqs", self.if_name);
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut irqs = Vec::new();
+        for entry in entries {
+            if let Ok(irq) = entry?.file_name().to_string_lossy().parse() {
+                irqs.push(irq);
+            }
+        }
+        irqs.sort_unstable();
+        Ok(Some(irqs))
+    }
+
+    /// Returns the counters the driver keeps for the interface, as reported by sysfs.
+    pub fn statistics(&self) -> io::Result<DeviceStatistics> {
+        let read = |name: &str| -> io::Result<u64> {
+            let path = format!("/sys/class/net/{}/statistics/{name}", self.if_name);
+            fs::read_to_string(path)?
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+        };
+        Ok(DeviceStatistics {
+            rx_packets: read("rx_packets")?,
+            tx_packets: read("tx_packets")?,
+            rx_bytes: read("rx_bytes")?,
+            tx_bytes: read("tx_bytes")?,
+            rx_dropped: read("rx_dropped")?,
+            tx_dropped: read("tx_dropped")?,
+            rx_errors: read("rx_errors")?,
+            tx_errors: read("tx_errors")?,
+            rx_missed_errors: read("rx_missed_errors")?,
+        })
     }
 
     pub fn open_queue(&self, queue_id: QueueId) -> Result<DeviceQueue, io::Error> {
@@ -205,6 +347,289 @@ impl NetworkDevice {
             tx: rp.tx_pending as usize,
         })
     }
+
+    /// Returns the number of queues sockets can be bound to.
+    ///
+    /// A socket needs both an rx and a tx queue with its id, so this is the smaller of the two
+    /// counts.
+    pub fn queue_count(&self) -> io::Result<usize> {
+        let path = format!("/sys/class/net/{}/queues", self.if_name);
+        let (mut rx, mut tx) = (0, 0);
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("rx-") {
+                rx += 1;
+            } else if name.starts_with("tx-") {
+                tx += 1;
+            }
+        }
+        Ok(rx.min(tx))
+    }
+
+    /// Returns the channel counts of the device, like `ethtool -l`.
+    pub fn channels(&self) -> Result<Channels, XdpError> {
+        let mut channels = EthtoolChannels {
+            cmd: ETHTOOL_GCHANNELS,
+            ..EthtoolChannels::default()
+        };
+        ethtool_ioctl(&self.if_name, &mut channels as *mut _ as *mut c_char)
+            .map_err(|e| XdpError::new("ioctl(ETHTOOL_GCHANNELS)", e))?;
+        Ok(Channels {
+            max_rx: channels.max_rx,
+            max_tx: channels.max_tx,
+            max_other: channels.max_other,
+            max_combined: channels.max_combined,
+            rx: channels.rx_count,
+            tx: channels.tx_count,
+            other: channels.other_count,
+            combined: channels.combined_count,
+        })
+    }
+
+    /// Changes the channel counts of the device, like `ethtool -L`. The max counts are ignored.
+    ///
+    /// Most drivers reset the device, and the RSS indirection table unless it was configured
+    /// explicitly.
+    pub fn set_channels(&self, channels: &Channels) -> Result<(), XdpError> {
+        let mut request = EthtoolChannels {
+            cmd: ETHTOOL_SCHANNELS,
+            rx_count: channels.rx,
+            tx_count: channels.tx,
+            other_count: channels.other,
+            combined_count: channels.combined,
+            ..EthtoolChannels::default()
+        };
+        ethtool_ioctl(&self.if_name, &mut request as *mut _ as *mut c_char)
+            .map_err(|e| XdpError::new("ioctl(ETHTOOL_SCHANNELS)", e))
+    }
+
+    /// Returns the RSS indirection table, the queue each hash bucket of received flows is
+    /// steered to, like `ethtool -x`.
+    pub fn rss_indirection_table(&self) -> Result<Vec<u32>, XdpError> {
+        let error = |e| XdpError::new("ioctl(ETHTOOL_GRXFHINDIR)", e);
+        // the first call with size 0 returns the size of the table
+        let mut header = [ETHTOOL_GRXFHINDIR, 0];
+        ethtool_ioctl(&self.if_name, header.as_mut_ptr() as *mut c_char).map_err(error)?;
+        let size = header[1] as usize;
+        let mut buf = vec![0u32; size.saturating_add(2)];
+        buf[0] = ETHTOOL_GRXFHINDIR;
+        buf[1] = size as u32;
+        ethtool_ioctl(&self.if_name, buf.as_mut_ptr() as *mut c_char).map_err(error)?;
+        Ok(buf.split_off(2))
+    }
+
+    /// Replaces the RSS indirection table, like `ethtool -X`. `table` must have the size
+    /// returned by [`rss_indirection_table`](Self::rss_indirection_table), an empty table
+    /// restores the driver's default.
+    pub fn set_rss_indirection_table(&self, table: &[u32]) -> Result<(), XdpError> {
+        let mut buf = Vec::with_capacity(table.len().saturating_add(2));
+        buf.extend([ETHTOOL_SRXFHINDIR, table.len() as u32]);
+        buf.extend_from_slice(table);
+        ethtool_ioctl(&self.if_name, buf.as_mut_ptr() as *mut c_char)
+            .map_err(|e| XdpError::new("ioctl(ETHTOOL_SRXFHINDIR)", e))
+    }
+
+    /// Returns the n-tuple filters steering UDP/IPv4 flows by destination port, like
+    /// `ethtool -n`. Filters matching on anything else are skipped.
+    pub fn flow_rules(&self) -> Result<Vec<FlowRule>, XdpError> {
+        let locations = self.flow_rule_locations()?;
+        let mut rules = Vec::with_capacity(locations.len());
+        for location in locations {
+            let mut rule = EthtoolRxnfc::new(ETHTOOL_GRXCLSRULE);
+            rule.fs.location = location;
+            ethtool_ioctl(&self.if_name, &mut rule as *mut _ as *mut c_char)
+                .map_err(|e| XdpError::new("ioctl(ETHTOOL_GRXCLSRULE)", e))?;
+            if let Some(rule) = rule.fs.udp4_dst_port_rule() {
+                rules.push(rule);
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Steers the UDP/IPv4 packets received on `port` to `queue_id`, like
+    /// `ethtool -N <interface> flow-type udp4 dst-port <port> action <queue_id>`. Returns the
+    /// new filter.
+    pub fn add_flow_rule(&self, port: u16, queue_id: QueueId) -> Result<FlowRule, XdpError> {
+        let mut rule = EthtoolRxnfc::new(ETHTOOL_SRXCLSRLINS);
+        rule.fs.flow_type = UDP_V4_FLOW;
+        rule.fs.h_u[UDP4_DST_PORT].copy_from_slice(&port.to_be_bytes());
+        rule.fs.m_u[UDP4_DST_PORT].copy_from_slice(&[0xff; 2]);
+        rule.fs.ring_cookie = queue_id.0;
+        rule.fs.location = RX_CLS_LOC_ANY;
+        match ethtool_ioctl(&self.if_name, &mut rule as *mut _ as *mut c_char) {
+            Ok(()) => {}
+            // some drivers don't pick a location themselves, take the first free one
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                let used = self.flow_rule_locations()?;
+                rule.fs.location = (0..).find(|loc| !used.contains(loc)).unwrap_or_default();
+                ethtool_ioctl(&self.if_name, &mut rule as *mut _ as *mut c_char)
+                    .map_err(|e| XdpError::new("ioctl(ETHTOOL_SRXCLSRLINS)", e))?;
+            }
+            Err(e) => return Err(XdpError::new("ioctl(ETHTOOL_SRXCLSRLINS)", e)),
+        }
+        Ok(FlowRule {
+            location: rule.fs.location,
+            port,
+            queue_id,
+        })
+    }
+
+    /// Deletes the n-tuple filter at `location`, like `ethtool -N <interface> delete <location>`.
+    pub fn remove_flow_rule(&self, location: u32) -> Result<(), XdpError> {
+        let mut rule = EthtoolRxnfc::new(ETHTOOL_SRXCLSRLDEL);
+        rule.fs.location = location;
+        ethtool_ioctl(&self.if_name, &mut rule as *mut _ as *mut c_char)
+            .map_err(|e| XdpError::new("ioctl(ETHTOOL_SRXCLSRLDEL)", e))
+    }
+
+    // Returns the locations of all the n-tuple filters, whatever they match on.
+    fn flow_rule_locations(&self) -> Result<Vec<u32>, XdpError> {
+        let error = |e| XdpError::new("ioctl(ETHTOOL_GRXCLSRLALL)", e);
+        let mut count = EthtoolRxnfc::new(ETHTOOL_GRXCLSRLCNT);
+        ethtool_ioctl(&self.if_name, &mut count as *mut _ as *mut c_char).map_err(error)?;
+        // the locations follow rule_cnt, within the padding at the end of the struct
+        let header = mem::offset_of!(EthtoolRxnfc, rule_cnt) / 4 + 1;
+        let words = (header + count.rule_cnt as usize).max(mem::size_of::<EthtoolRxnfc>() / 4);
+        let mut buf = vec![0u32; words];
+        // Safety: buf is large enough and aligned for an EthtoolRxnfc
+        let all = unsafe { &mut *(buf.as_mut_ptr() as *mut EthtoolRxnfc) };
+        all.cmd = ETHTOOL_GRXCLSRLALL;
+        all.data = count.data;
+        all.rule_cnt = count.rule_cnt;
+        ethtool_ioctl(&self.if_name, buf.as_mut_ptr() as *mut c_char).map_err(error)?;
+        buf.truncate(header + count.rule_cnt as usize);
+        Ok(buf.split_off(header))
+    }
+}
+
+const ETHTOOL_GRXCLSRLCNT: u32 = 0x0000002e;
+const ETHTOOL_GRXCLSRULE: u32 = 0x0000002f;
+const ETHTOOL_GRXCLSRLALL: u32 = 0x00000030;
+const ETHTOOL_SRXCLSRLDEL: u32 = 0x00000031;
+const ETHTOOL_SRXCLSRLINS: u32 = 0x00000032;
+const ETHTOOL_GRXFHINDIR: u32 = 0x00000038;
+const ETHTOOL_SRXFHINDIR: u32 = 0x00000039;
+const ETHTOOL_GCHANNELS: u32 = 0x0000003c;
+const ETHTOOL_SCHANNELS: u32 = 0x0000003d;
+
+// struct ethtool_channels
+#[repr(C)]
+#[derive(Default)]
+struct EthtoolChannels {
+    cmd: u32,
+    max_rx: u32,
+    max_tx: u32,
+    max_other: u32,
+    max_combined: u32,
+    rx_count: u32,
+    tx_count: u32,
+    other_count: u32,
+    combined_count: u32,
+}
+
+const UDP_V4_FLOW: u32 = 0x02;
+const RX_CLS_LOC_ANY: u32 = 0x80000000;
+// the destination port in struct ethtool_tcpip4_spec
+const UDP4_DST_PORT: Range<usize> = 10..12;
+
+// struct ethtool_rx_flow_spec
+#[repr(C)]
+struct EthtoolFlowSpec {
+    flow_type: u32,
+    h_u: [u8; 52],
+    h_ext: [u8; 20],
+    m_u: [u8; 52],
+    m_ext: [u8; 20],
+    ring_cookie: u64,
+    location: u32,
+}
+
+impl EthtoolFlowSpec {
+    // Returns the rule if it only matches on the destination port of UDP/IPv4 packets.
+    fn udp4_dst_port_rule(&self) -> Option<FlowRule> {
+        let mut mask = [0u8; 52];
+        mask[UDP4_DST_PORT].copy_from_slice(&[0xff; 2]);
+        if self.flow_type != UDP_V4_FLOW || self.m_u != mask || self.m_ext != [0; 20] {
+            return None;
+        }
+        Some(FlowRule {
+            location: self.location,
+            port: u16::from_be_bytes(self.h_u[UDP4_DST_PORT].try_into().ok()?),
+            queue_id: QueueId(self.ring_cookie),
+        })
+    }
+}
+
+// struct ethtool_rxnfc, without the trailing rule locations
+#[repr(C)]
+struct EthtoolRxnfc {
+    cmd: u32,
+    flow_type: u32,
+    data: u64,
+    fs: EthtoolFlowSpec,
+    rule_cnt: u32,
+}
+
+impl EthtoolRxnfc {
+    fn new(cmd: u32) -> Self {
+        // Safety: plain old data
+        let mut rxnfc = unsafe { mem::zeroed::<Self>() };
+        rxnfc.cmd = cmd;
+        rxnfc
+    }
+}
+
+/// An n-tuple filter steering the UDP/IPv4 packets received on a port to a queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlowRule {
+    /// Where the filter is in the device's table.
+    pub location: u32,
+    pub port: u16,
+    pub queue_id: QueueId,
+}
+
+// Issues the SIOCETHTOOL ioctl on `if_name`, `data` pointing to the ethtool command struct.
+fn ethtool_ioctl(if_name: &str, data: *mut c_char) -> io::Result<()> {
+    // Safety: libc wrapper
+    let fd = unsafe { socket(AF_INET, SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: fd was just created and is owned by nothing else
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // Safety: ifreq is plain old data
+    let mut ifr: ifreq = unsafe { mem::zeroed() };
+    // Safety: both pointers are valid and the copy is bounded by the size of ifr_name
+    unsafe {
+        ptr::copy_nonoverlapping(
+            if_name.as_ptr() as *const c_char,
+            ifr.ifr_name.as_mut_ptr(),
+            if_name.len().min(IF_NAMESIZE),
+        );
+    }
+    ifr.ifr_name[IF_NAMESIZE - 1] = 0;
+    ifr.ifr_ifru.ifru_data = data;
+
+    // Safety: the caller passes a command struct large enough for the command
+    if unsafe { syscall(SYS_ioctl, fd.as_raw_fd(), SIOCETHTOOL, &ifr) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The channel counts of a device. Queues are numbered with the combined channels first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Channels {
+    pub max_rx: u32,
+    pub max_tx: u32,
+    pub max_other: u32,
+    pub max_combined: u32,
+    pub rx: u32,
+    pub tx: u32,
+    pub other: u32,
+    pub combined: u32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -221,6 +646,235 @@ impl Default for RingSizes {
     }
 }
 
+fn read_driver(if_name: &str) -> io::Result<String> {
+    let path = format!("/sys/class/net/{}/device/driver", if_name);
+
+    let path = fs::read_link(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "Failed to read driver link for interface {}: {}",
+                if_name, e
+            ),
+        )
+    })?;
+
+    Ok(path.file_name().unwrap().to_str().unwrap().into())
+}
+
+/// Identifies a network device independently of its name.
+///
+/// Interface names aren't stable: udev can apply predictable names after we started, bonding
+/// renames its slaves, and a name freed by one device can be taken by another. The if_index stays
+/// the same for as long as the device exists, and the permanent MAC address of a NIC, when it has
+/// one, identifies it even if its driver is reloaded and it comes back under a new if_index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub if_index: u32,
+    pub permanent_mac_addr: Option<MacAddress>,
+}
+
+impl DeviceId {
+    fn from_link(link: &LinkInfo) -> Self {
+        Self {
+            if_index: link.if_index,
+            permanent_mac_addr: link.perm_address,
+        }
+    }
+
+    /// Resolves the interface currently named `name`, e.g. one given on the command line.
+    ///
+    /// This should be done once at startup, and the identity used from then on.
+    pub fn resolve(name: &str) -> Result<Self, XdpError> {
+        netlink_get_links()?
+            .iter()
+            .find(|link| link.name == name)
+            .map(Self::from_link)
+            .ok_or_else(|| {
+                XdpError::other(
+                    XdpErrorKind::Misconfigured,
+                    "RTM_GETLINK",
+                    format!("no interface named {name}"),
+                )
+            })
+    }
+
+    /// Returns the current attributes of the device, looking it up by permanent MAC address if
+    /// its if_index is gone or now belongs to another device.
+    pub fn locate(&self) -> Result<LinkInfo, XdpError> {
+        let links = netlink_get_links()?;
+        let matches_mac = |link: &&LinkInfo| {
+            self.permanent_mac_addr.is_none() || link.perm_address == self.permanent_mac_addr
+        };
+        links
+            .iter()
+            .filter(|link| link.if_index == self.if_index)
+            .find(matches_mac)
+            .or_else(|| {
+                self.permanent_mac_addr?;
+                links.iter().find(matches_mac)
+            })
+            .cloned()
+            .ok_or_else(|| {
+                XdpError::other(
+                    XdpErrorKind::Misconfigured,
+                    "RTM_GETLINK",
+                    format!("{self} is gone"),
+                )
+            })
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "if_index {}", self.if_index)?;
+        if let Some(mac) = self.permanent_mac_addr {
+            write!(f, " ({mac})")?;
+        }
+        Ok(())
+    }
+}
+
+/// The operational state of an interface, see RFC 2863.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperState {
+    /// The driver doesn't track the state, common for virtual interfaces.
+    Unknown,
+    NotPresent,
+    Down,
+    LowerLayerDown,
+    Testing,
+    Dormant,
+    Up,
+}
+
+impl OperState {
+    fn from_raw(state: u8) -> Self {
+        match state as i32 {
+            IF_OPER_NOTPRESENT => Self::NotPresent,
+            IF_OPER_DOWN => Self::Down,
+            IF_OPER_LOWERLAYERDOWN => Self::LowerLayerDown,
+            IF_OPER_TESTING => Self::Testing,
+            IF_OPER_DORMANT => Self::Dormant,
+            IF_OPER_UP => Self::Up,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplex {
+    Half,
+    Full,
+}
+
+/// A snapshot of the state of a network interface.
+///
+/// The link attributes come from `RTM_GETLINK`, the speed, duplex and driver from sysfs. Fields
+/// are `None` when the kernel or the driver doesn't report them, e.g. the speed of a link that's
+/// down or the driver of a virtual interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub if_index: u32,
+    pub name: String,
+    pub operstate: OperState,
+    /// Whether the interface is administratively up.
+    pub admin_up: bool,
+    /// Whether the interface has a carrier.
+    pub carrier: bool,
+    pub mtu: Option<u32>,
+    pub mac_addr: Option<MacAddress>,
+    /// The MAC address assigned by the manufacturer, which doesn't change when `mac_addr` is
+    /// overridden.
+    pub permanent_mac_addr: Option<MacAddress>,
+    pub speed_mbps: Option<u32>,
+    pub duplex: Option<Duplex>,
+    pub driver: Option<String>,
+    pub tx_queues: Option<u32>,
+    pub rx_queues: Option<u32>,
+    /// The id of the XDP program attached to the interface, ours or someone else's.
+    pub xdp_prog_id: Option<u32>,
+}
+
+impl DeviceInfo {
+    pub fn query(if_index: u32) -> Result<Self, XdpError> {
+        let link = netlink_get_link(if_index)?;
+        Ok(Self::from_link(link))
+    }
+
+    fn from_link(link: LinkInfo) -> Self {
+        let sysfs = |name: &str| {
+            fs::read_to_string(format!("/sys/class/net/{}/{name}", link.name))
+                .ok()
+                .map(|value| value.trim().to_string())
+        };
+        // reading these fails with EINVAL while the link is down, and the speed is -1 if unknown
+        let speed_mbps = sysfs("speed").and_then(|speed| speed.parse().ok());
+        let duplex = sysfs("duplex").and_then(|duplex| match duplex.as_str() {
+            "full" => Some(Duplex::Full),
+            "half" => Some(Duplex::Half),
+            _ => None,
+        });
+        Self {
+            if_index: link.if_index,
+            operstate: OperState::from_raw(link.operstate),
+            admin_up: link.flags & IFF_UP as u32 != 0,
+            carrier: link.flags & IFF_LOWER_UP as u32 != 0,
+            mtu: link.mtu,
+            mac_addr: link.address,
+            permanent_mac_addr: link.perm_address,
+            speed_mbps,
+            duplex,
+            driver: read_driver(&link.name).ok(),
+            tx_queues: link.num_tx_queues,
+            rx_queues: link.num_rx_queues,
+            xdp_prog_id: link.xdp_prog_id,
+            name: link.name,
+        }
+    }
+
+    /// Queries the kernel again.
+    pub fn refresh(&mut self) -> Result<(), XdpError> {
+        *self = Self::query(self.if_index)?;
+        Ok(())
+    }
+
+    /// Refreshes the info if `event` is about this interface, e.g. one returned by a
+    /// [`LinkMonitor`](crate::netlink::LinkMonitor). Returns whether it did.
+    pub fn handle_event(&mut self, event: &LinkEvent) -> Result<bool, XdpError> {
+        if event.if_index != self.if_index {
+            return Ok(false);
+        }
+        self.refresh()?;
+        Ok(true)
+    }
+
+    /// Whether the interface can pass traffic. Interfaces whose driver doesn't track the
+    /// operational state count as up if they're administratively up and have a carrier.
+    pub fn is_up(&self) -> bool {
+        match self.operstate {
+            OperState::Up => true,
+            OperState::Unknown => self.admin_up && self.carrier,
+            _ => false,
+        }
+    }
+}
+
+/// The interface counters from `/sys/class/net/<interface>/statistics`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeviceStatistics {
+    pub rx_packets: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+    pub rx_dropped: u64,
+    pub tx_dropped: u64,
+    pub rx_errors: u64,
+    pub tx_errors: u64,
+    /// Packets the NIC dropped because the host didn't keep up, e.g. the rx ring was full.
+    pub rx_missed_errors: u64,
+}
+
 pub struct DeviceQueue {
     if_index: u32,
     queue_id: QueueId,
@@ -276,6 +930,11 @@ impl RingConsumer {
         self.cached_producer.wrapping_sub(self.cached_consumer)
     }
 
+    // Entries produced and not yet consumed, reading the producer index without syncing.
+    pub fn occupancy(&self) -> u32 {
+        unsafe { (*self.producer).load(Ordering::Acquire) }.wrapping_sub(self.cached_consumer)
+    }
+
     pub fn consume(&mut self) -> Option<u32> {
         if self.cached_consumer == self.cached_producer {
             return None;
@@ -322,6 +981,12 @@ impl RingProducer {
             .saturating_sub(self.cached_producer.wrapping_sub(self.cached_consumer))
     }
 
+    // Entries produced and not yet consumed, reading the consumer index without syncing.
+    pub fn occupancy(&self) -> u32 {
+        self.cached_producer
+            .wrapping_sub(unsafe { (*self.consumer).load(Ordering::Acquire) })
+    }
+
     pub fn produce(&mut self) -> Option<u32> {
         if self.available() == 0 {
             return None;
@@ -374,6 +1039,15 @@ impl TxCompletionRing {
         Some(FrameOffset(index))
     }
 
+    pub fn capacity(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Returns the number of completions the driver published that haven't been read yet.
+    pub fn occupancy(&self) -> usize {
+        self.consumer.occupancy() as usize
+    }
+
     pub fn commit(&mut self) {
         self.consumer.commit();
     }
@@ -387,7 +1061,7 @@ pub struct RxFillRing<F: Frame> {
     mmap: RingMmap<u64>,
     producer: RingProducer,
     size: u32,
-    _fd: RawFd,
+    fd: RawFd,
     _frame: PhantomData<F>,
 }
 
@@ -398,7 +1072,7 @@ impl<F: Frame> RxFillRing<F> {
             producer: RingProducer::new(mmap.producer, mmap.consumer, size),
             mmap,
             size,
-            _fd: fd,
+            fd,
             _frame: PhantomData,
         }
     }
@@ -417,6 +1091,42 @@ impl<F: Frame> RxFillRing<F> {
         Ok(())
     }
 
+    /// Returns true if the driver must be woken up to pick up the frames in the ring.
+    pub fn needs_wakeup(&self) -> bool {
+        unsafe { (*self.mmap.flags).load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0 }
+    }
+
+    pub fn wake(&self) -> Result<(), io::Error> {
+        // Safety: libc wrapper, a zero length read doesn't touch the buffer
+        let result = unsafe {
+            recvfrom(
+                self.fd,
+                ptr::null_mut(),
+                0,
+                MSG_DONTWAIT,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.size as usize
+    }
+
+    pub fn available(&self) -> usize {
+        self.producer.available() as usize
+    }
+
+    /// Returns the number of frames posted that the driver hasn't taken yet.
+    pub fn occupancy(&self) -> usize {
+        self.producer.occupancy() as usize
+    }
+
     pub fn commit(&mut self) {
         self.producer.commit();
     }
@@ -501,8 +1211,11 @@ mod test {
             assert_eq!(ring.available(), size - i - 1);
         }
         assert_eq!(ring.produce(), None);
+        assert_eq!(ring.occupancy(), size);
 
         consumer.store(1, Ordering::Release);
+        // the occupancy reads the consumer without waiting for a sync
+        assert_eq!(ring.occupancy(), size - 1);
         assert_eq!(ring.produce(), None);
         ring.commit();
         assert_eq!(ring.produce(), None);
@@ -547,6 +1260,7 @@ mod test {
 
         producer.store(1, Ordering::Release);
         assert_eq!(ring.available(), 0);
+        assert_eq!(ring.occupancy(), 1);
         ring.sync(true);
         assert_eq!(ring.available(), 1);
 
@@ -585,4 +1299,93 @@ mod test {
         ring.sync(true);
         assert_eq!(ring.consume(), Some(1));
     }
+
+    #[test]
+    fn test_device_info() {
+        let lo = NetworkDevice::new("lo").unwrap();
+        let mut info = lo.info().unwrap();
+        assert_eq!((info.if_index, info.name.as_str()), (lo.if_index(), "lo"));
+        assert_eq!(info.mac_addr, Some(MacAddress([0; 6])));
+        assert!(info.mtu.unwrap() > 0);
+        assert!(info.tx_queues.unwrap() >= 1 && info.rx_queues.unwrap() >= 1);
+        // loopback isn't backed by a device, and doesn't track its operational state
+        assert_eq!(info.driver, None);
+        assert_eq!(info.operstate, OperState::Unknown);
+        assert_eq!(info.is_up(), info.admin_up && info.carrier);
+
+        let other = LinkEvent {
+            if_index: lo.if_index() + 1,
+            up: false,
+        };
+        assert!(!info.handle_event(&other).unwrap());
+        let event = LinkEvent {
+            if_index: lo.if_index(),
+            up: true,
+        };
+        assert!(info.handle_event(&event).unwrap());
+        assert_eq!(info.name, "lo");
+    }
+
+    #[test]
+    fn test_addresses() {
+        let lo = NetworkDevice::new("lo").unwrap();
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        assert!(lo
+            .addresses()
+            .unwrap()
+            .iter()
+            .any(|a| a.address == localhost));
+        assert_eq!(lo.validate_addr(localhost).unwrap().address, localhost);
+        let e = lo.validate_addr("192.0.2.1".parse().unwrap()).unwrap_err();
+        assert_eq!(e.kind(), XdpErrorKind::Misconfigured);
+        assert!(e.to_string().contains("127.0.0.1"), "{e}");
+        // loopback addresses are host scoped
+        assert!(lo.preferred_ipv4_addr().is_err());
+    }
+
+    #[test]
+    fn test_device_id() {
+        let id = DeviceId::resolve("lo").unwrap();
+        let lo = NetworkDevice::new("lo").unwrap();
+        assert_eq!(id.if_index, lo.if_index());
+        // only NICs have a permanent address
+        assert_eq!(id.permanent_mac_addr, None);
+        assert_eq!(lo.id().unwrap(), id);
+        assert_eq!(NetworkDevice::new_from_id(&id).unwrap().name(), "lo");
+
+        assert!(DeviceId::resolve("no-such-dev").is_err());
+        let gone = DeviceId {
+            if_index: u32::MAX,
+            permanent_mac_addr: Some(MacAddress([0x02, 0, 0, 0, 0, 1])),
+        };
+        let e = gone.locate().unwrap_err();
+        assert_eq!(e.kind(), XdpErrorKind::Misconfigured);
+        assert!(e.to_string().contains("02:00:00:00:00:01"), "{e}");
+    }
+
+    #[test]
+    fn test_flow_spec() {
+        // the layout of struct ethtool_rxnfc
+        assert_eq!(mem::size_of::<EthtoolRxnfc>(), 192);
+        assert_eq!(mem::offset_of!(EthtoolRxnfc, fs), 16);
+        assert_eq!(mem::offset_of!(EthtoolRxnfc, rule_cnt), 184);
+
+        let mut rule = EthtoolRxnfc::new(ETHTOOL_GRXCLSRULE);
+        rule.fs.flow_type = UDP_V4_FLOW;
+        rule.fs.h_u[UDP4_DST_PORT].copy_from_slice(&8001u16.to_be_bytes());
+        rule.fs.m_u[UDP4_DST_PORT].copy_from_slice(&[0xff; 2]);
+        rule.fs.ring_cookie = 3;
+        rule.fs.location = 7;
+        assert_eq!(
+            rule.fs.udp4_dst_port_rule(),
+            Some(FlowRule {
+                location: 7,
+                port: 8001,
+                queue_id: QueueId(3),
+            })
+        );
+        // also matching on the destination address
+        rule.fs.m_u[4..8].copy_from_slice(&[0xff; 4]);
+        assert_eq!(rule.fs.udp4_dst_port_rule(), None);
+    }
 }
@@ -0,0 +1,438 @@
+//! Checks of the host before enabling XDP.
+//!
+//! Most of what goes wrong the first time XDP is enabled on a host is environmental: a driver
+//! without native XDP support, an MTU the driver won't attach a program with, a firewall dropping
+//! the traffic the kernel still handles, a memlock limit too low for the UMEM. None of these make
+//! the tx or rx loops fail, packets are just lost. [`validate_environment`] looks for them up front
+//! and returns a report that can be logged or shown to the operator.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::device::{DeviceInfo, NetworkDevice, RingSizes},
+    caps::{CapSet, Capability::CAP_IPC_LOCK},
+    libc::{getrlimit, rlimit, RLIMIT_MEMLOCK, RLIM_INFINITY},
+    solana_perf::packet::PACKET_DATA_SIZE,
+    std::{fmt, fs, process::Command},
+};
+
+// Drivers with native XDP and AF_XDP zero copy support.
+const ZERO_COPY_DRIVERS: &[&str] = &["i40e", "ice", "idpf", "igc", "ixgbe", "mlx5_core", "stmmac"];
+// Drivers with native XDP support, whose sockets can only be bound in copy mode.
+const NATIVE_DRIVERS: &[&str] = &[
+    "bnxt_en",
+    "ena",
+    "gve",
+    "hv_netvsc",
+    "mlx4_en",
+    "nfp",
+    "qede",
+    "veth",
+    "virtio_net",
+];
+// Drivers refuse to attach a program in native mode when a frame doesn't fit in a page, unless
+// they support multi-buffer XDP. This is the limit with 4K pages.
+const MAX_XDP_MTU: u32 = 3498;
+const IP_UDP_HEADER_SIZE: u32 = 28;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CheckStatus {
+    Pass,
+    /// Might cause packet loss or degraded performance depending on the configuration.
+    Warn,
+    /// Will cause packet loss or prevent XDP from starting.
+    Fail,
+}
+
+impl fmt::Display for CheckStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pass => "PASS",
+            Self::Warn => "WARN",
+            Self::Fail => "FAIL",
+        })
+    }
+}
+
+/// The outcome of one of the checks of [`validate_environment`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EnvironmentReport {
+    pub checks: Vec<Check>,
+}
+
+impl EnvironmentReport {
+    /// Returns the worst status of all the checks.
+    pub fn status(&self) -> CheckStatus {
+        self.checks
+            .iter()
+            .map(|check| check.status)
+            .max()
+            .unwrap_or(CheckStatus::Pass)
+    }
+
+    /// Logs each check at a level matching its status.
+    pub fn log(&self) {
+        for check in &self.checks {
+            let level = match check.status {
+                CheckStatus::Pass => log::Level::Info,
+                CheckStatus::Warn => log::Level::Warn,
+                CheckStatus::Fail => log::Level::Error,
+            };
+            log::log!(level, "xdp environment {}: {}", check.name, check.detail);
+        }
+    }
+
+    fn push(&mut self, name: &'static str, status: CheckStatus, detail: impl Into<String>) {
+        self.checks.push(Check {
+            name,
+            status,
+            detail: detail.into(),
+        });
+    }
+}
+
+impl fmt::Display for EnvironmentReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "[{}] {}: {}", check.status, check.name, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether `if_name` and the host are set up to send and receive on the UDP `ports`
+/// through XDP.
+///
+/// Nothing is changed. Some checks need privileges to read what they check, e.g. the firewall
+/// rules, and report a warning when they can't.
+pub fn validate_environment(if_name: &str, ports: &[u16]) -> EnvironmentReport {
+    let mut report = EnvironmentReport::default();
+    let info = match NetworkDevice::new(if_name)
+        .map_err(|e| e.to_string())
+        .and_then(|dev| DeviceInfo::query(dev.if_index()).map_err(|e| e.to_string()))
+    {
+        Ok(info) => info,
+        Err(e) => {
+            report.push("interface", CheckStatus::Fail, format!("{if_name}: {e}"));
+            return report;
+        }
+    };
+
+    if info.is_up() {
+        report.push("interface", CheckStatus::Pass, format!("{if_name} is up"));
+    } else {
+        report.push(
+            "interface",
+            CheckStatus::Fail,
+            format!("{if_name} is {:?}", info.operstate),
+        );
+    }
+    check_driver(&mut report, info.driver.as_deref());
+    check_queues(&mut report, &info);
+    check_mtu(&mut report, info.mtu);
+    check_rp_filter(&mut report, if_name);
+    check_firewall(&mut report, ports);
+    check_memlock(&mut report);
+    match info.xdp_prog_id {
+        None => report.push("xdp program", CheckStatus::Pass, "no program attached"),
+        Some(id) => report.push(
+            "xdp program",
+            CheckStatus::Warn,
+            format!(
+                "program {id} is attached to {if_name}, attaching ours fails until it's detached"
+            ),
+        ),
+    }
+    report
+}
+
+fn check_driver(report: &mut EnvironmentReport, driver: Option<&str>) {
+    match driver {
+        Some(driver) if ZERO_COPY_DRIVERS.contains(&driver) => report.push(
+            "driver",
+            CheckStatus::Pass,
+            format!("{driver} supports native XDP and zero copy"),
+        ),
+        Some(driver) if NATIVE_DRIVERS.contains(&driver) => report.push(
+            "driver",
+            CheckStatus::Warn,
+            format!("{driver} supports native XDP but not zero copy"),
+        ),
+        Some(driver) => report.push(
+            "driver",
+            CheckStatus::Warn,
+            format!("{driver} isn't known to support native XDP, sockets may use generic mode"),
+        ),
+        None => report.push(
+            "driver",
+            CheckStatus::Warn,
+            "unknown driver, probably a virtual interface, sockets may use generic mode",
+        ),
+    }
+}
+
+fn check_queues(report: &mut EnvironmentReport, info: &DeviceInfo) {
+    let queues = info.rx_queues.unwrap_or(0).min(info.tx_queues.unwrap_or(0));
+    match queues {
+        0 => report.push(
+            "queues",
+            CheckStatus::Fail,
+            "the interface reports no queues",
+        ),
+        1 => report.push(
+            "queues",
+            CheckStatus::Warn,
+            "single queue, the tx loop shares it with all the received traffic",
+        ),
+        queues => report.push("queues", CheckStatus::Pass, format!("{queues} queues")),
+    }
+}
+
+fn check_mtu(report: &mut EnvironmentReport, mtu: Option<u32>) {
+    let min_mtu = PACKET_DATA_SIZE as u32 + IP_UDP_HEADER_SIZE;
+    match mtu {
+        None => report.push("mtu", CheckStatus::Warn, "the interface reports no MTU"),
+        Some(mtu) if mtu < min_mtu => report.push(
+            "mtu",
+            CheckStatus::Fail,
+            format!("{mtu} is too small for full size packets, which need {min_mtu}"),
+        ),
+        Some(mtu) if mtu > MAX_XDP_MTU => report.push(
+            "mtu",
+            CheckStatus::Warn,
+            format!(
+                "{mtu} is above {MAX_XDP_MTU}, drivers without multi-buffer XDP support refuse to \
+                 attach programs"
+            ),
+        ),
+        Some(mtu) => report.push("mtu", CheckStatus::Pass, format!("{mtu}")),
+    }
+}
+
+fn check_rp_filter(report: &mut EnvironmentReport, if_name: &str) {
+    let read = |conf: &str| {
+        fs::read_to_string(format!("/proc/sys/net/ipv4/conf/{conf}/rp_filter"))
+            .ok()
+            .and_then(|value| value.trim().parse::<u32>().ok())
+    };
+    // the kernel uses the stricter of the two
+    match read("all").max(read(if_name)) {
+        None => report.push("rp_filter", CheckStatus::Warn, "can't read rp_filter"),
+        Some(1) => report.push(
+            "rp_filter",
+            CheckStatus::Warn,
+            "strict reverse path filtering drops packets received on an interface that isn't the \
+             route back to their source",
+        ),
+        Some(mode) => report.push("rp_filter", CheckStatus::Pass, format!("mode {mode}")),
+    }
+}
+
+// Packets redirected to AF_XDP sockets never reach the firewall, but those passed to the kernel,
+// which is all of them when only the tx path uses XDP, go through it like any other.
+fn check_firewall(report: &mut EnvironmentReport, ports: &[u16]) {
+    let rulesets = [
+        ("nft", &["list", "ruleset"][..]),
+        ("iptables-save", &[][..]),
+    ]
+    .into_iter()
+    .filter_map(|(program, args)| {
+        let output = Command::new(program).args(args).output().ok()?;
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    })
+    .collect::<Vec<_>>();
+    if rulesets.is_empty() {
+        report.push(
+            "firewall",
+            CheckStatus::Warn,
+            "can't read the nftables or iptables rules, reading them needs CAP_NET_ADMIN",
+        );
+        return;
+    }
+
+    let findings = rulesets
+        .iter()
+        .flat_map(|ruleset| firewall_findings(ruleset, ports))
+        .collect::<Vec<_>>();
+    match findings.iter().map(|(status, _)| *status).max() {
+        None => report.push(
+            "firewall",
+            CheckStatus::Pass,
+            format!("no rule drops ports {ports:?}"),
+        ),
+        Some(status) => {
+            let details = findings
+                .into_iter()
+                .map(|(_, detail)| detail)
+                .collect::<Vec<_>>();
+            report.push("firewall", status, details.join("; "));
+        }
+    }
+}
+
+// Looks for rules of an `nft list ruleset` or `iptables-save` dump that drop `ports`, or an input
+// policy dropping them unless a rule accepts them. This is a heuristic: rules are matched on the
+// port alone, whatever else they match on.
+pub(crate) fn firewall_findings(ruleset: &str, ports: &[u16]) -> Vec<(CheckStatus, String)> {
+    let mut findings = Vec::new();
+    let input_drop_policy = ruleset.lines().any(|line| {
+        let line = line.trim();
+        (line.contains("hook input") && line.contains("policy drop"))
+            || line.starts_with(":INPUT DROP")
+    });
+    for &port in ports {
+        let mut accepted = false;
+        // `nft -a` appends the handle of each rule as a comment
+        for line in ruleset
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or(line).trim())
+        {
+            if !(line.contains("dport") && matches_port(line, port)) {
+                continue;
+            }
+            let lower = line.to_ascii_lowercase();
+            if lower.contains("drop") || lower.contains("reject") {
+                findings.push((
+                    CheckStatus::Fail,
+                    format!("port {port} dropped by `{line}`"),
+                ));
+            } else if lower.contains("accept") {
+                accepted = true;
+            }
+        }
+        if input_drop_policy && !accepted {
+            findings.push((
+                CheckStatus::Warn,
+                format!("the input policy drops packets and no rule accepts port {port}"),
+            ));
+        }
+    }
+    findings
+}
+
+// Whether any of the ports or port ranges (a-b in nftables, a:b in iptables) of `rule` include
+// `port`.
+fn matches_port(rule: &str, port: u16) -> bool {
+    rule.split(|c: char| c.is_whitespace() || matches!(c, ',' | '{' | '}'))
+        .filter_map(|token| {
+            let (start, end) = token.split_once(['-', ':']).unwrap_or((token, token));
+            Some((start.parse::<u16>().ok()?, end.parse::<u16>().ok()?))
+        })
+        .any(|(start, end)| (start..=end).contains(&port))
+}
+
+fn check_memlock(report: &mut EnvironmentReport) {
+    // the UMEM of a queue at the default ring sizes, see tx_loop
+    let RingSizes { rx, tx } = RingSizes::default();
+    let umem_size = ((rx + tx) * 2 * 4096) as u64;
+
+    if caps::has_cap(None, CapSet::Permitted, CAP_IPC_LOCK).unwrap_or(false) {
+        report.push(
+            "memlock",
+            CheckStatus::Pass,
+            "CAP_IPC_LOCK lifts the memlock limit",
+        );
+        return;
+    }
+    let mut limit = rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safety: libc wrapper, limit outlives the call
+    if unsafe { getrlimit(RLIMIT_MEMLOCK, &mut limit) } < 0 {
+        report.push("memlock", CheckStatus::Warn, "can't read RLIMIT_MEMLOCK");
+        return;
+    }
+    let detail = format!(
+        "RLIMIT_MEMLOCK is {} bytes, each queue locks about {umem_size} bytes",
+        limit.rlim_cur
+    );
+    if limit.rlim_cur == RLIM_INFINITY {
+        report.push("memlock", CheckStatus::Pass, "RLIMIT_MEMLOCK is unlimited");
+    } else if limit.rlim_cur < umem_size {
+        report.push("memlock", CheckStatus::Fail, detail);
+    } else {
+        report.push("memlock", CheckStatus::Warn, detail);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_firewall_findings() {
+        let nft = "table inet filter {
+            chain input {
+                type filter hook input priority filter; policy drop;
+                ct state established,related accept
+                udp dport { 8000-8010, 8020 } accept
+                udp dport 9000 drop
+            }
+        }";
+        assert_eq!(firewall_findings(nft, &[8005, 8020]), vec![]);
+        assert_eq!(
+            firewall_findings(nft, &[9000, 9001]),
+            vec![
+                (
+                    CheckStatus::Fail,
+                    "port 9000 dropped by `udp dport 9000 drop`".to_string()
+                ),
+                (
+                    CheckStatus::Warn,
+                    "the input policy drops packets and no rule accepts port 9000".to_string()
+                ),
+                (
+                    CheckStatus::Warn,
+                    "the input policy drops packets and no rule accepts port 9001".to_string()
+                ),
+            ]
+        );
+
+        let iptables = "*filter
+:INPUT ACCEPT [0:0]
+-A INPUT -p udp -m multiport --dports 8000:8010 -j REJECT
+COMMIT";
+        assert_eq!(firewall_findings(iptables, &[8011]), vec![]);
+        assert_eq!(firewall_findings(iptables, &[8001]).len(), 1);
+    }
+
+    #[test]
+    fn test_validate_environment() {
+        let report = validate_environment("lo", &[8001]);
+        let names = report
+            .checks
+            .iter()
+            .map(|check| check.name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "interface",
+                "driver",
+                "queues",
+                "mtu",
+                "rp_filter",
+                "firewall",
+                "memlock",
+                "xdp program"
+            ]
+        );
+        // loopback has no driver
+        assert_eq!(report.checks[1].status, CheckStatus::Warn);
+        assert!(report.status() >= CheckStatus::Warn);
+
+        let report = validate_environment("nonexistent0", &[]);
+        assert_eq!(report.status(), CheckStatus::Fail);
+        assert_eq!(report.checks.len(), 1);
+    }
+}
@@ -0,0 +1,194 @@
+//! Errors returned when setting up sockets, UMEMs and querying netlink.
+//!
+//! Errors carry the syscall that failed, the interface and queue it was made for, and a hint on
+//! how to fix the common cases. [`XdpError::kind`] tells apart what can't work on this host from
+//! what's misconfigured and from what's worth retrying.
+
+use {
+    crate::device::QueueId,
+    std::{fmt, io},
+    thiserror::Error,
+};
+
+/// The broad class of an [`XdpError`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XdpErrorKind {
+    /// The kernel, the driver or the NIC doesn't support the operation, e.g. zero copy.
+    Unsupported,
+    /// The operation is invalid for this host, e.g. the queue doesn't exist or a size is wrong.
+    Misconfigured,
+    /// The process is missing capabilities.
+    PermissionDenied,
+    /// The operation may succeed if retried, e.g. the device is busy or memory is short.
+    Transient,
+    Other,
+}
+
+#[derive(Debug, Error)]
+pub struct XdpError {
+    kind: XdpErrorKind,
+    syscall: &'static str,
+    if_index: Option<u32>,
+    queue_id: Option<QueueId>,
+    #[source]
+    source: io::Error,
+}
+
+impl XdpError {
+    /// Creates an error for `syscall` failing with `source`, classified by its errno.
+    pub fn new(syscall: &'static str, source: io::Error) -> Self {
+        Self {
+            kind: classify(source.raw_os_error()),
+            syscall,
+            if_index: None,
+            queue_id: None,
+            source,
+        }
+    }
+
+    /// Creates an error for `syscall` failing with `errno`.
+    pub fn last_os_error(syscall: &'static str) -> Self {
+        Self::new(syscall, io::Error::last_os_error())
+    }
+
+    /// Creates an error that doesn't come from the kernel.
+    pub fn other(kind: XdpErrorKind, syscall: &'static str, msg: impl Into<String>) -> Self {
+        Self {
+            kind,
+            ..Self::new(syscall, io::Error::other(msg.into()))
+        }
+    }
+
+    /// Records the interface and queue the operation was made for.
+    pub fn with_queue(mut self, if_index: u32, queue_id: QueueId) -> Self {
+        self.if_index = Some(if_index);
+        self.queue_id = Some(queue_id);
+        self
+    }
+
+    pub fn kind(&self) -> XdpErrorKind {
+        self.kind
+    }
+
+    /// The syscall, or operation, that failed.
+    pub fn syscall(&self) -> &'static str {
+        self.syscall
+    }
+
+    pub fn errno(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+
+    pub fn if_index(&self) -> Option<u32> {
+        self.if_index
+    }
+
+    pub fn queue_id(&self) -> Option<QueueId> {
+        self.queue_id
+    }
+
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+
+    /// Returns what to check to fix the error, if it's a common one.
+    pub fn hint(&self) -> Option<&'static str> {
+        let hint = match (self.errno()?, self.syscall) {
+            (libc::EAFNOSUPPORT, _) => "the kernel was built without AF_XDP (CONFIG_XDP_SOCKETS)",
+            (libc::EPERM | libc::EACCES, _) => "CAP_NET_ADMIN and CAP_NET_RAW are required",
+            (libc::EOPNOTSUPP, "bind") => "the driver doesn't support zero copy, use copy mode",
+            (libc::EBUSY, "bind") => "another AF_XDP socket is bound to the queue",
+            (libc::EINVAL, "bind") => {
+                "the queue may not exist, check the channel count with `ethtool -l`"
+            }
+            (libc::ENOMEM | libc::ENOBUFS, "setsockopt(XDP_UMEM_REG)") => {
+                "the UMEM is locked in memory, raise RLIMIT_MEMLOCK"
+            }
+            (libc::ENODEV, _) => "the interface doesn't exist",
+            _ => return None,
+        };
+        Some(hint)
+    }
+}
+
+impl fmt::Display for XdpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed", self.syscall)?;
+        if let Some(if_index) = self.if_index {
+            write!(f, " on if_index {if_index}")?;
+        }
+        if let Some(queue_id) = self.queue_id {
+            write!(f, " queue {}", queue_id.0)?;
+        }
+        write!(f, ": {}", self.source)?;
+        if let Some(hint) = self.hint() {
+            write!(f, " ({hint})")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<XdpError> for io::Error {
+    fn from(e: XdpError) -> Self {
+        io::Error::new(e.source.kind(), e)
+    }
+}
+
+fn classify(errno: Option<i32>) -> XdpErrorKind {
+    match errno {
+        Some(libc::EOPNOTSUPP | libc::EAFNOSUPPORT | libc::EPROTONOSUPPORT | libc::ENOPROTOOPT) => {
+            XdpErrorKind::Unsupported
+        }
+        Some(libc::EINVAL | libc::ENODEV | libc::ENXIO | libc::ENOENT | libc::ERANGE) => {
+            XdpErrorKind::Misconfigured
+        }
+        Some(libc::EPERM | libc::EACCES) => XdpErrorKind::PermissionDenied,
+        Some(
+            libc::EAGAIN
+            | libc::EBUSY
+            | libc::EINTR
+            | libc::ENOMEM
+            | libc::ENOBUFS
+            | libc::ENETDOWN,
+        ) => XdpErrorKind::Transient,
+        _ => XdpErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xdp_error() {
+        let e = XdpError::new("bind", io::Error::from_raw_os_error(libc::EBUSY))
+            .with_queue(3, QueueId(1));
+        assert_eq!(e.kind(), XdpErrorKind::Transient);
+        assert_eq!(e.errno(), Some(libc::EBUSY));
+        assert_eq!(
+            (e.if_index(), e.queue_id().map(|q| q.0)),
+            (Some(3), Some(1))
+        );
+        assert!(e.hint().is_some());
+        let msg = e.to_string();
+        assert!(
+            msg.starts_with("bind failed on if_index 3 queue 1: "),
+            "{msg}"
+        );
+
+        let e = XdpError::new(
+            "socket(AF_XDP)",
+            io::Error::from_raw_os_error(libc::EAFNOSUPPORT),
+        );
+        assert_eq!(e.kind(), XdpErrorKind::Unsupported);
+        let e = XdpError::other(XdpErrorKind::Misconfigured, "reserve", "out of frames");
+        assert_eq!(
+            (e.kind(), e.errno(), e.hint()),
+            (XdpErrorKind::Misconfigured, None, None)
+        );
+
+        // the context survives the conversion for callers that only deal with io::Error
+        let e = io::Error::from(XdpError::last_os_error("mmap"));
+        assert!(e.get_ref().unwrap().is::<XdpError>());
+    }
+}
@@ -0,0 +1,340 @@
+//! Sending through multiple network interfaces, failing over to a backup interface when the active
+//! link goes down.
+
+use {
+    crate::{
+        device::NetworkDevice,
+        netlink::{LinkEvent, LinkMonitor},
+        route::Router,
+        transport::{DatagramTransport, XdpTransport, XdpTransportConfig, XdpTransportService},
+    },
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        error::Error,
+        io,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Weak,
+        },
+        thread::{self, Builder},
+        time::Duration,
+    },
+};
+
+const NO_ACTIVE_LINK: usize = usize::MAX;
+
+pub(crate) struct Link<T> {
+    pub(crate) if_index: u32,
+    pub(crate) up: AtomicBool,
+    pub(crate) transport: T,
+}
+
+impl<T> Link<T> {
+    pub(crate) fn new(if_index: u32, transport: T) -> Self {
+        Self {
+            if_index,
+            up: AtomicBool::new(true),
+            transport,
+        }
+    }
+}
+
+/// A transport spreading traffic over multiple network links.
+pub trait LinkStateListener: DatagramTransport {
+    /// Records the link state of `if_index`. Returns true if this changed where traffic is sent.
+    fn set_link_state(&self, if_index: u32, up: bool) -> bool;
+}
+
+/// Sends through the first interface, in configuration order, whose link is up.
+///
+/// Link state changes are fed through [`set_link_state`](Self::set_link_state), normally by the
+/// link monitor started by [`MultiNicTransportService`]. Traffic fails back to a higher priority
+/// interface as soon as its link comes back up.
+pub struct FailoverTransport<T> {
+    links: Vec<Link<T>>,
+    active: AtomicUsize,
+}
+
+impl<T: DatagramTransport> FailoverTransport<T> {
+    /// Creates a transport over `(if_index, transport)` pairs given in priority order.
+    ///
+    /// All the links start out as up.
+    pub fn new(links: impl IntoIterator<Item = (u32, T)>) -> Self {
+        let links = links
+            .into_iter()
+            .map(|(if_index, transport)| Link::new(if_index, transport))
+            .collect::<Vec<_>>();
+        let active = if links.is_empty() { NO_ACTIVE_LINK } else { 0 };
+        Self {
+            links,
+            active: AtomicUsize::new(active),
+        }
+    }
+
+    /// Returns the index of the interface traffic is currently sent through.
+    pub fn active_if_index(&self) -> Option<u32> {
+        self.links
+            .get(self.active.load(Ordering::Relaxed))
+            .map(|link| link.if_index)
+    }
+}
+
+impl<T: DatagramTransport> LinkStateListener for FailoverTransport<T> {
+    /// Records the link state of `if_index`. Returns true if the active interface changed.
+    fn set_link_state(&self, if_index: u32, up: bool) -> bool {
+        let Some(link) = self.links.iter().find(|link| link.if_index == if_index) else {
+            return false;
+        };
+        link.up.store(up, Ordering::Relaxed);
+
+        let active = self
+            .links
+            .iter()
+            .position(|link| link.up.load(Ordering::Relaxed))
+            .unwrap_or(NO_ACTIVE_LINK);
+        self.active.swap(active, Ordering::Relaxed) != active
+    }
+}
+
+impl<T: DatagramTransport> DatagramTransport for FailoverTransport<T> {
+    fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+        match self.links.get(self.active.load(Ordering::Relaxed)) {
+            Some(link) => link.transport.send_to(payload, addrs),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MultiNicConfig {
+    /// The interfaces to send from, in priority order. Each gets its own tx loops and UMEMs.
+    pub links: Vec<XdpTransportConfig>,
+    /// How often the link monitor checks whether the transports have been dropped.
+    pub monitor_interval: Duration,
+}
+
+impl MultiNicConfig {
+    const DEFAULT_MONITOR_INTERVAL: Duration = Duration::from_millis(100);
+
+    pub fn new(links: Vec<XdpTransportConfig>) -> Self {
+        Self {
+            links,
+            monitor_interval: Self::DEFAULT_MONITOR_INTERVAL,
+        }
+    }
+}
+
+/// [`XdpTransportService`]s on multiple interfaces, with traffic spread over them according to
+/// their link state.
+pub struct MultiNicTransportService {
+    services: Vec<XdpTransportService>,
+    monitor: thread::JoinHandle<()>,
+}
+
+impl MultiNicTransportService {
+    /// Starts the tx loops of every configured interface and returns the (normal, high) priority
+    /// transports, which send through the first interface whose link is up.
+    #[allow(clippy::type_complexity)]
+    pub fn new(
+        config: MultiNicConfig,
+    ) -> Result<
+        (
+            Self,
+            Arc<FailoverTransport<XdpTransport>>,
+            Arc<FailoverTransport<XdpTransport>>,
+        ),
+        Box<dyn Error>,
+    > {
+        Self::with_transports(config, FailoverTransport::new)
+    }
+
+    /// Starts the tx loops of every configured interface and returns the (normal, high) priority
+    /// transports built by `make_transport` from the `(if_index, transport)` pairs of each
+    /// interface.
+    ///
+    /// Interfaces that aren't the kernel's preferred route send every packet to the gateway of
+    /// their own default route, unless [`XdpTransportConfig::dest_mac`] is set.
+    ///
+    /// The tx loops and the link monitor exit once both transports have been dropped.
+    #[allow(clippy::type_complexity)]
+    pub fn with_transports<T: LinkStateListener + 'static>(
+        config: MultiNicConfig,
+        make_transport: impl Fn(Vec<(u32, XdpTransport)>) -> T,
+    ) -> Result<(Self, Arc<T>, Arc<T>), Box<dyn Error>> {
+        if config.links.is_empty() {
+            return Err("no interfaces configured".into());
+        }
+
+        // subscribe before querying the initial state so we don't miss any change
+        let monitor = LinkMonitor::new()?;
+        let router = Router::new()?;
+        let default_if_index = router.default().ok().map(|next_hop| next_hop.if_index);
+
+        let mut services = vec![];
+        let mut links = vec![];
+        let mut priority_links = vec![];
+        let mut link_states = vec![];
+        for mut link in config.links {
+            let dev = match &link.interface {
+                Some(interface) => NetworkDevice::new(interface.clone())?,
+                None => NetworkDevice::new_from_default_route()?,
+            };
+            if link.dest_mac.is_none() && default_if_index != Some(dev.if_index()) {
+                link.dest_mac = router
+                    .default_via(dev.if_index())
+                    .ok()
+                    .and_then(|next_hop| next_hop.mac_addr);
+                match link.dest_mac {
+                    Some(mac) => log::info!("sending through {} via gateway {mac}", dev.name()),
+                    None => log::warn!(
+                        "no gateway found for {}, packets will only be sent to destinations \
+                         routed through it",
+                        dev.name()
+                    ),
+                }
+            }
+            link.interface = Some(dev.name().to_owned());
+            link_states.push((dev.if_index(), dev.is_up().unwrap_or(true)));
+
+            let (service, transport, priority_transport) = XdpTransportService::new(link)?;
+            links.push((service.if_index(), transport));
+            priority_links.push((service.if_index(), priority_transport));
+            services.push(service);
+        }
+
+        let transport = Arc::new(make_transport(links));
+        let priority_transport = Arc::new(make_transport(priority_links));
+        for (if_index, up) in link_states {
+            if !up {
+                log::warn!("link on if_index {if_index} is down");
+            }
+            transport.set_link_state(if_index, up);
+            priority_transport.set_link_state(if_index, up);
+        }
+
+        let monitor = {
+            let transport = Arc::downgrade(&transport);
+            let priority_transport = Arc::downgrade(&priority_transport);
+            Builder::new()
+                .name("solXdpLinkMon".to_owned())
+                .spawn(move || {
+                    run_link_monitor(
+                        monitor,
+                        config.monitor_interval,
+                        transport,
+                        priority_transport,
+                    )
+                })
+                .unwrap()
+        };
+
+        Ok((Self { services, monitor }, transport, priority_transport))
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        self.monitor.join()?;
+        for service in self.services {
+            service.join()?;
+        }
+        Ok(())
+    }
+}
+
+fn run_link_monitor<T: LinkStateListener>(
+    monitor: LinkMonitor,
+    interval: Duration,
+    transport: Weak<T>,
+    priority_transport: Weak<T>,
+) {
+    loop {
+        let events = match monitor.recv(interval) {
+            Ok(events) => events,
+            Err(e) => {
+                log::error!("failed to read link events: {e}");
+                thread::sleep(interval);
+                continue;
+            }
+        };
+        // don't keep the tx loops alive once everybody else is gone
+        let transports = [transport.upgrade(), priority_transport.upgrade()];
+        if transports.iter().all(Option::is_none) {
+            break;
+        }
+        for LinkEvent { if_index, up } in events {
+            let mut changed = false;
+            for transport in transports.iter().flatten() {
+                changed |= transport.set_link_state(if_index, up);
+            }
+            if changed {
+                log::warn!(
+                    "link on if_index {if_index} is {}, rerouting traffic",
+                    if up { "up" } else { "down" },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::sync::Mutex};
+
+    #[derive(Default)]
+    struct RecordingTransport {
+        sent: Mutex<Vec<Bytes>>,
+    }
+
+    impl DatagramTransport for RecordingTransport {
+        fn send_to(&self, payload: Bytes, _addrs: &[SocketAddr]) -> io::Result<()> {
+            self.sent.lock().unwrap().push(payload);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failover_transport() {
+        let transport = FailoverTransport::new([
+            (2, RecordingTransport::default()),
+            (3, RecordingTransport::default()),
+        ]);
+        let addr: SocketAddr = "10.0.0.1:8000".parse().unwrap();
+        let sent = |i: usize| transport.links[i].transport.sent.lock().unwrap().len();
+
+        assert_eq!(transport.active_if_index(), Some(2));
+        transport
+            .send_to(Bytes::from_static(b"a"), &[addr])
+            .unwrap();
+
+        // unknown interfaces are ignored, and so are changes to the backup link
+        assert!(!transport.set_link_state(7, false));
+        assert!(!transport.set_link_state(3, true));
+
+        assert!(transport.set_link_state(2, false));
+        assert_eq!(transport.active_if_index(), Some(3));
+        transport
+            .send_to(Bytes::from_static(b"b"), &[addr])
+            .unwrap();
+        assert_eq!((sent(0), sent(1)), (1, 1));
+
+        assert!(transport.set_link_state(3, false));
+        assert_eq!(transport.active_if_index(), None);
+        assert_eq!(
+            transport
+                .send_to(Bytes::from_static(b"c"), &[addr])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotConnected
+        );
+
+        // the backup comes back first, then traffic fails back to the primary
+        assert!(transport.set_link_state(3, true));
+        assert_eq!(transport.active_if_index(), Some(3));
+        assert!(transport.set_link_state(2, true));
+        assert_eq!(transport.active_if_index(), Some(2));
+        transport
+            .send_to(Bytes::from_static(b"d"), &[addr])
+            .unwrap();
+        assert_eq!((sent(0), sent(1)), (2, 1));
+    }
+}
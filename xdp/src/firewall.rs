@@ -0,0 +1,285 @@
+//! Opt-in management of the nftables rules XDP traffic depends on.
+//!
+//! Packets sent through XDP bypass the kernel, so conntrack never sees the flows and a stateful
+//! firewall treats the ICMP errors they trigger, and the ARP and neighbor discovery traffic needed
+//! to resolve next hops, as unsolicited. [`FirewallRules::install`] inserts rules accepting those
+//! at the top of every input chain, and removes them when dropped. It never touches the rules it
+//! didn't insert, and fails if an existing rule drops the validator ports rather than trying to
+//! work around it.
+
+use {
+    crate::{
+        environment::{firewall_findings, CheckStatus},
+        error::{XdpError, XdpErrorKind},
+    },
+    std::process::Command,
+};
+
+// Tags the rules we insert, so that those left behind by a crash can be found and removed.
+const COMMENT: &str = "comment \"agave-xdp\"";
+const ICMP_RULE: &str = "icmp type { destination-unreachable, time-exceeded } accept";
+const ICMPV6_RULE: &str = "icmpv6 type { destination-unreachable, packet-too-big, time-exceeded, \
+                           nd-neighbor-solicit, nd-neighbor-advert } accept";
+const ARP_RULE: &str = "arp operation { request, reply } accept";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FirewallMode {
+    /// Leave the firewall alone.
+    #[default]
+    Disabled,
+    /// Check the ruleset and log the commands that would be run, without running them.
+    DryRun,
+    /// Insert the rules. Requires `CAP_NET_ADMIN`.
+    Apply,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Chain {
+    family: String,
+    table: String,
+    name: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Rule {
+    chain: Chain,
+    handle: u64,
+}
+
+/// Rules inserted by [`FirewallRules::install`], removed when dropped.
+#[derive(Debug)]
+pub struct FirewallRules {
+    mode: FirewallMode,
+    rules: Vec<Rule>,
+}
+
+impl FirewallRules {
+    /// Checks that no rule drops `ports` and inserts the rules XDP traffic depends on, according
+    /// to `mode`.
+    ///
+    /// Rules left behind by a previous run that didn't shut down cleanly are removed first.
+    pub fn install(ports: &[u16], mode: FirewallMode) -> Result<Self, XdpError> {
+        let mut rules = Self {
+            mode,
+            rules: Vec::new(),
+        };
+        if mode == FirewallMode::Disabled {
+            return Ok(rules);
+        }
+
+        let ruleset = nft(&["-a", "list", "ruleset"])?;
+        let (blocking, warnings): (Vec<_>, Vec<_>) = firewall_findings(&ruleset, ports)
+            .into_iter()
+            .partition(|(status, _)| *status == CheckStatus::Fail);
+        for (_, warning) in warnings {
+            log::warn!("{warning}");
+        }
+        if !blocking.is_empty() {
+            let details = blocking
+                .into_iter()
+                .map(|(_, detail)| detail)
+                .collect::<Vec<_>>();
+            return Err(XdpError::other(
+                XdpErrorKind::Misconfigured,
+                "nft",
+                details.join("; "),
+            ));
+        }
+
+        let (chains, stale) = parse_ruleset(&ruleset);
+        for rule in stale {
+            log::info!(
+                "removing rule {} left in {} {} {}",
+                rule.handle,
+                rule.chain.family,
+                rule.chain.table,
+                rule.chain.name
+            );
+            rules.rules.push(rule);
+        }
+        rules.remove();
+
+        for chain in chains {
+            for rule in family_rules(&chain.family) {
+                let args = [
+                    "insert",
+                    "rule",
+                    &chain.family,
+                    &chain.table,
+                    &chain.name,
+                    rule,
+                    COMMENT,
+                ];
+                if mode == FirewallMode::DryRun {
+                    log::info!("would run: nft {}", args.join(" "));
+                    continue;
+                }
+                // on error the rules inserted so far are removed when `rules` is dropped
+                let output = nft(&[&["--echo", "--handle"][..], &args].concat())?;
+                let handle = output.lines().find_map(parse_handle).ok_or_else(|| {
+                    XdpError::other(
+                        XdpErrorKind::Other,
+                        "nft",
+                        format!("no handle in `{}`", output.trim()),
+                    )
+                })?;
+                rules.rules.push(Rule {
+                    chain: chain.clone(),
+                    handle,
+                });
+            }
+        }
+        Ok(rules)
+    }
+
+    /// The number of rules inserted.
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    fn remove(&mut self) {
+        for Rule { chain, handle } in self.rules.drain(..) {
+            let handle = handle.to_string();
+            let args = [
+                "delete",
+                "rule",
+                &chain.family,
+                &chain.table,
+                &chain.name,
+                "handle",
+                &handle,
+            ];
+            if self.mode == FirewallMode::DryRun {
+                log::info!("would run: nft {}", args.join(" "));
+            } else if let Err(e) = nft(&args) {
+                log::warn!("failed to remove firewall rule: {e}");
+            }
+        }
+    }
+}
+
+impl Drop for FirewallRules {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
+
+fn family_rules(family: &str) -> &'static [&'static str] {
+    match family {
+        "ip" => &[ICMP_RULE],
+        "ip6" => &[ICMPV6_RULE],
+        "inet" => &[ICMP_RULE, ICMPV6_RULE],
+        "arp" => &[ARP_RULE],
+        _ => &[],
+    }
+}
+
+fn nft(args: &[&str]) -> Result<String, XdpError> {
+    let output = Command::new("nft")
+        .args(args)
+        .output()
+        .map_err(|e| XdpError::new("nft", e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let kind = if stderr.contains("Operation not permitted") {
+            XdpErrorKind::PermissionDenied
+        } else {
+            XdpErrorKind::Other
+        };
+        return Err(XdpError::other(
+            kind,
+            "nft",
+            format!("nft {}: {}", args.join(" "), stderr.trim()),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Returns the input chains of an `nft -a list ruleset` dump, and the rules we inserted in any
+// chain.
+fn parse_ruleset(ruleset: &str) -> (Vec<Chain>, Vec<Rule>) {
+    let mut chains = Vec::new();
+    let mut stale = Vec::new();
+    let mut chain = None;
+    for line in ruleset.lines().map(str::trim) {
+        let mut tokens = line.split_whitespace();
+        match (tokens.next(), tokens.next(), tokens.next()) {
+            (Some("table"), Some(family), Some(table)) => {
+                chain = Some(Chain {
+                    family: family.to_string(),
+                    table: table.to_string(),
+                    name: String::new(),
+                });
+            }
+            (Some("chain"), Some(name), _) => {
+                if let Some(chain) = chain.as_mut() {
+                    chain.name = name.to_string();
+                }
+            }
+            _ => {
+                let Some(chain) = chain.as_ref() else {
+                    continue;
+                };
+                if line.starts_with("type ") && line.contains("hook input") {
+                    chains.push(chain.clone());
+                } else if line.contains(COMMENT) {
+                    if let Some(handle) = parse_handle(line) {
+                        stale.push(Rule {
+                            chain: chain.clone(),
+                            handle,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    (chains, stale)
+}
+
+fn parse_handle(line: &str) -> Option<u64> {
+    line.rsplit_once("# handle ")?.1.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ruleset() {
+        let ruleset = r#"table inet filter { # handle 1
+	chain input { # handle 1
+		type filter hook input priority filter; policy drop;
+		icmp type { destination-unreachable, time-exceeded } accept comment "agave-xdp" # handle 7
+		ct state established,related accept # handle 2
+	}
+	chain output { # handle 3
+		type filter hook output priority filter; policy accept;
+	}
+}
+table arp filter { # handle 2
+	chain input { # handle 1
+		type filter hook input priority filter; policy accept;
+	}
+}"#;
+        let chain = |family: &str| Chain {
+            family: family.to_string(),
+            table: "filter".to_string(),
+            name: "input".to_string(),
+        };
+        let (chains, stale) = parse_ruleset(ruleset);
+        assert_eq!(chains, [chain("inet"), chain("arp")]);
+        assert_eq!(
+            stale,
+            [Rule {
+                chain: chain("inet"),
+                handle: 7
+            }]
+        );
+        assert_eq!(family_rules("inet").len(), 2);
+        assert!(family_rules("netdev").is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+//! Routes gossip's outbound packets through a [`DatagramTransport`].
+//!
+//! Gossip sends lots of small packets, and pushes the same message to many peers back to back. The
+//! shim coalesces runs of identical payloads into a single multi-destination send so that the tx
+//! loop writes the payload into the UMEM once per run instead of once per peer.
+//!
+//! The tx loop drops packets to peers whose next hop MAC address isn't known. Gossip talks to peers
+//! we've never sent to before all the time, so destinations are pre-resolved against a periodically
+//! refreshed neighbor cache: peers that can't be sent through XDP yet go through the fallback
+//! socket, which makes the kernel resolve the neighbor so that subsequent packets can take the fast
+//! path.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{route::Router, transport::DatagramTransport},
+    crossbeam_channel::{Receiver, RecvTimeoutError},
+    solana_perf::packet::{bytes::Bytes, PacketBatch, PacketRef},
+    std::{
+        collections::HashMap,
+        io,
+        net::{IpAddr, SocketAddr},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread::{self, Builder},
+        time::{Duration, Instant},
+    },
+};
+
+#[derive(Clone, Debug)]
+pub struct GossipEgressConfig {
+    /// Index of the interface the XDP transport sends from.
+    pub if_index: u32,
+    /// How often the neighbor cache is reloaded from the kernel.
+    pub neighbor_refresh_interval: Duration,
+    /// Maximum number of destinations coalesced into a single send.
+    pub max_coalesced_destinations: usize,
+}
+
+impl GossipEgressConfig {
+    const DEFAULT_NEIGHBOR_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+    // gossip push fanout is well below this
+    const DEFAULT_MAX_COALESCED_DESTINATIONS: usize = 32;
+
+    pub fn new(if_index: u32) -> Self {
+        Self {
+            if_index,
+            neighbor_refresh_interval: Self::DEFAULT_NEIGHBOR_REFRESH_INTERVAL,
+            max_coalesced_destinations: Self::DEFAULT_MAX_COALESCED_DESTINATIONS,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GossipEgressStats {
+    /// Packets sent through the XDP transport.
+    pub xdp_packets: u64,
+    /// Packets sent through the fallback transport.
+    pub fallback_packets: u64,
+    /// Sends issued to either transport, after coalescing.
+    pub sends: u64,
+    /// Packets that couldn't be sent.
+    pub errors: u64,
+}
+
+/// Whether destinations can be reached through XDP.
+struct NeighborCache {
+    if_index: u32,
+    refresh_interval: Duration,
+    router: Option<Router>,
+    last_refresh: Instant,
+    resolved: HashMap<IpAddr, bool>,
+}
+
+impl NeighborCache {
+    fn new(if_index: u32, refresh_interval: Duration) -> Self {
+        let mut cache = Self {
+            if_index,
+            refresh_interval,
+            router: None,
+            last_refresh: Instant::now(),
+            resolved: HashMap::new(),
+        };
+        cache.refresh();
+        cache
+    }
+
+    fn refresh(&mut self) {
+        self.router = Router::new()
+            .inspect_err(|e| log::warn!("failed to load routes and neighbors: {e}"))
+            .ok();
+        self.resolved.clear();
+        self.last_refresh = Instant::now();
+    }
+
+    fn maybe_refresh(&mut self) {
+        if self.last_refresh.elapsed() >= self.refresh_interval {
+            self.refresh();
+        }
+    }
+
+    fn is_xdp_routable(&mut self, ip: IpAddr) -> bool {
+        let Some(router) = &self.router else {
+            return false;
+        };
+        let if_index = self.if_index;
+        *self.resolved.entry(ip).or_insert_with(|| {
+            // the tx loop only does IPv4
+            ip.is_ipv4()
+                && router.route(ip).is_ok_and(|next_hop| {
+                    next_hop.if_index == if_index && next_hop.mac_addr.is_some()
+                })
+        })
+    }
+}
+
+/// Sends gossip packets through XDP when possible, and through a fallback transport otherwise.
+pub struct GossipEgress<X, F> {
+    xdp: X,
+    fallback: F,
+    neighbors: NeighborCache,
+    max_coalesced_destinations: usize,
+    stats: GossipEgressStats,
+}
+
+impl<X: DatagramTransport, F: DatagramTransport> GossipEgress<X, F> {
+    /// Creates a new shim. `fallback` is typically the gossip socket.
+    pub fn new(xdp: X, fallback: F, config: GossipEgressConfig) -> Self {
+        Self {
+            xdp,
+            fallback,
+            neighbors: NeighborCache::new(config.if_index, config.neighbor_refresh_interval),
+            max_coalesced_destinations: config.max_coalesced_destinations.max(1),
+            stats: GossipEgressStats::default(),
+        }
+    }
+
+    /// Sends all the packets in `batch` to the address in their meta.
+    pub fn send_batch(&mut self, batch: &PacketBatch) {
+        self.neighbors.maybe_refresh();
+
+        let mut run: Option<Bytes> = None;
+        let mut xdp_addrs = Vec::new();
+        let mut fallback_addrs = Vec::new();
+        for packet in batch.iter() {
+            let Some(data) = packet.data(..) else {
+                continue;
+            };
+            let addr = packet.meta().socket_addr();
+
+            let same_payload = run.as_ref().is_some_and(|p| p[..] == *data);
+            if !same_payload
+                || xdp_addrs.len() + fallback_addrs.len() >= self.max_coalesced_destinations
+            {
+                if let Some(payload) = run.take() {
+                    self.flush(payload, &mut xdp_addrs, &mut fallback_addrs);
+                }
+                run = Some(payload_bytes(packet, data));
+            }
+
+            if self.neighbors.is_xdp_routable(addr.ip()) {
+                xdp_addrs.push(addr);
+            } else {
+                fallback_addrs.push(addr);
+            }
+        }
+        if let Some(payload) = run {
+            self.flush(payload, &mut xdp_addrs, &mut fallback_addrs);
+        }
+    }
+
+    fn flush(
+        &mut self,
+        payload: Bytes,
+        xdp_addrs: &mut Vec<SocketAddr>,
+        fallback_addrs: &mut Vec<SocketAddr>,
+    ) {
+        if !xdp_addrs.is_empty() {
+            let count = xdp_addrs.len() as u64;
+            match self.xdp.send_to(payload.clone(), xdp_addrs) {
+                Ok(()) => self.stats.xdp_packets += count,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => self.stats.errors += count,
+                Err(e) => {
+                    log::warn!("xdp gossip send failed: {e}");
+                    self.stats.errors += count;
+                }
+            }
+            self.stats.sends += 1;
+            xdp_addrs.clear();
+        }
+        if !fallback_addrs.is_empty() {
+            let count = fallback_addrs.len() as u64;
+            match self.fallback.send_to(payload, fallback_addrs) {
+                Ok(()) => self.stats.fallback_packets += count,
+                Err(_) => self.stats.errors += count,
+            }
+            self.stats.sends += 1;
+            fallback_addrs.clear();
+        }
+    }
+
+    /// Returns the stats accumulated since the last call.
+    pub fn take_stats(&mut self) -> GossipEgressStats {
+        std::mem::take(&mut self.stats)
+    }
+}
+
+fn payload_bytes(packet: PacketRef, data: &[u8]) -> Bytes {
+    match packet {
+        // avoid copying when the packet is already backed by `Bytes`
+        PacketRef::Bytes(packet) => packet.buffer().slice(..data.len()),
+        PacketRef::Packet(_) => Bytes::copy_from_slice(data),
+    }
+}
+
+/// Spawns a thread sending the batches received from `receiver` through `egress`.
+///
+/// This is a drop-in replacement for gossip's responder thread. The thread exits when `exit` is set
+/// or `receiver` is disconnected.
+pub fn spawn_gossip_egress<X, F>(
+    receiver: Receiver<PacketBatch>,
+    mut egress: GossipEgress<X, F>,
+    exit: Arc<AtomicBool>,
+) -> thread::JoinHandle<()>
+where
+    X: DatagramTransport + 'static,
+    F: DatagramTransport + 'static,
+{
+    const STATS_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+    Builder::new()
+        .name("solXdpGossipTx".to_owned())
+        .spawn(move || {
+            let mut last_report = Instant::now();
+            while !exit.load(Ordering::Relaxed) {
+                match receiver.recv_timeout(Duration::from_millis(100)) {
+                    Ok(batch) => egress.send_batch(&batch),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                if last_report.elapsed() >= STATS_REPORT_INTERVAL {
+                    let stats = egress.take_stats();
+                    log::info!(
+                        "gossip egress: xdp {} fallback {} sends {} errors {}",
+                        stats.xdp_packets,
+                        stats.fallback_packets,
+                        stats.sends,
+                        stats.errors
+                    );
+                    last_report = Instant::now();
+                }
+            }
+        })
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        solana_perf::packet::{BytesPacket, Meta},
+        std::sync::Mutex,
+    };
+
+    #[derive(Default)]
+    struct RecordingTransport {
+        sends: Mutex<Vec<(Bytes, Vec<SocketAddr>)>>,
+    }
+
+    impl DatagramTransport for Arc<RecordingTransport> {
+        fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+            self.sends.lock().unwrap().push((payload, addrs.to_vec()));
+            Ok(())
+        }
+    }
+
+    fn packet(payload: &[u8], addr: SocketAddr) -> BytesPacket {
+        let mut meta = Meta {
+            size: payload.len(),
+            ..Meta::default()
+        };
+        meta.set_socket_addr(&addr);
+        BytesPacket::new(Bytes::copy_from_slice(payload), meta)
+    }
+
+    fn addr(i: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 8000 + i))
+    }
+
+    #[test]
+    fn test_coalesce_identical_payloads() {
+        let xdp = Arc::new(RecordingTransport::default());
+        let fallback = Arc::new(RecordingTransport::default());
+        let config = GossipEgressConfig {
+            max_coalesced_destinations: 3,
+            // nothing is routable through this interface
+            ..GossipEgressConfig::new(u32::MAX)
+        };
+        let mut egress = GossipEgress::new(Arc::clone(&xdp), Arc::clone(&fallback), config);
+
+        let mut packets = (0..4).map(|i| packet(b"push", addr(i))).collect::<Vec<_>>();
+        packets.push(packet(b"pull", addr(4)));
+        packets.push(packet(b"push", addr(5)));
+        egress.send_batch(&PacketBatch::from(packets));
+
+        assert!(xdp.sends.lock().unwrap().is_empty());
+        let sends = fallback.sends.lock().unwrap();
+        assert_eq!(
+            *sends,
+            vec![
+                (Bytes::from_static(b"push"), vec![addr(0), addr(1), addr(2)]),
+                (Bytes::from_static(b"push"), vec![addr(3)]),
+                (Bytes::from_static(b"pull"), vec![addr(4)]),
+                (Bytes::from_static(b"push"), vec![addr(5)]),
+            ]
+        );
+        assert_eq!(
+            egress.take_stats(),
+            GossipEgressStats {
+                xdp_packets: 0,
+                fallback_packets: 6,
+                sends: 4,
+                errors: 0,
+            }
+        );
+    }
+
+    #[test]
+    // plain sockets are fine for a loopback test
+    #[allow(clippy::disallowed_methods)]
+    fn test_unroutable_peers_use_fallback_socket() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let fallback = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let xdp = Arc::new(RecordingTransport::default());
+        let mut egress = GossipEgress::new(
+            Arc::clone(&xdp),
+            fallback,
+            GossipEgressConfig::new(u32::MAX),
+        );
+
+        let dest = receiver.local_addr().unwrap();
+        egress.send_batch(&PacketBatch::from(vec![packet(b"ping", dest)]));
+
+        let mut buf = [0u8; 16];
+        let (len, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+        assert!(xdp.sends.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,353 @@
+//! Handing AF_XDP sockets over to another process.
+//!
+//! Creating an AF_XDP socket requires `CAP_NET_RAW`, but nothing done with it afterwards requires
+//! any capability. A privileged helper can create the sockets with [`SocketHandoff::create`] and
+//! pass them over a unix socket with [`SocketHandoff::send`]. The validator receives them with
+//! [`SocketHandoff::recv`] and drives them without `CAP_NET_ADMIN` or `CAP_NET_RAW`. Loading the
+//! XDP program and inserting the sockets in its map stay with the helper.
+//!
+//! The UMEM is allocated in a memfd passed along with the socket, so that both processes map the
+//! same memory. The helper only registers the UMEM and sizes the rings: the kernel refuses to map
+//! the rings of a bound socket, so the receiver maps them and binds the socket itself. The pages of
+//! the UMEM are charged to the `RLIMIT_MEMLOCK` of the helper's user.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        device::{DeviceQueue, QueueId},
+        error::{XdpError, XdpErrorKind},
+        socket::{Rx, Socket, Tx},
+        umem::{AllocError, PageAlignedMemory, SliceUmem, Umem},
+    },
+    libc::{
+        c_void, cmsghdr, iovec, msghdr, recvmsg, sendmsg, CMSG_DATA, CMSG_FIRSTHDR, CMSG_LEN,
+        CMSG_NXTHDR, CMSG_SPACE, MSG_CMSG_CLOEXEC, MSG_CTRUNC, SCM_RIGHTS, SOL_SOCKET,
+    },
+    std::{
+        io, mem,
+        os::{
+            fd::{AsFd as _, AsRawFd as _, BorrowedFd, FromRawFd as _, OwnedFd, RawFd},
+            unix::net::UnixStream,
+        },
+        ptr,
+    },
+};
+
+const MAGIC: [u8; 4] = *b"axsk";
+const INFO_SIZE: usize = 41;
+// the socket and the memfd
+const FD_COUNT: usize = 2;
+
+/// What the receiving process needs to know to drive a socket, sent along with its fds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandoffInfo {
+    pub if_index: u32,
+    pub queue_id: QueueId,
+    pub zero_copy: bool,
+    /// The size of the UMEM frames, some drivers require the page size.
+    pub frame_size: u32,
+    pub frame_count: u32,
+    pub rx_fill_ring_size: u32,
+    /// 0 for a tx only socket.
+    pub rx_ring_size: u32,
+    pub tx_completion_ring_size: u32,
+    pub tx_ring_size: u32,
+}
+
+impl HandoffInfo {
+    fn encode(&self) -> [u8; INFO_SIZE] {
+        let mut buf = [0u8; INFO_SIZE];
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4..8].copy_from_slice(&self.if_index.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.queue_id.0.to_le_bytes());
+        buf[16] = self.zero_copy as u8;
+        for (i, value) in [
+            self.frame_size,
+            self.frame_count,
+            self.rx_fill_ring_size,
+            self.rx_ring_size,
+            self.tx_completion_ring_size,
+            self.tx_ring_size,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            buf[17 + i * 4..][..4].copy_from_slice(&value.to_le_bytes());
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() != INFO_SIZE || buf[..4] != MAGIC {
+            return None;
+        }
+        let u32_at = |offset: usize| u32::from_le_bytes(buf[offset..][..4].try_into().unwrap());
+        Some(Self {
+            if_index: u32_at(4),
+            queue_id: QueueId(u64::from_le_bytes(buf[8..16].try_into().unwrap())),
+            zero_copy: buf[16] != 0,
+            frame_size: u32_at(17),
+            frame_count: u32_at(21),
+            rx_fill_ring_size: u32_at(25),
+            rx_ring_size: u32_at(29),
+            tx_completion_ring_size: u32_at(33),
+            tx_ring_size: u32_at(37),
+        })
+    }
+}
+
+/// An AF_XDP socket and its UMEM, ready to be passed to another process.
+#[derive(Debug)]
+pub struct SocketHandoff {
+    info: HandoffInfo,
+    socket: OwnedFd,
+    memory: OwnedFd,
+}
+
+impl SocketHandoff {
+    /// Creates a socket and its UMEM as described by `info`. Requires `CAP_NET_RAW`.
+    pub fn create(info: HandoffInfo) -> Result<Self, XdpError> {
+        let (mut memory, memory_fd) =
+            PageAlignedMemory::alloc_shared(info.frame_size as usize, info.frame_count as usize)
+                .map_err(alloc_error)?;
+        let umem = SliceUmem::new(&mut memory, info.frame_size)?;
+        let socket = Socket::<SliceUmem<'_>>::open(
+            &umem,
+            info.rx_fill_ring_size as usize,
+            info.rx_ring_size as usize,
+            info.tx_completion_ring_size as usize,
+            info.tx_ring_size as usize,
+        )
+        .map_err(|e| e.with_queue(info.if_index, info.queue_id))?;
+        // the kernel keeps the registered pages pinned, our mapping isn't needed anymore
+        Ok(Self {
+            info,
+            socket,
+            memory: memory_fd,
+        })
+    }
+
+    pub fn info(&self) -> &HandoffInfo {
+        &self.info
+    }
+
+    /// Sends the socket, its UMEM and their description over `stream`.
+    pub fn send(&self, stream: &UnixStream) -> io::Result<()> {
+        send_with_fds(
+            stream,
+            &self.info.encode(),
+            &[self.socket.as_fd(), self.memory.as_fd()],
+        )
+    }
+
+    /// Receives a socket sent with [`send`](Self::send).
+    pub fn recv(stream: &UnixStream) -> io::Result<Self> {
+        let mut data = [0u8; INFO_SIZE];
+        let (received, fds) = recv_with_fds(stream, &mut data, FD_COUNT)?;
+        let info = HandoffInfo::decode(&data[..received])
+            .ok_or_else(|| invalid_data("bad socket handoff"))?;
+        let Ok([socket, memory]) = <[OwnedFd; FD_COUNT]>::try_from(fds) else {
+            return Err(invalid_data("socket handoff without its fds"));
+        };
+        Ok(Self {
+            info,
+            socket,
+            memory,
+        })
+    }
+
+    /// Maps the UMEM memory, to create the UMEM passed to [`into_socket`](Self::into_socket).
+    pub fn map_memory(&self) -> Result<PageAlignedMemory, XdpError> {
+        let len = self.info.frame_size as usize * self.info.frame_count as usize;
+        PageAlignedMemory::map_shared(self.memory.as_fd(), len).map_err(alloc_error)
+    }
+
+    /// Maps the rings of the socket and binds it to its queue. `umem` must be created over the
+    /// memory returned by [`map_memory`](Self::map_memory).
+    #[allow(clippy::type_complexity)]
+    pub fn into_socket<U: Umem>(
+        self,
+        umem: U,
+    ) -> Result<(Socket<U>, Rx<U::Frame>, Tx<U::Frame>), XdpError> {
+        let info = self.info;
+        if umem.frame_size() != info.frame_size as usize
+            || umem.len() < info.frame_size as usize * info.frame_count as usize
+        {
+            return Err(XdpError::other(
+                XdpErrorKind::Misconfigured,
+                "bind",
+                format!(
+                    "the UMEM doesn't match the {} frames of {} bytes it was registered with",
+                    info.frame_count, info.frame_size
+                ),
+            ));
+        }
+        Socket::from_fd(
+            self.socket,
+            DeviceQueue::new(info.if_index, info.queue_id, None),
+            umem,
+            info.zero_copy,
+            info.rx_fill_ring_size as usize,
+            info.rx_ring_size as usize,
+            info.tx_completion_ring_size as usize,
+            info.tx_ring_size as usize,
+        )
+        .map_err(|e| e.with_queue(info.if_index, info.queue_id))
+    }
+}
+
+// Sends `data` with `fds` attached as SCM_RIGHTS in a single message.
+pub(crate) fn send_with_fds(
+    stream: &UnixStream,
+    data: &[u8],
+    fds: &[BorrowedFd<'_>],
+) -> io::Result<()> {
+    let fds = fds.iter().map(|fd| fd.as_raw_fd()).collect::<Vec<RawFd>>();
+    let fds_size = mem::size_of_val(fds.as_slice()) as u32;
+    let mut iov = iovec {
+        iov_base: data.as_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+    // u64s for the alignment of cmsghdr
+    // Safety: CMSG_SPACE is a pure function
+    let mut control = vec![0u64; unsafe { CMSG_SPACE(fds_size) } as usize / 8 + 1];
+
+    // Safety: the message points to buffers that outlive the call, and the control buffer is
+    // large enough for one cmsghdr carrying the fds.
+    unsafe {
+        let mut msg: msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = CMSG_SPACE(fds_size) as usize;
+        let cmsg = CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = SOL_SOCKET;
+        (*cmsg).cmsg_type = SCM_RIGHTS;
+        (*cmsg).cmsg_len = CMSG_LEN(fds_size) as usize;
+        ptr::copy_nonoverlapping(fds.as_ptr(), CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+
+        let sent = sendmsg(stream.as_raw_fd(), &msg, 0);
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if sent as usize != data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "short write sending fds",
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Receives a message sent with send_with_fds() into `data`, accepting up to `max_fds` fds.
+// Returns the length of the data and the fds.
+pub(crate) fn recv_with_fds(
+    stream: &UnixStream,
+    data: &mut [u8],
+    max_fds: usize,
+) -> io::Result<(usize, Vec<OwnedFd>)> {
+    let mut iov = iovec {
+        iov_base: data.as_mut_ptr() as *mut c_void,
+        iov_len: data.len(),
+    };
+    let fds_size = (max_fds * mem::size_of::<RawFd>()) as u32;
+    // Safety: CMSG_SPACE is a pure function
+    let mut control = vec![0u64; unsafe { CMSG_SPACE(fds_size) } as usize / 8 + 1];
+    let mut fds = Vec::with_capacity(max_fds);
+
+    // Safety: the message points to buffers that outlive the call, and the fds read from the
+    // control messages are owned by us once received.
+    let (received, flags) = unsafe {
+        let mut msg: msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut c_void;
+        msg.msg_controllen = control.len() * 8;
+
+        let received = recvmsg(stream.as_raw_fd(), &mut msg, MSG_CMSG_CLOEXEC);
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut cmsg: *const cmsghdr = CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == SOL_SOCKET && (*cmsg).cmsg_type == SCM_RIGHTS {
+                let len = (*cmsg).cmsg_len - CMSG_LEN(0) as usize;
+                let data = CMSG_DATA(cmsg) as *const RawFd;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = CMSG_NXTHDR(&msg, cmsg);
+        }
+        (received as usize, msg.msg_flags)
+    };
+    if flags & MSG_CTRUNC != 0 {
+        return Err(invalid_data("control message truncated"));
+    }
+    Ok((received, fds))
+}
+
+pub(crate) fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn alloc_error(e: AllocError) -> XdpError {
+    match e {
+        AllocError::Mmap(e) => XdpError::new("mmap(umem)", e),
+        e @ AllocError::CapExceeded { .. } => {
+            XdpError::other(XdpErrorKind::Misconfigured, "mmap(umem)", e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_recv() {
+        let info = HandoffInfo {
+            if_index: 3,
+            queue_id: QueueId(5),
+            zero_copy: true,
+            frame_size: 4096,
+            frame_count: 4,
+            rx_fill_ring_size: 2048,
+            rx_ring_size: 0,
+            tx_completion_ring_size: 1024,
+            tx_ring_size: 512,
+        };
+        assert_eq!(HandoffInfo::decode(&info.encode()), Some(info));
+        assert_eq!(HandoffInfo::decode(&info.encode()[1..]), None);
+
+        // creating an AF_XDP socket needs privileges, pass another memfd in its place
+        let (mut memory, memory_fd) = PageAlignedMemory::alloc_shared(4096, 4).unwrap();
+        let (_, socket) = PageAlignedMemory::alloc_shared(4096, 1).unwrap();
+        let handoff = SocketHandoff {
+            info,
+            socket,
+            memory: memory_fd,
+        };
+        let (a, b) = UnixStream::pair().unwrap();
+        handoff.send(&a).unwrap();
+        let received = SocketHandoff::recv(&b).unwrap();
+        assert_eq!(received.info(), &info);
+
+        // both processes see the same UMEM
+        let mut mapped = received.map_memory().unwrap();
+        assert_eq!(mapped.len(), 4 * 4096);
+        memory[4096] = 42;
+        assert_eq!(mapped[4096], 42);
+        mapped[0] = 7;
+        assert_eq!(memory[0], 7);
+
+        // the receiver refuses a UMEM of the wrong size
+        let mut small = PageAlignedMemory::alloc(4096, 2).unwrap();
+        let umem = SliceUmem::new(&mut small, 4096).unwrap();
+        assert_eq!(
+            received.into_socket(umem).err().unwrap().kind(),
+            XdpErrorKind::Misconfigured
+        );
+    }
+}
@@ -0,0 +1,249 @@
+//! Tracks where to send to based on the leader schedule.
+//!
+//! Transaction and vote forwarding send almost everything to the current leader, and switch to a
+//! new destination every few slots. [`LeaderDestinations`] holds the TPU addresses of the upcoming
+//! leaders with their routes resolved ahead of time, so that sending doesn't need a route lookup
+//! per packet and the first packets after a leader handoff don't pay for the resolution.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{netlink::MacAddress, route::NextHop},
+    std::{
+        collections::{BTreeMap, HashMap},
+        net::{IpAddr, SocketAddr},
+        ops::Range,
+    },
+};
+
+/// A leader's destination along with its resolved next hop.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeaderDestination {
+    pub addr: SocketAddr,
+    /// The slots the leader is scheduled for.
+    pub slots: Range<u64>,
+    /// The MAC address of the next hop, `None` if it couldn't be resolved.
+    pub mac_addr: Option<MacAddress>,
+    /// The interface the destination is routed through, `None` if there's no route.
+    pub if_index: Option<u32>,
+}
+
+impl LeaderDestination {
+    /// Returns whether packets can be sent to this destination without resolving its next hop.
+    pub fn is_resolved(&self) -> bool {
+        self.mac_addr.is_some() && self.if_index.is_some()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Resolved {
+    mac_addr: Option<MacAddress>,
+    if_index: Option<u32>,
+}
+
+/// Destination table following the leader schedule.
+pub struct LeaderDestinations {
+    // consecutive slots with the same leader, keyed by their first slot
+    schedule: BTreeMap<u64, (u64, SocketAddr)>,
+    resolved: HashMap<IpAddr, Resolved>,
+    lookahead_slots: u64,
+    primary: Option<LeaderDestination>,
+    next: Option<LeaderDestination>,
+}
+
+impl LeaderDestinations {
+    /// Creates an empty table. The routes to leaders scheduled within `lookahead_slots` of the
+    /// current slot are resolved ahead of time.
+    pub fn new(lookahead_slots: u64) -> Self {
+        Self {
+            schedule: BTreeMap::new(),
+            resolved: HashMap::new(),
+            lookahead_slots: lookahead_slots.max(1),
+            primary: None,
+            next: None,
+        }
+    }
+
+    /// Replaces the upcoming schedule with `leaders`, given as `(slot, leader TPU address)`
+    /// pairs in slot order.
+    pub fn set_schedule(&mut self, leaders: impl IntoIterator<Item = (u64, SocketAddr)>) {
+        self.schedule.clear();
+        let mut run: Option<(u64, u64, SocketAddr)> = None;
+        for (slot, addr) in leaders {
+            match &mut run {
+                Some((_, end, run_addr)) if *end == slot && *run_addr == addr => *end += 1,
+                _ => {
+                    if let Some((start, end, addr)) = run.take() {
+                        self.schedule.insert(start, (end, addr));
+                    }
+                    run = Some((slot, slot + 1, addr));
+                }
+            }
+        }
+        if let Some((start, end, addr)) = run {
+            self.schedule.insert(start, (end, addr));
+        }
+    }
+
+    /// Moves to `slot`, resolving the routes of the upcoming leaders with `resolve`.
+    ///
+    /// `resolve` is only called for destinations that haven't been resolved yet, so this is cheap
+    /// to call on every slot. Returns true if the primary destination changed.
+    pub fn advance(
+        &mut self,
+        slot: u64,
+        mut resolve: impl FnMut(IpAddr) -> Option<NextHop>,
+    ) -> bool {
+        // forget about the leaders that are done
+        let done = self
+            .schedule
+            .iter()
+            .take_while(|(_, (end, _))| *end <= slot)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        for start in done {
+            self.schedule.remove(&start);
+        }
+
+        // prefetch the upcoming leaders
+        let horizon = slot.saturating_add(self.lookahead_slots);
+        for (_, (_, addr)) in self.schedule.range(..horizon) {
+            self.resolved.entry(addr.ip()).or_insert_with(|| {
+                let next_hop = resolve(addr.ip());
+                Resolved {
+                    mac_addr: next_hop.as_ref().and_then(|next_hop| next_hop.mac_addr),
+                    if_index: next_hop.map(|next_hop| next_hop.if_index),
+                }
+            });
+        }
+
+        let mut upcoming = self
+            .schedule
+            .iter()
+            .filter(|(start, _)| **start <= slot || **start < horizon)
+            .map(|(start, (end, addr))| self.destination(*addr, *start..*end));
+        let primary = upcoming.next().filter(|dest| dest.slots.contains(&slot));
+        let next = match &primary {
+            Some(primary) => upcoming.find(|dest| dest.addr != primary.addr),
+            None => self
+                .schedule
+                .iter()
+                .find(|(start, _)| **start < horizon)
+                .map(|(start, (end, addr))| self.destination(*addr, *start..*end)),
+        };
+
+        let changed =
+            primary.as_ref().map(|dest| dest.addr) != self.primary.as_ref().map(|dest| dest.addr);
+        self.primary = primary;
+        self.next = next;
+        changed
+    }
+
+    /// Returns the destination of the leader of the current slot.
+    pub fn primary(&self) -> Option<&LeaderDestination> {
+        self.primary.as_ref()
+    }
+
+    /// Returns the destination of the next leader after the current one, if it's scheduled
+    /// within the lookahead window.
+    pub fn next(&self) -> Option<&LeaderDestination> {
+        self.next.as_ref()
+    }
+
+    /// Forgets all the resolved routes, for example after the neighbor table changed.
+    ///
+    /// Routes are resolved again on the next call to [`advance`](Self::advance).
+    pub fn invalidate(&mut self) {
+        self.resolved.clear();
+        self.primary = None;
+        self.next = None;
+    }
+
+    fn destination(&self, addr: SocketAddr, slots: Range<u64>) -> LeaderDestination {
+        let resolved = self.resolved.get(&addr.ip());
+        LeaderDestination {
+            addr,
+            slots,
+            mac_addr: resolved.and_then(|resolved| resolved.mac_addr),
+            if_index: resolved.and_then(|resolved| resolved.if_index),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::cell::RefCell};
+
+    fn next_hop(ip: IpAddr) -> Option<NextHop> {
+        let IpAddr::V4(v4) = ip else {
+            return None;
+        };
+        Some(NextHop {
+            mac_addr: Some(MacAddress([0, 0, 0, 0, 0, v4.octets()[3]])),
+            ip_addr: ip,
+            if_index: 1,
+            src_ip: None,
+        })
+    }
+
+    #[test]
+    fn test_leader_handoff() {
+        let a: SocketAddr = "10.0.0.1:8003".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:8003".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:8003".parse().unwrap();
+        let mut table = LeaderDestinations::new(8);
+        table.set_schedule(
+            [a, a, a, a, b, b, b, b, c, c, c, c]
+                .into_iter()
+                .enumerate()
+                .map(|(i, addr)| (100 + i as u64, addr)),
+        );
+
+        let resolved = RefCell::new(vec![]);
+        let resolve = |ip| {
+            resolved.borrow_mut().push(ip);
+            next_hop(ip)
+        };
+
+        assert!(table.advance(100, resolve));
+        let primary = table.primary().unwrap();
+        assert_eq!(primary.addr, a);
+        assert_eq!(primary.slots, 100..104);
+        assert_eq!(primary.mac_addr, Some(MacAddress([0, 0, 0, 0, 0, 1])));
+        // the next leader has been prefetched, the one after that is beyond the lookahead
+        let next = table.next().unwrap();
+        assert_eq!(next.addr, b);
+        assert!(next.is_resolved());
+        assert_eq!(*resolved.borrow(), vec![a.ip(), b.ip()]);
+
+        assert!(!table.advance(103, next_hop));
+        assert!(table.advance(104, next_hop));
+        assert_eq!(table.primary().unwrap().addr, b);
+        assert_eq!(table.next().unwrap().addr, c);
+
+        // past the end of the schedule
+        assert!(table.advance(200, next_hop));
+        assert!(table.primary().is_none());
+        assert!(table.next().is_none());
+    }
+
+    #[test]
+    fn test_gap_in_schedule() {
+        let a: SocketAddr = "10.0.0.1:8003".parse().unwrap();
+        let mut table = LeaderDestinations::new(4);
+        table.set_schedule([(10, a), (11, a)]);
+
+        // no leader yet, but the upcoming one is prefetched
+        assert!(!table.advance(7, next_hop));
+        assert!(table.primary().is_none());
+        assert_eq!(table.next().unwrap().addr, a);
+        assert!(table.next().unwrap().is_resolved());
+
+        assert!(table.advance(10, |_| None));
+        assert_eq!(table.primary().unwrap().addr, a);
+
+        // unresolvable destinations are still returned
+        table.invalidate();
+        table.advance(11, |_| None);
+        assert!(!table.primary().unwrap().is_resolved());
+    }
+}
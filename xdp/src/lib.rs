@@ -17,22 +17,105 @@
 #![warn(unsafe_attr_outside_unsafe)]
 #![warn(unsafe_op_in_unsafe_fn)]
 
+#[cfg(all(target_os = "linux", feature = "async"))]
+pub mod async_socket;
+#[cfg(target_os = "linux")]
+pub mod blocklist;
+#[cfg(target_os = "linux")]
+pub mod capture;
+#[cfg(target_os = "linux")]
+pub mod config;
+#[cfg(target_os = "linux")]
+pub mod delivery;
 #[cfg(target_os = "linux")]
 pub mod device;
 #[cfg(target_os = "linux")]
-pub mod netlink;
+pub mod environment;
+#[cfg(target_os = "linux")]
+pub mod error;
+#[cfg(target_os = "linux")]
+pub mod failover;
+#[cfg(target_os = "linux")]
+pub mod firewall;
+#[cfg(target_os = "linux")]
+pub mod gossip_egress;
+#[cfg(target_os = "linux")]
+pub mod handoff;
 #[cfg(target_os = "linux")]
+pub mod header_cache;
+#[cfg(target_os = "linux")]
+pub mod leader_destinations;
+#[cfg(target_os = "linux")]
+pub mod metrics;
+#[cfg(target_os = "linux")]
+pub mod mirror;
+#[cfg(all(target_os = "linux", any(test, feature = "test-utils")))]
+pub mod mock;
+#[cfg(target_os = "linux")]
+pub mod multicast;
+#[cfg(target_os = "linux")]
+pub mod multipath;
+#[cfg(target_os = "linux")]
+pub mod netlink;
 pub mod packet;
 #[cfg(target_os = "linux")]
-mod program;
+pub mod pcap;
+#[cfg(target_os = "linux")]
+pub mod placement;
+#[cfg(target_os = "linux")]
+pub mod program;
+#[cfg(all(target_os = "linux", feature = "quic"))]
+pub mod quic_socket;
+#[cfg(target_os = "linux")]
+pub mod reload;
+#[cfg(target_os = "linux")]
+pub mod repair;
+#[cfg(target_os = "linux")]
+pub mod replay;
+#[cfg(target_os = "linux")]
+pub mod rollout;
 #[cfg(target_os = "linux")]
 pub mod route;
 #[cfg(target_os = "linux")]
+pub mod rx_batch;
+#[cfg(target_os = "linux")]
+pub mod rx_filter;
+#[cfg(target_os = "linux")]
+pub mod rx_loop;
+#[cfg(target_os = "linux")]
+pub mod schedule;
+#[cfg(target_os = "linux")]
+pub mod shaping;
+#[cfg(target_os = "linux")]
+pub mod shred_sender;
+#[cfg(all(target_os = "linux", any(test, feature = "test-utils")))]
+pub mod sim;
+#[cfg(target_os = "linux")]
 pub mod socket;
 #[cfg(target_os = "linux")]
+pub mod submit;
+#[cfg(target_os = "linux")]
+mod trace;
+#[cfg(target_os = "linux")]
+pub mod transport;
+#[cfg(target_os = "linux")]
+pub mod tuner;
+#[cfg(target_os = "linux")]
 pub mod tx_loop;
 #[cfg(target_os = "linux")]
+pub mod tx_queues;
+#[cfg(target_os = "linux")]
+pub mod udp_socket;
+#[cfg(target_os = "linux")]
 pub mod umem;
+#[cfg(target_os = "linux")]
+pub mod upgrade;
+#[cfg(target_os = "linux")]
+pub mod warmup;
 
 #[cfg(target_os = "linux")]
-pub use program::load_xdp_program;
+pub use program::{
+    attach_rx_program, load_rx_program, load_rx_program_pinned, load_rx_program_with_options,
+    load_xdp_program, load_xdp_program_with_options, xdp_program_id, XdpAttachMode,
+    XdpAttachOptions,
+};
@@ -0,0 +1,725 @@
+//! Periodic reporting of the datapath statistics.
+//!
+//! The counters of the tx and rx loops, the XDP program and the NIC are registered with an
+//! [`XdpMetrics`], labeled with the interface and queue they belong to. A [`MetricsReporter`]
+//! then submits them as solana-metrics datapoints every interval and, if configured, serves them
+//! to Prometheus in the text exposition format. The loops a validator runs register with the
+//! [global](XdpMetrics::global) one, which its admin interface reports from.
+//!
+//! The stats of the tx and rx loops are also tagged with the CPUs and NUMA nodes their threads are
+//! pinned to, as recorded in the registry of pinned threads, so that a change in throughput can be
+//! matched with a change in placement.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        device::{NetworkDevice, QueueId},
+        rx_loop::{RxQueueStats, RxService},
+        socket::{RingOccupancyStats, XdpSocketStats},
+        tx_loop::TxLoopStats,
+        umem::umem_memory,
+    },
+    agave_cpu_utils::{pinned_threads, PinnedThread},
+    log::warn,
+    solana_metrics::datapoint::DataPoint,
+    std::{
+        collections::HashMap,
+        fmt::Write as _,
+        io::{self, ErrorKind, Read as _, Write as _},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, OnceLock,
+        },
+        thread::{self, Builder},
+        time::{Duration, Instant},
+    },
+};
+
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug)]
+pub struct MetricsConfig {
+    /// How often datapoints are submitted.
+    pub interval: Duration,
+    /// Whether to submit solana-metrics datapoints.
+    pub datapoints: bool,
+    /// The address to serve Prometheus metrics on, at any path.
+    pub prometheus_addr: Option<SocketAddr>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(10),
+            datapoints: true,
+            prometheus_addr: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+struct Field {
+    name: &'static str,
+    value: u64,
+    kind: MetricKind,
+}
+
+impl Field {
+    fn counter(name: &'static str, value: u64) -> Self {
+        Self {
+            name,
+            value,
+            kind: MetricKind::Counter,
+        }
+    }
+
+    fn gauge(name: &'static str, value: u64) -> Self {
+        Self {
+            name,
+            value,
+            kind: MetricKind::Gauge,
+        }
+    }
+}
+
+/// The threads running a tx or rx loop, recorded by the loops so that their stats can be tagged
+/// with where the threads ran.
+#[derive(Debug, Default)]
+pub struct LoopThreads {
+    tids: Mutex<Vec<libc::pid_t>>,
+}
+
+impl LoopThreads {
+    /// Records the calling thread.
+    pub fn record_current(&self) {
+        // safety: gettid has no preconditions
+        let tid = unsafe { libc::gettid() };
+        let mut tids = self.tids.lock().unwrap();
+        if !tids.contains(&tid) {
+            tids.push(tid);
+        }
+    }
+
+    // Returns the CPUs and NUMA nodes the recorded threads that are still pinned are pinned to.
+    fn placement(&self, pinned: &[PinnedThread]) -> Placement {
+        let tids = self.tids.lock().unwrap();
+        let mut placement = Placement::default();
+        for thread in pinned.iter().filter(|thread| tids.contains(&thread.tid)) {
+            placement.cpus.extend(&thread.cpus);
+            placement.numa_nodes.extend(&thread.numa_nodes);
+        }
+        for list in [&mut placement.cpus, &mut placement.numa_nodes] {
+            list.sort_unstable();
+            list.dedup();
+        }
+        placement
+    }
+}
+
+#[derive(Default)]
+struct Placement {
+    cpus: Vec<usize>,
+    numa_nodes: Vec<usize>,
+}
+
+impl Placement {
+    // Returns the tags to add, none for sources that aren't run by pinned threads.
+    fn tags(&self) -> Vec<(&'static str, String)> {
+        let join = |list: &[usize]| {
+            list.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mut tags = Vec::new();
+        if !self.cpus.is_empty() {
+            tags.push(("cpus", join(&self.cpus)));
+        }
+        if !self.numa_nodes.is_empty() {
+            tags.push(("numa_nodes", join(&self.numa_nodes)));
+        }
+        tags
+    }
+}
+
+enum Source {
+    Tx(Arc<TxLoopStats>),
+    Rx(Arc<RxQueueStats>),
+    Device(Arc<NetworkDevice>),
+    Program(u32),
+    Umem,
+}
+
+impl Source {
+    fn measurement(&self) -> &'static str {
+        match self {
+            Source::Tx(_) => "xdp-tx",
+            Source::Rx(_) => "xdp-rx",
+            Source::Device(_) => "xdp-device",
+            Source::Program(_) => "xdp-program",
+            Source::Umem => "xdp-umem",
+        }
+    }
+
+    fn threads(&self) -> Option<&LoopThreads> {
+        match self {
+            Source::Tx(stats) => Some(&stats.threads),
+            Source::Rx(stats) => Some(&stats.threads),
+            Source::Device(_) | Source::Program(_) | Source::Umem => None,
+        }
+    }
+
+    // Returns `None` if the source can't be read, e.g. the interface went away.
+    fn fields(&self) -> Option<Vec<Field>> {
+        let load = |counter: &std::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+        let fields = match self {
+            Source::Tx(stats) => {
+                let mut fields = vec![
+                    Field::counter("packets_sent", load(&stats.packets_sent)),
+                    Field::counter("priority_packets", load(&stats.priority_packets)),
+                    Field::counter("packets_dropped", load(&stats.packets_dropped)),
+                    Field::counter("packets_completed", load(&stats.packets_completed)),
+                    Field::counter("completion_latency_us", load(&stats.completion_latency_us)),
+                    Field::gauge(
+                        "max_completion_latency_us",
+                        load(&stats.max_completion_latency_us),
+                    ),
+                    Field::counter("kicks", load(&stats.kicks)),
+                    Field::counter("ring_full_drops", load(&stats.ring_full_drops)),
+                    Field::counter(
+                        "priority_ring_full_drops",
+                        load(&stats.priority_ring_full_drops),
+                    ),
+                    Field::counter("throttled_drops", load(&stats.throttled_drops)),
+                    Field::counter("blocked_drops", load(&stats.blocked_drops)),
+                    Field::counter("stall_drops", load(&stats.stall_drops)),
+                ];
+                socket_fields(&stats.socket, &mut fields);
+                ring_fields(
+                    [
+                        (
+                            "tx_ring_occupancy",
+                            "tx_ring_high_watermark",
+                            &stats.rings.tx,
+                        ),
+                        (
+                            "completion_ring_occupancy",
+                            "completion_ring_high_watermark",
+                            &stats.rings.completion,
+                        ),
+                    ],
+                    &mut fields,
+                );
+                fields
+            }
+            Source::Rx(stats) => {
+                let mut fields = vec![
+                    Field::counter("packets", load(&stats.packets)),
+                    Field::counter("batches", load(&stats.batches)),
+                    Field::counter("channel_full", load(&stats.channel_full)),
+                    Field::counter("invalid", load(&stats.invalid)),
+                    Field::counter("oversized", load(&stats.oversized)),
+                    Field::counter("copied", load(&stats.copied)),
+                    Field::gauge("fill_target", load(&stats.fill_target)),
+                ];
+                socket_fields(&stats.socket, &mut fields);
+                ring_fields(
+                    [
+                        (
+                            "fill_ring_occupancy",
+                            "fill_ring_high_watermark",
+                            &stats.rings.fill,
+                        ),
+                        (
+                            "rx_ring_occupancy",
+                            "rx_ring_high_watermark",
+                            &stats.rings.rx,
+                        ),
+                    ],
+                    &mut fields,
+                );
+                fields
+            }
+            Source::Device(device) => {
+                let stats = device.statistics().ok()?;
+                vec![
+                    Field::counter("rx_packets", stats.rx_packets),
+                    Field::counter("tx_packets", stats.tx_packets),
+                    Field::counter("rx_bytes", stats.rx_bytes),
+                    Field::counter("tx_bytes", stats.tx_bytes),
+                    Field::counter("rx_dropped", stats.rx_dropped),
+                    Field::counter("tx_dropped", stats.tx_dropped),
+                    Field::counter("rx_errors", stats.rx_errors),
+                    Field::counter("tx_errors", stats.tx_errors),
+                    Field::counter("rx_missed_errors", stats.rx_missed_errors),
+                ]
+            }
+            Source::Program(id) => {
+                // the kernel only counts while kernel.bpf_stats_enabled is set
+                let info = aya::programs::loaded_programs()
+                    .filter_map(Result::ok)
+                    .find(|info| info.id() == *id)?;
+                vec![
+                    Field::counter("run_count", info.run_count()),
+                    Field::counter("run_time_ns", info.run_time().as_nanos() as u64),
+                ]
+            }
+            Source::Umem => {
+                let memory = umem_memory();
+                let mut fields = vec![
+                    Field::gauge("committed_bytes", memory.committed as u64),
+                    Field::gauge("peak_bytes", memory.peak as u64),
+                    Field::gauge("allocations", memory.allocations as u64),
+                ];
+                if let Some(cap) = memory.cap {
+                    fields.push(Field::gauge("cap_bytes", cap as u64));
+                }
+                fields
+            }
+        };
+        Some(fields)
+    }
+}
+
+fn socket_fields(stats: &XdpSocketStats, fields: &mut Vec<Field>) {
+    let stats = stats.load();
+    fields.extend([
+        Field::counter("socket_rx_dropped", stats.rx_dropped),
+        Field::counter("socket_rx_invalid_descs", stats.rx_invalid_descs),
+        Field::counter("socket_tx_invalid_descs", stats.tx_invalid_descs),
+        Field::counter("socket_rx_ring_full", stats.rx_ring_full),
+        Field::counter(
+            "socket_rx_fill_ring_empty_descs",
+            stats.rx_fill_ring_empty_descs,
+        ),
+        Field::counter("socket_tx_ring_empty_descs", stats.tx_ring_empty_descs),
+    ]);
+}
+
+fn ring_fields<const N: usize>(
+    rings: [(&'static str, &'static str, &RingOccupancyStats); N],
+    fields: &mut Vec<Field>,
+) {
+    for (occupancy, high_watermark, stats) in rings {
+        let stats = stats.load();
+        fields.extend([
+            Field::gauge(occupancy, stats.current as u64),
+            Field::gauge(high_watermark, stats.high_watermark as u64),
+        ]);
+    }
+}
+
+/// The current values of a registered source, see [`XdpMetrics::samples`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MetricsSample {
+    /// What the values are about, e.g. `xdp-tx` or `xdp-program`.
+    pub measurement: &'static str,
+    /// Empty for process wide sources.
+    pub interface: String,
+    pub queue: Option<QueueId>,
+    /// The CPUs the threads of the loop are pinned to, empty for sources that aren't loops or
+    /// whose threads aren't pinned.
+    pub cpus: Vec<usize>,
+    /// The NUMA nodes of `cpus`.
+    pub numa_nodes: Vec<usize>,
+    pub values: Vec<(&'static str, u64)>,
+}
+
+struct Registration {
+    interface: String,
+    queue: Option<QueueId>,
+    source: Source,
+}
+
+/// The statistics to report, with the interface and queue they belong to.
+///
+/// Sources can be added at any time, including while a [`MetricsReporter`] is running.
+#[derive(Default)]
+pub struct XdpMetrics {
+    sources: Mutex<Vec<Registration>>,
+}
+
+impl XdpMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the process wide registry.
+    pub fn global() -> Arc<XdpMetrics> {
+        static GLOBAL: OnceLock<Arc<XdpMetrics>> = OnceLock::new();
+        Arc::clone(GLOBAL.get_or_init(Default::default))
+    }
+
+    fn add(&self, interface: &str, queue: Option<QueueId>, source: Source) {
+        self.sources.lock().unwrap().push(Registration {
+            interface: interface.to_string(),
+            queue,
+            source,
+        });
+    }
+
+    /// Adds the stats of a tx loop, `queue` is `None` if they're shared by multiple loops.
+    pub fn add_tx(&self, interface: &str, queue: Option<QueueId>, stats: Arc<TxLoopStats>) {
+        self.add(interface, queue, Source::Tx(stats));
+    }
+
+    pub fn add_rx(&self, interface: &str, queue: QueueId, stats: Arc<RxQueueStats>) {
+        self.add(interface, Some(queue), Source::Rx(stats));
+    }
+
+    /// Adds the stats of every queue of `service` and of its XDP program.
+    pub fn add_rx_service(&self, interface: &str, service: &RxService) {
+        for (queue, stats) in service.queue_stats().iter().enumerate() {
+            self.add_rx(interface, QueueId(queue as u64), Arc::clone(stats));
+        }
+        if let Some(id) = service.program_id() {
+            self.add_program(interface, id);
+        }
+    }
+
+    /// Adds the counters the driver keeps for `device`.
+    pub fn add_device(&self, device: Arc<NetworkDevice>) {
+        let interface = device.name().to_string();
+        self.add(&interface, None, Source::Device(device));
+    }
+
+    /// Adds the run count and run time of the BPF program with id `program_id`.
+    pub fn add_program(&self, interface: &str, program_id: u32) {
+        self.add(interface, None, Source::Program(program_id));
+    }
+
+    /// Adds the memory committed to UMEMs, which is process wide and reported without an
+    /// interface.
+    pub fn add_umem(&self) {
+        self.add("", None, Source::Umem);
+    }
+
+    // Calls `f` with the index, registration, placement and current fields of each readable
+    // source.
+    fn for_each(&self, mut f: impl FnMut(usize, &Registration, Placement, Vec<Field>)) {
+        let pinned = pinned_threads();
+        let sources = self.sources.lock().unwrap();
+        for (index, registration) in sources.iter().enumerate() {
+            if let Some(fields) = registration.source.fields() {
+                let placement = registration
+                    .source
+                    .threads()
+                    .map(|threads| threads.placement(&pinned))
+                    .unwrap_or_default();
+                f(index, registration, placement, fields);
+            }
+        }
+    }
+
+    /// Returns the current values of all the sources that can be read.
+    pub fn samples(&self) -> Vec<MetricsSample> {
+        let mut samples = Vec::new();
+        self.for_each(|_, registration, placement, fields| {
+            samples.push(MetricsSample {
+                measurement: registration.source.measurement(),
+                interface: registration.interface.clone(),
+                queue: registration.queue,
+                cpus: placement.cpus,
+                numa_nodes: placement.numa_nodes,
+                values: fields
+                    .into_iter()
+                    .map(|field| (field.name, field.value))
+                    .collect(),
+            })
+        });
+        samples
+    }
+
+    /// Renders all the stats in the Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        // the exposition format requires all the samples of a metric to be grouped together
+        let mut metrics = Vec::<(String, MetricKind, Vec<String>)>::new();
+        self.for_each(|_, registration, placement, fields| {
+            let mut labels = Vec::new();
+            if !registration.interface.is_empty() {
+                labels.push(format!(
+                    "interface=\"{}\"",
+                    escape_label(&registration.interface)
+                ));
+            }
+            if let Some(queue) = registration.queue {
+                labels.push(format!("queue=\"{}\"", queue.0));
+            }
+            for (name, value) in placement.tags() {
+                labels.push(format!("{name}=\"{value}\""));
+            }
+            let labels = if labels.is_empty() {
+                String::new()
+            } else {
+                format!("{{{}}}", labels.join(","))
+            };
+            for field in fields {
+                let mut name = format!(
+                    "agave_{}_{}",
+                    registration.source.measurement().replace('-', "_"),
+                    field.name
+                );
+                if field.kind == MetricKind::Counter {
+                    name.push_str("_total");
+                }
+                let sample = format!("{name}{labels} {}", field.value);
+                match metrics.iter_mut().find(|(n, _, _)| *n == name) {
+                    Some((_, _, samples)) => samples.push(sample),
+                    None => metrics.push((name, field.kind, vec![sample])),
+                }
+            }
+        });
+
+        let mut out = String::new();
+        for (name, kind, samples) in metrics {
+            let kind = match kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            for sample in samples {
+                writeln!(out, "{sample}").unwrap();
+            }
+        }
+        out
+    }
+
+    // Returns a datapoint per source. Counters are reported as the change since the values in
+    // `last`, which is updated.
+    fn datapoints(&self, last: &mut HashMap<(usize, &'static str), u64>) -> Vec<DataPoint> {
+        let mut points = Vec::new();
+        self.for_each(|index, registration, placement, fields| {
+            let mut point = DataPoint::new(registration.source.measurement());
+            if !registration.interface.is_empty() {
+                point.add_tag("interface", &registration.interface);
+            }
+            if let Some(queue) = registration.queue {
+                point.add_tag("queue", &queue.0.to_string());
+            }
+            for (name, value) in placement.tags() {
+                point.add_tag(name, &value);
+            }
+            for field in fields {
+                let value = match field.kind {
+                    MetricKind::Counter => {
+                        let last = last.entry((index, field.name)).or_default();
+                        let delta = field.value.saturating_sub(*last);
+                        *last = field.value;
+                        delta
+                    }
+                    MetricKind::Gauge => field.value,
+                };
+                point.add_field_i64(field.name, value as i64);
+            }
+            points.push(point);
+        });
+        points
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Reports the stats registered with an [`XdpMetrics`] until `exit` is set.
+pub struct MetricsReporter {
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl MetricsReporter {
+    /// Starts reporting, failing if the Prometheus address can't be bound.
+    pub fn new(
+        metrics: Arc<XdpMetrics>,
+        config: MetricsConfig,
+        exit: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        let mut threads = Vec::new();
+
+        if let Some(addr) = config.prometheus_addr {
+            let listener = TcpListener::bind(addr)?;
+            // nonblocking so that we notice exit
+            listener.set_nonblocking(true)?;
+            let metrics = Arc::clone(&metrics);
+            let exit = Arc::clone(&exit);
+            threads.push(
+                Builder::new()
+                    .name("solXdpPromHttp".to_string())
+                    .spawn(move || serve_prometheus(listener, &metrics, &exit))?,
+            );
+        }
+
+        if config.datapoints {
+            threads.push(
+                Builder::new()
+                    .name("solXdpMetrics".to_string())
+                    .spawn(move || {
+                        let mut last = HashMap::new();
+                        let mut last_report = Instant::now();
+                        while !exit.load(Ordering::Relaxed) {
+                            thread::sleep(EXIT_POLL_INTERVAL);
+                            if last_report.elapsed() < config.interval {
+                                continue;
+                            }
+                            last_report = Instant::now();
+                            for point in metrics.datapoints(&mut last) {
+                                solana_metrics::submit(point, log::Level::Info);
+                            }
+                        }
+                    })?,
+            );
+        }
+
+        Ok(Self { threads })
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        for handle in self.threads {
+            handle.join()?;
+        }
+        Ok(())
+    }
+}
+
+fn serve_prometheus(listener: TcpListener, metrics: &XdpMetrics, exit: &AtomicBool) {
+    while !exit.load(Ordering::Relaxed) {
+        let accepted = listener.accept();
+        match accepted {
+            Ok((stream, _)) => {
+                if let Err(e) = respond(stream, metrics) {
+                    warn!("failed to serve prometheus metrics: {e}");
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(EXIT_POLL_INTERVAL),
+            Err(e) => {
+                warn!("failed to accept prometheus connection: {e}");
+                thread::sleep(EXIT_POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+// Answers any request with the metrics. Scrapers send a single GET per connection.
+fn respond(mut stream: TcpStream, metrics: &XdpMetrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let body = metrics.render_prometheus();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: \
+         {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xdp_metrics() {
+        let metrics = XdpMetrics::new();
+        let tx = Arc::new(TxLoopStats::default());
+        let rx = Arc::new(RxQueueStats::default());
+        metrics.add_tx("eth0", None, Arc::clone(&tx));
+        metrics.add_rx("eth0", QueueId(1), Arc::clone(&rx));
+        tx.packets_sent.store(10, Ordering::Relaxed);
+        tx.max_completion_latency_us.store(7, Ordering::Relaxed);
+        rx.packets.store(3, Ordering::Relaxed);
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("# TYPE agave_xdp_tx_packets_sent_total counter\n"));
+        assert!(text.contains("agave_xdp_tx_packets_sent_total{interface=\"eth0\"} 10\n"));
+        assert!(text.contains("# TYPE agave_xdp_tx_max_completion_latency_us gauge\n"));
+        assert!(text.contains("agave_xdp_rx_packets_total{interface=\"eth0\",queue=\"1\"} 3\n"));
+
+        // datapoints report what the counters did since the last report
+        let field = |point: &DataPoint, name: &str| {
+            point
+                .fields
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.clone())
+                .unwrap()
+        };
+        let mut last = HashMap::new();
+        let points = metrics.datapoints(&mut last);
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].name, "xdp-tx");
+        assert_eq!(field(&points[0], "packets_sent"), "10i");
+        assert_eq!(
+            points[1].tags,
+            vec![
+                ("interface", "eth0".to_string()),
+                ("queue", "1".to_string())
+            ]
+        );
+
+        tx.packets_sent.store(15, Ordering::Relaxed);
+        let points = metrics.datapoints(&mut last);
+        assert_eq!(field(&points[0], "packets_sent"), "5i");
+        assert_eq!(field(&points[0], "max_completion_latency_us"), "7i");
+        assert_eq!(field(&points[1], "packets"), "0i");
+
+        let samples = metrics.samples();
+        assert_eq!(samples[1].measurement, "xdp-rx");
+        assert_eq!(samples[1].queue, Some(QueueId(1)));
+        assert!(samples[0].values.contains(&("packets_sent", 15)));
+
+        // process wide stats have no labels
+        metrics.add_umem();
+        assert!(metrics
+            .render_prometheus()
+            .contains("\nagave_xdp_umem_committed_bytes "));
+    }
+
+    #[test]
+    fn test_xdp_metrics_placement() {
+        let metrics = XdpMetrics::new();
+        let tx = Arc::new(TxLoopStats::default());
+        metrics.add_tx("eth0", Some(QueueId(0)), Arc::clone(&tx));
+        metrics.add_umem();
+
+        // threads that aren't pinned don't tag the stats
+        tx.threads.record_current();
+        assert!(metrics.samples()[0].cpus.is_empty());
+
+        // the thread has to be running to be found in the registry
+        thread::spawn(move || {
+            let cpu = agave_cpu_utils::cpu_affinity().unwrap()[0];
+            agave_cpu_utils::pin_thread("xdp-tx", [cpu]).unwrap();
+            tx.threads.record_current();
+
+            let samples = metrics.samples();
+            assert_eq!(samples[0].cpus, [cpu]);
+            assert!(samples[1].cpus.is_empty());
+            assert!(metrics.render_prometheus().contains(&format!(
+                "agave_xdp_tx_packets_sent_total{{interface=\"eth0\",queue=\"0\",cpus=\"{cpu}\""
+            )));
+            let points = metrics.datapoints(&mut HashMap::new());
+            assert!(points[0].tags.contains(&("cpus", cpu.to_string())));
+            assert_eq!(
+                points[0].tags.iter().any(|(name, _)| *name == "numa_nodes"),
+                !samples[0].numa_nodes.is_empty()
+            );
+        })
+        .join()
+        .unwrap();
+    }
+}
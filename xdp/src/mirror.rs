@@ -0,0 +1,133 @@
+//! Mirroring of transmitted frames to an in-process channel.
+//!
+//! Like the [pcap tap](crate::pcap::PcapTap), but the copies go to a channel instead of a file,
+//! so they can be inspected live, e.g. to check the frames built by the tx loop on a canary. The
+//! tx loop never blocks on the channel: frames that don't fit are dropped and counted.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::device::QueueId,
+    crossbeam_channel::{Sender, TrySendError},
+    std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        time::SystemTime,
+    },
+};
+
+/// A copy of a transmitted frame.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MirroredFrame {
+    /// The queue the frame was sent on.
+    pub queue_id: QueueId,
+    pub timestamp: SystemTime,
+    /// Length of the frame on the wire. Can be larger than `data.len()` if the frame was
+    /// truncated to the snaplen.
+    pub orig_len: u32,
+    pub data: Vec<u8>,
+}
+
+/// Configuration for mirroring transmitted frames to a channel.
+#[derive(Clone, Debug)]
+pub struct MirrorConfig {
+    /// Where the copies are sent. All the tx loops share it.
+    pub sender: Sender<MirroredFrame>,
+    /// Mirror one every `sample_rate` frames. 1 mirrors every frame.
+    pub sample_rate: u32,
+    /// Maximum number of bytes copied for each frame.
+    pub snaplen: u32,
+    /// Counts the frames dropped because the channel was full.
+    pub dropped: Arc<AtomicU64>,
+}
+
+impl MirrorConfig {
+    pub fn new(sender: Sender<MirroredFrame>) -> Self {
+        Self {
+            sender,
+            sample_rate: 1,
+            snaplen: u32::MAX,
+            dropped: Arc::default(),
+        }
+    }
+}
+
+/// A sampling mirror on the tx path of a queue.
+///
+/// The frames are copied on the tx loop thread, so mirroring every frame at high packet rates
+/// will reduce throughput.
+pub struct TxMirror {
+    config: MirrorConfig,
+    queue_id: QueueId,
+    seen: u32,
+}
+
+impl TxMirror {
+    pub fn new(config: MirrorConfig, queue_id: QueueId) -> Self {
+        Self {
+            config: MirrorConfig {
+                sample_rate: config.sample_rate.max(1),
+                ..config
+            },
+            queue_id,
+            seen: 0,
+        }
+    }
+
+    /// Offers a transmitted frame to the mirror, which copies it if it's selected by sampling.
+    #[inline]
+    pub fn mirror(&mut self, frame: &[u8]) {
+        self.seen += 1;
+        if self.seen < self.config.sample_rate {
+            return;
+        }
+        self.seen = 0;
+        self.mirror_slow(frame);
+    }
+
+    #[inline(never)]
+    fn mirror_slow(&mut self, frame: &[u8]) {
+        let captured = frame.len().min(self.config.snaplen as usize);
+        let mirrored = MirroredFrame {
+            queue_id: self.queue_id,
+            timestamp: SystemTime::now(),
+            orig_len: frame.len() as u32,
+            data: frame[..captured].to_vec(),
+        };
+        match self.config.sender.try_send(mirrored) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                self.config.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crossbeam_channel::bounded};
+
+    #[test]
+    fn test_mirror() {
+        let (sender, receiver) = bounded(2);
+        let config = MirrorConfig {
+            sample_rate: 2,
+            snaplen: 4,
+            ..MirrorConfig::new(sender)
+        };
+        let mut mirror = TxMirror::new(config.clone(), QueueId(3));
+        for i in 0..8u8 {
+            mirror.mirror(&[i; 6]);
+        }
+
+        // every other frame is mirrored, until the channel is full
+        let frames = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].queue_id, QueueId(3));
+        assert_eq!(frames[0].orig_len, 6);
+        assert_eq!(frames[0].data, [1; 4]);
+        assert_eq!(frames[1].data, [3; 4]);
+        assert_eq!(config.dropped.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,283 @@
+//! In-memory stand-ins for a UMEM and the transmit side of a socket, for unit tests.
+//!
+//! Unlike the [simulation backend](crate::sim), nothing runs in the background: frames only move
+//! from the ring to the completion queue when the test calls [`MockTxQueue::transmit`], or when
+//! the queue is kicked if the test gave it a driver with [`MockTxQueue::on_kick`]. A test can
+//! therefore stop the driver at any point and check how the code under test copes with a full ring
+//! or late completions, and every run behaves the same. Neither needs privileges or a NIC.
+//!
+//! Enabled by the `test-utils` feature.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        socket::{RingFull, TxQueue},
+        umem::{Frame, FrameOffset, Umem},
+    },
+    std::{collections::VecDeque, io},
+};
+
+#[derive(Debug)]
+pub struct MockFrame {
+    offset: usize,
+    len: usize,
+}
+
+impl Frame for MockFrame {
+    fn offset(&self) -> FrameOffset {
+        FrameOffset(self.offset)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+}
+
+/// A UMEM backed by a `Vec`.
+///
+/// Frames are reserved in order of their offset, and releasing a frame that isn't reserved
+/// panics, which catches double releases and leaks in the code under test.
+pub struct MockUmem {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    // popped from the back so that frame 0 is reserved first
+    available: Vec<usize>,
+    reserved: Vec<bool>,
+}
+
+impl MockUmem {
+    pub fn new(frame_size: usize, frame_count: usize) -> Self {
+        Self {
+            buffer: vec![0; frame_size * frame_count],
+            frame_size,
+            available: (0..frame_count).rev().collect(),
+            reserved: vec![false; frame_count],
+        }
+    }
+
+    pub fn is_reserved(&self, offset: FrameOffset) -> bool {
+        self.reserved[offset.0 / self.frame_size]
+    }
+}
+
+impl Umem for MockUmem {
+    type Frame = MockFrame;
+
+    fn as_ptr(&self) -> *const u8 {
+        self.buffer.as_ptr()
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.buffer.as_mut_ptr()
+    }
+
+    fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn reserve(&mut self) -> Option<MockFrame> {
+        let index = self.available.pop()?;
+        self.reserved[index] = true;
+        Some(MockFrame {
+            offset: index * self.frame_size,
+            len: 0,
+        })
+    }
+
+    fn release(&mut self, frame: FrameOffset) {
+        let index = frame.0 / self.frame_size;
+        assert!(
+            self.reserved[index],
+            "released frame {} which isn't reserved",
+            frame.0
+        );
+        self.reserved[index] = false;
+        self.available.push(index);
+    }
+
+    fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    fn capacity(&self) -> usize {
+        self.reserved.len()
+    }
+
+    fn available(&self) -> usize {
+        self.available.len()
+    }
+}
+
+/// A tx ring and a driver that only sends when told to.
+///
+/// Submitted frames take up a slot in the ring until they're transmitted, and are then returned
+/// by [`complete`](TxQueue::complete) in the order they were submitted.
+pub struct MockTxQueue<F: Frame> {
+    capacity: usize,
+    // submitted but not committed
+    pending: VecDeque<F>,
+    committed: VecDeque<F>,
+    completed: VecDeque<FrameOffset>,
+    kicks: usize,
+    driver: Option<Box<dyn FnMut(usize) -> usize>>,
+}
+
+impl<F: Frame> MockTxQueue<F> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pending: VecDeque::new(),
+            committed: VecDeque::new(),
+            completed: VecDeque::new(),
+            kicks: 0,
+            driver: None,
+        }
+    }
+
+    /// Calls `driver` with the number of committed frames on every kick, and transmits as many
+    /// frames as it returns, like a driver woken up by the kick.
+    ///
+    /// This lets code that kicks the queue and waits for completions, like a tx loop, run against
+    /// the mock on its own.
+    pub fn on_kick(&mut self, driver: impl FnMut(usize) -> usize + 'static) {
+        self.driver = Some(Box::new(driver));
+    }
+
+    /// Sends up to `max` committed frames, in order, and returns them so that their contents can
+    /// be checked with [`Umem::map_frame`]. They're completed right away.
+    pub fn transmit(&mut self, max: usize) -> Vec<F> {
+        let count = max.min(self.committed.len());
+        let frames = self.committed.drain(..count).collect::<Vec<_>>();
+        self.completed
+            .extend(frames.iter().map(|frame| frame.offset()));
+        frames
+    }
+
+    /// Returns the number of committed frames waiting to be transmitted.
+    pub fn committed(&self) -> usize {
+        self.committed.len()
+    }
+
+    /// Returns how many times the driver was kicked.
+    pub fn kicks(&self) -> usize {
+        self.kicks
+    }
+}
+
+impl<F: Frame> TxQueue for MockTxQueue<F> {
+    type Frame = F;
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn available(&self) -> usize {
+        self.capacity - self.pending.len() - self.committed.len()
+    }
+
+    fn submit(&mut self, frame: F, _options: u32) -> Result<(), RingFull<F>> {
+        if self.available() == 0 {
+            return Err(RingFull(frame));
+        }
+        self.pending.push_back(frame);
+        Ok(())
+    }
+
+    fn commit(&mut self) {
+        self.committed.append(&mut self.pending);
+    }
+
+    fn kick(&mut self) -> Result<(), io::Error> {
+        self.kicks += 1;
+        if let Some(driver) = self.driver.as_mut() {
+            let frames = driver(self.committed.len());
+            self.transmit(frames);
+        }
+        Ok(())
+    }
+
+    fn complete(&mut self) -> Option<FrameOffset> {
+        self.completed.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            submit::{submit, Submission, Submitted},
+            umem::{PageAlignedMemory, SliceUmem},
+        },
+        std::io::IoSlice,
+    };
+
+    #[test]
+    fn test_mock_tx_queue() {
+        let mut umem = MockUmem::new(2048, 4);
+        let mut queue = MockTxQueue::new(2);
+
+        for payload in [b"one", b"two", b"six"] {
+            let mut frame = umem.reserve().unwrap();
+            frame.set_len(payload.len());
+            umem.map_frame_mut(&frame).copy_from_slice(payload);
+            if let Err(RingFull(frame)) = queue.submit(frame, 0) {
+                // the third frame doesn't fit until the driver sends something
+                assert_eq!(frame.offset().0, 4096);
+                umem.release(frame.offset());
+            }
+        }
+        assert_eq!(queue.available(), 0);
+        // nothing is sent before being committed
+        assert!(queue.transmit(usize::MAX).is_empty());
+        queue.commit();
+        queue.kick().unwrap();
+
+        let sent = queue.transmit(1);
+        assert_eq!(umem.map_frame(&sent[0]), b"one");
+        assert_eq!(queue.committed(), 1);
+        assert_eq!(queue.available(), 1);
+        let offset = queue.complete().unwrap();
+        assert!(umem.is_reserved(offset));
+        umem.release(offset);
+        assert_eq!(queue.complete().map(|offset| offset.0), None);
+        assert_eq!(umem.available(), 3);
+        assert_eq!(queue.kicks(), 1);
+    }
+
+    #[test]
+    fn test_submit_mock_tx_queue() {
+        // the crate's own helpers work against the mock without a socket
+        let mut memory = PageAlignedMemory::alloc(2048, 4).unwrap();
+        let mut umem = SliceUmem::new(&mut memory, 2048).unwrap();
+        let mut queue = MockTxQueue::new(4);
+        let header = [0xaa; 4];
+        let payload = [IoSlice::new(b"hello")];
+        let mut submissions = (0..3)
+            .map(|_| Submission {
+                set_udp_len: false,
+                ..Submission::udp(&header, &payload)
+            })
+            .collect();
+
+        assert_eq!(
+            submit(&mut queue, &mut umem, &mut submissions),
+            Submitted {
+                packets: 3,
+                rejected: 0
+            }
+        );
+        queue.commit();
+        for frame in queue.transmit(usize::MAX) {
+            assert_eq!(umem.map_frame(&frame), b"\xaa\xaa\xaa\xaahello");
+        }
+        while let Some(offset) = queue.complete() {
+            umem.release(offset);
+        }
+        assert_eq!(umem.available(), 4);
+    }
+}
@@ -0,0 +1,156 @@
+//! Multicast group membership.
+//!
+//! Frames sent by the tx loop bypass the kernel, so the kernel doesn't know which groups we care
+//! about. Switches doing IGMP/MLD snooping only forward a group to the ports that joined it, and
+//! NICs drop multicast frames they weren't told to accept. [`MulticastMembership`] joins groups
+//! through a regular socket so that the kernel sends the reports and programs the NIC filters,
+//! for as long as the membership is alive.
+
+use {
+    crate::{device::NetworkDevice, error::XdpError},
+    libc::{in_addr, ip_mreqn, setsockopt, IPPROTO_IP, IP_ADD_MEMBERSHIP, IP_DROP_MEMBERSHIP},
+    std::{
+        io, mem,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket},
+        os::fd::AsRawFd as _,
+    },
+};
+
+/// Multicast groups joined on an interface. Dropping it leaves all of them.
+pub struct MulticastMembership {
+    if_index: u32,
+    // created on the first join of each family
+    socket_v4: Option<UdpSocket>,
+    socket_v6: Option<UdpSocket>,
+    groups: Vec<IpAddr>,
+}
+
+impl MulticastMembership {
+    pub fn new(dev: &NetworkDevice) -> Self {
+        Self {
+            if_index: dev.if_index(),
+            socket_v4: None,
+            socket_v6: None,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Joins `group`, which the kernel announces with an IGMP or MLD report.
+    pub fn join(&mut self, group: IpAddr) -> Result<(), XdpError> {
+        if self.groups.contains(&group) {
+            return Ok(());
+        }
+        match group {
+            IpAddr::V4(group) => {
+                let socket = Self::socket(&mut self.socket_v4, Ipv4Addr::UNSPECIFIED.into())?;
+                set_membership_v4(socket, self.if_index, group, IP_ADD_MEMBERSHIP)
+                    .map_err(|e| XdpError::new("setsockopt(IP_ADD_MEMBERSHIP)", e))?;
+            }
+            IpAddr::V6(group) => {
+                let socket = Self::socket(&mut self.socket_v6, Ipv6Addr::UNSPECIFIED.into())?;
+                socket
+                    .join_multicast_v6(&group, self.if_index)
+                    .map_err(|e| XdpError::new("setsockopt(IPV6_ADD_MEMBERSHIP)", e))?;
+            }
+        }
+        self.groups.push(group);
+        Ok(())
+    }
+
+    /// Leaves `group`. Does nothing if it wasn't joined.
+    pub fn leave(&mut self, group: IpAddr) -> Result<(), XdpError> {
+        let Some(index) = self.groups.iter().position(|g| *g == group) else {
+            return Ok(());
+        };
+        match (group, &self.socket_v4, &self.socket_v6) {
+            (IpAddr::V4(group), Some(socket), _) => {
+                set_membership_v4(socket, self.if_index, group, IP_DROP_MEMBERSHIP)
+                    .map_err(|e| XdpError::new("setsockopt(IP_DROP_MEMBERSHIP)", e))?
+            }
+            (IpAddr::V6(group), _, Some(socket)) => socket
+                .leave_multicast_v6(&group, self.if_index)
+                .map_err(|e| XdpError::new("setsockopt(IPV6_DROP_MEMBERSHIP)", e))?,
+            _ => unreachable!("joined without a socket"),
+        }
+        self.groups.swap_remove(index);
+        Ok(())
+    }
+
+    pub fn groups(&self) -> &[IpAddr] {
+        &self.groups
+    }
+
+    // the socket is never read from or sent on, it only holds the memberships
+    #[allow(clippy::disallowed_methods)]
+    fn socket(socket: &mut Option<UdpSocket>, addr: IpAddr) -> Result<&UdpSocket, XdpError> {
+        if socket.is_none() {
+            *socket = Some(UdpSocket::bind((addr, 0)).map_err(|e| XdpError::new("bind", e))?);
+        }
+        Ok(socket.as_ref().unwrap())
+    }
+}
+
+// UdpSocket::join_multicast_v4 takes the address of the interface rather than its index,
+// which is ambiguous when multiple interfaces share an address
+fn set_membership_v4(
+    socket: &UdpSocket,
+    if_index: u32,
+    group: Ipv4Addr,
+    op: i32,
+) -> io::Result<()> {
+    let mreq = ip_mreqn {
+        imr_multiaddr: in_addr {
+            s_addr: u32::from_ne_bytes(group.octets()),
+        },
+        imr_address: in_addr { s_addr: 0 },
+        imr_ifindex: if_index as i32,
+    };
+    // Safety: libc wrapper, mreq outlives the call
+    if unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            IPPROTO_IP,
+            op,
+            &mreq as *const _ as *const _,
+            mem::size_of::<ip_mreqn>() as u32,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::netlink::MacAddress};
+
+    #[test]
+    fn test_multicast_membership() {
+        let group: IpAddr = "239.1.2.3".parse().unwrap();
+        assert_eq!(
+            MacAddress::multicast(group),
+            Some(MacAddress([0x01, 0x00, 0x5e, 0x01, 0x02, 0x03]))
+        );
+        // only the low 23 bits of the group make it into the MAC
+        assert_eq!(
+            MacAddress::multicast("224.129.2.3".parse().unwrap()),
+            MacAddress::multicast("239.1.2.3".parse().unwrap())
+        );
+        assert_eq!(
+            MacAddress::multicast("ff02::1:ff00:1234".parse().unwrap()),
+            Some(MacAddress([0x33, 0x33, 0xff, 0x00, 0x12, 0x34]))
+        );
+        assert_eq!(MacAddress::multicast("10.0.0.1".parse().unwrap()), None);
+
+        let lo = NetworkDevice::new("lo").unwrap();
+        let mut membership = MulticastMembership::new(&lo);
+        membership.join(group).unwrap();
+        membership.join(group).unwrap();
+        assert_eq!(membership.groups(), &[group]);
+        membership.leave(group).unwrap();
+        assert!(membership.groups().is_empty());
+        // leaving a group that wasn't joined is a no-op
+        membership.leave(group).unwrap();
+    }
+}
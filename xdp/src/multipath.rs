@@ -0,0 +1,213 @@
+//! Spraying traffic over multiple network interfaces at the same time.
+//!
+//! Each destination sticks to one interface so that its packets aren't reordered. Destinations are
+//! assigned with rendezvous hashing, so when a link goes down only the destinations that were sent
+//! through it move, and they move back once it comes back up.
+
+use {
+    crate::{
+        failover::{Link, LinkStateListener, MultiNicConfig, MultiNicTransportService},
+        transport::{DatagramTransport, XdpTransport},
+    },
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        error::Error,
+        hash::{DefaultHasher, Hash, Hasher},
+        io,
+        net::SocketAddr,
+        sync::{atomic::Ordering, Arc},
+    },
+};
+
+/// What identifies a flow when picking the interface to send it through.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MultipathHash {
+    /// All the traffic to an IP address goes through the same interface.
+    DestinationIp,
+    /// All the traffic to an IP address and port goes through the same interface.
+    #[default]
+    DestinationAddr,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MultipathConfig {
+    pub hash: MultipathHash,
+    /// Changes which interface each destination is assigned to.
+    pub seed: u64,
+}
+
+/// Sends through all the interfaces whose link is up, sticking each destination to one of them.
+pub struct MultipathTransport<T> {
+    links: Vec<Link<T>>,
+    config: MultipathConfig,
+}
+
+impl<T: DatagramTransport> MultipathTransport<T> {
+    /// Creates a transport over `(if_index, transport)` pairs. All the links start out as up.
+    pub fn new(links: impl IntoIterator<Item = (u32, T)>, config: MultipathConfig) -> Self {
+        Self {
+            links: links
+                .into_iter()
+                .map(|(if_index, transport)| Link::new(if_index, transport))
+                .collect(),
+            config,
+        }
+    }
+
+    /// Returns the index of the interface traffic to `addr` is currently sent through.
+    pub fn if_index_for(&self, addr: &SocketAddr) -> Option<u32> {
+        self.link_for(addr).map(|i| self.links[i].if_index)
+    }
+
+    fn link_for(&self, addr: &SocketAddr) -> Option<usize> {
+        self.links
+            .iter()
+            .enumerate()
+            .filter(|(_, link)| link.up.load(Ordering::Relaxed))
+            .max_by_key(|(_, link)| {
+                let mut hasher = DefaultHasher::new();
+                self.config.seed.hash(&mut hasher);
+                link.if_index.hash(&mut hasher);
+                match self.config.hash {
+                    MultipathHash::DestinationIp => addr.ip().hash(&mut hasher),
+                    MultipathHash::DestinationAddr => addr.hash(&mut hasher),
+                }
+                hasher.finish()
+            })
+            .map(|(i, _)| i)
+    }
+}
+
+impl<T: DatagramTransport> LinkStateListener for MultipathTransport<T> {
+    /// Records the link state of `if_index`. Returns true if the link state changed, which moves
+    /// the destinations assigned to it.
+    fn set_link_state(&self, if_index: u32, up: bool) -> bool {
+        self.links
+            .iter()
+            .find(|link| link.if_index == if_index)
+            .is_some_and(|link| link.up.swap(up, Ordering::Relaxed) != up)
+    }
+}
+
+impl<T: DatagramTransport> DatagramTransport for MultipathTransport<T> {
+    fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+        let mut per_link = vec![Vec::new(); self.links.len()];
+        for addr in addrs {
+            let Some(i) = self.link_for(addr) else {
+                return Err(io::ErrorKind::NotConnected.into());
+            };
+            per_link[i].push(*addr);
+        }
+
+        let mut result = Ok(());
+        for (link, addrs) in self.links.iter().zip(per_link) {
+            if addrs.is_empty() {
+                continue;
+            }
+            if let Err(e) = link.transport.send_to(payload.clone(), &addrs) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl MultiNicTransportService {
+    /// Starts the tx loops of every configured interface and returns the (normal, high) priority
+    /// transports, which spread traffic over all the interfaces whose link is up.
+    #[allow(clippy::type_complexity)]
+    pub fn new_multipath(
+        config: MultiNicConfig,
+        multipath: MultipathConfig,
+    ) -> Result<
+        (
+            Self,
+            Arc<MultipathTransport<XdpTransport>>,
+            Arc<MultipathTransport<XdpTransport>>,
+        ),
+        Box<dyn Error>,
+    > {
+        Self::with_transports(config, |links| MultipathTransport::new(links, multipath))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::sync::Mutex};
+
+    #[derive(Default)]
+    struct RecordingTransport {
+        sent: Mutex<Vec<SocketAddr>>,
+    }
+
+    impl DatagramTransport for RecordingTransport {
+        fn send_to(&self, _payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+            self.sent.lock().unwrap().extend_from_slice(addrs);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_multipath_stickiness() {
+        let transport = MultipathTransport::new(
+            [
+                (2, RecordingTransport::default()),
+                (3, RecordingTransport::default()),
+            ],
+            MultipathConfig::default(),
+        );
+        let addrs = (0..64)
+            .map(|i| SocketAddr::from(([10, 0, 0, i], 8000)))
+            .collect::<Vec<_>>();
+        transport.send_to(Bytes::from_static(b"a"), &addrs).unwrap();
+
+        // every destination went through exactly one link, and both links are used
+        let sent = |i: usize| transport.links[i].transport.sent.lock().unwrap().clone();
+        let (first, second) = (sent(0), sent(1));
+        assert_eq!(first.len() + second.len(), addrs.len());
+        assert!(!first.is_empty() && !second.is_empty());
+        for addr in &first {
+            assert_eq!(transport.if_index_for(addr), Some(2));
+        }
+
+        // only the destinations of the link that went down move
+        assert!(transport.set_link_state(3, false));
+        assert!(!transport.set_link_state(3, false));
+        for addr in &addrs {
+            assert_eq!(transport.if_index_for(addr), Some(2));
+        }
+        assert!(transport.set_link_state(3, true));
+        for addr in &second {
+            assert_eq!(transport.if_index_for(addr), Some(3));
+        }
+
+        assert!(transport.set_link_state(2, false));
+        assert!(transport.set_link_state(3, false));
+        assert_eq!(
+            transport
+                .send_to(Bytes::from_static(b"b"), &addrs)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotConnected
+        );
+    }
+
+    #[test]
+    fn test_multipath_hash() {
+        let transport = MultipathTransport::new(
+            (1..=4).map(|if_index| (if_index, RecordingTransport::default())),
+            MultipathConfig {
+                hash: MultipathHash::DestinationIp,
+                seed: 42,
+            },
+        );
+        // all the ports of a host go through the same link
+        let if_index = transport.if_index_for(&SocketAddr::from(([10, 0, 0, 1], 8000)));
+        for port in 8001..8100 {
+            assert_eq!(
+                transport.if_index_for(&SocketAddr::from(([10, 0, 0, 1], port))),
+                if_index
+            );
+        }
+    }
+}
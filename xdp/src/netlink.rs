@@ -1,20 +1,38 @@
 #![allow(clippy::arithmetic_side_effects)]
 
 use {
+    crate::error::{XdpError, XdpErrorKind},
+    crossbeam_channel::{Receiver, Sender},
     libc::{
-        getsockname, nlattr, nlmsgerr, nlmsghdr, recv, send, setsockopt, sockaddr_nl, socket,
-        AF_INET, AF_INET6, AF_NETLINK, NDA_DST, NDA_LLADDR, NETLINK_EXT_ACK, NETLINK_ROUTE,
-        NLA_ALIGNTO, NLA_TYPE_MASK, NLMSG_DONE, NLMSG_ERROR, NLM_F_DUMP, NLM_F_MULTI,
-        NLM_F_REQUEST, NUD_PERMANENT, NUD_REACHABLE, NUD_STALE, RTA_DST, RTA_GATEWAY, RTA_IIF,
-        RTA_OIF, RTA_PREFSRC, RTA_PRIORITY, RTA_TABLE, RTM_GETNEIGH, RTM_GETROUTE, RTM_NEWNEIGH,
-        RTM_NEWROUTE, RT_TABLE_MAIN, SOCK_RAW, SOL_NETLINK,
+        bind, fcntl, getsockname, nlattr, nlmsgerr, nlmsghdr, recv, send, setsockopt, sockaddr_nl,
+        socket, timeval, AF_INET, AF_INET6, AF_NETLINK, AF_UNSPEC, F_GETFL, F_SETFL, IFA_ADDRESS,
+        IFA_FLAGS, IFA_F_DADFAILED, IFA_F_DEPRECATED, IFA_F_SECONDARY, IFA_F_TENTATIVE, IFA_LOCAL,
+        IFF_LOWER_UP, IFF_RUNNING, IFF_UP, IFLA_ADDRESS, IFLA_IFNAME, IFLA_MTU, IFLA_NUM_RX_QUEUES,
+        IFLA_NUM_TX_QUEUES, IFLA_OPERSTATE, IFLA_PERM_ADDRESS, IFLA_XDP, NDA_DST, NDA_LLADDR,
+        NETLINK_EXT_ACK, NETLINK_ROUTE, NLA_ALIGNTO, NLA_F_NESTED, NLA_TYPE_MASK, NLMSG_DONE,
+        NLMSG_ERROR, NLM_F_ACK, NLM_F_CREATE, NLM_F_DUMP, NLM_F_MULTI, NLM_F_REPLACE,
+        NLM_F_REQUEST, NTF_USE, NUD_NONE, NUD_PERMANENT, NUD_REACHABLE, NUD_STALE, O_NONBLOCK,
+        RTA_DST, RTA_GATEWAY, RTA_IIF, RTA_OIF, RTA_PREFSRC, RTA_PRIORITY, RTA_TABLE,
+        RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV4_RULE, RTMGRP_IPV6_IFADDR,
+        RTMGRP_IPV6_ROUTE, RTMGRP_LINK, RTMGRP_NEIGH, RTM_DELADDR, RTM_DELLINK, RTM_DELNEIGH,
+        RTM_DELROUTE, RTM_DELRULE, RTM_GETADDR, RTM_GETLINK, RTM_GETNEIGH, RTM_GETQDISC,
+        RTM_GETROUTE, RTM_GETRULE, RTM_NEWADDR, RTM_NEWLINK, RTM_NEWNEIGH, RTM_NEWQDISC,
+        RTM_NEWROUTE, RTM_NEWRULE, RTM_SETLINK, RT_TABLE_MAIN, SOCK_RAW, SOL_NETLINK, SOL_SOCKET,
+        SO_RCVTIMEO, TCA_KIND, TCA_OPTIONS,
     },
     std::{
         collections::HashMap,
-        io, mem,
+        io, iter, mem,
         net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        ops::{BitOr, Range},
         os::fd::{AsRawFd, FromRawFd, OwnedFd},
         ptr, slice,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread::{self, Builder},
+        time::Duration,
     },
     thiserror::Error,
 };
@@ -27,11 +45,11 @@ pub struct NetlinkSocket {
 }
 
 impl NetlinkSocket {
-    fn open() -> Result<Self, io::Error> {
+    fn open() -> Result<Self, XdpError> {
         // Safety: libc wrapper
         let sock = unsafe { socket(AF_NETLINK, SOCK_RAW, NETLINK_ROUTE) };
         if sock < 0 {
-            return Err(io::Error::last_os_error());
+            return Err(XdpError::last_os_error("socket(AF_NETLINK)"));
         }
         // SAFETY: `socket` returns a file descriptor.
         let sock = unsafe { OwnedFd::from_raw_fd(sock) };
@@ -48,7 +66,7 @@ impl NetlinkSocket {
             )
         } < 0
         {
-            return Err(io::Error::last_os_error());
+            return Err(XdpError::last_os_error("setsockopt(NETLINK_EXT_ACK)"));
         }
 
         // Safety: sockaddr_nl is POD so this is safe
@@ -64,7 +82,7 @@ impl NetlinkSocket {
             )
         } < 0
         {
-            return Err(io::Error::last_os_error());
+            return Err(XdpError::last_os_error("getsockname"));
         }
 
         Ok(Self {
@@ -73,7 +91,61 @@ impl NetlinkSocket {
         })
     }
 
-    fn send(&self, msg: &[u8]) -> Result<(), io::Error> {
+    /// Opens a socket subscribed to the given `RTMGRP_*` multicast `groups`.
+    fn open_multicast(groups: u32) -> Result<Self, XdpError> {
+        let sock = Self::open()?;
+
+        // Safety: sockaddr_nl is POD so this is safe
+        let mut addr = unsafe { mem::zeroed::<sockaddr_nl>() };
+        addr.nl_family = AF_NETLINK as u16;
+        addr.nl_groups = groups;
+        // Safety: libc wrapper
+        if unsafe {
+            bind(
+                sock.sock.as_raw_fd(),
+                &addr as *const _ as *const _,
+                mem::size_of::<sockaddr_nl>() as u32,
+            )
+        } < 0
+        {
+            return Err(XdpError::last_os_error("bind(AF_NETLINK)"));
+        }
+
+        Ok(sock)
+    }
+
+    fn set_recv_timeout(&self, timeout: Duration) -> Result<(), XdpError> {
+        let tv = timeval {
+            tv_sec: timeout.as_secs() as _,
+            tv_usec: timeout.subsec_micros() as _,
+        };
+        // Safety: libc wrapper
+        if unsafe {
+            setsockopt(
+                self.sock.as_raw_fd(),
+                SOL_SOCKET,
+                SO_RCVTIMEO,
+                &tv as *const _ as *const _,
+                mem::size_of::<timeval>() as u32,
+            )
+        } < 0
+        {
+            return Err(XdpError::last_os_error("setsockopt(SO_RCVTIMEO)"));
+        }
+        Ok(())
+    }
+
+    fn set_nonblocking(&self) -> Result<(), XdpError> {
+        // Safety: libc wrapper
+        let flags = unsafe { fcntl(self.sock.as_raw_fd(), F_GETFL) };
+        // Safety: libc wrapper
+        if flags < 0 || unsafe { fcntl(self.sock.as_raw_fd(), F_SETFL, flags | O_NONBLOCK) } < 0 {
+            return Err(XdpError::last_os_error("fcntl(O_NONBLOCK)"));
+        }
+        Ok(())
+    }
+
+    fn send(&self, msg: &[u8]) -> Result<(), XdpError> {
         if unsafe {
             send(
                 self.sock.as_raw_fd(),
@@ -83,12 +155,12 @@ impl NetlinkSocket {
             )
         } < 0
         {
-            return Err(io::Error::last_os_error());
+            return Err(XdpError::last_os_error("send(AF_NETLINK)"));
         }
         Ok(())
     }
 
-    fn recv(&self) -> Result<Vec<NetlinkMessage>, io::Error> {
+    fn recv(&self) -> Result<Vec<NetlinkMessage>, XdpError> {
         let mut buf = [0u8; 4096];
         let mut messages = Vec::new();
         let mut multipart = true;
@@ -104,7 +176,7 @@ impl NetlinkSocket {
                 )
             };
             if len < 0 {
-                return Err(io::Error::last_os_error());
+                return Err(XdpError::last_os_error("recv(AF_NETLINK)"));
             }
             if len == 0 {
                 break;
@@ -113,7 +185,8 @@ impl NetlinkSocket {
             let len = len as usize;
             let mut offset = 0;
             while offset < len {
-                let message = NetlinkMessage::read(&buf[offset..])?;
+                let message = NetlinkMessage::read(&buf[offset..])
+                    .map_err(|e| XdpError::new("parse netlink message", e))?;
                 offset += align_to(message.header.nlmsg_len as usize, NLMSG_ALIGNTO as usize);
                 multipart = message.header.nlmsg_flags & NLM_F_MULTI as u16 != 0;
                 match message.header.nlmsg_type as i32 {
@@ -123,7 +196,10 @@ impl NetlinkSocket {
                             // this is an ACK
                             continue;
                         }
-                        return Err(io::Error::from_raw_os_error(-err.error));
+                        return Err(XdpError::new(
+                            "netlink request",
+                            io::Error::from_raw_os_error(-err.error),
+                        ));
                     }
                     NLMSG_DONE => break 'out,
                     _ => messages.push(message),
@@ -273,7 +349,7 @@ fn bytes_of<T>(val: &T) -> &[u8] {
 
 const NLMSG_ALIGNTO: u32 = 4;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct MacAddress(pub [u8; 6]);
 
 impl MacAddress {
@@ -284,6 +360,23 @@ impl MacAddress {
     pub fn as_bytes(&self) -> &[u8; 6] {
         &self.0
     }
+
+    /// Returns the Ethernet address frames to the multicast group `ip` are sent to, `None` if
+    /// `ip` isn't a multicast address. See RFC 1112 and RFC 2464.
+    pub fn multicast(ip: IpAddr) -> Option<Self> {
+        match ip {
+            IpAddr::V4(ip) if ip.is_multicast() => {
+                let [_, b, c, d] = ip.octets();
+                // the low 23 bits of the group
+                Some(Self([0x01, 0x00, 0x5e, b & 0x7f, c, d]))
+            }
+            IpAddr::V6(ip) if ip.is_multicast() => {
+                let [.., a, b, c, d] = ip.octets();
+                Some(Self([0x33, 0x33, a, b, c, d]))
+            }
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for MacAddress {
@@ -338,7 +431,7 @@ struct NeighRequest {
 pub fn netlink_get_neighbors(
     if_index: Option<i32>,
     family: u8,
-) -> Result<Vec<NeighborEntry>, io::Error> {
+) -> Result<Vec<NeighborEntry>, XdpError> {
     let sock = NetlinkSocket::open()?;
 
     // Safety: NeighRequest is POD
@@ -462,7 +555,7 @@ fn parse_ip_address(data: &[u8], family: u8) -> Option<IpAddr> {
     }
 }
 
-pub fn netlink_get_routes(family: u8) -> Result<Vec<RouteEntry>, io::Error> {
+pub fn netlink_get_routes(family: u8) -> Result<Vec<RouteEntry>, XdpError> {
     let sock = NetlinkSocket::open()?;
 
     // Safety: RouteRequest is POD
@@ -515,7 +608,7 @@ pub fn parse_rtm_newroute(msg: NetlinkMessage) -> Option<RouteEntry> {
         out_if_index: None,
         in_if_index: None,
         priority: None,
-        table: None,
+        table: Some(rt_msg.rtm_table as u32),
         protocol: rt_msg.rtm_protocol,
         scope: rt_msg.rtm_scope,
         type_: rt_msg.rtm_type,
@@ -543,8 +636,9 @@ pub fn parse_rtm_newroute(msg: NetlinkMessage) -> Option<RouteEntry> {
     if let Some(priority_attr) = attrs.get(&RTA_PRIORITY) {
         route.priority = u32_from_ne_bytes(priority_attr.data);
     }
+    // rtm_table only has room for the first 255 tables
     if let Some(table_attr) = attrs.get(&RTA_TABLE) {
-        route.table = u32_from_ne_bytes(table_attr.data);
+        route.table = u32_from_ne_bytes(table_attr.data).or(route.table);
     }
     if let Some(prefsrc_attr) = attrs.get(&RTA_PREFSRC) {
         route.pref_src = parse_ip_address(prefsrc_attr.data, rt_msg.rtm_family);
@@ -552,7 +646,136 @@ pub fn parse_rtm_newroute(msg: NetlinkMessage) -> Option<RouteEntry> {
     Some(route)
 }
 
-pub fn netlink_get_default_gateway(family: u8) -> Result<Option<RouteEntry>, io::Error> {
+/// An address assigned to a network interface.
+#[derive(Debug, Clone)]
+pub struct InterfaceAddress {
+    pub if_index: u32,
+    pub address: IpAddr,
+    pub prefix_len: u8,
+    // RT_SCOPE_* scope
+    pub scope: u8,
+    /// Whether this is a secondary address within its subnet.
+    pub secondary: bool,
+    // IFA_F_* flags
+    pub flags: u32,
+}
+
+impl InterfaceAddress {
+    /// Returns false if the address can't be sent from, e.g. while IPv6 duplicate address
+    /// detection is still running or after it failed.
+    pub fn is_usable(&self) -> bool {
+        self.flags & (IFA_F_TENTATIVE | IFA_F_DADFAILED) == 0
+    }
+
+    /// Returns true if the address is still valid but its preferred lifetime expired, so new
+    /// traffic should use another address if there's one.
+    pub fn is_deprecated(&self) -> bool {
+        self.flags & IFA_F_DEPRECATED != 0
+    }
+
+    /// Returns true if `ip` is in this address' subnet.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(address), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32u32.saturating_sub(self.prefix_len as u32))
+                    .unwrap_or(0);
+                u32::from(address) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(address), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128u32.saturating_sub(self.prefix_len as u32))
+                    .unwrap_or(0);
+                u128::from(address) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct ifaddrmsg {
+    ifa_family: u8,
+    ifa_prefixlen: u8,
+    ifa_flags: u8,
+    ifa_scope: u8,
+    ifa_index: u32,
+}
+
+#[repr(C)]
+struct AddrRequest {
+    header: nlmsghdr,
+    ifa: ifaddrmsg,
+}
+
+/// fetch the addresses of all the interfaces, of both families if `family` is `AF_UNSPEC`
+pub fn netlink_get_addresses(family: u8) -> Result<Vec<InterfaceAddress>, XdpError> {
+    let sock = NetlinkSocket::open()?;
+
+    // Safety: AddrRequest is POD
+    let mut req = unsafe { mem::zeroed::<AddrRequest>() };
+
+    let nlmsg_len = mem::size_of::<nlmsghdr>() + mem::size_of::<ifaddrmsg>();
+    req.header = nlmsghdr {
+        nlmsg_len: nlmsg_len as u32,
+        nlmsg_flags: (NLM_F_REQUEST | NLM_F_DUMP) as u16,
+        nlmsg_type: RTM_GETADDR,
+        nlmsg_pid: 0,
+        nlmsg_seq: 1,
+    };
+
+    req.ifa.ifa_family = family;
+
+    sock.send(&bytes_of(&req)[..req.header.nlmsg_len as usize])?;
+
+    let mut addresses = Vec::new();
+
+    for msg in sock.recv()? {
+        if msg.header.nlmsg_type != RTM_NEWADDR {
+            continue;
+        }
+
+        if msg.data.len() < mem::size_of::<ifaddrmsg>() {
+            continue;
+        }
+
+        let Some(address) = parse_rtm_newaddr(msg) else {
+            continue;
+        };
+
+        addresses.push(address);
+    }
+
+    Ok(addresses)
+}
+
+pub fn parse_rtm_newaddr(msg: NetlinkMessage) -> Option<InterfaceAddress> {
+    let ifa_msg = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const ifaddrmsg) };
+    let Ok(attrs) = parse_attrs(&msg.data[mem::size_of::<ifaddrmsg>()..]) else {
+        return None;
+    };
+    // IFA_ADDRESS is the peer address on point to point links, IFA_LOCAL is always ours
+    let address = attrs
+        .get(&IFA_LOCAL)
+        .or_else(|| attrs.get(&IFA_ADDRESS))
+        .and_then(|attr| parse_ip_address(attr.data, ifa_msg.ifa_family))?;
+    // ifa_flags only has room for the first 8 flags
+    let flags = attrs
+        .get(&IFA_FLAGS)
+        .and_then(|attr| Some(u32::from_ne_bytes(attr.data.get(..4)?.try_into().ok()?)))
+        .unwrap_or(ifa_msg.ifa_flags as u32);
+    Some(InterfaceAddress {
+        if_index: ifa_msg.ifa_index,
+        address,
+        prefix_len: ifa_msg.ifa_prefixlen,
+        scope: ifa_msg.ifa_scope,
+        secondary: flags & IFA_F_SECONDARY != 0,
+        flags,
+    })
+}
+
+pub fn netlink_get_default_gateway(family: u8) -> Result<Option<RouteEntry>, XdpError> {
     let routes = netlink_get_routes(family)?;
 
     for route in routes {
@@ -572,3 +795,918 @@ pub fn netlink_get_default_gateway(family: u8) -> Result<Option<RouteEntry>, io:
 
     Ok(None)
 }
+
+// from linux/fib_rules.h, which libc doesn't have
+const FRA_DST: u16 = 1;
+const FRA_SRC: u16 = 2;
+const FRA_IIFNAME: u16 = 3;
+const FRA_PRIORITY: u16 = 6;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+const FRA_FWMASK: u16 = 16;
+const FRA_OIFNAME: u16 = 17;
+const FIB_RULE_INVERT: u32 = 0x2;
+
+pub const FR_ACT_TO_TBL: u8 = 1;
+pub const FR_ACT_GOTO: u8 = 2;
+pub const FR_ACT_NOP: u8 = 3;
+pub const FR_ACT_BLACKHOLE: u8 = 6;
+pub const FR_ACT_UNREACHABLE: u8 = 7;
+pub const FR_ACT_PROHIBIT: u8 = 8;
+
+/// A policy routing rule, as listed by `ip rule`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleEntry {
+    pub family: u8,
+    pub priority: u32,
+    // FR_ACT_* action
+    pub action: u8,
+    /// The table to look up for [`FR_ACT_TO_TBL`] rules.
+    pub table: u32,
+    pub source: Option<IpAddr>,
+    pub src_len: u8,
+    pub destination: Option<IpAddr>,
+    pub dst_len: u8,
+    pub iif_name: Option<String>,
+    pub oif_name: Option<String>,
+    /// Matches packets whose mark is `fwmark` once masked with `fwmask`.
+    pub fwmark: Option<u32>,
+    pub fwmask: u32,
+    /// Ignore routes with a prefix this long or shorter, see `suppress_prefixlength`.
+    pub suppress_prefixlen: Option<u8>,
+    /// Whether the rule applies to the packets that don't match its selectors.
+    pub invert: bool,
+}
+
+// same layout as rtmsg
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct fib_rule_hdr {
+    family: u8,
+    dst_len: u8,
+    src_len: u8,
+    tos: u8,
+    table: u8,
+    res1: u8,
+    res2: u8,
+    action: u8,
+    flags: u32,
+}
+
+#[repr(C)]
+struct RuleRequest {
+    header: nlmsghdr,
+    frh: fib_rule_hdr,
+}
+
+/// fetch the policy routing rules of `family`
+pub fn netlink_get_rules(family: u8) -> Result<Vec<RuleEntry>, XdpError> {
+    let sock = NetlinkSocket::open()?;
+
+    // Safety: RuleRequest is POD
+    let mut req = unsafe { mem::zeroed::<RuleRequest>() };
+
+    let nlmsg_len = mem::size_of::<nlmsghdr>() + mem::size_of::<fib_rule_hdr>();
+    req.header = nlmsghdr {
+        nlmsg_len: nlmsg_len as u32,
+        nlmsg_flags: (NLM_F_REQUEST | NLM_F_DUMP) as u16,
+        nlmsg_type: RTM_GETRULE,
+        nlmsg_pid: 0,
+        nlmsg_seq: 1,
+    };
+
+    req.frh.family = family;
+
+    sock.send(&bytes_of(&req)[..req.header.nlmsg_len as usize])?;
+
+    Ok(sock
+        .recv()?
+        .into_iter()
+        .filter(|msg| msg.header.nlmsg_type == RTM_NEWRULE)
+        .filter(|msg| msg.data.len() >= mem::size_of::<fib_rule_hdr>())
+        .filter_map(parse_rtm_newrule)
+        .collect())
+}
+
+pub fn parse_rtm_newrule(msg: NetlinkMessage) -> Option<RuleEntry> {
+    let frh = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const fib_rule_hdr) };
+    let Ok(attrs) = parse_attrs(&msg.data[mem::size_of::<fib_rule_hdr>()..]) else {
+        return None;
+    };
+    let u32_attr = |kind| {
+        attrs
+            .get(&kind)
+            .and_then(|attr| Some(u32::from_ne_bytes(attr.data.get(..4)?.try_into().ok()?)))
+    };
+    let ip_attr = |kind| {
+        attrs
+            .get(&kind)
+            .and_then(|attr| parse_ip_address(attr.data, frh.family))
+    };
+    let name_attr = |kind| {
+        attrs.get(&kind).map(|attr| {
+            // NUL terminated
+            let name = attr.data.split(|&b| b == 0).next().unwrap_or_default();
+            String::from_utf8_lossy(name).into_owned()
+        })
+    };
+    let fwmark = u32_attr(FRA_FWMARK);
+    Some(RuleEntry {
+        family: frh.family,
+        priority: u32_attr(FRA_PRIORITY).unwrap_or(0),
+        action: frh.action,
+        table: u32_attr(FRA_TABLE).unwrap_or(frh.table as u32),
+        source: ip_attr(FRA_SRC),
+        src_len: frh.src_len,
+        destination: ip_attr(FRA_DST),
+        dst_len: frh.dst_len,
+        iif_name: name_attr(FRA_IIFNAME),
+        oif_name: name_attr(FRA_OIFNAME),
+        fwmark,
+        // a mark without a mask must match exactly
+        fwmask: u32_attr(FRA_FWMASK).unwrap_or(if fwmark.is_some() { u32::MAX } else { 0 }),
+        // -1 when not set
+        suppress_prefixlen: u32_attr(FRA_SUPPRESS_PREFIXLEN).and_then(|len| u8::try_from(len).ok()),
+        invert: frh.flags & FIB_RULE_INVERT != 0,
+    })
+}
+
+/// A change in the state of a network interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkEvent {
+    pub if_index: u32,
+    /// Whether the interface is administratively up and has a carrier.
+    pub up: bool,
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct ifinfomsg {
+    ifi_family: u8,
+    ifi_pad: u8,
+    ifi_type: u16,
+    ifi_index: i32,
+    ifi_flags: u32,
+    ifi_change: u32,
+}
+
+/// The attributes of a network interface, as reported by `RTM_GETLINK`.
+#[derive(Debug, Clone)]
+pub struct LinkInfo {
+    pub if_index: u32,
+    pub name: String,
+    // IFF_* flags
+    pub flags: u32,
+    // IF_OPER_* state
+    pub operstate: u8,
+    pub mtu: Option<u32>,
+    pub address: Option<MacAddress>,
+    /// The address assigned by the manufacturer, unaffected by changes to `address`. Older
+    /// kernels don't report it.
+    pub perm_address: Option<MacAddress>,
+    pub num_tx_queues: Option<u32>,
+    pub num_rx_queues: Option<u32>,
+    /// The id of the XDP program attached to the interface, if any.
+    pub xdp_prog_id: Option<u32>,
+}
+
+// nested in IFLA_XDP
+const IFLA_XDP_FD: u16 = 1;
+const IFLA_XDP_FLAGS: u16 = 3;
+const IFLA_XDP_PROG_ID: u16 = 4;
+
+#[repr(C)]
+struct LinkRequest {
+    header: nlmsghdr,
+    ifi: ifinfomsg,
+}
+
+/// fetch the attributes of the interface with index `if_index`
+pub fn netlink_get_link(if_index: u32) -> Result<LinkInfo, XdpError> {
+    request_links(Some(if_index))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| {
+            XdpError::other(
+                XdpErrorKind::Other,
+                "RTM_GETLINK",
+                format!("no link info returned for if_index {if_index}"),
+            )
+        })
+}
+
+/// fetch the attributes of all the interfaces
+pub fn netlink_get_links() -> Result<Vec<LinkInfo>, XdpError> {
+    request_links(None)
+}
+
+// Dumps all the links if `if_index` is None.
+fn request_links(if_index: Option<u32>) -> Result<Vec<LinkInfo>, XdpError> {
+    let sock = NetlinkSocket::open()?;
+
+    // Safety: LinkRequest is POD
+    let mut req = unsafe { mem::zeroed::<LinkRequest>() };
+
+    let nlmsg_len = mem::size_of::<nlmsghdr>() + mem::size_of::<ifinfomsg>();
+    req.header = nlmsghdr {
+        nlmsg_len: nlmsg_len as u32,
+        nlmsg_flags: match if_index {
+            Some(_) => NLM_F_REQUEST,
+            None => NLM_F_REQUEST | NLM_F_DUMP,
+        } as u16,
+        nlmsg_type: RTM_GETLINK,
+        nlmsg_pid: 0,
+        nlmsg_seq: 1,
+    };
+
+    req.ifi.ifi_family = AF_UNSPEC as u8;
+    req.ifi.ifi_index = if_index.unwrap_or(0) as i32;
+
+    sock.send(&bytes_of(&req)[..req.header.nlmsg_len as usize])?;
+
+    Ok(sock
+        .recv()?
+        .into_iter()
+        .filter(|msg| msg.header.nlmsg_type == RTM_NEWLINK)
+        .filter_map(parse_rtm_newlink)
+        .collect())
+}
+
+/// detach the XDP program attached to `if_index` in the mode given by `flags`, one of the
+/// `XDP_FLAGS_*_MODE` flags. Programs attached through a bpf link can only be detached by
+/// closing the link, and fail with EBUSY.
+pub fn netlink_detach_xdp(if_index: u32, flags: u32) -> Result<(), XdpError> {
+    let sock = NetlinkSocket::open()?;
+
+    let xdp = [
+        nl_attr(IFLA_XDP_FD, &(-1i32).to_ne_bytes()),
+        nl_attr(IFLA_XDP_FLAGS, &flags.to_ne_bytes()),
+    ]
+    .concat();
+    let xdp = nl_attr(IFLA_XDP | NLA_F_NESTED as u16, &xdp);
+
+    // Safety: LinkRequest is POD
+    let mut req = unsafe { mem::zeroed::<LinkRequest>() };
+    req.header = nlmsghdr {
+        nlmsg_len: (mem::size_of::<LinkRequest>() + xdp.len()) as u32,
+        nlmsg_flags: (NLM_F_REQUEST | NLM_F_ACK) as u16,
+        nlmsg_type: RTM_SETLINK,
+        nlmsg_pid: 0,
+        nlmsg_seq: 1,
+    };
+    req.ifi.ifi_family = AF_UNSPEC as u8;
+    req.ifi.ifi_index = if_index as i32;
+
+    sock.send(&[bytes_of(&req), &xdp].concat())?;
+    sock.recv().map(|_| ())
+}
+
+/// ask the kernel to resolve the MAC address of the neighbor `ip` on `if_index`, like sending a
+/// packet to it would. Returns once the request is accepted, the entry shows up in the neighbor
+/// table once resolution completes. Requires CAP_NET_ADMIN.
+pub fn netlink_resolve_neighbor(if_index: u32, ip: IpAddr) -> Result<(), XdpError> {
+    let sock = NetlinkSocket::open()?;
+
+    let (family, dst) = match ip {
+        IpAddr::V4(ip) => (AF_INET, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (AF_INET6, ip.octets().to_vec()),
+    };
+    let dst = nl_attr(NDA_DST, &dst);
+
+    // Safety: NeighRequest is POD
+    let mut req = unsafe { mem::zeroed::<NeighRequest>() };
+    req.header = nlmsghdr {
+        nlmsg_len: (mem::size_of::<NeighRequest>() + dst.len()) as u32,
+        nlmsg_flags: (NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE) as u16,
+        nlmsg_type: RTM_NEWNEIGH,
+        nlmsg_pid: 0,
+        nlmsg_seq: 1,
+    };
+    req.ndm.ndm_family = family as u8;
+    req.ndm.ndm_ifindex = if_index as i32;
+    req.ndm.ndm_state = NUD_NONE;
+    // NTF_USE makes the kernel start resolution instead of setting the entry
+    req.ndm.ndm_flags = NTF_USE;
+
+    sock.send(&[bytes_of(&req), &dst].concat())?;
+    sock.recv().map(|_| ())
+}
+
+#[repr(C)]
+#[allow(non_camel_case_types)]
+struct tcmsg {
+    tcm_family: u8,
+    tcm_pad1: u8,
+    tcm_pad2: u16,
+    tcm_ifindex: i32,
+    tcm_handle: u32,
+    tcm_parent: u32,
+    tcm_info: u32,
+}
+
+#[repr(C)]
+struct QdiscRequest {
+    header: nlmsghdr,
+    tcm: tcmsg,
+}
+
+const TC_H_ROOT: u32 = 0xffff_ffff;
+const TC_QOPT_MAX_QUEUE: usize = 16;
+const TC_QOPT_BITMASK: usize = 15;
+// size of struct tc_mqprio_qopt
+const MQPRIO_QOPT_LEN: usize = 2 + TC_QOPT_BITMASK + 1 + 2 * 2 * TC_QOPT_MAX_QUEUE;
+
+/// The traffic classes of a device with an mqprio root qdisc.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MqprioConfig {
+    /// The traffic class of each priority.
+    pub prio_tc_map: [u8; TC_QOPT_BITMASK + 1],
+    /// Whether the classes are offloaded to the NIC, e.g. by DCB.
+    pub hw: bool,
+    /// The tx queues of each traffic class.
+    pub queues: Vec<Range<u32>>,
+}
+
+impl MqprioConfig {
+    /// Returns the tx queues of the traffic class `priority` maps to.
+    pub fn queues_for_priority(&self, priority: u8) -> Option<Range<u32>> {
+        let tc = *self.prio_tc_map.get(priority as usize)?;
+        self.queues.get(tc as usize).cloned()
+    }
+}
+
+/// get the mqprio configuration of `if_index`, or None if its root qdisc isn't mqprio
+pub fn netlink_get_mqprio(if_index: u32) -> Result<Option<MqprioConfig>, XdpError> {
+    let sock = NetlinkSocket::open()?;
+
+    // Safety: QdiscRequest is POD
+    let mut req = unsafe { mem::zeroed::<QdiscRequest>() };
+    req.header = nlmsghdr {
+        nlmsg_len: mem::size_of::<QdiscRequest>() as u32,
+        nlmsg_flags: (NLM_F_REQUEST | NLM_F_DUMP) as u16,
+        nlmsg_type: RTM_GETQDISC,
+        nlmsg_pid: 0,
+        nlmsg_seq: 1,
+    };
+    req.tcm.tcm_family = AF_UNSPEC as u8;
+    req.tcm.tcm_ifindex = if_index as i32;
+
+    sock.send(bytes_of(&req))?;
+
+    // older kernels dump the qdiscs of every device, whatever the ifindex
+    Ok(sock
+        .recv()?
+        .into_iter()
+        .filter(|msg| msg.header.nlmsg_type == RTM_NEWQDISC)
+        .find_map(|msg| parse_rtm_newqdisc_mqprio(msg, if_index)))
+}
+
+fn parse_rtm_newqdisc_mqprio(msg: NetlinkMessage, if_index: u32) -> Option<MqprioConfig> {
+    if msg.data.len() < mem::size_of::<tcmsg>() {
+        return None;
+    }
+    // Safety: the message holds a tcmsg
+    let tcm = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const tcmsg) };
+    if tcm.tcm_ifindex != if_index as i32 || tcm.tcm_parent != TC_H_ROOT {
+        return None;
+    }
+    let attrs = parse_attrs(&msg.data[mem::size_of::<tcmsg>()..]).ok()?;
+    if attrs.get(&TCA_KIND)?.data.split(|&b| b == 0).next()? != b"mqprio" {
+        return None;
+    }
+    parse_mqprio_qopt(attrs.get(&TCA_OPTIONS)?.data)
+}
+
+// Parses a struct tc_mqprio_qopt, which starts the options of mqprio qdiscs.
+fn parse_mqprio_qopt(data: &[u8]) -> Option<MqprioConfig> {
+    let data = data.get(..MQPRIO_QOPT_LEN)?;
+    let num_tc = (data[0] as usize).min(TC_QOPT_MAX_QUEUE);
+    let prio_tc_map = data[1..][..TC_QOPT_BITMASK + 1].try_into().ok()?;
+    let hw = data[TC_QOPT_BITMASK + 2] != 0;
+    let u16_at = |offset: usize| u16::from_ne_bytes([data[offset], data[offset + 1]]);
+    let counts = TC_QOPT_BITMASK + 3;
+    let offsets = counts + 2 * TC_QOPT_MAX_QUEUE;
+    let queues = (0..num_tc)
+        .map(|tc| {
+            let offset = u32::from(u16_at(offsets + 2 * tc));
+            offset..offset + u32::from(u16_at(counts + 2 * tc))
+        })
+        .collect();
+    Some(MqprioConfig {
+        prio_tc_map,
+        hw,
+        queues,
+    })
+}
+
+// Encodes an attribute, padded to NLA_ALIGNTO.
+fn nl_attr(nla_type: u16, payload: &[u8]) -> Vec<u8> {
+    let len = NLA_HDR_LEN + payload.len();
+    let mut attr = Vec::with_capacity(align_to(len, NLA_ALIGNTO as usize));
+    attr.extend_from_slice(&(len as u16).to_ne_bytes());
+    attr.extend_from_slice(&nla_type.to_ne_bytes());
+    attr.extend_from_slice(payload);
+    attr.resize(align_to(len, NLA_ALIGNTO as usize), 0);
+    attr
+}
+
+pub fn parse_rtm_newlink(msg: NetlinkMessage) -> Option<LinkInfo> {
+    if msg.data.len() < mem::size_of::<ifinfomsg>() {
+        return None;
+    }
+    let if_msg = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const ifinfomsg) };
+    let Ok(attrs) = parse_attrs(&msg.data[mem::size_of::<ifinfomsg>()..]) else {
+        return None;
+    };
+    let u32_attr = |ty: u16| -> Option<u32> {
+        let data = attrs.get(&ty)?.data;
+        Some(u32::from_ne_bytes(data.get(..4)?.try_into().ok()?))
+    };
+    let mac_attr = |ty: u16| -> Option<MacAddress> {
+        // non ethernet links, e.g. tunnels, have addresses of other sizes
+        Some(MacAddress(attrs.get(&ty)?.data.try_into().ok()?))
+    };
+    let name = attrs.get(&IFLA_IFNAME).map(|attr| {
+        // NUL terminated
+        let name = attr.data.split(|&b| b == 0).next().unwrap_or_default();
+        String::from_utf8_lossy(name).into_owned()
+    });
+    Some(LinkInfo {
+        if_index: if_msg.ifi_index as u32,
+        name: name.unwrap_or_default(),
+        flags: if_msg.ifi_flags,
+        operstate: attrs
+            .get(&IFLA_OPERSTATE)
+            .and_then(|attr| attr.data.first().copied())
+            .unwrap_or(0),
+        mtu: u32_attr(IFLA_MTU),
+        address: mac_attr(IFLA_ADDRESS),
+        perm_address: mac_attr(IFLA_PERM_ADDRESS),
+        num_tx_queues: u32_attr(IFLA_NUM_TX_QUEUES),
+        num_rx_queues: u32_attr(IFLA_NUM_RX_QUEUES),
+        xdp_prog_id: attrs.get(&IFLA_XDP).and_then(|attr| {
+            let data = parse_attrs(attr.data).ok()?.get(&IFLA_XDP_PROG_ID)?.data;
+            Some(u32::from_ne_bytes(data.get(..4)?.try_into().ok()?))
+        }),
+    })
+}
+
+/// A set of rtnetlink multicast groups.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetlinkGroups(u32);
+
+impl NetlinkGroups {
+    pub const LINK: Self = Self(RTMGRP_LINK as u32);
+    pub const NEIGHBOR: Self = Self(RTMGRP_NEIGH as u32);
+    pub const IPV4_ADDRESS: Self = Self(RTMGRP_IPV4_IFADDR as u32);
+    pub const IPV6_ADDRESS: Self = Self(RTMGRP_IPV6_IFADDR as u32);
+    pub const IPV4_ROUTE: Self = Self(RTMGRP_IPV4_ROUTE as u32);
+    pub const IPV6_ROUTE: Self = Self(RTMGRP_IPV6_ROUTE as u32);
+    pub const IPV4_RULE: Self = Self(RTMGRP_IPV4_RULE as u32);
+    pub const ALL: Self = Self(
+        Self::LINK.0
+            | Self::NEIGHBOR.0
+            | Self::IPV4_ADDRESS.0
+            | Self::IPV6_ADDRESS.0
+            | Self::IPV4_ROUTE.0
+            | Self::IPV6_ROUTE.0
+            | Self::IPV4_RULE.0,
+    );
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(&self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for NetlinkGroups {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// A change reported on one of the rtnetlink multicast groups.
+#[derive(Debug, Clone)]
+pub enum NetlinkEvent {
+    NewLink(LinkInfo),
+    DelLink(LinkInfo),
+    NewAddress(InterfaceAddress),
+    DelAddress(InterfaceAddress),
+    NewRoute(RouteEntry),
+    DelRoute(RouteEntry),
+    NewNeighbor(NeighborEntry),
+    DelNeighbor(NeighborEntry),
+    NewRule(RuleEntry),
+    DelRule(RuleEntry),
+    /// The kernel dropped events because they weren't read fast enough. Anything derived from
+    /// the state reported by netlink should be read again.
+    Overrun,
+}
+
+impl NetlinkEvent {
+    fn parse(msg: NetlinkMessage) -> Option<Self> {
+        let header_len = match msg.header.nlmsg_type {
+            RTM_NEWLINK | RTM_DELLINK => mem::size_of::<ifinfomsg>(),
+            RTM_NEWADDR | RTM_DELADDR => mem::size_of::<ifaddrmsg>(),
+            RTM_NEWROUTE | RTM_DELROUTE => mem::size_of::<rtmsg>(),
+            RTM_NEWNEIGH | RTM_DELNEIGH => mem::size_of::<ndmsg>(),
+            RTM_NEWRULE | RTM_DELRULE => mem::size_of::<fib_rule_hdr>(),
+            _ => return None,
+        };
+        if msg.data.len() < header_len {
+            return None;
+        }
+        let event = match msg.header.nlmsg_type {
+            RTM_NEWLINK => Self::NewLink(parse_rtm_newlink(msg)?),
+            RTM_DELLINK => Self::DelLink(parse_rtm_newlink(msg)?),
+            RTM_NEWADDR => Self::NewAddress(parse_rtm_newaddr(msg)?),
+            RTM_DELADDR => Self::DelAddress(parse_rtm_newaddr(msg)?),
+            RTM_NEWROUTE => Self::NewRoute(parse_rtm_newroute(msg)?),
+            RTM_DELROUTE => Self::DelRoute(parse_rtm_newroute(msg)?),
+            RTM_NEWNEIGH => Self::NewNeighbor(parse_rtm_newneigh(msg, None)?),
+            RTM_DELNEIGH => Self::DelNeighbor(parse_rtm_newneigh(msg, None)?),
+            RTM_NEWRULE => Self::NewRule(parse_rtm_newrule(msg)?),
+            RTM_DELRULE => Self::DelRule(parse_rtm_newrule(msg)?),
+            _ => return None,
+        };
+        Some(event)
+    }
+
+    /// The group the event was reported on, or all of them for [`NetlinkEvent::Overrun`].
+    pub fn group(&self) -> NetlinkGroups {
+        match self {
+            Self::NewLink(_) | Self::DelLink(_) => NetlinkGroups::LINK,
+            Self::NewAddress(address) | Self::DelAddress(address) => match address.address {
+                IpAddr::V4(_) => NetlinkGroups::IPV4_ADDRESS,
+                IpAddr::V6(_) => NetlinkGroups::IPV6_ADDRESS,
+            },
+            Self::NewRoute(route) | Self::DelRoute(route) => {
+                if route.family == AF_INET6 as u8 {
+                    NetlinkGroups::IPV6_ROUTE
+                } else {
+                    NetlinkGroups::IPV4_ROUTE
+                }
+            }
+            Self::NewNeighbor(_) | Self::DelNeighbor(_) => NetlinkGroups::NEIGHBOR,
+            Self::NewRule(_) | Self::DelRule(_) => NetlinkGroups::IPV4_RULE,
+            Self::Overrun => NetlinkGroups::ALL,
+        }
+    }
+}
+
+type Subscribers = Arc<Mutex<Vec<(NetlinkGroups, Sender<NetlinkEvent>)>>>;
+
+// Sends `event` to the subscribers of its group, forgetting the ones that went away.
+fn dispatch(subscribers: &Mutex<Vec<(NetlinkGroups, Sender<NetlinkEvent>)>>, event: NetlinkEvent) {
+    let group = event.group();
+    subscribers
+        .lock()
+        .unwrap()
+        .retain(|(groups, sender)| !groups.intersects(group) || sender.send(event.clone()).is_ok());
+}
+
+/// A netlink socket shared by everything that needs to follow changes to links, addresses, routes
+/// or neighbors.
+///
+/// A thread reads the events of the groups the socket was opened for and delivers them, parsed,
+/// to the channel of each subscriber interested in them.
+pub struct NetlinkEvents {
+    groups: NetlinkGroups,
+    subscribers: Subscribers,
+    thread: thread::JoinHandle<()>,
+}
+
+impl NetlinkEvents {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Subscribes to `groups` and delivers their events until `exit` is set.
+    pub fn new(groups: NetlinkGroups, exit: Arc<AtomicBool>) -> Result<Self, XdpError> {
+        let sock = NetlinkSocket::open_multicast(groups.0)?;
+        // so that we notice exit
+        sock.set_recv_timeout(Self::POLL_INTERVAL)?;
+        let subscribers = Subscribers::default();
+        let thread = {
+            let subscribers = Arc::clone(&subscribers);
+            Builder::new()
+                .name("solNetlinkEvts".to_string())
+                .spawn(move || {
+                    while !exit.load(Ordering::Relaxed) {
+                        match sock.recv() {
+                            Ok(messages) => {
+                                for event in messages.into_iter().filter_map(NetlinkEvent::parse) {
+                                    dispatch(&subscribers, event);
+                                }
+                            }
+                            Err(e) if e.io_error().kind() == io::ErrorKind::WouldBlock => {}
+                            Err(e) if e.errno() == Some(libc::ENOBUFS) => {
+                                dispatch(&subscribers, NetlinkEvent::Overrun)
+                            }
+                            Err(e) => {
+                                log::error!("failed to read netlink events: {e}");
+                                thread::sleep(Self::POLL_INTERVAL);
+                            }
+                        }
+                    }
+                })
+                .map_err(|e| XdpError::new("spawn", e))?
+        };
+        Ok(Self {
+            groups,
+            subscribers,
+            thread,
+        })
+    }
+
+    /// Returns a channel receiving the events of `groups`. Only groups the socket was opened
+    /// for are ever delivered.
+    pub fn subscribe(&self, groups: NetlinkGroups) -> Receiver<NetlinkEvent> {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        self.subscribers.lock().unwrap().push((groups, sender));
+        receiver
+    }
+
+    pub fn groups(&self) -> NetlinkGroups {
+        self.groups
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        self.thread.join()
+    }
+}
+
+/// Watches for network interfaces going up and down.
+pub struct LinkMonitor {
+    source: MonitorSource,
+}
+
+// Where a monitor gets its messages from.
+enum MonitorSource {
+    Socket(NetlinkSocket),
+    Events(Receiver<NetlinkEvent>),
+}
+
+impl LinkMonitor {
+    /// Opens a socket of its own.
+    pub fn new() -> Result<Self, XdpError> {
+        Ok(Self {
+            source: MonitorSource::Socket(NetlinkSocket::open_multicast(RTMGRP_LINK as u32)?),
+        })
+    }
+
+    /// Watches the events delivered by `events`, which must have been opened for
+    /// [`NetlinkGroups::LINK`].
+    pub fn with_events(events: &NetlinkEvents) -> Self {
+        debug_assert!(events.groups().contains(NetlinkGroups::LINK));
+        Self {
+            source: MonitorSource::Events(events.subscribe(NetlinkGroups::LINK)),
+        }
+    }
+
+    /// Waits up to `timeout` for link changes.
+    ///
+    /// Returns an empty list if nothing changed in the meantime. The kernel also reports changes
+    /// that don't affect the link state, so the same state can be reported more than once.
+    pub fn recv(&self, timeout: Duration) -> Result<Vec<LinkEvent>, XdpError> {
+        let receiver = match &self.source {
+            MonitorSource::Socket(sock) => {
+                sock.set_recv_timeout(timeout)?;
+                let messages = match sock.recv() {
+                    Ok(messages) => messages,
+                    Err(e) if e.io_error().kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(Vec::new())
+                    }
+                    Err(e) => return Err(e),
+                };
+                return Ok(messages.into_iter().filter_map(parse_rtm_link).collect());
+            }
+            MonitorSource::Events(receiver) => receiver,
+        };
+        let Ok(first) = receiver.recv_timeout(timeout) else {
+            return Ok(Vec::new());
+        };
+        let running = (IFF_UP | IFF_RUNNING | IFF_LOWER_UP) as u32;
+        Ok(iter::once(first)
+            .chain(receiver.try_iter())
+            .filter_map(|event| match event {
+                NetlinkEvent::NewLink(link) => Some(LinkEvent {
+                    if_index: link.if_index,
+                    up: link.flags & running == running,
+                }),
+                NetlinkEvent::DelLink(link) => Some(LinkEvent {
+                    if_index: link.if_index,
+                    up: false,
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+}
+
+/// Watches for changes to the routing table, the routing rules, the neighbor table and interface
+/// addresses, which invalidate anything derived from a [`Router`](crate::route::Router).
+pub struct RouteMonitor {
+    source: MonitorSource,
+}
+
+impl RouteMonitor {
+    const GROUPS: NetlinkGroups = NetlinkGroups(
+        NetlinkGroups::NEIGHBOR.0
+            | NetlinkGroups::IPV4_ROUTE.0
+            | NetlinkGroups::IPV4_ADDRESS.0
+            | NetlinkGroups::IPV4_RULE.0,
+    );
+
+    /// Opens a socket of its own.
+    pub fn new() -> Result<Self, XdpError> {
+        let sock = NetlinkSocket::open_multicast(Self::GROUPS.0)?;
+        sock.set_nonblocking()?;
+        Ok(Self {
+            source: MonitorSource::Socket(sock),
+        })
+    }
+
+    /// Watches the events delivered by `events`, which must have been opened for the neighbor,
+    /// IPv4 route, IPv4 address and IPv4 rule groups.
+    pub fn with_events(events: &NetlinkEvents) -> Self {
+        debug_assert!(events.groups().contains(Self::GROUPS));
+        Self {
+            source: MonitorSource::Events(events.subscribe(Self::GROUPS)),
+        }
+    }
+
+    /// Returns true if anything changed since the last call. Never blocks.
+    pub fn changed(&self) -> Result<bool, XdpError> {
+        let sock = match &self.source {
+            MonitorSource::Socket(sock) => sock,
+            MonitorSource::Events(receiver) => return Ok(receiver.try_iter().count() > 0),
+        };
+        let mut changed = false;
+        loop {
+            match sock.recv() {
+                Ok(messages) if messages.is_empty() => return Ok(changed),
+                Ok(_) => changed = true,
+                Err(e) if e.io_error().kind() == io::ErrorKind::WouldBlock => return Ok(changed),
+                // we missed some events, so something changed
+                Err(e) if e.errno() == Some(libc::ENOBUFS) => changed = true,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+pub fn parse_rtm_link(msg: NetlinkMessage) -> Option<LinkEvent> {
+    if !matches!(msg.header.nlmsg_type, RTM_NEWLINK | RTM_DELLINK) {
+        return None;
+    }
+    if msg.data.len() < mem::size_of::<ifinfomsg>() {
+        return None;
+    }
+    let if_msg = unsafe { ptr::read_unaligned(msg.data.as_ptr() as *const ifinfomsg) };
+    let running = (IFF_UP | IFF_RUNNING | IFF_LOWER_UP) as u32;
+    Some(LinkEvent {
+        if_index: if_msg.ifi_index as u32,
+        up: msg.header.nlmsg_type == RTM_NEWLINK && if_msg.ifi_flags & running == running,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link_message(nlmsg_type: u16, if_index: i32, name: &str, mtu: u32) -> NetlinkMessage {
+        let attr = |ty: u16, data: &[u8]| {
+            let mut attr = bytes_of(&nlattr {
+                nla_len: (NLA_HDR_LEN + data.len()) as u16,
+                nla_type: ty,
+            })
+            .to_vec();
+            attr.extend_from_slice(data);
+            attr.resize(align_to(attr.len(), NLA_ALIGNTO as usize), 0);
+            attr
+        };
+        // Safety: ifinfomsg is POD
+        let mut ifi = unsafe { mem::zeroed::<ifinfomsg>() };
+        ifi.ifi_index = if_index;
+        ifi.ifi_flags = (IFF_UP | IFF_RUNNING | IFF_LOWER_UP) as u32;
+        let mut data = bytes_of(&ifi).to_vec();
+        data.extend(attr(IFLA_IFNAME, format!("{name}\0").as_bytes()));
+        data.extend(attr(IFLA_MTU, &mtu.to_ne_bytes()));
+
+        let header = nlmsghdr {
+            nlmsg_len: (mem::size_of::<nlmsghdr>() + data.len()) as u32,
+            nlmsg_type,
+            nlmsg_flags: 0,
+            nlmsg_seq: 0,
+            nlmsg_pid: 0,
+        };
+        let mut buf = bytes_of(&header).to_vec();
+        buf.extend(data);
+        NetlinkMessage::read(&buf).unwrap()
+    }
+
+    #[test]
+    fn test_netlink_events() {
+        let Some(NetlinkEvent::NewLink(link)) =
+            NetlinkEvent::parse(link_message(RTM_NEWLINK, 7, "eth1", 9000))
+        else {
+            panic!("expected a new link");
+        };
+        assert_eq!(
+            (link.if_index, link.name.as_str(), link.mtu),
+            (7, "eth1", Some(9000))
+        );
+        let del = NetlinkEvent::parse(link_message(RTM_DELLINK, 7, "eth1", 9000)).unwrap();
+        assert!(matches!(del, NetlinkEvent::DelLink(_)));
+
+        let subscribers = Mutex::new(Vec::new());
+        let subscribe = |groups| {
+            let (sender, receiver) = crossbeam_channel::unbounded();
+            subscribers.lock().unwrap().push((groups, sender));
+            receiver
+        };
+        let links = subscribe(NetlinkGroups::LINK);
+        let routes = subscribe(NetlinkGroups::IPV4_ROUTE | NetlinkGroups::NEIGHBOR);
+        let dropped = subscribe(NetlinkGroups::ALL);
+        drop(dropped);
+
+        let route = |family: i32| RouteEntry {
+            destination: None,
+            gateway: None,
+            pref_src: None,
+            out_if_index: Some(7),
+            in_if_index: None,
+            priority: None,
+            table: None,
+            protocol: 0,
+            scope: 0,
+            type_: 0,
+            family: family as u8,
+            dst_len: 0,
+        };
+        dispatch(&subscribers, del);
+        dispatch(&subscribers, NetlinkEvent::NewRoute(route(AF_INET)));
+        // not subscribed to ipv6 routes
+        dispatch(&subscribers, NetlinkEvent::NewRoute(route(AF_INET6)));
+        dispatch(&subscribers, NetlinkEvent::Overrun);
+        assert_eq!(subscribers.lock().unwrap().len(), 2);
+
+        let links = links.try_iter().collect::<Vec<_>>();
+        assert!(matches!(
+            links.as_slice(),
+            [NetlinkEvent::DelLink(_), NetlinkEvent::Overrun]
+        ));
+        let routes = routes.try_iter().collect::<Vec<_>>();
+        assert!(matches!(
+            routes.as_slice(),
+            [NetlinkEvent::NewRoute(RouteEntry { family, .. }), NetlinkEvent::Overrun]
+                if *family == AF_INET as u8
+        ));
+    }
+
+    #[test]
+    fn test_nl_attr() {
+        let xdp = [
+            nl_attr(IFLA_XDP_FD, &(-1i32).to_ne_bytes()),
+            // padded
+            nl_attr(IFLA_XDP_FLAGS, &[1, 2]),
+        ]
+        .concat();
+        assert_eq!(xdp.len(), 16);
+        let xdp = nl_attr(IFLA_XDP | NLA_F_NESTED as u16, &xdp);
+        let attrs = parse_attrs(&xdp).unwrap();
+        let nested = parse_attrs(attrs[&IFLA_XDP].data).unwrap();
+        assert_eq!(nested[&IFLA_XDP_FD].data, (-1i32).to_ne_bytes());
+        assert_eq!(nested[&IFLA_XDP_FLAGS].data, [1, 2]);
+    }
+
+    #[test]
+    fn test_parse_mqprio_qopt() {
+        // tc qdisc add dev eth0 root mqprio num_tc 3 map 0 0 0 0 1 2 2 1 queues 4@0 2@4 2@6 hw 1
+        let mut qopt = vec![3];
+        qopt.extend([0, 0, 0, 0, 1, 2, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        qopt.push(1);
+        let mut count = [0u16; TC_QOPT_MAX_QUEUE];
+        let mut offset = [0u16; TC_QOPT_MAX_QUEUE];
+        count[..3].copy_from_slice(&[4, 2, 2]);
+        offset[..3].copy_from_slice(&[0, 4, 6]);
+        qopt.extend(count.iter().chain(&offset).flat_map(|v| v.to_ne_bytes()));
+        assert_eq!(qopt.len(), MQPRIO_QOPT_LEN);
+        // followed by nested attributes
+        qopt.extend([0; 8]);
+
+        let config = parse_mqprio_qopt(&qopt).unwrap();
+        assert!(config.hw);
+        assert_eq!(config.queues, [0..4, 4..6, 6..8]);
+        assert_eq!(config.queues_for_priority(0), Some(0..4));
+        assert_eq!(config.queues_for_priority(5), Some(6..8));
+        assert_eq!(config.queues_for_priority(7), Some(4..6));
+        assert_eq!(config.queues_for_priority(16), None);
+        assert!(parse_mqprio_qopt(&qopt[..MQPRIO_QOPT_LEN - 1]).is_none());
+    }
+}
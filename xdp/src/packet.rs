@@ -1,15 +1,225 @@
+//! Building and parsing Ethernet/IPv4/UDP frames.
+//!
+//! Nothing here depends on AF_XDP or on the host: header fields are always read and written
+//! through [`Be16`] or the `{to,from}_be_bytes` conversions, never by casting a pointer into the
+//! frame, so the same bytes are produced on little and big endian hosts and on architectures that
+//! don't allow unaligned loads. The module builds on every target.
 #![allow(clippy::arithmetic_side_effects)]
 
-use {libc::ETH_P_IP, std::net::Ipv4Addr};
+use {
+    std::net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    thiserror::Error,
+};
 
 pub const ETH_HEADER_SIZE: usize = 14;
 pub const IP_HEADER_SIZE: usize = 20;
 pub const UDP_HEADER_SIZE: usize = 8;
+pub const VLAN_HEADER_SIZE: usize = 4;
+
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_8021Q: u16 = 0x8100;
+const IPPROTO_UDP: u8 = 17;
+// more fragments flag and fragment offset
+const IP_FRAG_MASK: u16 = 0x3fff;
+
+/// A 16 bit header field in network byte order, as it's laid out in the frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct Be16([u8; 2]);
+
+impl Be16 {
+    pub const fn new(value: u16) -> Self {
+        Self(value.to_be_bytes())
+    }
+
+    /// Returns the value in host byte order.
+    pub const fn get(self) -> u16 {
+        u16::from_be_bytes(self.0)
+    }
+
+    /// Reads the field at `offset` of `buf`, which needn't be aligned.
+    #[inline]
+    pub fn read(buf: &[u8], offset: usize) -> Self {
+        Self([buf[offset], buf[offset + 1]])
+    }
+
+    /// Writes the field at `offset` of `buf`.
+    #[inline]
+    pub fn write(self, buf: &mut [u8], offset: usize) {
+        buf[offset..offset + 2].copy_from_slice(&self.0);
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ParseError {
+    #[error("frame truncated")]
+    Truncated,
+    #[error("unsupported ether type {0:#06x}")]
+    UnsupportedEtherType(u16),
+    #[error("unsupported IP version {0}")]
+    UnsupportedIpVersion(u8),
+    #[error("invalid IP header length {0}")]
+    InvalidIpHeaderLength(usize),
+    #[error("invalid IP total length {0}")]
+    InvalidIpTotalLength(usize),
+    #[error("invalid IP header checksum")]
+    InvalidIpChecksum,
+    #[error("fragmented IP packet")]
+    Fragmented,
+    #[error("unsupported IP protocol {0}")]
+    UnsupportedProtocol(u8),
+    #[error("invalid UDP length {0}")]
+    InvalidUdpLength(usize),
+    #[error("invalid UDP checksum")]
+    InvalidUdpChecksum,
+}
+
+/// An 802.1Q tag.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VlanTag {
+    pub vlan_id: u16,
+    /// The 802.1p priority code point, from 0 (best effort) to 7.
+    pub pcp: u8,
+}
+
+impl VlanTag {
+    /// Returns the tag control information: the PCP, a clear DEI bit and the VLAN id.
+    pub const fn tci(self) -> u16 {
+        ((self.pcp as u16 & 0x7) << 13) | (self.vlan_id & 0x0fff)
+    }
+}
+
+/// A validated UDP/IPv4 frame.
+///
+/// The payload borrows from the frame it was parsed from so no data is copied.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UdpFrame<'a> {
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6],
+    /// The VLAN id if the frame carries an 802.1Q tag.
+    pub vlan_id: Option<u16>,
+    pub src_ip: Ipv4Addr,
+    pub dst_ip: Ipv4Addr,
+    /// The DSCP and ECN bits.
+    pub tos: u8,
+    pub ttl: u8,
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// The UDP payload, excluding any link layer padding.
+    pub payload: &'a [u8],
+}
+
+impl UdpFrame<'_> {
+    pub fn src_addr(&self) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(self.src_ip, self.src_port))
+    }
+
+    pub fn dst_addr(&self) -> SocketAddr {
+        SocketAddr::V4(SocketAddrV4::new(self.dst_ip, self.dst_port))
+    }
+}
+
+/// Parses and validates an Ethernet (optionally VLAN tagged) IPv4/UDP frame.
+///
+/// The IP header checksum is always verified. Verifying the UDP checksum requires summing the whole
+/// payload so it's optional; a zero UDP checksum means the sender didn't compute one and is
+/// accepted regardless.
+pub fn parse_udp_frame(
+    frame: &[u8],
+    verify_udp_checksum: bool,
+) -> Result<UdpFrame<'_>, ParseError> {
+    if frame.len() < ETH_HEADER_SIZE {
+        return Err(ParseError::Truncated);
+    }
+    let dst_mac: [u8; 6] = frame[0..6].try_into().unwrap();
+    let src_mac: [u8; 6] = frame[6..12].try_into().unwrap();
+    let mut ether_type = Be16::read(frame, 12).get();
+    let mut offset = ETH_HEADER_SIZE;
+    let mut vlan_id = None;
+    if ether_type == ETH_P_8021Q {
+        let tag = frame
+            .get(offset..offset + VLAN_HEADER_SIZE)
+            .ok_or(ParseError::Truncated)?;
+        vlan_id = Some(Be16::read(tag, 0).get() & 0x0fff);
+        ether_type = Be16::read(tag, 2).get();
+        offset += VLAN_HEADER_SIZE;
+    }
+    if ether_type != ETH_P_IP {
+        return Err(ParseError::UnsupportedEtherType(ether_type));
+    }
+
+    let ip = &frame[offset..];
+    if ip.len() < IP_HEADER_SIZE {
+        return Err(ParseError::Truncated);
+    }
+    let version = ip[0] >> 4;
+    if version != 4 {
+        return Err(ParseError::UnsupportedIpVersion(version));
+    }
+    let ihl = ((ip[0] & 0x0f) as usize) * 4;
+    if ihl < IP_HEADER_SIZE {
+        return Err(ParseError::InvalidIpHeaderLength(ihl));
+    }
+    let total_len = Be16::read(ip, 2).get() as usize;
+    if total_len < ihl {
+        return Err(ParseError::InvalidIpTotalLength(total_len));
+    }
+    if ip.len() < total_len {
+        return Err(ParseError::Truncated);
+    }
+    // anything past total_len is link layer padding
+    let ip = &ip[..total_len];
+    if calculate_ip_checksum(&ip[..ihl]) != 0 {
+        return Err(ParseError::InvalidIpChecksum);
+    }
+    if Be16::read(ip, 6).get() & IP_FRAG_MASK != 0 {
+        return Err(ParseError::Fragmented);
+    }
+    if ip[9] != IPPROTO_UDP {
+        return Err(ParseError::UnsupportedProtocol(ip[9]));
+    }
+    let src_ip = Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]);
+    let dst_ip = Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]);
+
+    let udp = &ip[ihl..];
+    if udp.len() < UDP_HEADER_SIZE {
+        return Err(ParseError::Truncated);
+    }
+    let udp_len = Be16::read(udp, 4).get() as usize;
+    if udp_len < UDP_HEADER_SIZE || udp_len > udp.len() {
+        return Err(ParseError::InvalidUdpLength(udp_len));
+    }
+    let udp = &udp[..udp_len];
+    let checksum = Be16::read(udp, 6).get();
+    if verify_udp_checksum && checksum != 0 {
+        // a computed checksum of zero is transmitted as all ones
+        let expected = match calculate_udp_checksum(udp, &src_ip, &dst_ip) {
+            0 => 0xffff,
+            c => c,
+        };
+        if checksum != expected {
+            return Err(ParseError::InvalidUdpChecksum);
+        }
+    }
+
+    Ok(UdpFrame {
+        src_mac,
+        dst_mac,
+        vlan_id,
+        src_ip,
+        dst_ip,
+        tos: ip[1],
+        ttl: ip[8],
+        src_port: Be16::read(udp, 0).get(),
+        dst_port: Be16::read(udp, 2).get(),
+        payload: &udp[UDP_HEADER_SIZE..],
+    })
+}
 
 pub fn write_eth_header(packet: &mut [u8], src_mac: &[u8; 6], dst_mac: &[u8; 6]) {
     packet[0..6].copy_from_slice(dst_mac);
     packet[6..12].copy_from_slice(src_mac);
-    packet[12..14].copy_from_slice(&(ETH_P_IP as u16).to_be_bytes());
+    Be16::new(ETH_P_IP).write(packet, 12);
 }
 
 pub fn write_ip_header(packet: &mut [u8], src_ip: &Ipv4Addr, dst_ip: &Ipv4Addr, udp_len: u16) {
@@ -19,22 +229,22 @@ pub fn write_ip_header(packet: &mut [u8], src_ip: &Ipv4Addr, dst_ip: &Ipv4Addr,
     packet[0] = 0x45;
     // tos
     packet[1] = 0;
-    packet[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+    Be16::new(total_len as u16).write(packet, 2);
     // identification
-    packet[4..6].copy_from_slice(&0u16.to_be_bytes());
+    Be16::new(0).write(packet, 4);
     // flags & frag offset
-    packet[6..8].copy_from_slice(&0u16.to_be_bytes());
+    Be16::new(0).write(packet, 6);
     // TTL
     packet[8] = 64;
     // protocol (UDP = 17)
     packet[9] = 17;
     // checksum
-    packet[10..12].copy_from_slice(&0u16.to_be_bytes());
+    Be16::new(0).write(packet, 10);
     packet[12..16].copy_from_slice(&src_ip.octets());
     packet[16..20].copy_from_slice(&dst_ip.octets());
 
     let checksum = calculate_ip_checksum(&packet[..IP_HEADER_SIZE]);
-    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    Be16::new(checksum).write(packet, 10);
 }
 
 pub fn write_udp_header(
@@ -48,17 +258,92 @@ pub fn write_udp_header(
 ) {
     let udp_len = UDP_HEADER_SIZE + payload_len as usize;
 
-    packet[0..2].copy_from_slice(&src_port.to_be_bytes());
-    packet[2..4].copy_from_slice(&dst_port.to_be_bytes());
-    packet[4..6].copy_from_slice(&(udp_len as u16).to_be_bytes());
-    packet[6..8].copy_from_slice(&0u16.to_be_bytes());
+    Be16::new(src_port).write(packet, 0);
+    Be16::new(dst_port).write(packet, 2);
+    Be16::new(udp_len as u16).write(packet, 4);
+    Be16::new(0).write(packet, 6);
 
     if csum {
         let checksum = calculate_udp_checksum(&packet[..udp_len], src_ip, dst_ip);
-        packet[6..8].copy_from_slice(&checksum.to_be_bytes());
+        Be16::new(checksum).write(packet, 6);
     }
 }
 
+/// Sets the IP and UDP lengths of an Ethernet/IPv4/UDP frame carrying `payload_len` bytes.
+///
+/// The IP checksum is updated incrementally, so it must be valid to begin with. The UDP checksum
+/// is cleared.
+pub fn set_udp_frame_len(frame: &mut [u8], payload_len: u16) {
+    let udp_len = UDP_HEADER_SIZE as u16 + payload_len;
+    let ip = &mut frame[ETH_HEADER_SIZE..];
+    set_ip_u16(ip, 2, IP_HEADER_SIZE as u16 + udp_len);
+
+    let udp = &mut ip[IP_HEADER_SIZE..];
+    Be16::new(udp_len).write(udp, 4);
+    Be16::new(0).write(udp, 6);
+}
+
+/// Tags the untagged frame starting at `frame[VLAN_HEADER_SIZE..]` with `tag`, by moving its MAC
+/// addresses to the start of `frame` and writing the tag between them and the ether type.
+///
+/// Building the frame after the room for the tag and then tagging it moves 12 bytes, instead of
+/// the whole frame to insert the tag after the fact.
+pub fn push_vlan_tag(frame: &mut [u8], tag: VlanTag) {
+    frame.copy_within(VLAN_HEADER_SIZE..VLAN_HEADER_SIZE + 12, 0);
+    Be16::new(ETH_P_8021Q).write(frame, 12);
+    Be16::new(tag.tci()).write(frame, 14);
+}
+
+/// Sets the TTL of an IPv4 header, updating its checksum incrementally.
+pub fn set_ip_ttl(ip: &mut [u8], ttl: u8) {
+    // the TTL shares a 16 bit word with the protocol
+    set_ip_u16(ip, 8, u16::from_be_bytes([ttl, ip[9]]));
+}
+
+/// Sets the ports of a UDP header, updating its checksum incrementally unless it's zero (not
+/// computed).
+pub fn set_udp_ports(udp: &mut [u8], src_port: u16, dst_port: u16) {
+    let mut checksum = Be16::read(udp, 6).get();
+    for (offset, port) in [(0, src_port), (2, dst_port)] {
+        let old = Be16::read(udp, offset).get();
+        checksum = update_checksum(checksum, old, port);
+        Be16::new(port).write(udp, offset);
+    }
+    if udp[6..8] != [0, 0] {
+        // zero means no checksum in UDP, so it's sent as all ones instead
+        let checksum = if checksum == 0 { 0xffff } else { checksum };
+        Be16::new(checksum).write(udp, 6);
+    }
+}
+
+/// Returns `checksum` updated for a 16 bit word of the checksummed data changing from `old` to
+/// `new`, without summing the rest of the data again.
+///
+/// This is equation 3 of [RFC 1624]: `HC' = ~(~HC + ~m + m')`.
+///
+/// [RFC 1624]: https://www.rfc-editor.org/rfc/rfc1624
+pub fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Like [`update_checksum`] for a 32 bit field such as an IPv4 address.
+pub fn update_checksum_u32(checksum: u16, old: u32, new: u32) -> u16 {
+    let checksum = update_checksum(checksum, (old >> 16) as u16, (new >> 16) as u16);
+    update_checksum(checksum, old as u16, new as u16)
+}
+
+// Sets the 16 bit word at `offset` of an IPv4 header and updates the header checksum.
+fn set_ip_u16(ip: &mut [u8], offset: usize, value: u16) {
+    let old = Be16::read(ip, offset).get();
+    let checksum = Be16::read(ip, 10).get();
+    Be16::new(value).write(ip, offset);
+    Be16::new(update_checksum(checksum, old, value)).write(ip, 10);
+}
+
 fn calculate_udp_checksum(udp_packet: &[u8], src_ip: &Ipv4Addr, dst_ip: &Ipv4Addr) -> u16 {
     let udp_len = udp_packet.len();
 
@@ -112,3 +397,189 @@ fn calculate_ip_checksum(header: &[u8]) -> u16 {
 
     !(sum as u16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const DST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn build_frame(payload: &[u8], csum: bool) -> Vec<u8> {
+        let len = payload.len();
+        let mut frame = vec![0u8; ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + len];
+        frame[ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE..].copy_from_slice(payload);
+        write_eth_header(&mut frame, &[1; 6], &[2; 6]);
+        write_ip_header(
+            &mut frame[ETH_HEADER_SIZE..],
+            &SRC_IP,
+            &DST_IP,
+            (UDP_HEADER_SIZE + len) as u16,
+        );
+        write_udp_header(
+            &mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &SRC_IP,
+            1234,
+            &DST_IP,
+            5678,
+            len as u16,
+            csum,
+        );
+        frame
+    }
+
+    #[test]
+    fn test_header_encoding() {
+        // checked against the bytes on the wire, so that a host byte order dependency fails here
+        // on big endian targets rather than on the network
+        let frame = build_frame(&[0xab; 2], true);
+        #[rustfmt::skip]
+        let expected = [
+            // ethernet: destination, source, ether type
+            2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 0x08, 0x00,
+            // ip: version and IHL, tos, total length, identification, flags and fragment offset
+            0x45, 0, 0, 30, 0, 0, 0, 0,
+            // ttl, protocol, checksum, source, destination
+            64, 17, 0x66, 0xcd, 10, 0, 0, 1, 10, 0, 0, 2,
+            // udp: source port, destination port, length, checksum
+            0x04, 0xd2, 0x16, 0x2e, 0, 10, 0x25, 0x2c,
+            0xab, 0xab,
+        ];
+        assert_eq!(frame, expected);
+
+        let mut field = [0u8; 3];
+        Be16::new(0x1234).write(&mut field, 1);
+        assert_eq!(field, [0, 0x12, 0x34]);
+        assert_eq!(Be16::read(&field, 1).get(), 0x1234);
+    }
+
+    #[test]
+    fn test_parse_udp_frame() {
+        let frame = build_frame(&[7; 33], true);
+        let parsed = parse_udp_frame(&frame, true).unwrap();
+        assert_eq!(parsed.src_mac, [1; 6]);
+        assert_eq!(parsed.dst_mac, [2; 6]);
+        assert_eq!(parsed.vlan_id, None);
+        assert_eq!(parsed.tos, 0);
+        assert_eq!(parsed.ttl, 64);
+        assert_eq!(parsed.src_addr(), "10.0.0.1:1234".parse().unwrap());
+        assert_eq!(parsed.dst_addr(), "10.0.0.2:5678".parse().unwrap());
+        assert_eq!(parsed.payload, &[7; 33]);
+
+        // link layer padding isn't part of the payload
+        let mut padded = build_frame(&[7; 4], false);
+        padded.resize(60, 0);
+        assert_eq!(parse_udp_frame(&padded, true).unwrap().payload, &[7; 4]);
+    }
+
+    #[test]
+    fn test_incremental_checksum() {
+        let ip_checksum = |frame: &[u8]| {
+            let mut ip = frame[ETH_HEADER_SIZE..][..IP_HEADER_SIZE].to_vec();
+            ip[10..12].fill(0);
+            calculate_ip_checksum(&ip).to_be_bytes()
+        };
+
+        let mut frame = build_frame(&[7; 33], true);
+        set_ip_ttl(&mut frame[ETH_HEADER_SIZE..], 3);
+        set_udp_ports(&mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..], 4321, 8765);
+        let parsed = parse_udp_frame(&frame, true).unwrap();
+        assert_eq!(
+            (parsed.ttl, parsed.src_port, parsed.dst_port),
+            (3, 4321, 8765)
+        );
+        assert_eq!(frame[ETH_HEADER_SIZE + 10..][..2], ip_checksum(&frame));
+
+        // grow the frame built for an empty payload
+        let mut frame = build_frame(&[], false);
+        frame.extend_from_slice(&[7; 1000]);
+        set_udp_frame_len(&mut frame, 1000);
+        assert_eq!(parse_udp_frame(&frame, true).unwrap().payload, &[7; 1000]);
+        assert_eq!(frame[ETH_HEADER_SIZE + 10..][..2], ip_checksum(&frame));
+
+        let new_ip = Ipv4Addr::new(192, 168, 1, 1);
+        let ip = &mut frame[ETH_HEADER_SIZE..];
+        let checksum = update_checksum_u32(
+            u16::from_be_bytes([ip[10], ip[11]]),
+            DST_IP.to_bits(),
+            new_ip.to_bits(),
+        );
+        ip[16..20].copy_from_slice(&new_ip.octets());
+        ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(parse_udp_frame(&frame, false).unwrap().dst_ip, new_ip);
+    }
+
+    #[test]
+    fn test_parse_vlan_frame() {
+        let frame = build_frame(&[7; 10], false);
+        let mut tagged = frame[..12].to_vec();
+        tagged.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+        tagged.extend_from_slice(&0x2064u16.to_be_bytes());
+        tagged.extend_from_slice(&frame[12..]);
+        let parsed = parse_udp_frame(&tagged, true).unwrap();
+        assert_eq!(parsed.vlan_id, Some(0x64));
+        assert_eq!(parsed.payload, &[7; 10]);
+    }
+
+    #[test]
+    fn test_push_vlan_tag() {
+        let frame = build_frame(&[7; 10], true);
+        let mut tagged = vec![0; VLAN_HEADER_SIZE];
+        tagged.extend_from_slice(&frame);
+        let tag = VlanTag {
+            vlan_id: 0x64,
+            pcp: 5,
+        };
+        push_vlan_tag(&mut tagged, tag);
+        assert_eq!(&tagged[..12], &frame[..12]);
+        assert_eq!(tagged[14] >> 5, 5);
+        let parsed = parse_udp_frame(&tagged, true).unwrap();
+        assert_eq!(parsed.vlan_id, Some(0x64));
+        assert_eq!(parsed.payload, &[7; 10]);
+    }
+
+    #[test]
+    fn test_parse_invalid_frames() {
+        let frame = build_frame(&[7; 10], true);
+
+        assert_eq!(
+            parse_udp_frame(&frame[..ETH_HEADER_SIZE + 10], false),
+            Err(ParseError::Truncated)
+        );
+        assert_eq!(
+            parse_udp_frame(&frame[..frame.len() - 1], false),
+            Err(ParseError::Truncated)
+        );
+
+        let mut bad = frame.clone();
+        bad[12..14].copy_from_slice(&0x86ddu16.to_be_bytes());
+        assert_eq!(
+            parse_udp_frame(&bad, false),
+            Err(ParseError::UnsupportedEtherType(0x86dd))
+        );
+
+        let mut bad = frame.clone();
+        bad[ETH_HEADER_SIZE + 8] = 1;
+        assert_eq!(
+            parse_udp_frame(&bad, false),
+            Err(ParseError::InvalidIpChecksum)
+        );
+
+        // corrupting the payload is only caught when checking the UDP checksum
+        let mut bad = frame.clone();
+        *bad.last_mut().unwrap() = 0;
+        assert!(parse_udp_frame(&bad, false).is_ok());
+        assert_eq!(
+            parse_udp_frame(&bad, true),
+            Err(ParseError::InvalidUdpChecksum)
+        );
+
+        let mut bad = frame.clone();
+        let udp_len = ETH_HEADER_SIZE + IP_HEADER_SIZE + 4;
+        bad[udp_len..udp_len + 2].copy_from_slice(&100u16.to_be_bytes());
+        assert_eq!(
+            parse_udp_frame(&bad, false),
+            Err(ParseError::InvalidUdpLength(100))
+        );
+    }
+}
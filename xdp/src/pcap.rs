@@ -0,0 +1,461 @@
+//! Minimal pcap reader and writer used to capture and replay frames.
+//!
+//! Files are written in the classic libpcap format with microsecond timestamps and an ethernet
+//! link type, so they can be opened directly with Wireshark or tcpdump. The reader also accepts
+//! nanosecond timestamps and either byte order.
+#![allow(clippy::arithmetic_side_effects)]
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Default number of bytes captured for each frame.
+pub const DEFAULT_SNAPLEN: u32 = 65535;
+// Largest record read, whatever the snaplen of the file says. tcpdump captures up to 256KiB.
+const MAX_RECORD_LEN: u32 = 262_144;
+
+/// Writes frames to a pcap stream.
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    snaplen: u32,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Creates a new writer and writes the pcap file header.
+    ///
+    /// Frames longer than `snaplen` are truncated.
+    pub fn new(mut writer: W, snaplen: u32) -> io::Result<Self> {
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+        // thiszone
+        writer.write_all(&0i32.to_le_bytes())?;
+        // sigfigs
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&snaplen.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        Ok(Self { writer, snaplen })
+    }
+
+    /// Writes a single frame record.
+    pub fn write_frame(&mut self, timestamp: SystemTime, frame: &[u8]) -> io::Result<()> {
+        self.write_frame_truncated(timestamp, frame.len() as u32, frame)
+    }
+
+    /// Writes the record of a frame of `orig_len` bytes that was already truncated to `frame`.
+    pub fn write_frame_truncated(
+        &mut self,
+        timestamp: SystemTime,
+        orig_len: u32,
+        frame: &[u8],
+    ) -> io::Result<()> {
+        let ts = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let captured = frame.len().min(self.snaplen as usize);
+        self.writer
+            .write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+        self.writer.write_all(&ts.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&(captured as u32).to_le_bytes())?;
+        self.writer.write_all(&orig_len.to_le_bytes())?;
+        self.writer.write_all(&frame[..captured])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// A frame read from a pcap stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PcapRecord {
+    pub timestamp: SystemTime,
+    /// Length of the frame on the wire. Can be larger than `data.len()` if the frame was
+    /// truncated at capture time.
+    pub orig_len: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reads frames from a pcap stream.
+pub struct PcapReader<R: Read> {
+    reader: R,
+    swapped: bool,
+    nanos: bool,
+    snaplen: u32,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Creates a new reader and parses the pcap file header.
+    ///
+    /// Only ethernet captures are supported.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let (swapped, nanos) = match magic {
+            PCAP_MAGIC => (false, false),
+            PCAP_MAGIC_NANOS => (false, true),
+            m if m == PCAP_MAGIC.swap_bytes() => (true, false),
+            m if m == PCAP_MAGIC_NANOS.swap_bytes() => (true, true),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid pcap magic {magic:#x}"),
+                ))
+            }
+        };
+
+        let mut this = Self {
+            reader,
+            swapped,
+            nanos,
+            snaplen: 0,
+        };
+        this.snaplen = this.u32_at(&header, 16);
+        let link_type = this.u32_at(&header, 20);
+        if link_type != LINKTYPE_ETHERNET {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported pcap link type {link_type}"),
+            ));
+        }
+        Ok(this)
+    }
+
+    pub fn snaplen(&self) -> u32 {
+        self.snaplen
+    }
+
+    /// Reads the next record. Returns `None` at the end of the stream.
+    pub fn read_record(&mut self) -> io::Result<Option<PcapRecord>> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let ts_sec = self.u32_at(&header, 0);
+        let ts_frac = self.u32_at(&header, 4);
+        let incl_len = self.u32_at(&header, 8);
+        let orig_len = self.u32_at(&header, 12);
+        // guard against corrupted files making us allocate huge buffers
+        if incl_len > self.snaplen.clamp(DEFAULT_SNAPLEN, MAX_RECORD_LEN) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("pcap record length {incl_len} exceeds snaplen"),
+            ));
+        }
+
+        let mut data = vec![0u8; incl_len as usize];
+        self.reader.read_exact(&mut data)?;
+
+        let frac = if self.nanos {
+            Duration::from_nanos(ts_frac as u64)
+        } else {
+            Duration::from_micros(ts_frac as u64)
+        };
+        Ok(Some(PcapRecord {
+            timestamp: UNIX_EPOCH + Duration::from_secs(ts_sec as u64) + frac,
+            orig_len,
+            data,
+        }))
+    }
+
+    fn u32_at(&self, buf: &[u8], offset: usize) -> u32 {
+        let v = u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap());
+        if self.swapped {
+            v.swap_bytes()
+        } else {
+            v
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<PcapRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// Configuration for capturing transmitted frames to a pcap file.
+#[derive(Clone, Debug)]
+pub struct PcapTapConfig {
+    /// Path of the capture file. Each tx loop writes to its own file, suffixed with the queue id.
+    pub path: PathBuf,
+    /// Capture one every `sample_rate` frames. 1 captures every frame.
+    pub sample_rate: u32,
+    /// Maximum number of bytes captured for each frame.
+    pub snaplen: u32,
+}
+
+impl PcapTapConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            sample_rate: 1,
+            snaplen: DEFAULT_SNAPLEN,
+        }
+    }
+
+    pub(crate) fn path_for_queue(&self, queue_id: u64) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".q{queue_id}"));
+        path.into()
+    }
+}
+
+/// A sampling tap on the tx path.
+///
+/// Captured frames are written from the tx loop thread through a buffered writer, so capturing
+/// every frame at high packet rates will reduce throughput. Use a sample rate for production
+/// debugging.
+///
+/// The tap stops capturing after the first failed write, since the file can't be trusted past a
+/// partially written record.
+pub struct PcapTap {
+    writer: PcapWriter<Box<dyn Write + Send>>,
+    sample_rate: u32,
+    seen: u32,
+    failed: bool,
+}
+
+impl PcapTap {
+    pub fn new(writer: Box<dyn Write + Send>, sample_rate: u32, snaplen: u32) -> io::Result<Self> {
+        Ok(Self {
+            writer: PcapWriter::new(writer, snaplen)?,
+            sample_rate: sample_rate.max(1),
+            seen: 0,
+            failed: false,
+        })
+    }
+
+    /// Creates a tap writing to a new file at `path`.
+    pub fn create(path: &Path, sample_rate: u32, snaplen: u32) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Self::new(Box::new(file), sample_rate, snaplen)
+    }
+
+    /// Offers a transmitted frame to the tap, which captures it if it's selected by sampling.
+    #[inline]
+    pub fn capture(&mut self, frame: &[u8]) {
+        self.seen += 1;
+        if self.seen < self.sample_rate {
+            return;
+        }
+        self.seen = 0;
+        if !self.failed {
+            self.capture_slow(frame);
+        }
+    }
+
+    #[inline(never)]
+    fn capture_slow(&mut self, frame: &[u8]) {
+        if let Err(e) = self.writer.write_frame(SystemTime::now(), frame) {
+            log::warn!("failed to write pcap frame, stopping the capture: {e}");
+            self.failed = true;
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Drop for PcapTap {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn test_pcap_writer() {
+        let mut writer = PcapWriter::new(Vec::new(), 4).unwrap();
+        writer
+            .write_frame(
+                UNIX_EPOCH + Duration::from_micros(1_000_002),
+                &[1, 2, 3, 4, 5, 6],
+            )
+            .unwrap();
+        let buf = writer.into_inner();
+
+        assert_eq!(buf.len(), 24 + 16 + 4);
+        assert_eq!(&buf[0..4], &PCAP_MAGIC.to_le_bytes());
+        assert_eq!(&buf[16..20], &4u32.to_le_bytes());
+        assert_eq!(&buf[20..24], &LINKTYPE_ETHERNET.to_le_bytes());
+        // ts_sec, ts_usec
+        assert_eq!(&buf[24..28], &1u32.to_le_bytes());
+        assert_eq!(&buf[28..32], &2u32.to_le_bytes());
+        // incl_len is truncated to the snaplen, orig_len isn't
+        assert_eq!(&buf[32..36], &4u32.to_le_bytes());
+        assert_eq!(&buf[36..40], &6u32.to_le_bytes());
+        assert_eq!(&buf[40..], &[1, 2, 3, 4]);
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pcap_tap_sampling() {
+        let buf = SharedBuf::default();
+        let mut tap = PcapTap::new(Box::new(buf.clone()), 3, DEFAULT_SNAPLEN).unwrap();
+        for i in 0..10u8 {
+            tap.capture(&[i; 10]);
+        }
+        drop(tap);
+
+        // frames 2, 5 and 8 are captured
+        let buf = buf.0.lock().unwrap();
+        assert_eq!(buf.len(), 24 + 3 * (16 + 10));
+        for (i, frame) in [2u8, 5, 8].into_iter().enumerate() {
+            let offset = 24 + i * 26 + 16;
+            assert_eq!(&buf[offset..offset + 10], &[frame; 10]);
+        }
+    }
+
+    #[test]
+    fn test_pcap_tap_write_error() {
+        struct FailingWriter(Arc<Mutex<usize>>);
+
+        impl Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                let mut writes = self.0.lock().unwrap();
+                *writes += 1;
+                // let the file header through
+                if *writes > 7 {
+                    return Err(io::Error::other("disk full"));
+                }
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let writes = Arc::new(Mutex::new(0));
+        let mut tap = PcapTap::new(Box::new(FailingWriter(writes.clone())), 1, 8).unwrap();
+        for _ in 0..10 {
+            tap.capture(&[0; 4]);
+        }
+        // only the first frame is attempted
+        assert_eq!(*writes.lock().unwrap(), 8);
+    }
+
+    #[test]
+    fn test_pcap_reader_roundtrip() {
+        let mut writer = PcapWriter::new(Vec::new(), 8).unwrap();
+        let ts = UNIX_EPOCH + Duration::from_micros(5_000_007);
+        writer.write_frame(ts, &[1, 2, 3]).unwrap();
+        writer.write_frame(ts, &[4; 10]).unwrap();
+        let buf = writer.into_inner();
+
+        let mut reader = PcapReader::new(&buf[..]).unwrap();
+        assert_eq!(reader.snaplen(), 8);
+        assert_eq!(
+            reader.read_record().unwrap(),
+            Some(PcapRecord {
+                timestamp: ts,
+                orig_len: 3,
+                data: vec![1, 2, 3],
+            })
+        );
+        assert_eq!(
+            reader.read_record().unwrap(),
+            Some(PcapRecord {
+                timestamp: ts,
+                orig_len: 10,
+                data: vec![4; 8],
+            })
+        );
+        assert_eq!(reader.read_record().unwrap(), None);
+    }
+
+    #[test]
+    fn test_pcap_reader_big_endian_nanos() {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&PCAP_MAGIC_NANOS.to_be_bytes());
+        buf.extend_from_slice(&PCAP_VERSION_MAJOR.to_be_bytes());
+        buf.extend_from_slice(&PCAP_VERSION_MINOR.to_be_bytes());
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&1500u32.to_be_bytes());
+        buf.extend_from_slice(&LINKTYPE_ETHERNET.to_be_bytes());
+        for v in [7u32, 9, 2, 2] {
+            buf.extend_from_slice(&v.to_be_bytes());
+        }
+        buf.extend_from_slice(&[0xaa, 0xbb]);
+
+        let records = PcapReader::new(&buf[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![PcapRecord {
+                timestamp: UNIX_EPOCH + Duration::from_secs(7) + Duration::from_nanos(9),
+                orig_len: 2,
+                data: vec![0xaa, 0xbb],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_pcap_reader_invalid() {
+        assert!(PcapReader::new(&[0u8; 24][..]).is_err());
+        assert!(PcapReader::new(&[0u8; 3][..]).is_err());
+
+        // a corrupted snaplen doesn't let records grow past MAX_RECORD_LEN
+        let mut buf = PcapWriter::new(Vec::new(), u32::MAX).unwrap().into_inner();
+        for v in [0, 0, MAX_RECORD_LEN + 1, MAX_RECORD_LEN + 1] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        let mut reader = PcapReader::new(&buf[..]).unwrap();
+        assert_eq!(
+            reader.read_record().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_path_for_queue() {
+        let config = PcapTapConfig::new("/tmp/tx.pcap");
+        assert_eq!(config.path_for_queue(3), PathBuf::from("/tmp/tx.pcap.q3"));
+    }
+}
@@ -0,0 +1,363 @@
+//! Aligning NIC queue interrupts with the workers processing the queues.
+//!
+//! Each queue gets a worker CPU, which is what the tx/rx loops of the queue should be pinned to,
+//! and a neighboring CPU on the same NUMA node that handles the queue's interrupts. Ideally both
+//! are on the NUMA node the NIC is attached to. When interrupts land on a far away CPU, or on the
+//! busy polling worker itself, throughput silently drops.
+
+use {
+    crate::device::NetworkDevice,
+    agave_cpu_utils::{cpu_affinity, parse_cpu_range_list, CpuAffinityError},
+    std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        fs, io,
+    },
+    thiserror::Error,
+};
+
+#[derive(Debug, Error)]
+pub enum PlacementError {
+    #[error("need {needed} CPUs but only {available} are available")]
+    NotEnoughCpus { needed: usize, available: usize },
+
+    #[error("no interrupts found for {0}")]
+    NoIrqs(String),
+
+    #[error(transparent)]
+    Cpu(#[from] CpuAffinityError),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Where a queue is processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueuePlacement {
+    pub queue_id: u64,
+    /// The CPU the queue's worker should be pinned to.
+    pub worker_cpu: usize,
+    /// The CPU handling the queue's interrupts.
+    pub irq_cpu: usize,
+}
+
+/// Something wrong with how a queue is placed, as reported by [`verify_placement`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Misalignment {
+    /// No interrupt could be found for the queue.
+    MissingIrq { queue_id: u64 },
+    /// An interrupt of the queue is handled by other CPUs than the planned one.
+    IrqAffinity {
+        queue_id: u64,
+        irq: u32,
+        expected_cpu: usize,
+        effective_cpus: Vec<usize>,
+    },
+    /// A CPU used for the queue is on another NUMA node than the NIC.
+    RemoteNode {
+        queue_id: u64,
+        cpu: usize,
+        cpu_node: usize,
+        nic_node: usize,
+    },
+}
+
+/// The parts of the CPU topology that matter for placing queues.
+#[derive(Clone, Debug, Default)]
+pub struct CpuTopology {
+    nodes: HashMap<usize, usize>,
+    siblings: HashMap<usize, Vec<usize>>,
+}
+
+impl CpuTopology {
+    /// Reads the NUMA node and SMT siblings of each CPU this thread is allowed to run on.
+    pub fn from_sysfs() -> Result<Self, PlacementError> {
+        let mut topology = Self::default();
+        for cpu in cpu_affinity()? {
+            let cpu_dir = format!("/sys/devices/system/cpu/cpu{cpu}");
+            if let Ok(entries) = fs::read_dir(&cpu_dir) {
+                let node = entries.filter_map(Result::ok).find_map(|entry| {
+                    entry
+                        .file_name()
+                        .to_str()?
+                        .strip_prefix("node")?
+                        .parse()
+                        .ok()
+                });
+                if let Some(node) = node {
+                    topology.nodes.insert(cpu, node);
+                }
+            }
+            if let Ok(siblings) =
+                fs::read_to_string(format!("{cpu_dir}/topology/thread_siblings_list"))
+            {
+                topology
+                    .siblings
+                    .insert(cpu, parse_cpu_range_list(siblings.trim())?);
+            }
+        }
+        Ok(topology)
+    }
+
+    /// Creates a topology from the NUMA node and SMT siblings of each CPU.
+    pub fn new(
+        nodes: impl IntoIterator<Item = (usize, usize)>,
+        siblings: impl IntoIterator<Item = (usize, Vec<usize>)>,
+    ) -> Self {
+        Self {
+            nodes: nodes.into_iter().collect(),
+            siblings: siblings.into_iter().collect(),
+        }
+    }
+
+    pub fn node_of(&self, cpu: usize) -> Option<usize> {
+        self.nodes.get(&cpu).copied()
+    }
+
+    fn siblings_of(&self, cpu: usize) -> &[usize] {
+        self.siblings.get(&cpu).map(Vec::as_slice).unwrap_or(&[])
+    }
+}
+
+/// Picks a worker CPU and an interrupt CPU for each of `queues` queues out of `budget`.
+///
+/// CPUs on `nic_node` are used first. The interrupt CPU is the SMT sibling of the worker CPU if
+/// it's in the budget, otherwise another CPU on the same NUMA node.
+pub fn plan_placement(
+    budget: &[usize],
+    queues: usize,
+    nic_node: Option<usize>,
+    topology: &CpuTopology,
+) -> Result<Vec<QueuePlacement>, PlacementError> {
+    let needed = queues.saturating_mul(2);
+    let mut by_node = BTreeMap::<(bool, Option<usize>), Vec<usize>>::new();
+    for &cpu in budget.iter().collect::<HashSet<_>>() {
+        let node = topology.node_of(cpu);
+        let remote = nic_node.is_some() && node != nic_node;
+        by_node.entry((remote, node)).or_default().push(cpu);
+    }
+
+    let mut placements = Vec::with_capacity(queues);
+    for cpus in by_node.values_mut() {
+        cpus.sort_unstable();
+        while placements.len() < queues && cpus.len() >= 2 {
+            let worker_cpu = cpus.remove(0);
+            let irq_index = topology
+                .siblings_of(worker_cpu)
+                .iter()
+                .find_map(|sibling| cpus.iter().position(|cpu| cpu == sibling))
+                .unwrap_or(0);
+            let irq_cpu = cpus.remove(irq_index);
+            placements.push(QueuePlacement {
+                queue_id: placements.len() as u64,
+                worker_cpu,
+                irq_cpu,
+            });
+        }
+    }
+
+    if placements.len() < queues {
+        return Err(PlacementError::NotEnoughCpus {
+            needed,
+            available: budget.len(),
+        });
+    }
+    Ok(placements)
+}
+
+/// Returns the interrupts of each queue of `dev`, keyed by queue id.
+pub fn queue_irqs(dev: &NetworkDevice) -> Result<BTreeMap<u64, Vec<u32>>, PlacementError> {
+    let interrupts = fs::read_to_string("/proc/interrupts")?;
+    let msi_irqs = dev.msi_irqs()?.unwrap_or_default();
+    Ok(parse_queue_irqs(&interrupts, dev.name(), &msi_irqs))
+}
+
+// Drivers name their queue interrupts differently, e.g. `eth0-TxRx-3`, `i40e-eth0-TxRx-3` or
+// `mlx5_comp3@pci:0000:3b:00.0`. The queue id is the trailing number of the name, after the
+// interface name if it's in there.
+fn parse_queue_irqs(interrupts: &str, if_name: &str, msi_irqs: &[u32]) -> BTreeMap<u64, Vec<u32>> {
+    let mut irqs = BTreeMap::<u64, Vec<u32>>::new();
+    for line in interrupts.lines() {
+        let Some((irq, rest)) = line.split_once(':') else {
+            continue;
+        };
+        let Ok(irq) = irq.trim().parse::<u32>() else {
+            continue;
+        };
+        let Some(name) = rest.split_whitespace().last() else {
+            continue;
+        };
+        let name = name.split('@').next().unwrap_or(name);
+        let name = match name.find(if_name) {
+            Some(start) => &name[start + if_name.len()..],
+            None if msi_irqs.contains(&irq) => name,
+            None => continue,
+        };
+        // not queue interrupts
+        if ["async", "ctrl", "link"]
+            .iter()
+            .any(|skip| name.to_ascii_lowercase().contains(skip))
+        {
+            continue;
+        }
+        let digits = name.len() - name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+        let Ok(queue_id) = name[name.len() - digits..].parse::<u64>() else {
+            continue;
+        };
+        irqs.entry(queue_id).or_default().push(irq);
+    }
+    irqs
+}
+
+/// Routes the interrupts of each queue of `dev` to the planned CPU.
+pub fn apply_placement(
+    dev: &NetworkDevice,
+    placements: &[QueuePlacement],
+) -> Result<(), PlacementError> {
+    let irqs = queue_irqs(dev)?;
+    if irqs.is_empty() {
+        return Err(PlacementError::NoIrqs(dev.name().to_owned()));
+    }
+    for placement in placements {
+        for irq in irqs.get(&placement.queue_id).into_iter().flatten() {
+            fs::write(
+                format!("/proc/irq/{irq}/smp_affinity_list"),
+                placement.irq_cpu.to_string(),
+            )
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("failed to set the affinity of irq {irq}: {e}"),
+                )
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the interrupts of each queue of `dev` are handled by the planned CPU, and that the
+/// planned CPUs are local to the NIC.
+pub fn verify_placement(
+    dev: &NetworkDevice,
+    placements: &[QueuePlacement],
+    topology: &CpuTopology,
+) -> Result<Vec<Misalignment>, PlacementError> {
+    let irqs = queue_irqs(dev)?;
+    let nic_node = dev.numa_node()?;
+    let mut misalignments = Vec::new();
+    for placement in placements {
+        let queue_id = placement.queue_id;
+        match irqs.get(&queue_id) {
+            None => misalignments.push(Misalignment::MissingIrq { queue_id }),
+            Some(irqs) => {
+                for &irq in irqs {
+                    let effective_cpus = irq_effective_affinity(irq)?;
+                    if effective_cpus != [placement.irq_cpu] {
+                        misalignments.push(Misalignment::IrqAffinity {
+                            queue_id,
+                            irq,
+                            expected_cpu: placement.irq_cpu,
+                            effective_cpus,
+                        });
+                    }
+                }
+            }
+        }
+        if let Some(nic_node) = nic_node {
+            for cpu in [placement.worker_cpu, placement.irq_cpu] {
+                match topology.node_of(cpu) {
+                    Some(cpu_node) if cpu_node != nic_node => {
+                        misalignments.push(Misalignment::RemoteNode {
+                            queue_id,
+                            cpu,
+                            cpu_node,
+                            nic_node,
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(misalignments)
+}
+
+fn irq_effective_affinity(irq: u32) -> Result<Vec<usize>, PlacementError> {
+    // effective_affinity_list isn't available on all architectures
+    let cpus = fs::read_to_string(format!("/proc/irq/{irq}/effective_affinity_list"))
+        .or_else(|_| fs::read_to_string(format!("/proc/irq/{irq}/smp_affinity_list")))?;
+    Ok(parse_cpu_range_list(cpus.trim())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_placement() {
+        // two nodes with 4 cores each, SMT siblings are cpu and cpu + 8
+        let topology = CpuTopology::new(
+            (0..16).map(|cpu| (cpu, (cpu % 8) / 4)),
+            (0..16).map(|cpu| (cpu, vec![cpu % 8, cpu % 8 + 8])),
+        );
+
+        // the NIC is on node 1, the worker and its sibling are used together
+        let placements = plan_placement(&[0, 1, 4, 5, 12, 13], 2, Some(1), &topology).unwrap();
+        assert_eq!(
+            placements,
+            vec![
+                QueuePlacement {
+                    queue_id: 0,
+                    worker_cpu: 4,
+                    irq_cpu: 12,
+                },
+                QueuePlacement {
+                    queue_id: 1,
+                    worker_cpu: 5,
+                    irq_cpu: 13,
+                },
+            ]
+        );
+
+        // not enough CPUs on node 1, spill over to node 0 without splitting a pair across nodes
+        let placements = plan_placement(&[0, 1, 4, 5, 6], 2, Some(1), &topology).unwrap();
+        assert_eq!(
+            placements
+                .iter()
+                .map(|p| (p.worker_cpu, p.irq_cpu))
+                .collect::<Vec<_>>(),
+            vec![(4, 5), (0, 1)]
+        );
+
+        assert!(matches!(
+            plan_placement(&[0, 1, 4], 2, None, &topology),
+            Err(PlacementError::NotEnoughCpus {
+                needed: 4,
+                available: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_queue_irqs() {
+        let interrupts = "\
+            CPU0       CPU1
+  0:         36          0   IO-APIC   2-edge      timer
+ 40:          0          0   PCI-MSI 524288-edge      eth0
+ 41:        100          0   PCI-MSI 524289-edge      eth0-TxRx-0
+ 42:          0        100   PCI-MSI 524290-edge      eth0-TxRx-1
+ 43:          0        100   PCI-MSI 524291-edge      eth1-TxRx-0
+ 50:          0          0   PCI-MSI 1048576-edge      mlx5_async0@pci:0000:3b:00.0
+ 51:          0          0   PCI-MSI 1048577-edge      mlx5_comp0@pci:0000:3b:00.0
+ 52:          0          0   PCI-MSI 1048578-edge      mlx5_comp1@pci:0000:3b:00.0
+NMI:          0          0   Non-maskable interrupts
+";
+        assert_eq!(
+            parse_queue_irqs(interrupts, "eth0", &[]),
+            BTreeMap::from([(0, vec![41]), (1, vec![42])])
+        );
+        assert_eq!(
+            parse_queue_irqs(interrupts, "enp59s0f0", &[50, 51, 52]),
+            BTreeMap::from([(0, vec![51]), (1, vec![52])])
+        );
+    }
+}
@@ -1,9 +1,23 @@
 #![allow(clippy::arithmetic_side_effects)]
 
 use {
-    crate::device::NetworkDevice,
-    aya::{programs::Xdp, Ebpf, EbpfLoader},
-    std::io::{Cursor, Write},
+    crate::{
+        device::NetworkDevice,
+        error::{XdpError, XdpErrorKind},
+        netlink::netlink_detach_xdp,
+    },
+    aya::{
+        programs::{
+            links::FdLink,
+            xdp::{XdpFlags, XdpLinkId},
+            Xdp,
+        },
+        Ebpf, EbpfLoader,
+    },
+    std::{
+        io::{Cursor, Write},
+        path::Path,
+    },
 };
 
 macro_rules! write_fields {
@@ -40,21 +54,179 @@ const XDP_PROG: &[u8] = &[
 // the string table
 const STRTAB: &[u8] = b"\0xdp\0.symtab\0.strtab\0agave_xdp\0";
 
+/// How the program is attached, see [`XdpAttachOptions::mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum XdpAttachMode {
+    /// In the driver. Required for zero copy.
+    #[default]
+    Native,
+    /// In the kernel's generic path, for drivers without XDP support. Only copy mode works and
+    /// it's much slower, mostly useful for testing.
+    Generic,
+}
+
+impl XdpAttachMode {
+    fn flags(self) -> XdpFlags {
+        match self {
+            Self::Native => XdpFlags::DRV_MODE,
+            Self::Generic => XdpFlags::SKB_MODE,
+        }
+    }
+}
+
+/// Controls how the programs are attached by the `load_*_with_options` functions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XdpAttachOptions {
+    pub mode: XdpAttachMode,
+    /// Detach the program already attached to the interface, if any, instead of failing. The
+    /// interface has no program in between, and programs attached through a bpf link, e.g. by
+    /// another agave process, can't be detached.
+    pub replace_existing: bool,
+    /// Only replace the program with this id, failing if another program or none is attached.
+    /// Ignored unless `replace_existing` is set.
+    pub expected_program_id: Option<u32>,
+}
+
+/// Loads the program passing every packet to the kernel, required for zero copy.
 pub fn load_xdp_program(dev: &NetworkDevice) -> Result<Ebpf, Box<dyn std::error::Error>> {
+    load_xdp_program_with_options(dev, &XdpAttachOptions::default())
+}
+
+/// Like [`load_xdp_program`], attaching the program according to `options`.
+pub fn load_xdp_program_with_options(
+    dev: &NetworkDevice,
+    options: &XdpAttachOptions,
+) -> Result<Ebpf, Box<dyn std::error::Error>> {
     let mut loader = EbpfLoader::new();
-    let broken_frags = dev.driver()? == "i40e";
+    let broken_frags = drops_multi_frags(dev)?;
     let mut ebpf = if broken_frags {
         loader.set_global("AGAVE_XDP_DROP_MULTI_FRAGS", &1u8, true);
         loader.load(&agave_xdp_ebpf::AGAVE_XDP_EBPF_PROGRAM)
     } else {
         loader.load(&generate_xdp_elf())
     }?;
+    attach_link(&mut ebpf, dev, options)?;
+    Ok(ebpf)
+}
+
+/// Loads the program redirecting the flows in its flow map to AF_XDP, see
+/// [`RxFilter`](crate::rx_filter::RxFilter). The flow map starts empty, so until flows are
+/// added every packet is passed to the kernel.
+pub fn load_rx_program(dev: &NetworkDevice) -> Result<Ebpf, Box<dyn std::error::Error>> {
+    load_rx_program_with_options(dev, &XdpAttachOptions::default())
+}
+
+/// Like [`load_rx_program`], attaching the program according to `options`.
+pub fn load_rx_program_with_options(
+    dev: &NetworkDevice,
+    options: &XdpAttachOptions,
+) -> Result<Ebpf, Box<dyn std::error::Error>> {
+    let mut ebpf = rx_program_loader(dev)?.load(&agave_xdp_ebpf::AGAVE_XDP_EBPF_PROGRAM)?;
+    attach_link(&mut ebpf, dev, options)?;
+    Ok(ebpf)
+}
+
+/// Attaches the program returned by [`load_rx_program`] to another interface, sharing its maps.
+/// Give the sockets of each interface their own slot with
+/// [`RxFilter::register_interface_socket`](crate::rx_filter::RxFilter::register_interface_socket).
+///
+/// The program stays attached until `ebpf` is dropped.
+pub fn attach_rx_program(
+    ebpf: &mut Ebpf,
+    dev: &NetworkDevice,
+    options: &XdpAttachOptions,
+) -> Result<XdpLinkId, Box<dyn std::error::Error>> {
+    let p: &mut Xdp = ebpf.program_mut("agave_xdp").unwrap().try_into().unwrap();
+    attach_loaded(p, dev, options)
+}
+
+/// Like [`load_rx_program`], but the link attaching the program is pinned at `link_path` on a
+/// bpffs mount instead of being owned by the returned `Ebpf`. The program then stays attached
+/// after the process exits, until the pin is removed, so that another process can take it over,
+/// see [`upgrade`](crate::upgrade).
+pub fn load_rx_program_pinned(
+    dev: &NetworkDevice,
+    link_path: &Path,
+) -> Result<Ebpf, Box<dyn std::error::Error>> {
+    let mut ebpf = rx_program_loader(dev)?.load(&agave_xdp_ebpf::AGAVE_XDP_EBPF_PROGRAM)?;
+    let link_id = attach_link(&mut ebpf, dev, &XdpAttachOptions::default())?;
+    let p: &mut Xdp = ebpf.program_mut("agave_xdp").unwrap().try_into().unwrap();
+    // requires a kernel attaching XDP programs with bpf links, 5.9 or later
+    let link = FdLink::try_from(p.take_link(link_id)?)?;
+    link.pin(link_path)?;
+    Ok(ebpf)
+}
+
+/// Returns the id the kernel gave the program loaded by any of the `load_*` functions.
+pub fn xdp_program_id(ebpf: &Ebpf) -> Option<u32> {
+    let program: &Xdp = ebpf.program("agave_xdp")?.try_into().ok()?;
+    program.info().ok().map(|info| info.id())
+}
+
+// i40e mishandles multi-buffer frames, so the program drops them. Virtual devices like veth have
+// no driver in sysfs.
+fn drops_multi_frags(dev: &NetworkDevice) -> Result<bool, std::io::Error> {
+    match dev.driver() {
+        Ok(driver) => Ok(driver == "i40e"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn rx_program_loader(dev: &NetworkDevice) -> Result<EbpfLoader<'static>, std::io::Error> {
+    let mut loader = EbpfLoader::new();
+    if drops_multi_frags(dev)? {
+        loader.set_global("AGAVE_XDP_DROP_MULTI_FRAGS", &1u8, true);
+    }
+    Ok(loader)
+}
+
+fn attach_link(
+    ebpf: &mut Ebpf,
+    dev: &NetworkDevice,
+    options: &XdpAttachOptions,
+) -> Result<XdpLinkId, Box<dyn std::error::Error>> {
     let p: &mut Xdp = ebpf.program_mut("agave_xdp").unwrap().try_into().unwrap();
     p.load()?;
+    attach_loaded(p, dev, options)
+}
 
-    p.attach_to_if_index(dev.if_index(), aya::programs::xdp::XdpFlags::DRV_MODE)?;
+fn attach_loaded(
+    p: &mut Xdp,
+    dev: &NetworkDevice,
+    options: &XdpAttachOptions,
+) -> Result<XdpLinkId, Box<dyn std::error::Error>> {
+    let attached = dev.info()?.xdp_prog_id;
+    if let Some(detach) = check_attached(attached, options)? {
+        log::info!("detaching xdp program {detach} from {}", dev.name());
+        netlink_detach_xdp(dev.if_index(), options.mode.flags().bits())?;
+    }
 
-    Ok(ebpf)
+    Ok(p.attach_to_if_index(dev.if_index(), options.mode.flags())?)
+}
+
+// Returns the program to detach before attaching ours, if any.
+fn check_attached(
+    attached: Option<u32>,
+    options: &XdpAttachOptions,
+) -> Result<Option<u32>, XdpError> {
+    let error =
+        |msg: String| XdpError::other(XdpErrorKind::Misconfigured, "bpf(BPF_LINK_CREATE)", msg);
+    match (
+        attached,
+        options.replace_existing,
+        options.expected_program_id,
+    ) {
+        (None, false, _) | (None, true, None) => Ok(None),
+        (Some(id), false, _) => Err(error(format!("xdp program {id} is already attached"))),
+        (None, true, Some(expected)) => Err(error(format!(
+            "expected xdp program {expected} to be attached, found none"
+        ))),
+        (Some(id), true, Some(expected)) if id != expected => Err(error(format!(
+            "expected xdp program {expected} to be attached, found {id}"
+        ))),
+        (Some(id), true, _) => Ok(Some(id)),
+    }
 }
 
 fn generate_xdp_elf() -> Vec<u8> {
@@ -209,3 +381,32 @@ fn write_section_headers(
     write_section_header(w, STRTAB_SYMTAB_OFF, SHT_SYMTAB, 0, 0, symtab_off, symtab_size, 2, 1, 0, 0)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_attached() {
+        let options = |replace_existing, expected_program_id| XdpAttachOptions {
+            mode: XdpAttachMode::Native,
+            replace_existing,
+            expected_program_id,
+        };
+        assert_eq!(check_attached(None, &options(false, None)).unwrap(), None);
+        assert!(check_attached(Some(5), &options(false, None)).is_err());
+        // the expected id only matters when replacing
+        assert!(check_attached(Some(5), &options(false, Some(5))).is_err());
+        assert_eq!(check_attached(None, &options(true, None)).unwrap(), None);
+        assert_eq!(
+            check_attached(Some(5), &options(true, None)).unwrap(),
+            Some(5)
+        );
+        assert_eq!(
+            check_attached(Some(5), &options(true, Some(5))).unwrap(),
+            Some(5)
+        );
+        assert!(check_attached(Some(6), &options(true, Some(5))).is_err());
+        assert!(check_attached(None, &options(true, Some(5))).is_err());
+    }
+}
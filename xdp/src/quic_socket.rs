@@ -0,0 +1,340 @@
+//! [`quinn`] socket backed by AF_XDP.
+//!
+//! [`XdpQuicSocket`] implements [`AsyncUdpSocket`] so that a quinn endpoint can send and receive
+//! through the XDP datapath. Outgoing datagrams are queued on an [`XdpTransport`]; a transmit
+//! carrying several segments (GSO) is split into individual datagrams which all go to the same tx
+//! queue, so that the packets of a connection are never reordered. Incoming datagrams are fed to
+//! the socket through a [`QuicRxSender`] by whatever drives the rx queue, typically after parsing
+//! frames with [`RecvDatagram::from_frame`].
+//!
+//! ECN codepoints of received datagrams are reported to quinn. The tx loop doesn't set ECN bits on
+//! outgoing packets, so quinn's ECN validation fails and it falls back to loss based congestion
+//! control as it would on a socket without ECN support.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{transport::XdpTransport, udp_socket::RecvDatagram},
+    crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError},
+    quinn::{
+        udp::{EcnCodepoint, RecvMeta, Transmit},
+        AsyncUdpSocket, UdpPoller,
+    },
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        fmt,
+        hash::{DefaultHasher, Hash, Hasher},
+        io::{self, IoSliceMut},
+        net::SocketAddr,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll, Waker},
+    },
+};
+
+// Max number of datagrams in a single transmit. Matches the batch size quinn uses for sendmmsg.
+const MAX_TRANSMIT_SEGMENTS: usize = 10;
+
+#[derive(Debug, Default)]
+struct ReadWaker {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl ReadWaker {
+    fn register(&self, waker: &Waker) {
+        let mut current = self.waker.lock().unwrap();
+        if !current.as_ref().is_some_and(|w| w.will_wake(waker)) {
+            *current = Some(waker.clone());
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Feeds received datagrams to an [`XdpQuicSocket`].
+#[derive(Clone)]
+pub struct QuicRxSender {
+    sender: Sender<RecvDatagram>,
+    readable: Arc<ReadWaker>,
+}
+
+impl QuicRxSender {
+    /// Queues `datagram`, failing if the socket can't keep up or has been dropped.
+    pub fn try_send(&self, datagram: RecvDatagram) -> Result<(), TrySendError<RecvDatagram>> {
+        self.sender.try_send(datagram)?;
+        self.readable.wake();
+        Ok(())
+    }
+}
+
+/// A quinn socket sending and receiving through XDP.
+pub struct XdpQuicSocket {
+    local_addr: SocketAddr,
+    transport: XdpTransport,
+    receiver: Receiver<RecvDatagram>,
+    readable: Arc<ReadWaker>,
+}
+
+impl fmt::Debug for XdpQuicSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XdpQuicSocket")
+            .field("local_addr", &self.local_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl XdpQuicSocket {
+    /// Creates a socket sending through `transport` from `local_addr`.
+    ///
+    /// `local_addr` must match the source address and port the tx loops are configured with.
+    /// Received datagrams are queued through the returned [`QuicRxSender`], up to `rx_channel_cap`
+    /// of them.
+    pub fn new(
+        local_addr: SocketAddr,
+        transport: XdpTransport,
+        rx_channel_cap: usize,
+    ) -> (Self, QuicRxSender) {
+        let (sender, receiver) = crossbeam_channel::bounded(rx_channel_cap);
+        let readable = Arc::new(ReadWaker::default());
+        (
+            Self {
+                local_addr,
+                transport,
+                receiver,
+                readable: Arc::clone(&readable),
+            },
+            QuicRxSender { sender, readable },
+        )
+    }
+
+    // All the packets to a destination go through the same queue.
+    fn queue_for(&self, addr: &SocketAddr) -> usize {
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        hasher.finish() as usize % self.transport.queues()
+    }
+}
+
+impl AsyncUdpSocket for XdpQuicSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        Box::pin(XdpUdpPoller { socket: self })
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        // endpoints bound to a dual stack address use IPv4-mapped addresses
+        let destination = SocketAddr::new(
+            transmit.destination.ip().to_canonical(),
+            transmit.destination.port(),
+        );
+        if destination.is_ipv6() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "xdp only supports IPv4",
+            ));
+        }
+        let segment_size = transmit
+            .segment_size
+            .unwrap_or(transmit.contents.len())
+            .max(1);
+        let segments = transmit
+            .contents
+            .chunks(segment_size)
+            .map(Bytes::copy_from_slice);
+        self.transport
+            .send_all_to_queue(self.queue_for(&destination), segments, destination)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut count = 0;
+            for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()) {
+                let received = self.receiver.try_recv();
+                let datagram = match received {
+                    Ok(datagram) => datagram,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) if count == 0 => {
+                        return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                };
+                // like with a regular socket, datagrams that don't fit are truncated
+                let len = datagram.payload.len().min(buf.len());
+                buf[..len].copy_from_slice(&datagram.payload[..len]);
+                *meta = RecvMeta {
+                    addr: datagram.src,
+                    len,
+                    stride: len,
+                    ecn: EcnCodepoint::from_bits(datagram.ecn),
+                    dst_ip: datagram.dst_ip,
+                };
+                count += 1;
+            }
+            if count > 0 {
+                return Poll::Ready(Ok(count));
+            }
+
+            self.readable.register(cx.waker());
+            // a datagram might have been queued while we were registering
+            if self.receiver.is_empty() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        MAX_TRANSMIT_SEGMENTS
+    }
+
+    fn may_fragment(&self) -> bool {
+        // packets are handed to the NIC as built, nothing on our side fragments them
+        false
+    }
+}
+
+#[derive(Debug)]
+struct XdpUdpPoller {
+    socket: Arc<XdpQuicSocket>,
+}
+
+impl UdpPoller for XdpUdpPoller {
+    fn poll_writable(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.socket.transport.poll_writable(cx).map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::packet::{
+            write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE, IP_HEADER_SIZE,
+            UDP_HEADER_SIZE,
+        },
+        std::{
+            net::{IpAddr, Ipv4Addr},
+            sync::atomic::{AtomicBool, Ordering},
+            task::Wake,
+        },
+    };
+
+    #[derive(Default)]
+    struct FlagWaker(AtomicBool);
+
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_try_send_splits_segments() {
+        let (transport, receivers) = XdpTransport::new(2, 4);
+        let (socket, _rx) = XdpQuicSocket::new("10.0.0.1:8009".parse().unwrap(), transport, 4);
+        let destination: SocketAddr = "10.0.0.2:8009".parse().unwrap();
+        let contents = [1u8; 25];
+        let transmit = Transmit {
+            // IPv4-mapped destinations are sent as IPv4
+            destination: "[::ffff:10.0.0.2]:8009".parse().unwrap(),
+            ecn: None,
+            contents: &contents,
+            segment_size: Some(10),
+            src_ip: None,
+        };
+        socket.try_send(&transmit).unwrap();
+
+        let queue = &receivers[socket.queue_for(&destination)];
+        let lens = queue
+            .try_iter()
+            .map(|(addrs, payload)| {
+                assert_eq!(addrs, vec![destination]);
+                payload.len()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lens, vec![10, 10, 5]);
+
+        // a transmit that doesn't fit entirely isn't sent at all
+        let contents = [2u8; 50];
+        let transmit = Transmit {
+            destination,
+            ecn: None,
+            contents: &contents,
+            segment_size: Some(10),
+            src_ip: None,
+        };
+        assert_eq!(
+            socket.try_send(&transmit).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert!(queue.is_empty());
+
+        let transmit = Transmit {
+            destination: "[2001:db8::1]:8009".parse().unwrap(),
+            ..transmit
+        };
+        assert_eq!(
+            socket.try_send(&transmit).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_poll_recv() {
+        let (transport, _receivers) = XdpTransport::new(1, 4);
+        let (socket, rx) = XdpQuicSocket::new("10.0.0.1:8009".parse().unwrap(), transport, 4);
+
+        let flag = Arc::new(FlagWaker::default());
+        let waker = Waker::from(Arc::clone(&flag));
+        let mut cx = Context::from_waker(&waker);
+        let mut storage = [[0u8; 16]; 2];
+        let mut meta = [RecvMeta::default(); 2];
+
+        {
+            let mut bufs = storage.each_mut().map(|b| IoSliceMut::new(b));
+            assert!(socket.poll_recv(&mut cx, &mut bufs, &mut meta).is_pending());
+        }
+
+        let src_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let dst_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let payload = [7u8; 20];
+        let mut frame = vec![0u8; ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + 20];
+        frame[ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE..].copy_from_slice(&payload);
+        write_eth_header(&mut frame, &[1; 6], &[2; 6]);
+        write_ip_header(&mut frame[ETH_HEADER_SIZE..], &src_ip, &dst_ip, 28);
+        write_udp_header(
+            &mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &src_ip,
+            8000,
+            &dst_ip,
+            8009,
+            20,
+            true,
+        );
+        rx.try_send(RecvDatagram::from_frame(&frame, true).unwrap())
+            .unwrap();
+        assert!(flag.0.load(Ordering::Relaxed));
+
+        let mut bufs = storage.each_mut().map(|b| IoSliceMut::new(b));
+        let Poll::Ready(Ok(count)) = socket.poll_recv(&mut cx, &mut bufs, &mut meta) else {
+            panic!("expected a datagram");
+        };
+        assert_eq!(count, 1);
+        // truncated to the size of the buffer
+        assert_eq!(meta[0].len, 16);
+        assert_eq!(meta[0].addr, "10.0.0.2:8000".parse().unwrap());
+        assert_eq!(meta[0].dst_ip, Some(IpAddr::V4(dst_ip)));
+        assert_eq!(meta[0].ecn, None);
+        assert_eq!(&*bufs[0], &[7u8; 16]);
+    }
+}
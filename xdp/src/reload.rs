@@ -0,0 +1,239 @@
+//! Reloading the tuning configuration while the node runs.
+//!
+//! Finding the right pacing and CPU layout takes a few tries, and restarting a validator for each
+//! one costs it its place in the cluster for a while. A [`TuningReloader`] rereads the
+//! [`TuningConfig`] it was created from and applies what can change live: the limits of the
+//! traffic classes, and the CPUs of the thread roles, moving the threads already running. The
+//! other settings, like the queues and their CPUs, are reported as pending a restart.
+//!
+//! Only the pacing limits are reloaded for the traffic classes: the tx loops have no weights
+//! between the classes, they send the priority channel first and the rest in arrival order.
+
+use {
+    crate::{
+        config::{ConfigError, TuningConfig, GLOBAL_PACING},
+        shaping::TrafficShaper,
+    },
+    agave_cpu_utils::{reload_cpu_profile, RepinnedThread},
+    std::{
+        collections::BTreeSet,
+        fmt,
+        path::PathBuf,
+        sync::{Mutex, OnceLock},
+    },
+};
+
+static TUNING_RELOADER: OnceLock<TuningReloader> = OnceLock::new();
+
+/// What [`TuningReloader::reload`] changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReloadReport {
+    /// The pacing keys whose limit was changed, added or removed.
+    pub pacing: Vec<String>,
+    /// The threads moved to the new CPUs of their role.
+    pub repinned: Vec<RepinnedThread>,
+    /// The settings that changed but only take effect on restart.
+    pub pending: Vec<&'static str>,
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pacing changed: {:?}", self.pacing)?;
+        for thread in &self.repinned {
+            write!(
+                f,
+                "; {} {} ({}) from cpus {:?} to {:?}",
+                thread.role, thread.name, thread.tid, thread.from, thread.to
+            )?;
+            if let Some(error) = &thread.error {
+                write!(f, " failed: {error}")?;
+            }
+        }
+        write!(f, "; pending restart: {:?}", self.pending)
+    }
+}
+
+/// Applies the changes to a tuning configuration file, see the [module documentation](self).
+pub struct TuningReloader {
+    path: PathBuf,
+    shaper: Option<TrafficShaper>,
+    current: Mutex<TuningConfig>,
+}
+
+impl TuningReloader {
+    /// Reads the configuration at `path` and sets the limits of `shaper`, if any, to its pacing.
+    ///
+    /// The CPU profile of the configuration is the caller's to install.
+    pub fn new(
+        path: impl Into<PathBuf>,
+        shaper: Option<TrafficShaper>,
+    ) -> Result<Self, ConfigError> {
+        let path = path.into();
+        let config = TuningConfig::load(&path)?;
+        Self::with_config(path, config, shaper)
+    }
+
+    fn with_config(
+        path: PathBuf,
+        config: TuningConfig,
+        shaper: Option<TrafficShaper>,
+    ) -> Result<Self, ConfigError> {
+        if let Some(shaper) = &shaper {
+            config.xdp.apply_pacing(shaper)?;
+        }
+        Ok(Self {
+            path,
+            shaper,
+            current: Mutex::new(config),
+        })
+    }
+
+    /// Returns the configuration last applied.
+    pub fn config(&self) -> TuningConfig {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Reads the configuration again and applies what changed.
+    ///
+    /// Nothing is changed if the new configuration is invalid. The pacing is pending a restart
+    /// when the reloader has no shaper.
+    pub fn reload(&self) -> Result<ReloadReport, ConfigError> {
+        self.apply(TuningConfig::load(&self.path)?)
+    }
+
+    fn apply(&self, config: TuningConfig) -> Result<ReloadReport, ConfigError> {
+        let mut current = self.current.lock().unwrap();
+        let mut report = ReloadReport::default();
+
+        let (old, new) = (&current.xdp, &config.xdp);
+        for (name, changed) in [
+            ("xdp.interface", old.interface != new.interface),
+            ("xdp.rx_queues", old.rx_queues != new.rx_queues),
+            ("xdp.tx_queues", old.tx_queues != new.tx_queues),
+            ("xdp.cpus", old.cpus != new.cpus),
+            ("xdp.rx_ports", old.rx_ports != new.rx_ports),
+            ("xdp.rx_ring_size", old.rx_ring_size != new.rx_ring_size),
+            ("xdp.tx_ring_size", old.tx_ring_size != new.tx_ring_size),
+            ("xdp.zero_copy", old.zero_copy != new.zero_copy),
+            ("cpu.isolated", current.cpu.isolated != config.cpu.isolated),
+        ] {
+            if changed {
+                report.pending.push(name);
+            }
+        }
+
+        let labels = old.pacing.keys().chain(new.pacing.keys());
+        let changed = labels
+            .filter(|label| old.pacing.get(*label) != new.pacing.get(*label))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        if !changed.is_empty() {
+            match &self.shaper {
+                Some(shaper) => {
+                    new.apply_pacing(shaper)?;
+                    for label in changed
+                        .iter()
+                        .filter(|label| !new.pacing.contains_key(*label))
+                    {
+                        clear_pacing(shaper, label);
+                    }
+                    report.pacing = changed.into_iter().collect();
+                }
+                None => report.pending.push("xdp.pacing"),
+            }
+        }
+
+        if current.cpu.profile != config.cpu.profile {
+            report.repinned = reload_cpu_profile(config.cpu_profile()?);
+        }
+
+        *current = config;
+        Ok(report)
+    }
+}
+
+fn clear_pacing(shaper: &TrafficShaper, label: &str) {
+    if label == GLOBAL_PACING {
+        shaper.set_global_limit(None);
+    } else if let Some(class) = shaper
+        .classes()
+        .into_iter()
+        .find(|class| shaper.label(*class) == label)
+    {
+        shaper.set_limit(class, None);
+    }
+}
+
+/// Makes `reloader` the process wide one, which the admin interface reloads.
+///
+/// A reloader can only be installed once, installing another one returns it back.
+pub fn install_tuning_reloader(reloader: TuningReloader) -> Result<(), TuningReloader> {
+    TUNING_RELOADER.set(reloader)
+}
+
+/// Returns the reloader installed with [`install_tuning_reloader`].
+pub fn tuning_reloader() -> Option<&'static TuningReloader> {
+    TUNING_RELOADER.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::shaping::{RateLimit, TrafficClass},
+    };
+
+    #[test]
+    fn test_tuning_reloader() {
+        let config = TuningConfig::from_toml(
+            "[xdp.pacing]\nglobal = { packets_per_second = 1000, burst = 10 }\nrepair = { \
+             packets_per_second = 100, burst = 1 }\n",
+        )
+        .unwrap();
+        let shaper = TrafficShaper::new();
+        let reloader =
+            TuningReloader::with_config(PathBuf::new(), config.clone(), Some(shaper.clone()))
+                .unwrap();
+        assert_eq!(shaper.global_limit().unwrap().packets_per_second, 1000);
+
+        // unchanged
+        assert_eq!(reloader.apply(config).unwrap(), ReloadReport::default());
+
+        let config = TuningConfig::from_toml(
+            "[xdp]\nzero_copy = true\n[xdp.pacing]\nrepair = { packets_per_second = 200, burst = \
+             2 }\n",
+        )
+        .unwrap();
+        let report = reloader.apply(config).unwrap();
+        assert_eq!(report.pacing, ["global", "repair"]);
+        assert_eq!(report.pending, ["xdp.zero_copy"]);
+        assert!(report.repinned.is_empty());
+        assert_eq!(shaper.global_limit(), None);
+        assert_eq!(
+            shaper.limit(TrafficClass::Repair),
+            Some(RateLimit {
+                packets_per_second: 200,
+                burst: 2
+            })
+        );
+        assert!(reloader.config().xdp.zero_copy);
+
+        // without a shaper the pacing waits for a restart
+        let reloader =
+            TuningReloader::with_config(PathBuf::new(), TuningConfig::default(), None).unwrap();
+        let report = reloader.apply(reloader.config()).unwrap();
+        assert!(report.pending.is_empty());
+        let mut config = reloader.config();
+        config.xdp.pacing.insert(
+            "vote".to_string(),
+            RateLimit {
+                packets_per_second: 10,
+                burst: 1,
+            },
+        );
+        assert_eq!(reloader.apply(config).unwrap().pending, ["xdp.pacing"]);
+        // a file that can't be read changes nothing
+        assert!(reloader.reload().is_err());
+        assert_eq!(reloader.config().xdp.pacing.len(), 1);
+    }
+}
@@ -0,0 +1,425 @@
+//! Repair request/response traffic over XDP.
+//!
+//! Repair is bursty: when a node falls behind it fires off thousands of requests at once, and the
+//! peers serving them answer with full size shreds. [`RepairSender`] pushes both requests and
+//! responses through a [`DatagramTransport`], pacing each peer so that a storm towards a single
+//! node doesn't flood it. [`RepairReceiver`] drives an XDP rx queue and splits the received
+//! packets between the repair (responses) and serve repair (requests) ports.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        packet::parse_udp_frame,
+        rx_batch::{refill, RxBatchBuilder, RxBatchStats, SharedUmemMemory},
+        socket::Rx,
+        transport::DatagramTransport,
+        umem::Umem,
+    },
+    solana_perf::packet::{bytes::Bytes, PacketBatch, PacketRef, PACKET_DATA_SIZE},
+    std::{
+        collections::HashMap,
+        io,
+        net::{IpAddr, SocketAddr},
+        slice,
+        sync::Arc,
+        time::Instant,
+    },
+};
+
+// Peers are forgotten when we track more than this many and they've been idle for a while.
+const MAX_TRACKED_PEERS: usize = 8192;
+
+/// Per-peer pacing applied by [`RepairSender`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepairPacingConfig {
+    /// Sustained packets per second to a single peer.
+    pub packets_per_second: u64,
+    /// Packets that can be sent to a single peer back to back.
+    pub burst: u64,
+}
+
+impl Default for RepairPacingConfig {
+    fn default() -> Self {
+        Self {
+            packets_per_second: 10_000,
+            burst: 512,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairSendStats {
+    pub sent: u64,
+    /// Packets dropped because the peer is over its pacing budget.
+    pub throttled: u64,
+    /// Packets dropped because the payload is larger than `PACKET_DATA_SIZE`.
+    pub oversized: u64,
+    /// Packets the transport failed to send.
+    pub errors: u64,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_update: Instant,
+}
+
+struct PeerPacer {
+    config: RepairPacingConfig,
+    peers: HashMap<IpAddr, TokenBucket>,
+}
+
+impl PeerPacer {
+    fn new(config: RepairPacingConfig) -> Self {
+        Self {
+            config,
+            peers: HashMap::new(),
+        }
+    }
+
+    fn try_acquire(&mut self, peer: IpAddr, now: Instant) -> bool {
+        let RepairPacingConfig {
+            packets_per_second,
+            burst,
+        } = self.config;
+        let burst = burst.max(1) as f64;
+
+        if self.peers.len() >= MAX_TRACKED_PEERS && !self.peers.contains_key(&peer) {
+            // peers that have been idle long enough to refill their bucket don't need tracking
+            self.peers.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.last_update);
+                bucket.tokens + elapsed.as_secs_f64() * (packets_per_second as f64) < burst
+            });
+        }
+
+        let bucket = self.peers.entry(peer).or_insert(TokenBucket {
+            tokens: burst,
+            last_update: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last_update);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * packets_per_second as f64).min(burst);
+        bucket.last_update = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Sends repair requests and responses with per-peer pacing.
+pub struct RepairSender<T> {
+    transport: T,
+    pacer: PeerPacer,
+    stats: RepairSendStats,
+}
+
+impl<T: DatagramTransport> RepairSender<T> {
+    pub fn new(transport: T, pacing: RepairPacingConfig) -> Self {
+        Self {
+            transport,
+            pacer: PeerPacer::new(pacing),
+            stats: RepairSendStats::default(),
+        }
+    }
+
+    /// Sends `payload` to `addr` if the peer is within its pacing budget.
+    pub fn send(&mut self, payload: Bytes, addr: SocketAddr) -> io::Result<()> {
+        if payload.len() > PACKET_DATA_SIZE {
+            self.stats.oversized += 1;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "repair payload larger than PACKET_DATA_SIZE",
+            ));
+        }
+        if !self.pacer.try_acquire(addr.ip(), Instant::now()) {
+            self.stats.throttled += 1;
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        match self.transport.send_to(payload, &[addr]) {
+            Ok(()) => {
+                self.stats.sent += 1;
+                Ok(())
+            }
+            Err(e) => {
+                self.stats.errors += 1;
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends every packet in `batch` to the address in its meta.
+    ///
+    /// This is the XDP counterpart of the repair responder. Failures are reflected in the stats.
+    pub fn send_batch(&mut self, batch: &PacketBatch) {
+        for packet in batch.iter() {
+            let Some(data) = packet.data(..) else {
+                continue;
+            };
+            let payload = match packet {
+                PacketRef::Bytes(packet) => packet.buffer().slice(..data.len()),
+                PacketRef::Packet(_) => Bytes::copy_from_slice(data),
+            };
+            let _ = self.send(payload, packet.meta().socket_addr());
+        }
+    }
+
+    /// Returns the stats accumulated since the last call.
+    pub fn take_stats(&mut self) -> RepairSendStats {
+        std::mem::take(&mut self.stats)
+    }
+}
+
+/// Packets received by [`RepairReceiver::poll`].
+#[derive(Default)]
+pub struct RepairBatches {
+    /// Packets sent to the repair port, ie responses to our requests.
+    pub responses: Option<PacketBatch>,
+    /// Packets sent to the serve repair port, ie requests from other nodes.
+    pub requests: Option<PacketBatch>,
+}
+
+/// Receives repair traffic from an XDP rx queue.
+pub struct RepairReceiver {
+    memory: Arc<SharedUmemMemory>,
+    repair_port: u16,
+    serve_repair_port: u16,
+    responses: RxBatchBuilder,
+    requests: RxBatchBuilder,
+    other_port: u64,
+}
+
+impl RepairReceiver {
+    /// Creates a new receiver. `memory` must back the umem of the socket passed to
+    /// [`poll`](Self::poll).
+    ///
+    /// `max_outstanding` bounds the number of frames referenced by packets, see
+    /// [`RxBatchBuilder::new`].
+    pub fn new(
+        memory: Arc<SharedUmemMemory>,
+        repair_port: u16,
+        serve_repair_port: u16,
+        max_outstanding: usize,
+    ) -> Self {
+        // responses are shreds which need to be held for longer
+        let requests_outstanding = max_outstanding / 4;
+        Self {
+            responses: RxBatchBuilder::new(
+                Arc::clone(&memory),
+                max_outstanding - requests_outstanding,
+                true,
+            ),
+            requests: RxBatchBuilder::new(Arc::clone(&memory), requests_outstanding, true),
+            memory,
+            repair_port,
+            serve_repair_port,
+            other_port: 0,
+        }
+    }
+
+    /// Refills the fill ring and returns the packets received since the last call.
+    pub fn poll<U: Umem>(&mut self, rx: &mut Rx<U::Frame>, umem: &mut U) -> RepairBatches {
+        self.responses.recycle(|offset| umem.release(offset));
+        self.requests.recycle(|offset| umem.release(offset));
+
+        refill(&mut rx.fill, umem, usize::MAX);
+
+        let Some(ring) = rx.ring.as_mut() else {
+            return RepairBatches::default();
+        };
+        ring.sync(true);
+        while let Some((offset, len)) = ring.read() {
+            assert!(offset.0 + len <= umem.len());
+            // Safety: the frame is within the umem and owned by us until it's released
+            let frame = unsafe { slice::from_raw_parts(self.memory.as_ptr().add(offset.0), len) };
+            let dst_port = parse_udp_frame(frame, false).map(|udp| udp.dst_port);
+            let builder = match dst_port {
+                Ok(port) if port == self.repair_port => &mut self.responses,
+                Ok(port) if port == self.serve_repair_port => &mut self.requests,
+                Ok(_) => {
+                    self.other_port += 1;
+                    umem.release(offset);
+                    continue;
+                }
+                // the builder counts and recycles invalid frames
+                Err(_) => &mut self.responses,
+            };
+            let _ = builder.push(offset, len);
+        }
+        ring.commit();
+
+        RepairBatches {
+            responses: self.responses.take_batch(),
+            requests: self.requests.take_batch(),
+        }
+    }
+
+    /// Returns the number of frames received for ports other than the repair ones.
+    pub fn other_port(&self) -> u64 {
+        self.other_port
+    }
+
+    /// Returns the rx stats of (responses, requests).
+    pub fn stats(&self) -> (&RxBatchStats, &RxBatchStats) {
+        (self.responses.stats(), self.requests.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            packet::{
+                write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE,
+                IP_HEADER_SIZE, UDP_HEADER_SIZE,
+            },
+            sim::{veth_pair, SimSocket},
+            umem::{PageAlignedMemory, SliceUmem},
+        },
+        std::{net::Ipv4Addr, sync::Mutex, time::Duration},
+    };
+
+    #[derive(Default)]
+    struct RecordingTransport {
+        sends: Mutex<Vec<(Bytes, Vec<SocketAddr>)>>,
+    }
+
+    impl DatagramTransport for Arc<RecordingTransport> {
+        fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+            self.sends.lock().unwrap().push((payload, addrs.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_per_peer_pacing() {
+        let mut pacer = PeerPacer::new(RepairPacingConfig {
+            packets_per_second: 1000,
+            burst: 3,
+        });
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(pacer.try_acquire(a, now));
+        }
+        assert!(!pacer.try_acquire(a, now));
+        // other peers have their own budget
+        assert!(pacer.try_acquire(b, now));
+        // one packet every millisecond
+        assert!(pacer.try_acquire(a, now + Duration::from_millis(1)));
+        assert!(!pacer.try_acquire(a, now + Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_repair_sender() {
+        let transport = Arc::new(RecordingTransport::default());
+        let mut sender = RepairSender::new(
+            Arc::clone(&transport),
+            RepairPacingConfig {
+                packets_per_second: 1,
+                burst: 2,
+            },
+        );
+        let addr: SocketAddr = "10.0.0.1:8008".parse().unwrap();
+        let response = Bytes::from(vec![1u8; PACKET_DATA_SIZE]);
+        sender.send(response.clone(), addr).unwrap();
+        sender.send(response.clone(), addr).unwrap();
+        assert_eq!(
+            sender.send(response, addr).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            sender
+                .send(Bytes::from(vec![0u8; PACKET_DATA_SIZE + 1]), addr)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            sender.take_stats(),
+            RepairSendStats {
+                sent: 2,
+                throttled: 1,
+                oversized: 1,
+                errors: 0,
+            }
+        );
+        assert_eq!(transport.sends.lock().unwrap().len(), 2);
+    }
+
+    fn udp_frame(dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let src_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let dst_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let len = payload.len();
+        let mut frame = vec![0u8; ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + len];
+        frame[ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE..].copy_from_slice(payload);
+        write_eth_header(&mut frame, &[1; 6], &[2; 6]);
+        write_ip_header(
+            &mut frame[ETH_HEADER_SIZE..],
+            &src_ip,
+            &dst_ip,
+            (UDP_HEADER_SIZE + len) as u16,
+        );
+        write_udp_header(
+            &mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &src_ip,
+            8000,
+            &dst_ip,
+            dst_port,
+            len as u16,
+            true,
+        );
+        frame
+    }
+
+    #[test]
+    fn test_repair_receiver() {
+        const FRAME_SIZE: usize = 2048;
+        const REPAIR_PORT: u16 = 9001;
+        const SERVE_REPAIR_PORT: u16 = 9002;
+
+        let memory = SharedUmemMemory::new(PageAlignedMemory::alloc(FRAME_SIZE, 16).unwrap());
+        // Safety: frames are only reused once the receiver recycles them
+        let umem = SliceUmem::new(unsafe { memory.as_mut_slice() }, FRAME_SIZE as u32).unwrap();
+        let (endpoint, peer) = veth_pair();
+        let (mut socket, mut rx) = SimSocket::rx(umem, endpoint, 8, 8).unwrap();
+        let mut receiver =
+            RepairReceiver::new(Arc::clone(&memory), REPAIR_PORT, SERVE_REPAIR_PORT, 8);
+
+        // populate the fill ring
+        receiver.poll(&mut rx, socket.umem());
+
+        peer.send(udp_frame(REPAIR_PORT, &[1; PACKET_DATA_SIZE]));
+        peer.send(udp_frame(SERVE_REPAIR_PORT, &[2; 160]));
+        peer.send(udp_frame(1234, &[3; 10]));
+
+        let start = Instant::now();
+        let mut responses = vec![];
+        let mut requests = vec![];
+        while responses.len() + requests.len() < 2 && start.elapsed() < Duration::from_secs(5) {
+            let batches = receiver.poll(&mut rx, socket.umem());
+            responses.extend(batches.responses);
+            requests.extend(batches.requests);
+        }
+
+        let response = responses[0].get(0).unwrap();
+        assert_eq!(response.data(..).unwrap(), &[1; PACKET_DATA_SIZE]);
+        let request = requests[0].get(0).unwrap();
+        assert_eq!(request.data(..).unwrap(), &[2; 160]);
+        assert_eq!(
+            request.meta().socket_addr(),
+            "10.0.0.1:8000".parse().unwrap()
+        );
+
+        // wait for the frame to the other port
+        while receiver.other_port() == 0 && start.elapsed() < Duration::from_secs(5) {
+            receiver.poll(&mut rx, socket.umem());
+        }
+        assert_eq!(receiver.other_port(), 1);
+        assert_eq!(receiver.stats().0.zero_copy, 1);
+        assert_eq!(receiver.stats().1.zero_copy, 1);
+    }
+}
@@ -0,0 +1,274 @@
+//! Replays UDP traffic from a pcap capture through the tx loop.
+//!
+//! Frames are parsed back into `(destination, payload)` pairs and queued on the same channel a
+//! [`tx_loop`](crate::tx_loop::tx_loop) consumes from, so replayed traffic goes through exactly the
+//! same packet building and ring management as production traffic. Headers are rebuilt by the tx
+//! loop, so only the destination address and the UDP payload of captured frames are used.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        packet::{parse_udp_frame, ParseError},
+        pcap::PcapReader,
+    },
+    crossbeam_channel::Sender,
+    std::{
+        collections::HashMap,
+        io::{self, Read},
+        net::SocketAddr,
+        thread,
+        time::{Duration, Instant, SystemTime},
+    },
+};
+
+/// How fast packets are replayed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReplayPacing {
+    /// Queue packets as fast as the tx loop accepts them.
+    #[default]
+    Unlimited,
+    /// Reproduce the inter-packet gaps of the capture, divided by `speedup`.
+    Original { speedup: f64 },
+    /// Send at a fixed packet rate.
+    Rate { packets_per_second: u64 },
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ReplayConfig {
+    pub pacing: ReplayPacing,
+    /// Per-destination rewrites, applied first.
+    pub rewrite: HashMap<SocketAddr, SocketAddr>,
+    /// Destination for all packets that don't match `rewrite`. If not set, packets are sent to
+    /// their original destination.
+    pub destination: Option<SocketAddr>,
+}
+
+impl ReplayConfig {
+    fn destination_for(&self, original: SocketAddr) -> SocketAddr {
+        self.rewrite
+            .get(&original)
+            .copied()
+            .or(self.destination)
+            .unwrap_or(original)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// Packets queued for transmission.
+    pub sent: u64,
+    /// Frames skipped because they're not valid IPv4/UDP.
+    pub skipped_non_udp: u64,
+    /// Frames skipped because the payload was truncated at capture time.
+    pub skipped_truncated: u64,
+}
+
+/// Replays all the UDP packets in `reader`, queueing them on `sender`.
+///
+/// `sender` is typically the sending half of the channel passed to
+/// [`tx_loop`](crate::tx_loop::tx_loop). Stops early if the receiving side is disconnected.
+pub fn replay<R: Read>(
+    reader: PcapReader<R>,
+    sender: &Sender<([SocketAddr; 1], Vec<u8>)>,
+    config: &ReplayConfig,
+) -> io::Result<ReplayStats> {
+    let mut stats = ReplayStats::default();
+    let mut pacer = Pacer::new(config.pacing);
+
+    for record in reader {
+        let record = record?;
+        let udp = match parse_udp_frame(&record.data, false) {
+            Ok(udp) => udp,
+            Err(ParseError::Truncated) => {
+                stats.skipped_truncated += 1;
+                continue;
+            }
+            Err(_) => {
+                stats.skipped_non_udp += 1;
+                continue;
+            }
+        };
+
+        pacer.wait(record.timestamp);
+
+        let dest = config.destination_for(udp.dst_addr());
+        if sender.send(([dest], udp.payload.to_vec())).is_err() {
+            log::warn!("tx loop exited, stopping replay");
+            break;
+        }
+        stats.sent += 1;
+    }
+
+    Ok(stats)
+}
+
+struct Pacer {
+    pacing: ReplayPacing,
+    start: Instant,
+    first_timestamp: Option<SystemTime>,
+    packets: u64,
+}
+
+impl Pacer {
+    fn new(pacing: ReplayPacing) -> Self {
+        Self {
+            pacing,
+            start: Instant::now(),
+            first_timestamp: None,
+            packets: 0,
+        }
+    }
+
+    fn wait(&mut self, timestamp: SystemTime) {
+        if let Some(deadline) = self.deadline(timestamp) {
+            let now = Instant::now();
+            if deadline > now {
+                thread::sleep(deadline - now);
+            }
+        }
+        self.packets += 1;
+    }
+
+    // Returns when the next packet should be sent relative to the start of the replay.
+    fn deadline(&mut self, timestamp: SystemTime) -> Option<Instant> {
+        match self.pacing {
+            ReplayPacing::Unlimited => None,
+            ReplayPacing::Original { speedup } => {
+                let first = *self.first_timestamp.get_or_insert(timestamp);
+                let offset = timestamp.duration_since(first).unwrap_or_default();
+                Some(self.start + offset.div_f64(speedup.max(f64::MIN_POSITIVE)))
+            }
+            ReplayPacing::Rate { packets_per_second } => {
+                let nanos =
+                    self.packets as u128 * 1_000_000_000 / packets_per_second.max(1) as u128;
+                Some(self.start + Duration::from_nanos(nanos as u64))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            packet::{
+                write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE,
+                IP_HEADER_SIZE, UDP_HEADER_SIZE,
+            },
+            pcap::PcapWriter,
+        },
+        std::{
+            net::{Ipv4Addr, SocketAddrV4},
+            time::UNIX_EPOCH,
+        },
+    };
+
+    fn udp_frame(dst: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        let src_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let len = payload.len();
+        let mut frame = vec![0u8; ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + len];
+        frame[ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE..].copy_from_slice(payload);
+        write_eth_header(&mut frame, &[1; 6], &[2; 6]);
+        write_ip_header(
+            &mut frame[ETH_HEADER_SIZE..],
+            &src_ip,
+            dst.ip(),
+            (UDP_HEADER_SIZE + len) as u16,
+        );
+        write_udp_header(
+            &mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &src_ip,
+            1234,
+            dst.ip(),
+            dst.port(),
+            len as u16,
+            false,
+        );
+        frame
+    }
+
+    #[test]
+    fn test_replay() {
+        let a = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8001);
+        let b = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 8002);
+        let rewritten: SocketAddr = "192.168.0.1:9000".parse().unwrap();
+
+        let mut writer = PcapWriter::new(Vec::new(), 64).unwrap();
+        writer
+            .write_frame(UNIX_EPOCH, &udp_frame(a, &[1; 10]))
+            .unwrap();
+        // not UDP
+        writer.write_frame(UNIX_EPOCH, &[0u8; 60]).unwrap();
+        writer
+            .write_frame(UNIX_EPOCH, &udp_frame(b, &[2; 10]))
+            .unwrap();
+        // truncated by the snaplen
+        writer
+            .write_frame(UNIX_EPOCH, &udp_frame(b, &[3; 100]))
+            .unwrap();
+        let buf = writer.into_inner();
+
+        let config = ReplayConfig {
+            rewrite: HashMap::from([(SocketAddr::V4(a), rewritten)]),
+            ..ReplayConfig::default()
+        };
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let stats = replay(PcapReader::new(&buf[..]).unwrap(), &sender, &config).unwrap();
+
+        assert_eq!(
+            stats,
+            ReplayStats {
+                sent: 2,
+                skipped_non_udp: 1,
+                skipped_truncated: 1,
+            }
+        );
+        assert_eq!(receiver.try_recv().unwrap(), ([rewritten], vec![1; 10]));
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            ([SocketAddr::V4(b)], vec![2; 10])
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_destination_override() {
+        let original: SocketAddr = "10.0.0.2:8001".parse().unwrap();
+        let rewritten: SocketAddr = "10.0.0.3:8001".parse().unwrap();
+        let fallback: SocketAddr = "10.0.0.4:8001".parse().unwrap();
+        let config = ReplayConfig {
+            rewrite: HashMap::from([(original, rewritten)]),
+            destination: Some(fallback),
+            ..ReplayConfig::default()
+        };
+        assert_eq!(config.destination_for(original), rewritten);
+        assert_eq!(config.destination_for(rewritten), fallback);
+    }
+
+    #[test]
+    fn test_pacer_deadlines() {
+        let mut pacer = Pacer::new(ReplayPacing::Rate {
+            packets_per_second: 1000,
+        });
+        let start = pacer.start;
+        assert_eq!(pacer.deadline(UNIX_EPOCH), Some(start));
+        pacer.packets = 10;
+        assert_eq!(
+            pacer.deadline(UNIX_EPOCH),
+            Some(start + Duration::from_millis(10))
+        );
+
+        let mut pacer = Pacer::new(ReplayPacing::Original { speedup: 2.0 });
+        let start = pacer.start;
+        let ts = UNIX_EPOCH + Duration::from_secs(100);
+        assert_eq!(pacer.deadline(ts), Some(start));
+        assert_eq!(
+            pacer.deadline(ts + Duration::from_millis(10)),
+            Some(start + Duration::from_millis(5))
+        );
+
+        let mut pacer = Pacer::new(ReplayPacing::Unlimited);
+        assert_eq!(pacer.deadline(UNIX_EPOCH), None);
+    }
+}
@@ -0,0 +1,280 @@
+//! Rolling the XDP transport out gradually.
+//!
+//! A [`RolloutTransport`] sends a percentage of the eligible traffic through XDP and the rest
+//! through a fallback, usually a UDP socket. Only IPv4 destinations are eligible since the tx loop
+//! doesn't do IPv6. Each destination sticks to one path so that its packets aren't reordered, and
+//! raising the percentage only moves destinations from the fallback to XDP.
+//!
+//! Operators keep a [`RolloutControl`] to change the percentage while the transport is running
+//! and to compare how each path is doing.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::transport::DatagramTransport,
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        hash::{DefaultHasher, Hash, Hasher},
+        io,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU64, AtomicU8, Ordering},
+            Arc,
+        },
+    },
+};
+
+/// Which transport a destination is sent through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RolloutPath {
+    Xdp,
+    Fallback,
+}
+
+#[derive(Default)]
+struct PathCounters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl PathCounters {
+    fn record(&self, packets: usize, result: &io::Result<()>) {
+        let counter = match result {
+            Ok(()) => &self.sent,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => &self.dropped,
+            Err(_) => &self.failed,
+        };
+        counter.fetch_add(packets as u64, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> PathStats {
+        PathStats {
+            sent: self.sent.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The packets handed to one path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PathStats {
+    /// Packets the transport accepted.
+    pub sent: u64,
+    /// Packets of sends the transport couldn't keep up with.
+    pub dropped: u64,
+    /// Packets of sends that failed otherwise.
+    pub failed: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RolloutStats {
+    pub xdp: PathStats,
+    pub fallback: PathStats,
+}
+
+struct RolloutInner {
+    xdp_percent: AtomicU8,
+    seed: u64,
+    xdp: PathCounters,
+    fallback: PathCounters,
+}
+
+/// Sets the share of the traffic sent through XDP and reports how each path is doing.
+///
+/// Cloning is cheap, the clones control the same transport.
+#[derive(Clone)]
+pub struct RolloutControl {
+    inner: Arc<RolloutInner>,
+}
+
+impl RolloutControl {
+    /// Creates a control sending `xdp_percent` of the eligible destinations through XDP.
+    /// `seed` changes which destinations those are.
+    pub fn new(xdp_percent: u8, seed: u64) -> Self {
+        Self {
+            inner: Arc::new(RolloutInner {
+                xdp_percent: AtomicU8::new(xdp_percent.min(100)),
+                seed,
+                xdp: PathCounters::default(),
+                fallback: PathCounters::default(),
+            }),
+        }
+    }
+
+    /// Sets the percentage of the eligible destinations sent through XDP, capped at 100.
+    pub fn set_xdp_percent(&self, percent: u8) {
+        self.inner
+            .xdp_percent
+            .store(percent.min(100), Ordering::Relaxed);
+    }
+
+    pub fn xdp_percent(&self) -> u8 {
+        self.inner.xdp_percent.load(Ordering::Relaxed)
+    }
+
+    /// Returns the path traffic to `addr` is currently sent through.
+    pub fn path_for(&self, addr: &SocketAddr) -> RolloutPath {
+        if !addr.is_ipv4() {
+            return RolloutPath::Fallback;
+        }
+        let mut hasher = DefaultHasher::new();
+        self.inner.seed.hash(&mut hasher);
+        addr.hash(&mut hasher);
+        if hasher.finish() % 100 < u64::from(self.xdp_percent()) {
+            RolloutPath::Xdp
+        } else {
+            RolloutPath::Fallback
+        }
+    }
+
+    pub fn stats(&self) -> RolloutStats {
+        RolloutStats {
+            xdp: self.inner.xdp.load(),
+            fallback: self.inner.fallback.load(),
+        }
+    }
+}
+
+/// Splits traffic between an XDP transport and a fallback as set by a [`RolloutControl`].
+pub struct RolloutTransport<X, F> {
+    xdp: X,
+    fallback: F,
+    control: RolloutControl,
+}
+
+impl<X: DatagramTransport, F: DatagramTransport> RolloutTransport<X, F> {
+    pub fn new(xdp: X, fallback: F, control: RolloutControl) -> Self {
+        Self {
+            xdp,
+            fallback,
+            control,
+        }
+    }
+
+    pub fn control(&self) -> &RolloutControl {
+        &self.control
+    }
+}
+
+impl<X: DatagramTransport, F: DatagramTransport> DatagramTransport for RolloutTransport<X, F> {
+    fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+        let (xdp, fallback): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+            .iter()
+            .partition(|addr| self.control.path_for(addr) == RolloutPath::Xdp);
+
+        let mut result = Ok(());
+        if !xdp.is_empty() {
+            let sent = self.xdp.send_to(payload.clone(), &xdp);
+            self.control.inner.xdp.record(xdp.len(), &sent);
+            result = result.and(sent);
+        }
+        if !fallback.is_empty() {
+            let sent = self.fallback.send_to(payload, &fallback);
+            self.control.inner.fallback.record(fallback.len(), &sent);
+            result = result.and(sent);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::sync::Mutex};
+
+    #[derive(Default)]
+    struct RecordingTransport {
+        sent: Mutex<Vec<SocketAddr>>,
+        would_block: bool,
+    }
+
+    impl DatagramTransport for RecordingTransport {
+        fn send_to(&self, _payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+            if self.would_block {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.sent.lock().unwrap().extend_from_slice(addrs);
+            Ok(())
+        }
+    }
+
+    fn addrs() -> Vec<SocketAddr> {
+        (0..=255)
+            .map(|i| SocketAddr::from(([10, 0, 0, i], 8000)))
+            .collect()
+    }
+
+    #[test]
+    fn test_rollout_split() {
+        let control = RolloutControl::new(0, 7);
+        let transport = RolloutTransport::new(
+            RecordingTransport::default(),
+            RecordingTransport::default(),
+            control.clone(),
+        );
+        let addrs = addrs();
+        transport.send_to(Bytes::from_static(b"a"), &addrs).unwrap();
+        assert!(transport.xdp.sent.lock().unwrap().is_empty());
+        assert_eq!(control.stats().fallback.sent, 256);
+
+        // raising the percentage only moves destinations to xdp
+        control.set_xdp_percent(30);
+        let at_30 = addrs
+            .iter()
+            .filter(|addr| control.path_for(addr) == RolloutPath::Xdp)
+            .copied()
+            .collect::<Vec<_>>();
+        assert!(!at_30.is_empty() && at_30.len() < addrs.len());
+        control.set_xdp_percent(60);
+        assert!(at_30
+            .iter()
+            .all(|addr| control.path_for(addr) == RolloutPath::Xdp));
+
+        control.set_xdp_percent(200);
+        assert_eq!(control.xdp_percent(), 100);
+        // IPv6 isn't eligible
+        let v6 = SocketAddr::from(([0xfe80, 0, 0, 0, 0, 0, 0, 1], 8000));
+        assert_eq!(control.path_for(&v6), RolloutPath::Fallback);
+        transport
+            .send_to(Bytes::from_static(b"b"), &[addrs[0], v6])
+            .unwrap();
+        assert_eq!(*transport.xdp.sent.lock().unwrap(), [addrs[0]]);
+        assert_eq!(
+            control.stats(),
+            RolloutStats {
+                xdp: PathStats {
+                    sent: 1,
+                    ..PathStats::default()
+                },
+                fallback: PathStats {
+                    sent: 257,
+                    ..PathStats::default()
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_rollout_path_stats() {
+        let control = RolloutControl::new(100, 0);
+        let transport = RolloutTransport::new(
+            RecordingTransport {
+                would_block: true,
+                ..RecordingTransport::default()
+            },
+            RecordingTransport::default(),
+            control.clone(),
+        );
+        let addrs = addrs();
+        let v6 = SocketAddr::from(([0xfe80, 0, 0, 0, 0, 0, 0, 1], 8000));
+        let err = transport
+            .send_to(Bytes::from_static(b"a"), &[addrs[0], addrs[1], v6])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // the fallback still got its share
+        assert_eq!(*transport.fallback.sent.lock().unwrap(), [v6]);
+        let stats = control.stats();
+        assert_eq!(stats.xdp.dropped, 2);
+        assert_eq!(stats.fallback.sent, 1);
+    }
+}
@@ -1,12 +1,14 @@
 use {
-    crate::netlink::{
-        netlink_get_neighbors, netlink_get_routes, MacAddress, NeighborEntry, RouteEntry,
-    },
-    libc::{AF_INET, AF_INET6},
-    std::{
-        io,
-        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    crate::{
+        error::XdpError,
+        netlink::{
+            netlink_get_addresses, netlink_get_neighbors, netlink_get_routes, netlink_get_rules,
+            InterfaceAddress, MacAddress, NeighborEntry, RouteEntry, RuleEntry, FR_ACT_BLACKHOLE,
+            FR_ACT_PROHIBIT, FR_ACT_TO_TBL, FR_ACT_UNREACHABLE,
+        },
     },
+    libc::{AF_INET, AF_INET6, RTN_BLACKHOLE, RTN_PROHIBIT, RTN_UNREACHABLE, RT_TABLE_MAIN},
+    std::net::{IpAddr, Ipv4Addr, Ipv6Addr},
     thiserror::Error,
 };
 
@@ -27,9 +29,41 @@ pub struct NextHop {
     pub mac_addr: Option<MacAddress>,
     pub ip_addr: IpAddr,
     pub if_index: u32,
+    /// The source address to send from, `None` if the interface has no suitable address.
+    pub src_ip: Option<IpAddr>,
+}
+
+// Picks the source address like the kernel does: the route's preferred source if it has one,
+// otherwise a primary address of the output interface with a scope at least as wide as the
+// route's, preferably in the same subnet as the next hop. Addresses that can't be sent from yet
+// are skipped, and deprecated ones are only used if there's nothing else.
+fn select_source(
+    addresses: &[InterfaceAddress],
+    route: &RouteEntry,
+    if_index: u32,
+    next_hop_ip: IpAddr,
+) -> Option<IpAddr> {
+    if route.pref_src.is_some() {
+        return route.pref_src;
+    }
+
+    addresses
+        .iter()
+        .filter(|a| {
+            a.if_index == if_index
+                && a.address.is_ipv4() == next_hop_ip.is_ipv4()
+                && a.scope <= route.scope
+                && a.is_usable()
+        })
+        // the first of the best candidates
+        .min_by_key(|a| (a.is_deprecated(), !a.contains(next_hop_ip), a.secondary))
+        .map(|a| a.address)
 }
 
-fn lookup_route(routes: &[RouteEntry], dest: IpAddr) -> Option<&RouteEntry> {
+fn lookup_route<'a>(
+    routes: impl IntoIterator<Item = &'a RouteEntry>,
+    dest: IpAddr,
+) -> Option<&'a RouteEntry> {
     let mut best_match = None;
 
     let family = match dest {
@@ -37,7 +71,7 @@ fn lookup_route(routes: &[RouteEntry], dest: IpAddr) -> Option<&RouteEntry> {
         IpAddr::V6(_) => AF_INET6 as u8,
     };
 
-    for route in routes.iter().filter(|r| r.family == family) {
+    for route in routes.into_iter().filter(|r| r.family == family) {
         match (dest, route.destination) {
             // this is the default route
             (_, None) => {
@@ -76,6 +110,65 @@ fn lookup_route(routes: &[RouteEntry], dest: IpAddr) -> Option<&RouteEntry> {
     best_match.map(|(route, _)| route)
 }
 
+// Whether `rule` applies to packets sent by this host to `dest` with mark `fwmark`. Like for a
+// socket that isn't bound to an address or an interface, the source is only known once routed and
+// the input interface is loopback, so rules selecting on either only match when inverted.
+fn rule_matches(rule: &RuleEntry, dest: IpAddr, fwmark: u32) -> bool {
+    let prefix_matches =
+        |network: Option<IpAddr>, prefix_len: u8, addr: Option<IpAddr>| match (network, addr) {
+            (None, _) => true,
+            (Some(IpAddr::V4(network)), Some(IpAddr::V4(addr))) => {
+                is_ipv4_match(addr, network, prefix_len)
+            }
+            (Some(IpAddr::V6(network)), Some(IpAddr::V6(addr))) => {
+                is_ipv6_match(addr, network, prefix_len)
+            }
+            _ => false,
+        };
+    let matches = prefix_matches(rule.destination, rule.dst_len, Some(dest))
+        && prefix_matches(rule.source, rule.src_len, None)
+        && rule.iif_name.as_deref().is_none_or(|name| name == "lo")
+        && rule.oif_name.is_none()
+        && (fwmark ^ rule.fwmark.unwrap_or(0)) & rule.fwmask == 0;
+    matches != rule.invert
+}
+
+// Looks up `dest` like the kernel does with policy routing: the rules are evaluated in order of
+// priority, and the first one pointing to a table with a route to `dest` wins. Rules jumping to
+// other rules aren't supported and are skipped.
+fn lookup_policy_route<'a>(
+    routes: &'a [RouteEntry],
+    rules: &[RuleEntry],
+    dest: IpAddr,
+    fwmark: u32,
+) -> Option<&'a RouteEntry> {
+    for rule in rules.iter().filter(|rule| rule_matches(rule, dest, fwmark)) {
+        match rule.action {
+            FR_ACT_TO_TBL => {
+                let table = routes
+                    .iter()
+                    .filter(|r| r.table.unwrap_or(RT_TABLE_MAIN as u32) == rule.table);
+                let Some(route) = lookup_route(table, dest) else {
+                    continue;
+                };
+                if rule
+                    .suppress_prefixlen
+                    .is_some_and(|len| route.dst_len <= len)
+                {
+                    continue;
+                }
+                if matches!(route.type_, RTN_BLACKHOLE | RTN_UNREACHABLE | RTN_PROHIBIT) {
+                    return None;
+                }
+                return Some(route);
+            }
+            FR_ACT_BLACKHOLE | FR_ACT_UNREACHABLE | FR_ACT_PROHIBIT => return None,
+            _ => continue,
+        }
+    }
+    None
+}
+
 fn is_ipv4_match(addr: Ipv4Addr, network: Ipv4Addr, prefix_len: u8) -> bool {
     if prefix_len == 0 {
         return true;
@@ -114,16 +207,35 @@ fn is_ipv6_match(addr: Ipv6Addr, network: Ipv6Addr, prefix_len: u8) -> bool {
 pub struct Router {
     arp_table: ArpTable,
     routes: Vec<RouteEntry>,
+    // sorted by priority
+    rules: Vec<RuleEntry>,
+    addresses: Vec<InterfaceAddress>,
+    fwmark: u32,
 }
 
 impl Router {
-    pub fn new() -> Result<Self, io::Error> {
+    pub fn new() -> Result<Self, XdpError> {
+        Self::with_fwmark(0)
+    }
+
+    /// Creates a router that routes packets as if they were marked with `fwmark`, like the
+    /// packets of a socket with `SO_MARK` set, so that they follow the same policy routing rules.
+    pub fn with_fwmark(fwmark: u32) -> Result<Self, XdpError> {
+        let mut rules = netlink_get_rules(AF_INET as u8)?;
+        rules.sort_by_key(|rule| rule.priority);
         Ok(Self {
             arp_table: ArpTable::new()?,
             routes: netlink_get_routes(AF_INET as u8)?,
+            rules,
+            addresses: netlink_get_addresses(AF_INET as u8)?,
+            fwmark,
         })
     }
 
+    pub fn fwmark(&self) -> u32 {
+        self.fwmark
+    }
+
     pub fn default(&self) -> Result<NextHop, RouteError> {
         let default_route = self
             .routes
@@ -141,32 +253,71 @@ impl Router {
         };
 
         let mac_addr = self.arp_table.lookup(next_hop_ip).cloned();
+        let src_ip = select_source(&self.addresses, default_route, if_index, next_hop_ip);
 
         Ok(NextHop {
             ip_addr: next_hop_ip,
             mac_addr,
             if_index,
+            src_ip,
+        })
+    }
+
+    /// Returns the next hop of the default route going through `if_index`, even if it's not the
+    /// preferred default route.
+    pub fn default_via(&self, if_index: u32) -> Result<NextHop, RouteError> {
+        let default_route = self
+            .routes
+            .iter()
+            .filter(|r| r.destination.is_none() && r.out_if_index == Some(if_index as i32))
+            .min_by_key(|r| r.priority.unwrap_or(0))
+            .ok_or(RouteError::NoRouteFound(IpAddr::V4(Ipv4Addr::UNSPECIFIED)))?;
+
+        let next_hop_ip = match default_route.gateway {
+            Some(gateway) => gateway,
+            None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        };
+
+        let mac_addr = self.arp_table.lookup(next_hop_ip).cloned();
+        let src_ip = select_source(&self.addresses, default_route, if_index, next_hop_ip);
+
+        Ok(NextHop {
+            ip_addr: next_hop_ip,
+            mac_addr,
+            if_index,
+            src_ip,
         })
     }
 
     pub fn route(&self, dest_ip: IpAddr) -> Result<NextHop, RouteError> {
-        let route = lookup_route(&self.routes, dest_ip).ok_or(RouteError::NoRouteFound(dest_ip))?;
+        // without rules, e.g. if the kernel doesn't support policy routing, all the routes are in
+        // a single table
+        let route = if self.rules.is_empty() {
+            lookup_route(&self.routes, dest_ip)
+        } else {
+            lookup_policy_route(&self.routes, &self.rules, dest_ip, self.fwmark)
+        }
+        .ok_or(RouteError::NoRouteFound(dest_ip))?;
 
         let if_index = route
             .out_if_index
             .ok_or(RouteError::MissingOutputInterface)? as u32;
 
+        // multicast is sent on the link even if the route has a gateway, to the group's MAC
         let next_hop_ip = match route.gateway {
-            Some(gateway) => gateway,
-            None => dest_ip,
+            Some(gateway) if !dest_ip.is_multicast() => gateway,
+            _ => dest_ip,
         };
 
-        let mac_addr = self.arp_table.lookup(next_hop_ip).cloned();
+        let mac_addr =
+            MacAddress::multicast(dest_ip).or_else(|| self.arp_table.lookup(next_hop_ip).cloned());
+        let src_ip = select_source(&self.addresses, route, if_index, next_hop_ip);
 
         Ok(NextHop {
             ip_addr: next_hop_ip,
             mac_addr,
             if_index,
+            src_ip,
         })
     }
 }
@@ -176,7 +327,7 @@ struct ArpTable {
 }
 
 impl ArpTable {
-    pub fn new() -> Result<Self, io::Error> {
+    pub fn new() -> Result<Self, XdpError> {
         let neighbors = netlink_get_neighbors(None, AF_INET as u8)?;
         Ok(Self { neighbors })
     }
@@ -191,7 +342,13 @@ impl ArpTable {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use {
+        super::*,
+        libc::{
+            IFA_F_DEPRECATED, IFA_F_SECONDARY, IFA_F_TENTATIVE, RTN_LOCAL, RTN_UNICAST,
+            RT_SCOPE_HOST, RT_SCOPE_LINK, RT_SCOPE_UNIVERSE,
+        },
+    };
 
     #[test]
     fn test_ipv4_match() {
@@ -243,6 +400,162 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_select_source() {
+        let address = |address: &str, prefix_len, scope, secondary| InterfaceAddress {
+            if_index: 2,
+            address: address.parse().unwrap(),
+            prefix_len,
+            scope,
+            secondary,
+            flags: if secondary { IFA_F_SECONDARY } else { 0 },
+        };
+        let addresses = [
+            address("127.0.0.1", 8, RT_SCOPE_HOST, false),
+            address("10.0.0.5", 24, RT_SCOPE_LINK, false),
+            address("192.168.1.10", 24, RT_SCOPE_UNIVERSE, false),
+            address("192.168.2.10", 24, RT_SCOPE_UNIVERSE, false),
+            address("192.168.2.11", 24, RT_SCOPE_UNIVERSE, true),
+        ];
+        let mut route = RouteEntry {
+            destination: None,
+            gateway: None,
+            pref_src: None,
+            out_if_index: Some(2),
+            in_if_index: None,
+            priority: None,
+            table: None,
+            protocol: 0,
+            scope: RT_SCOPE_UNIVERSE,
+            type_: 0,
+            family: AF_INET as u8,
+            dst_len: 0,
+        };
+        let select = |route: &RouteEntry, next_hop: &str| {
+            select_source(&addresses, route, 2, next_hop.parse().unwrap()).map(|ip| ip.to_string())
+        };
+
+        // the primary address in the gateway's subnet
+        assert_eq!(select(&route, "192.168.2.1").unwrap(), "192.168.2.10");
+        // no address in the gateway's subnet, use the first global one
+        assert_eq!(select(&route, "172.16.0.1").unwrap(), "192.168.1.10");
+        // link scoped addresses can only be used for link scoped routes
+        assert_eq!(select(&route, "10.0.0.1").unwrap(), "192.168.1.10");
+        route.scope = RT_SCOPE_LINK;
+        assert_eq!(select(&route, "10.0.0.1").unwrap(), "10.0.0.5");
+        // other interfaces' addresses are never used
+        assert_eq!(
+            select_source(&addresses, &route, 3, "10.0.0.1".parse().unwrap()),
+            None
+        );
+
+        route.pref_src = Some("192.168.2.11".parse().unwrap());
+        assert_eq!(select(&route, "10.0.0.1").unwrap(), "192.168.2.11");
+        route.pref_src = None;
+        route.scope = RT_SCOPE_UNIVERSE;
+
+        // deprecated addresses are a last resort, tentative ones are never used
+        let mut addresses = addresses.to_vec();
+        addresses[2].flags |= IFA_F_DEPRECATED;
+        addresses[3].flags |= IFA_F_TENTATIVE;
+        let select = |addresses: &[InterfaceAddress], next_hop: &str| {
+            select_source(addresses, &route, 2, next_hop.parse().unwrap()).map(|ip| ip.to_string())
+        };
+        assert_eq!(select(&addresses, "192.168.2.1").unwrap(), "192.168.2.11");
+        assert_eq!(select(&addresses, "192.168.1.1").unwrap(), "192.168.2.11");
+        addresses[4].flags |= IFA_F_DEPRECATED;
+        assert_eq!(select(&addresses, "172.16.0.1").unwrap(), "192.168.1.10");
+    }
+
+    #[test]
+    fn test_policy_route() {
+        let route = |destination: Option<&str>, dst_len, table, out_if_index, type_| RouteEntry {
+            destination: destination.map(|d| d.parse().unwrap()),
+            gateway: None,
+            pref_src: None,
+            out_if_index: Some(out_if_index),
+            in_if_index: None,
+            priority: None,
+            table: Some(table),
+            protocol: 0,
+            scope: RT_SCOPE_UNIVERSE,
+            type_,
+            family: AF_INET as u8,
+            dst_len,
+        };
+        let routes = [
+            route(Some("192.168.1.10"), 32, 255, 2, RTN_LOCAL),
+            route(None, 0, 254, 2, RTN_UNICAST),
+            route(Some("10.0.0.0"), 8, 254, 2, RTN_UNICAST),
+            route(None, 0, 100, 3, RTN_UNICAST),
+            route(None, 0, 51820, 4, RTN_UNICAST),
+            route(Some("198.51.100.0"), 24, 51820, 4, RTN_UNREACHABLE),
+        ];
+        let rule = |priority, table| RuleEntry {
+            family: AF_INET as u8,
+            priority,
+            action: FR_ACT_TO_TBL,
+            table,
+            source: None,
+            src_len: 0,
+            destination: None,
+            dst_len: 0,
+            iif_name: None,
+            oif_name: None,
+            fwmark: None,
+            fwmask: 0,
+            suppress_prefixlen: None,
+            invert: false,
+        };
+        let rules = [
+            rule(0, 255),
+            // only applies to packets from 10.0.0.0/8, which ours aren't before being routed
+            RuleEntry {
+                source: Some("10.0.0.0".parse().unwrap()),
+                src_len: 8,
+                ..rule(10, 100)
+            },
+            RuleEntry {
+                destination: Some("192.0.2.0".parse().unwrap()),
+                dst_len: 24,
+                action: FR_ACT_UNREACHABLE,
+                ..rule(50, 0)
+            },
+            RuleEntry {
+                fwmark: Some(0x10),
+                fwmask: 0xff,
+                ..rule(100, 100)
+            },
+            // the rules set up by wg-quick
+            RuleEntry {
+                suppress_prefixlen: Some(0),
+                ..rule(32764, 254)
+            },
+            RuleEntry {
+                fwmark: Some(0xca6c),
+                fwmask: u32::MAX,
+                invert: true,
+                ..rule(32765, 51820)
+            },
+            rule(32766, 254),
+        ];
+        let lookup = |dest: &str, fwmark| {
+            lookup_policy_route(&routes, &rules, dest.parse().unwrap(), fwmark)
+                .map(|route| (route.table.unwrap(), route.out_if_index.unwrap()))
+        };
+
+        assert_eq!(lookup("192.168.1.10", 0), Some((255, 2)));
+        // the main table has a more specific route than the default one it suppresses
+        assert_eq!(lookup("10.1.1.1", 0), Some((254, 2)));
+        assert_eq!(lookup("8.8.8.8", 0), Some((51820, 4)));
+        // marked packets skip the tunnel
+        assert_eq!(lookup("8.8.8.8", 0xca6c), Some((254, 2)));
+        assert_eq!(lookup("8.8.8.8", 0x110), Some((100, 3)));
+        assert_eq!(lookup("8.8.8.8", 0x11), Some((51820, 4)));
+        assert_eq!(lookup("192.0.2.1", 0x10), None);
+        assert_eq!(lookup("198.51.100.1", 0), None);
+    }
+
     #[test]
     fn test_router() {
         let router = Router::new().unwrap();
@@ -0,0 +1,365 @@
+//! Turns received UMEM frames into [`PacketBatch`]es without copying the payloads.
+//!
+//! Each packet references its UMEM frame directly. The frame is handed back to the
+//! [`RxBatchBuilder`] once the last reference to the packet is dropped, so it can be returned to
+//! the fill ring. To avoid starving the fill ring when consumers hold on to packets for a long
+//! time, the number of frames referenced by packets is bounded: past the bound payloads are copied
+//! and the frame is recycled right away.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        capture::CaptureRecorder,
+        device::RxFillRing,
+        packet::{parse_udp_frame, ParseError},
+        umem::{FrameOffset, PageAlignedMemory, Umem},
+    },
+    crossbeam_channel::{Receiver, Sender},
+    solana_perf::packet::{bytes::Bytes, BytesPacket, Meta, PacketBatch, PACKET_DATA_SIZE},
+    std::{
+        slice,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    },
+};
+
+/// UMEM memory that stays mapped for as long as packets reference it.
+pub struct SharedUmemMemory {
+    memory: PageAlignedMemory,
+}
+
+// Safety: the memory is a plain anonymous mapping. Frames are only ever accessed by their current
+// owner, either the umem/driver or a single received packet.
+unsafe impl Send for SharedUmemMemory {}
+unsafe impl Sync for SharedUmemMemory {}
+
+impl SharedUmemMemory {
+    pub fn new(memory: PageAlignedMemory) -> Arc<Self> {
+        Arc::new(Self { memory })
+    }
+
+    /// Returns the memory as a slice, to create the umem with.
+    ///
+    /// # Safety
+    ///
+    /// The caller must not write to frames that are referenced by packets. This is guaranteed when
+    /// frames are only reused after being returned by [`RxBatchBuilder::recycle`].
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn as_mut_slice(&self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.memory.as_ptr() as *mut u8, self.memory.len()) }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *const u8 {
+        self.memory.as_ptr()
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len()
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RxBatchStats {
+    /// Packets whose payload references their UMEM frame.
+    pub zero_copy: u64,
+    /// Packets whose payload was copied because too many frames were outstanding.
+    pub copied: u64,
+    /// Frames that failed to parse.
+    pub invalid: u64,
+    /// Frames whose payload is larger than `PACKET_DATA_SIZE`.
+    pub oversized: u64,
+}
+
+/// Builds packet batches out of received frames.
+pub struct RxBatchBuilder {
+    memory: Arc<SharedUmemMemory>,
+    verify_udp_checksum: bool,
+    max_outstanding: usize,
+    outstanding: Arc<AtomicUsize>,
+    recycle_sender: Sender<FrameOffset>,
+    recycle_receiver: Receiver<FrameOffset>,
+    // frames that can be reused right away since no packet references them
+    free: Vec<FrameOffset>,
+    packets: Vec<BytesPacket>,
+    stats: RxBatchStats,
+    capture: Option<CaptureRecorder>,
+}
+
+impl RxBatchBuilder {
+    /// Creates a new builder.
+    ///
+    /// At most `max_outstanding` frames are referenced by packets at any given time. This should be
+    /// comfortably lower than the size of the fill ring.
+    pub fn new(
+        memory: Arc<SharedUmemMemory>,
+        max_outstanding: usize,
+        verify_udp_checksum: bool,
+    ) -> Self {
+        let (recycle_sender, recycle_receiver) = crossbeam_channel::unbounded();
+        Self {
+            memory,
+            verify_udp_checksum,
+            max_outstanding,
+            outstanding: Arc::new(AtomicUsize::new(0)),
+            recycle_sender,
+            recycle_receiver,
+            free: Vec::new(),
+            packets: Vec::new(),
+            stats: RxBatchStats::default(),
+            capture: None,
+        }
+    }
+
+    /// Records every pushed frame with `capture`, valid or not.
+    pub fn with_capture(mut self, capture: CaptureRecorder) -> Self {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Adds the frame at `offset` to the current batch.
+    ///
+    /// Invalid frames are not added and become immediately available from
+    /// [`recycle`](Self::recycle).
+    pub fn push(&mut self, offset: FrameOffset, len: usize) -> Result<(), ParseError> {
+        assert!(offset.0 + len <= self.memory.len());
+        // Safety: the frame is within the umem and is owned by us until we recycle it
+        let frame = unsafe { slice::from_raw_parts(self.memory.as_ptr().add(offset.0), len) };
+        if let Some(capture) = self.capture.as_mut() {
+            capture.record(frame);
+        }
+
+        let (payload, addr) = match parse_udp_frame(frame, self.verify_udp_checksum) {
+            Ok(udp) => {
+                let start = udp.payload.as_ptr() as usize - frame.as_ptr() as usize;
+                (start..start + udp.payload.len(), udp.src_addr())
+            }
+            Err(e) => {
+                self.stats.invalid += 1;
+                self.free.push(offset);
+                return Err(e);
+            }
+        };
+        if payload.len() > PACKET_DATA_SIZE {
+            self.stats.oversized += 1;
+            self.free.push(offset);
+            return Ok(());
+        }
+
+        let mut meta = Meta {
+            size: payload.len(),
+            ..Meta::default()
+        };
+        meta.set_socket_addr(&addr);
+
+        let buffer = if self.outstanding.load(Ordering::Relaxed) < self.max_outstanding {
+            self.outstanding.fetch_add(1, Ordering::Relaxed);
+            self.stats.zero_copy += 1;
+            Bytes::from_owner(UmemFrame {
+                memory: Arc::clone(&self.memory),
+                offset,
+                len,
+                outstanding: Arc::clone(&self.outstanding),
+                recycle_sender: self.recycle_sender.clone(),
+            })
+            .slice(payload)
+        } else {
+            self.stats.copied += 1;
+            self.free.push(offset);
+            Bytes::copy_from_slice(&frame[payload])
+        };
+        self.packets.push(BytesPacket::new(buffer, meta));
+
+        Ok(())
+    }
+
+    /// Returns the packets pushed since the last call, or `None` if there are none.
+    pub fn take_batch(&mut self) -> Option<PacketBatch> {
+        if self.packets.is_empty() {
+            return None;
+        }
+        Some(PacketBatch::from(std::mem::take(&mut self.packets)))
+    }
+
+    /// Calls `f` with every frame that can be reused, typically to put it back in the fill ring.
+    pub fn recycle(&mut self, mut f: impl FnMut(FrameOffset)) {
+        for offset in self.free.drain(..) {
+            f(offset);
+        }
+        for offset in self.recycle_receiver.try_iter() {
+            f(offset);
+        }
+    }
+
+    /// Returns the number of frames currently referenced by packets.
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> &RxBatchStats {
+        &self.stats
+    }
+}
+
+/// Moves free frames from `umem` to the fill ring until there are `max_posted` frames in the ring
+/// or it's full.
+pub(crate) fn refill<U: Umem>(fill: &mut RxFillRing<U::Frame>, umem: &mut U, max_posted: usize) {
+    fill.sync(false);
+    let available = fill.available();
+    let posted = fill.capacity() - available;
+    for _ in 0..available.min(max_posted.saturating_sub(posted)) {
+        let Some(frame) = umem.reserve() else {
+            break;
+        };
+        // can't fail, we checked the available space
+        fill.write(frame).unwrap();
+    }
+    fill.commit();
+}
+
+// Owner of a frame referenced by a packet. Sends the frame back to the builder once dropped.
+struct UmemFrame {
+    memory: Arc<SharedUmemMemory>,
+    offset: FrameOffset,
+    len: usize,
+    outstanding: Arc<AtomicUsize>,
+    recycle_sender: Sender<FrameOffset>,
+}
+
+impl AsRef<[u8]> for UmemFrame {
+    fn as_ref(&self) -> &[u8] {
+        // Safety: the frame is not written to until it's recycled, which happens after we're
+        // dropped
+        unsafe { slice::from_raw_parts(self.memory.as_ptr().add(self.offset.0), self.len) }
+    }
+}
+
+impl Drop for UmemFrame {
+    fn drop(&mut self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+        // the builder might be gone already, in which case there's nothing to recycle into
+        let _ = self.recycle_sender.send(self.offset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::packet::{
+            write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE, IP_HEADER_SIZE,
+            UDP_HEADER_SIZE,
+        },
+        std::net::{Ipv4Addr, SocketAddr},
+    };
+
+    const FRAME_SIZE: usize = 2048;
+
+    fn write_frame(
+        memory: &SharedUmemMemory,
+        index: usize,
+        payload: &[u8],
+    ) -> (FrameOffset, usize) {
+        let src_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let dst_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let len = payload.len();
+        let frame_len = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + len;
+        let frame = unsafe { &mut memory.as_mut_slice()[index * FRAME_SIZE..][..frame_len] };
+        frame[ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE..].copy_from_slice(payload);
+        write_eth_header(frame, &[1; 6], &[2; 6]);
+        write_ip_header(
+            &mut frame[ETH_HEADER_SIZE..],
+            &src_ip,
+            &dst_ip,
+            (UDP_HEADER_SIZE + len) as u16,
+        );
+        write_udp_header(
+            &mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &src_ip,
+            8000 + index as u16,
+            &dst_ip,
+            9000,
+            len as u16,
+            false,
+        );
+        (FrameOffset(index * FRAME_SIZE), frame_len)
+    }
+
+    fn recycled(builder: &mut RxBatchBuilder) -> Vec<usize> {
+        let mut offsets = vec![];
+        builder.recycle(|offset| offsets.push(offset.0));
+        offsets.sort();
+        offsets
+    }
+
+    #[test]
+    fn test_zero_copy_batch() {
+        let memory = SharedUmemMemory::new(PageAlignedMemory::alloc(FRAME_SIZE, 8).unwrap());
+        let mut builder = RxBatchBuilder::new(Arc::clone(&memory), 8, true);
+        assert!(builder.take_batch().is_none());
+
+        for i in 0..3 {
+            let (offset, len) = write_frame(&memory, i, &[i as u8; 100]);
+            builder.push(offset, len).unwrap();
+        }
+        // not a valid frame, recycled straight away
+        assert_eq!(
+            builder.push(FrameOffset(3 * FRAME_SIZE), 10),
+            Err(ParseError::Truncated)
+        );
+
+        let batch = builder.take_batch().unwrap();
+        assert_eq!(batch.len(), 3);
+        for (i, packet) in batch.iter().enumerate() {
+            assert_eq!(packet.data(..).unwrap(), &[i as u8; 100]);
+            assert_eq!(packet.meta().size, 100);
+            let addr: SocketAddr = format!("10.0.0.1:{}", 8000 + i).parse().unwrap();
+            assert_eq!(packet.meta().socket_addr(), addr);
+            // the payload points straight into the umem
+            let ptr = packet.data(..).unwrap().as_ptr() as usize;
+            let frame = memory.as_ptr() as usize + i * FRAME_SIZE;
+            assert_eq!(
+                ptr,
+                frame + ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE
+            );
+        }
+        assert_eq!(builder.outstanding(), 3);
+        assert_eq!(recycled(&mut builder), vec![3 * FRAME_SIZE]);
+
+        // frames are recycled once the packets are dropped
+        drop(batch);
+        assert_eq!(builder.outstanding(), 0);
+        assert_eq!(recycled(&mut builder), vec![0, FRAME_SIZE, 2 * FRAME_SIZE]);
+        assert_eq!(
+            builder.stats(),
+            &RxBatchStats {
+                zero_copy: 3,
+                invalid: 1,
+                ..RxBatchStats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_copy_when_too_many_outstanding() {
+        let memory = SharedUmemMemory::new(PageAlignedMemory::alloc(FRAME_SIZE, 4).unwrap());
+        let mut builder = RxBatchBuilder::new(Arc::clone(&memory), 1, false);
+
+        for i in 0..3 {
+            let (offset, len) = write_frame(&memory, i, &[i as u8; 10]);
+            builder.push(offset, len).unwrap();
+        }
+        let batch = builder.take_batch().unwrap();
+        assert_eq!(builder.outstanding(), 1);
+        // the copied frames can be reused immediately
+        assert_eq!(recycled(&mut builder), vec![FRAME_SIZE, 2 * FRAME_SIZE]);
+
+        // overwriting recycled frames doesn't affect the copied packets
+        write_frame(&memory, 1, &[0xff; 10]);
+        for (i, packet) in batch.iter().enumerate() {
+            assert_eq!(packet.data(..).unwrap(), &[i as u8; 10]);
+        }
+        assert_eq!(builder.stats().copied, 2);
+    }
+}
@@ -0,0 +1,302 @@
+//! Steering of received packets to AF_XDP sockets.
+//!
+//! The XDP program loaded by [`load_rx_program`](crate::program::load_rx_program) only redirects
+//! the packets sent to one of the flows in its flow map to the AF_XDP socket of the queue they
+//! were received on, and passes everything else to the kernel untouched. This lets the rx loops
+//! share the NIC with the rest of the host. Both the flows and the sockets can be changed while
+//! the program is attached.
+//!
+//! The same program can be attached to several interfaces with
+//! [`attach_rx_program`](crate::program::attach_rx_program), sharing its flows. Each interface
+//! then gets its own slot in the socket map, see
+//! [`register_interface_socket`](RxFilter::register_interface_socket).
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        device::QueueId,
+        error::{XdpError, XdpErrorKind},
+    },
+    agave_xdp_ebpf::{
+        FlowKey, FLOWS_MAP, INTERFACES_MAP, MAX_INTERFACES, QUEUES_PER_INTERFACE, SOCKETS_MAP,
+    },
+    aya::{
+        maps::{HashMap, Map, MapData, XskMap},
+        Ebpf,
+    },
+    std::{
+        fmt,
+        net::{Ipv4Addr, SocketAddrV4},
+        os::fd::{AsFd, BorrowedFd, OwnedFd},
+        sync::Mutex,
+    },
+};
+
+/// The destination of packets to receive through AF_XDP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RxFlow {
+    /// The destination address, or `None` for any of the host's addresses.
+    pub addr: Option<Ipv4Addr>,
+    pub port: u16,
+}
+
+impl RxFlow {
+    /// Packets sent to `port` on any address.
+    pub fn port(port: u16) -> Self {
+        Self { addr: None, port }
+    }
+
+    fn key(&self) -> FlowKey {
+        let addr = self.addr.unwrap_or(Ipv4Addr::UNSPECIFIED);
+        FlowKey::new(addr.octets(), self.port.to_be_bytes())
+    }
+
+    fn from_key(key: &FlowKey) -> Self {
+        let addr = Ipv4Addr::from(key.addr);
+        Self {
+            addr: (!addr.is_unspecified()).then_some(addr),
+            port: u16::from_be_bytes(key.port),
+        }
+    }
+}
+
+impl From<SocketAddrV4> for RxFlow {
+    fn from(addr: SocketAddrV4) -> Self {
+        Self {
+            addr: Some(*addr.ip()),
+            port: addr.port(),
+        }
+    }
+}
+
+/// The flow and socket maps of an attached rx program.
+pub struct RxFilter {
+    flows: Mutex<HashMap<MapData, FlowKey, u8>>,
+    sockets: Mutex<XskMap<MapData>>,
+    interfaces: Mutex<HashMap<MapData, u32, u32>>,
+    // duplicates of the map fds, to hand the maps over to another process
+    flows_fd: OwnedFd,
+    sockets_fd: OwnedFd,
+    interfaces_fd: OwnedFd,
+}
+
+impl RxFilter {
+    /// Takes the maps out of `ebpf`, which must have been loaded with
+    /// [`load_rx_program`](crate::program::load_rx_program).
+    ///
+    /// Fails if the maps are missing, which happens when the prebuilt `agave-xdp-prog` bytecode is
+    /// older than its source.
+    pub fn new(ebpf: &mut Ebpf) -> Result<Self, XdpError> {
+        let missing = |map| {
+            XdpError::other(
+                XdpErrorKind::Misconfigured,
+                "Ebpf::take_map",
+                format!(
+                    "the xdp program has no {map} map, rebuild agave-xdp-prog with \
+                     scripts/build-agave-xdp-ebpf.sh"
+                ),
+            )
+        };
+        let flows = ebpf.take_map(FLOWS_MAP).ok_or_else(|| missing(FLOWS_MAP))?;
+        let sockets = ebpf
+            .take_map(SOCKETS_MAP)
+            .ok_or_else(|| missing(SOCKETS_MAP))?;
+        let interfaces = ebpf
+            .take_map(INTERFACES_MAP)
+            .ok_or_else(|| missing(INTERFACES_MAP))?;
+        Self::from_maps(flows, sockets, interfaces)
+    }
+
+    /// Opens the maps of an rx program attached by another process, from the fds returned by
+    /// [`map_fds`](Self::map_fds).
+    pub fn from_fds(
+        flows: OwnedFd,
+        sockets: OwnedFd,
+        interfaces: OwnedFd,
+    ) -> Result<Self, XdpError> {
+        let open = |fd| MapData::from_fd(fd).map_err(|e| map_error("bpf_obj_get_info_by_fd", e));
+        Self::from_maps(
+            Map::HashMap(open(flows)?),
+            Map::XskMap(open(sockets)?),
+            Map::HashMap(open(interfaces)?),
+        )
+    }
+
+    fn from_maps(flows: Map, sockets: Map, interfaces: Map) -> Result<Self, XdpError> {
+        let dup = |map: &Map| match map {
+            Map::HashMap(data) | Map::XskMap(data) => data
+                .fd()
+                .as_fd()
+                .try_clone_to_owned()
+                .map_err(|e| XdpError::new("fcntl(F_DUPFD_CLOEXEC)", e)),
+            _ => Err(XdpError::other(
+                XdpErrorKind::Misconfigured,
+                "Ebpf::take_map",
+                "unexpected map type",
+            )),
+        };
+        let (flows_fd, sockets_fd, interfaces_fd) =
+            (dup(&flows)?, dup(&sockets)?, dup(&interfaces)?);
+        Ok(Self {
+            flows: Mutex::new(
+                HashMap::try_from(flows).map_err(|e| map_error("Ebpf::take_map", e))?,
+            ),
+            sockets: Mutex::new(
+                XskMap::try_from(sockets).map_err(|e| map_error("Ebpf::take_map", e))?,
+            ),
+            interfaces: Mutex::new(
+                HashMap::try_from(interfaces).map_err(|e| map_error("Ebpf::take_map", e))?,
+            ),
+            flows_fd,
+            sockets_fd,
+            interfaces_fd,
+        })
+    }
+
+    /// Returns the fds of the flow, socket and interface maps.
+    pub fn map_fds(&self) -> (BorrowedFd<'_>, BorrowedFd<'_>, BorrowedFd<'_>) {
+        (
+            self.flows_fd.as_fd(),
+            self.sockets_fd.as_fd(),
+            self.interfaces_fd.as_fd(),
+        )
+    }
+
+    /// Starts redirecting the packets sent to `flow`.
+    pub fn insert(&self, flow: RxFlow) -> Result<(), XdpError> {
+        self.flows
+            .lock()
+            .unwrap()
+            .insert(flow.key(), 1, 0)
+            .map_err(|e| map_error("bpf_map_update_elem", e))
+    }
+
+    /// Stops redirecting the packets sent to `flow`. Does nothing if it wasn't redirected.
+    pub fn remove(&self, flow: RxFlow) -> Result<(), XdpError> {
+        match self.flows.lock().unwrap().remove(&flow.key()) {
+            Err(aya::maps::MapError::KeyNotFound) => Ok(()),
+            result => result.map_err(|e| map_error("bpf_map_delete_elem", e)),
+        }
+    }
+
+    /// Returns the flows currently redirected.
+    pub fn flows(&self) -> Result<Vec<RxFlow>, XdpError> {
+        self.flows
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|key| {
+                key.map(|key| RxFlow::from_key(&key))
+                    .map_err(|e| map_error("bpf_map_get_next_key", e))
+            })
+            .collect()
+    }
+
+    /// Redirects the packets received on `queue_id` to `socket`, on the interfaces that weren't
+    /// given a slot with [`register_interface_socket`](Self::register_interface_socket).
+    pub fn register_socket(&self, queue_id: QueueId, socket: impl AsFd) -> Result<(), XdpError> {
+        self.set_socket(0, queue_id, socket)
+    }
+
+    /// Redirects the packets received on `queue_id` of the interface `if_index` to `socket`,
+    /// giving the interface a slot in the socket map if it doesn't have one yet.
+    pub fn register_interface_socket(
+        &self,
+        if_index: u32,
+        queue_id: QueueId,
+        socket: impl AsFd,
+    ) -> Result<(), XdpError> {
+        let slot = self
+            .interface_slot(if_index)
+            .map_err(|e| e.with_queue(if_index, queue_id))?;
+        self.set_socket(slot, queue_id, socket)
+            .map_err(|e| e.with_queue(if_index, queue_id))
+    }
+
+    // Returns the slot of `if_index`, assigning it the first free one if it has none.
+    fn interface_slot(&self, if_index: u32) -> Result<u32, XdpError> {
+        let mut interfaces = self.interfaces.lock().unwrap();
+        let used = interfaces
+            .iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| map_error("bpf_map_lookup_elem", e))?;
+        if let Some((_, slot)) = used.iter().find(|(index, _)| *index == if_index) {
+            return Ok(*slot);
+        }
+        // slot 0 is for the interfaces without a slot
+        let slot = (1..MAX_INTERFACES)
+            .find(|slot| !used.iter().any(|(_, used)| used == slot))
+            .ok_or_else(|| {
+                XdpError::other(
+                    XdpErrorKind::Misconfigured,
+                    "bpf_map_update_elem",
+                    format!("the rx program can't serve more than {MAX_INTERFACES} interfaces"),
+                )
+            })?;
+        interfaces
+            .insert(if_index, slot, 0)
+            .map_err(|e| map_error("bpf_map_update_elem", e))?;
+        Ok(slot)
+    }
+
+    fn set_socket(&self, slot: u32, queue_id: QueueId, socket: impl AsFd) -> Result<(), XdpError> {
+        let index = socket_index(slot, queue_id)?;
+        self.sockets
+            .lock()
+            .unwrap()
+            .set(index, socket.as_fd(), 0)
+            .map_err(|e| map_error("bpf_map_update_elem", e))
+    }
+}
+
+// Returns the index of the socket of `queue_id` in the socket map, see QUEUES_PER_INTERFACE.
+fn socket_index(slot: u32, queue_id: QueueId) -> Result<u32, XdpError> {
+    if queue_id.0 >= QUEUES_PER_INTERFACE as u64 {
+        return Err(XdpError::other(
+            XdpErrorKind::Unsupported,
+            "bpf_map_update_elem",
+            format!("the rx program only redirects the first {QUEUES_PER_INTERFACE} queues"),
+        ));
+    }
+    Ok(slot * QUEUES_PER_INTERFACE + queue_id.0 as u32)
+}
+
+impl fmt::Debug for RxFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RxFilter").finish_non_exhaustive()
+    }
+}
+
+fn map_error(syscall: &'static str, e: aya::maps::MapError) -> XdpError {
+    XdpError::other(XdpErrorKind::Other, syscall, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rx_flow_key() {
+        let flow = RxFlow::from(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 8001));
+        let key = flow.key();
+        // the program compares against the headers as they are on the wire
+        assert_eq!(key.addr, [10, 0, 0, 1]);
+        assert_eq!(key.port, [0x1f, 0x41]);
+        assert_eq!(RxFlow::from_key(&key), flow);
+
+        let any = RxFlow::port(8001);
+        assert_eq!(any.key().addr, [0; 4]);
+        assert_eq!(RxFlow::from_key(&any.key()), any);
+    }
+
+    #[test]
+    fn test_socket_index() {
+        assert_eq!(socket_index(0, QueueId(3)).unwrap(), 3);
+        assert_eq!(socket_index(2, QueueId(3)).unwrap(), 515);
+        assert_eq!(
+            socket_index(MAX_INTERFACES - 1, QueueId(255)).unwrap(),
+            MAX_INTERFACES * QUEUES_PER_INTERFACE - 1
+        );
+        assert!(socket_index(0, QueueId(256)).is_err());
+    }
+}
@@ -0,0 +1,927 @@
+//! Receiving on multiple NIC queues, with one worker per queue feeding a shared channel.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        capture::{CaptureRing, Direction},
+        device::{DeviceQueue, NetworkDevice, QueueId, RingSizes},
+        load_rx_program, load_xdp_program,
+        metrics::LoopThreads,
+        rx_batch::{refill, RxBatchBuilder, SharedUmemMemory},
+        rx_filter::{RxFilter, RxFlow},
+        socket::{Rx, RxRing, Socket, StatisticsPoller, XdpRingStats, XdpSocketStats},
+        umem::{PageAlignedMemory, SliceUmem, SliceUmemFrame, Umem},
+        xdp_program_id,
+    },
+    agave_cpu_utils::pin_thread,
+    caps::{
+        CapSet,
+        Capability::{CAP_BPF, CAP_NET_ADMIN, CAP_NET_RAW, CAP_PERFMON},
+    },
+    crossbeam_channel::{Receiver, Sender, TrySendError},
+    libc::{
+        epoll_create1, epoll_ctl, epoll_event, epoll_wait, sysconf, _SC_PAGESIZE, EPOLLIN,
+        EPOLL_CLOEXEC, EPOLL_CTL_ADD,
+    },
+    solana_perf::packet::PacketBatch,
+    std::{
+        error::Error,
+        io,
+        os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+        sync::{
+            atomic::{AtomicBool, AtomicU64, Ordering},
+            Arc,
+        },
+        thread::{self, Builder},
+        time::Duration,
+    },
+};
+
+/// Counters updated by an rx loop.
+#[derive(Debug, Default)]
+pub struct RxQueueStats {
+    /// Packets handed to the channel.
+    pub packets: AtomicU64,
+    /// Batches handed to the channel.
+    pub batches: AtomicU64,
+    /// Packets dropped because the channel was full.
+    pub channel_full: AtomicU64,
+    /// Frames that failed to parse.
+    pub invalid: AtomicU64,
+    /// Frames whose payload is larger than `PACKET_DATA_SIZE`.
+    pub oversized: AtomicU64,
+    /// Packets whose payload was copied because too many frames were outstanding.
+    pub copied: AtomicU64,
+    /// The statistics the kernel keeps for the socket, polled about once a second.
+    pub socket: XdpSocketStats,
+    /// How many frames the loop currently keeps in the fill ring, see [`FillTuning`].
+    pub fill_target: AtomicU64,
+    /// How full the fill and rx rings are.
+    pub rings: XdpRingStats,
+    /// The threads running the loop.
+    pub threads: LoopThreads,
+}
+
+/// Bounds for how many frames an rx loop keeps in the fill ring.
+///
+/// The loop starts out keeping the fill ring full. Whenever the kernel reports dropping packets,
+/// the target doubles up to `max_frames`. After `quiet_intervals` stats intervals (about a second
+/// each) without drops, it backs off by an eighth down to `min_frames`. Frames that aren't posted
+/// to the fill ring are left in the UMEM, where they're available to hold received packets.
+#[derive(Clone, Copy, Debug)]
+pub struct FillTuning {
+    pub min_frames: usize,
+    /// Clamped to the size of the fill ring.
+    pub max_frames: usize,
+    pub quiet_intervals: u32,
+}
+
+impl Default for FillTuning {
+    fn default() -> Self {
+        Self {
+            min_frames: 64,
+            max_frames: usize::MAX,
+            quiet_intervals: 10,
+        }
+    }
+}
+
+// Adjusts the fill target to the drops reported by the kernel.
+struct FillTuner {
+    min_frames: usize,
+    max_frames: usize,
+    quiet_intervals: u32,
+    target: usize,
+    drops: u64,
+    quiet: u32,
+}
+
+impl FillTuner {
+    fn new(tuning: FillTuning, capacity: usize) -> Self {
+        let max_frames = tuning.max_frames.min(capacity);
+        Self {
+            min_frames: tuning.min_frames.min(max_frames),
+            max_frames,
+            quiet_intervals: tuning.quiet_intervals,
+            target: max_frames,
+            drops: 0,
+            quiet: 0,
+        }
+    }
+
+    fn target(&self) -> usize {
+        self.target
+    }
+
+    // Called once per stats interval with the total number of drops so far.
+    fn observe(&mut self, drops: u64) {
+        if drops > self.drops {
+            self.drops = drops;
+            self.quiet = 0;
+            self.target = self.target.saturating_mul(2).clamp(1, self.max_frames);
+            return;
+        }
+        self.quiet += 1;
+        if self.quiet >= self.quiet_intervals {
+            self.quiet = 0;
+            self.target = (self.target - self.target / 8).max(self.min_frames);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RxLoopConfig {
+    /// The maximum number of frames referenced by received packets, see [`RxBatchBuilder::new`].
+    pub max_outstanding: usize,
+    pub verify_udp_checksum: bool,
+    /// Adjust how many frames are kept in the fill ring to the observed drops. When `None` the
+    /// fill ring is always kept full.
+    pub fill_tuning: Option<FillTuning>,
+    pub stats: Option<Arc<RxQueueStats>>,
+    /// Registers the socket with the program redirecting packets to it.
+    pub filter: Option<Arc<RxFilter>>,
+    /// Keep the start of the last received frames in memory, to be dumped on demand.
+    pub capture: Option<CaptureRing>,
+}
+
+impl Default for RxLoopConfig {
+    fn default() -> Self {
+        Self {
+            max_outstanding: 4096,
+            verify_udp_checksum: true,
+            fill_tuning: Some(FillTuning::default()),
+            stats: None,
+            filter: None,
+            capture: None,
+        }
+    }
+}
+
+pub fn rx_loop(
+    cpu_id: usize,
+    dev: &NetworkDevice,
+    queue_id: QueueId,
+    zero_copy: bool,
+    sender: Sender<PacketBatch>,
+    exit: &AtomicBool,
+    config: RxLoopConfig,
+) {
+    log::info!(
+        "starting xdp rx loop on {} queue {queue_id:?} cpu {cpu_id}",
+        dev.name()
+    );
+
+    // each queue is bound to its own CPU core
+    pin_thread("xdp-rx", [cpu_id]).unwrap();
+
+    let (queue, rx_size, memory) = open_rx_queue(dev, queue_id, config.max_outstanding);
+    let (mut socket, mut rx) = create_rx_socket(queue, &memory, rx_size, zero_copy);
+    if let Some(filter) = &config.filter {
+        filter
+            .register_interface_socket(dev.if_index(), queue_id, &socket)
+            .unwrap_or_else(|e| panic!("failed to register AF_XDP socket: {e}"));
+    }
+    let mut builder = RxBatchBuilder::new(
+        Arc::clone(&memory),
+        config.max_outstanding,
+        config.verify_udp_checksum,
+    );
+    if let Some(capture) = &config.capture {
+        builder = builder.with_capture(capture.recorder(Direction::Rx, queue_id));
+    }
+    let stats = config.stats.unwrap_or_default();
+    stats.threads.record_current();
+    run_rx_loop(
+        &mut rx,
+        socket.umem(),
+        &mut builder,
+        &sender,
+        exit,
+        config.fill_tuning,
+        &stats,
+    );
+}
+
+/// Receives on all of `queues` from a single thread, for when there aren't enough CPUs to dedicate
+/// one to each queue.
+///
+/// Sockets are polled in turn while any of them has packets. Once they're all idle the thread
+/// sleeps in `epoll_wait()` until packets arrive. Each queue gets its own stats, taken from
+/// `configs`, which must have one entry per queue.
+pub fn multiplexed_rx_loop(
+    cpu_id: Option<usize>,
+    queues: &[(&NetworkDevice, QueueId)],
+    zero_copy: bool,
+    sender: Sender<PacketBatch>,
+    exit: &AtomicBool,
+    configs: Vec<RxLoopConfig>,
+) {
+    assert_eq!(queues.len(), configs.len());
+    log::info!(
+        "starting multiplexed xdp rx loop on {} queues cpu {cpu_id:?}",
+        queues.len()
+    );
+    if let Some(cpu_id) = cpu_id {
+        pin_thread("xdp-rx", [cpu_id]).unwrap();
+    }
+
+    let mut opened = Vec::with_capacity(queues.len());
+    let mut memories = Vec::with_capacity(queues.len());
+    for ((dev, queue_id), config) in queues.iter().zip(&configs) {
+        let (queue, rx_size, memory) = open_rx_queue(dev, *queue_id, config.max_outstanding);
+        opened.push((queue, rx_size));
+        memories.push(memory);
+    }
+    let mut sockets = opened
+        .into_iter()
+        .zip(&memories)
+        .map(|((queue, rx_size), memory)| create_rx_socket(queue, memory, rx_size, zero_copy))
+        .collect::<Vec<_>>();
+    for ((socket, _), ((dev, queue_id), config)) in sockets.iter().zip(queues.iter().zip(&configs))
+    {
+        if let Some(filter) = &config.filter {
+            filter
+                .register_interface_socket(dev.if_index(), *queue_id, socket)
+                .unwrap_or_else(|e| panic!("failed to register AF_XDP socket: {e}"));
+        }
+    }
+    let mut builders = memories
+        .iter()
+        .zip(queues.iter().zip(&configs))
+        .map(|(memory, ((_, queue_id), config))| {
+            let builder = RxBatchBuilder::new(
+                Arc::clone(memory),
+                config.max_outstanding,
+                config.verify_udp_checksum,
+            );
+            match &config.capture {
+                Some(capture) => builder.with_capture(capture.recorder(Direction::Rx, *queue_id)),
+                None => builder,
+            }
+        })
+        .collect::<Vec<_>>();
+    let stats = configs
+        .iter()
+        .map(|config| config.stats.clone().unwrap_or_default())
+        .collect::<Vec<_>>();
+    for stats in &stats {
+        stats.threads.record_current();
+    }
+
+    let rx_queues = sockets
+        .iter_mut()
+        .zip(&mut builders)
+        .zip(configs.iter().zip(&stats))
+        .map(|(((socket, rx), builder), (config, stats))| {
+            RxQueue::new(rx, socket.umem(), builder, config.fill_tuning, stats)
+        })
+        .collect();
+    run_multiplexed_rx_loop(rx_queues, &sender, exit);
+}
+
+// Opens `queue_id` of `dev` and allocates enough memory for its UMEM.
+fn open_rx_queue(
+    dev: &NetworkDevice,
+    queue_id: QueueId,
+    max_outstanding: usize,
+) -> (DeviceQueue, usize, Arc<SharedUmemMemory>) {
+    // some drivers require frame_size=page_size
+    let frame_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+
+    let queue = dev
+        .open_queue(queue_id)
+        .expect("failed to open queue for AF_XDP socket");
+    let RingSizes { rx: rx_size, .. } = queue.ring_sizes().unwrap_or_else(|| {
+        log::info!(
+            "using default ring sizes for {} queue {queue_id:?}",
+            dev.name()
+        );
+        RingSizes::default()
+    });
+
+    // enough frames to keep the fill ring full while packets are outstanding
+    let frame_count = (rx_size * 2 + max_outstanding).next_power_of_two();
+    let memory = PageAlignedMemory::alloc(frame_size, frame_count)
+        .unwrap_or_else(|e| panic!("failed to allocate UMEM: {e}"));
+    (queue, rx_size, SharedUmemMemory::new(memory))
+}
+
+fn create_rx_socket(
+    queue: DeviceQueue,
+    memory: &SharedUmemMemory,
+    rx_size: usize,
+    zero_copy: bool,
+) -> (Socket<SliceUmem<'_>>, Rx<SliceUmemFrame<'_>>) {
+    let frame_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+    // Safety: frames are only reused once the batch builder recycles them
+    let umem = SliceUmem::new(unsafe { memory.as_mut_slice() }, frame_size as u32).unwrap();
+
+    // we need NET_ADMIN and NET_RAW for the socket
+    for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
+        caps::raise(None, CapSet::Effective, cap).unwrap();
+    }
+
+    let (socket, rx) = Socket::rx(queue, umem, zero_copy, rx_size * 2, rx_size)
+        .unwrap_or_else(|e| panic!("failed to create AF_XDP socket: {e}"));
+
+    // we don't need higher caps anymore
+    for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
+        caps::drop(None, CapSet::Effective, cap).unwrap();
+    }
+    (socket, rx)
+}
+
+/// Receives packets until `exit` is set or the receiving end of `sender` is dropped.
+///
+/// This is split out of [`rx_loop`] so that it can be driven by the
+/// [simulation backend](crate::sim) as well as by a real socket.
+pub(crate) fn run_rx_loop<U: Umem>(
+    rx: &mut Rx<U::Frame>,
+    umem: &mut U,
+    builder: &mut RxBatchBuilder,
+    sender: &Sender<PacketBatch>,
+    exit: &AtomicBool,
+    fill_tuning: Option<FillTuning>,
+    stats: &RxQueueStats,
+) {
+    let mut queue = RxQueue::new(rx, umem, builder, fill_tuning, stats);
+    while !exit.load(Ordering::Relaxed) {
+        if queue.poll(sender).is_none() {
+            break;
+        }
+    }
+}
+
+/// Receives packets on all of `queues` until `exit` is set or the receiving end of `sender` is
+/// dropped.
+pub(crate) fn run_multiplexed_rx_loop<U: Umem>(
+    mut queues: Vec<RxQueue<'_, U>>,
+    sender: &Sender<PacketBatch>,
+    exit: &AtomicBool,
+) {
+    // wake up now and then to recycle frames released by the consumers of the batches
+    const POLL_TIMEOUT_MS: i32 = 10;
+
+    // Safety: libc wrapper
+    let epoll = unsafe { epoll_create1(EPOLL_CLOEXEC) };
+    assert!(
+        epoll >= 0,
+        "failed to create epoll instance: {}",
+        io::Error::last_os_error()
+    );
+    // Safety: epoll_create1 returns a file descriptor
+    let epoll = unsafe { OwnedFd::from_raw_fd(epoll) };
+    for (i, queue) in queues.iter().enumerate() {
+        let Some(fd) = queue.rx.ring.as_ref().map(RxRing::fd) else {
+            continue;
+        };
+        let mut event = epoll_event {
+            events: EPOLLIN as u32,
+            u64: i as u64,
+        };
+        // Safety: libc wrapper
+        if unsafe { epoll_ctl(epoll.as_raw_fd(), EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+            // not a socket, e.g. the simulation backend. We'll wake up on the timeout.
+            log::debug!(
+                "failed to register rx socket with epoll: {}",
+                io::Error::last_os_error()
+            );
+        }
+    }
+
+    let mut events = vec![epoll_event { events: 0, u64: 0 }; queues.len().max(1)];
+    while !exit.load(Ordering::Relaxed) {
+        let mut received = 0;
+        for queue in queues.iter_mut() {
+            let Some(frames) = queue.poll(sender) else {
+                return;
+            };
+            received += frames;
+        }
+        if received > 0 {
+            continue;
+        }
+
+        // Safety: libc wrapper, events has room for events.len() entries
+        let ready = unsafe {
+            epoll_wait(
+                epoll.as_raw_fd(),
+                events.as_mut_ptr(),
+                events.len() as i32,
+                POLL_TIMEOUT_MS,
+            )
+        };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                log::error!("epoll_wait failed: {e}");
+                thread::sleep(Duration::from_millis(POLL_TIMEOUT_MS as u64));
+            }
+        }
+    }
+}
+
+// The state of a socket driven by an rx loop.
+pub(crate) struct RxQueue<'a, U: Umem> {
+    rx: &'a mut Rx<U::Frame>,
+    umem: &'a mut U,
+    builder: &'a mut RxBatchBuilder,
+    stats: &'a RxQueueStats,
+    poller: StatisticsPoller,
+    tuner: Option<FillTuner>,
+}
+
+impl<'a, U: Umem> RxQueue<'a, U> {
+    pub(crate) fn new(
+        rx: &'a mut Rx<U::Frame>,
+        umem: &'a mut U,
+        builder: &'a mut RxBatchBuilder,
+        fill_tuning: Option<FillTuning>,
+        stats: &'a RxQueueStats,
+    ) -> Self {
+        let tuner = fill_tuning.map(|tuning| FillTuner::new(tuning, rx.fill.capacity()));
+        Self {
+            rx,
+            umem,
+            builder,
+            stats,
+            poller: StatisticsPoller::new(),
+            tuner,
+        }
+    }
+
+    // Refills the fill ring and sends what was received to `sender`. Returns the number of frames
+    // read from the rx ring, or None if there's nothing left to receive.
+    fn poll(&mut self, sender: &Sender<PacketBatch>) -> Option<usize> {
+        let Self {
+            rx,
+            umem,
+            builder,
+            stats,
+            poller,
+            tuner,
+        } = self;
+
+        builder.recycle(|offset| umem.release(offset));
+        let max_posted = tuner.as_ref().map_or(usize::MAX, FillTuner::target);
+        refill(&mut rx.fill, *umem, max_posted);
+        if rx.fill.needs_wakeup() {
+            if let Err(e) = rx.fill.wake() {
+                if !matches!(e.raw_os_error(), Some(libc::EAGAIN | libc::EBUSY)) {
+                    log::debug!("failed to wake up the driver: {e}");
+                }
+            }
+        }
+
+        let ring = rx.ring.as_mut()?;
+        stats
+            .rings
+            .fill
+            .sample(rx.fill.occupancy(), rx.fill.capacity());
+        stats.rings.rx.sample(ring.occupancy(), ring.capacity());
+        if poller.poll(|| ring.statistics(), &stats.socket, false) {
+            if let Some(tuner) = tuner.as_mut() {
+                let socket = stats.socket.load();
+                // both mean the kernel had nowhere to put a packet
+                tuner.observe(socket.rx_dropped + socket.rx_fill_ring_empty_descs);
+                stats
+                    .fill_target
+                    .store(tuner.target() as u64, Ordering::Relaxed);
+            }
+        }
+        ring.sync(true);
+        let mut frames = 0;
+        while let Some((offset, len)) = ring.read() {
+            // the builder counts and recycles invalid frames
+            let _ = builder.push(offset, len);
+            frames += 1;
+        }
+        ring.commit();
+
+        let builder_stats = builder.stats();
+        stats
+            .invalid
+            .store(builder_stats.invalid, Ordering::Relaxed);
+        stats
+            .oversized
+            .store(builder_stats.oversized, Ordering::Relaxed);
+        stats.copied.store(builder_stats.copied, Ordering::Relaxed);
+
+        let Some(batch) = builder.take_batch() else {
+            return Some(frames);
+        };
+        let len = batch.len() as u64;
+        match sender.try_send(batch) {
+            Ok(()) => {
+                stats.packets.fetch_add(len, Ordering::Relaxed);
+                stats.batches.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Full(_)) => {
+                stats.channel_full.fetch_add(len, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => return None,
+        }
+        Some(frames)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RxServiceConfig {
+    /// The interface to receive on. Defaults to the interface of the default route.
+    pub interface: Option<String>,
+    /// CPUs to run the rx loops on, one per NIC queue.
+    pub cpus: Vec<usize>,
+    /// The number of NIC queues to receive on, one per CPU by default. When there are more queues
+    /// than CPUs, the queues are spread over the CPUs and each thread multiplexes its queues, see
+    /// [`multiplexed_rx_loop`].
+    pub queues: Option<usize>,
+    pub zero_copy: bool,
+    /// Only receive the packets sent to these flows and pass everything else to the kernel, so
+    /// that the rest of the host keeps working on the interface. The flows can be changed later
+    /// through [`RxService::filter`]. When empty, no filtering program is attached.
+    pub flows: Vec<RxFlow>,
+    /// The capacity of the channel all the rx loops send their batches to.
+    pub channel_cap: usize,
+    pub rx_loop: RxLoopConfig,
+}
+
+impl RxServiceConfig {
+    const DEFAULT_CHANNEL_CAP: usize = 4096;
+
+    pub fn new(cpus: Vec<usize>) -> Self {
+        Self {
+            interface: None,
+            cpus,
+            queues: None,
+            zero_copy: false,
+            flows: Vec::new(),
+            channel_cap: Self::DEFAULT_CHANNEL_CAP,
+            rx_loop: RxLoopConfig::default(),
+        }
+    }
+}
+
+/// One rx loop per NIC queue, all sending their batches to the same channel.
+pub struct RxService {
+    threads: Vec<thread::JoinHandle<()>>,
+    stats: Vec<Arc<RxQueueStats>>,
+    // keeps the program attached for as long as we're receiving
+    ebpf: Option<aya::Ebpf>,
+    filter: Option<Arc<RxFilter>>,
+}
+
+impl RxService {
+    /// Starts one rx loop per configured CPU, receiving on queue 0, 1, ... in order, and returns
+    /// the receiving end of the channel they feed.
+    ///
+    /// If more queues than CPUs are configured, queue `i` is received on by the thread running on
+    /// CPU `i % cpus.len()`.
+    ///
+    /// The rx loops exit once `exit` is set or the receiver is dropped.
+    pub fn new(
+        config: RxServiceConfig,
+        exit: Arc<AtomicBool>,
+    ) -> Result<(Self, Receiver<PacketBatch>), Box<dyn Error>> {
+        // switch to higher caps while we setup XDP. We assume that an error in
+        // this function is irrecoverable so we don't try to drop on errors.
+        for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_BPF, CAP_PERFMON] {
+            caps::raise(None, CapSet::Effective, cap)
+                .map_err(|e| format!("failed to raise {cap:?} capability: {e}"))?;
+        }
+
+        let dev = Arc::new(match config.interface {
+            Some(interface) => NetworkDevice::new(interface)?,
+            None => NetworkDevice::new_from_default_route()?,
+        });
+
+        let (ebpf, filter) = if !config.flows.is_empty() {
+            let mut ebpf =
+                load_rx_program(&dev).map_err(|e| format!("failed to attach xdp program: {e}"))?;
+            let filter = RxFilter::new(&mut ebpf)?;
+            for flow in &config.flows {
+                filter.insert(*flow)?;
+            }
+            (Some(ebpf), Some(Arc::new(filter)))
+        } else if config.zero_copy {
+            let ebpf =
+                load_xdp_program(&dev).map_err(|e| format!("failed to attach xdp program: {e}"))?;
+            (Some(ebpf), None)
+        } else {
+            (None, None)
+        };
+
+        for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_BPF, CAP_PERFMON] {
+            caps::drop(None, CapSet::Effective, cap).unwrap();
+        }
+
+        let (sender, receiver) = crossbeam_channel::bounded(config.channel_cap);
+        let queues = config.queues.unwrap_or(config.cpus.len());
+        let stats = (0..queues)
+            .map(|_| Arc::new(RxQueueStats::default()))
+            .collect::<Vec<_>>();
+        let rx_loop_config = |queue: usize| RxLoopConfig {
+            stats: Some(Arc::clone(&stats[queue])),
+            filter: filter.clone(),
+            ..config.rx_loop.clone()
+        };
+        let mut threads = vec![];
+        let cpu_count = config.cpus.len();
+        for (i, cpu_id) in config.cpus.into_iter().enumerate().take(queues) {
+            let dev = Arc::clone(&dev);
+            let sender = sender.clone();
+            let exit = Arc::clone(&exit);
+            let zero_copy = config.zero_copy;
+            let thread = if queues > cpu_count {
+                let thread_queues = (i..queues).step_by(cpu_count).collect::<Vec<_>>();
+                let configs = thread_queues
+                    .iter()
+                    .map(|&queue| rx_loop_config(queue))
+                    .collect();
+                Builder::new()
+                    .name(format!("solXdpRx{i:02}"))
+                    .spawn(move || {
+                        let queues = thread_queues
+                            .into_iter()
+                            .map(|queue| (dev.as_ref(), QueueId(queue as u64)))
+                            .collect::<Vec<_>>();
+                        multiplexed_rx_loop(
+                            Some(cpu_id),
+                            &queues,
+                            zero_copy,
+                            sender,
+                            &exit,
+                            configs,
+                        )
+                    })
+            } else {
+                let rx_loop_config = rx_loop_config(i);
+                Builder::new()
+                    .name(format!("solXdpRx{i:02}"))
+                    .spawn(move || {
+                        rx_loop(
+                            cpu_id,
+                            &dev,
+                            QueueId(i as u64),
+                            zero_copy,
+                            sender,
+                            &exit,
+                            rx_loop_config,
+                        )
+                    })
+            };
+            threads.push(thread.unwrap());
+        }
+
+        Ok((
+            Self {
+                threads,
+                stats,
+                ebpf,
+                filter,
+            },
+            receiver,
+        ))
+    }
+
+    /// Returns the stats of each queue, indexed by queue id.
+    pub fn queue_stats(&self) -> &[Arc<RxQueueStats>] {
+        &self.stats
+    }
+
+    /// Returns the flows redirected to the rx loops, if [`RxServiceConfig::flows`] was set.
+    pub fn filter(&self) -> Option<&RxFilter> {
+        self.filter.as_deref()
+    }
+
+    /// Returns the id of the XDP program attached to the interface, if zero copy is enabled or
+    /// flows are filtered.
+    pub fn program_id(&self) -> Option<u32> {
+        xdp_program_id(self.ebpf.as_ref()?)
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        for handle in self.threads {
+            handle.join()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            packet::{
+                write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE,
+                IP_HEADER_SIZE, UDP_HEADER_SIZE,
+            },
+            sim::{veth_pair, SimSocket},
+        },
+        std::{net::Ipv4Addr, sync::Barrier, time::Duration},
+    };
+
+    fn udp_frame(src_port: u16, payload: &[u8]) -> Vec<u8> {
+        let src_ip = Ipv4Addr::new(10, 0, 0, 1);
+        let dst_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let len = payload.len();
+        let mut frame = vec![0u8; ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE + len];
+        frame[ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE..].copy_from_slice(payload);
+        write_eth_header(&mut frame, &[1; 6], &[2; 6]);
+        write_ip_header(
+            &mut frame[ETH_HEADER_SIZE..],
+            &src_ip,
+            &dst_ip,
+            (UDP_HEADER_SIZE + len) as u16,
+        );
+        write_udp_header(
+            &mut frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &src_ip,
+            src_port,
+            &dst_ip,
+            9000,
+            len as u16,
+            true,
+        );
+        frame
+    }
+
+    #[test]
+    fn test_run_rx_loop_fan_in() {
+        const FRAME_SIZE: usize = 2048;
+        const QUEUES: usize = 2;
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let exit = AtomicBool::new(false);
+        let stats = (0..QUEUES)
+            .map(|_| RxQueueStats::default())
+            .collect::<Vec<_>>();
+
+        let ready = Barrier::new(QUEUES + 1);
+        thread::scope(|scope| {
+            let mut peers = vec![];
+            for queue_stats in &stats {
+                let (endpoint, peer) = veth_pair();
+                peers.push(peer);
+                let (sender, exit, ready) = (sender.clone(), &exit, &ready);
+                scope.spawn(move || {
+                    let memory =
+                        SharedUmemMemory::new(PageAlignedMemory::alloc(FRAME_SIZE, 16).unwrap());
+                    // Safety: frames are only reused once the builder recycles them
+                    let umem = SliceUmem::new(unsafe { memory.as_mut_slice() }, FRAME_SIZE as u32)
+                        .unwrap();
+                    let (mut socket, mut rx) = SimSocket::rx(umem, endpoint, 8, 8).unwrap();
+                    // don't drop packets sent before the loop gets to the socket
+                    refill(&mut rx.fill, socket.umem(), usize::MAX);
+                    ready.wait();
+                    let mut builder = RxBatchBuilder::new(Arc::clone(&memory), 8, true);
+                    run_rx_loop(
+                        &mut rx,
+                        socket.umem(),
+                        &mut builder,
+                        &sender,
+                        exit,
+                        Some(FillTuning::default()),
+                        queue_stats,
+                    );
+                });
+            }
+
+            ready.wait();
+
+            // each queue receives its own packets and they all come out of the same channel
+            for (i, peer) in peers.iter().enumerate() {
+                for _ in 0..3 {
+                    assert!(peer.send(udp_frame(8000 + i as u16, &[i as u8; 100])));
+                }
+            }
+            let mut received = vec![0; QUEUES];
+            while received.iter().sum::<usize>() < 3 * QUEUES {
+                let batch = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+                for packet in batch.iter() {
+                    let queue = packet.meta().socket_addr().port() as usize - 8000;
+                    assert_eq!(packet.data(..).unwrap(), &[queue as u8; 100]);
+                    received[queue] += 1;
+                }
+            }
+            assert_eq!(received, vec![3; QUEUES]);
+            exit.store(true, Ordering::Relaxed);
+        });
+
+        for queue_stats in &stats {
+            assert_eq!(queue_stats.packets.load(Ordering::Relaxed), 3);
+            assert_eq!(queue_stats.invalid.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    #[test]
+    fn test_run_multiplexed_rx_loop() {
+        const FRAME_SIZE: usize = 2048;
+        const QUEUES: usize = 3;
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let exit = AtomicBool::new(false);
+        let stats = (0..QUEUES)
+            .map(|_| RxQueueStats::default())
+            .collect::<Vec<_>>();
+        let (endpoints, peers): (Vec<_>, Vec<_>) = (0..QUEUES).map(|_| veth_pair()).unzip();
+
+        let ready = Barrier::new(2);
+        thread::scope(|scope| {
+            let (sender, exit, stats, ready) = (&sender, &exit, &stats, &ready);
+            // a single thread serves all the queues
+            scope.spawn(move || {
+                let memories = (0..QUEUES)
+                    .map(|_| {
+                        SharedUmemMemory::new(PageAlignedMemory::alloc(FRAME_SIZE, 16).unwrap())
+                    })
+                    .collect::<Vec<_>>();
+                let mut sockets = memories
+                    .iter()
+                    .zip(endpoints)
+                    .map(|(memory, endpoint)| {
+                        // Safety: frames are only reused once the builder recycles them
+                        let umem =
+                            SliceUmem::new(unsafe { memory.as_mut_slice() }, FRAME_SIZE as u32)
+                                .unwrap();
+                        let (mut socket, mut rx) = SimSocket::rx(umem, endpoint, 8, 8).unwrap();
+                        // don't drop packets sent before the loop gets to the socket
+                        refill(&mut rx.fill, socket.umem(), usize::MAX);
+                        (socket, rx)
+                    })
+                    .collect::<Vec<_>>();
+                ready.wait();
+                let mut builders = memories
+                    .iter()
+                    .map(|memory| RxBatchBuilder::new(Arc::clone(memory), 8, true))
+                    .collect::<Vec<_>>();
+                let queues = sockets
+                    .iter_mut()
+                    .zip(&mut builders)
+                    .zip(stats)
+                    .map(|(((socket, rx), builder), stats)| {
+                        RxQueue::new(rx, socket.umem(), builder, None, stats)
+                    })
+                    .collect();
+                run_multiplexed_rx_loop(queues, sender, exit);
+            });
+            ready.wait();
+
+            for (i, peer) in peers.iter().enumerate() {
+                for _ in 0..2 {
+                    assert!(peer.send(udp_frame(8000 + i as u16, &[i as u8; 50])));
+                }
+            }
+            let mut received = vec![0; QUEUES];
+            while received.iter().sum::<usize>() < 2 * QUEUES {
+                let batch = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+                for packet in batch.iter() {
+                    let queue = packet.meta().socket_addr().port() as usize - 8000;
+                    assert_eq!(packet.data(..).unwrap(), &[queue as u8; 50]);
+                    received[queue] += 1;
+                }
+            }
+            assert_eq!(received, vec![2; QUEUES]);
+            exit.store(true, Ordering::Relaxed);
+        });
+
+        for queue_stats in &stats {
+            assert_eq!(queue_stats.packets.load(Ordering::Relaxed), 2);
+        }
+    }
+
+    #[test]
+    fn test_fill_tuner() {
+        let mut tuner = FillTuner::new(
+            FillTuning {
+                min_frames: 100,
+                max_frames: 4096,
+                quiet_intervals: 2,
+            },
+            1024,
+        );
+        // starts out full, max_frames is clamped to the ring size
+        assert_eq!(tuner.target(), 1024);
+
+        // backs off while there are no drops
+        for _ in 0..2 {
+            tuner.observe(0);
+        }
+        assert_eq!(tuner.target(), 896);
+        for _ in 0..100 {
+            tuner.observe(0);
+        }
+        assert_eq!(tuner.target(), 100);
+
+        // ramps up as soon as there are drops
+        tuner.observe(5);
+        assert_eq!(tuner.target(), 200);
+        tuner.observe(5);
+        tuner.observe(8);
+        assert_eq!(tuner.target(), 400);
+        for drops in 9..20 {
+            tuner.observe(drops);
+        }
+        assert_eq!(tuner.target(), 1024);
+    }
+}
@@ -0,0 +1,817 @@
+//! Recording the scheduling decisions of the tx loop, and replaying them.
+//!
+//! With [`TxLoopConfig::schedule_log`](crate::tx_loop::TxLoopConfig::schedule_log) set, each tx
+//! loop writes a compact log of the batches it admits, the packets it writes to the ring and which
+//! channel they came from, its drops, its driver kicks and the completions it reaps.
+//! [`replay_schedule`] checks the invariants of the loop against a log, then feeds the recorded
+//! batches to the loop running over the [mock backend](crate::mock), with a driver completing
+//! frames as the recorded one did, and checks that the loop makes the same decisions. A pacing or
+//! priority bug seen once can thus be reproduced and stepped through deterministically.
+//!
+//! A log starts with a [`ScheduleHeader`], followed by the events. Each event is a tag byte, the
+//! microseconds since the previous event and its fields, all encoded as LEB128 varints.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::umem::FrameOffset,
+    std::{
+        cell::RefCell,
+        fs::File,
+        io::{self, BufWriter, ErrorKind, Read, Write},
+        path::Path,
+        time::{Duration, Instant},
+    },
+};
+#[cfg(any(test, feature = "test-utils"))]
+use {
+    crate::{
+        blocklist::DestinationBlocklist,
+        error::XdpError,
+        mock::{MockTxQueue, MockUmem},
+        tx_loop::{mock_tx_loop, TxLoopConfig},
+    },
+    std::{
+        cell::Cell,
+        collections::{HashSet, VecDeque},
+        mem,
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        ops::Range,
+        rc::Rc,
+        sync::{Arc, Mutex},
+    },
+    thiserror::Error,
+};
+
+const MAGIC: [u8; 4] = *b"axts";
+const VERSION: u8 = 1;
+
+const TAG_BATCH: u8 = 1;
+const TAG_ENQUEUED: u8 = 2;
+const TAG_DROPPED: u8 = 3;
+const TAG_KICK: u8 = 4;
+const TAG_COMPLETED: u8 = 5;
+
+/// A scheduling decision of the tx loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScheduleEvent {
+    /// A batch of packets was admitted from the channels.
+    Batch { packets: u32, priority_packets: u32 },
+    /// A packet was written to the ring, in the UMEM frame with this index.
+    Enqueued { frame: u32, priority: bool },
+    /// A packet was dropped.
+    Dropped,
+    /// The driver was kicked, `pending` packets after the previous kick.
+    Kick { pending: u32 },
+    /// The driver completed the UMEM frame with this index.
+    Completed { frame: u32 },
+}
+
+/// The setup of the recorded tx loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScheduleHeader {
+    pub frame_size: u32,
+    pub frame_count: u32,
+    pub ring_size: u32,
+}
+
+/// Writes a schedule log.
+pub struct ScheduleWriter<W: Write> {
+    writer: W,
+    // when the previous event happened, relative to the start of the log
+    last: Duration,
+}
+
+impl<W: Write> ScheduleWriter<W> {
+    /// Creates a new writer and writes the header.
+    pub fn new(mut writer: W, header: ScheduleHeader) -> io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        for value in [header.frame_size, header.frame_count, header.ring_size] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(Self {
+            writer,
+            last: Duration::ZERO,
+        })
+    }
+
+    /// Writes an event that happened `elapsed` after the start of the log.
+    pub fn write_event(&mut self, elapsed: Duration, event: ScheduleEvent) -> io::Result<()> {
+        let delta = elapsed.saturating_sub(self.last).as_micros() as u64;
+        self.last = elapsed;
+        let mut buf = [0u8; 32];
+        let (tag, fields): (u8, &[u64]) = match event {
+            ScheduleEvent::Batch {
+                packets,
+                priority_packets,
+            } => (TAG_BATCH, &[packets as u64, priority_packets as u64]),
+            ScheduleEvent::Enqueued { frame, priority } => {
+                (TAG_ENQUEUED, &[frame as u64, priority as u64])
+            }
+            ScheduleEvent::Dropped => (TAG_DROPPED, &[]),
+            ScheduleEvent::Kick { pending } => (TAG_KICK, &[pending as u64]),
+            ScheduleEvent::Completed { frame } => (TAG_COMPLETED, &[frame as u64]),
+        };
+        buf[0] = tag;
+        let mut len = 1;
+        for value in [delta].iter().chain(fields) {
+            len += write_varint(&mut buf[len..], *value);
+        }
+        self.writer.write_all(&buf[..len])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads a schedule log.
+pub struct ScheduleReader<R: Read> {
+    reader: R,
+    header: ScheduleHeader,
+    elapsed: Duration,
+}
+
+impl<R: Read> ScheduleReader<R> {
+    /// Creates a new reader and parses the header.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 17];
+        reader.read_exact(&mut header)?;
+        if header[..4] != MAGIC || header[4] != VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "not a tx schedule log",
+            ));
+        }
+        let u32_at = |offset: usize| u32::from_le_bytes(header[offset..][..4].try_into().unwrap());
+        Ok(Self {
+            reader,
+            header: ScheduleHeader {
+                frame_size: u32_at(5),
+                frame_count: u32_at(9),
+                ring_size: u32_at(13),
+            },
+            elapsed: Duration::ZERO,
+        })
+    }
+
+    pub fn header(&self) -> ScheduleHeader {
+        self.header
+    }
+
+    /// Reads the next event and when it happened, relative to the start of the log. Returns
+    /// `None` at the end of the log.
+    pub fn read_event(&mut self) -> io::Result<Option<(Duration, ScheduleEvent)>> {
+        let mut tag = [0u8; 1];
+        match self.reader.read_exact(&mut tag) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let delta = self.read_varint()?;
+        self.elapsed += Duration::from_micros(delta);
+        let event = match tag[0] {
+            TAG_BATCH => ScheduleEvent::Batch {
+                packets: self.read_u32()?,
+                priority_packets: self.read_u32()?,
+            },
+            TAG_ENQUEUED => ScheduleEvent::Enqueued {
+                frame: self.read_u32()?,
+                priority: self.read_varint()? != 0,
+            },
+            TAG_DROPPED => ScheduleEvent::Dropped,
+            TAG_KICK => ScheduleEvent::Kick {
+                pending: self.read_u32()?,
+            },
+            TAG_COMPLETED => ScheduleEvent::Completed {
+                frame: self.read_u32()?,
+            },
+            tag => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid schedule event {tag}"),
+                ))
+            }
+        };
+        Ok(Some((self.elapsed, event)))
+    }
+
+    fn read_u32(&mut self) -> io::Result<u32> {
+        u32::try_from(self.read_varint()?)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "schedule field out of range"))
+    }
+
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0u8; 1];
+            self.reader.read_exact(&mut byte)?;
+            value |= u64::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(io::Error::new(ErrorKind::InvalidData, "varint too long"))
+    }
+}
+
+impl<R: Read> Iterator for ScheduleReader<R> {
+    type Item = io::Result<(Duration, ScheduleEvent)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_event().transpose()
+    }
+}
+
+// Writes `value` as a LEB128 varint and returns how many bytes it took.
+fn write_varint(buf: &mut [u8], mut value: u64) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            return len + 1;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+type LogWriter = ScheduleWriter<BufWriter<Box<dyn Write + Send>>>;
+
+/// Records the schedule of a tx loop.
+///
+/// The tracer and the kicker of the loop both record into it, hence the shared reference. A
+/// failed write is logged and stops the recording.
+pub struct ScheduleRecorder {
+    writer: RefCell<Option<LogWriter>>,
+    frame_size: usize,
+    start: Instant,
+}
+
+impl ScheduleRecorder {
+    pub fn new(writer: Box<dyn Write + Send>, header: ScheduleHeader) -> io::Result<Self> {
+        Ok(Self {
+            writer: RefCell::new(Some(ScheduleWriter::new(BufWriter::new(writer), header)?)),
+            frame_size: header.frame_size as usize,
+            start: Instant::now(),
+        })
+    }
+
+    /// Creates a recorder writing to a new file at `path`.
+    pub fn create(path: &Path, header: ScheduleHeader) -> io::Result<Self> {
+        Self::new(Box::new(File::create(path)?), header)
+    }
+
+    pub(crate) fn record(&self, event: ScheduleEvent) {
+        let mut writer = self.writer.borrow_mut();
+        if let Some(w) = writer.as_mut() {
+            if let Err(e) = w.write_event(self.start.elapsed(), event) {
+                log::warn!("failed to write tx schedule, stopping the recording: {e}");
+                *writer = None;
+            }
+        }
+    }
+
+    pub(crate) fn frame(&self, offset: FrameOffset) -> u32 {
+        (offset.0 / self.frame_size) as u32
+    }
+}
+
+impl Drop for ScheduleRecorder {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.get_mut().as_mut() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+/// What a replayed schedule did.
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScheduleReplay {
+    pub batches: usize,
+    pub packets: usize,
+    pub priority_packets: usize,
+    pub dropped: usize,
+    pub kicks: usize,
+    pub completed: usize,
+    /// The most packets waiting for completion at any point.
+    pub max_in_flight: usize,
+    /// When the last event happened.
+    pub duration: Duration,
+}
+
+/// Why a schedule failed to replay. `event` is the index of the offending event in the log.
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    #[error("failed to read schedule: {0}")]
+    Io(#[from] io::Error),
+    #[error("failed to create router: {0}")]
+    Router(#[from] XdpError),
+    #[error("event {event}: frame {frame} doesn't exist")]
+    InvalidFrame { event: usize, frame: u32 },
+    #[error("event {event}: frame {frame} enqueued while in flight")]
+    FrameInFlight { event: usize, frame: u32 },
+    #[error("event {event}: frame {frame} completed, expected {expected:?}")]
+    UnexpectedCompletion {
+        event: usize,
+        frame: u32,
+        expected: Option<u32>,
+    },
+    #[error("event {event}: priority packet enqueued after a normal one in the same batch")]
+    PriorityInversion { event: usize },
+    #[error("event {event}: packet enqueued outside of a batch")]
+    OutsideBatch { event: usize },
+    #[error("event {event}: the tx loop replayed {expected:?} as {actual:?}")]
+    Diverged {
+        event: usize,
+        expected: ScheduleEvent,
+        actual: Option<ScheduleEvent>,
+    },
+    #[error("event {event}: the tx loop found no room for the packet")]
+    NoRoom { event: usize },
+}
+
+/// Replays the schedule read by `reader` through the tx loop, running with the kick, retry and
+/// idle policies of `config` over a [`MockUmem`] and a [`MockTxQueue`] set up like the recorded
+/// loop.
+///
+/// The log is checked first: frames are expected to complete in the order they were enqueued,
+/// and priority packets to be written before the normal ones of their batch. The loop is then fed
+/// the packets of each recorded batch once it's done with the previous one, and its driver
+/// completes no more frames than the recorded one had by the time each packet was written or
+/// dropped. The loop must write and drop the same packets as the recorded one, in the same
+/// order. Packets dropped by the recorded loop are sent to a blocked destination, so the replay
+/// doesn't tell a full ring from the other reasons, and the packets dropped before they made it
+/// into a batch aren't replayed.
+#[cfg(any(test, feature = "test-utils"))]
+pub fn replay_schedule<R: Read>(
+    reader: ScheduleReader<R>,
+    config: &TxLoopConfig,
+) -> Result<ScheduleReplay, ScheduleError> {
+    let header = reader.header();
+    let recorded = check_schedule(reader)?;
+    let log = run_schedule(header, &recorded, config)?;
+    let replayed = check_schedule(ScheduleReader::new(&log[..])?)?;
+
+    for (i, expected) in recorded.outcomes.iter().enumerate() {
+        let actual = replayed.outcomes.get(i).map(|outcome| outcome.event);
+        let same = match (expected.event, actual) {
+            (
+                ScheduleEvent::Enqueued { priority, .. },
+                Some(ScheduleEvent::Enqueued {
+                    priority: replayed, ..
+                }),
+            ) => priority == replayed,
+            (ScheduleEvent::Dropped, Some(ScheduleEvent::Dropped)) => true,
+            _ => false,
+        };
+        if !same {
+            return Err(ScheduleError::Diverged {
+                event: expected.index,
+                expected: expected.event,
+                actual,
+            });
+        }
+    }
+    Ok(recorded.replay)
+}
+
+// What the recorded loop did with a packet of a batch.
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Clone, Copy)]
+struct Outcome {
+    // the index of the event in the log
+    index: usize,
+    // Enqueued or Dropped
+    event: ScheduleEvent,
+    priority: bool,
+    // how many frames had completed by then
+    completed: usize,
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+struct CheckedSchedule {
+    replay: ScheduleReplay,
+    outcomes: Vec<Outcome>,
+    // the outcomes of each batch
+    batches: Vec<Range<usize>>,
+}
+
+// Checks the invariants of the loop against the schedule read by `reader`, and collects what it
+// did with the packets of each batch.
+#[cfg(any(test, feature = "test-utils"))]
+fn check_schedule<R: Read>(reader: ScheduleReader<R>) -> Result<CheckedSchedule, ScheduleError> {
+    let frame_count = reader.header().frame_count;
+    let mut in_flight = HashSet::new();
+    let mut order = VecDeque::new();
+    let mut normal_in_batch = false;
+    let mut replay = ScheduleReplay::default();
+    let mut outcomes = Vec::new();
+    let mut batches: Vec<Range<usize>> = Vec::new();
+    // the packets of the current batch not written or dropped yet, and how many are priority
+    // packets
+    let mut batch_remaining = 0;
+    let mut batch_priority = 0;
+
+    for (event_index, event) in reader.enumerate() {
+        let (elapsed, event) = event?;
+        replay.duration = elapsed;
+        match event {
+            ScheduleEvent::Batch {
+                packets,
+                priority_packets,
+            } => {
+                replay.batches += 1;
+                normal_in_batch = false;
+                batch_remaining = packets as usize;
+                batch_priority = priority_packets as usize;
+                batches.push(outcomes.len()..outcomes.len());
+            }
+            ScheduleEvent::Enqueued { frame, priority } => {
+                if batch_remaining == 0 {
+                    return Err(ScheduleError::OutsideBatch { event: event_index });
+                }
+                if priority && normal_in_batch {
+                    return Err(ScheduleError::PriorityInversion { event: event_index });
+                }
+                normal_in_batch |= !priority;
+                if frame >= frame_count {
+                    return Err(ScheduleError::InvalidFrame {
+                        event: event_index,
+                        frame,
+                    });
+                }
+                if !in_flight.insert(frame) {
+                    return Err(ScheduleError::FrameInFlight {
+                        event: event_index,
+                        frame,
+                    });
+                }
+                order.push_back(frame);
+                replay.packets += 1;
+                replay.priority_packets += usize::from(priority);
+                replay.max_in_flight = replay.max_in_flight.max(order.len());
+            }
+            ScheduleEvent::Dropped => replay.dropped += 1,
+            ScheduleEvent::Kick { .. } => replay.kicks += 1,
+            ScheduleEvent::Completed { frame } => {
+                let expected = order.front().copied();
+                if expected != Some(frame) {
+                    return Err(ScheduleError::UnexpectedCompletion {
+                        event: event_index,
+                        frame,
+                        expected,
+                    });
+                }
+                order.pop_front();
+                in_flight.remove(&frame);
+                replay.completed += 1;
+            }
+        }
+        // drops outside of a batch happen before the packets are admitted
+        if matches!(
+            event,
+            ScheduleEvent::Enqueued { .. } | ScheduleEvent::Dropped
+        ) && batch_remaining > 0
+        {
+            let batch = batches.last_mut().unwrap();
+            outcomes.push(Outcome {
+                index: event_index,
+                event,
+                priority: batch.len() < batch_priority,
+                completed: replay.completed,
+            });
+            batch.end += 1;
+            batch_remaining -= 1;
+        }
+    }
+    Ok(CheckedSchedule {
+        replay,
+        outcomes,
+        batches,
+    })
+}
+
+// Runs the tx loop over the mocks, feeding it the batches of `recorded` and letting the driver
+// complete frames as the recorded one did. Returns the schedule of the replay.
+#[cfg(any(test, feature = "test-utils"))]
+fn run_schedule(
+    header: ScheduleHeader,
+    recorded: &CheckedSchedule,
+    config: &TxLoopConfig,
+) -> Result<Vec<u8>, ScheduleError> {
+    // where the packets written and dropped by the recorded loop are sent
+    const SENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DROPPED: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+    // how many times the loop may kick the driver for the same packet without getting a frame
+    // when it never gives up
+    const MAX_FUTILE_KICKS: usize = 100;
+
+    let ScheduleHeader {
+        frame_size,
+        frame_count,
+        ring_size,
+    } = header;
+    let mut umem = MockUmem::new(frame_size as usize, frame_count as usize);
+    // the driver consumes descriptors before completing them, so up to the whole UMEM can be in
+    // flight whatever the size of the ring
+    let mut queue = MockTxQueue::new(ring_size.max(frame_count) as usize);
+
+    let blocklist = DestinationBlocklist::new();
+    blocklist.block(DROPPED);
+    let config = TxLoopConfig {
+        kick: config.kick,
+        retry: config.retry,
+        idle: config.idle,
+        blocklist: Some(blocklist),
+        ..TxLoopConfig::default()
+    };
+    let retry_forever = config.retry.max_retries.is_none();
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let (priority_sender, priority_receiver) = crossbeam_channel::unbounded();
+    let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+    let outcomes = recorded.outcomes.clone();
+    let mut batches = recorded.batches.clone().into_iter();
+    let mut senders = Some((sender, priority_sender));
+    // the packets fed to the loop, and the frames the driver completed
+    let mut fed = 0;
+    let mut transmitted = 0;
+    // the packet the loop last kicked the driver for without getting a frame, and how many times
+    let mut futile = (0, 0);
+    let stuck = Rc::new(Cell::new(None));
+    queue.on_kick({
+        let stuck = stuck.clone();
+        move |committed| {
+            // the packets the loop wrote or dropped, it hands them back once done
+            let decided = drop_receiver.len();
+            if decided == fed {
+                match batches.next() {
+                    Some(batch) => {
+                        let (sender, priority_sender) = senders.as_ref().unwrap();
+                        for outcome in &outcomes[batch.clone()] {
+                            let ip = match outcome.event {
+                                ScheduleEvent::Dropped => DROPPED,
+                                _ => SENT,
+                            };
+                            let sender = if outcome.priority {
+                                priority_sender
+                            } else {
+                                sender
+                            };
+                            let packet = ([SocketAddr::V4(SocketAddrV4::new(ip, 8000))], vec![]);
+                            sender.send(packet).unwrap();
+                        }
+                        fed = batch.end;
+                    }
+                    // let the loop exit
+                    None => senders = None,
+                }
+            }
+            if decided >= outcomes.len() || stuck.get().is_some() {
+                return committed;
+            }
+
+            let frames = outcomes[decided]
+                .completed
+                .saturating_sub(transmitted)
+                .min(committed);
+            transmitted += frames;
+            if frames > 0 || futile.0 != decided {
+                futile = (decided, 0);
+            } else {
+                futile.1 += 1;
+                if retry_forever && futile.1 > MAX_FUTILE_KICKS {
+                    // the loop would wait forever, let it go
+                    stuck.set(Some(outcomes[decided].index));
+                }
+            }
+            frames
+        }
+    });
+
+    let log = SharedLog::default();
+    let schedule = ScheduleRecorder::new(Box::new(log.clone()), header)?;
+    mock_tx_loop(
+        &mut queue,
+        &mut umem,
+        receiver,
+        Some(priority_receiver),
+        drop_sender,
+        &config,
+        Some(&schedule),
+    )?;
+    drop(schedule);
+    if let Some(event) = stuck.get() {
+        return Err(ScheduleError::NoRoom { event });
+    }
+    let log = mem::take(&mut *log.0.lock().unwrap());
+    Ok(log)
+}
+
+// Keeps a schedule log in memory.
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Clone, Default)]
+struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(any(test, feature = "test-utils"))]
+impl Write for SharedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::tx_loop::TxRetryPolicy};
+
+    const HEADER: ScheduleHeader = ScheduleHeader {
+        frame_size: 4096,
+        frame_count: 4,
+        ring_size: 2,
+    };
+
+    fn log(events: &[ScheduleEvent]) -> Vec<u8> {
+        let mut writer = ScheduleWriter::new(Vec::new(), HEADER).unwrap();
+        for (i, event) in events.iter().enumerate() {
+            writer
+                .write_event(Duration::from_micros(i as u64 * 300), *event)
+                .unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_schedule_roundtrip_replay() {
+        let events = [
+            ScheduleEvent::Batch {
+                packets: 4,
+                priority_packets: 1,
+            },
+            ScheduleEvent::Enqueued {
+                frame: 0,
+                priority: true,
+            },
+            ScheduleEvent::Kick { pending: 1 },
+            ScheduleEvent::Enqueued {
+                frame: 1,
+                priority: false,
+            },
+            ScheduleEvent::Enqueued {
+                frame: 2,
+                priority: false,
+            },
+            ScheduleEvent::Dropped,
+            ScheduleEvent::Kick { pending: 2 },
+            ScheduleEvent::Completed { frame: 0 },
+            ScheduleEvent::Completed { frame: 1 },
+            ScheduleEvent::Batch {
+                packets: 1,
+                priority_packets: 1,
+            },
+            // frame 0 is free again
+            ScheduleEvent::Enqueued {
+                frame: 0,
+                priority: true,
+            },
+            ScheduleEvent::Completed { frame: 2 },
+            ScheduleEvent::Completed { frame: 0 },
+        ];
+        let buf = log(&events);
+
+        let reader = ScheduleReader::new(&buf[..]).unwrap();
+        assert_eq!(reader.header(), HEADER);
+        let read = reader.collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            read.iter().map(|(_, event)| *event).collect::<Vec<_>>(),
+            events
+        );
+        assert_eq!(read[12].0, Duration::from_micros(3600));
+
+        assert_eq!(
+            replay_schedule(
+                ScheduleReader::new(&buf[..]).unwrap(),
+                &TxLoopConfig::default()
+            )
+            .unwrap(),
+            ScheduleReplay {
+                batches: 2,
+                packets: 4,
+                priority_packets: 2,
+                dropped: 1,
+                kicks: 2,
+                completed: 4,
+                max_in_flight: 3,
+                duration: Duration::from_micros(3600),
+            }
+        );
+    }
+
+    #[test]
+    fn test_replay_diverged() {
+        let normal = |frame| ScheduleEvent::Enqueued {
+            frame,
+            priority: false,
+        };
+        // the fifth packet is written once the first frame completes
+        let buf = log(&[
+            ScheduleEvent::Batch {
+                packets: 5,
+                priority_packets: 0,
+            },
+            normal(0),
+            normal(1),
+            normal(2),
+            normal(3),
+            ScheduleEvent::Completed { frame: 0 },
+            normal(0),
+        ]);
+        let replay =
+            |config: &TxLoopConfig| replay_schedule(ScheduleReader::new(&buf[..]).unwrap(), config);
+
+        assert_eq!(replay(&TxLoopConfig::default()).unwrap().packets, 5);
+        // a loop that doesn't wait for room drops it
+        let config = TxLoopConfig {
+            retry: TxRetryPolicy {
+                max_retries: Some(0),
+                ..TxRetryPolicy::default()
+            },
+            ..TxLoopConfig::default()
+        };
+        assert!(matches!(
+            replay(&config),
+            Err(ScheduleError::Diverged {
+                event: 6,
+                expected: ScheduleEvent::Enqueued { frame: 0, .. },
+                actual: Some(ScheduleEvent::Dropped),
+            })
+        ));
+    }
+
+    #[test]
+    fn test_replay_broken_invariants() {
+        let normal = |frame| ScheduleEvent::Enqueued {
+            frame,
+            priority: false,
+        };
+        let replay = |events: &[ScheduleEvent]| {
+            let batch = ScheduleEvent::Batch {
+                packets: events.len() as u32,
+                priority_packets: 1,
+            };
+            let buf = log(&[&[batch], events].concat());
+            replay_schedule(
+                ScheduleReader::new(&buf[..]).unwrap(),
+                &TxLoopConfig::default(),
+            )
+        };
+
+        assert!(matches!(
+            replay(&[
+                normal(0),
+                ScheduleEvent::Enqueued {
+                    frame: 1,
+                    priority: true
+                },
+            ]),
+            Err(ScheduleError::PriorityInversion { event: 2 })
+        ));
+        assert!(matches!(
+            replay(&[normal(0), normal(0)]),
+            Err(ScheduleError::FrameInFlight { event: 2, frame: 0 })
+        ));
+        assert!(matches!(
+            replay(&[normal(0), normal(1), ScheduleEvent::Completed { frame: 1 }]),
+            Err(ScheduleError::UnexpectedCompletion {
+                event: 3,
+                frame: 1,
+                expected: Some(0)
+            })
+        ));
+        assert!(matches!(
+            replay(&[normal(4)]),
+            Err(ScheduleError::InvalidFrame { event: 1, frame: 4 })
+        ));
+
+        let buf = log(&[normal(0)]);
+        assert!(matches!(
+            replay_schedule(
+                ScheduleReader::new(&buf[..]).unwrap(),
+                &TxLoopConfig::default()
+            ),
+            Err(ScheduleError::OutsideBatch { event: 0 })
+        ));
+    }
+}
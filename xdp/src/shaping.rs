@@ -0,0 +1,459 @@
+//! Rate limiting egress traffic per class, adjustable at runtime.
+//!
+//! A [`TrafficShaper`] holds a token bucket per [`TrafficClass`] plus a global one shared by all
+//! the classes. Each sender wraps its transport in a [`ShapedTransport`] tagged with its class, and
+//! operators keep a clone of the shaper to change the limits while traffic is flowing, e.g. to
+//! throttle repair egress during an incident without restarting the tx loops.
+//!
+//! Besides the built-in classes, integrators can register their own with
+//! [`TrafficShaper::register_class`], which get their own bucket and counters.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::transport::DatagramTransport,
+    serde::{Deserialize, Serialize},
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        fmt, io,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex, RwLock,
+        },
+        time::Instant,
+    },
+};
+
+/// The kinds of traffic that can be limited independently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrafficClass {
+    Turbine,
+    Repair,
+    Gossip,
+    Vote,
+    Other,
+    /// A class registered with [`TrafficShaper::register_class`]. Only meaningful to the shaper
+    /// it was registered with.
+    Custom(u16),
+}
+
+impl TrafficClass {
+    const COUNT: usize = 5;
+
+    /// The built-in classes.
+    pub const ALL: [Self; Self::COUNT] = [
+        Self::Turbine,
+        Self::Repair,
+        Self::Gossip,
+        Self::Vote,
+        Self::Other,
+    ];
+
+    /// The default 802.1p priority code point of the class, for switches to prioritize votes and
+    /// shreds over the rest of the traffic.
+    pub const fn pcp(self) -> u8 {
+        match self {
+            Self::Vote => 6,
+            Self::Turbine => 5,
+            Self::Repair => 4,
+            Self::Gossip => 3,
+            Self::Other | Self::Custom(_) => 0,
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Self::Turbine => 0,
+            Self::Repair => 1,
+            Self::Gossip => 2,
+            Self::Vote => 3,
+            Self::Other => 4,
+            Self::Custom(id) => Self::COUNT + id as usize,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Turbine => "turbine",
+            Self::Repair => "repair",
+            Self::Gossip => "gossip",
+            Self::Vote => "vote",
+            Self::Other => "other",
+            Self::Custom(_) => "custom",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RateLimit {
+    /// Sustained packets per second.
+    pub packets_per_second: u64,
+    /// Packets that can be sent back to back.
+    pub burst: u64,
+}
+
+struct BucketState {
+    limit: Option<RateLimit>,
+    tokens: f64,
+    last_update: Instant,
+}
+
+struct TokenBucket {
+    state: Mutex<BucketState>,
+    throttled: AtomicU64,
+}
+
+impl TokenBucket {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(BucketState {
+                limit: None,
+                tokens: 0.0,
+                last_update: Instant::now(),
+            }),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    fn set_limit(&self, limit: Option<RateLimit>) {
+        let mut state = self.state.lock().unwrap();
+        let burst = limit.map_or(0.0, |limit| limit.burst.max(1) as f64);
+        // start with a full bucket when turning the limit on, and don't let a smaller burst
+        // carry over the tokens accumulated under the old one
+        state.tokens = match state.limit {
+            Some(_) => state.tokens.min(burst),
+            None => burst,
+        };
+        state.limit = limit;
+        state.last_update = Instant::now();
+    }
+
+    fn limit(&self) -> Option<RateLimit> {
+        self.state.lock().unwrap().limit
+    }
+
+    fn try_acquire(&self, packets: u64, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(RateLimit {
+            packets_per_second,
+            burst,
+        }) = state.limit
+        else {
+            return true;
+        };
+        let elapsed = now.saturating_duration_since(state.last_update);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * packets_per_second as f64)
+            .min(burst.max(1) as f64);
+        state.last_update = now;
+        if state.tokens >= packets as f64 {
+            state.tokens -= packets as f64;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn refund(&self, packets: u64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(limit) = state.limit {
+            state.tokens = (state.tokens + packets as f64).min(limit.burst.max(1) as f64);
+        }
+    }
+}
+
+// The bucket and counters of a class.
+struct ClassState {
+    label: String,
+    bucket: TokenBucket,
+    sent: AtomicU64,
+}
+
+impl ClassState {
+    fn new(label: String) -> Self {
+        Self {
+            label,
+            bucket: TokenBucket::new(),
+            sent: AtomicU64::new(0),
+        }
+    }
+}
+
+struct ShaperInner {
+    global: TokenBucket,
+    // indexed by TrafficClass::index(), the built-in classes first
+    classes: RwLock<Vec<Arc<ClassState>>>,
+}
+
+/// Per class and global rate limits, shared by all the [`ShapedTransport`]s created from it.
+///
+/// Cloning returns a handle to the same limits. Nothing is limited until a limit is set.
+#[derive(Clone)]
+pub struct TrafficShaper {
+    inner: Arc<ShaperInner>,
+}
+
+impl fmt::Debug for TrafficShaper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let classes = self.inner.classes.read().unwrap();
+        f.debug_struct("TrafficShaper")
+            .field("global", &self.inner.global.limit())
+            .field(
+                "classes",
+                &classes
+                    .iter()
+                    .map(|state| (&state.label, state.bucket.limit()))
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl Default for TrafficShaper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TrafficShaper {
+    pub fn new() -> Self {
+        let classes = TrafficClass::ALL
+            .iter()
+            .map(|class| Arc::new(ClassState::new(class.label().to_owned())))
+            .collect();
+        Self {
+            inner: Arc::new(ShaperInner {
+                global: TokenBucket::new(),
+                classes: RwLock::new(classes),
+            }),
+        }
+    }
+
+    /// Registers a class of traffic the validator doesn't know about, with its own limit and
+    /// counters, and returns it. Registering a label again, including the label of a built-in
+    /// class, returns the same class.
+    ///
+    /// Panics if more than `u16::MAX` classes are registered.
+    pub fn register_class(&self, label: &str) -> TrafficClass {
+        let mut classes = self.inner.classes.write().unwrap();
+        if let Some(index) = classes.iter().position(|state| state.label == label) {
+            return self.class_at(index);
+        }
+        let id =
+            u16::try_from(classes.len() - TrafficClass::COUNT).expect("too many traffic classes");
+        classes.push(Arc::new(ClassState::new(label.to_owned())));
+        TrafficClass::Custom(id)
+    }
+
+    /// Returns all the classes, the built-in ones first.
+    pub fn classes(&self) -> Vec<TrafficClass> {
+        let count = self.inner.classes.read().unwrap().len();
+        (0..count).map(|index| self.class_at(index)).collect()
+    }
+
+    /// Returns the label of `class`, e.g. to name its metrics.
+    pub fn label(&self, class: TrafficClass) -> String {
+        self.class(class).label.clone()
+    }
+
+    fn class_at(&self, index: usize) -> TrafficClass {
+        TrafficClass::ALL
+            .get(index)
+            .copied()
+            .unwrap_or_else(|| TrafficClass::Custom((index - TrafficClass::COUNT) as u16))
+    }
+
+    // Panics if `class` wasn't registered with this shaper.
+    fn class(&self, class: TrafficClass) -> Arc<ClassState> {
+        self.inner
+            .classes
+            .read()
+            .unwrap()
+            .get(class.index())
+            .cloned()
+            .unwrap_or_else(|| panic!("{class:?} isn't registered with this shaper"))
+    }
+
+    /// Sets the limit of `class`, or removes it if `limit` is `None`. Takes effect right away.
+    pub fn set_limit(&self, class: TrafficClass, limit: Option<RateLimit>) {
+        self.class(class).bucket.set_limit(limit);
+    }
+
+    /// Sets the limit applied to all the classes together.
+    pub fn set_global_limit(&self, limit: Option<RateLimit>) {
+        self.inner.global.set_limit(limit);
+    }
+
+    pub fn limit(&self, class: TrafficClass) -> Option<RateLimit> {
+        self.class(class).bucket.limit()
+    }
+
+    pub fn global_limit(&self) -> Option<RateLimit> {
+        self.inner.global.limit()
+    }
+
+    /// Returns how many packets of `class` were dropped for being over a limit.
+    pub fn throttled(&self, class: TrafficClass) -> u64 {
+        self.class(class).bucket.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Returns how many packets of `class` were let through.
+    pub fn sent(&self, class: TrafficClass) -> u64 {
+        self.class(class).sent.load(Ordering::Relaxed)
+    }
+
+    /// Takes `packets` tokens from both the bucket of `class` and the global one, or none at all.
+    pub fn try_acquire(&self, class: TrafficClass, packets: u64) -> bool {
+        self.try_acquire_at(class, packets, Instant::now())
+    }
+
+    fn try_acquire_at(&self, class: TrafficClass, packets: u64, now: Instant) -> bool {
+        self.try_acquire_class(&self.class(class), packets, now)
+    }
+
+    fn try_acquire_class(&self, class: &ClassState, packets: u64, now: Instant) -> bool {
+        let bucket = &class.bucket;
+        let acquired = if bucket.try_acquire(packets, now) {
+            if self.inner.global.try_acquire(packets, now) {
+                true
+            } else {
+                bucket.refund(packets);
+                false
+            }
+        } else {
+            false
+        };
+        if acquired {
+            class.sent.fetch_add(packets, Ordering::Relaxed);
+        } else {
+            bucket.throttled.fetch_add(packets, Ordering::Relaxed);
+        }
+        acquired
+    }
+
+    /// Wraps `transport` so that everything sent through it counts as `class`.
+    pub fn shape<T>(&self, transport: T, class: TrafficClass) -> ShapedTransport<T> {
+        ShapedTransport {
+            transport,
+            class,
+            state: self.class(class),
+            shaper: self.clone(),
+        }
+    }
+}
+
+static TRAFFIC_SHAPER: RwLock<Option<TrafficShaper>> = RwLock::new(None);
+
+/// Makes `shaper` the process wide shaper, which the tx loops of the validator are limited by.
+pub fn install_traffic_shaper(shaper: TrafficShaper) {
+    *TRAFFIC_SHAPER.write().unwrap() = Some(shaper);
+}
+
+/// Returns the shaper installed with [`install_traffic_shaper`], if any.
+pub fn traffic_shaper() -> Option<TrafficShaper> {
+    TRAFFIC_SHAPER.read().unwrap().clone()
+}
+
+/// A transport whose datagrams are subject to the limits of a [`TrafficShaper`].
+///
+/// Datagrams over the limit are dropped and reported as [`io::ErrorKind::WouldBlock`]. A send to
+/// multiple destinations goes through either to all of them or to none.
+pub struct ShapedTransport<T> {
+    transport: T,
+    class: TrafficClass,
+    // looked up once rather than on every send
+    state: Arc<ClassState>,
+    shaper: TrafficShaper,
+}
+
+impl<T> ShapedTransport<T> {
+    pub fn class(&self) -> TrafficClass {
+        self.class
+    }
+
+    pub fn shaper(&self) -> &TrafficShaper {
+        &self.shaper
+    }
+}
+
+impl<T: DatagramTransport> DatagramTransport for ShapedTransport<T> {
+    fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+        if !self
+            .shaper
+            .try_acquire_class(&self.state, addrs.len() as u64, Instant::now())
+        {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.transport.send_to(payload, addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::Duration};
+
+    #[test]
+    fn test_traffic_shaper() {
+        let shaper = TrafficShaper::new();
+        let now = Instant::now();
+        assert!(shaper.try_acquire_at(TrafficClass::Repair, 1_000_000, now));
+
+        let limit = RateLimit {
+            packets_per_second: 100,
+            burst: 10,
+        };
+        shaper.set_limit(TrafficClass::Repair, Some(limit));
+        let now = Instant::now();
+        assert!(shaper.try_acquire_at(TrafficClass::Repair, 10, now));
+        assert!(!shaper.try_acquire_at(TrafficClass::Repair, 1, now));
+        assert_eq!(shaper.throttled(TrafficClass::Repair), 1);
+        // other classes aren't affected
+        assert!(shaper.try_acquire_at(TrafficClass::Turbine, 100, now));
+        // tokens come back over time
+        let later = now + Duration::from_millis(50);
+        assert!(shaper.try_acquire_at(TrafficClass::Repair, 5, later));
+        assert!(!shaper.try_acquire_at(TrafficClass::Repair, 1, later));
+
+        // lifting the limit at runtime
+        shaper.set_limit(TrafficClass::Repair, None);
+        assert!(shaper.try_acquire_at(TrafficClass::Repair, 100, later));
+
+        // the global limit applies to everything, and class tokens aren't wasted when it's hit
+        shaper.set_limit(TrafficClass::Gossip, Some(limit));
+        shaper.set_global_limit(Some(RateLimit {
+            packets_per_second: 0,
+            burst: 4,
+        }));
+        let now = Instant::now();
+        assert!(shaper.try_acquire_at(TrafficClass::Turbine, 4, now));
+        assert!(!shaper.try_acquire_at(TrafficClass::Gossip, 1, now));
+        shaper.set_global_limit(None);
+        assert!(shaper.try_acquire_at(TrafficClass::Gossip, 10, now));
+    }
+
+    #[test]
+    fn test_register_class() {
+        let shaper = TrafficShaper::new();
+        let bundles = shaper.register_class("bundles");
+        assert_eq!(bundles, TrafficClass::Custom(0));
+        assert_eq!(shaper.register_class("bundles"), bundles);
+        assert_eq!(shaper.register_class("vote"), TrafficClass::Vote);
+        assert_eq!(shaper.label(bundles), "bundles");
+        assert_eq!(shaper.classes().len(), TrafficClass::COUNT + 1);
+
+        shaper.set_limit(
+            bundles,
+            Some(RateLimit {
+                packets_per_second: 0,
+                burst: 2,
+            }),
+        );
+        let now = Instant::now();
+        assert!(shaper.try_acquire_at(bundles, 2, now));
+        assert!(!shaper.try_acquire_at(bundles, 1, now));
+        assert_eq!(shaper.sent(bundles), 2);
+        assert_eq!(shaper.throttled(bundles), 1);
+        // the built-in classes keep their own buckets
+        assert!(shaper.try_acquire_at(TrafficClass::Other, 10, now));
+        assert_eq!(shaper.sent(TrafficClass::Other), 10);
+    }
+}
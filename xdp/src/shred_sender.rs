@@ -0,0 +1,340 @@
+//! High level sender for turbine retransmit and broadcast.
+//!
+//! Jobs are a shred payload plus the destinations it needs to be sent to. Each job is queued to one
+//! of the tx loops, which copies the payload into the UMEM once and reuses it for all the
+//! destinations. Send stats are tracked per slot so that delivery problems can be attributed.
+
+use {
+    crate::{
+        device::{NetworkDevice, QueueId},
+        load_xdp_program,
+        tx_loop::{tx_loop, TxLoopConfig},
+    },
+    caps::{
+        CapSet,
+        Capability::{CAP_BPF, CAP_NET_ADMIN, CAP_NET_RAW, CAP_PERFMON},
+    },
+    crossbeam_channel::{Sender, TryRecvError, TrySendError},
+    std::{
+        collections::BTreeMap,
+        error::Error,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        thread::{self, Builder},
+        time::Duration,
+    },
+};
+
+// Slots are tracked until there are this many newer ones.
+const MAX_TRACKED_SLOTS: usize = 256;
+
+#[derive(Clone, Debug)]
+pub struct ShredSenderConfig {
+    /// The interface to send from. Defaults to the interface of the default route.
+    pub interface: Option<String>,
+    /// CPUs to run the tx loops on, one per NIC queue.
+    pub cpus: Vec<usize>,
+    pub zero_copy: bool,
+    pub src_port: u16,
+    /// The capacity of the channel in front of each tx loop.
+    pub channel_cap: usize,
+    pub tx_loop: TxLoopConfig,
+}
+
+impl ShredSenderConfig {
+    // A nice round number
+    const DEFAULT_CHANNEL_CAP: usize = 1_000_000;
+
+    pub fn new(cpus: Vec<usize>, src_port: u16) -> Self {
+        Self {
+            interface: None,
+            cpus,
+            zero_copy: false,
+            src_port,
+            channel_cap: Self::DEFAULT_CHANNEL_CAP,
+            tx_loop: TxLoopConfig::default(),
+        }
+    }
+}
+
+/// A shred payload queued for transmission.
+pub struct ShredJob<P> {
+    pub slot: u64,
+    pub payload: P,
+}
+
+impl<P: AsRef<[u8]>> AsRef<[u8]> for ShredJob<P> {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self.payload.as_ref()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SlotSendStats {
+    /// Packets handed to the NIC, counting each destination.
+    pub sent: u64,
+    /// Packets dropped because the tx loop couldn't keep up, counting each destination.
+    pub dropped: u64,
+}
+
+#[derive(Debug, Default)]
+struct SlotStatsMap {
+    slots: BTreeMap<u64, SlotSendStats>,
+}
+
+impl SlotStatsMap {
+    fn record(&mut self, slot: u64, f: impl FnOnce(&mut SlotSendStats)) {
+        if self.slots.len() >= MAX_TRACKED_SLOTS && !self.slots.contains_key(&slot) {
+            match self.slots.first_key_value() {
+                // too old to be tracked
+                Some((oldest, _)) if slot < *oldest => return,
+                _ => {
+                    self.slots.pop_first();
+                }
+            }
+        }
+        f(self.slots.entry(slot).or_default());
+    }
+
+    fn drain(&mut self) -> Vec<(u64, SlotSendStats)> {
+        std::mem::take(&mut self.slots).into_iter().collect()
+    }
+}
+
+type Job<P> = (Vec<SocketAddr>, ShredJob<P>);
+
+/// Handle used to queue shreds. Cheap to clone.
+pub struct ShredSender<P> {
+    senders: Vec<Sender<Job<P>>>,
+    stats: Arc<Mutex<SlotStatsMap>>,
+}
+
+impl<P> Clone for ShredSender<P> {
+    fn clone(&self) -> Self {
+        Self {
+            senders: self.senders.clone(),
+            stats: Arc::clone(&self.stats),
+        }
+    }
+}
+
+impl<P> ShredSender<P> {
+    /// Queues `payload` to be sent to all of `addrs`.
+    ///
+    /// `sender_index` selects the tx loop. Callers should spread load by using e.g. their thread
+    /// index.
+    #[inline]
+    pub fn try_send(
+        &self,
+        sender_index: usize,
+        slot: u64,
+        payload: P,
+        addrs: Vec<SocketAddr>,
+    ) -> Result<(), TrySendError<Job<P>>> {
+        let result = self.senders[sender_index % self.senders.len()]
+            .try_send((addrs, ShredJob { slot, payload }));
+        if let Err(TrySendError::Full((addrs, _)) | TrySendError::Disconnected((addrs, _))) =
+            &result
+        {
+            let dropped = addrs.len() as u64;
+            self.stats
+                .lock()
+                .unwrap()
+                .record(slot, |stats| stats.dropped += dropped);
+        }
+        result
+    }
+
+    /// Returns the stats of the slots that had activity since the last call, in slot order.
+    pub fn take_slot_stats(&self) -> Vec<(u64, SlotSendStats)> {
+        self.stats.lock().unwrap().drain()
+    }
+}
+
+/// The threads backing a [`ShredSender`].
+pub struct ShredSenderService {
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl ShredSenderService {
+    /// Starts one tx loop per configured CPU.
+    ///
+    /// The tx loops exit once all the [`ShredSender`] handles have been dropped.
+    pub fn new<P: AsRef<[u8]> + Send + 'static>(
+        config: ShredSenderConfig,
+    ) -> Result<(Self, ShredSender<P>), Box<dyn Error>> {
+        const DROP_CHANNEL_CAP: usize = 1_000_000;
+
+        // switch to higher caps while we setup XDP. We assume that an error in
+        // this function is irrecoverable so we don't try to drop on errors.
+        for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_BPF, CAP_PERFMON] {
+            caps::raise(None, CapSet::Effective, cap)
+                .map_err(|e| format!("failed to raise {cap:?} capability: {e}"))?;
+        }
+
+        let dev = Arc::new(match config.interface {
+            Some(interface) => NetworkDevice::new(interface)?,
+            None => NetworkDevice::new_from_default_route()?,
+        });
+
+        let ebpf = if config.zero_copy {
+            Some(load_xdp_program(&dev).map_err(|e| format!("failed to attach xdp program: {e}"))?)
+        } else {
+            None
+        };
+
+        for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_BPF, CAP_PERFMON] {
+            caps::drop(None, CapSet::Effective, cap).unwrap();
+        }
+
+        let (senders, receivers) = (0..config.cpus.len())
+            .map(|_| crossbeam_channel::bounded(config.channel_cap))
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+        let stats = Arc::new(Mutex::new(SlotStatsMap::default()));
+
+        let mut threads = vec![];
+
+        // sent jobs come back through this channel
+        let (drop_sender, drop_receiver) = crossbeam_channel::bounded::<Job<P>>(DROP_CHANNEL_CAP);
+        let drop_stats = Arc::clone(&stats);
+        threads.push(
+            Builder::new()
+                .name("solXdpShredDrop".to_owned())
+                .spawn(move || {
+                    loop {
+                        // drop payloads in a dedicated thread so that we never lock/madvise() from
+                        // the xdp thread
+                        let received = drop_receiver.try_recv();
+                        match received {
+                            Ok((addrs, job)) => {
+                                let sent = addrs.len() as u64;
+                                drop_stats
+                                    .lock()
+                                    .unwrap()
+                                    .record(job.slot, |stats| stats.sent += sent);
+                            }
+                            Err(TryRecvError::Empty) => {
+                                thread::sleep(Duration::from_millis(1));
+                            }
+                            Err(TryRecvError::Disconnected) => break,
+                        }
+                    }
+                    // move the ebpf program here so it stays attached until we exit
+                    drop(ebpf);
+                })
+                .unwrap(),
+        );
+
+        for (i, (receiver, cpu_id)) in receivers.into_iter().zip(config.cpus).enumerate() {
+            let dev = Arc::clone(&dev);
+            let drop_sender = drop_sender.clone();
+            let tx_loop_config = config.tx_loop.clone();
+            let src_port = config.src_port;
+            let zero_copy = config.zero_copy;
+            threads.push(
+                Builder::new()
+                    .name(format!("solXdpShred{i:02}"))
+                    .spawn(move || {
+                        tx_loop(
+                            cpu_id,
+                            &dev,
+                            QueueId(i as u64),
+                            zero_copy,
+                            None,
+                            None,
+                            src_port,
+                            None,
+                            receiver,
+                            None,
+                            drop_sender,
+                            tx_loop_config,
+                        )
+                    })
+                    .unwrap(),
+            );
+        }
+
+        Ok((Self { threads }, ShredSender { senders, stats }))
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        for handle in self.threads {
+            handle.join()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slot_stats_eviction() {
+        let mut map = SlotStatsMap::default();
+        for slot in 0..MAX_TRACKED_SLOTS as u64 {
+            map.record(slot + 10, |stats| stats.sent += 1);
+        }
+        // older than everything we track
+        map.record(0, |stats| stats.sent += 1);
+        assert!(!map.slots.contains_key(&0));
+
+        // a new slot evicts the oldest one
+        map.record(1000, |stats| stats.dropped += 2);
+        assert!(!map.slots.contains_key(&10));
+        assert_eq!(map.slots.len(), MAX_TRACKED_SLOTS);
+
+        let drained = map.drain();
+        assert_eq!(
+            drained.first(),
+            Some(&(
+                11,
+                SlotSendStats {
+                    sent: 1,
+                    dropped: 0
+                }
+            ))
+        );
+        assert_eq!(
+            drained.last(),
+            Some(&(
+                1000,
+                SlotSendStats {
+                    sent: 0,
+                    dropped: 2
+                }
+            ))
+        );
+        assert!(map.slots.is_empty());
+    }
+
+    #[test]
+    fn test_try_send_records_drops() {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let shred_sender = ShredSender {
+            senders: vec![sender],
+            stats: Arc::default(),
+        };
+        let addrs = vec!["127.0.0.1:8000".parse().unwrap(); 3];
+        shred_sender
+            .try_send(0, 5, vec![0u8; 10], addrs.clone())
+            .unwrap();
+        assert!(shred_sender.try_send(0, 5, vec![0u8; 10], addrs).is_err());
+        assert_eq!(
+            shred_sender.take_slot_stats(),
+            vec![(
+                5,
+                SlotSendStats {
+                    sent: 0,
+                    dropped: 3
+                }
+            )]
+        );
+
+        let (addrs, job) = receiver.try_recv().unwrap();
+        assert_eq!(addrs.len(), 3);
+        assert_eq!(job.slot, 5);
+        assert_eq!(job.as_ref(), &[0u8; 10]);
+    }
+}
@@ -0,0 +1,797 @@
+//! In-memory simulation backend.
+//!
+//! [`SimSocket`] is a drop-in replacement for [`Socket`](crate::socket::Socket) that doesn't need
+//! a NIC, root, or zero copy support. The fill, completion, tx and rx rings are the same
+//! [`Tx`]/[`Rx`] types returned by a real socket, but they're backed by shared memory that a
+//! background thread services the way the kernel would: TX descriptors are copied out of the UMEM
+//! and sent to a [`SimEndpoint`], then handed back on the completion ring. Frames sent to the
+//! other end of the endpoint pair are written into fill ring frames and published on the RX
+//! ring.
+//!
+//! The endpoints behave like the two ends of a veth pair, so two sockets can be wired together
+//! or a test can act as the remote host. An endpoint can also delay, reorder and drop the frames
+//! it sends, see [`LinkImpairments`], to test retries and pacing without netem.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        device::{RingConsumer, RingMmap, RingProducer, RxFillRing, TxCompletionRing, XdpDesc},
+        packet::parse_udp_frame,
+        socket::{Rx, RxRing, Tx, TxRing},
+        umem::Umem,
+    },
+    crossbeam_channel::{Receiver, RecvTimeoutError, Sender},
+    libc::{ftruncate, memfd_create, mmap, MFD_CLOEXEC},
+    std::{
+        cmp::Reverse,
+        collections::{BinaryHeap, HashMap},
+        io, mem,
+        net::SocketAddr,
+        os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+        ptr,
+        sync::{
+            atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+            Arc, Mutex,
+        },
+        thread::{self, JoinHandle},
+        time::{Duration, Instant},
+    },
+};
+
+// Layout of a simulated ring. Each of the producer, consumer and flags words gets its own cache
+// line like in the kernel.
+const PRODUCER_OFFSET: usize = 0;
+const CONSUMER_OFFSET: usize = 64;
+const FLAGS_OFFSET: usize = 128;
+const DESC_OFFSET: usize = 192;
+
+// How long the simulated kernel sleeps when there's no work to do.
+const IDLE_SLEEP: Duration = Duration::from_micros(10);
+
+/// How a simulated link mistreats the frames sent through it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Impairment {
+    /// Added to the delivery time of every frame.
+    pub delay: Duration,
+    /// Up to this much is randomly added to the delay. Frames sent closer together than the
+    /// jitter can overtake each other.
+    pub jitter: Duration,
+    /// Probability that a frame is held back and delivered right after the next frame to the same
+    /// destination.
+    pub reorder: f64,
+    /// Probability that a frame is dropped.
+    pub loss: f64,
+}
+
+/// The impairments of the frames sent through an endpoint, by destination.
+///
+/// The random decisions come from `seed`, so the same frames sent in the same order are always
+/// delayed, reordered and dropped the same way.
+#[derive(Clone, Debug, Default)]
+pub struct LinkImpairments {
+    /// Applied to the frames whose destination isn't in `destinations`, and to non UDP frames.
+    pub default: Impairment,
+    /// Applied to the UDP frames sent to these addresses.
+    pub destinations: HashMap<SocketAddr, Impairment>,
+    pub seed: u64,
+}
+
+// A frame on its way to the peer, with the time it's delivered at.
+type InFlight = (Instant, Vec<u8>);
+
+/// One end of a simulated link.
+///
+/// Frames sent by one endpoint are received by its peer, see [`veth_pair`].
+pub struct SimEndpoint {
+    tx: Sender<InFlight>,
+    rx: Receiver<InFlight>,
+    impairments: Option<Mutex<ImpairedLink>>,
+    pending: Mutex<Pending>,
+}
+
+/// Creates a pair of connected endpoints.
+pub fn veth_pair() -> (SimEndpoint, SimEndpoint) {
+    let (a_tx, b_rx) = crossbeam_channel::unbounded();
+    let (b_tx, a_rx) = crossbeam_channel::unbounded();
+    (SimEndpoint::new(a_tx, a_rx), SimEndpoint::new(b_tx, b_rx))
+}
+
+impl SimEndpoint {
+    fn new(tx: Sender<InFlight>, rx: Receiver<InFlight>) -> Self {
+        Self {
+            tx,
+            rx,
+            impairments: None,
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Impairs the frames sent by this endpoint.
+    pub fn with_impairments(mut self, impairments: LinkImpairments) -> Self {
+        self.impairments = Some(Mutex::new(ImpairedLink::new(impairments)));
+        self
+    }
+
+    /// Sends a frame to the peer endpoint.
+    ///
+    /// Returns false if the peer has been dropped. Frames lost to impairments count as sent.
+    pub fn send(&self, frame: Vec<u8>) -> bool {
+        let now = Instant::now();
+        match &self.impairments {
+            None => self.tx.send((now, frame)).is_ok(),
+            Some(link) => link
+                .lock()
+                .unwrap()
+                .impair(now, frame)
+                .into_iter()
+                .all(|in_flight| self.tx.send(in_flight).is_ok()),
+        }
+    }
+
+    pub fn try_recv(&self) -> Option<Vec<u8>> {
+        let mut pending = self.pending.lock().unwrap();
+        while let Ok(in_flight) = self.rx.try_recv() {
+            pending.push(in_flight);
+        }
+        pending.pop(Instant::now())
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(frame) = self.try_recv() {
+                return Some(frame);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            // wait for a new frame, or for the next delayed one to be due
+            let next_due = self.pending.lock().unwrap().next_due();
+            let wake = next_due.map_or(deadline, |due| due.min(deadline));
+            match self.rx.recv_deadline(wake) {
+                Ok(in_flight) => self.pending.lock().unwrap().push(in_flight),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) if next_due.is_some() => {
+                    thread::sleep(wake.saturating_duration_since(now));
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+// The frames received from the peer, in delivery order. Frames due at the same time are delivered
+// in the order they were sent.
+#[derive(Default)]
+struct Pending {
+    frames: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    received: u64,
+}
+
+impl Pending {
+    fn push(&mut self, (due, frame): InFlight) {
+        self.frames.push(Reverse((due, self.received, frame)));
+        self.received += 1;
+    }
+
+    fn pop(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.next_due()? > now {
+            return None;
+        }
+        self.frames.pop().map(|Reverse((_, _, frame))| frame)
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.frames.peek().map(|Reverse((due, _, _))| *due)
+    }
+}
+
+// The sending side of an impaired link.
+struct ImpairedLink {
+    impairments: LinkImpairments,
+    rng: u64,
+    // the frames held back to be reordered, by destination
+    held: HashMap<Option<SocketAddr>, InFlight>,
+}
+
+impl ImpairedLink {
+    fn new(impairments: LinkImpairments) -> Self {
+        Self {
+            rng: impairments.seed,
+            impairments,
+            held: HashMap::new(),
+        }
+    }
+
+    // Returns the frames to send to the peer now: none, `frame`, or `frame` followed by the
+    // frame held back for its destination.
+    fn impair(&mut self, now: Instant, frame: Vec<u8>) -> Vec<InFlight> {
+        let destination = parse_udp_frame(&frame, false)
+            .ok()
+            .map(|udp| udp.dst_addr());
+        let impairment = destination
+            .and_then(|addr| self.impairments.destinations.get(&addr))
+            .copied()
+            .unwrap_or(self.impairments.default);
+
+        if self.chance(impairment.loss) {
+            return Vec::new();
+        }
+        let due = now + impairment.delay + impairment.jitter.mul_f64(self.next_f64());
+        if let Some((held_due, held)) = self.held.remove(&destination) {
+            return vec![(due, frame), (held_due.max(due), held)];
+        }
+        if self.chance(impairment.reorder) {
+            self.held.insert(destination, (due, frame));
+            return Vec::new();
+        }
+        vec![(due, frame)]
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+
+    // Returns a number in [0, 1) from splitmix64.
+    fn next_f64(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Counters maintained by the simulated kernel.
+#[derive(Debug, Default)]
+pub struct SimStats {
+    /// Frames consumed from the TX ring and sent to the endpoint.
+    pub tx_frames: AtomicU64,
+    /// Frames received from the endpoint and published on the RX ring.
+    pub rx_frames: AtomicU64,
+    /// Frames received from the endpoint and dropped because the fill ring was empty, the RX ring
+    /// was full, or the frame didn't fit in a UMEM frame.
+    pub rx_dropped: AtomicU64,
+}
+
+/// A simulated AF_XDP socket.
+///
+/// Owns the UMEM the same way [`Socket`](crate::socket::Socket) does. The simulated kernel thread
+/// is stopped when the socket is dropped.
+pub struct SimSocket<U: Umem> {
+    umem: U,
+    stats: Arc<SimStats>,
+    exit: Arc<AtomicBool>,
+    tx_stalled: Arc<AtomicBool>,
+    kernel: Option<JoinHandle<()>>,
+    // the rings store the raw fds so keep them open for as long as the socket exists
+    _fds: Vec<OwnedFd>,
+}
+
+impl<U: Umem> SimSocket<U> {
+    /// Creates a simulated socket connected to `endpoint`.
+    ///
+    /// Ring sizes must be powers of two. Pass `rx_ring_size == 0` for a TX only socket.
+    #[allow(clippy::type_complexity)]
+    pub fn new(
+        mut umem: U,
+        endpoint: SimEndpoint,
+        rx_fill_ring_size: usize,
+        rx_ring_size: usize,
+        tx_completion_ring_size: usize,
+        tx_ring_size: usize,
+    ) -> Result<(Self, Rx<U::Frame>, Tx<U::Frame>), io::Error> {
+        for size in [
+            rx_fill_ring_size,
+            tx_completion_ring_size,
+            tx_ring_size,
+            rx_ring_size.max(1),
+        ] {
+            if !size.is_power_of_two() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "ring sizes must be a power of two",
+                ));
+            }
+        }
+
+        let (completion_fd, completion_user, completion_kernel) =
+            sim_ring::<u64>(tx_completion_ring_size)?;
+        let (fill_fd, fill_user, fill_kernel) = sim_ring::<u64>(rx_fill_ring_size)?;
+        let (tx_fd, tx_user, tx_kernel) = sim_ring::<XdpDesc>(tx_ring_size)?;
+        let rx = if rx_ring_size > 0 {
+            Some(sim_ring::<XdpDesc>(rx_ring_size)?)
+        } else {
+            None
+        };
+
+        let tx = Tx {
+            completion: TxCompletionRing::new(completion_user, tx_completion_ring_size as u32),
+            ring: Some(TxRing::new(tx_user, tx_ring_size as u32, tx_fd.as_raw_fd())),
+        };
+
+        let mut fds = vec![completion_fd, tx_fd];
+        let (rx_ring, rx_kernel) = match rx {
+            Some((rx_fd, rx_user, rx_kernel)) => {
+                let rx_ring = RxRing::new(rx_user, rx_ring_size as u32, rx_fd.as_raw_fd());
+                fds.push(rx_fd);
+                (Some(rx_ring), Some((rx_kernel, rx_ring_size as u32)))
+            }
+            None => (None, None),
+        };
+        let rx = Rx {
+            fill: RxFillRing::new(fill_user, rx_fill_ring_size as u32, fill_fd.as_raw_fd()),
+            ring: rx_ring,
+        };
+        fds.push(fill_fd);
+
+        let stats = Arc::new(SimStats::default());
+        let exit = Arc::new(AtomicBool::new(false));
+        let tx_stalled = Arc::new(AtomicBool::new(false));
+        let kernel = SimKernel {
+            umem: umem.as_mut_ptr(),
+            umem_len: umem.len(),
+            frame_size: umem.frame_size(),
+            tx: KernelConsumer::new(tx_kernel, tx_ring_size as u32),
+            completion: KernelProducer::new(completion_kernel, tx_completion_ring_size as u32),
+            fill: KernelConsumer::new(fill_kernel, rx_fill_ring_size as u32),
+            rx: rx_kernel.map(|(mmap, size)| KernelProducer::new(mmap, size)),
+            endpoint,
+            stats: Arc::clone(&stats),
+            exit: Arc::clone(&exit),
+            tx_stalled: Arc::clone(&tx_stalled),
+        };
+        let kernel = thread::Builder::new()
+            .name("solXdpSimKrnl".to_owned())
+            .spawn(move || kernel.run())?;
+
+        Ok((
+            Self {
+                umem,
+                stats,
+                exit,
+                tx_stalled,
+                kernel: Some(kernel),
+                _fds: fds,
+            },
+            rx,
+            tx,
+        ))
+    }
+
+    /// Creates a TX only simulated socket connected to `endpoint`.
+    pub fn tx(
+        umem: U,
+        endpoint: SimEndpoint,
+        completion_size: usize,
+        ring_size: usize,
+    ) -> Result<(Self, Tx<U::Frame>), io::Error> {
+        let (socket, _, tx) = Self::new(umem, endpoint, 1, 0, completion_size, ring_size)?;
+        Ok((socket, tx))
+    }
+
+    /// Creates an RX only simulated socket connected to `endpoint`.
+    pub fn rx(
+        umem: U,
+        endpoint: SimEndpoint,
+        fill_size: usize,
+        ring_size: usize,
+    ) -> Result<(Self, Rx<U::Frame>), io::Error> {
+        let (socket, rx, _) = Self::new(umem, endpoint, fill_size, ring_size, 1, 1)?;
+        Ok((socket, rx))
+    }
+
+    pub fn umem(&mut self) -> &mut U {
+        &mut self.umem
+    }
+
+    pub fn stats(&self) -> &SimStats {
+        &self.stats
+    }
+
+    /// Stops consuming TX descriptors, like a wedged driver, until called again with `false`.
+    pub fn set_tx_stalled(&self, stalled: bool) {
+        self.tx_stalled.store(stalled, Ordering::Relaxed);
+    }
+}
+
+impl<U: Umem> Drop for SimSocket<U> {
+    fn drop(&mut self) {
+        // the kernel thread accesses the UMEM so it must be stopped before the UMEM is dropped
+        self.exit.store(true, Ordering::Relaxed);
+        if let Some(kernel) = self.kernel.take() {
+            let _ = kernel.join();
+        }
+    }
+}
+
+// Creates a shared memory ring and maps it twice: once for the user side and once for the
+// simulated kernel. Having two mappings means each side can unmap independently.
+fn sim_ring<T>(size: usize) -> Result<(OwnedFd, RingMmap<T>, RingMmap<T>), io::Error> {
+    let map_size = DESC_OFFSET.saturating_add(size.saturating_mul(mem::size_of::<T>()));
+
+    // Safety: libc wrapper, the name is a valid NUL terminated string
+    let fd = unsafe { memfd_create(c"agave-xdp-sim".as_ptr(), MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: memfd_create returns a file descriptor
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    // Safety: libc wrapper. The memfd is zero filled so all ring positions and flags start at 0.
+    if unsafe { ftruncate(fd.as_raw_fd(), map_size as i64) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let user = map_sim_ring(&fd, map_size)?;
+    let kernel = map_sim_ring(&fd, map_size)?;
+    Ok((fd, user, kernel))
+}
+
+fn map_sim_ring<T>(fd: &OwnedFd, map_size: usize) -> Result<RingMmap<T>, io::Error> {
+    // Safety: just a libc wrapper. We pass a valid size and file descriptor.
+    let map_addr = unsafe {
+        mmap(
+            ptr::null_mut(),
+            map_size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr::eq(map_addr, libc::MAP_FAILED) {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safety: all the offsets are within map_size
+    unsafe {
+        Ok(RingMmap {
+            mmap: map_addr as *const u8,
+            mmap_len: map_size,
+            producer: map_addr.add(PRODUCER_OFFSET) as *mut AtomicU32,
+            consumer: map_addr.add(CONSUMER_OFFSET) as *mut AtomicU32,
+            desc: map_addr.add(DESC_OFFSET) as *mut T,
+            flags: map_addr.add(FLAGS_OFFSET) as *mut AtomicU32,
+        })
+    }
+}
+
+// Kernel side of a ring the user produces into (TX and fill rings).
+struct KernelConsumer<T> {
+    mmap: RingMmap<T>,
+    consumer: RingConsumer,
+    size: u32,
+}
+
+impl<T> KernelConsumer<T> {
+    fn new(mmap: RingMmap<T>, size: u32) -> Self {
+        Self {
+            consumer: RingConsumer::new(mmap.producer, mmap.consumer),
+            mmap,
+            size,
+        }
+    }
+
+    fn read(&mut self) -> Option<T> {
+        let index = self.consumer.consume()? & self.size.saturating_sub(1);
+        // Safety: index is within the ring so the pointer is valid
+        Some(unsafe { self.mmap.desc.add(index as usize).read() })
+    }
+}
+
+// Kernel side of a ring the user consumes from (completion and RX rings).
+struct KernelProducer<T> {
+    mmap: RingMmap<T>,
+    producer: RingProducer,
+    size: u32,
+}
+
+impl<T> KernelProducer<T> {
+    fn new(mmap: RingMmap<T>, size: u32) -> Self {
+        Self {
+            producer: RingProducer::new(mmap.producer, mmap.consumer, size),
+            mmap,
+            size,
+        }
+    }
+
+    fn write(&mut self, desc: T) -> bool {
+        let Some(index) = self.producer.produce() else {
+            return false;
+        };
+        let index = index & self.size.saturating_sub(1);
+        // Safety: index is within the ring so the pointer is valid
+        unsafe { self.mmap.desc.add(index as usize).write(desc) };
+        true
+    }
+}
+
+struct SimKernel {
+    umem: *mut u8,
+    umem_len: usize,
+    frame_size: usize,
+    tx: KernelConsumer<XdpDesc>,
+    completion: KernelProducer<u64>,
+    fill: KernelConsumer<u64>,
+    rx: Option<KernelProducer<XdpDesc>>,
+    endpoint: SimEndpoint,
+    stats: Arc<SimStats>,
+    exit: Arc<AtomicBool>,
+    tx_stalled: Arc<AtomicBool>,
+}
+
+// Safety: the raw pointers point to ring mappings owned by SimKernel and to the UMEM, which is
+// owned by the SimSocket and outlives the kernel thread (see SimSocket::drop).
+unsafe impl Send for SimKernel {}
+
+impl SimKernel {
+    fn run(mut self) {
+        while !self.exit.load(Ordering::Relaxed) {
+            let tx_work = self.process_tx();
+            let rx_work = self.process_rx();
+            if !tx_work && !rx_work {
+                thread::sleep(IDLE_SLEEP);
+            }
+        }
+    }
+
+    fn process_tx(&mut self) -> bool {
+        if self.tx_stalled.load(Ordering::Relaxed) {
+            return false;
+        }
+        self.tx.consumer.sync(false);
+        self.completion.producer.sync(false);
+
+        let mut work = false;
+        // like the kernel we stop consuming TX descriptors when there's no room to complete them
+        while self.completion.producer.available() > 0 {
+            let Some(desc) = self.tx.read() else {
+                break;
+            };
+            work = true;
+
+            let frame = self.frame(desc.addr as usize, desc.len as usize).to_vec();
+            self.completion.write(desc.addr);
+            self.stats.tx_frames.fetch_add(1, Ordering::Relaxed);
+            // a disconnected peer is like an unplugged cable: the frame is silently lost
+            let _ = self.endpoint.send(frame);
+        }
+
+        if work {
+            self.tx.consumer.commit();
+            self.completion.producer.commit();
+        }
+        work
+    }
+
+    fn process_rx(&mut self) -> bool {
+        let Some(rx) = self.rx.as_mut() else {
+            return false;
+        };
+
+        let mut work = false;
+        while let Some(frame) = self.endpoint.try_recv() {
+            work = true;
+
+            self.fill.consumer.sync(false);
+            rx.producer.sync(false);
+            if frame.len() > self.frame_size || rx.producer.available() == 0 {
+                self.stats.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let Some(addr) = self.fill.read() else {
+                self.stats.rx_dropped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            };
+
+            let addr = addr as usize;
+            assert!(addr.saturating_add(frame.len()) <= self.umem_len);
+            // Safety: the fill ring only contains frames within the UMEM, checked above
+            unsafe {
+                ptr::copy_nonoverlapping(frame.as_ptr(), self.umem.add(addr), frame.len());
+            }
+            rx.write(XdpDesc {
+                addr: addr as u64,
+                len: frame.len() as u32,
+                options: 0,
+            });
+            self.fill.consumer.commit();
+            rx.producer.commit();
+            self.stats.rx_frames.fetch_add(1, Ordering::Relaxed);
+        }
+        work
+    }
+
+    fn frame(&self, addr: usize, len: usize) -> &[u8] {
+        assert!(addr.saturating_add(len) <= self.umem_len);
+        // Safety: the range is within the UMEM, checked above
+        unsafe { std::slice::from_raw_parts(self.umem.add(addr), len) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            header_cache::build_udp_frame_header,
+            netlink::MacAddress,
+            packet::set_udp_frame_len,
+            umem::{Frame as _, PageAlignedMemory, SliceUmem},
+        },
+        std::net::{Ipv4Addr, SocketAddrV4},
+    };
+
+    const FRAME_SIZE: usize = 2048;
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_sim_tx() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 64).unwrap();
+        let umem = SliceUmem::new(&mut memory, FRAME_SIZE as u32).unwrap();
+        let (endpoint, peer) = veth_pair();
+        let (mut socket, tx) = SimSocket::tx(umem, endpoint, 32, 32).unwrap();
+        let Tx {
+            ring,
+            mut completion,
+        } = tx;
+        let mut ring = ring.unwrap();
+
+        for i in 0..10u8 {
+            let umem = socket.umem();
+            let mut frame = umem.reserve().unwrap();
+            frame.set_len(100 + i as usize);
+            umem.map_frame_mut(&frame).fill(i);
+            ring.write(frame, 0).map_err(|_| "ring full").unwrap();
+        }
+        ring.commit();
+
+        for i in 0..10u8 {
+            let frame = peer.recv_timeout(TIMEOUT).unwrap();
+            assert_eq!(frame, vec![i; 100 + i as usize]);
+        }
+
+        let mut completed = 0;
+        let start = Instant::now();
+        while completed < 10 && start.elapsed() < TIMEOUT {
+            completion.sync(true);
+            while let Some(offset) = completion.read() {
+                socket.umem().release(offset);
+                completed += 1;
+            }
+        }
+        assert_eq!(completed, 10);
+        assert_eq!(socket.umem().available(), 64);
+        assert_eq!(socket.stats().tx_frames.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn test_sim_rx() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 64).unwrap();
+        let umem = SliceUmem::new(&mut memory, FRAME_SIZE as u32).unwrap();
+        let (endpoint, peer) = veth_pair();
+        let (mut socket, rx) = SimSocket::rx(umem, endpoint, 4, 4).unwrap();
+        let Rx { mut fill, ring } = rx;
+        let mut ring = ring.unwrap();
+
+        for _ in 0..2 {
+            fill.write(socket.umem().reserve().unwrap()).unwrap();
+        }
+        fill.commit();
+
+        // only two fill frames are available, the third frame is dropped
+        for i in 0..3u8 {
+            assert!(peer.send(vec![i; 64]));
+        }
+
+        let mut received = Vec::new();
+        let start = Instant::now();
+        while socket.stats().rx_dropped.load(Ordering::Relaxed) < 1 && start.elapsed() < TIMEOUT {
+            thread::sleep(Duration::from_millis(1));
+        }
+        ring.sync(true);
+        while let Some((offset, len)) = ring.read() {
+            let umem = socket.umem();
+            // Safety: the simulated kernel only publishes frames within the UMEM
+            let data = unsafe { std::slice::from_raw_parts(umem.as_ptr().add(offset.0), len) };
+            received.push(data.to_vec());
+            umem.release(offset);
+        }
+        ring.commit();
+
+        assert_eq!(received, vec![vec![0u8; 64], vec![1u8; 64]]);
+        assert_eq!(socket.stats().rx_frames.load(Ordering::Relaxed), 2);
+        assert_eq!(socket.stats().rx_dropped.load(Ordering::Relaxed), 1);
+    }
+
+    fn udp_frame(dst: SocketAddrV4, payload: u8) -> Vec<u8> {
+        let header = build_udp_frame_header(
+            &MacAddress([1, 2, 3, 4, 5, 6]),
+            &MacAddress([6, 5, 4, 3, 2, 1]),
+            &Ipv4Addr::new(10, 0, 0, 1),
+            8000,
+            &dst,
+        );
+        let mut frame = header.to_vec();
+        frame.extend_from_slice(&[payload; 10]);
+        set_udp_frame_len(&mut frame, 10);
+        frame
+    }
+
+    #[test]
+    fn test_impairments() {
+        let lossy = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000);
+        let reordered = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 8000);
+        let delayed = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 4), 8000);
+        let impairments = LinkImpairments {
+            default: Impairment {
+                loss: 0.5,
+                ..Impairment::default()
+            },
+            destinations: HashMap::from([
+                (
+                    SocketAddr::V4(lossy),
+                    Impairment {
+                        loss: 1.0,
+                        ..Impairment::default()
+                    },
+                ),
+                (
+                    SocketAddr::V4(reordered),
+                    Impairment {
+                        reorder: 1.0,
+                        ..Impairment::default()
+                    },
+                ),
+                (
+                    SocketAddr::V4(delayed),
+                    Impairment {
+                        delay: Duration::from_millis(50),
+                        ..Impairment::default()
+                    },
+                ),
+            ]),
+            seed: 42,
+        };
+        let (endpoint, peer) = veth_pair();
+        let endpoint = endpoint.with_impairments(impairments.clone());
+
+        for i in 0..4 {
+            assert!(endpoint.send(udp_frame(lossy, i)));
+            assert!(endpoint.send(udp_frame(reordered, i)));
+        }
+        // every frame is held back until the next one overtakes it
+        let received = (0..4).map(|_| peer.try_recv().unwrap()).collect::<Vec<_>>();
+        assert_eq!(received[0], udp_frame(reordered, 1));
+        assert_eq!(received[1], udp_frame(reordered, 0));
+        assert_eq!(received[2], udp_frame(reordered, 3));
+        assert_eq!(received[3], udp_frame(reordered, 2));
+        assert!(peer.try_recv().is_none());
+
+        let start = Instant::now();
+        assert!(endpoint.send(udp_frame(delayed, 0)));
+        assert!(peer.try_recv().is_none());
+        assert_eq!(peer.recv_timeout(TIMEOUT).unwrap(), udp_frame(delayed, 0));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // the same seed drops the same frames
+        let mut runs = Vec::new();
+        for _ in 0..2 {
+            let (endpoint, peer) = veth_pair();
+            let endpoint = endpoint.with_impairments(impairments.clone());
+            for i in 0..100 {
+                assert!(endpoint.send(vec![i; 64]));
+            }
+            runs.push(
+                std::iter::from_fn(|| peer.try_recv())
+                    .map(|frame| frame[0])
+                    .collect::<Vec<_>>(),
+            );
+        }
+        assert_eq!(runs[0], runs[1]);
+        assert!(runs[0].len() > 20 && runs[0].len() < 80);
+    }
+}
@@ -1,28 +1,51 @@
+#[cfg(feature = "mio")]
+use mio::{event, unix::SourceFd, Interest, Registry, Token};
 use {
     crate::{
         device::{
             mmap_ring, DeviceQueue, RingConsumer, RingMmap, RingProducer, RxFillRing,
             TxCompletionRing, XdpDesc,
         },
-        umem::{Frame, Umem},
+        error::{XdpError, XdpErrorKind},
+        umem::{Frame, FrameOffset, Umem},
     },
     libc::{
         bind, getsockopt, sa_family_t, sendto, setsockopt, sockaddr, sockaddr_xdp, socket,
-        socklen_t, xdp_mmap_offsets, xdp_umem_reg, AF_XDP, SOCK_RAW, SOL_XDP, XDP_COPY,
-        XDP_MMAP_OFFSETS, XDP_PGOFF_RX_RING, XDP_PGOFF_TX_RING, XDP_RING_NEED_WAKEUP, XDP_RX_RING,
-        XDP_TX_RING, XDP_UMEM_COMPLETION_RING, XDP_UMEM_FILL_RING, XDP_UMEM_PGOFF_COMPLETION_RING,
-        XDP_UMEM_PGOFF_FILL_RING, XDP_USE_NEED_WAKEUP, XDP_ZEROCOPY,
+        socklen_t, xdp_mmap_offsets, xdp_statistics, xdp_umem_reg, AF_XDP, SOCK_RAW, SOL_XDP,
+        XDP_COPY, XDP_MMAP_OFFSETS, XDP_PGOFF_RX_RING, XDP_PGOFF_TX_RING, XDP_RING_NEED_WAKEUP,
+        XDP_RX_RING, XDP_STATISTICS, XDP_TX_RING, XDP_UMEM_COMPLETION_RING, XDP_UMEM_FILL_RING,
+        XDP_UMEM_PGOFF_COMPLETION_RING, XDP_UMEM_PGOFF_FILL_RING, XDP_UMEM_UNALIGNED_CHUNK_FLAG,
+        XDP_USE_NEED_WAKEUP, XDP_ZEROCOPY, XSK_UNALIGNED_BUF_ADDR_MASK,
+        XSK_UNALIGNED_BUF_OFFSET_SHIFT,
     },
     std::{
         io,
         marker::PhantomData,
         mem,
-        os::fd::{AsFd, AsRawFd as _, BorrowedFd, FromRawFd as _, OwnedFd, RawFd},
+        os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd as _, OwnedFd, RawFd},
         ptr,
-        sync::atomic::Ordering,
+        sync::atomic::{AtomicU32, AtomicU64, Ordering},
+        time::{Duration, Instant},
     },
 };
 
+/// An AF_XDP socket bound to a device queue.
+///
+/// # Readiness
+///
+/// The socket can be polled, or registered with mio as an `event::Source` with the `mio` feature,
+/// to wait on its rings:
+///
+/// - it's readable when the rx ring has frames. The driver only fills the rx ring from frames
+///   posted to the fill ring, which it may need to be woken up to pick up, see
+///   [`RxFillRing::needs_wakeup`]. A socket whose fill ring is empty never becomes readable.
+/// - it's writable when at least half of the tx ring is free. The driver only consumes the tx
+///   ring, and so frees room in it and produces completions, once woken up when
+///   [`TxRing::needs_wakeup`] is set.
+///
+/// So before waiting, a poll loop must refill the fill ring and wake up the driver for both rings
+/// if they ask for it. The completion ring has no readiness of its own: completions come with the
+/// tx ring becoming writable.
 pub struct Socket<U: Umem> {
     fd: OwnedFd,
     dev_queue: DeviceQueue,
@@ -33,17 +56,68 @@ impl<U: Umem> Socket<U> {
     #[allow(clippy::type_complexity)]
     pub fn new(
         dev_queue: DeviceQueue,
-        mut umem: U,
+        umem: U,
         zero_copy: bool,
         rx_fill_ring_size: usize,
         rx_ring_size: usize,
         tx_completion_ring_size: usize,
         tx_ring_size: usize,
-    ) -> Result<(Self, Rx<U::Frame>, Tx<U::Frame>), io::Error> {
+    ) -> Result<(Self, Rx<U::Frame>, Tx<U::Frame>), XdpError> {
+        let (if_index, queue_id) = (dev_queue.if_index(), dev_queue.id());
+        Self::create(
+            dev_queue,
+            umem,
+            zero_copy,
+            rx_fill_ring_size,
+            rx_ring_size,
+            tx_completion_ring_size,
+            tx_ring_size,
+        )
+        .map_err(|e| e.with_queue(if_index, queue_id))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn create(
+        dev_queue: DeviceQueue,
+        umem: U,
+        zero_copy: bool,
+        rx_fill_ring_size: usize,
+        rx_ring_size: usize,
+        tx_completion_ring_size: usize,
+        tx_ring_size: usize,
+    ) -> Result<(Self, Rx<U::Frame>, Tx<U::Frame>), XdpError> {
+        let fd = Self::open(
+            &umem,
+            rx_fill_ring_size,
+            rx_ring_size,
+            tx_completion_ring_size,
+            tx_ring_size,
+        )?;
+        Self::from_fd(
+            fd,
+            dev_queue,
+            umem,
+            zero_copy,
+            rx_fill_ring_size,
+            rx_ring_size,
+            tx_completion_ring_size,
+            tx_ring_size,
+        )
+    }
+
+    // Creates a socket, registers `umem` and sizes its rings. This is the part that requires
+    // CAP_NET_RAW, see crate::handoff.
+    pub(crate) fn open(
+        umem: &U,
+        rx_fill_ring_size: usize,
+        rx_ring_size: usize,
+        tx_completion_ring_size: usize,
+        tx_ring_size: usize,
+    ) -> Result<OwnedFd, XdpError> {
         unsafe {
             let fd = socket(AF_XDP, SOCK_RAW, 0);
             if fd < 0 {
-                return Err(io::Error::last_os_error());
+                return Err(XdpError::last_os_error("socket(AF_XDP)"));
             }
             let fd = OwnedFd::from_raw_fd(fd);
 
@@ -52,7 +126,11 @@ impl<U: Umem> Socket<U> {
                 len: umem.len() as u64,
                 chunk_size: umem.frame_size() as u32,
                 headroom: 0,
-                flags: 0,
+                flags: if umem.unaligned() {
+                    XDP_UMEM_UNALIGNED_CHUNK_FLAG
+                } else {
+                    0
+                },
                 tx_metadata_len: 0,
             };
 
@@ -64,14 +142,22 @@ impl<U: Umem> Socket<U> {
                 mem::size_of::<xdp_umem_reg>() as libc::socklen_t,
             ) < 0
             {
-                return Err(io::Error::last_os_error());
+                return Err(XdpError::last_os_error("setsockopt(XDP_UMEM_REG)"));
             }
 
-            for (ring, size) in [
-                (XDP_UMEM_COMPLETION_RING, tx_completion_ring_size),
-                (XDP_UMEM_FILL_RING, rx_fill_ring_size),
-                (XDP_TX_RING, tx_ring_size),
-                (XDP_RX_RING, rx_ring_size),
+            for (ring, size, syscall) in [
+                (
+                    XDP_UMEM_COMPLETION_RING,
+                    tx_completion_ring_size,
+                    "setsockopt(XDP_UMEM_COMPLETION_RING)",
+                ),
+                (
+                    XDP_UMEM_FILL_RING,
+                    rx_fill_ring_size,
+                    "setsockopt(XDP_UMEM_FILL_RING)",
+                ),
+                (XDP_TX_RING, tx_ring_size, "setsockopt(XDP_TX_RING)"),
+                (XDP_RX_RING, rx_ring_size, "setsockopt(XDP_RX_RING)"),
             ] {
                 if ring == XDP_RX_RING && size == 0 {
                     // tx only
@@ -86,10 +172,27 @@ impl<U: Umem> Socket<U> {
                     mem::size_of::<u32>() as socklen_t,
                 ) < 0
                 {
-                    return Err(io::Error::last_os_error());
+                    return Err(XdpError::last_os_error(syscall));
                 }
             }
+            Ok(fd)
+        }
+    }
 
+    // Maps the rings of a socket created by `open()` and binds it to `dev_queue`. The kernel
+    // refuses to map the rings of a bound socket, so this has to be done before binding.
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
+    pub(crate) fn from_fd(
+        fd: OwnedFd,
+        dev_queue: DeviceQueue,
+        mut umem: U,
+        zero_copy: bool,
+        rx_fill_ring_size: usize,
+        rx_ring_size: usize,
+        tx_completion_ring_size: usize,
+        tx_ring_size: usize,
+    ) -> Result<(Self, Rx<U::Frame>, Tx<U::Frame>), XdpError> {
+        unsafe {
             let mut offsets: xdp_mmap_offsets = mem::zeroed();
             let mut optlen = mem::size_of::<xdp_mmap_offsets>() as socklen_t;
             if getsockopt(
@@ -100,7 +203,7 @@ impl<U: Umem> Socket<U> {
                 &mut optlen,
             ) < 0
             {
-                return Err(io::Error::last_os_error());
+                return Err(XdpError::last_os_error("getsockopt(XDP_MMAP_OFFSETS)"));
             }
 
             let tx_completion_ring = TxCompletionRing::new(
@@ -109,7 +212,8 @@ impl<U: Umem> Socket<U> {
                     tx_completion_ring_size.saturating_mul(mem::size_of::<u64>()),
                     &offsets.cr,
                     XDP_UMEM_PGOFF_COMPLETION_RING,
-                )?,
+                )
+                .map_err(|e| XdpError::new("mmap(completion ring)", e))?,
                 tx_completion_ring_size as u32,
             );
 
@@ -119,7 +223,8 @@ impl<U: Umem> Socket<U> {
                     rx_fill_ring_size.saturating_mul(mem::size_of::<u64>()),
                     &offsets.fr,
                     XDP_UMEM_PGOFF_FILL_RING,
-                )?,
+                )
+                .map_err(|e| XdpError::new("mmap(fill ring)", e))?,
                 rx_fill_ring_size as u32,
                 fd.as_raw_fd(),
             );
@@ -129,9 +234,15 @@ impl<U: Umem> Socket<U> {
                 // pre-populated before calling bind()
                 for _ in 0..rx_fill_ring_size {
                     let Some(frame) = umem.reserve() else {
-                        return Err(io::Error::other("Failed to reserve frame for RX fill ring"));
+                        return Err(XdpError::other(
+                            XdpErrorKind::Misconfigured,
+                            "reserve",
+                            "not enough frames in the UMEM to populate the RX fill ring",
+                        ));
                     };
-                    rx_fill_ring.write(frame)?;
+                    rx_fill_ring
+                        .write(frame)
+                        .map_err(|e| XdpError::new("write(fill ring)", e))?;
                 }
                 rx_fill_ring.commit();
             }
@@ -142,7 +253,8 @@ impl<U: Umem> Socket<U> {
                     tx_ring_size.saturating_mul(mem::size_of::<XdpDesc>()),
                     &offsets.tx,
                     XDP_PGOFF_TX_RING as u64,
-                )?,
+                )
+                .map_err(|e| XdpError::new("mmap(tx ring)", e))?,
                 tx_ring_size as u32,
                 fd.as_raw_fd(),
             ));
@@ -154,7 +266,8 @@ impl<U: Umem> Socket<U> {
                         rx_ring_size.saturating_mul(mem::size_of::<XdpDesc>()),
                         &offsets.rx,
                         XDP_PGOFF_RX_RING as u64,
-                    )?,
+                    )
+                    .map_err(|e| XdpError::new("mmap(rx ring)", e))?,
                     rx_ring_size as u32,
                     fd.as_raw_fd(),
                 ))
@@ -177,7 +290,7 @@ impl<U: Umem> Socket<U> {
                 mem::size_of::<sockaddr_xdp>() as socklen_t,
             ) < 0
             {
-                return Err(io::Error::last_os_error());
+                return Err(XdpError::last_os_error("bind"));
             }
 
             let tx = Tx {
@@ -206,12 +319,19 @@ impl<U: Umem> Socket<U> {
         zero_copy: bool,
         completion_size: usize,
         ring_size: usize,
-    ) -> Result<(Self, Tx<U::Frame>), io::Error> {
+    ) -> Result<(Self, Tx<U::Frame>), XdpError> {
         let (fill_size, rx_size) = if zero_copy {
             // See Socket::new() as to why this is needed
             let rx = queue
                 .ring_sizes()
-                .ok_or_else(|| io::Error::other("zero copy requires a set ring size"))?
+                .ok_or_else(|| {
+                    XdpError::other(
+                        XdpErrorKind::Misconfigured,
+                        "ETHTOOL_GRINGPARAM",
+                        "zero copy requires a set ring size",
+                    )
+                    .with_queue(queue.if_index(), queue.id())
+                })?
                 .rx;
             (rx, rx)
         } else {
@@ -236,7 +356,7 @@ impl<U: Umem> Socket<U> {
         zero_copy: bool,
         fill_size: usize,
         ring_size: usize,
-    ) -> Result<(Self, Rx<U::Frame>), io::Error> {
+    ) -> Result<(Self, Rx<U::Frame>), XdpError> {
         let (socket, rx, _) = Self::new(queue, umem, zero_copy, fill_size, ring_size, 0, 0)?;
         Ok((socket, rx))
     }
@@ -248,6 +368,11 @@ impl<U: Umem> Socket<U> {
     pub fn umem(&mut self) -> &mut U {
         &mut self.umem
     }
+
+    /// Returns the statistics the kernel keeps for the socket.
+    pub fn statistics(&self) -> Result<XdpStatistics, io::Error> {
+        xdp_statistics(self.fd.as_raw_fd())
+    }
 }
 
 impl<U: Umem> AsFd for Socket<U> {
@@ -256,6 +381,37 @@ impl<U: Umem> AsFd for Socket<U> {
     }
 }
 
+impl<U: Umem> AsRawFd for Socket<U> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(feature = "mio")]
+impl<U: Umem> event::Source for Socket<U> {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.fd.as_raw_fd()).deregister(registry)
+    }
+}
+
 pub struct Tx<F: Frame> {
     pub completion: TxCompletionRing,
     pub ring: Option<TxRing<F>>,
@@ -266,6 +422,116 @@ pub struct Rx<F: Frame> {
     pub ring: Option<RxRing>,
 }
 
+/// The transmit side of a socket.
+///
+/// Frames are written with [`submit`](Self::submit), handed to the driver with
+/// [`commit`](Self::commit) and [`kick`](Self::kick), and come back from
+/// [`complete`](Self::complete) once sent, after which they can be released to their UMEM.
+/// [`Tx`] implements it on top of the ring and completion ring of a socket; with the `test-utils`
+/// feature, `mock::MockTxQueue` implements it in memory for unit tests.
+pub trait TxQueue {
+    type Frame: Frame;
+
+    /// Returns how many frames the ring holds.
+    fn capacity(&self) -> usize;
+
+    /// Returns how many more frames can be submitted before the ring is full.
+    fn available(&self) -> usize;
+
+    /// Picks up the room the driver made in the ring since the last call, which
+    /// [`available`](Self::available) may not count until then.
+    fn refresh(&mut self) {}
+
+    fn submit(&mut self, frame: Self::Frame, options: u32) -> Result<(), RingFull<Self::Frame>>;
+
+    /// Makes the frames submitted so far visible to the driver.
+    fn commit(&mut self);
+
+    /// Whether the driver must be kicked to pick up the committed frames.
+    fn needs_wakeup(&self) -> bool {
+        true
+    }
+
+    /// Wakes up the driver if it needs it to pick up the committed frames.
+    fn kick(&mut self) -> Result<(), io::Error>;
+
+    /// Returns the next frame the driver is done with, if any.
+    fn complete(&mut self) -> Option<FrameOffset>;
+
+    /// Records how full the ring and the completion ring are in `stats`.
+    fn sample_occupancy(&self, _stats: &XdpRingStats) {}
+
+    /// Returns the statistics the kernel keeps for the socket the queue belongs to.
+    fn statistics(&self) -> Result<XdpStatistics, io::Error> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl<F: Frame> TxQueue for Tx<F> {
+    type Frame = F;
+
+    fn capacity(&self) -> usize {
+        self.ring.as_ref().map_or(0, TxRing::capacity)
+    }
+
+    fn available(&self) -> usize {
+        self.ring.as_ref().map_or(0, TxRing::available)
+    }
+
+    fn refresh(&mut self) {
+        if let Some(ring) = self.ring.as_mut() {
+            ring.sync(false);
+        }
+    }
+
+    fn submit(&mut self, frame: F, options: u32) -> Result<(), RingFull<F>> {
+        match self.ring.as_mut() {
+            Some(ring) => ring.write(frame, options),
+            None => Err(RingFull(frame)),
+        }
+    }
+
+    fn commit(&mut self) {
+        if let Some(ring) = self.ring.as_mut() {
+            ring.sync(true);
+        }
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        self.ring.as_ref().is_some_and(TxRing::needs_wakeup)
+    }
+
+    fn kick(&mut self) -> Result<(), io::Error> {
+        match self.ring.as_ref() {
+            Some(ring) if ring.needs_wakeup() => ring.wake().map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    fn complete(&mut self) -> Option<FrameOffset> {
+        self.completion.read().or_else(|| {
+            self.completion.sync(true);
+            self.completion.read()
+        })
+    }
+
+    fn sample_occupancy(&self, stats: &XdpRingStats) {
+        if let Some(ring) = self.ring.as_ref() {
+            stats.tx.sample(ring.occupancy(), ring.capacity());
+        }
+        stats
+            .completion
+            .sample(self.completion.occupancy(), self.completion.capacity());
+    }
+
+    fn statistics(&self) -> Result<XdpStatistics, io::Error> {
+        match self.ring.as_ref() {
+            Some(ring) => ring.statistics(),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
 pub struct TxRing<F: Frame> {
     mmap: RingMmap<XdpDesc>,
     producer: RingProducer,
@@ -278,7 +544,7 @@ pub struct TxRing<F: Frame> {
 pub struct RingFull<F: Frame>(pub F);
 
 impl<F: Frame> TxRing<F> {
-    fn new(mmap: RingMmap<XdpDesc>, size: u32, fd: RawFd) -> Self {
+    pub(crate) fn new(mmap: RingMmap<XdpDesc>, size: u32, fd: RawFd) -> Self {
         debug_assert!(size.is_power_of_two());
         Self {
             producer: RingProducer::new(mmap.producer, mmap.consumer, size),
@@ -309,6 +575,16 @@ impl<F: Frame> TxRing<F> {
         unsafe { (*self.mmap.flags).load(Ordering::Relaxed) & XDP_RING_NEED_WAKEUP != 0 }
     }
 
+    /// Returns the statistics the kernel keeps for the socket the ring belongs to.
+    pub fn statistics(&self) -> Result<XdpStatistics, io::Error> {
+        xdp_statistics(self.fd)
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn fd(&self) -> RawFd {
+        self.fd
+    }
+
     pub fn wake(&self) -> Result<u64, io::Error> {
         let result = unsafe { sendto(self.fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0) };
         if result < 0 {
@@ -325,6 +601,11 @@ impl<F: Frame> TxRing<F> {
         self.producer.available() as usize
     }
 
+    /// Returns the number of frames written that the driver hasn't picked up yet.
+    pub fn occupancy(&self) -> usize {
+        self.producer.occupancy() as usize
+    }
+
     pub fn commit(&mut self) {
         self.producer.commit();
     }
@@ -335,16 +616,14 @@ impl<F: Frame> TxRing<F> {
 }
 
 pub struct RxRing {
-    #[allow(dead_code)]
     mmap: RingMmap<XdpDesc>,
     consumer: RingConsumer,
     size: u32,
-    #[allow(dead_code)]
     fd: RawFd,
 }
 
 impl RxRing {
-    fn new(mmap: RingMmap<XdpDesc>, size: u32, fd: RawFd) -> Self {
+    pub(crate) fn new(mmap: RingMmap<XdpDesc>, size: u32, fd: RawFd) -> Self {
         debug_assert!(size.is_power_of_two());
         Self {
             consumer: RingConsumer::new(mmap.producer, mmap.consumer),
@@ -354,6 +633,23 @@ impl RxRing {
         }
     }
 
+    /// Returns the offset and length of the next received frame, if any.
+    pub fn read(&mut self) -> Option<(FrameOffset, usize)> {
+        let index = self.consumer.consume()? & self.size.saturating_sub(1);
+        // Safety: index is within the ring so the pointer is valid
+        let desc = unsafe { self.mmap.desc.add(index as usize).read() };
+        Some((FrameOffset(unaligned_addr(desc.addr)), desc.len as usize))
+    }
+
+    /// Returns the statistics the kernel keeps for the socket the ring belongs to.
+    pub fn statistics(&self) -> Result<XdpStatistics, io::Error> {
+        xdp_statistics(self.fd)
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.fd
+    }
+
     pub fn capacity(&self) -> usize {
         self.size as usize
     }
@@ -362,6 +658,11 @@ impl RxRing {
         self.consumer.available() as usize
     }
 
+    /// Returns the number of received frames that haven't been read yet.
+    pub fn occupancy(&self) -> usize {
+        self.consumer.occupancy() as usize
+    }
+
     pub fn commit(&mut self) {
         self.consumer.commit();
     }
@@ -370,3 +671,303 @@ impl RxRing {
         self.consumer.sync(commit);
     }
 }
+
+// In unaligned chunk mode the kernel stores the offset of the packet within its frame in the upper
+// bits of rx descriptors. In aligned mode the upper bits are always zero.
+#[inline]
+fn unaligned_addr(addr: u64) -> usize {
+    ((addr & XSK_UNALIGNED_BUF_ADDR_MASK) + (addr >> XSK_UNALIGNED_BUF_OFFSET_SHIFT)) as usize
+}
+
+/// The statistics the kernel keeps for an XDP socket.
+///
+/// These are the ground truth for packets that never made it to or from the rings. The counters
+/// are cumulative over the lifetime of the socket. Kernels older than 5.9 don't report the ring
+/// full and ring empty counters, which are then always zero.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XdpStatistics {
+    /// Packets dropped for reasons other than invalid descriptors, e.g. the rx ring being full.
+    pub rx_dropped: u64,
+    /// Invalid descriptors found in the fill ring.
+    pub rx_invalid_descs: u64,
+    /// Invalid descriptors found in the tx ring.
+    pub tx_invalid_descs: u64,
+    /// Packets dropped because the rx ring was full.
+    pub rx_ring_full: u64,
+    /// Times the fill ring was empty when the kernel needed a frame.
+    pub rx_fill_ring_empty_descs: u64,
+    /// Times the tx ring was empty when the kernel was woken up to transmit.
+    pub tx_ring_empty_descs: u64,
+}
+
+impl XdpStatistics {
+    fn saturating_sub(&self, other: &Self) -> Self {
+        Self {
+            rx_dropped: self.rx_dropped.saturating_sub(other.rx_dropped),
+            rx_invalid_descs: self.rx_invalid_descs.saturating_sub(other.rx_invalid_descs),
+            tx_invalid_descs: self.tx_invalid_descs.saturating_sub(other.tx_invalid_descs),
+            rx_ring_full: self.rx_ring_full.saturating_sub(other.rx_ring_full),
+            rx_fill_ring_empty_descs: self
+                .rx_fill_ring_empty_descs
+                .saturating_sub(other.rx_fill_ring_empty_descs),
+            tx_ring_empty_descs: self
+                .tx_ring_empty_descs
+                .saturating_sub(other.tx_ring_empty_descs),
+        }
+    }
+}
+
+fn xdp_statistics(fd: RawFd) -> Result<XdpStatistics, io::Error> {
+    // Safety: xdp_statistics is plain old data
+    let mut stats: xdp_statistics = unsafe { mem::zeroed() };
+    // older kernels fill in a prefix of the struct and shrink optlen accordingly
+    let mut optlen = mem::size_of::<xdp_statistics>() as socklen_t;
+    // Safety: libc wrapper, stats is large enough for optlen bytes
+    if unsafe {
+        getsockopt(
+            fd,
+            SOL_XDP,
+            XDP_STATISTICS,
+            &mut stats as *mut _ as *mut libc::c_void,
+            &mut optlen,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(XdpStatistics {
+        rx_dropped: stats.rx_dropped,
+        rx_invalid_descs: stats.rx_invalid_descs,
+        tx_invalid_descs: stats.tx_invalid_descs,
+        rx_ring_full: stats.rx_ring_full,
+        rx_fill_ring_empty_descs: stats.rx_fill_ring_empty_descs,
+        tx_ring_empty_descs: stats.tx_ring_empty_descs,
+    })
+}
+
+/// [`XdpStatistics`] accumulated over one or more sockets.
+#[derive(Debug, Default)]
+pub struct XdpSocketStats {
+    pub rx_dropped: AtomicU64,
+    pub rx_invalid_descs: AtomicU64,
+    pub tx_invalid_descs: AtomicU64,
+    pub rx_ring_full: AtomicU64,
+    pub rx_fill_ring_empty_descs: AtomicU64,
+    pub tx_ring_empty_descs: AtomicU64,
+}
+
+impl XdpSocketStats {
+    pub fn add(&self, stats: &XdpStatistics) {
+        for (counter, value) in [
+            (&self.rx_dropped, stats.rx_dropped),
+            (&self.rx_invalid_descs, stats.rx_invalid_descs),
+            (&self.tx_invalid_descs, stats.tx_invalid_descs),
+            (&self.rx_ring_full, stats.rx_ring_full),
+            (
+                &self.rx_fill_ring_empty_descs,
+                stats.rx_fill_ring_empty_descs,
+            ),
+            (&self.tx_ring_empty_descs, stats.tx_ring_empty_descs),
+        ] {
+            counter.fetch_add(value, Ordering::Relaxed);
+        }
+    }
+
+    pub fn load(&self) -> XdpStatistics {
+        XdpStatistics {
+            rx_dropped: self.rx_dropped.load(Ordering::Relaxed),
+            rx_invalid_descs: self.rx_invalid_descs.load(Ordering::Relaxed),
+            tx_invalid_descs: self.tx_invalid_descs.load(Ordering::Relaxed),
+            rx_ring_full: self.rx_ring_full.load(Ordering::Relaxed),
+            rx_fill_ring_empty_descs: self.rx_fill_ring_empty_descs.load(Ordering::Relaxed),
+            tx_ring_empty_descs: self.tx_ring_empty_descs.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How full a ring is, see [`RingOccupancyStats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RingOccupancy {
+    /// Entries in the ring when it was last sampled.
+    pub current: u32,
+    /// The most entries seen in the ring since it was created or the watermark was taken.
+    pub high_watermark: u32,
+    pub capacity: u32,
+}
+
+/// The occupancy of a ring, sampled by the loop driving it and readable from any thread.
+///
+/// The loops sample their rings once per iteration, which costs a couple of relaxed stores. When
+/// the stats are shared by multiple sockets, `current` is the last sample of any of them while
+/// the high watermark covers all of them.
+#[derive(Debug, Default)]
+pub struct RingOccupancyStats {
+    current: AtomicU32,
+    high_watermark: AtomicU32,
+    capacity: AtomicU32,
+}
+
+impl RingOccupancyStats {
+    #[inline]
+    pub(crate) fn sample(&self, current: usize, capacity: usize) {
+        let current = current as u32;
+        self.current.store(current, Ordering::Relaxed);
+        self.capacity.store(capacity as u32, Ordering::Relaxed);
+        // only pay for the read-modify-write when the watermark actually moves
+        if current > self.high_watermark.load(Ordering::Relaxed) {
+            self.high_watermark.fetch_max(current, Ordering::Relaxed);
+        }
+    }
+
+    pub fn load(&self) -> RingOccupancy {
+        RingOccupancy {
+            current: self.current.load(Ordering::Relaxed),
+            high_watermark: self.high_watermark.load(Ordering::Relaxed),
+            capacity: self.capacity.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Returns the high watermark and restarts it from the current occupancy.
+    pub fn take_high_watermark(&self) -> u32 {
+        self.high_watermark
+            .swap(self.current.load(Ordering::Relaxed), Ordering::Relaxed)
+    }
+}
+
+/// [`RingOccupancyStats`] for each ring of a socket. Rings a socket doesn't use stay at zero.
+#[derive(Debug, Default)]
+pub struct XdpRingStats {
+    pub fill: RingOccupancyStats,
+    pub rx: RingOccupancyStats,
+    pub tx: RingOccupancyStats,
+    pub completion: RingOccupancyStats,
+}
+
+// Periodically reads the statistics of a socket and adds what changed to an XdpSocketStats, so
+// that the totals are right when multiple sockets share the same stats.
+pub(crate) struct StatisticsPoller {
+    last: XdpStatistics,
+    last_poll: Instant,
+    enabled: bool,
+}
+
+impl StatisticsPoller {
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    pub(crate) fn new() -> Self {
+        Self {
+            last: XdpStatistics::default(),
+            last_poll: Instant::now(),
+            enabled: true,
+        }
+    }
+
+    // Returns true if the statistics were read.
+    pub(crate) fn poll(
+        &mut self,
+        read: impl FnOnce() -> Result<XdpStatistics, io::Error>,
+        stats: &XdpSocketStats,
+        force: bool,
+    ) -> bool {
+        if !self.enabled || (!force && self.last_poll.elapsed() < Self::INTERVAL) {
+            return false;
+        }
+        self.last_poll = Instant::now();
+        match read() {
+            Ok(current) => {
+                stats.add(&current.saturating_sub(&self.last));
+                self.last = current;
+                true
+            }
+            Err(e) => {
+                // not an XDP socket, e.g. the simulation backend
+                log::debug!("failed to read xdp socket statistics: {e}");
+                self.enabled = false;
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::umem::{PageAlignedMemory, SliceUmem},
+    };
+
+    #[test]
+    fn test_unaligned_umem() {
+        assert_eq!(unaligned_addr(4096), 4096);
+        assert_eq!(unaligned_addr(4096 | (256 << 48)), 4352);
+
+        let mut memory = PageAlignedMemory::alloc(4096, 4).unwrap();
+        assert!(SliceUmem::new_unaligned(&mut memory, 1500).is_err());
+
+        // frames are packed without rounding up to a power of two
+        let mut umem = SliceUmem::new_unaligned(&mut memory, 3000).unwrap();
+        assert!(umem.unaligned());
+        assert_eq!(umem.capacity(), 5);
+        let frame = umem.reserve_with_headroom(256).unwrap();
+        assert_eq!(frame.offset().0, 4 * 3000 + 256);
+        umem.release(frame.offset());
+        assert_eq!(umem.reserve().unwrap().offset().0, 4 * 3000);
+    }
+
+    #[test]
+    fn test_ring_occupancy_stats() {
+        let stats = RingOccupancyStats::default();
+        stats.sample(10, 64);
+        stats.sample(3, 64);
+        assert_eq!(
+            stats.load(),
+            RingOccupancy {
+                current: 3,
+                high_watermark: 10,
+                capacity: 64,
+            }
+        );
+        assert_eq!(stats.take_high_watermark(), 10);
+        assert_eq!(stats.load().high_watermark, 3);
+        stats.sample(5, 64);
+        assert_eq!(stats.take_high_watermark(), 5);
+    }
+
+    #[test]
+    fn test_statistics_poller() {
+        let stats = XdpSocketStats::default();
+        let mut poller = StatisticsPoller::new();
+        let reading = |rx_dropped, tx_invalid_descs| {
+            move || {
+                Ok(XdpStatistics {
+                    rx_dropped,
+                    tx_invalid_descs,
+                    ..XdpStatistics::default()
+                })
+            }
+        };
+
+        poller.poll(reading(3, 1), &stats, true);
+        // not due yet
+        poller.poll(reading(100, 100), &stats, false);
+        poller.poll(reading(5, 1), &stats, true);
+        assert_eq!(
+            stats.load(),
+            XdpStatistics {
+                rx_dropped: 5,
+                tx_invalid_descs: 1,
+                ..XdpStatistics::default()
+            }
+        );
+
+        // a second socket sharing the stats adds to the totals
+        let mut other = StatisticsPoller::new();
+        other.poll(reading(2, 0), &stats, true);
+        assert_eq!(stats.load().rx_dropped, 7);
+
+        // polling stops once reading fails
+        poller.poll(|| Err(io::ErrorKind::InvalidInput.into()), &stats, true);
+        poller.poll(reading(50, 1), &stats, true);
+        assert_eq!(stats.load().rx_dropped, 7);
+    }
+}
@@ -0,0 +1,335 @@
+//! Submitting packets assembled from a prebuilt header and payload slices.
+//!
+//! [`tx_loop`](crate::tx_loop::tx_loop) builds the headers of every packet itself and copies the
+//! payload into the frame. Callers that already have their headers and payloads assembled can
+//! write them to the ring directly, with a single copy into the UMEM, or none at all when the
+//! payload is already in a UMEM frame.
+//!
+//! Producers that generate their packets rather than copy them, like shred signing, can skip the
+//! payload buffer altogether: [`reserve`] hands out frames to build packets in place, which
+//! [`Reservation::commit`] then publishes to the ring.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        packet::{set_udp_frame_len, ETH_HEADER_SIZE, IP_HEADER_SIZE, UDP_HEADER_SIZE},
+        socket::TxQueue,
+        umem::{Frame as _, SliceUmem, SliceUmemFrame, Umem as _},
+    },
+    std::{collections::VecDeque, io::IoSlice, mem, slice},
+};
+
+/// Where the payload of a [`Submission`] is.
+pub enum Payload<'p, 'a> {
+    /// Copied into the frame after the header, in order.
+    Slices(&'p [IoSlice<'p>]),
+    /// Already in a UMEM frame, at `offset..offset + len`. This is the case when the caller's
+    /// memory is registered as the UMEM. The header is written in the bytes right before the
+    /// payload, so `offset` must leave room for it, and nothing is copied.
+    Frame {
+        frame: SliceUmemFrame<'a>,
+        offset: usize,
+        len: usize,
+    },
+}
+
+/// A packet to submit.
+pub struct Submission<'p, 'a> {
+    /// The headers of the packet, e.g. an Ethernet/IPv4/UDP header built once per destination.
+    pub header: &'p [u8],
+    pub payload: Payload<'p, 'a>,
+    /// Set the IP and UDP lengths in `header` to match the payload, see [`set_udp_frame_len`].
+    pub set_udp_len: bool,
+}
+
+impl<'p> Submission<'p, '_> {
+    /// Creates a submission of an Ethernet/IPv4/UDP header template and payload slices to copy.
+    pub fn udp(header: &'p [u8], payload: &'p [IoSlice<'p>]) -> Self {
+        Self {
+            header,
+            payload: Payload::Slices(payload),
+            set_udp_len: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Submitted {
+    /// Packets written to the ring.
+    pub packets: usize,
+    /// Submissions dropped because they didn't fit in a frame.
+    pub rejected: usize,
+}
+
+/// Writes `submissions` to `ring`, assembling each packet in a single pass.
+///
+/// Stops once the ring or the UMEM is out of room, leaving what didn't fit in `submissions`.
+/// Frames of rejected submissions are released back to `umem`. The ring isn't committed, that's
+/// left to the caller along with kicking the driver.
+pub fn submit<'a>(
+    ring: &mut impl TxQueue<Frame = SliceUmemFrame<'a>>,
+    umem: &mut SliceUmem<'a>,
+    submissions: &mut VecDeque<Submission<'_, 'a>>,
+) -> Submitted {
+    const UDP_FRAME_HEADER_SIZE: usize = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE;
+
+    let mut submitted = Submitted::default();
+    while ring.available() > 0 {
+        let Some(submission) = submissions.front() else {
+            break;
+        };
+        if matches!(submission.payload, Payload::Slices(_)) && umem.available() == 0 {
+            break;
+        }
+        let Some(Submission {
+            header,
+            payload,
+            set_udp_len,
+        }) = submissions.pop_front()
+        else {
+            break;
+        };
+
+        let frame_size = umem.frame_size();
+        let (frame, payload_len) = match payload {
+            Payload::Slices(slices) => {
+                let payload_len = slices.iter().map(|slice| slice.len()).sum::<usize>();
+                if header.len() + payload_len > frame_size {
+                    submitted.rejected += 1;
+                    continue;
+                }
+                let mut frame = umem.reserve().unwrap();
+                frame.set_len(header.len() + payload_len);
+                let packet = umem.map_frame_mut(&frame);
+                let (packet_header, mut rest) = packet.split_at_mut(header.len());
+                packet_header.copy_from_slice(header);
+                for slice in slices {
+                    let (dst, tail) = rest.split_at_mut(slice.len());
+                    dst.copy_from_slice(slice);
+                    rest = tail;
+                }
+                (frame, payload_len)
+            }
+            Payload::Frame {
+                mut frame,
+                offset,
+                len,
+            } => {
+                let in_chunk = frame.offset().0 % frame_size;
+                if header.len() > offset || in_chunk + offset + len > frame_size {
+                    submitted.rejected += 1;
+                    umem.release(frame.offset());
+                    continue;
+                }
+                frame.advance(offset - header.len());
+                frame.set_len(header.len() + len);
+                umem.map_frame_mut(&frame)[..header.len()].copy_from_slice(header);
+                (frame, len)
+            }
+        };
+
+        if set_udp_len && header.len() == UDP_FRAME_HEADER_SIZE {
+            set_udp_frame_len(umem.map_frame_mut(&frame), payload_len as u16);
+        }
+        // can't fail, we checked the available space
+        ring.submit(frame, 0).map_err(|_| "ring full").unwrap();
+        submitted.packets += 1;
+    }
+    submitted
+}
+
+/// Reserves up to `n` frames to build packets in, as many as fit in both `ring` and `umem`.
+///
+/// The frames are reserved from `umem` right away and nothing is written to the ring until
+/// [`Reservation::commit`]. Dropping the reservation releases the frames.
+pub fn reserve<'r, 'a, Q: TxQueue<Frame = SliceUmemFrame<'a>>>(
+    ring: &'r mut Q,
+    umem: &'r mut SliceUmem<'a>,
+    n: usize,
+) -> Reservation<'r, 'a, Q> {
+    let n = n.min(ring.available()).min(umem.available());
+    let frames = (0..n).map_while(|_| umem.reserve()).collect();
+    Reservation { ring, umem, frames }
+}
+
+/// Frames reserved with [`reserve`], filled in place before being submitted to the ring.
+pub struct Reservation<'r, 'a, Q: TxQueue<Frame = SliceUmemFrame<'a>>> {
+    ring: &'r mut Q,
+    umem: &'r mut SliceUmem<'a>,
+    frames: Vec<SliceUmemFrame<'a>>,
+}
+
+impl<'a, Q: TxQueue<Frame = SliceUmemFrame<'a>>> Reservation<'_, 'a, Q> {
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Returns the whole buffer of the `index`th frame, the size of a UMEM frame.
+    pub fn buffer_mut(&mut self, index: usize) -> &mut [u8] {
+        self.buffers_mut()
+            .nth(index)
+            .expect("frame index out of range")
+    }
+
+    /// Returns the buffers of all the frames, so that a batch of packets can be built at once.
+    pub fn buffers_mut(&mut self) -> impl ExactSizeIterator<Item = &mut [u8]> {
+        let base = self.umem.as_mut_ptr();
+        let frame_size = self.umem.frame_size();
+        // Safety: each frame is a distinct chunk of the UMEM, reserved by us and not submitted
+        // yet, and the UMEM stays borrowed for as long as the buffers are alive
+        self.frames.iter().map(move |frame| unsafe {
+            slice::from_raw_parts_mut(base.add(frame.offset().0), frame_size)
+        })
+    }
+
+    /// Sets the length of the packet built in the `index`th frame. Frames left empty are released
+    /// instead of being sent.
+    pub fn set_len(&mut self, index: usize, len: usize) {
+        assert!(len <= self.umem.frame_size());
+        self.frames[index].set_len(len);
+    }
+
+    /// Writes the non empty frames to the ring, in order, and commits it. Returns how many were
+    /// written. Kicking the driver is left to the caller.
+    pub fn commit(mut self) -> usize {
+        let mut committed = 0;
+        for frame in mem::take(&mut self.frames) {
+            if frame.is_empty() {
+                self.umem.release(frame.offset());
+                continue;
+            }
+            // can't fail, we only reserved as many frames as there were slots
+            self.ring.submit(frame, 0).map_err(|_| "ring full").unwrap();
+            committed += 1;
+        }
+        self.ring.commit();
+        committed
+    }
+}
+
+impl<'a, Q: TxQueue<Frame = SliceUmemFrame<'a>>> Drop for Reservation<'_, 'a, Q> {
+    fn drop(&mut self) {
+        for frame in self.frames.drain(..) {
+            self.umem.release(frame.offset());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            packet::{parse_udp_frame, write_eth_header, write_ip_header, write_udp_header},
+            sim::{veth_pair, SimSocket},
+            umem::PageAlignedMemory,
+        },
+        std::{net::Ipv4Addr, time::Duration},
+    };
+
+    #[test]
+    fn test_submit() {
+        const FRAME_SIZE: usize = 2048;
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 8).unwrap();
+        let umem = SliceUmem::new(&mut memory, FRAME_SIZE as u32).unwrap();
+        let (endpoint, peer) = veth_pair();
+        let (mut socket, mut tx) = SimSocket::tx(umem, endpoint, 4, 4).unwrap();
+
+        // the template is built for an empty payload
+        let (src_ip, dst_ip) = (Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2));
+        let mut header = [0u8; ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE];
+        write_eth_header(&mut header, &[1; 6], &[2; 6]);
+        write_ip_header(
+            &mut header[ETH_HEADER_SIZE..],
+            &src_ip,
+            &dst_ip,
+            UDP_HEADER_SIZE as u16,
+        );
+        write_udp_header(
+            &mut header[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
+            &src_ip,
+            8000,
+            &dst_ip,
+            9000,
+            0,
+            false,
+        );
+
+        // a payload already in the UMEM, with room for the header in front of it
+        let umem = socket.umem();
+        let mut frame = umem.reserve().unwrap();
+        frame.set_len(FRAME_SIZE);
+        umem.map_frame_mut(&frame)[128..138].copy_from_slice(b"in umem!!!");
+
+        let slices = [IoSlice::new(b"hello "), IoSlice::new(b"world")];
+        let oversized = [IoSlice::new(&[0; FRAME_SIZE])];
+        let mut submissions = VecDeque::from([
+            Submission::udp(&header, &slices),
+            Submission {
+                header: &header,
+                payload: Payload::Frame {
+                    frame,
+                    offset: 128,
+                    len: 10,
+                },
+                set_udp_len: true,
+            },
+            // too large for a frame
+            Submission::udp(&header, &oversized),
+        ]);
+        assert_eq!(
+            submit(&mut tx, umem, &mut submissions),
+            Submitted {
+                packets: 2,
+                rejected: 1
+            }
+        );
+        assert!(submissions.is_empty());
+        tx.commit();
+
+        for payload in [&b"hello world"[..], b"in umem!!!"] {
+            let frame = peer.recv_timeout(Duration::from_secs(5)).unwrap();
+            let packet = parse_udp_frame(&frame, true).unwrap();
+            assert_eq!(packet.payload, payload);
+            assert_eq!(packet.dst_port, 9000);
+        }
+    }
+
+    #[test]
+    fn test_reserve_commit() {
+        const FRAME_SIZE: usize = 2048;
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 8).unwrap();
+        let umem = SliceUmem::new(&mut memory, FRAME_SIZE as u32).unwrap();
+        let (endpoint, peer) = veth_pair();
+        let (mut socket, mut tx) = SimSocket::tx(umem, endpoint, 4, 4).unwrap();
+        let umem = socket.umem();
+
+        // dropping a reservation gives the frames back
+        assert_eq!(reserve(&mut tx, umem, 2).len(), 2);
+        assert_eq!(umem.available(), 8);
+
+        // capped by the room in the ring
+        let mut reservation = reserve(&mut tx, umem, 8);
+        assert_eq!(reservation.len(), 4);
+        for (i, buffer) in reservation.buffers_mut().enumerate() {
+            assert_eq!(buffer.len(), FRAME_SIZE);
+            buffer[..2].copy_from_slice(&[0xaa, i as u8]);
+        }
+        for i in 0..3 {
+            reservation.set_len(i, 2 + i);
+        }
+        // the last frame is left empty and isn't sent
+        assert_eq!(reservation.commit(), 3);
+        assert_eq!(umem.available(), 5);
+
+        for i in 0..3u8 {
+            let frame = peer.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(&frame[..2], &[0xaa, i]);
+            assert_eq!(frame.len(), 2 + i as usize);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Tracing of the tx path.
+//!
+//! With the `tracing` feature enabled, the tx loop opens a span per batch of packets it writes to
+//! the ring and emits `TRACE` level events for each step a packet goes through: submission to the
+//! loop, frame acquisition, ring enqueue, driver kicks and completion. Completions are tagged
+//! with the id of the batch their frame was written in, so a slow batch can be followed end to
+//! end in a trace viewer even though its frames complete while later batches are being written.
+//!
+//! Without the feature all of this compiles to nothing, except for the events also recorded in
+//! the [schedule log](crate::schedule) when the tx loop is given one.
+
+#[cfg(feature = "tracing")]
+use tracing::span::EnteredSpan;
+use {
+    crate::{
+        schedule::{ScheduleEvent, ScheduleRecorder},
+        umem::{FrameOffset, Umem},
+    },
+    std::net::SocketAddr,
+};
+
+pub(crate) struct TxTracer<'a> {
+    schedule: Option<&'a ScheduleRecorder>,
+    #[cfg(feature = "tracing")]
+    batch_id: u64,
+    #[cfg(feature = "tracing")]
+    frame_size: usize,
+    // the batch each frame was last written in
+    #[cfg(feature = "tracing")]
+    frame_batches: Vec<u64>,
+    #[cfg(feature = "tracing")]
+    span: Option<EnteredSpan>,
+}
+
+#[cfg(feature = "tracing")]
+impl<'a> TxTracer<'a> {
+    pub(crate) fn new(umem: &impl Umem, schedule: Option<&'a ScheduleRecorder>) -> Self {
+        Self {
+            schedule,
+            batch_id: 0,
+            frame_size: umem.frame_size(),
+            frame_batches: vec![0; umem.capacity()],
+            span: None,
+        }
+    }
+
+    /// Ends the current batch span, if any, and starts a new one.
+    pub(crate) fn begin_batch(&mut self, packets: usize, priority_packets: usize) {
+        self.record_batch(packets, priority_packets);
+        self.span = None;
+        self.batch_id = self.batch_id.wrapping_add(1);
+        self.span = Some(
+            tracing::trace_span!(
+                "xdp_tx_batch",
+                batch_id = self.batch_id,
+                packets,
+                priority_packets
+            )
+            .entered(),
+        );
+    }
+
+    pub(crate) fn end_batch(&mut self) {
+        self.span = None;
+    }
+
+    pub(crate) fn submitted(&self, destinations: usize, priority: bool) {
+        tracing::trace!(destinations, priority, "submitted");
+    }
+
+    pub(crate) fn frame_acquired(&self, offset: FrameOffset) {
+        tracing::trace!(frame = offset.0, "frame acquired");
+    }
+
+    pub(crate) fn enqueued(&mut self, offset: FrameOffset, addr: &SocketAddr, priority: bool) {
+        self.record_enqueued(offset, priority);
+        if let Some(batch) = self.frame_batches.get_mut(offset.0 / self.frame_size) {
+            *batch = self.batch_id;
+        }
+        tracing::trace!(frame = offset.0, %addr, "ring enqueue");
+    }
+
+    pub(crate) fn dropped(&self, addr: &SocketAddr, reason: &'static str) {
+        self.record(ScheduleEvent::Dropped);
+        tracing::trace!(%addr, reason, "dropped");
+    }
+
+    pub(crate) fn completed(&self, offset: FrameOffset) {
+        self.record_completed(offset);
+        let batch_id = self
+            .frame_batches
+            .get(offset.0 / self.frame_size)
+            .copied()
+            .unwrap_or_default();
+        tracing::trace!(frame = offset.0, batch_id, "completion");
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl<'a> TxTracer<'a> {
+    #[inline(always)]
+    pub(crate) fn new(_umem: &impl Umem, schedule: Option<&'a ScheduleRecorder>) -> Self {
+        Self { schedule }
+    }
+
+    #[inline(always)]
+    pub(crate) fn begin_batch(&mut self, packets: usize, priority_packets: usize) {
+        self.record_batch(packets, priority_packets);
+    }
+
+    #[inline(always)]
+    pub(crate) fn end_batch(&mut self) {}
+
+    #[inline(always)]
+    pub(crate) fn submitted(&self, _destinations: usize, _priority: bool) {}
+
+    #[inline(always)]
+    pub(crate) fn frame_acquired(&self, _offset: FrameOffset) {}
+
+    #[inline(always)]
+    pub(crate) fn enqueued(&mut self, offset: FrameOffset, _addr: &SocketAddr, priority: bool) {
+        self.record_enqueued(offset, priority);
+    }
+
+    #[inline(always)]
+    pub(crate) fn dropped(&self, _addr: &SocketAddr, _reason: &'static str) {
+        self.record(ScheduleEvent::Dropped);
+    }
+
+    #[inline(always)]
+    pub(crate) fn completed(&self, offset: FrameOffset) {
+        self.record_completed(offset);
+    }
+}
+
+impl TxTracer<'_> {
+    #[inline(always)]
+    fn record(&self, event: ScheduleEvent) {
+        if let Some(schedule) = self.schedule {
+            schedule.record(event);
+        }
+    }
+
+    #[inline(always)]
+    fn record_batch(&self, packets: usize, priority_packets: usize) {
+        self.record(ScheduleEvent::Batch {
+            packets: packets as u32,
+            priority_packets: priority_packets as u32,
+        });
+    }
+
+    #[inline(always)]
+    fn record_enqueued(&self, offset: FrameOffset, priority: bool) {
+        if let Some(schedule) = self.schedule {
+            schedule.record(ScheduleEvent::Enqueued {
+                frame: schedule.frame(offset),
+                priority,
+            });
+        }
+    }
+
+    #[inline(always)]
+    fn record_completed(&self, offset: FrameOffset) {
+        if let Some(schedule) = self.schedule {
+            schedule.record(ScheduleEvent::Completed {
+                frame: schedule.frame(offset),
+            });
+        }
+    }
+}
+
+/// Records a kick of the driver after `pending` packets were written since the previous one.
+#[inline(always)]
+pub(crate) fn kicked(pending: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::trace!(pending, "kick");
+    #[cfg(not(feature = "tracing"))]
+    let _ = pending;
+}
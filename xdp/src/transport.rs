@@ -0,0 +1,413 @@
+//! A common interface for sending datagrams through either the XDP tx path or a regular socket.
+
+use {
+    crate::{
+        device::{NetworkDevice, QueueId},
+        load_xdp_program,
+        netlink::MacAddress,
+        shaping::TrafficClass,
+        tx_loop::{tx_loop, TxLoopConfig},
+        tx_queues::{select_class_tx_queues, select_tx_queues, QueueReservation},
+    },
+    caps::{
+        CapSet,
+        Capability::{CAP_BPF, CAP_NET_ADMIN, CAP_NET_RAW, CAP_PERFMON},
+    },
+    crossbeam_channel::{Receiver, Sender, TryRecvError, TrySendError},
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        error::Error,
+        io,
+        net::{SocketAddr, UdpSocket},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Poll, Waker},
+        thread::{self, Builder},
+        time::Duration,
+    },
+};
+
+/// Something datagrams can be sent through.
+pub trait DatagramTransport: Send + Sync {
+    /// Sends `payload` to each of `addrs`.
+    ///
+    /// Sending is best effort: implementations may drop datagrams when they can't keep up, in which
+    /// case they return an error of kind [`io::ErrorKind::WouldBlock`].
+    fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()>;
+}
+
+impl DatagramTransport for UdpSocket {
+    fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+        let mut result = Ok(());
+        for addr in addrs {
+            if let Err(e) = UdpSocket::send_to(self, &payload, addr) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+/// Wakes up tasks waiting for room in an [`XdpTransport`].
+///
+/// Whoever drains the tx loops' drop channel should call [`notify`](Self::notify) as payloads come
+/// back, see [`XdpTransport::poll_writable`].
+#[derive(Clone, Debug, Default)]
+pub struct WritableNotifier {
+    wakers: Arc<Mutex<Vec<Waker>>>,
+}
+
+impl WritableNotifier {
+    fn register(&self, waker: &Waker) {
+        let mut wakers = self.wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    pub fn notify(&self) {
+        let wakers = std::mem::take(&mut *self.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Transport feeding one or more [`tx_loop`](crate::tx_loop::tx_loop)s.
+pub struct XdpTransport {
+    senders: Vec<Sender<(Vec<SocketAddr>, Bytes)>>,
+    next: AtomicUsize,
+    writable: WritableNotifier,
+}
+
+impl XdpTransport {
+    /// Creates a transport spreading datagrams over `queues` channels of capacity `channel_cap`.
+    ///
+    /// Each returned receiver must be passed to a tx loop.
+    #[allow(clippy::type_complexity)]
+    pub fn new(
+        queues: usize,
+        channel_cap: usize,
+    ) -> (Self, Vec<Receiver<(Vec<SocketAddr>, Bytes)>>) {
+        let (senders, receivers) = (0..queues)
+            .map(|_| crossbeam_channel::bounded(channel_cap))
+            .unzip();
+        (
+            Self {
+                senders,
+                next: AtomicUsize::new(0),
+                writable: WritableNotifier::default(),
+            },
+            receivers,
+        )
+    }
+
+    pub fn queues(&self) -> usize {
+        self.senders.len()
+    }
+
+    /// Queues each of `payloads` to `addr` on the tx loop at index `queue`.
+    ///
+    /// Payloads are queued either all or none, unless the queue is concurrently filled by another
+    /// thread in which case the payloads that don't fit are dropped.
+    pub fn send_all_to_queue(
+        &self,
+        queue: usize,
+        payloads: impl ExactSizeIterator<Item = Bytes>,
+        addr: SocketAddr,
+    ) -> io::Result<()> {
+        let sender = &self.senders[queue % self.senders.len()];
+        let room = sender
+            .capacity()
+            .unwrap_or(usize::MAX)
+            .saturating_sub(sender.len());
+        if payloads.len() > room {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        for payload in payloads {
+            try_send(sender, vec![addr], payload)?;
+        }
+        Ok(())
+    }
+
+    /// Returns `Ready` if every queue has room, otherwise registers the task to be woken once
+    /// the notifier returned by [`writable_notifier`](Self::writable_notifier) fires.
+    pub fn poll_writable(&self, cx: &mut Context) -> Poll<()> {
+        if !self.is_full() {
+            return Poll::Ready(());
+        }
+        self.writable.register(cx.waker());
+        // payloads might have been sent while we were registering
+        if self.is_full() {
+            Poll::Pending
+        } else {
+            Poll::Ready(())
+        }
+    }
+
+    pub fn writable_notifier(&self) -> WritableNotifier {
+        self.writable.clone()
+    }
+
+    fn is_full(&self) -> bool {
+        self.senders.iter().any(|sender| sender.is_full())
+    }
+}
+
+fn try_send(
+    sender: &Sender<(Vec<SocketAddr>, Bytes)>,
+    addrs: Vec<SocketAddr>,
+    payload: Bytes,
+) -> io::Result<()> {
+    match sender.try_send((addrs, payload)) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(_)) => Err(io::ErrorKind::WouldBlock.into()),
+        Err(TrySendError::Disconnected(_)) => Err(io::ErrorKind::BrokenPipe.into()),
+    }
+}
+
+impl DatagramTransport for XdpTransport {
+    fn send_to(&self, payload: Bytes, addrs: &[SocketAddr]) -> io::Result<()> {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.senders.len();
+        try_send(&self.senders[index], addrs.to_vec(), payload)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct XdpTransportConfig {
+    /// The interface to send from. Defaults to the interface of the default route.
+    pub interface: Option<String>,
+    /// CPUs to run the tx loops on, one per NIC queue.
+    pub cpus: Vec<usize>,
+    pub zero_copy: bool,
+    pub src_port: u16,
+    /// Sends every packet to this MAC address instead of looking up the next hop in the routing
+    /// table. Needed on interfaces the kernel doesn't route through, like a backup uplink.
+    pub dest_mac: Option<MacAddress>,
+    /// Queues the tx loops must not bind to, e.g. those used by an
+    /// [`RxService`](crate::rx_loop::RxService) on the same interface.
+    pub rx_queues: Vec<QueueId>,
+    /// Whether the device may be reconfigured to free up queues for tx, see
+    /// [`select_tx_queues`].
+    pub queue_reservation: QueueReservation,
+    /// The kind of traffic sent through the transports. On devices configured with mqprio, the
+    /// tx loops bind to the queues of the hardware traffic class it maps to, see
+    /// [`select_class_tx_queues`]. `queue_reservation` is ignored then.
+    pub traffic_class: Option<TrafficClass>,
+    /// The capacity of the normal priority channel in front of each tx loop.
+    pub channel_cap: usize,
+    /// The capacity of the high priority channel in front of each tx loop.
+    pub priority_channel_cap: usize,
+    pub tx_loop: TxLoopConfig,
+}
+
+impl XdpTransportConfig {
+    const DEFAULT_CHANNEL_CAP: usize = 100_000;
+    const DEFAULT_PRIORITY_CHANNEL_CAP: usize = 1_024;
+
+    pub fn new(cpus: Vec<usize>, src_port: u16) -> Self {
+        Self {
+            interface: None,
+            cpus,
+            zero_copy: false,
+            src_port,
+            dest_mac: None,
+            rx_queues: Vec::new(),
+            queue_reservation: QueueReservation::default(),
+            traffic_class: None,
+            channel_cap: Self::DEFAULT_CHANNEL_CAP,
+            priority_channel_cap: Self::DEFAULT_PRIORITY_CHANNEL_CAP,
+            tx_loop: TxLoopConfig::default(),
+        }
+    }
+}
+
+/// The tx loops backing a pair of [`XdpTransport`]s.
+///
+/// The high priority transport is meant for latency sensitive traffic like votes: its packets are
+/// sent ahead of the normal priority ones and the driver is kicked right away instead of waiting
+/// for a full batch.
+pub struct XdpTransportService {
+    if_index: u32,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl XdpTransportService {
+    /// Starts one tx loop per configured CPU and returns the (normal, high) priority transports.
+    ///
+    /// The tx loops exit once both transports have been dropped.
+    pub fn new(
+        config: XdpTransportConfig,
+    ) -> Result<(Self, XdpTransport, XdpTransport), Box<dyn Error>> {
+        // switch to higher caps while we setup XDP. We assume that an error in
+        // this function is irrecoverable so we don't try to drop on errors.
+        for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_BPF, CAP_PERFMON] {
+            caps::raise(None, CapSet::Effective, cap)
+                .map_err(|e| format!("failed to raise {cap:?} capability: {e}"))?;
+        }
+
+        let dev = Arc::new(match config.interface {
+            Some(interface) => NetworkDevice::new(interface)?,
+            None => NetworkDevice::new_from_default_route()?,
+        });
+
+        let ebpf = if config.zero_copy {
+            Some(load_xdp_program(&dev).map_err(|e| format!("failed to attach xdp program: {e}"))?)
+        } else {
+            None
+        };
+
+        let queue_ids = match config.traffic_class {
+            Some(class) => {
+                select_class_tx_queues(&dev, class, config.cpus.len(), &config.rx_queues)?
+            }
+            None => select_tx_queues(
+                &dev,
+                config.cpus.len(),
+                &config.rx_queues,
+                config.queue_reservation,
+            )?,
+        };
+        log::info!("sending on {} queues {queue_ids:?}", dev.name());
+
+        for cap in [CAP_NET_ADMIN, CAP_NET_RAW, CAP_BPF, CAP_PERFMON] {
+            caps::drop(None, CapSet::Effective, cap).unwrap();
+        }
+
+        let queues = config.cpus.len();
+        let (transport, receivers) = XdpTransport::new(queues, config.channel_cap);
+        let (priority_transport, priority_receivers) =
+            XdpTransport::new(queues, config.priority_channel_cap);
+
+        let writable = transport.writable_notifier();
+        let priority_writable = priority_transport.writable_notifier();
+        let mut threads = vec![];
+
+        // sent payloads come back through this channel
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        threads.push(
+            Builder::new()
+                .name("solXdpTxDrop".to_owned())
+                .spawn(move || {
+                    loop {
+                        // drop payloads in a dedicated thread so that we never lock/madvise() from
+                        // the xdp thread
+                        let received = drop_receiver.try_recv();
+                        match received {
+                            Ok(item) => drop(item),
+                            Err(TryRecvError::Empty) => {
+                                // the tx loops have made progress, wake up blocked senders
+                                writable.notify();
+                                priority_writable.notify();
+                                thread::sleep(Duration::from_millis(1));
+                            }
+                            Err(TryRecvError::Disconnected) => break,
+                        }
+                    }
+                    // move the ebpf program here so it stays attached until we exit
+                    drop(ebpf);
+                })
+                .unwrap(),
+        );
+
+        for (i, (((receiver, priority_receiver), cpu_id), queue_id)) in receivers
+            .into_iter()
+            .zip(priority_receivers)
+            .zip(config.cpus)
+            .zip(queue_ids)
+            .enumerate()
+        {
+            let dev = Arc::clone(&dev);
+            let drop_sender = drop_sender.clone();
+            let tx_loop_config = config.tx_loop.clone();
+            let src_port = config.src_port;
+            let zero_copy = config.zero_copy;
+            let dest_mac = config.dest_mac;
+            threads.push(
+                Builder::new()
+                    .name(format!("solXdpTx{i:02}"))
+                    .spawn(move || {
+                        tx_loop(
+                            cpu_id,
+                            &dev,
+                            queue_id,
+                            zero_copy,
+                            None,
+                            None,
+                            src_port,
+                            dest_mac,
+                            receiver,
+                            Some(priority_receiver),
+                            drop_sender,
+                            tx_loop_config,
+                        )
+                    })
+                    .unwrap(),
+            );
+        }
+
+        let if_index = dev.if_index();
+        Ok((Self { if_index, threads }, transport, priority_transport))
+    }
+
+    /// Returns the index of the interface the tx loops send from.
+    pub fn if_index(&self) -> u32 {
+        self.if_index
+    }
+
+    pub fn join(self) -> thread::Result<()> {
+        for handle in self.threads {
+            handle.join()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::Duration};
+
+    #[test]
+    // plain sockets are fine for a loopback test
+    #[allow(clippy::disallowed_methods)]
+    fn test_udp_socket_transport() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        DatagramTransport::send_to(&sender, Bytes::from_static(b"hello"), &[addr, addr]).unwrap();
+        let mut buf = [0u8; 16];
+        for _ in 0..2 {
+            let (len, from) = receiver.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"hello");
+            assert_eq!(from, sender.local_addr().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_xdp_transport() {
+        let (transport, receivers) = XdpTransport::new(2, 1);
+        let addr: SocketAddr = "10.0.0.1:8000".parse().unwrap();
+        transport
+            .send_to(Bytes::from_static(b"a"), &[addr])
+            .unwrap();
+        transport
+            .send_to(Bytes::from_static(b"b"), &[addr])
+            .unwrap();
+        // both queues are full
+        assert_eq!(
+            transport
+                .send_to(Bytes::from_static(b"c"), &[addr])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(receivers[0].try_recv().unwrap().1, Bytes::from_static(b"a"));
+        assert_eq!(receivers[1].try_recv().unwrap().1, Bytes::from_static(b"b"));
+    }
+}
@@ -0,0 +1,350 @@
+//! Tuning a NIC and the CPUs around it for the rx and tx loops.
+//!
+//! Getting good throughput out of the loops takes a NIC with one channel per loop, RSS and n-tuple
+//! filters steering received traffic to the rx queues only, the interrupts of each queue handled
+//! next to the loop's CPU, and the loops pinned to CPUs on the NIC's NUMA node. [`plan_tuning`]
+//! works all of that out from the device and a CPU budget, [`apply_tuning`] configures the device
+//! and the interrupts accordingly. The pinning part of the plan is what the rx and tx loops must
+//! be configured with.
+//!
+//! Queues are numbered with the rx queues first, since drivers spread RSS from queue 0, and the tx
+//! queues after them.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        device::{Channels, FlowRule, NetworkDevice, QueueId},
+        error::XdpError,
+        placement::{
+            apply_placement, plan_placement, verify_placement, CpuTopology, Misalignment,
+            PlacementError, QueuePlacement,
+        },
+    },
+    std::fmt,
+    thiserror::Error,
+};
+
+#[derive(Debug, Error)]
+pub enum TunerError {
+    #[error("need {needed} combined channels but {if_name} supports at most {max}")]
+    NotEnoughChannels {
+        if_name: String,
+        needed: u32,
+        max: u32,
+    },
+
+    #[error("no rx queue to steer ports {0:?} to")]
+    NoRxQueue(Vec<u16>),
+
+    #[error(transparent)]
+    Placement(#[from] PlacementError),
+
+    #[error(transparent)]
+    Xdp(#[from] XdpError),
+}
+
+/// What to tune the device for.
+#[derive(Clone, Debug, Default)]
+pub struct TunerConfig {
+    pub rx_queues: usize,
+    pub tx_queues: usize,
+    /// The CPUs the loops and the interrupts of their queues may use, two per queue.
+    pub cpus: Vec<usize>,
+    /// UDP ports steered to the rx queues with n-tuple filters, spread over the queues.
+    pub rx_ports: Vec<u16>,
+}
+
+/// The configuration [`plan_tuning`] worked out for a device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TuningPlan {
+    pub if_name: String,
+    /// The channels to configure, `None` if the device already has the right number.
+    pub channels: Option<Channels>,
+    /// The RSS indirection table spreading received flows over the rx queues. Empty if the
+    /// driver doesn't report one.
+    pub rss_table: Vec<u32>,
+    /// The n-tuple filters to add, their location is picked when they're added.
+    pub flow_rules: Vec<FlowRule>,
+    /// The existing n-tuple filters on `rx_ports` that steer elsewhere and must be removed.
+    pub stale_flow_rules: Vec<FlowRule>,
+    pub rx: Vec<QueuePlacement>,
+    pub tx: Vec<QueuePlacement>,
+}
+
+impl TuningPlan {
+    pub fn rx_queue_ids(&self) -> Vec<QueueId> {
+        self.rx.iter().map(|p| QueueId(p.queue_id)).collect()
+    }
+
+    pub fn tx_queue_ids(&self) -> Vec<QueueId> {
+        self.tx.iter().map(|p| QueueId(p.queue_id)).collect()
+    }
+}
+
+/// Plans the tuning of `dev` for `config`, without changing anything.
+pub fn plan_tuning(dev: &NetworkDevice, config: &TunerConfig) -> Result<TuningPlan, TunerError> {
+    let channels = dev.channels()?;
+    let rss_size = dev
+        .rss_indirection_table()
+        .map(|table| table.len())
+        .unwrap_or_else(|e| {
+            log::warn!("can't read the RSS table of {}: {e}", dev.name());
+            0
+        });
+    let flow_rules = if config.rx_ports.is_empty() {
+        Vec::new()
+    } else {
+        dev.flow_rules()?
+    };
+    let topology = CpuTopology::from_sysfs()?;
+    plan(
+        dev.name(),
+        channels,
+        rss_size,
+        &flow_rules,
+        dev.numa_node().map_err(PlacementError::from)?,
+        &topology,
+        config,
+    )
+}
+
+fn plan(
+    if_name: &str,
+    channels: Channels,
+    rss_size: usize,
+    existing_rules: &[FlowRule],
+    nic_node: Option<usize>,
+    topology: &CpuTopology,
+    config: &TunerConfig,
+) -> Result<TuningPlan, TunerError> {
+    let queues = config.rx_queues + config.tx_queues;
+    let needed = queues as u32;
+    if needed > channels.max_combined {
+        return Err(TunerError::NotEnoughChannels {
+            if_name: if_name.to_owned(),
+            needed,
+            max: channels.max_combined,
+        });
+    }
+    if config.rx_queues == 0 && !config.rx_ports.is_empty() {
+        return Err(TunerError::NoRxQueue(config.rx_ports.clone()));
+    }
+
+    let mut placements = plan_placement(&config.cpus, queues, nic_node, topology)?;
+    let tx = placements.split_off(config.rx_queues);
+    let rx = placements;
+
+    let rss_table = if config.rx_queues == 0 {
+        Vec::new()
+    } else {
+        (0..config.rx_queues as u32)
+            .cycle()
+            .take(rss_size)
+            .collect()
+    };
+
+    let mut flow_rules = Vec::new();
+    let mut stale_flow_rules = Vec::new();
+    for (i, &port) in config.rx_ports.iter().enumerate() {
+        let queue_id = QueueId((i % config.rx_queues) as u64);
+        let existing = existing_rules.iter().filter(|rule| rule.port == port);
+        let mut present = false;
+        for rule in existing {
+            if rule.queue_id == queue_id && !present {
+                present = true;
+            } else {
+                stale_flow_rules.push(*rule);
+            }
+        }
+        if !present {
+            flow_rules.push(FlowRule {
+                location: 0,
+                port,
+                queue_id,
+            });
+        }
+    }
+
+    Ok(TuningPlan {
+        if_name: if_name.to_owned(),
+        channels: (channels.combined != needed).then_some(Channels {
+            combined: needed,
+            ..channels
+        }),
+        rss_table,
+        flow_rules,
+        stale_flow_rules,
+        rx,
+        tx,
+    })
+}
+
+/// Configures `dev` and the interrupts of its queues as planned, then checks the result.
+///
+/// Returns what's still misaligned afterwards, e.g. interrupts the kernel wouldn't move.
+/// Requires `CAP_NET_ADMIN` and write access to `/proc/irq`.
+pub fn apply_tuning(
+    dev: &NetworkDevice,
+    plan: &TuningPlan,
+) -> Result<Vec<Misalignment>, TunerError> {
+    // changing the channels resets the RSS table, so it goes first
+    if let Some(channels) = plan.channels.as_ref() {
+        log::info!(
+            "setting {} combined channels on {}",
+            channels.combined,
+            dev.name()
+        );
+        dev.set_channels(channels)?;
+    }
+    if !plan.rss_table.is_empty() {
+        log::info!("steering RSS of {} to the rx queues", dev.name());
+        dev.set_rss_indirection_table(&plan.rss_table)?;
+    }
+    for rule in &plan.stale_flow_rules {
+        log::info!("removing n-tuple filter {rule:?} from {}", dev.name());
+        dev.remove_flow_rule(rule.location)?;
+    }
+    for rule in &plan.flow_rules {
+        let rule = dev.add_flow_rule(rule.port, rule.queue_id)?;
+        log::info!("added n-tuple filter {rule:?} to {}", dev.name());
+    }
+
+    let placements = plan.rx.iter().chain(&plan.tx).copied().collect::<Vec<_>>();
+    apply_placement(dev, &placements)?;
+    let topology = CpuTopology::from_sysfs()?;
+    Ok(verify_placement(dev, &placements, &topology)?)
+}
+
+impl fmt::Display for TuningPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cpus = |placements: &[QueuePlacement]| {
+            placements
+                .iter()
+                .map(|p| p.worker_cpu.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        writeln!(f, "interface {}", self.if_name)?;
+        match &self.channels {
+            Some(channels) => writeln!(f, "  set combined channels to {}", channels.combined)?,
+            None => writeln!(f, "  keep the channels as they are")?,
+        }
+        if !self.rss_table.is_empty() {
+            writeln!(
+                f,
+                "  spread RSS over rx queues 0-{} ({} buckets)",
+                self.rx.len().saturating_sub(1),
+                self.rss_table.len()
+            )?;
+        }
+        for rule in &self.stale_flow_rules {
+            writeln!(
+                f,
+                "  remove n-tuple filter {}: udp4 dst-port {} -> queue {}",
+                rule.location, rule.port, rule.queue_id.0
+            )?;
+        }
+        for rule in &self.flow_rules {
+            writeln!(
+                f,
+                "  add n-tuple filter: udp4 dst-port {} -> queue {}",
+                rule.port, rule.queue_id.0
+            )?;
+        }
+        for (kind, placements) in [("rx", &self.rx), ("tx", &self.tx)] {
+            for p in placements {
+                writeln!(
+                    f,
+                    "  {kind} queue {}: worker on cpu {}, interrupts on cpu {}",
+                    p.queue_id, p.worker_cpu, p.irq_cpu
+                )?;
+            }
+        }
+        writeln!(f, "pin the rx loops to cpus {}", cpus(&self.rx))?;
+        write!(f, "pin the tx loops to cpus {}", cpus(&self.tx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        // one node with 8 cores, SMT siblings are cpu and cpu + 8
+        let topology = CpuTopology::new(
+            (0..16).map(|cpu| (cpu, 0)),
+            (0..16).map(|cpu| (cpu, vec![cpu % 8, cpu % 8 + 8])),
+        );
+        let channels = Channels {
+            max_combined: 8,
+            combined: 8,
+            ..Channels::default()
+        };
+        let config = TunerConfig {
+            rx_queues: 2,
+            tx_queues: 1,
+            cpus: vec![2, 3, 4, 10, 11, 12],
+            rx_ports: vec![8001, 8002, 8003],
+        };
+        let existing = [
+            FlowRule {
+                location: 0,
+                port: 8001,
+                queue_id: QueueId(0),
+            },
+            FlowRule {
+                location: 1,
+                port: 8002,
+                queue_id: QueueId(5),
+            },
+        ];
+
+        let tuning = plan("eth0", channels, 8, &existing, Some(0), &topology, &config).unwrap();
+        assert_eq!(tuning.channels.unwrap().combined, 3);
+        assert_eq!(tuning.rss_table, [0, 1, 0, 1, 0, 1, 0, 1]);
+        // 8001 is already steered to its queue, 8002 is steered to a queue that's going away
+        assert_eq!(tuning.stale_flow_rules, existing[1..]);
+        assert_eq!(
+            tuning
+                .flow_rules
+                .iter()
+                .map(|rule| (rule.port, rule.queue_id.0))
+                .collect::<Vec<_>>(),
+            [(8002, 1), (8003, 0)]
+        );
+        assert_eq!(tuning.rx_queue_ids(), [QueueId(0), QueueId(1)]);
+        assert_eq!(tuning.tx_queue_ids(), [QueueId(2)]);
+        assert_eq!(
+            tuning
+                .rx
+                .iter()
+                .chain(&tuning.tx)
+                .map(|p| (p.worker_cpu, p.irq_cpu))
+                .collect::<Vec<_>>(),
+            [(2, 10), (3, 11), (4, 12)]
+        );
+        assert!(tuning.to_string().ends_with("pin the tx loops to cpus 4"));
+
+        assert!(matches!(
+            plan(
+                "eth0",
+                channels,
+                8,
+                &[],
+                None,
+                &topology,
+                &TunerConfig {
+                    rx_queues: 8,
+                    tx_queues: 1,
+                    ..config
+                }
+            ),
+            Err(TunerError::NotEnoughChannels {
+                needed: 9,
+                max: 8,
+                ..
+            })
+        ));
+    }
+}
@@ -2,30 +2,411 @@
 
 use {
     crate::{
+        blocklist::DestinationBlocklist,
+        capture::{CaptureRecorder, CaptureRing, Direction},
+        delivery::{DeliveryRecorder, DeliveryStats},
         device::{NetworkDevice, QueueId, RingSizes},
-        netlink::MacAddress,
-        packet::{
-            write_eth_header, write_ip_header, write_udp_header, ETH_HEADER_SIZE, IP_HEADER_SIZE,
-            UDP_HEADER_SIZE,
+        header_cache::{
+            build_udp_frame_header, HeaderCache, UdpFrameHeader, UDP_FRAME_HEADER_SIZE,
         },
-        route::Router,
-        socket::{Socket, Tx, TxRing},
-        umem::{Frame as _, PageAlignedMemory, SliceUmem, SliceUmemFrame, Umem as _},
+        metrics::LoopThreads,
+        mirror::{MirrorConfig, TxMirror},
+        netlink::{MacAddress, RouteMonitor},
+        packet::{push_vlan_tag, set_udp_frame_len, VlanTag, VLAN_HEADER_SIZE},
+        pcap::{PcapTap, PcapTapConfig},
+        route::{NextHop, RouteError, Router},
+        schedule::{ScheduleEvent, ScheduleHeader, ScheduleRecorder},
+        shaping::{TrafficClass, TrafficShaper},
+        socket::{Socket, StatisticsPoller, TxQueue, XdpRingStats, XdpSocketStats},
+        trace::{self, TxTracer},
+        umem::{AllocError, Frame as _, FrameOffset, PageAlignedMemory, SliceUmem, Umem},
+        warmup::{PeerWarmup, Warmer},
     },
-    agave_cpu_utils::set_cpu_affinity,
+    agave_cpu_utils::pin_thread,
     caps::{
         CapSet,
         Capability::{CAP_NET_ADMIN, CAP_NET_RAW},
     },
-    crossbeam_channel::{Receiver, Sender, TryRecvError},
-    libc::{sysconf, _SC_PAGESIZE},
+    crossbeam_channel::{Receiver, Select, Sender, TryRecvError},
+    libc::{clock_gettime, sysconf, timespec, _SC_PAGESIZE, CLOCK_THREAD_CPUTIME_ID},
     std::{
-        net::{IpAddr, Ipv4Addr, SocketAddr},
+        collections::HashMap,
+        mem,
+        net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+        path::PathBuf,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex,
+        },
         thread,
-        time::Duration,
+        time::{Duration, Instant},
     },
 };
 
+/// Optional features of the tx loop.
+#[derive(Clone, Debug, Default)]
+pub struct TxLoopConfig {
+    /// Capture (sampled) transmitted frames to a pcap file.
+    pub pcap: Option<PcapTapConfig>,
+    /// Send copies of (sampled) transmitted frames to a channel.
+    pub mirror: Option<MirrorConfig>,
+    /// Keep the start of the last transmitted frames in memory, to be dumped on demand.
+    pub capture: Option<CaptureRing>,
+    /// Counters updated as packets are sent and completed.
+    pub stats: Option<Arc<TxLoopStats>>,
+    /// Counters of the packets sent, completed and dropped by destination.
+    pub delivery: Option<Arc<DeliveryStats>>,
+    /// When to kick the driver after writing packets to the ring.
+    pub kick: KickPolicy,
+    /// How long to wait for room in the ring before dropping packets.
+    pub retry: TxRetryPolicy,
+    /// How the UDP source port of each destination is picked.
+    pub src_port: SrcPortPolicy,
+    /// Route packets as if they were marked with this fwmark, so that they follow the same
+    /// policy routing rules as the host's traffic marked with `SO_MARK` or by the firewall.
+    pub fwmark: Option<u32>,
+    /// Recreate the socket when the driver stops completing frames.
+    pub watchdog: Option<TxWatchdogConfig>,
+    /// How the loop waits for packets when its channels are empty.
+    pub idle: TxIdlePolicy,
+    /// Tag the transmitted frames with a VLAN and priority code point.
+    pub vlan: Option<VlanMarking>,
+    /// Record the scheduling decisions of the loop to a file for
+    /// [`replay_schedule`](crate::schedule::replay_schedule). Each tx loop writes to its own
+    /// file, suffixed with the queue id.
+    pub schedule_log: Option<PathBuf>,
+    /// Drop the packets to these destinations instead of sending them.
+    pub blocklist: Option<DestinationBlocklist>,
+    /// Get ready to send to the peers in here before traffic to them starts.
+    pub warmup: Option<PeerWarmup>,
+    /// Drop the packets from the normal priority channel that are over the limits of the shaper,
+    /// counting them as the class. Priority packets aren't limited.
+    pub shaping: Option<(TrafficShaper, TrafficClass)>,
+}
+
+/// 802.1Q tagging of the transmitted frames, so that switches can prioritize them by their PCP.
+///
+/// The tags are written into the frames, so the tx loop must run on the parent device of the
+/// VLAN rather than on the VLAN device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VlanMarking {
+    pub vlan_id: u16,
+    /// The PCP of the packets from the normal priority channel.
+    pub pcp: u8,
+    /// The PCP of the packets from the high priority channel.
+    pub priority_pcp: u8,
+}
+
+impl VlanMarking {
+    /// Marks normal priority packets as turbine traffic and high priority ones as votes, see
+    /// [`TrafficClass::pcp`].
+    pub fn new(vlan_id: u16) -> Self {
+        Self {
+            vlan_id,
+            pcp: TrafficClass::Turbine.pcp(),
+            priority_pcp: TrafficClass::Vote.pcp(),
+        }
+    }
+
+    fn tag(&self, priority: bool) -> VlanTag {
+        VlanTag {
+            vlan_id: self.vlan_id,
+            pcp: if priority {
+                self.priority_pcp
+            } else {
+                self.pcp
+            },
+        }
+    }
+}
+
+/// When the tx loop kicks the driver with `sendto()` after committing packets to the ring.
+///
+/// Kicking less often saves syscalls at the cost of latency. Priority packets, an idle channel
+/// and a full ring always kick right away regardless of the policy, and the driver is only
+/// kicked when it asks for it with `XDP_RING_NEED_WAKEUP`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KickPolicy {
+    /// Kick once at least this many packets have been written since the last kick.
+    EveryFrames(usize),
+    /// Kick once at least this much time has passed since the last kick.
+    Timer(Duration),
+    /// Kick after every packet while few packets are in flight, and less often as more are
+    /// queued, since a busy driver picks up new packets on its own. At most `max_frames` packets
+    /// are written between kicks.
+    Adaptive { max_frames: usize },
+}
+
+impl Default for KickPolicy {
+    fn default() -> Self {
+        Self::EveryFrames(BATCH_SIZE)
+    }
+}
+
+/// How the tx loop picks the UDP source port of the packets it sends.
+///
+/// Routers doing ECMP and NICs doing RSS pick a path or a receive queue by hashing the addresses
+/// and ports of a packet. With a single source port, all the packets we send to a host hash to
+/// the same path and the same queue on the receiver, whatever their destination port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SrcPortPolicy {
+    /// Send everything from the source port given to the loop.
+    #[default]
+    Fixed,
+    /// Pick one of `count` consecutive ports starting at the source port given to the loop by
+    /// hashing the destination, so that each destination gets its own flow but all the packets
+    /// to a destination stay on the same one.
+    DestinationHash { count: u16 },
+}
+
+impl SrcPortPolicy {
+    /// Returns the source port to send to `dst` from, given the loop's `src_port`.
+    pub fn src_port(&self, src_port: u16, dst: &SocketAddrV4) -> u16 {
+        match *self {
+            Self::Fixed | Self::DestinationHash { count: 0 } => src_port,
+            Self::DestinationHash { count } => {
+                // the splitmix64 finalizer, so that nearby destinations get unrelated ports
+                let mut hash = (u64::from(dst.ip().to_bits()) << 16) | u64::from(dst.port());
+                hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+                hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+                hash ^= hash >> 31;
+                src_port.wrapping_add((hash % u64::from(count)) as u16)
+            }
+        }
+    }
+}
+
+/// How the tx loop detects and recovers from a stalled driver.
+///
+/// Some drivers stop completing frames after a reset or a firmware hiccup, which leaves the ring
+/// full and the tx loop unable to send anything ever again. When frames have been in flight for
+/// `stall_timeout` without a single completion, the tx loop logs the state of the ring, drops the
+/// packets it's holding and recreates its socket, binding the queue again.
+#[derive(Clone, Debug)]
+pub struct TxWatchdogConfig {
+    pub stall_timeout: Duration,
+    /// Notified of stalls and recoveries.
+    pub events: Option<Sender<TxLoopEvent>>,
+}
+
+impl Default for TxWatchdogConfig {
+    fn default() -> Self {
+        Self {
+            stall_timeout: Duration::from_millis(500),
+            events: None,
+        }
+    }
+}
+
+/// Reported by the tx loop to [`TxWatchdogConfig::events`].
+#[derive(Clone, Debug)]
+pub enum TxLoopEvent {
+    /// No frame was completed for `stalled_for` while `in_flight` frames were queued. The socket
+    /// is being recreated.
+    Stalled {
+        if_index: u32,
+        queue_id: QueueId,
+        in_flight: usize,
+        stalled_for: Duration,
+    },
+    /// The socket was recreated after a stall.
+    Recovered { if_index: u32, queue_id: QueueId },
+}
+
+/// Why [`run_tx_loop`] returned.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum TxLoopExit {
+    /// The channels were disconnected and all the packets were completed.
+    Finished,
+    /// The driver stopped completing frames, see [`TxWatchdogConfig`].
+    Stalled {
+        in_flight: usize,
+        stalled_for: Duration,
+    },
+}
+
+/// How the tx loop waits for packets when its channels are empty.
+///
+/// By default the loop polls its channels in a tight loop, which keeps latency as low as possible
+/// but uses a whole core even when there's nothing to send. That's fine on a core dedicated to the
+/// loop, and makes XDP unusable on hosts that can't spare one.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TxIdlePolicy {
+    /// Poll the channels, only sleeping for a microsecond between polls.
+    #[default]
+    Spin,
+    /// Spin for a while after the channels run empty, then block waiting for packets, keeping the
+    /// spinning within a share of the core. See [`TxCpuBudget`].
+    Budget(TxCpuBudget),
+}
+
+/// The CPU the tx loop may spend waiting for packets, see [`TxIdlePolicy::Budget`].
+///
+/// Once its channels are empty, the loop spins for up to `max_spin` in case more packets follow,
+/// then blocks on the channels for `poll_timeout` at a time while frames are in flight so that
+/// it keeps reaping completions, and for `park_timeout` at a time once nothing is in flight. The
+/// time spent spinning is halved whenever the CPU used by the loop exceeds `cpu_share` of the
+/// core, and grows back while it stays below. Only waiting is cut back: a loop with more to send
+/// than its share allows keeps sending.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TxCpuBudget {
+    /// Fraction of a core, between 0 and 1.
+    pub cpu_share: f64,
+    pub max_spin: Duration,
+    pub poll_timeout: Duration,
+    pub park_timeout: Duration,
+}
+
+impl Default for TxCpuBudget {
+    fn default() -> Self {
+        Self {
+            cpu_share: 0.25,
+            max_spin: Duration::from_micros(50),
+            poll_timeout: Duration::from_micros(100),
+            park_timeout: Duration::from_millis(10),
+        }
+    }
+}
+
+/// How the tx loop waits for the driver when the ring or the UMEM is full.
+///
+/// Each retry reaps completions and kicks the driver, which can itself fail with `EAGAIN` when the
+/// driver is busy, then backs off. Once `max_retries` is exhausted the packet is dropped and
+/// counted in [`TxLoopStats`]. Until a packet makes it to the ring again, the following packets
+/// are dropped after a single retry so that a stuck queue sheds load at a predictable rate instead
+/// of stalling for every packet.
+///
+/// By default the loop retries for as long as it takes and never drops a packet because the ring
+/// is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxRetryPolicy {
+    /// Retries before dropping a packet, or `None`, the default, to wait for as long as it takes.
+    pub max_retries: Option<u32>,
+    /// Sleep after the first retry, doubled after each of the following ones. Zero busy polls.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for TxRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: None,
+            backoff: Duration::from_micros(10),
+            max_backoff: Duration::from_millis(1),
+        }
+    }
+}
+
+/// Counters updated by the tx loop when enabled with [`TxLoopConfig::stats`].
+///
+/// The same instance can be shared by multiple tx loops to get aggregate numbers.
+#[derive(Debug, Default)]
+pub struct TxLoopStats {
+    /// Packets written to the tx ring.
+    pub packets_sent: AtomicU64,
+    /// Packets received from the priority channel, counting each destination.
+    pub priority_packets: AtomicU64,
+    /// Packets dropped because they couldn't be routed through the interface.
+    pub packets_dropped: AtomicU64,
+    /// Packets the driver reported as completed.
+    pub packets_completed: AtomicU64,
+    /// Sum of the time between writing packets to the ring and reading their completion.
+    pub completion_latency_us: AtomicU64,
+    /// Maximum time between writing a packet to the ring and reading its completion.
+    pub max_completion_latency_us: AtomicU64,
+    /// Times the driver was kicked with `sendto()`.
+    pub kicks: AtomicU64,
+    /// Normal priority packets dropped because the ring stayed full, see [`TxRetryPolicy`].
+    pub ring_full_drops: AtomicU64,
+    /// Priority packets dropped because the ring stayed full.
+    pub priority_ring_full_drops: AtomicU64,
+    /// Packets dropped by the traffic shaper, see [`TxLoopConfig::shaping`].
+    pub throttled_drops: AtomicU64,
+    /// Packets to destinations in the [`blocklist`](TxLoopConfig::blocklist).
+    pub blocked_drops: AtomicU64,
+    /// Packets not yet written to the ring when the driver stalled, see [`TxWatchdogConfig`].
+    pub stall_drops: AtomicU64,
+    /// Packets dropped because the ring stayed full, by destination. Only the first
+    /// `MAX_DROP_DESTINATIONS` destinations are tracked between calls to
+    /// [`take_ring_full_drops`](Self::take_ring_full_drops).
+    pub ring_full_drops_by_destination: Mutex<HashMap<SocketAddr, u64>>,
+    /// The statistics the kernel keeps for the sockets, polled about once a second.
+    pub socket: XdpSocketStats,
+    /// How full the tx and completion rings are.
+    pub rings: XdpRingStats,
+    /// The threads running the loops.
+    pub threads: LoopThreads,
+}
+
+impl TxLoopStats {
+    /// Returns the average completion latency in microseconds.
+    pub fn avg_completion_latency_us(&self) -> u64 {
+        self.completion_latency_us
+            .load(Ordering::Relaxed)
+            .checked_div(self.packets_completed.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Returns the packets dropped per destination because the ring stayed full since the last
+    /// call.
+    pub fn take_ring_full_drops(&self) -> HashMap<SocketAddr, u64> {
+        mem::take(&mut *self.ring_full_drops_by_destination.lock().unwrap())
+    }
+
+    #[inline(never)]
+    fn ring_full_drop(&self, addr: &SocketAddr, priority: bool) {
+        if priority {
+            &self.priority_ring_full_drops
+        } else {
+            &self.ring_full_drops
+        }
+        .fetch_add(1, Ordering::Relaxed);
+
+        let mut destinations = self.ring_full_drops_by_destination.lock().unwrap();
+        if destinations.len() < MAX_DROP_DESTINATIONS || destinations.contains_key(addr) {
+            *destinations.entry(*addr).or_default() += 1;
+        }
+    }
+}
+
+// Tracks when each frame was submitted so we can measure how long the driver takes to complete it.
+struct CompletionTracker<'a> {
+    stats: &'a TxLoopStats,
+    frame_size: usize,
+    submitted: Vec<Instant>,
+}
+
+impl<'a> CompletionTracker<'a> {
+    fn new(stats: &'a TxLoopStats, umem: &impl Umem) -> Self {
+        let now = Instant::now();
+        Self {
+            stats,
+            frame_size: umem.frame_size(),
+            submitted: vec![now; umem.capacity()],
+        }
+    }
+
+    #[inline]
+    fn submitted(&mut self, offset: FrameOffset) {
+        self.submitted[offset.0 / self.frame_size] = Instant::now();
+        self.stats.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn completed(&mut self, offset: FrameOffset) {
+        let latency_us = self.submitted[offset.0 / self.frame_size]
+            .elapsed()
+            .as_micros() as u64;
+        self.stats.packets_completed.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .completion_latency_us
+            .fetch_add(latency_us, Ordering::Relaxed);
+        self.stats
+            .max_completion_latency_us
+            .fetch_max(latency_us, Ordering::Relaxed);
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn tx_loop<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
     cpu_id: usize,
@@ -37,7 +418,9 @@ pub fn tx_loop<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
     src_port: u16,
     dest_mac: Option<MacAddress>,
     receiver: Receiver<(A, T)>,
+    priority_receiver: Option<Receiver<(A, T)>>,
     drop_sender: Sender<(A, T)>,
+    config: TxLoopConfig,
 ) {
     log::info!(
         "starting xdp loop on {} queue {queue_id:?} cpu {cpu_id}",
@@ -45,18 +428,33 @@ pub fn tx_loop<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
     );
 
     // each queue is bound to its own CPU core
-    set_cpu_affinity([cpu_id]).unwrap();
+    pin_thread("xdp-tx", [cpu_id]).unwrap();
+    if let Some(stats) = &config.stats {
+        stats.threads.record_current();
+    }
 
     let src_mac = src_mac.unwrap_or_else(|| {
         // if no source MAC is provided, use the device's MAC address
         dev.mac_addr()
             .expect("no src_mac provided, device must have a MAC address")
     });
-    let src_ip = src_ip.unwrap_or_else(|| {
-        // if no source IP is provided, use the device's IPv4 address
-        dev.ipv4_addr()
-            .expect("no src_ip provided, device must have an IPv4 address")
-    });
+    // if no source IP is provided, pick it based on the route to each destination, falling back
+    // to the device's preferred IPv4 address
+    let route_src_ip = src_ip.is_none();
+    let src_ip = match src_ip {
+        Some(src_ip) => {
+            // sending from an address that's routed to the host rather than assigned to the
+            // interface can be intentional, so only warn
+            if let Err(e) = dev.validate_addr(IpAddr::V4(src_ip)) {
+                log::warn!("sending from {src_ip} on {}: {e}", dev.name());
+            }
+            src_ip
+        }
+        None => dev
+            .preferred_ipv4_addr()
+            .or_else(|_| dev.ipv4_addr())
+            .expect("no src_ip provided, device must have an IPv4 address"),
+    };
 
     // some drivers require frame_size=page_size
     let frame_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
@@ -81,245 +479,1220 @@ pub fn tx_loop<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
     const HUGE_2MB: usize = 2 * 1024 * 1024;
     let mut memory =
         PageAlignedMemory::alloc_with_page_size(frame_size, frame_count, HUGE_2MB, true)
-            .or_else(|_| {
-                log::warn!("huge page alloc failed, falling back to regular page size");
-                PageAlignedMemory::alloc(frame_size, frame_count)
+            .or_else(|e| match e {
+                AllocError::CapExceeded { .. } => Err(e),
+                AllocError::Mmap(e) => {
+                    log::warn!("huge page alloc failed, falling back to regular page size: {e}");
+                    PageAlignedMemory::alloc(frame_size, frame_count)
+                }
             })
-            .unwrap();
-    let umem = SliceUmem::new(&mut memory, frame_size as u32).unwrap();
+            .unwrap_or_else(|e| panic!("failed to allocate UMEM: {e}"));
+    // subscribe before reading the routing table so we don't miss any change
+    let mut header_cache = match RouteMonitor::new() {
+        Ok(monitor) => HeaderCache::new(HEADER_CACHE_CAPACITY).with_monitor(monitor),
+        Err(e) => {
+            log::warn!("failed to monitor routes, not caching packet headers: {e}");
+            HeaderCache::new(0)
+        }
+    };
+    // get the routing table from netlink
+    let mut router =
+        Router::with_fwmark(config.fwmark.unwrap_or(0)).expect("failed to create router");
 
-    // we need NET_ADMIN and NET_RAW for the socket
-    for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
-        caps::raise(None, CapSet::Effective, cap).unwrap();
-    }
+    let mut pcap_tap = config.pcap.as_ref().and_then(|pcap| {
+        let path = pcap.path_for_queue(queue_id.0);
+        PcapTap::create(&path, pcap.sample_rate, pcap.snaplen)
+            .inspect(|_| log::info!("capturing transmitted frames to {}", path.display()))
+            .inspect_err(|e| log::error!("failed to create pcap file {}: {e}", path.display()))
+            .ok()
+    });
+    let mut mirror = config
+        .mirror
+        .clone()
+        .map(|mirror| TxMirror::new(mirror, queue_id));
+    let mut capture = config
+        .capture
+        .as_ref()
+        .map(|capture| capture.recorder(Direction::Tx, queue_id));
+    let schedule = config.schedule_log.as_ref().and_then(|path| {
+        let mut path = path.clone().into_os_string();
+        path.push(format!(".q{}", queue_id.0));
+        let path = PathBuf::from(path);
+        let header = ScheduleHeader {
+            frame_size: frame_size as u32,
+            frame_count: frame_count as u32,
+            ring_size: tx_size as u32,
+        };
+        ScheduleRecorder::create(&path, header)
+            .inspect(|_| log::info!("recording the tx schedule to {}", path.display()))
+            .inspect_err(|e| log::error!("failed to create {}: {e}", path.display()))
+            .ok()
+    });
 
-    let Ok((mut socket, tx)) = Socket::tx(queue, umem, zero_copy, tx_size * 2, tx_size) else {
-        panic!("failed to create AF_XDP socket on queue {queue_id:?}");
+    let addressing = TxAddressing {
+        if_index: dev.if_index(),
+        src_mac,
+        src_ip,
+        route_src_ip,
+        src_port,
+        src_port_policy: config.src_port,
+        dest_mac,
+    };
+    let notify = |event: TxLoopEvent| {
+        if let Some(events) = config.watchdog.as_ref().and_then(|w| w.events.as_ref()) {
+            let _ = events.try_send(event);
+        }
     };
+    let mut queue = Some(queue);
+    let mut recovering = false;
+    loop {
+        let queue = queue.take().unwrap_or_else(|| {
+            dev.open_queue(queue_id)
+                .expect("failed to open queue for AF_XDP socket")
+        });
+        let umem = SliceUmem::new(&mut memory, frame_size as u32).unwrap();
 
-    let umem = socket.umem();
-    let umem_tx_capacity = umem.available();
-    let Tx {
-        // this is where we'll queue frames
-        ring,
-        // this is where we'll get completion events once frames have been picked up by the NIC
-        mut completion,
-    } = tx;
-    let mut ring = ring.unwrap();
+        // we need NET_ADMIN and NET_RAW for the socket
+        for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
+            caps::raise(None, CapSet::Effective, cap).unwrap();
+        }
 
-    // get the routing table from netlink
-    let router = Router::new().expect("failed to create router");
+        let (mut socket, tx) = Socket::tx(queue, umem, zero_copy, tx_size * 2, tx_size)
+            .unwrap_or_else(|e| panic!("failed to create AF_XDP socket: {e}"));
+
+        // we don't need higher caps anymore
+        for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
+            caps::drop(None, CapSet::Effective, cap).unwrap();
+        }
 
-    // we don't need higher caps anymore
-    for cap in [CAP_NET_ADMIN, CAP_NET_RAW] {
-        caps::drop(None, CapSet::Effective, cap).unwrap();
+        if mem::take(&mut recovering) {
+            log::info!(
+                "recreated AF_XDP socket on {} queue {queue_id:?}",
+                dev.name()
+            );
+            notify(TxLoopEvent::Recovered {
+                if_index: dev.if_index(),
+                queue_id,
+            });
+        }
+
+        let umem = socket.umem();
+        // frames are queued to the ring and come back on the completion ring once picked up by
+        // the NIC
+        let mut tx = tx;
+        assert!(tx.ring.is_some(), "AF_XDP socket has no tx ring");
+
+        let exit = run_tx_loop(
+            &mut tx,
+            umem,
+            &mut router,
+            &mut header_cache,
+            &addressing,
+            TxChannels {
+                receiver: receiver.clone(),
+                priority_receiver: priority_receiver.clone(),
+                drop_sender: drop_sender.clone(),
+            },
+            &config,
+            TxLoopHooks {
+                pcap_tap: pcap_tap.as_mut(),
+                mirror: mirror.as_mut(),
+                capture: capture.as_mut(),
+                schedule: schedule.as_ref(),
+                stall_timeout: config.watchdog.as_ref().map(|w| w.stall_timeout),
+            },
+        );
+        let TxLoopExit::Stalled {
+            in_flight,
+            stalled_for,
+        } = exit
+        else {
+            break;
+        };
+        log::error!(
+            "{} queue {queue_id:?} stalled with {in_flight} frames in flight and no completion \
+             for {stalled_for:?}, recreating the AF_XDP socket",
+            dev.name()
+        );
+        notify(TxLoopEvent::Stalled {
+            if_index: dev.if_index(),
+            queue_id,
+            in_flight,
+            stalled_for,
+        });
+        recovering = true;
     }
+}
 
-    // How long we sleep waiting to receive shreds from the channel.
-    const RECV_TIMEOUT: Duration = Duration::from_nanos(1000);
+/// Runs a tx loop over a [`SimSocket`](crate::sim::SimSocket) connected to `endpoint` until
+/// `receiver` is disconnected, sending from `src` to the MAC address `dest_mac`.
+///
+/// Lets benchmarks and tests outside the crate drive the real loop without a NIC. The captures,
+/// the mirror, the schedule recorder and the watchdog of `config` are ignored.
+#[cfg(any(test, feature = "test-utils"))]
+pub fn sim_tx_loop<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
+    endpoint: crate::sim::SimEndpoint,
+    src: SocketAddrV4,
+    dest_mac: MacAddress,
+    ring_size: usize,
+    receiver: Receiver<(A, T)>,
+    drop_sender: Sender<(A, T)>,
+    config: &TxLoopConfig,
+) -> Result<(), Box<dyn std::error::Error>> {
+    const FRAME_SIZE: usize = 2048;
+    let frame_count = ring_size * 2;
+    let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, frame_count)?;
+    let umem = SliceUmem::new(&mut memory, FRAME_SIZE as u32)?;
+    // completions are only reaped once the UMEM runs out, so the completion ring must hold all of
+    // it or the simulated kernel stops sending
+    let (mut socket, mut tx) = crate::sim::SimSocket::tx(umem, endpoint, frame_count, ring_size)?;
+    let mut router = Router::new()?;
+
+    let addressing = TxAddressing {
+        if_index: 0,
+        src_mac: MacAddress([2, 0, 0, 0, 0, 1]),
+        src_ip: *src.ip(),
+        route_src_ip: false,
+        src_port: src.port(),
+        src_port_policy: config.src_port,
+        dest_mac: Some(dest_mac),
+    };
+
+    run_tx_loop(
+        &mut tx,
+        socket.umem(),
+        &mut router,
+        &mut HeaderCache::new(HEADER_CACHE_CAPACITY),
+        &addressing,
+        TxChannels {
+            receiver,
+            priority_receiver: None,
+            drop_sender,
+        },
+        config,
+        TxLoopHooks::default(),
+    );
+    Ok(())
+}
 
-    const MAX_TIMEOUTS: usize = 1;
+/// Runs a tx loop over `queue` and `umem` until `receiver` and `priority_receiver` are
+/// disconnected, sending from 10.0.0.1:9000 and 01:02:03:04:05:06 to 06:05:04:03:02:01.
+///
+/// Lets [`replay_schedule`](crate::schedule::replay_schedule) drive the real loop with a mock
+/// driver. The captures, the mirror and the watchdog of `config` are ignored.
+#[cfg(any(test, feature = "test-utils"))]
+pub(crate) fn mock_tx_loop<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
+    queue: &mut crate::mock::MockTxQueue<crate::mock::MockFrame>,
+    umem: &mut crate::mock::MockUmem,
+    receiver: Receiver<(A, T)>,
+    priority_receiver: Option<Receiver<(A, T)>>,
+    drop_sender: Sender<(A, T)>,
+    config: &TxLoopConfig,
+    schedule: Option<&ScheduleRecorder>,
+) -> Result<TxLoopExit, crate::error::XdpError> {
+    let addressing = TxAddressing {
+        if_index: 0,
+        src_mac: MacAddress([1, 2, 3, 4, 5, 6]),
+        src_ip: Ipv4Addr::new(10, 0, 0, 1),
+        route_src_ip: false,
+        src_port: 9000,
+        src_port_policy: config.src_port,
+        dest_mac: Some(MacAddress([6, 5, 4, 3, 2, 1])),
+    };
 
-    // We try to collect _at least_ BATCH_SIZE packets before queueing into the NIC. This is to
-    // avoid introducing too much per-packet overhead and giving the NIC time to complete work
-    // before we queue the next chunk of packets.
-    const BATCH_SIZE: usize = 64;
+    Ok(run_tx_loop(
+        queue,
+        umem,
+        &mut Router::new()?,
+        &mut HeaderCache::new(HEADER_CACHE_CAPACITY),
+        &addressing,
+        TxChannels {
+            receiver,
+            priority_receiver,
+            drop_sender,
+        },
+        config,
+        TxLoopHooks {
+            schedule,
+            ..TxLoopHooks::default()
+        },
+    ))
+}
+
+// Where the packets sent by run_tx_loop come from, and where they go when they aren't routed.
+pub(crate) struct TxAddressing {
+    if_index: u32,
+    src_mac: MacAddress,
+    src_ip: Ipv4Addr,
+    // send routed packets from the source address selected for their route, falling back to
+    // src_ip when the route has none
+    route_src_ip: bool,
+    src_port: u16,
+    src_port_policy: SrcPortPolicy,
+    // skip routing and send everything to this MAC address
+    dest_mac: Option<MacAddress>,
+}
+
+// The channels run_tx_loop receives packets from, and hands them back to once sent.
+pub(crate) struct TxChannels<A, T> {
+    receiver: Receiver<(A, T)>,
+    // sent ahead of everything else, see run_tx_loop
+    priority_receiver: Option<Receiver<(A, T)>>,
+    drop_sender: Sender<(A, T)>,
+}
+
+// The optional hooks of run_tx_loop that tx_loop creates from its TxLoopConfig once and keeps
+// across socket restarts.
+#[derive(Default)]
+pub(crate) struct TxLoopHooks<'h> {
+    pcap_tap: Option<&'h mut PcapTap>,
+    mirror: Option<&'h mut TxMirror>,
+    capture: Option<&'h mut CaptureRecorder>,
+    schedule: Option<&'h ScheduleRecorder>,
+    stall_timeout: Option<Duration>,
+}
+
+/// Runs the transmit loop on an already created socket until the channels are disconnected and
+/// all the queued packets have been completed.
+///
+/// Packets received from the priority channel are sent ahead of everything else and the driver is
+/// kicked as soon as they're in the ring, instead of waiting for a full batch.
+///
+/// The headers built for each destination are kept in `header_cache`. When the cache reports that
+/// routes or neighbors changed, `router` is refreshed as well.
+///
+/// The policies, stats and filters of `config` are applied, while the captures, the mirror, the
+/// schedule recorder and the stall timeout come from `hooks`. If the stall timeout is set and no
+/// frame is completed for that long while some are in flight, the packets not yet written to the
+/// ring are dropped and [`TxLoopExit::Stalled`] is returned without waiting for the frames in
+/// flight.
+///
+/// This is split out of [`tx_loop`] so that it can be driven by the
+/// [simulation backend](crate::sim) and by the [mocks](crate::mock) as well as by a real socket.
+pub(crate) fn run_tx_loop<Q, U, T, A>(
+    queue: &mut Q,
+    umem: &mut U,
+    router: &mut Router,
+    header_cache: &mut HeaderCache,
+    addressing: &TxAddressing,
+    channels: TxChannels<A, T>,
+    config: &TxLoopConfig,
+    hooks: TxLoopHooks<'_>,
+) -> TxLoopExit
+where
+    Q: TxQueue<Frame = U::Frame>,
+    U: Umem,
+    T: AsRef<[u8]>,
+    A: AsRef<[SocketAddr]>,
+{
+    let TxChannels {
+        receiver,
+        mut priority_receiver,
+        drop_sender,
+    } = channels;
+    let TxLoopHooks {
+        mut pcap_tap,
+        mut mirror,
+        mut capture,
+        schedule,
+        stall_timeout,
+    } = hooks;
+    let if_index = addressing.if_index;
+    let (src_port, src_port_policy) = (addressing.src_port, addressing.src_port_policy);
+    let stats = config.stats.as_deref();
+    let blocklist = config.blocklist.as_ref();
+
+    let umem_tx_capacity = umem.available();
+    let mut stall = stall_timeout.map(StallDetector::new);
+    let mut tracker = stats.map(|stats| CompletionTracker::new(stats, umem));
+    let mut recorder = config
+        .delivery
+        .as_deref()
+        .map(|delivery| DeliveryRecorder::new(delivery, umem));
+    let mut poller = StatisticsPoller::new();
+    let mut kicker = Kicker::new(config.kick, stats, schedule);
+    let mut tracer = TxTracer::new(umem, schedule);
+    let mut idle = IdleWaiter::new(config.idle);
+    let mut warmer = config.warmup.as_ref().map(Warmer::new);
 
     // Local buffer where we store packets before sending themi.
-    let mut batched_items = Vec::with_capacity(BATCH_SIZE);
+    let mut batched_items: Vec<(A, T)> = Vec::with_capacity(BATCH_SIZE);
+    // Priority packets, sent before batched_items.
+    let mut priority_items: Vec<(A, T)> = Vec::with_capacity(BATCH_SIZE);
+    // The cached headers of the destinations of the payload being fanned out.
+    let mut fanout_headers: Vec<UdpFrameHeader> = Vec::new();
 
     // How many packets we've batched. This is _not_ batched_items.len(), but item * peers. For
     // example if we have 3 packets to transmit to 2 destination addresses each, we have 6 batched
     // packets.
     let mut batched_packets = 0;
 
-    let mut timeouts = 0;
+    let mut disconnected = false;
+    // set once we've given up waiting for room in the ring, until a packet is written again
+    let mut shedding = false;
     loop {
-        match receiver.try_recv() {
-            Ok((addrs, payload)) => {
-                batched_packets += addrs.as_ref().len();
-                batched_items.push((addrs, payload));
-                timeouts = 0;
-                if batched_packets < BATCH_SIZE {
-                    continue;
-                }
-            }
-            Err(TryRecvError::Empty) => {
-                if timeouts < MAX_TIMEOUTS {
-                    timeouts += 1;
-                    thread::sleep(RECV_TIMEOUT);
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.maybe_flush();
+        }
+        if let Some(stats) = stats {
+            poller.poll(|| queue.statistics(), &stats.socket, false);
+            queue.sample_occupancy(&stats.rings);
+        }
+        if let Some(detector) = stall.as_mut() {
+            let in_flight = umem_tx_capacity - umem.available();
+            if in_flight == 0 {
+                detector.progress();
+            } else if let Some(stalled_for) = detector.stalled_for() {
+                // completions are only read when the ring fills up, look for some before giving up
+                if reap_completions(queue, umem, &mut tracker, &mut recorder, &tracer) > 0 {
+                    detector.progress();
                 } else {
-                    timeouts = 0;
-                    // we haven't received anything in a while, kick the driver
-                    ring.commit();
-                    kick(&ring);
+                    log::warn!(
+                        "if_index {if_index}: tx ring stalled, {in_flight} frames in flight, ring \
+                         {}/{} available, needs wakeup {}, statistics {:?}",
+                        queue.available(),
+                        queue.capacity(),
+                        queue.needs_wakeup(),
+                        queue.statistics(),
+                    );
+                    for (addrs, payload) in priority_items.drain(..).chain(batched_items.drain(..))
+                    {
+                        for addr in addrs.as_ref() {
+                            tracer.dropped(addr, "stalled");
+                            if let Some(stats) = stats {
+                                stats.stall_drops.fetch_add(1, Ordering::Relaxed);
+                            }
+                            if let Some(recorder) = recorder.as_mut() {
+                                recorder.dropped(addr);
+                            }
+                        }
+                        let _ = drop_sender.try_send((addrs, payload));
+                    }
+                    return TxLoopExit::Stalled {
+                        in_flight,
+                        stalled_for,
+                    };
                 }
             }
-            Err(TryRecvError::Disconnected) => {
-                // keep looping until we've flushed all the packets
-                if batched_packets == 0 {
-                    break;
+        }
+        if header_cache.poll_invalidation() {
+            match Router::with_fwmark(router.fwmark()) {
+                Ok(new_router) => *router = new_router,
+                Err(e) => log::warn!("failed to refresh the routing table: {e}"),
+            }
+            if let Some(warmer) = warmer.as_mut() {
+                warmer.invalidate();
+            }
+        }
+        if let Some(warmer) = warmer.as_mut() {
+            if let Some(peers) = warmer.poll() {
+                warm_up(warmer, &peers, router, header_cache, addressing);
+            }
+        }
+
+        let mut priority_packets = 0;
+        if let Some(priority) = priority_receiver.as_ref() {
+            let received = priority.try_recv();
+            match received {
+                Ok(item) => {
+                    idle.active();
+                    priority_items.push(item);
+                    // bound the number of items so that normal traffic isn't starved
+                    priority_items.extend(priority.try_iter().take(BATCH_SIZE - 1));
+                    priority_packets = priority_items
+                        .iter()
+                        .map(|(addrs, _)| addrs.as_ref().len())
+                        .sum();
+                    tracer.submitted(priority_packets, true);
+                    if let Some(stats) = stats {
+                        stats
+                            .priority_packets
+                            .fetch_add(priority_packets as u64, Ordering::Relaxed);
+                    }
+                    batched_packets += priority_packets;
                 }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => priority_receiver = None,
             }
-        };
+        }
+
+        // priority packets skip batching
+        if priority_packets == 0 {
+            let received = if disconnected {
+                Err(TryRecvError::Disconnected)
+            } else {
+                receiver.try_recv()
+            };
+            match received {
+                Ok((addrs, payload)) => {
+                    let packets = addrs.as_ref().len();
+                    tracer.submitted(packets, false);
+                    idle.active();
+                    if let Some((shaper, class)) = &config.shaping {
+                        if !shaper.try_acquire(*class, packets as u64) {
+                            for addr in addrs.as_ref() {
+                                tracer.dropped(addr, "throttled");
+                                if let Some(recorder) = recorder.as_mut() {
+                                    recorder.dropped(addr);
+                                }
+                            }
+                            if let Some(stats) = stats {
+                                stats
+                                    .throttled_drops
+                                    .fetch_add(packets as u64, Ordering::Relaxed);
+                            }
+                            let _ = drop_sender.try_send((addrs, payload));
+                            continue;
+                        }
+                    }
+                    batched_packets += packets;
+                    batched_items.push((addrs, payload));
+                    if batched_packets < BATCH_SIZE {
+                        continue;
+                    }
+                }
+                Err(TryRecvError::Empty) => {
+                    if idle.should_spin() {
+                        thread::sleep(RECV_TIMEOUT);
+                    } else {
+                        // we haven't received anything in a while, kick the driver
+                        queue.commit();
+                        kicker.kick(queue);
+                        if idle.blocks() {
+                            if reap_completions(queue, umem, &mut tracker, &mut recorder, &tracer)
+                                > 0
+                            {
+                                if let Some(detector) = stall.as_mut() {
+                                    detector.progress();
+                                }
+                            }
+                            let in_flight = umem_tx_capacity - umem.available();
+                            idle.block(&receiver, priority_receiver.as_ref(), in_flight > 0);
+                        }
+                    }
+                }
+                Err(TryRecvError::Disconnected) => {
+                    disconnected = true;
+                    // keep looping until we've flushed all the packets
+                    if batched_packets == 0 {
+                        if priority_receiver.is_none() {
+                            break;
+                        }
+                        // only priority packets are left to wait for
+                        thread::sleep(RECV_TIMEOUT);
+                    }
+                }
+            };
+        }
 
         // this is the number of packets after which we commit the ring and kick the driver if
-        // necessary
-        let mut chunk_remaining = BATCH_SIZE.min(batched_packets);
-
-        for (addrs, payload) in batched_items.drain(..) {
-            for addr in addrs.as_ref() {
-                if ring.available() == 0 || umem.available() == 0 {
-                    // loop until we have space for the next packet
-                    loop {
-                        completion.sync(true);
-                        // we haven't written any frames so we only need to sync the consumer position
-                        ring.sync(false);
+        // necessary. Priority packets are committed and kicked on their own.
+        let mut priority_chunk = priority_packets > 0;
+        let mut chunk_remaining = if priority_chunk {
+            priority_packets
+        } else {
+            BATCH_SIZE.min(batched_packets)
+        };
+
+        let priority_count = priority_items.len();
+        if priority_count > 0 || !batched_items.is_empty() {
+            tracer.begin_batch(batched_packets, priority_packets);
+        }
+        for (i, (addrs, payload)) in priority_items
+            .drain(..)
+            .chain(batched_items.drain(..))
+            .enumerate()
+        {
+            // the last frame we wrote this payload into
+            let mut prev_frame: Option<FrameOffset> = None;
+            let tag = config.vlan.map(|vlan| vlan.tag(i < priority_count));
+            let header_len = UDP_FRAME_HEADER_SIZE + tag.map_or(0, |_| VLAN_HEADER_SIZE);
+
+            // Fan-out fast path. When the headers of all the destinations are cached and there's
+            // room for all the frames, write them back to back without checking for room, routing
+            // or building headers for each of them. The payload is copied from the caller once and
+            // then from the previous frame, which is still in cache.
+            let fanout = addrs.as_ref();
+            fanout_headers.clear();
+            // blocked destinations are dropped on the slow path
+            if fanout.len() > 1
+                && blocklist.is_none_or(|blocklist| blocklist.is_empty())
+                && queue.available() >= fanout.len()
+                && umem.available() >= fanout.len()
+            {
+                fanout_headers.extend(fanout.iter().map_while(|addr| {
+                    let SocketAddr::V4(dst) = addr else {
+                        panic!("IPv6 not supported");
+                    };
+                    header_cache
+                        .get(dst, src_port_policy.src_port(src_port, dst))
+                        .copied()
+                }));
+            }
+            let fast_path = fanout.len() > 1 && fanout_headers.len() == fanout.len();
+            if fast_path {
+                let len = payload.as_ref().len();
+                for (addr, header) in fanout.iter().zip(fanout_headers.drain(..)) {
+                    let mut frame = umem.reserve().unwrap();
+                    tracer.frame_acquired(frame.offset());
+                    frame.set_len(header_len + len);
+                    if let Some(prev_frame) = prev_frame {
+                        umem.copy_frame(prev_frame, frame.offset(), header_len..header_len + len);
+                    }
+                    let packet = umem.map_frame_mut(&frame);
+                    if prev_frame.is_none() {
+                        packet[header_len..][..len].copy_from_slice(payload.as_ref());
+                    }
+                    prev_frame = Some(frame.offset());
+                    write_header(packet, &header, len, tag);
+
+                    if let Some(tap) = pcap_tap.as_mut() {
+                        tap.capture(packet);
+                    }
+                    if let Some(mirror) = mirror.as_mut() {
+                        mirror.mirror(packet);
+                    }
+                    if let Some(capture) = capture.as_mut() {
+                        capture.record(packet);
+                    }
+                    if let Some(tracker) = tracker.as_mut() {
+                        tracker.submitted(frame.offset());
+                    }
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.submitted(frame.offset(), addr);
+                    }
+                    tracer.enqueued(frame.offset(), addr, i < priority_count);
+                    queue
+                        .submit(frame, 0)
+                        .map_err(|_| "ring full")
+                        // we checked there's room for the whole fan-out above
+                        .expect("failed to write to ring");
+                    kicker.written();
+                    shedding = false;
+
+                    batched_packets -= 1;
+                    end_packet(
+                        queue,
+                        &mut kicker,
+                        &mut chunk_remaining,
+                        &mut priority_chunk,
+                        batched_packets,
+                        umem_tx_capacity - umem.available(),
+                    );
+                }
+            }
+
+            let remaining = if fast_path { &[][..] } else { fanout };
+            for addr in remaining {
+                if blocklist.is_some_and(|blocklist| blocklist.suppress(addr)) {
+                    tracer.dropped(addr, "blocked");
+                    if let Some(stats) = stats {
+                        stats.blocked_drops.fetch_add(1, Ordering::Relaxed);
+                    }
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder.dropped(addr);
+                    }
+                    batched_packets -= 1;
+                    continue;
+                }
+                if queue.available() == 0 || umem.available() == 0 {
+                    let max_retries = if shedding {
+                        Some(1)
+                    } else {
+                        config.retry.max_retries
+                    };
+                    let mut retries = 0;
+                    let mut backoff = Duration::ZERO;
+                    // the driver can't make room while the frames of the current chunk aren't
+                    // committed
+                    queue.commit();
+                    // loop until we have space for the next packet, or we run out of retries
+                    let has_room = loop {
+                        // we haven't written any frames so we only need to see what the driver
+                        // picked up
+                        queue.refresh();
 
                         // check if any frames were completed
-                        while let Some(frame_offset) = completion.read() {
-                            umem.release(frame_offset);
+                        if reap_completions(queue, umem, &mut tracker, &mut recorder, &tracer) > 0 {
+                            if let Some(detector) = stall.as_mut() {
+                                detector.progress();
+                            }
                         }
 
-                        if ring.available() > 0 && umem.available() > 0 {
+                        if queue.available() > 0 && umem.available() > 0 {
                             // we have space for the next packet, break out of the loop
-                            break;
+                            break true;
                         }
+                        if max_retries.is_some_and(|max_retries| retries >= max_retries) {
+                            break false;
+                        }
+                        // don't wait forever for a stalled driver, the next iteration of the main
+                        // loop recovers from the stall
+                        if stall.as_ref().is_some_and(|d| d.stalled_for().is_some()) {
+                            break false;
+                        }
+                        retries += 1;
 
                         // queues are full, if NEEDS_WAKEUP is set kick the driver so hopefully it'll
                         // complete some work
-                        kick(&ring);
+                        kicker.kick(queue);
+
+                        if !backoff.is_zero() {
+                            thread::sleep(backoff);
+                        }
+                        backoff = (backoff * 2)
+                            .max(config.retry.backoff)
+                            .min(config.retry.max_backoff);
+                    };
+                    if !has_room {
+                        shedding = true;
+                        tracer.dropped(addr, "ring full");
+                        if let Some(stats) = stats {
+                            stats.ring_full_drop(addr, i < priority_count);
+                        }
+                        if let Some(recorder) = recorder.as_mut() {
+                            recorder.dropped(addr);
+                        }
+                        batched_packets -= 1;
+                        continue;
                     }
                 }
 
                 // at this point we're guaranteed to have a frame to write the next packet into and
                 // a slot in the ring to submit it
                 let mut frame = umem.reserve().unwrap();
-                let IpAddr::V4(dst_ip) = addr.ip() else {
+                tracer.frame_acquired(frame.offset());
+                let SocketAddr::V4(dst) = addr else {
                     panic!("IPv6 not supported");
                 };
 
-                let dest_mac = if let Some(mac) = dest_mac {
-                    mac
-                } else {
-                    let next_hop = router.route(addr.ip()).unwrap();
-
-                    let mut skip = false;
-
-                    // sanity check that the address is routable through our NIC
-                    if next_hop.if_index != dev.if_index() {
-                        log::warn!(
-                            "dropping packet: turbine peer {addr} must be routed through \
-                             if_index: {} our if_index: {}",
-                            next_hop.if_index,
-                            dev.if_index()
-                        );
-                        skip = true;
-                    }
+                let src_port = src_port_policy.src_port(src_port, dst);
+                let header = match header_cache.get(dst, src_port).copied() {
+                    Some(header) => header,
+                    None => {
+                        let resolved = resolve_header(router, addressing, src_port, dst);
+                        let header = match resolved {
+                            Ok(header) => header,
+                            Err(Unroutable::Route(e)) => panic!("failed to route {addr}: {e}"),
+                            Err(Unroutable::NextHop(next_hop)) => {
+                                // sanity check that the address is routable through our NIC
+                                if next_hop.if_index != if_index {
+                                    log::warn!(
+                                        "dropping packet: turbine peer {addr} must be routed \
+                                         through if_index: {} our if_index: {if_index}",
+                                        next_hop.if_index,
+                                    );
+                                }
 
-                    // we need the MAC address to send the packet
-                    if next_hop.mac_addr.is_none() {
-                        log::warn!(
-                            "dropping packet: turbine peer {addr} must be routed through {} which \
-                             has no known MAC address",
-                            next_hop.ip_addr
-                        );
-                        skip = true;
-                    };
+                                // we need the MAC address to send the packet
+                                if next_hop.mac_addr.is_none() {
+                                    log::warn!(
+                                        "dropping packet: turbine peer {addr} must be routed \
+                                         through {} which has no known MAC address",
+                                        next_hop.ip_addr
+                                    );
+                                };
 
-                    if skip {
-                        batched_packets -= 1;
-                        umem.release(frame.offset());
-                        continue;
+                                tracer.dropped(addr, "unroutable");
+                                if let Some(stats) = stats {
+                                    stats.packets_dropped.fetch_add(1, Ordering::Relaxed);
+                                }
+                                if let Some(recorder) = recorder.as_mut() {
+                                    recorder.dropped(addr);
+                                }
+                                batched_packets -= 1;
+                                umem.release(frame.offset());
+                                continue;
+                            }
+                        };
+                        header_cache.insert(*dst, src_port, header);
+                        header
                     }
-
-                    next_hop.mac_addr.unwrap()
                 };
 
-                const PACKET_HEADER_SIZE: usize =
-                    ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE;
                 let len = payload.as_ref().len();
-                frame.set_len(PACKET_HEADER_SIZE + len);
-                let packet = umem.map_frame_mut(&frame);
+                frame.set_len(header_len + len);
 
-                // write the payload first as it's needed for checksum calculation (if enabled)
-                packet[PACKET_HEADER_SIZE..][..len].copy_from_slice(payload.as_ref());
+                // When fanning out to multiple destinations, copy the payload from the previous
+                // frame which is likely still in cache.
+                if let Some(prev_frame) = prev_frame {
+                    umem.copy_frame(prev_frame, frame.offset(), header_len..header_len + len);
+                }
+                let packet = umem.map_frame_mut(&frame);
+                if prev_frame.is_none() {
+                    packet[header_len..][..len].copy_from_slice(payload.as_ref());
+                }
+                prev_frame = Some(frame.offset());
 
-                write_eth_header(packet, &src_mac.0, &dest_mac.0);
+                // don't do checksums
+                write_header(packet, &header, len, tag);
 
-                write_ip_header(
-                    &mut packet[ETH_HEADER_SIZE..],
-                    &src_ip,
-                    &dst_ip,
-                    (UDP_HEADER_SIZE + len) as u16,
-                );
+                if let Some(tap) = pcap_tap.as_mut() {
+                    tap.capture(packet);
+                }
+                if let Some(mirror) = mirror.as_mut() {
+                    mirror.mirror(packet);
+                }
+                if let Some(capture) = capture.as_mut() {
+                    capture.record(packet);
+                }
 
-                write_udp_header(
-                    &mut packet[ETH_HEADER_SIZE + IP_HEADER_SIZE..],
-                    &src_ip,
-                    src_port,
-                    &dst_ip,
-                    addr.port(),
-                    len as u16,
-                    // don't do checksums
-                    false,
-                );
+                if let Some(tracker) = tracker.as_mut() {
+                    tracker.submitted(frame.offset());
+                }
+                if let Some(recorder) = recorder.as_mut() {
+                    recorder.submitted(frame.offset(), addr);
+                }
 
                 // write the packet into the ring
-                ring.write(frame, 0)
+                tracer.enqueued(frame.offset(), addr, i < priority_count);
+                queue
+                    .submit(frame, 0)
                     .map_err(|_| "ring full")
                     // this should never happen as we check for available slots above
                     .expect("failed to write to ring");
+                kicker.written();
+                shedding = false;
 
                 batched_packets -= 1;
-                chunk_remaining -= 1;
-
-                // check if it's time to commit the ring and kick the driver
-                if chunk_remaining == 0 {
-                    chunk_remaining = BATCH_SIZE.min(batched_packets);
-
-                    // commit new frames
-                    ring.commit();
-                    kick(&ring);
-                }
+                end_packet(
+                    queue,
+                    &mut kicker,
+                    &mut chunk_remaining,
+                    &mut priority_chunk,
+                    batched_packets,
+                    umem_tx_capacity - umem.available(),
+                );
             }
             let _ = drop_sender.try_send((addrs, payload));
         }
+        tracer.end_batch();
         debug_assert_eq!(batched_packets, 0);
     }
     assert_eq!(batched_packets, 0);
 
-    // drain the ring
-    while umem.available() < umem_tx_capacity || ring.available() < ring.capacity() {
+    // drain the ring. Packets dropped from the last chunk leave it uncommitted.
+    queue.commit();
+    while umem.available() < umem_tx_capacity || queue.available() < queue.capacity() {
         log::debug!(
             "draining xdp ring umem {}/{} ring {}/{}",
             umem.available(),
             umem_tx_capacity,
-            ring.available(),
-            ring.capacity()
+            queue.available(),
+            queue.capacity()
         );
 
-        completion.sync(true);
-        while let Some(frame_offset) = completion.read() {
-            umem.release(frame_offset);
+        reap_completions(queue, umem, &mut tracker, &mut recorder, &tracer);
+
+        queue.refresh();
+        kicker.kick(queue);
+    }
+
+    if let Some(stats) = stats {
+        poller.poll(|| queue.statistics(), &stats.socket, true);
+    }
+    TxLoopExit::Finished
+}
+
+// Why the packets to a destination can't be sent.
+enum Unroutable {
+    Route(RouteError),
+    // the next hop isn't on our interface or has no known MAC address
+    NextHop(NextHop),
+}
+
+// Builds the headers of the packets to `dst` sent from `src_port`, routing them through `router`
+// unless the addressing has a destination MAC address.
+fn resolve_header(
+    router: &Router,
+    addressing: &TxAddressing,
+    src_port: u16,
+    dst: &SocketAddrV4,
+) -> Result<UdpFrameHeader, Unroutable> {
+    let TxAddressing {
+        if_index,
+        src_mac,
+        src_ip,
+        route_src_ip,
+        dest_mac,
+        ..
+    } = *addressing;
+    let (dest_mac, src_ip) = if let Some(mac) = dest_mac {
+        // multicast goes to the group's MAC, not to the one we were given
+        let mac = MacAddress::multicast(IpAddr::V4(*dst.ip())).unwrap_or(mac);
+        (mac, src_ip)
+    } else {
+        let next_hop = router
+            .route(IpAddr::V4(*dst.ip()))
+            .map_err(Unroutable::Route)?;
+        let Some(mac) = next_hop.mac_addr.filter(|_| next_hop.if_index == if_index) else {
+            return Err(Unroutable::NextHop(next_hop));
+        };
+        let src_ip = match next_hop.src_ip {
+            Some(IpAddr::V4(ip)) if route_src_ip => ip,
+            _ => src_ip,
+        };
+        (mac, src_ip)
+    };
+    Ok(build_udp_frame_header(
+        &src_mac, &dest_mac, &src_ip, src_port, dst,
+    ))
+}
+
+// Caches the headers of the peers to warm up, and asks the kernel to resolve the next hops that
+// have no known MAC address so that they're known by the time traffic starts.
+fn warm_up(
+    warmer: &mut Warmer,
+    peers: &[SocketAddrV4],
+    router: &Router,
+    header_cache: &mut HeaderCache,
+    addressing: &TxAddressing,
+) {
+    let if_index = addressing.if_index;
+    let mut warmed = 0;
+    for dst in peers {
+        let src_port = addressing
+            .src_port_policy
+            .src_port(addressing.src_port, dst);
+        if header_cache.get(dst, src_port).is_some() {
+            continue;
+        }
+        match resolve_header(router, addressing, src_port, dst) {
+            Ok(header) => {
+                header_cache.insert(*dst, src_port, header);
+                warmed += 1;
+            }
+            Err(Unroutable::NextHop(next_hop))
+                if next_hop.if_index == if_index && next_hop.mac_addr.is_none() =>
+            {
+                warmer.resolve_neighbor(if_index, next_hop.ip_addr);
+            }
+            Err(_) => {}
+        }
+    }
+    log::debug!(
+        "if_index {if_index}: warmed up {warmed} of {} peers",
+        peers.len()
+    );
+}
+
+// Writes `header` and the lengths of a `len` bytes payload at the start of `packet`, tagged with
+// `tag` if any. The payload must already be in place after the header and the tag.
+#[inline(always)]
+fn write_header(packet: &mut [u8], header: &UdpFrameHeader, len: usize, tag: Option<VlanTag>) {
+    let untagged = &mut packet[tag.map_or(0, |_| VLAN_HEADER_SIZE)..];
+    untagged[..UDP_FRAME_HEADER_SIZE].copy_from_slice(header);
+    set_udp_frame_len(untagged, len as u16);
+    if let Some(tag) = tag {
+        push_vlan_tag(packet, tag);
+    }
+}
+
+// Commits the ring and kicks the driver at the end of a chunk, or earlier if the kick policy says
+// so.
+#[inline(always)]
+fn end_packet(
+    queue: &mut impl TxQueue,
+    kicker: &mut Kicker<'_>,
+    chunk_remaining: &mut usize,
+    priority_chunk: &mut bool,
+    batched_packets: usize,
+    in_flight: usize,
+) {
+    *chunk_remaining -= 1;
+    if *chunk_remaining == 0 {
+        *chunk_remaining = BATCH_SIZE.min(batched_packets);
+
+        // commit new frames
+        queue.commit();
+        if mem::take(priority_chunk) {
+            kicker.kick(queue);
+        } else {
+            kicker.maybe_kick(queue, in_flight);
+        }
+    } else if !*priority_chunk && kicker.is_due(in_flight) {
+        queue.commit();
+        kicker.kick(queue);
+    }
+}
+
+// Releases the frames on the completion ring. Returns how many were completed.
+#[inline]
+fn reap_completions(
+    queue: &mut impl TxQueue,
+    umem: &mut impl Umem,
+    tracker: &mut Option<CompletionTracker<'_>>,
+    recorder: &mut Option<DeliveryRecorder<'_>>,
+    tracer: &TxTracer<'_>,
+) -> usize {
+    let mut completed = 0;
+    while let Some(frame_offset) = queue.complete() {
+        if let Some(tracker) = tracker.as_mut() {
+            tracker.completed(frame_offset);
+        }
+        if let Some(recorder) = recorder.as_mut() {
+            recorder.completed(frame_offset);
+        }
+        tracer.completed(frame_offset);
+        umem.release(frame_offset);
+        completed += 1;
+    }
+    completed
+}
+
+// Decides how the loop waits for packets according to a TxIdlePolicy.
+struct IdleWaiter {
+    budget: Option<TxCpuBudget>,
+    // empty polls since the last packet, with TxIdlePolicy::Spin
+    timeouts: usize,
+    // when the channels ran empty, with TxIdlePolicy::Budget
+    idle_since: Option<Instant>,
+    spin: Duration,
+    window_start: Instant,
+    window_cpu: Duration,
+}
+
+impl IdleWaiter {
+    fn new(policy: TxIdlePolicy) -> Self {
+        let budget = match policy {
+            TxIdlePolicy::Spin => None,
+            TxIdlePolicy::Budget(budget) => Some(budget),
+        };
+        Self {
+            budget,
+            timeouts: 0,
+            idle_since: None,
+            spin: budget.map(|b| b.max_spin).unwrap_or_default(),
+            window_start: Instant::now(),
+            window_cpu: thread_cpu_time(),
+        }
+    }
+
+    // Records that a packet was received.
+    #[inline]
+    fn active(&mut self) {
+        self.timeouts = 0;
+        self.idle_since = None;
+    }
+
+    // Returns whether to poll the channels again after a short sleep. Once it returns false, the
+    // ring should be committed and the driver kicked, and with a budget the loop should block.
+    #[inline]
+    fn should_spin(&mut self) -> bool {
+        if self.budget.is_none() {
+            if self.timeouts < MAX_TIMEOUTS {
+                self.timeouts += 1;
+                return true;
+            }
+            self.timeouts = 0;
+            return false;
+        }
+        self.update_budget();
+        let idle_since = *self.idle_since.get_or_insert_with(Instant::now);
+        idle_since.elapsed() < self.spin
+    }
+
+    #[inline]
+    fn blocks(&self) -> bool {
+        self.budget.is_some()
+    }
+
+    // Blocks until one of the channels has something to receive or the timeout for `in_flight`
+    // expires. The loop keeps blocking without spinning again until it receives a packet.
+    fn block<T>(
+        &mut self,
+        receiver: &Receiver<T>,
+        priority: Option<&Receiver<T>>,
+        in_flight: bool,
+    ) {
+        let Some(budget) = self.budget else {
+            return;
+        };
+        let mut select = Select::new();
+        select.recv(receiver);
+        if let Some(priority) = priority {
+            select.recv(priority);
+        }
+        let timeout = if in_flight {
+            budget.poll_timeout
+        } else {
+            budget.park_timeout
+        };
+        let _ = select.ready_timeout(timeout);
+    }
+
+    fn update_budget(&mut self) {
+        let Some(budget) = self.budget else {
+            return;
+        };
+        let wall = self.window_start.elapsed();
+        if wall < BUDGET_WINDOW {
+            return;
+        }
+        let cpu = thread_cpu_time();
+        let used = cpu.saturating_sub(self.window_cpu).as_secs_f64() / wall.as_secs_f64();
+        self.spin = adjust_spin(self.spin, &budget, used);
+        self.window_start = Instant::now();
+        self.window_cpu = cpu;
+    }
+}
+
+// Returns the spin time for the next window, given the share of the core `used` in the last one.
+fn adjust_spin(spin: Duration, budget: &TxCpuBudget, used: f64) -> Duration {
+    if used > budget.cpu_share {
+        spin / 2
+    } else if used < budget.cpu_share * 0.8 {
+        // start from a microsecond after spinning was cut to zero
+        (spin * 2)
+            .max(Duration::from_micros(1))
+            .min(budget.max_spin)
+    } else {
+        spin
+    }
+}
+
+// Returns the CPU time used by the calling thread.
+fn thread_cpu_time() -> Duration {
+    let mut ts = timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Safety: libc wrapper, ts outlives the call
+    if unsafe { clock_gettime(CLOCK_THREAD_CPUTIME_ID, &mut ts) } < 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+// Detects a driver that stopped completing frames, see TxWatchdogConfig.
+struct StallDetector {
+    timeout: Duration,
+    last_progress: Instant,
+}
+
+impl StallDetector {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            last_progress: Instant::now(),
+        }
+    }
+
+    // Records that frames were completed, or that none are in flight.
+    #[inline]
+    fn progress(&mut self) {
+        self.last_progress = Instant::now();
+    }
+
+    #[inline]
+    fn stalled_for(&self) -> Option<Duration> {
+        let elapsed = self.last_progress.elapsed();
+        (elapsed >= self.timeout).then_some(elapsed)
+    }
+}
+
+// We try to collect _at least_ BATCH_SIZE packets before queueing into the NIC. This is to avoid
+// introducing too much per-packet overhead and giving the NIC time to complete work before we queue
+// the next chunk of packets.
+const BATCH_SIZE: usize = 64;
+
+// How long we sleep waiting to receive shreds from the channel.
+const RECV_TIMEOUT: Duration = Duration::from_nanos(1000);
+
+// Empty polls before kicking the driver, with TxIdlePolicy::Spin.
+const MAX_TIMEOUTS: usize = 1;
+
+// How often the CPU used by the loop is compared to its TxCpuBudget.
+const BUDGET_WINDOW: Duration = Duration::from_millis(100);
+
+// How many destinations TxLoopStats tracks ring full drops for.
+const MAX_DROP_DESTINATIONS: usize = 1024;
+
+// How many destinations we keep prebuilt headers for. Turbine and repair traffic go to a few
+// thousand peers at most.
+const HEADER_CACHE_CAPACITY: usize = 16_384;
+
+// Decides when to kick the driver according to a KickPolicy.
+struct Kicker<'a> {
+    policy: KickPolicy,
+    // packets written since the last kick
+    pending: usize,
+    last_kick: Instant,
+    stats: Option<&'a TxLoopStats>,
+    schedule: Option<&'a ScheduleRecorder>,
+}
+
+impl<'a> Kicker<'a> {
+    fn new(
+        policy: KickPolicy,
+        stats: Option<&'a TxLoopStats>,
+        schedule: Option<&'a ScheduleRecorder>,
+    ) -> Self {
+        Self {
+            policy,
+            pending: 0,
+            last_kick: Instant::now(),
+            stats,
+            schedule,
         }
+    }
+
+    #[inline]
+    fn written(&mut self) {
+        self.pending += 1;
+    }
+
+    #[inline]
+    fn is_due(&self, in_flight: usize) -> bool {
+        if self.pending == 0 {
+            return false;
+        }
+        match self.policy {
+            KickPolicy::EveryFrames(frames) => self.pending >= frames,
+            KickPolicy::Timer(interval) => self.last_kick.elapsed() >= interval,
+            KickPolicy::Adaptive { max_frames } => {
+                self.pending >= (in_flight / 4).clamp(1, max_frames.max(1))
+            }
+        }
+    }
+
+    #[inline]
+    fn maybe_kick(&mut self, queue: &mut impl TxQueue, in_flight: usize) {
+        if self.is_due(in_flight) {
+            self.kick(queue);
+        }
+    }
 
-        ring.sync(false);
-        kick(&ring);
+    #[inline]
+    fn kick(&mut self, queue: &mut impl TxQueue) {
+        let pending = mem::take(&mut self.pending);
+        self.last_kick = Instant::now();
+        if kick(queue) {
+            trace::kicked(pending);
+            if let Some(schedule) = self.schedule {
+                schedule.record(ScheduleEvent::Kick {
+                    pending: pending as u32,
+                });
+            }
+            if let Some(stats) = self.stats {
+                stats.kicks.fetch_add(1, Ordering::Relaxed);
+            }
+        }
     }
 }
 
 // With some drivers, or always when we work in SKB mode, we need to explicitly kick the driver once
-// we want the NIC to do something.
+// we want the NIC to do something. Returns true if the driver was kicked.
 #[inline(always)]
-fn kick(ring: &TxRing<SliceUmemFrame<'_>>) {
-    if !ring.needs_wakeup() {
-        return;
+fn kick(queue: &mut impl TxQueue) -> bool {
+    if !queue.needs_wakeup() {
+        return false;
     }
 
-    if let Err(e) = ring.wake() {
+    if let Err(e) = queue.kick() {
         kick_error(e);
     }
+    true
 }
 
 #[inline(never)]
@@ -338,3 +1711,793 @@ fn kick_error(e: std::io::Error) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{
+            delivery::DestinationStats,
+            mock::{MockFrame, MockTxQueue, MockUmem},
+            packet::{parse_udp_frame, ETH_HEADER_SIZE, IP_HEADER_SIZE, UDP_HEADER_SIZE},
+            schedule::{replay_schedule, ScheduleReader},
+            shaping::RateLimit,
+            sim::{veth_pair, SimEndpoint, SimSocket},
+            socket::Tx,
+            umem::SliceUmemFrame,
+        },
+        std::{cell::RefCell, collections::HashSet, os::unix::net::UnixStream, rc::Rc},
+    };
+
+    const FRAME_SIZE: usize = 2048;
+
+    // A tx loop over a simulated socket, with the state it keeps across sockets.
+    struct SimTx<'a> {
+        socket: SimSocket<SliceUmem<'a>>,
+        tx: Tx<SliceUmemFrame<'a>>,
+        router: Router,
+        header_cache: HeaderCache,
+        addressing: TxAddressing,
+    }
+
+    impl<'a> SimTx<'a> {
+        // Creates a socket with rings of `ring_size` over `memory`, connected to the returned
+        // endpoint. Packets are sent from 10.0.0.1:9000 and 01:02:03:04:05:06 to
+        // 06:05:04:03:02:01.
+        fn new(memory: &'a mut PageAlignedMemory, ring_size: usize) -> (Self, SimEndpoint) {
+            let umem = SliceUmem::new(memory, FRAME_SIZE as u32).unwrap();
+            let (endpoint, peer) = veth_pair();
+            let (socket, tx) = SimSocket::tx(umem, endpoint, ring_size, ring_size).unwrap();
+            let sim = Self {
+                socket,
+                tx,
+                router: Router::new().unwrap(),
+                header_cache: HeaderCache::new(HEADER_CACHE_CAPACITY),
+                addressing: test_addressing(),
+            };
+            (sim, peer)
+        }
+
+        fn run<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
+            &mut self,
+            receiver: Receiver<(A, T)>,
+            priority_receiver: Option<Receiver<(A, T)>>,
+            drop_sender: Sender<(A, T)>,
+            config: &TxLoopConfig,
+            hooks: TxLoopHooks<'_>,
+        ) -> TxLoopExit {
+            run_tx_loop(
+                &mut self.tx,
+                self.socket.umem(),
+                &mut self.router,
+                &mut self.header_cache,
+                &self.addressing,
+                TxChannels {
+                    receiver,
+                    priority_receiver,
+                    drop_sender,
+                },
+                config,
+                hooks,
+            )
+        }
+    }
+
+    // Sends from 10.0.0.1:9000 and 01:02:03:04:05:06 to 06:05:04:03:02:01.
+    fn test_addressing() -> TxAddressing {
+        TxAddressing {
+            if_index: 0,
+            src_mac: MacAddress([1, 2, 3, 4, 5, 6]),
+            src_ip: Ipv4Addr::new(10, 0, 0, 1),
+            route_src_ip: false,
+            src_port: 9000,
+            src_port_policy: SrcPortPolicy::Fixed,
+            dest_mac: Some(MacAddress([6, 5, 4, 3, 2, 1])),
+        }
+    }
+
+    // Runs a tx loop over the mocks, addressed like SimTx.
+    fn run_mock<T: AsRef<[u8]>, A: AsRef<[SocketAddr]>>(
+        queue: &mut MockTxQueue<MockFrame>,
+        umem: &mut MockUmem,
+        receiver: Receiver<(A, T)>,
+        drop_sender: Sender<(A, T)>,
+        config: &TxLoopConfig,
+        hooks: TxLoopHooks<'_>,
+    ) -> TxLoopExit {
+        run_tx_loop(
+            queue,
+            umem,
+            &mut Router::new().unwrap(),
+            &mut HeaderCache::new(HEADER_CACHE_CAPACITY),
+            &test_addressing(),
+            TxChannels {
+                receiver,
+                priority_receiver: None,
+                drop_sender,
+            },
+            config,
+            hooks,
+        )
+    }
+
+    #[test]
+    fn test_run_tx_loop_sim() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 128).unwrap();
+        // 100 packets don't fit in the ring so the loop has to wait for completions
+        let (mut sim, peer) = SimTx::new(&mut memory, 64);
+        let TxAddressing {
+            src_mac,
+            src_ip,
+            dest_mac,
+            ..
+        } = sim.addressing;
+        let dest_mac = dest_mac.unwrap();
+        let addrs = (0..4)
+            .map(|i| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000 + i)))
+            .collect::<Vec<_>>();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        for i in 0..25u8 {
+            sender.send((addrs.clone(), vec![i; 100])).unwrap();
+        }
+        drop(sender);
+
+        let stats = Arc::new(TxLoopStats::default());
+        let delivery = Arc::new(DeliveryStats::default());
+        let (mirror_sender, mirror_receiver) = crossbeam_channel::unbounded();
+        let mut mirror = TxMirror::new(
+            MirrorConfig {
+                sample_rate: 10,
+                ..MirrorConfig::new(mirror_sender)
+            },
+            QueueId(0),
+        );
+        let config = TxLoopConfig {
+            stats: Some(stats.clone()),
+            delivery: Some(delivery.clone()),
+            ..TxLoopConfig::default()
+        };
+        sim.run(
+            receiver,
+            None,
+            drop_sender,
+            &config,
+            TxLoopHooks {
+                mirror: Some(&mut mirror),
+                ..TxLoopHooks::default()
+            },
+        );
+
+        // every payload is handed back once it has been sent to all its destinations
+        assert_eq!(drop_receiver.len(), 25);
+        // every frame has been completed and released
+        assert_eq!(sim.socket.umem().available(), 128);
+        assert_eq!(stats.packets_sent.load(Ordering::Relaxed), 100);
+        assert_eq!(stats.packets_completed.load(Ordering::Relaxed), 100);
+        assert_eq!(stats.packets_dropped.load(Ordering::Relaxed), 0);
+        // the loop merges its counts into the delivery stats when it returns
+        assert_eq!(delivery.len(), 4);
+        assert_eq!(
+            delivery.get(&addrs[0]).unwrap(),
+            DestinationStats {
+                submitted: 25,
+                completed: 25,
+                dropped: 0,
+            }
+        );
+        assert!(delivery.worst(4).is_empty());
+        // one every 10 frames is mirrored
+        let mirrored = mirror_receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(mirrored.len(), 10);
+        assert!(mirrored
+            .iter()
+            .all(|frame| frame.data.len() == UDP_FRAME_HEADER_SIZE + 100));
+
+        for i in 0..25u8 {
+            for addr in &addrs {
+                let frame = peer.recv_timeout(Duration::from_secs(5)).unwrap();
+                assert_eq!(&frame[0..6], &dest_mac.0);
+                assert_eq!(&frame[6..12], &src_mac.0);
+                let ip = &frame[ETH_HEADER_SIZE..];
+                assert_eq!(&ip[12..16], &src_ip.octets());
+                assert_eq!(&ip[16..20], &[10, 0, 0, 2]);
+                let udp = &ip[IP_HEADER_SIZE..];
+                assert_eq!(u16::from_be_bytes([udp[0], udp[1]]), 9000);
+                assert_eq!(u16::from_be_bytes([udp[2], udp[3]]), addr.port());
+                assert_eq!(&udp[UDP_HEADER_SIZE..], &[i; 100]);
+            }
+        }
+        assert!(peer.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_run_tx_loop_mock() {
+        // the ring holds fewer packets than the loop is given, so it has to kick the driver and
+        // wait for completions
+        let mut umem = MockUmem::new(FRAME_SIZE, 16);
+        let mut queue = MockTxQueue::new(8);
+        queue.on_kick(|committed| committed);
+        let addrs = (0..3)
+            .map(|i| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000 + i)))
+            .collect::<Vec<_>>();
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        for i in 0..10u8 {
+            sender.send((addrs.clone(), vec![i; 100])).unwrap();
+        }
+        drop(sender);
+
+        let stats = Arc::new(TxLoopStats::default());
+        let (mirror_sender, mirror_receiver) = crossbeam_channel::unbounded();
+        let mut mirror = TxMirror::new(
+            MirrorConfig {
+                sample_rate: 1,
+                ..MirrorConfig::new(mirror_sender)
+            },
+            QueueId(0),
+        );
+        let config = TxLoopConfig {
+            stats: Some(stats.clone()),
+            // the ring is smaller than a batch, commit before it fills up
+            kick: KickPolicy::EveryFrames(4),
+            ..TxLoopConfig::default()
+        };
+        let exit = run_mock(
+            &mut queue,
+            &mut umem,
+            receiver,
+            drop_sender,
+            &config,
+            TxLoopHooks {
+                mirror: Some(&mut mirror),
+                ..TxLoopHooks::default()
+            },
+        );
+
+        assert!(matches!(exit, TxLoopExit::Finished));
+        assert_eq!(drop_receiver.len(), 10);
+        // every frame has been completed and released exactly once
+        assert_eq!(umem.available(), 16);
+        assert_eq!(queue.committed(), 0);
+        assert!(queue.kicks() > 0);
+        assert_eq!(stats.packets_sent.load(Ordering::Relaxed), 30);
+        assert_eq!(stats.packets_completed.load(Ordering::Relaxed), 30);
+        assert_eq!(stats.packets_dropped.load(Ordering::Relaxed), 0);
+
+        let dest_mac = test_addressing().dest_mac.unwrap();
+        let frames = mirror_receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(frames.len(), 30);
+        for (i, frame) in frames.iter().enumerate() {
+            let frame = &frame.data;
+            assert_eq!(&frame[0..6], &dest_mac.0);
+            let udp = &frame[ETH_HEADER_SIZE + IP_HEADER_SIZE..];
+            assert_eq!(
+                u16::from_be_bytes([udp[2], udp[3]]),
+                addrs[i % addrs.len()].port()
+            );
+            assert_eq!(&udp[UDP_HEADER_SIZE..], &[(i / addrs.len()) as u8; 100]);
+        }
+    }
+
+    #[test]
+    fn test_run_tx_loop_fanout() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 512).unwrap();
+        let (mut sim, peer) = SimTx::new(&mut memory, 256);
+        let dest_mac = sim.addressing.dest_mac.unwrap();
+        // more destinations than BATCH_SIZE, so the fan-out spans several chunks
+        let addrs = (0..150)
+            .map(|i| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000 + i)))
+            .collect::<Vec<_>>();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        // the first payload builds the headers, the others take the fast path
+        for i in 0..3u8 {
+            sender.send((addrs.clone(), vec![i; 1000])).unwrap();
+        }
+        drop(sender);
+
+        let stats = Arc::new(TxLoopStats::default());
+        let config = TxLoopConfig {
+            stats: Some(stats.clone()),
+            ..TxLoopConfig::default()
+        };
+        sim.run(receiver, None, drop_sender, &config, TxLoopHooks::default());
+
+        assert_eq!(drop_receiver.len(), 3);
+        assert_eq!(sim.socket.umem().available(), 512);
+        assert_eq!(stats.packets_completed.load(Ordering::Relaxed), 450);
+        for i in 0..3u8 {
+            for addr in &addrs {
+                let frame = peer.recv_timeout(Duration::from_secs(5)).unwrap();
+                assert_eq!(&frame[0..6], &dest_mac.0);
+                let ip = &frame[ETH_HEADER_SIZE..];
+                assert_eq!(u16::from_be_bytes([ip[2], ip[3]]), 1028);
+                let udp = &ip[IP_HEADER_SIZE..];
+                assert_eq!(u16::from_be_bytes([udp[2], udp[3]]), addr.port());
+                assert_eq!(u16::from_be_bytes([udp[4], udp[5]]), 1008);
+                assert_eq!(&udp[UDP_HEADER_SIZE..], &[i; 1000]);
+            }
+        }
+        assert!(peer.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_run_tx_loop_shaping() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 128).unwrap();
+        let (mut sim, peer) = SimTx::new(&mut memory, 64);
+        let addrs = (0..2)
+            .map(|i| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000 + i)))
+            .collect::<Vec<_>>();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        for i in 0..5u8 {
+            sender.send((addrs.clone(), vec![i; 100])).unwrap();
+        }
+        drop(sender);
+
+        // room for the first 4 payloads to both destinations, and no refill
+        let shaper = TrafficShaper::new();
+        shaper.set_limit(
+            TrafficClass::Turbine,
+            Some(RateLimit {
+                packets_per_second: 0,
+                burst: 8,
+            }),
+        );
+        let stats = Arc::new(TxLoopStats::default());
+        let config = TxLoopConfig {
+            stats: Some(stats.clone()),
+            shaping: Some((shaper.clone(), TrafficClass::Turbine)),
+            ..TxLoopConfig::default()
+        };
+        sim.run(receiver, None, drop_sender, &config, TxLoopHooks::default());
+
+        // the throttled payload is handed back too
+        assert_eq!(drop_receiver.len(), 5);
+        assert_eq!(stats.packets_completed.load(Ordering::Relaxed), 8);
+        assert_eq!(stats.throttled_drops.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.packets_dropped.load(Ordering::Relaxed), 0);
+        assert_eq!(shaper.sent(TrafficClass::Turbine), 8);
+        assert_eq!(shaper.throttled(TrafficClass::Turbine), 2);
+        for i in 0..4u8 {
+            for _ in &addrs {
+                let frame = peer.recv_timeout(Duration::from_secs(5)).unwrap();
+                assert_eq!(&frame[UDP_FRAME_HEADER_SIZE..], &[i; 100]);
+            }
+        }
+        assert!(peer.try_recv().is_none());
+    }
+
+    #[test]
+    fn test_run_tx_loop_blocklist() {
+        let mut umem = MockUmem::new(FRAME_SIZE, 16);
+        let mut queue = MockTxQueue::new(16);
+        queue.on_kick(|committed| committed);
+        let addrs =
+            [2, 3].map(|i| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, i), 8000)));
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        for i in 0..3u8 {
+            sender.send((addrs, vec![i; 100])).unwrap();
+        }
+        drop(sender);
+
+        let blocklist = DestinationBlocklist::new();
+        blocklist.block(Ipv4Addr::new(10, 0, 0, 3));
+        let stats = Arc::new(TxLoopStats::default());
+        let config = TxLoopConfig {
+            stats: Some(stats.clone()),
+            blocklist: Some(blocklist),
+            ..TxLoopConfig::default()
+        };
+        run_mock(
+            &mut queue,
+            &mut umem,
+            receiver,
+            drop_sender,
+            &config,
+            TxLoopHooks::default(),
+        );
+
+        // the fan-outs take the slow path and skip the blocked destination
+        assert_eq!(drop_receiver.len(), 3);
+        assert_eq!(stats.packets_sent.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.blocked_drops.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.packets_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_run_tx_loop_stalled() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 128).unwrap();
+        let (mut sim, _peer) = SimTx::new(&mut memory, 64);
+        sim.socket.set_tx_stalled(true);
+
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000));
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        // more than the ring can hold
+        for i in 0..100u8 {
+            sender.send(([addr], vec![i; 100])).unwrap();
+        }
+
+        let stats = Arc::new(TxLoopStats::default());
+        let config = TxLoopConfig {
+            stats: Some(stats.clone()),
+            ..TxLoopConfig::default()
+        };
+        let exit = sim.run(
+            receiver,
+            None,
+            drop_sender,
+            &config,
+            TxLoopHooks {
+                stall_timeout: Some(Duration::from_millis(50)),
+                ..TxLoopHooks::default()
+            },
+        );
+
+        // the loop gives up on the wedged ring even though the sender is still connected
+        let TxLoopExit::Stalled {
+            in_flight,
+            stalled_for,
+        } = exit
+        else {
+            panic!("expected a stall, got {exit:?}");
+        };
+        assert_eq!(in_flight, 64);
+        assert!(stalled_for >= Duration::from_millis(50));
+        // nothing was sent, and every payload the loop received was handed back, including the
+        // ones that didn't make it to the ring
+        assert_eq!(sim.socket.stats().tx_frames.load(Ordering::Relaxed), 0);
+        assert_eq!(drop_receiver.len() + sender.len(), 100);
+        // the payloads past the ring are counted as stall or ring full drops, not unroutable
+        assert_eq!(
+            stats.stall_drops.load(Ordering::Relaxed)
+                + stats.ring_full_drops.load(Ordering::Relaxed),
+            drop_receiver.len() as u64 - 64
+        );
+        assert_eq!(stats.packets_dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_ring_full_drops() {
+        let stats = TxLoopStats::default();
+        let addr = |i: usize| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, i as u16));
+        for i in 0..MAX_DROP_DESTINATIONS + 10 {
+            stats.ring_full_drop(&addr(i), false);
+        }
+        stats.ring_full_drop(&addr(0), true);
+        assert_eq!(
+            stats.ring_full_drops.load(Ordering::Relaxed),
+            MAX_DROP_DESTINATIONS as u64 + 10
+        );
+        assert_eq!(stats.priority_ring_full_drops.load(Ordering::Relaxed), 1);
+
+        // destinations past the limit are only counted in the totals
+        let drops = stats.take_ring_full_drops();
+        assert_eq!(drops.len(), MAX_DROP_DESTINATIONS);
+        assert_eq!(drops[&addr(0)], 2);
+        assert!(!drops.contains_key(&addr(MAX_DROP_DESTINATIONS)));
+        assert!(stats.take_ring_full_drops().is_empty());
+    }
+
+    #[test]
+    fn test_ring_full_retries() {
+        const MAX_RETRIES: usize = 4;
+        let mut umem = MockUmem::new(FRAME_SIZE, 16);
+        let mut queue = MockTxQueue::new(2);
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000));
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        // two batches of three packets, the last one of each finds the ring full
+        for i in 0..3u8 {
+            sender.send(([addr], vec![i; 100])).unwrap();
+        }
+
+        // The driver stalls while the loop waits for room for the last packet of a batch, and
+        // counts the retries by the kicks it gets meanwhile. The loop hands each payload back once
+        // it's done with it, which tells where it is.
+        let retries = Rc::new(RefCell::new([0; 2]));
+        let mut sender = Some(sender);
+        queue.on_kick({
+            let retries = retries.clone();
+            let drop_receiver = drop_receiver.clone();
+            move |committed| match drop_receiver.len() {
+                2 => {
+                    retries.borrow_mut()[0] += 1;
+                    0
+                }
+                5 => {
+                    retries.borrow_mut()[1] += 1;
+                    0
+                }
+                // the first batch is done: free the ring without the loop waiting for room, which
+                // doesn't reset shedding on its own, then send the second batch
+                3 => match sender.take() {
+                    Some(sender) => {
+                        for i in 3..6u8 {
+                            sender.send(([addr], vec![i; 100])).unwrap();
+                        }
+                        committed
+                    }
+                    None => 0,
+                },
+                6 => committed,
+                _ => 0,
+            }
+        });
+
+        let stats = Arc::new(TxLoopStats::default());
+        let config = TxLoopConfig {
+            stats: Some(stats.clone()),
+            kick: KickPolicy::EveryFrames(1),
+            retry: TxRetryPolicy {
+                max_retries: Some(MAX_RETRIES as u32),
+                backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            },
+            ..TxLoopConfig::default()
+        };
+        run_mock(
+            &mut queue,
+            &mut umem,
+            receiver,
+            drop_sender,
+            &config,
+            TxLoopHooks::default(),
+        );
+
+        // the packet after the ring freed up was written, so the second drop waited as long as the
+        // first one
+        assert_eq!(*retries.borrow(), [MAX_RETRIES, MAX_RETRIES]);
+        assert_eq!(stats.ring_full_drops.load(Ordering::Relaxed), 2);
+        assert_eq!(stats.packets_sent.load(Ordering::Relaxed), 4);
+        assert_eq!(umem.available(), 16);
+    }
+
+    #[test]
+    fn test_src_port_policy() {
+        let dst = |i: u8, port| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, i), port);
+        assert_eq!(SrcPortPolicy::Fixed.src_port(9000, &dst(1, 8000)), 9000);
+        assert_eq!(
+            SrcPortPolicy::DestinationHash { count: 0 }.src_port(9000, &dst(1, 8000)),
+            9000
+        );
+
+        let policy = SrcPortPolicy::DestinationHash { count: 64 };
+        let ports = (0..=255)
+            .flat_map(|i| [dst(i, 8000), dst(i, 8001)])
+            .map(|dst| {
+                let port = policy.src_port(9000, &dst);
+                // the same destination always gets the same port
+                assert_eq!(port, policy.src_port(9000, &dst));
+                assert!((9000..9064).contains(&port));
+                port
+            })
+            .collect::<HashSet<_>>();
+        // destinations are spread over the whole range
+        assert!(ports.len() > 60, "{}", ports.len());
+    }
+
+    #[test]
+    fn test_kick_policy() {
+        let due = |policy, pending, in_flight| {
+            let mut kicker = Kicker::new(policy, None, None);
+            for _ in 0..pending {
+                kicker.written();
+            }
+            kicker.is_due(in_flight)
+        };
+
+        // nothing to kick for
+        assert!(!due(KickPolicy::EveryFrames(1), 0, 0));
+        assert!(!due(KickPolicy::EveryFrames(8), 7, 7));
+        assert!(due(KickPolicy::EveryFrames(8), 8, 8));
+
+        assert!(!due(KickPolicy::Timer(Duration::from_secs(60)), 100, 100));
+        assert!(due(KickPolicy::Timer(Duration::ZERO), 1, 1));
+
+        // kick every packet when the ring is nearly idle, less often as it fills up
+        let adaptive = KickPolicy::Adaptive { max_frames: 32 };
+        assert!(due(adaptive, 1, 1));
+        assert!(!due(adaptive, 1, 100));
+        assert!(due(adaptive, 25, 100));
+        assert!(!due(adaptive, 31, 1000));
+        assert!(due(adaptive, 32, 1000));
+    }
+
+    #[test]
+    fn test_run_tx_loop_priority() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 64).unwrap();
+        let (mut sim, peer) = SimTx::new(&mut memory, 32);
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000));
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (priority_sender, priority_receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        for i in 0..10u8 {
+            sender.send(([addr], vec![i; 10])).unwrap();
+        }
+        for _ in 0..2 {
+            priority_sender.send(([addr], vec![0xff; 10])).unwrap();
+        }
+        drop(sender);
+        drop(priority_sender);
+
+        let stats = Arc::new(TxLoopStats::default());
+        let (schedule_writer, schedule_reader) = UnixStream::pair().unwrap();
+        let schedule = ScheduleRecorder::new(
+            Box::new(schedule_writer),
+            ScheduleHeader {
+                frame_size: FRAME_SIZE as u32,
+                frame_count: 64,
+                ring_size: 32,
+            },
+        )
+        .unwrap();
+        let config = TxLoopConfig {
+            stats: Some(stats.clone()),
+            ..TxLoopConfig::default()
+        };
+        sim.run(
+            receiver,
+            Some(priority_receiver),
+            drop_sender,
+            &config,
+            TxLoopHooks {
+                schedule: Some(&schedule),
+                ..TxLoopHooks::default()
+            },
+        );
+        assert_eq!(drop_receiver.len(), 12);
+        assert_eq!(stats.packets_sent.load(Ordering::Relaxed), 12);
+        assert_eq!(stats.priority_packets.load(Ordering::Relaxed), 2);
+
+        const PAYLOAD_OFFSET: usize = ETH_HEADER_SIZE + IP_HEADER_SIZE + UDP_HEADER_SIZE;
+        // priority packets go out first even though they were queued last
+        let payloads = (0..12)
+            .map(|_| peer.recv_timeout(Duration::from_secs(5)).unwrap()[PAYLOAD_OFFSET])
+            .collect::<Vec<_>>();
+        assert_eq!(&payloads[..2], &[0xff, 0xff]);
+        assert_eq!(&payloads[2..], &(0..10).collect::<Vec<u8>>());
+
+        // the recorded schedule replays cleanly
+        drop(schedule);
+        let replay =
+            replay_schedule(ScheduleReader::new(schedule_reader).unwrap(), &config).unwrap();
+        assert_eq!(replay.packets, 12);
+        assert_eq!(replay.priority_packets, 2);
+        assert_eq!(replay.completed, 12);
+    }
+
+    #[test]
+    fn test_run_tx_loop_vlan() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 64).unwrap();
+        let (mut sim, peer) = SimTx::new(&mut memory, 32);
+
+        let addrs = (0..2)
+            .map(|i| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000 + i)))
+            .collect::<Vec<_>>();
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (priority_sender, priority_receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, _drop_receiver) = crossbeam_channel::unbounded();
+        // the first payload builds the headers, the others take the fan-out fast path
+        for i in 0..3u8 {
+            sender.send((addrs.clone(), vec![i; 10])).unwrap();
+        }
+        priority_sender
+            .send((addrs[..1].to_vec(), vec![0xff; 10]))
+            .unwrap();
+        drop(sender);
+        drop(priority_sender);
+
+        let config = TxLoopConfig {
+            vlan: Some(VlanMarking::new(100)),
+            ..TxLoopConfig::default()
+        };
+        sim.run(
+            receiver,
+            Some(priority_receiver),
+            drop_sender,
+            &config,
+            TxLoopHooks::default(),
+        );
+
+        for i in 0..7 {
+            let frame = peer.recv_timeout(Duration::from_secs(5)).unwrap();
+            let parsed = parse_udp_frame(&frame, false).unwrap();
+            assert_eq!(parsed.vlan_id, Some(100));
+            assert_eq!(parsed.payload.len(), 10);
+            let pcp = frame[ETH_HEADER_SIZE] >> 5;
+            if i == 0 {
+                assert_eq!(parsed.payload[0], 0xff);
+                assert_eq!(pcp, TrafficClass::Vote.pcp());
+            } else {
+                assert_eq!(parsed.payload[0], (i - 1) / 2);
+                assert_eq!(pcp, TrafficClass::Turbine.pcp());
+            }
+        }
+    }
+
+    #[test]
+    fn test_run_tx_loop_warmup() {
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 64).unwrap();
+        let (mut sim, _peer) = SimTx::new(&mut memory, 32);
+
+        let peers = (0..2)
+            .map(|i| SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000 + i))
+            .collect::<Vec<_>>();
+        let warmup = PeerWarmup::new();
+        warmup.warm(peers.iter().copied().map(SocketAddr::V4));
+        let src_port_policy = SrcPortPolicy::DestinationHash { count: 4 };
+        sim.addressing.src_port_policy = src_port_policy;
+        // nothing is sent, the loop exits right after warming up
+        let (_, receiver) = crossbeam_channel::unbounded::<(Vec<SocketAddr>, Vec<u8>)>();
+        let (drop_sender, _drop_receiver) = crossbeam_channel::unbounded();
+
+        let config = TxLoopConfig {
+            warmup: Some(warmup),
+            ..TxLoopConfig::default()
+        };
+        sim.run(receiver, None, drop_sender, &config, TxLoopHooks::default());
+
+        assert_eq!(sim.header_cache.len(), 2);
+        for peer in &peers {
+            let src_port = src_port_policy.src_port(9000, peer);
+            let header = sim.header_cache.get(peer, src_port).unwrap();
+            let parsed = parse_udp_frame(header, false).unwrap();
+            assert_eq!(parsed.dst_ip, *peer.ip());
+            assert_eq!(parsed.dst_port, peer.port());
+            assert_eq!(parsed.src_port, src_port);
+        }
+    }
+
+    #[test]
+    fn test_run_tx_loop_idle_budget() {
+        let budget = TxCpuBudget::default();
+        // over budget, spinning is cut back down to nothing
+        let spin = adjust_spin(budget.max_spin, &budget, 0.9);
+        assert_eq!(spin, budget.max_spin / 2);
+        assert_eq!(adjust_spin(Duration::ZERO, &budget, 0.9), Duration::ZERO);
+        // under budget it grows back, up to max_spin
+        assert_eq!(
+            adjust_spin(Duration::ZERO, &budget, 0.01),
+            Duration::from_micros(1)
+        );
+        assert_eq!(adjust_spin(spin, &budget, 0.01), budget.max_spin);
+        assert_eq!(adjust_spin(spin, &budget, 0.22), spin);
+
+        let mut memory = PageAlignedMemory::alloc(FRAME_SIZE, 128).unwrap();
+        let (mut sim, peer) = SimTx::new(&mut memory, 64);
+        let addr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 8000));
+
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let (drop_sender, drop_receiver) = crossbeam_channel::unbounded();
+        let config = TxLoopConfig {
+            idle: TxIdlePolicy::Budget(budget),
+            ..TxLoopConfig::default()
+        };
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                // packets arriving while the loop is parked are picked up
+                for i in 0..20u8 {
+                    if i % 5 == 0 {
+                        thread::sleep(Duration::from_millis(20));
+                    }
+                    sender.send(([addr], vec![i; 100])).unwrap();
+                }
+            });
+            sim.run(receiver, None, drop_sender, &config, TxLoopHooks::default());
+        });
+
+        assert_eq!(drop_receiver.len(), 20);
+        assert_eq!(sim.socket.umem().available(), 128);
+        for i in 0..20u8 {
+            let frame = peer.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(frame[UDP_FRAME_HEADER_SIZE], i);
+        }
+    }
+}
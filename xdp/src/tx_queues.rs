@@ -0,0 +1,282 @@
+//! Picking the NIC queues the tx loops send on.
+//!
+//! RSS steers received flows to the queues listed in the device's indirection table, and the rx
+//! loops bind sockets to their own queues. A tx loop sending on a queue that also receives has
+//! its completions contend with rx interrupts on the same CPU, and can't bind at all to a queue an
+//! rx loop is bound to. [`select_tx_queues`] picks queues nothing is steered to when there are
+//! some, and can reconfigure the device to free up more.
+//!
+//! Devices configured with mqprio, e.g. by DCB, split their queues between hardware traffic
+//! classes that the NIC schedules against each other. [`select_class_tx_queues`] picks queues of
+//! the hardware class the priority of a [`TrafficClass`] maps to, so that what the NIC schedules
+//! lines up with our pacing and with the PCP switches see.
+
+use {
+    crate::{
+        device::{Channels, NetworkDevice, QueueId},
+        error::{XdpError, XdpErrorKind},
+        netlink::netlink_get_mqprio,
+        shaping::TrafficClass,
+    },
+    std::{collections::HashSet, ops::Range},
+};
+
+/// Whether [`select_tx_queues`] may reconfigure the device when there aren't enough free queues.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueReservation {
+    /// Share queues with RSS when there aren't enough free ones.
+    #[default]
+    Share,
+    /// Add combined channels if the device has spare ones, otherwise steer RSS away from the
+    /// queues picked for tx. Requires `CAP_NET_ADMIN`, and adding channels resets the device.
+    Reconfigure,
+}
+
+/// Returns `count` queues of `dev` for tx loops to bind to, never one of `rx_queues`.
+///
+/// Queues RSS doesn't steer to are preferred, highest first, since RSS and rx loops usually
+/// start from queue 0. If the driver doesn't report its RSS table, every queue is assumed to
+/// receive.
+pub fn select_tx_queues(
+    dev: &NetworkDevice,
+    count: usize,
+    rx_queues: &[QueueId],
+    reservation: QueueReservation,
+) -> Result<Vec<QueueId>, XdpError> {
+    let rx_queues = rx_queues.iter().map(|q| q.0 as u32).collect::<HashSet<_>>();
+    let (mut queue_count, mut table) = queue_state(dev)?;
+    let mut queues = pick_queues(0..queue_count, &rx_queues, &table, count);
+    if queues.len() < count {
+        return Err(XdpError::other(
+            XdpErrorKind::Misconfigured,
+            "select_tx_queues",
+            format!(
+                "need {count} tx queues but {} only has {queue_count} queues and {} are used for \
+                 rx",
+                dev.name(),
+                rx_queues.len()
+            ),
+        ));
+    }
+
+    if reservation == QueueReservation::Reconfigure && queues.iter().any(|q| table.contains(q)) {
+        reserve_queues(dev, &rx_queues, &queues, queue_count, &table)?;
+        (queue_count, table) = queue_state(dev)?;
+        queues = pick_queues(0..queue_count, &rx_queues, &table, count);
+    }
+
+    warn_shared(dev, &queues, &table);
+    Ok(queues.into_iter().map(|q| QueueId(q as u64)).collect())
+}
+
+/// Returns `count` queues of `dev` for tx loops sending `class` to bind to, never one of
+/// `rx_queues`.
+///
+/// If `dev` has an mqprio root qdisc, the queues are picked among those of the hardware traffic
+/// class the [PCP](TrafficClass::pcp) of `class` maps to, preferring those RSS doesn't steer to.
+/// The device isn't reconfigured since that would change the layout of the classes. Otherwise
+/// this is [`select_tx_queues`] sharing queues with RSS.
+pub fn select_class_tx_queues(
+    dev: &NetworkDevice,
+    class: TrafficClass,
+    count: usize,
+    rx_queues: &[QueueId],
+) -> Result<Vec<QueueId>, XdpError> {
+    let Some(class_queues) = class_queues(dev, class)? else {
+        return select_tx_queues(dev, count, rx_queues, QueueReservation::Share);
+    };
+    let rx_queues = rx_queues.iter().map(|q| q.0 as u32).collect::<HashSet<_>>();
+    let (_, table) = queue_state(dev)?;
+    let queues = pick_queues(class_queues.clone(), &rx_queues, &table, count);
+    if queues.len() < count {
+        return Err(XdpError::other(
+            XdpErrorKind::Misconfigured,
+            "select_class_tx_queues",
+            format!(
+                "need {count} tx queues for {class:?} but its traffic class on {} only has queues \
+                 {class_queues:?} and {} are used for rx",
+                dev.name(),
+                rx_queues.len()
+            ),
+        ));
+    }
+    warn_shared(dev, &queues, &table);
+    Ok(queues.into_iter().map(|q| QueueId(q as u64)).collect())
+}
+
+/// Returns the queues of the hardware traffic class `class` maps to, or None if `dev` doesn't
+/// have an mqprio root qdisc.
+pub fn class_queues(
+    dev: &NetworkDevice,
+    class: TrafficClass,
+) -> Result<Option<Range<u32>>, XdpError> {
+    let Some(mqprio) = netlink_get_mqprio(dev.if_index())? else {
+        return Ok(None);
+    };
+    if !mqprio.hw {
+        log::warn!(
+            "the mqprio classes of {} aren't offloaded, the NIC schedules its queues equally",
+            dev.name()
+        );
+    }
+    mqprio
+        .queues_for_priority(class.pcp())
+        .filter(|queues| !queues.is_empty())
+        .map(Some)
+        .ok_or_else(|| {
+            XdpError::other(
+                XdpErrorKind::Misconfigured,
+                "select_class_tx_queues",
+                format!(
+                    "priority {} of {class:?} maps to no traffic class with queues on {}",
+                    class.pcp(),
+                    dev.name()
+                ),
+            )
+        })
+}
+
+fn warn_shared(dev: &NetworkDevice, queues: &[u32], table: &[u32]) {
+    let shared = queues
+        .iter()
+        .filter(|q| table.contains(q))
+        .collect::<Vec<_>>();
+    if !shared.is_empty() {
+        log::warn!(
+            "tx queues {shared:?} of {} also receive RSS traffic, consider reserving queues",
+            dev.name()
+        );
+    }
+}
+
+// Returns the number of queues and the RSS indirection table of `dev`.
+fn queue_state(dev: &NetworkDevice) -> Result<(u32, Vec<u32>), XdpError> {
+    let queue_count = dev
+        .queue_count()
+        .map_err(|e| XdpError::new("read_dir(queues)", e))? as u32;
+    let table = dev.rss_indirection_table().unwrap_or_else(|e| {
+        log::debug!("can't read the RSS table of {}: {e}", dev.name());
+        (0..queue_count).collect()
+    });
+    Ok((queue_count, table))
+}
+
+// Frees the queues in `queues` that RSS steers to.
+fn reserve_queues(
+    dev: &NetworkDevice,
+    rx_queues: &HashSet<u32>,
+    queues: &[u32],
+    queue_count: u32,
+    table: &[u32],
+) -> Result<(), XdpError> {
+    let missing = queues.iter().filter(|q| table.contains(q)).count() as u32;
+    let channels = dev.channels()?;
+    if channels.combined.saturating_add(missing) <= channels.max_combined {
+        log::info!(
+            "adding {missing} combined channels to {} for tx queues",
+            dev.name()
+        );
+        dev.set_channels(&Channels {
+            combined: channels.combined.saturating_add(missing),
+            ..channels
+        })?;
+        // drivers spread RSS over the new channels too, steer it back to the old queues
+        let mut rss_queues = table.to_vec();
+        rss_queues.sort_unstable();
+        rss_queues.dedup();
+        let size = dev.rss_indirection_table()?.len();
+        let table = rss_queues
+            .into_iter()
+            .cycle()
+            .take(size)
+            .collect::<Vec<_>>();
+        return dev.set_rss_indirection_table(&table);
+    }
+
+    // no spare channels, take the queues picked for tx out of the table
+    let reserved = pick_queues(0..queue_count, rx_queues, table, queues.len());
+    let table = steer_away(table, &reserved).ok_or_else(|| {
+        XdpError::other(
+            XdpErrorKind::Misconfigured,
+            "select_tx_queues",
+            format!("{} has no queue left for RSS", dev.name()),
+        )
+    })?;
+    log::info!(
+        "steering RSS of {} away from tx queues {reserved:?}",
+        dev.name()
+    );
+    dev.set_rss_indirection_table(&table)
+}
+
+// Picks up to `count` of `queues` that aren't in `rx_queues`, preferring those the RSS `table`
+// doesn't steer to, highest first.
+fn pick_queues(
+    queues: Range<u32>,
+    rx_queues: &HashSet<u32>,
+    table: &[u32],
+    count: usize,
+) -> Vec<u32> {
+    let mut queues = queues
+        .rev()
+        .filter(|q| !rx_queues.contains(q))
+        .collect::<Vec<_>>();
+    // stable, so each group stays highest first
+    queues.sort_by_key(|q| table.contains(q));
+    queues.truncate(count);
+    queues
+}
+
+// Returns `table` with the buckets steered to `reserved` spread over the other queues in the
+// table, or None if there are no other queues.
+fn steer_away(table: &[u32], reserved: &[u32]) -> Option<Vec<u32>> {
+    let mut remaining = table
+        .iter()
+        .copied()
+        .filter(|q| !reserved.contains(q))
+        .collect::<Vec<_>>();
+    remaining.sort_unstable();
+    remaining.dedup();
+    let mut next = remaining.iter().copied().cycle();
+    table
+        .iter()
+        .map(|q| {
+            if reserved.contains(q) {
+                next.next()
+            } else {
+                Some(*q)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_queues() {
+        // 8 queues, RSS over the first 4, an rx loop on queue 0
+        let table = [0, 1, 2, 3, 0, 1, 2, 3];
+        let rx_queues = HashSet::from([0]);
+        assert_eq!(pick_queues(0..8, &rx_queues, &table, 2), [7, 6]);
+        // once the free queues run out, the ones RSS steers to are shared
+        assert_eq!(pick_queues(0..8, &rx_queues, &table, 6), [7, 6, 5, 4, 3, 2]);
+        // rx queues are never picked
+        assert_eq!(pick_queues(0..8, &rx_queues, &table, 8).len(), 7);
+        assert_eq!(pick_queues(0..2, &rx_queues, &[0, 1], 1), [1]);
+        // the queues of an mqprio traffic class
+        assert_eq!(pick_queues(2..6, &rx_queues, &table, 3), [5, 4, 3]);
+    }
+
+    #[test]
+    fn test_steer_away() {
+        let table = [0, 1, 2, 3, 0, 1, 2, 3];
+        assert_eq!(
+            steer_away(&table, &[3, 2]).unwrap(),
+            [0, 1, 0, 1, 0, 1, 0, 1]
+        );
+        assert_eq!(steer_away(&table, &[]).unwrap(), table);
+        assert_eq!(steer_away(&table, &[0, 1, 2, 3]), None);
+    }
+}
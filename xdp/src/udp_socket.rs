@@ -0,0 +1,368 @@
+//! A [`UdpSocket`](std::net::UdpSocket) lookalike backed by AF_XDP.
+//!
+//! [`XdpUdpSocket`] offers the `send_to`/`recv_from` family of methods, blocking or not, so that
+//! code written against a regular socket can be moved to the XDP datapath one call site at a time.
+//! Datagrams are sent through an [`XdpTransport`], and received datagrams are fed to the socket
+//! through the sender returned by [`XdpUdpSocket::new`], like with the `XdpQuicSocket` of the
+//! `quic` feature.
+//!
+//! Only the common paths are covered: IPv4 only, no socket options beyond blocking mode and
+//! timeouts, and sending fails with [`io::ErrorKind::WouldBlock`] rather than waiting for buffer
+//! space in non-blocking mode.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        packet::{parse_udp_frame, ParseError},
+        transport::XdpTransport,
+    },
+    crossbeam_channel::{Receiver, RecvTimeoutError, Sender, TryRecvError},
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        fmt,
+        hash::{DefaultHasher, Hash, Hasher},
+        io,
+        net::{IpAddr, SocketAddr, ToSocketAddrs},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Wake, Waker},
+        thread::{self, Thread},
+        time::{Duration, Instant},
+    },
+};
+
+/// A datagram received from the rx queue.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecvDatagram {
+    pub src: SocketAddr,
+    pub dst_ip: Option<IpAddr>,
+    /// The ECN bits of the IP header.
+    pub ecn: u8,
+    pub payload: Bytes,
+}
+
+impl RecvDatagram {
+    /// Parses a received frame, copying its payload.
+    pub fn from_frame(frame: &[u8], verify_udp_checksum: bool) -> Result<Self, ParseError> {
+        let udp = parse_udp_frame(frame, verify_udp_checksum)?;
+        Ok(Self {
+            src: udp.src_addr(),
+            dst_ip: Some(IpAddr::V4(udp.dst_ip)),
+            ecn: udp.tos & 0b11,
+            payload: Bytes::copy_from_slice(udp.payload),
+        })
+    }
+}
+
+/// A UDP socket sending and receiving through XDP.
+pub struct XdpUdpSocket {
+    local_addr: SocketAddr,
+    transport: XdpTransport,
+    receiver: Receiver<RecvDatagram>,
+    peer: Mutex<Option<SocketAddr>>,
+    nonblocking: AtomicBool,
+    read_timeout: Mutex<Option<Duration>>,
+    write_timeout: Mutex<Option<Duration>>,
+}
+
+impl fmt::Debug for XdpUdpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XdpUdpSocket")
+            .field("local_addr", &self.local_addr)
+            .finish_non_exhaustive()
+    }
+}
+
+impl XdpUdpSocket {
+    /// Creates a blocking socket sending through `transport` from `local_addr`.
+    ///
+    /// `local_addr` must match the source address and port the tx loops are configured with.
+    /// Received datagrams are queued through the returned sender, up to `rx_channel_cap` of them.
+    pub fn new(
+        local_addr: SocketAddr,
+        transport: XdpTransport,
+        rx_channel_cap: usize,
+    ) -> (Self, Sender<RecvDatagram>) {
+        let (sender, receiver) = crossbeam_channel::bounded(rx_channel_cap);
+        (
+            Self {
+                local_addr,
+                transport,
+                receiver,
+                peer: Mutex::new(None),
+                nonblocking: AtomicBool::new(false),
+                read_timeout: Mutex::new(None),
+                write_timeout: Mutex::new(None),
+            },
+            sender,
+        )
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Sets the default destination of [`send`](Self::send), and only receives from `addr` with
+    /// [`recv`](Self::recv) and [`recv_from`](Self::recv_from).
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        *self.peer.lock().unwrap() = Some(resolve(addr)?);
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.peer
+            .lock()
+            .unwrap()
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Like [`UdpSocket::set_read_timeout`](std::net::UdpSocket::set_read_timeout), a zero
+    /// duration is rejected.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.write_timeout.lock().unwrap() = check_timeout(timeout)?;
+        Ok(())
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(*self.write_timeout.lock().unwrap())
+    }
+
+    /// Queues `buf` to be sent to `addr`. Returns the number of bytes queued, which is always all
+    /// of them.
+    ///
+    /// In blocking mode this waits for room in the transport, up to the write timeout. Once queued,
+    /// the datagram can still be dropped by the tx loop, as the kernel would drop it on a full
+    /// device queue.
+    pub fn send_to(&self, buf: &[u8], addr: impl ToSocketAddrs) -> io::Result<usize> {
+        let addr = resolve(addr)?;
+        // sockets bound to a dual stack address use IPv4-mapped addresses
+        let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+        if addr.is_ipv6() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "xdp only supports IPv4",
+            ));
+        }
+        let queue = self.queue_for(&addr);
+        let payload = Bytes::copy_from_slice(buf);
+        let deadline = self.deadline(*self.write_timeout.lock().unwrap());
+        loop {
+            match self
+                .transport
+                .send_all_to_queue(queue, [payload.clone()].into_iter(), addr)
+            {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result.map(|()| buf.len()),
+            }
+            let Some(deadline) = deadline else {
+                return Err(io::ErrorKind::WouldBlock.into());
+            };
+            self.wait_writable(deadline)?;
+        }
+    }
+
+    /// Sends `buf` to the address given to [`connect`](Self::connect).
+    pub fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_to(buf, self.peer_addr()?)
+    }
+
+    /// Receives a datagram into `buf`, returning its length and where it came from. Like with a
+    /// regular socket, a datagram that doesn't fit is truncated.
+    ///
+    /// Fails with [`io::ErrorKind::WouldBlock`] if nothing is received in non-blocking mode or
+    /// before the read timeout.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let peer = *self.peer.lock().unwrap();
+        let deadline = self.deadline(*self.read_timeout.lock().unwrap());
+        loop {
+            let datagram = match deadline {
+                None => self.receiver.try_recv().map_err(|e| match e {
+                    TryRecvError::Empty => io::ErrorKind::WouldBlock,
+                    TryRecvError::Disconnected => io::ErrorKind::BrokenPipe,
+                }),
+                Some(deadline) => self.receiver.recv_deadline(deadline).map_err(|e| match e {
+                    RecvTimeoutError::Timeout => io::ErrorKind::WouldBlock,
+                    RecvTimeoutError::Disconnected => io::ErrorKind::BrokenPipe,
+                }),
+            }?;
+            // a connected socket only receives from its peer
+            if peer.is_some_and(|peer| peer != datagram.src) {
+                continue;
+            }
+            let len = datagram.payload.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram.payload[..len]);
+            return Ok((len, datagram.src));
+        }
+    }
+
+    /// Receives a datagram from the address given to [`connect`](Self::connect).
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.peer_addr()?;
+        self.recv_from(buf).map(|(len, _)| len)
+    }
+
+    // Returns when a blocking call gives up, None in non-blocking mode.
+    fn deadline(&self, timeout: Option<Duration>) -> Option<Instant> {
+        if self.nonblocking.load(Ordering::Relaxed) {
+            return None;
+        }
+        // far enough to never be reached, without overflowing
+        let timeout = timeout.unwrap_or(Duration::from_secs(u32::MAX.into()));
+        Some(Instant::now() + timeout)
+    }
+
+    fn wait_writable(&self, deadline: Instant) -> io::Result<()> {
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        while self.transport.poll_writable(&mut cx).is_pending() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            thread::park_timeout(deadline - now);
+        }
+        Ok(())
+    }
+
+    // All the packets to a destination go through the same queue, so they're never reordered.
+    fn queue_for(&self, addr: &SocketAddr) -> usize {
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        (hasher.finish() % self.transport.queues() as u64) as usize
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+fn resolve(addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to send data to"))
+}
+
+fn check_timeout(timeout: Option<Duration>) -> io::Result<Option<Duration>> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datagram(src: &str, payload: &[u8]) -> RecvDatagram {
+        RecvDatagram {
+            src: src.parse().unwrap(),
+            dst_ip: None,
+            ecn: 0,
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    #[test]
+    fn test_send_to() {
+        let (transport, receivers) = XdpTransport::new(1, 1);
+        let (socket, _rx) = XdpUdpSocket::new("10.0.0.1:8009".parse().unwrap(), transport, 4);
+        assert_eq!(socket.send_to(b"hello", "10.0.0.2:8000").unwrap(), 5);
+        let (addrs, payload) = receivers[0].try_recv().unwrap();
+        assert_eq!(addrs, vec!["10.0.0.2:8000".parse().unwrap()]);
+        assert_eq!(&payload[..], b"hello");
+
+        // the channel is full, a blocking send waits up to the write timeout
+        socket.send_to(b"one", "10.0.0.2:8000").unwrap();
+        socket
+            .set_write_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let start = Instant::now();
+        assert_eq!(
+            socket.send_to(b"two", "10.0.0.2:8000").unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        socket.set_nonblocking(true).unwrap();
+        assert_eq!(
+            socket.send_to(b"two", "10.0.0.2:8000").unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        assert_eq!(
+            socket.send(b"hello").unwrap_err().kind(),
+            io::ErrorKind::NotConnected
+        );
+        assert_eq!(
+            socket
+                .send_to(b"hello", "[2001:db8::1]:8000")
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::Unsupported
+        );
+    }
+
+    #[test]
+    fn test_recv_from() {
+        let (transport, _receivers) = XdpTransport::new(1, 4);
+        let (socket, rx) = XdpUdpSocket::new("10.0.0.1:8009".parse().unwrap(), transport, 4);
+        let mut buf = [0u8; 4];
+
+        socket.set_nonblocking(true).unwrap();
+        assert_eq!(
+            socket.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        socket.set_nonblocking(false).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        assert_eq!(
+            socket.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        rx.send(datagram("10.0.0.2:8000", b"hello")).unwrap();
+        // truncated to the size of the buffer
+        assert_eq!(
+            socket.recv_from(&mut buf).unwrap(),
+            (4, "10.0.0.2:8000".parse().unwrap())
+        );
+        assert_eq!(&buf, b"hell");
+
+        // once connected, datagrams from other peers are dropped
+        socket.connect("10.0.0.3:8000").unwrap();
+        rx.send(datagram("10.0.0.2:8000", b"nope")).unwrap();
+        rx.send(datagram("10.0.0.3:8000", b"yes")).unwrap();
+        assert_eq!(socket.recv(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], b"yes");
+
+        drop(rx);
+        assert_eq!(
+            socket.recv(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
@@ -1,14 +1,18 @@
 #![allow(clippy::arithmetic_side_effects)]
 
 use {
-    libc::{munmap, sysconf, _SC_PAGESIZE},
+    crate::error::{XdpError, XdpErrorKind},
+    libc::{ftruncate, memfd_create, munmap, off_t, sysconf, _SC_PAGESIZE, MFD_CLOEXEC},
     std::{
         ffi::c_void,
         io,
         marker::PhantomData,
-        ops::{Deref, DerefMut},
+        ops::{Deref, DerefMut, Range},
+        os::fd::{AsFd as _, AsRawFd as _, BorrowedFd, FromRawFd as _, OwnedFd},
         ptr, slice,
+        sync::atomic::{AtomicUsize, Ordering},
     },
+    thiserror::Error,
 };
 
 #[derive(Copy, Clone, Debug)]
@@ -17,6 +21,7 @@ pub struct FrameOffset(pub(crate) usize);
 pub trait Frame {
     fn offset(&self) -> FrameOffset;
     fn len(&self) -> usize;
+    fn set_len(&mut self, len: usize);
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
@@ -33,12 +38,30 @@ pub trait Umem {
     fn reserve(&mut self) -> Option<Self::Frame>;
     fn release(&mut self, frame: FrameOffset);
     fn frame_size(&self) -> usize;
+    /// Returns the number of frames in the UMEM.
+    fn capacity(&self) -> usize;
+    /// Returns the number of frames that can be reserved.
+    fn available(&self) -> usize;
+    /// Whether frames may start anywhere within the UMEM, see `XDP_UMEM_UNALIGNED_CHUNK_FLAG`.
+    fn unaligned(&self) -> bool {
+        false
+    }
     fn map_frame(&self, frame: &Self::Frame) -> &[u8] {
         unsafe { slice::from_raw_parts(self.as_ptr().add(frame.offset().0), frame.len()) }
     }
     fn map_frame_mut(&mut self, frame: &Self::Frame) -> &mut [u8] {
         unsafe { slice::from_raw_parts_mut(self.as_mut_ptr().add(frame.offset().0), frame.len()) }
     }
+    /// Copies `range` of the frame at `src` to the same range of the frame at `dst`.
+    fn copy_frame(&mut self, src: FrameOffset, dst: FrameOffset, range: Range<usize>) {
+        // a frame released and reserved again still holds its previous contents
+        if src.0 == dst.0 {
+            return;
+        }
+        assert!(range.end <= self.frame_size());
+        let buffer = unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) };
+        buffer.copy_within(src.0 + range.start..src.0 + range.end, dst.0 + range.start);
+    }
 }
 
 pub struct SliceUmemFrame<'a> {
@@ -48,8 +71,10 @@ pub struct SliceUmemFrame<'a> {
 }
 
 impl SliceUmemFrame<'_> {
-    pub fn set_len(&mut self, len: usize) {
-        self.len = len;
+    // Moves the start of the frame `bytes` into its chunk. The frame is still released to the
+    // chunk it was reserved from.
+    pub(crate) fn advance(&mut self, bytes: usize) {
+        self.offset += bytes;
     }
 }
 
@@ -61,6 +86,10 @@ impl Frame for SliceUmemFrame<'_> {
     fn len(&self) -> usize {
         self.len
     }
+
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
 }
 
 pub struct SliceUmem<'a> {
@@ -68,26 +97,81 @@ pub struct SliceUmem<'a> {
     frame_size: u32,
     available_frames: Vec<u64>,
     capacity: usize,
+    unaligned: bool,
 }
 
 impl<'a> SliceUmem<'a> {
-    pub fn new(buffer: &'a mut [u8], frame_size: u32) -> Result<Self, io::Error> {
-        debug_assert!(frame_size.is_power_of_two());
+    // XDP_UMEM_MIN_CHUNK_SIZE
+    const MIN_FRAME_SIZE: u32 = 2048;
+
+    /// Creates a UMEM in aligned chunk mode, where the kernel requires `frame_size` to be a power
+    /// of two between 2048 bytes and the page size.
+    pub fn new(buffer: &'a mut [u8], frame_size: u32) -> Result<Self, XdpError> {
+        if !frame_size.is_power_of_two() {
+            return Err(XdpError::other(
+                XdpErrorKind::Misconfigured,
+                "SliceUmem::new",
+                format!("aligned frame size must be a power of two, got {frame_size}"),
+            ));
+        }
+        Self::check_frame_size("SliceUmem::new", frame_size)?;
+        Ok(Self::with_frame_size(buffer, frame_size, false))
+    }
+
+    /// Creates a UMEM in unaligned chunk mode.
+    ///
+    /// `frame_size` doesn't need to be a power of two, so frames can be packed tightly, and frames
+    /// reserved with [`reserve_with_headroom`](Self::reserve_with_headroom) can start anywhere
+    /// within their chunk. The kernel requires `frame_size` to be at least 2048 bytes and at most
+    /// the page size.
+    ///
+    /// Frames may cross a page boundary when the page size isn't a multiple of `frame_size`, like
+    /// 3000 byte frames on 4KiB pages. That works in copy mode, but zero-copy drivers drop the
+    /// descriptors of frames spanning pages that aren't contiguous in DMA memory. Use a
+    /// `frame_size` dividing the page size, or memory backed by huge pages, with zero-copy.
+    pub fn new_unaligned(buffer: &'a mut [u8], frame_size: u32) -> Result<Self, XdpError> {
+        Self::check_frame_size("SliceUmem::new_unaligned", frame_size)?;
+        Ok(Self::with_frame_size(buffer, frame_size, true))
+    }
+
+    fn check_frame_size(context: &'static str, frame_size: u32) -> Result<(), XdpError> {
+        // Safety: just a libc wrapper
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) } as u32;
+        if !(Self::MIN_FRAME_SIZE..=page_size).contains(&frame_size) {
+            return Err(XdpError::other(
+                XdpErrorKind::Misconfigured,
+                context,
+                format!(
+                    "frame size must be between {} and {page_size}, got {frame_size}",
+                    Self::MIN_FRAME_SIZE
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn with_frame_size(buffer: &'a mut [u8], frame_size: u32, unaligned: bool) -> Self {
         let capacity = buffer.len() / frame_size as usize;
-        Ok(Self {
+        Self {
             available_frames: Vec::from_iter(0..capacity as u64),
             capacity,
             frame_size,
             buffer,
-        })
+            unaligned,
+        }
     }
 
-    pub fn capacity(&self) -> usize {
-        self.capacity
-    }
-
-    pub fn available(&self) -> usize {
-        self.available_frames.len()
+    /// Reserves a frame starting `headroom` bytes into its chunk.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the UMEM isn't in unaligned chunk mode or `headroom` isn't within the chunk.
+    pub fn reserve_with_headroom(&mut self, headroom: usize) -> Option<SliceUmemFrame<'a>> {
+        assert!(self.unaligned, "headroom requires an unaligned umem");
+        assert!(headroom < self.frame_size as usize);
+        let mut frame = self.reserve()?;
+        frame.advance(headroom);
+        Some(frame)
     }
 }
 
@@ -110,6 +194,18 @@ impl<'a> Umem for SliceUmem<'a> {
         self.frame_size as usize
     }
 
+    fn unaligned(&self) -> bool {
+        self.unaligned
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn available(&self) -> usize {
+        self.available_frames.len()
+    }
+
     fn reserve(&mut self) -> Option<SliceUmemFrame<'a>> {
         let index = self.available_frames.pop()?;
 
@@ -121,13 +217,111 @@ impl<'a> Umem for SliceUmem<'a> {
     }
 
     fn release(&mut self, frame: FrameOffset) {
+        // frames with headroom still map to the chunk they were reserved from
         let index = frame.0 / self.frame_size as usize;
         self.available_frames.push(index as u64);
     }
 }
 
-#[derive(Debug)]
-pub struct AllocError;
+#[derive(Debug, Error)]
+pub enum AllocError {
+    #[error("mmap failed: {0}")]
+    Mmap(#[source] io::Error),
+    #[error(
+        "allocating {requested} bytes would exceed the UMEM memory cap of {cap} bytes, \
+         {committed} bytes are already committed"
+    )]
+    CapExceeded {
+        requested: usize,
+        committed: usize,
+        cap: usize,
+    },
+}
+
+/// The memory committed to UMEMs by this process, see [`umem_memory`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UmemMemory {
+    /// Bytes currently allocated for UMEMs.
+    pub committed: usize,
+    /// The most bytes ever allocated at once.
+    pub peak: usize,
+    /// The number of live UMEM allocations.
+    pub allocations: usize,
+    /// The configured cap, see [`set_umem_memory_cap`].
+    pub cap: Option<usize>,
+}
+
+// Every PageAlignedMemory is accounted here for as long as it's alive. UMEMs are locked in memory
+// by the kernel once registered, so with many queues and NICs this adds up quickly.
+static UMEM_ACCOUNTING: UmemAccounting = UmemAccounting::new();
+
+/// Limits the memory all the UMEMs of this process can use, `None` for no limit.
+///
+/// Allocations that would go over the cap fail with [`AllocError::CapExceeded`]. Lowering the cap
+/// below what's already committed doesn't free anything, it only fails new allocations.
+pub fn set_umem_memory_cap(cap: Option<usize>) {
+    UMEM_ACCOUNTING.set_cap(cap);
+}
+
+/// Returns the memory currently committed to UMEMs.
+pub fn umem_memory() -> UmemMemory {
+    UMEM_ACCOUNTING.load()
+}
+
+struct UmemAccounting {
+    committed: AtomicUsize,
+    peak: AtomicUsize,
+    allocations: AtomicUsize,
+    // usize::MAX for no cap
+    cap: AtomicUsize,
+}
+
+impl UmemAccounting {
+    const fn new() -> Self {
+        Self {
+            committed: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            cap: AtomicUsize::new(usize::MAX),
+        }
+    }
+
+    fn set_cap(&self, cap: Option<usize>) {
+        self.cap.store(cap.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    fn reserve(&self, len: usize) -> Result<(), AllocError> {
+        let cap = self.cap.load(Ordering::Relaxed);
+        let committed = self
+            .committed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |committed| {
+                committed.checked_add(len).filter(|total| *total <= cap)
+            })
+            .map_err(|committed| AllocError::CapExceeded {
+                requested: len,
+                committed,
+                cap,
+            })?;
+        self.peak.fetch_max(committed + len, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn release(&self, len: usize) {
+        self.committed.fetch_sub(len, Ordering::Relaxed);
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> UmemMemory {
+        let cap = self.cap.load(Ordering::Relaxed);
+        UmemMemory {
+            committed: self.committed.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            cap: (cap != usize::MAX).then_some(cap),
+        }
+    }
+}
 
 pub struct PageAlignedMemory {
     ptr: *mut u8,
@@ -156,32 +350,75 @@ impl PageAlignedMemory {
         debug_assert!(page_size.is_power_of_two());
         let memory_size = frame_count * frame_size;
         let aligned_size = (memory_size + page_size - 1) & !(page_size - 1);
+        let memory = Self::map(
+            aligned_size,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | if huge { libc::MAP_HUGETLB } else { 0 },
+            None,
+        )?;
+
+        // Safety: ptr is valid for aligned_size bytes
+        unsafe {
+            ptr::write_bytes(memory.ptr, 0, aligned_size);
+        }
+        Ok(memory)
+    }
+
+    /// Allocates memory backed by a memfd, which can be mapped by other processes with
+    /// [`map_shared`](Self::map_shared).
+    pub fn alloc_shared(
+        frame_size: usize,
+        frame_count: usize,
+    ) -> Result<(Self, OwnedFd), AllocError> {
+        // Safety: just a libc wrapper
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as usize };
+        let len = (frame_count * frame_size + page_size - 1) & !(page_size - 1);
+
+        // Safety: the name is NUL terminated
+        let fd = unsafe { memfd_create(c"agave-xdp-umem".as_ptr(), MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(AllocError::Mmap(io::Error::last_os_error()));
+        }
+        // Safety: memfd_create returned a new fd
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // a new memfd is empty, the pages are allocated zeroed on first access
+        // Safety: just a libc wrapper
+        if unsafe { ftruncate(fd.as_raw_fd(), len as off_t) } < 0 {
+            return Err(AllocError::Mmap(io::Error::last_os_error()));
+        }
+        let memory = Self::map_shared(fd.as_fd(), len)?;
+        Ok((memory, fd))
+    }
+
+    /// Maps `len` bytes of the memfd returned by [`alloc_shared`](Self::alloc_shared).
+    pub fn map_shared(fd: BorrowedFd<'_>, len: usize) -> Result<Self, AllocError> {
+        Self::map(len, libc::MAP_SHARED, Some(fd))
+    }
+
+    fn map(len: usize, flags: i32, fd: Option<BorrowedFd<'_>>) -> Result<Self, AllocError> {
+        UMEM_ACCOUNTING.reserve(len)?;
 
         // Safety:
-        // doing an ANONYMOUS alloc. addr=NULL is ok, fd is not used.
+        // addr=NULL is ok, fd is -1 for ANONYMOUS allocs and valid otherwise.
         let ptr = unsafe {
             libc::mmap(
                 ptr::null_mut(),
-                aligned_size,
+                len,
                 libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | if huge { libc::MAP_HUGETLB } else { 0 },
-                -1,
+                flags,
+                fd.map_or(-1, |fd| fd.as_raw_fd()),
                 0,
             )
         };
 
         if std::ptr::eq(ptr, libc::MAP_FAILED) {
-            return Err(AllocError);
-        }
-
-        // Safety: ptr is valid for aligned_size bytes
-        unsafe {
-            ptr::write_bytes(ptr as *mut u8, 0, aligned_size);
+            let e = io::Error::last_os_error();
+            UMEM_ACCOUNTING.release(len);
+            return Err(AllocError::Mmap(e));
         }
 
         Ok(Self {
             ptr: ptr as *mut u8,
-            len: aligned_size,
+            len,
         })
     }
 }
@@ -193,6 +430,7 @@ impl Drop for PageAlignedMemory {
         unsafe {
             munmap(self.ptr as *mut c_void, self.len);
         }
+        UMEM_ACCOUNTING.release(self.len);
     }
 }
 
@@ -209,3 +447,109 @@ impl DerefMut for PageAlignedMemory {
         unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_umem_accounting() {
+        // a private instance, the global one is shared with the other tests
+        let accounting = UmemAccounting::new();
+        accounting.reserve(4096).unwrap();
+        accounting.reserve(8192).unwrap();
+        accounting.release(4096);
+        assert_eq!(
+            accounting.load(),
+            UmemMemory {
+                committed: 8192,
+                peak: 12288,
+                allocations: 1,
+                cap: None,
+            }
+        );
+
+        accounting.set_cap(Some(12288));
+        accounting.reserve(4096).unwrap();
+        assert!(matches!(
+            accounting.reserve(1),
+            Err(AllocError::CapExceeded {
+                requested: 1,
+                committed: 12288,
+                cap: 12288,
+            })
+        ));
+        // a failed reservation doesn't count
+        assert_eq!(accounting.load().committed, 12288);
+        assert_eq!(accounting.load().allocations, 2);
+
+        // other tests allocate concurrently, so only check that this allocation is included
+        let memory = PageAlignedMemory::alloc(4096, 1).unwrap();
+        assert!(umem_memory().committed >= memory.len());
+        assert!(umem_memory().allocations >= 1);
+    }
+
+    fn page_size() -> u32 {
+        unsafe { sysconf(_SC_PAGESIZE) as u32 }
+    }
+
+    #[test]
+    fn test_slice_umem_frame_size() {
+        let page_size = page_size();
+        let mut buffer = vec![0u8; page_size as usize * 2];
+        for frame_size in [2048, page_size] {
+            assert!(SliceUmem::new(&mut buffer, frame_size).is_ok());
+            assert!(SliceUmem::new_unaligned(&mut buffer, frame_size).is_ok());
+        }
+        // unaligned frames can be packed tightly
+        let umem = SliceUmem::new_unaligned(&mut buffer, 3000).unwrap();
+        assert!(umem.unaligned());
+        assert_eq!(umem.capacity(), page_size as usize * 2 / 3000);
+        assert!(SliceUmem::new(&mut buffer, 3000).is_err());
+        for frame_size in [0, 1024, 2047, page_size + 1, page_size * 2] {
+            assert!(SliceUmem::new(&mut buffer, frame_size).is_err());
+            assert!(SliceUmem::new_unaligned(&mut buffer, frame_size).is_err());
+        }
+    }
+
+    #[test]
+    fn test_slice_umem_headroom() {
+        const FRAME_SIZE: usize = 3000;
+        let mut buffer = vec![0u8; FRAME_SIZE * 2];
+        let mut umem = SliceUmem::new_unaligned(&mut buffer, FRAME_SIZE as u32).unwrap();
+
+        // the frames start past the headroom, in the chunk they were reserved from
+        let mut first = umem.reserve_with_headroom(256).unwrap();
+        let mut second = umem.reserve_with_headroom(FRAME_SIZE - 1).unwrap();
+        assert_eq!(first.offset().0, FRAME_SIZE + 256);
+        assert_eq!(second.offset().0, FRAME_SIZE - 1);
+        assert!(umem.reserve_with_headroom(0).is_none());
+        first.set_len(FRAME_SIZE - 256);
+        umem.map_frame_mut(&first).fill(1);
+        second.set_len(1);
+        umem.map_frame_mut(&second).fill(2);
+        assert!(buffer[..FRAME_SIZE - 1].iter().all(|b| *b == 0));
+        assert_eq!(buffer[FRAME_SIZE - 1], 2);
+        assert!(buffer[FRAME_SIZE..][..256].iter().all(|b| *b == 0));
+        assert!(buffer[FRAME_SIZE + 256..].iter().all(|b| *b == 1));
+
+        // released frames go back to their chunk and can be reserved again, with any headroom
+        let mut umem = SliceUmem::new_unaligned(&mut buffer, FRAME_SIZE as u32).unwrap();
+        let first = umem.reserve_with_headroom(256).unwrap();
+        umem.release(first.offset());
+        assert_eq!(umem.available(), 2);
+        assert_eq!(umem.reserve().unwrap().offset().0, FRAME_SIZE);
+        let first = umem.reserve_with_headroom(128).unwrap();
+        assert_eq!(first.offset().0, 128);
+        umem.release(first.offset());
+        assert_eq!(umem.reserve_with_headroom(64).unwrap().offset().0, 64);
+    }
+
+    #[test]
+    #[should_panic(expected = "headroom requires an unaligned umem")]
+    fn test_slice_umem_headroom_aligned() {
+        let mut buffer = vec![0u8; 4096];
+        let mut umem = SliceUmem::new(&mut buffer, 2048).unwrap();
+        umem.reserve_with_headroom(64);
+    }
+}
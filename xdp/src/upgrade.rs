@@ -0,0 +1,244 @@
+//! Handing the XDP datapath over to a new process on upgrade.
+//!
+//! Normally the XDP program is detached when the validator exits and attached again by the new
+//! process, and until then the packets meant for AF_XDP go to the kernel. To avoid that window:
+//!
+//! 1. The program is attached with [`load_rx_program_pinned`](crate::load_rx_program_pinned), so
+//!    that the attachment belongs to a pin on bpffs and survives the process.
+//! 2. The new process connects to the old one over a unix socket, and the old one sends its
+//!    [`XdpState`]: the link, the program's maps and its AF_XDP sockets.
+//! 3. The new process opens the maps with [`XdpState::into_parts`], creates its own sockets and
+//!    registers them, replacing the old ones queue by queue, then calls [`confirm_takeover`].
+//! 4. The old process, waiting in [`wait_for_takeover`], exits.
+//!
+//! The flows carry over with the flow map. The old sockets can't be driven by the new process,
+//! the kernel refuses to map the rings of a bound socket, but holding them keeps their entries in
+//! the socket map until the new sockets replace them, even if the old process exits early.
+#![allow(clippy::arithmetic_side_effects)]
+
+use {
+    crate::{
+        device::QueueId,
+        error::{XdpError, XdpErrorKind},
+        handoff::{invalid_data, recv_with_fds, send_with_fds},
+        rx_filter::RxFilter,
+    },
+    libc::{c_long, syscall, SYS_bpf},
+    std::{
+        ffi::CString,
+        io::{self, Read as _, Write as _},
+        mem,
+        os::{
+            fd::{AsFd, BorrowedFd, FromRawFd as _, OwnedFd},
+            unix::{ffi::OsStrExt as _, net::UnixStream},
+        },
+        path::Path,
+    },
+};
+
+const MAGIC: [u8; 4] = *b"axup";
+// SCM_MAX_FD is 253, minus the link and the three maps
+const MAX_SOCKETS: usize = 249;
+const BPF_OBJ_GET: c_long = 7;
+const TAKEOVER_CONFIRMED: u8 = 1;
+
+// The part of union bpf_attr used by BPF_OBJ_GET.
+#[repr(C)]
+struct BpfObjAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+    path_fd: i32,
+}
+
+/// The XDP state of a process, to hand over to its successor.
+#[derive(Debug)]
+pub struct XdpState {
+    if_index: u32,
+    link: OwnedFd,
+    flows: OwnedFd,
+    sockets: OwnedFd,
+    interfaces: OwnedFd,
+    xsks: Vec<(QueueId, OwnedFd)>,
+}
+
+impl XdpState {
+    /// Collects the state of the rx program attached to `if_index` by
+    /// [`load_rx_program_pinned`](crate::load_rx_program_pinned) with `link_path`, whose maps are
+    /// in `filter`.
+    pub fn new(if_index: u32, link_path: &Path, filter: &RxFilter) -> Result<Self, XdpError> {
+        let (flows, sockets, interfaces) = filter.map_fds();
+        Ok(Self {
+            if_index,
+            link: bpf_obj_get(link_path)?,
+            flows: dup(flows)?,
+            sockets: dup(sockets)?,
+            interfaces: dup(interfaces)?,
+            xsks: Vec::new(),
+        })
+    }
+
+    /// Adds the AF_XDP socket registered for `queue_id`, so that the new process can keep it
+    /// open until it has registered its own.
+    pub fn add_socket(&mut self, queue_id: QueueId, socket: impl AsFd) -> Result<(), XdpError> {
+        if self.xsks.len() >= MAX_SOCKETS {
+            return Err(XdpError::other(
+                XdpErrorKind::Misconfigured,
+                "sendmsg",
+                format!("can't hand over more than {MAX_SOCKETS} sockets"),
+            ));
+        }
+        self.xsks.push((queue_id, dup(socket.as_fd())?));
+        Ok(())
+    }
+
+    pub fn if_index(&self) -> u32 {
+        self.if_index
+    }
+
+    /// Returns the queues of the sockets added with [`add_socket`](Self::add_socket).
+    pub fn queues(&self) -> impl Iterator<Item = QueueId> + '_ {
+        self.xsks.iter().map(|(queue_id, _)| *queue_id)
+    }
+
+    /// Sends the state over `stream`.
+    pub fn send(&self, stream: &UnixStream) -> io::Result<()> {
+        let mut data = Vec::with_capacity(12 + self.xsks.len() * 4);
+        data.extend_from_slice(&MAGIC);
+        data.extend_from_slice(&self.if_index.to_le_bytes());
+        data.extend_from_slice(&(self.xsks.len() as u32).to_le_bytes());
+        for (queue_id, _) in &self.xsks {
+            data.extend_from_slice(&(queue_id.0 as u32).to_le_bytes());
+        }
+        let fds = [
+            self.link.as_fd(),
+            self.flows.as_fd(),
+            self.sockets.as_fd(),
+            self.interfaces.as_fd(),
+        ]
+        .into_iter()
+        .chain(self.xsks.iter().map(|(_, fd)| fd.as_fd()))
+        .collect::<Vec<_>>();
+        send_with_fds(stream, &data, &fds)
+    }
+
+    /// Receives the state sent with [`send`](Self::send).
+    pub fn recv(stream: &UnixStream) -> io::Result<Self> {
+        let mut data = vec![0u8; 12 + MAX_SOCKETS * 4];
+        let (received, fds) = recv_with_fds(stream, &mut data, MAX_SOCKETS + 4)?;
+        let data = &data[..received];
+        if data.len() < 12 || data[..4] != MAGIC {
+            return Err(invalid_data("bad xdp state"));
+        }
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..][..4].try_into().unwrap());
+        let if_index = u32_at(4);
+        let count = u32_at(8) as usize;
+        if data.len() != 12 + count * 4 || fds.len() != count + 4 {
+            return Err(invalid_data("xdp state doesn't match its fds"));
+        }
+
+        let mut fds = fds.into_iter();
+        let mut next = || fds.next().unwrap();
+        let (link, flows, sockets, interfaces) = (next(), next(), next(), next());
+        let xsks = (0..count)
+            .map(|i| (QueueId(u32_at(12 + i * 4) as u64), next()))
+            .collect();
+        Ok(Self {
+            if_index,
+            link,
+            flows,
+            sockets,
+            interfaces,
+            xsks,
+        })
+    }
+
+    /// Opens the maps and returns the filter to register the new sockets with, along with the
+    /// link and the old sockets.
+    ///
+    /// Dropping the link doesn't detach the program while it's pinned. The old sockets should be
+    /// dropped once new sockets are registered for their queues.
+    #[allow(clippy::type_complexity)]
+    pub fn into_parts(self) -> Result<(RxFilter, OwnedFd, Vec<(QueueId, OwnedFd)>), XdpError> {
+        let filter = RxFilter::from_fds(self.flows, self.sockets, self.interfaces)?;
+        Ok((filter, self.link, self.xsks))
+    }
+}
+
+/// Tells the old process, once the new sockets are registered, that it can exit.
+pub fn confirm_takeover(mut stream: &UnixStream) -> io::Result<()> {
+    stream.write_all(&[TAKEOVER_CONFIRMED])
+}
+
+/// Waits for the new process to call [`confirm_takeover`]. Fails if it exits without confirming.
+pub fn wait_for_takeover(mut stream: &UnixStream) -> io::Result<()> {
+    let mut buf = [0u8; 1];
+    stream.read_exact(&mut buf)?;
+    if buf[0] != TAKEOVER_CONFIRMED {
+        return Err(invalid_data("bad takeover confirmation"));
+    }
+    Ok(())
+}
+
+// Opens the bpf object pinned at `path`.
+fn bpf_obj_get(path: &Path) -> Result<OwnedFd, XdpError> {
+    let pathname = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| XdpError::new("bpf(BPF_OBJ_GET)", io::Error::other(e)))?;
+    let mut attr = BpfObjAttr {
+        pathname: pathname.as_ptr() as u64,
+        bpf_fd: 0,
+        file_flags: 0,
+        path_fd: 0,
+    };
+    // Safety: attr is a valid bpf_attr prefix for BPF_OBJ_GET and outlives the call
+    let fd = unsafe {
+        syscall(
+            SYS_bpf,
+            BPF_OBJ_GET,
+            &mut attr as *mut BpfObjAttr,
+            mem::size_of::<BpfObjAttr>() as u32,
+        )
+    };
+    if fd < 0 {
+        return Err(XdpError::last_os_error("bpf(BPF_OBJ_GET)"));
+    }
+    // Safety: the kernel returned a new fd
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn dup(fd: BorrowedFd<'_>) -> Result<OwnedFd, XdpError> {
+    fd.try_clone_to_owned()
+        .map_err(|e| XdpError::new("fcntl(F_DUPFD_CLOEXEC)", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::umem::PageAlignedMemory};
+
+    #[test]
+    fn test_send_recv() {
+        // placeholders, real links and maps need privileges
+        let fd = || PageAlignedMemory::alloc_shared(4096, 1).unwrap().1;
+        let state = XdpState {
+            if_index: 7,
+            link: fd(),
+            flows: fd(),
+            sockets: fd(),
+            interfaces: fd(),
+            xsks: vec![(QueueId(0), fd()), (QueueId(3), fd())],
+        };
+        let (a, b) = UnixStream::pair().unwrap();
+        state.send(&a).unwrap();
+        let received = XdpState::recv(&b).unwrap();
+        assert_eq!(received.if_index(), 7);
+        assert_eq!(
+            received.queues().collect::<Vec<_>>(),
+            [QueueId(0), QueueId(3)]
+        );
+
+        confirm_takeover(&b).unwrap();
+        wait_for_takeover(&a).unwrap();
+        drop(b);
+        assert!(wait_for_takeover(&a).is_err());
+    }
+}
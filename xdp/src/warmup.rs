@@ -0,0 +1,150 @@
+//! Warming up the tx loops for the peers they're about to send to.
+//!
+//! The first packet to a destination pays for a route lookup and for building its headers, and is
+//! dropped if the MAC address of its next hop isn't in the neighbor table yet. That shows up as
+//! latency spikes whenever turbine or forwarding switch to new peers, e.g. at a leader handoff.
+//! Given the upcoming peers, from gossip or the leader schedule, a [`PeerWarmup`] makes the tx
+//! loops resolve their routes, ask the kernel to resolve the missing neighbors and cache their
+//! headers before any traffic is sent to them.
+
+use {
+    crate::netlink::netlink_resolve_neighbor,
+    caps::{CapSet, Capability::CAP_NET_ADMIN},
+    std::{
+        collections::HashSet,
+        fmt,
+        net::{IpAddr, SocketAddr, SocketAddrV4},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, RwLock,
+        },
+    },
+};
+
+#[derive(Default)]
+struct WarmupInner {
+    peers: RwLock<Arc<Vec<SocketAddrV4>>>,
+    // bumped whenever the peers change
+    generation: AtomicU64,
+}
+
+/// The peers the tx loops should get ready to send to, shared by all of them.
+///
+/// Cloning returns a handle to the same list.
+#[derive(Clone, Default)]
+pub struct PeerWarmup {
+    inner: Arc<WarmupInner>,
+}
+
+impl PeerWarmup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the peers to warm up, replacing the previous ones. The tx loops pick them up on their
+    /// next iteration. IPv6 peers are ignored since the tx loops can't send to them.
+    pub fn warm(&self, peers: impl IntoIterator<Item = SocketAddr>) {
+        let peers = peers
+            .into_iter()
+            .filter_map(|peer| match peer {
+                SocketAddr::V4(peer) => Some(peer),
+                SocketAddr::V6(_) => None,
+            })
+            .collect::<Vec<_>>();
+        *self.inner.peers.write().unwrap() = Arc::new(peers);
+        self.inner.generation.fetch_add(1, Ordering::Release);
+    }
+
+    pub fn peers(&self) -> Arc<Vec<SocketAddrV4>> {
+        Arc::clone(&self.inner.peers.read().unwrap())
+    }
+}
+
+impl fmt::Debug for PeerWarmup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.peers().iter()).finish()
+    }
+}
+
+/// The tx loop side of a [`PeerWarmup`].
+pub(crate) struct Warmer<'a> {
+    warmup: &'a PeerWarmup,
+    generation: u64,
+    stale: bool,
+    // the next hops whose resolution was requested for the current peers, so that a neighbor
+    // that fails to resolve isn't probed again every time the routes change
+    requested: HashSet<(u32, IpAddr)>,
+}
+
+impl<'a> Warmer<'a> {
+    pub(crate) fn new(warmup: &'a PeerWarmup) -> Self {
+        Self {
+            warmup,
+            generation: 0,
+            stale: false,
+            requested: HashSet::new(),
+        }
+    }
+
+    /// Returns the peers to warm up if they changed since the last call, or if the loop was
+    /// [invalidated](Self::invalidate) since.
+    #[inline]
+    pub(crate) fn poll(&mut self) -> Option<Arc<Vec<SocketAddrV4>>> {
+        let generation = self.warmup.inner.generation.load(Ordering::Acquire);
+        if generation != self.generation {
+            self.generation = generation;
+            self.requested.clear();
+        } else if !self.stale {
+            return None;
+        }
+        self.stale = false;
+        Some(self.warmup.peers())
+    }
+
+    /// Makes the next [`poll`](Self::poll) return the peers again, e.g. because the headers
+    /// cached for them were dropped.
+    pub(crate) fn invalidate(&mut self) {
+        self.stale = true;
+    }
+
+    /// Asks the kernel to resolve the next hop `ip` on `if_index`, once per set of peers.
+    pub(crate) fn resolve_neighbor(&mut self, if_index: u32, ip: IpAddr) {
+        if !self.requested.insert((if_index, ip)) {
+            return;
+        }
+        // the tx loop runs without its capabilities, raise CAP_NET_ADMIN if we're allowed to
+        let raised = !caps::has_cap(None, CapSet::Effective, CAP_NET_ADMIN).unwrap_or(true)
+            && caps::raise(None, CapSet::Effective, CAP_NET_ADMIN).is_ok();
+        if let Err(e) = netlink_resolve_neighbor(if_index, ip) {
+            log::warn!("failed to resolve neighbor {ip} on if_index {if_index}: {e}");
+        }
+        if raised {
+            let _ = caps::drop(None, CapSet::Effective, CAP_NET_ADMIN);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warmer_poll() {
+        let warmup = PeerWarmup::new();
+        let mut warmer = Warmer::new(&warmup);
+        assert!(warmer.poll().is_none());
+
+        let peer = SocketAddrV4::new([10, 0, 0, 1].into(), 8000);
+        warmup.warm([SocketAddr::V4(peer), "[::1]:8000".parse().unwrap()]);
+        assert_eq!(*warmer.poll().unwrap(), [peer]);
+        assert!(warmer.poll().is_none());
+
+        // the peers come back after the cached headers are dropped
+        warmer.invalidate();
+        assert_eq!(*warmer.poll().unwrap(), [peer]);
+        assert!(warmer.poll().is_none());
+
+        warmup.warm([]);
+        assert!(warmer.poll().unwrap().is_empty());
+    }
+}
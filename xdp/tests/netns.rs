@@ -0,0 +1,426 @@
+//! End-to-end tests of the XDP datapath over veth pairs in private network namespaces.
+//!
+//! Each test creates its own namespaces and links with `ip`, so they need root, `iproute2` and a
+//! kernel with AF_XDP, and are ignored by default:
+//!
+//! ```text
+//! sudo -E cargo test -p agave-xdp --test netns -- --ignored
+//! ```
+#![cfg(target_os = "linux")]
+#![allow(deprecated, clippy::arithmetic_side_effects)]
+
+use {
+    agave_cpu_utils::cpu_affinity,
+    agave_xdp::{
+        device::NetworkDevice,
+        failover::{MultiNicConfig, MultiNicTransportService},
+        load_xdp_program,
+        netlink::{netlink_get_link, MacAddress, RouteMonitor},
+        route::Router,
+        rx_filter::RxFlow,
+        rx_loop::{RxService, RxServiceConfig},
+        transport::{DatagramTransport, XdpTransportConfig, XdpTransportService},
+        xdp_program_id,
+    },
+    solana_perf::packet::bytes::Bytes,
+    std::{
+        ffi::CString,
+        fs::{self, File},
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+        os::fd::AsRawFd,
+        panic,
+        process::{self, Command},
+        ptr,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn ip(args: &[&str]) {
+    let status = Command::new("ip")
+        .args(args)
+        .status()
+        .expect("failed to run ip");
+    assert!(status.success(), "ip {} failed", args.join(" "));
+}
+
+fn check(res: libc::c_int, what: &str) {
+    assert_eq!(res, 0, "{what} failed: {}", std::io::Error::last_os_error());
+}
+
+/// A network namespace, deleted when dropped.
+struct Netns {
+    name: String,
+}
+
+impl Netns {
+    fn new() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "agave-xdp-{}-{}",
+            process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        ip(&["netns", "add", &name]);
+        let netns = Self { name };
+        netns.ip("link set lo up");
+        netns
+    }
+
+    /// Runs `ip` in the namespace.
+    fn ip(&self, args: &str) {
+        let mut argv = vec!["-n", self.name.as_str()];
+        argv.extend(args.split_whitespace());
+        ip(&argv);
+    }
+
+    /// Sets the sysctl `key`, e.g. `net/ipv4/conf/all/rp_filter`, in the namespace.
+    fn sysctl(&self, key: &str, value: &str) {
+        self.run(|| fs::write(format!("/proc/sys/{key}"), value).unwrap());
+    }
+
+    /// Runs `f` on a thread inside the namespace. Like with `ip netns exec`, the thread sees the
+    /// sysfs of the namespace, and so do the threads it spawns.
+    fn run<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    self.enter();
+                    f()
+                })
+                .join()
+                .unwrap_or_else(|e| panic::resume_unwind(e))
+        })
+    }
+
+    fn enter(&self) {
+        let netns = File::open(format!("/run/netns/{}", self.name)).unwrap();
+        let (none, root, sys, sysfs) = (
+            CString::new("none").unwrap(),
+            CString::new("/").unwrap(),
+            CString::new("/sys").unwrap(),
+            CString::new("sysfs").unwrap(),
+        );
+        // safety: plain syscalls on valid fds and nul terminated strings. They only affect the
+        // calling thread, whose mounts are made private before /sys is replaced
+        unsafe {
+            check(libc::setns(netns.as_raw_fd(), libc::CLONE_NEWNET), "setns");
+            check(libc::unshare(libc::CLONE_NEWNS), "unshare");
+            check(
+                libc::mount(
+                    none.as_ptr(),
+                    root.as_ptr(),
+                    ptr::null(),
+                    libc::MS_REC | libc::MS_SLAVE,
+                    ptr::null(),
+                ),
+                "mount --make-rslave /",
+            );
+            check(libc::umount2(sys.as_ptr(), libc::MNT_DETACH), "umount /sys");
+            check(
+                libc::mount(sysfs.as_ptr(), sys.as_ptr(), sysfs.as_ptr(), 0, ptr::null()),
+                "mount /sys",
+            );
+        }
+    }
+}
+
+impl Drop for Netns {
+    fn drop(&mut self) {
+        // deleting the namespace deletes the links in it
+        let _ = Command::new("ip")
+            .args(["netns", "del", &self.name])
+            .status();
+    }
+}
+
+/// One end of a veth pair.
+struct VethEnd<'a> {
+    netns: &'a Netns,
+    name: &'a str,
+    mac: MacAddress,
+    addr: Ipv4Addr,
+}
+
+/// Creates a veth pair between the namespaces of `a` and `b`, with addresses in the same `/24`
+/// and static neighbor entries so that nothing waits for ARP.
+fn veth_pair(a: &VethEnd, b: &VethEnd) {
+    ip(&[
+        "link",
+        "add",
+        a.name,
+        "address",
+        &a.mac.to_string(),
+        "netns",
+        &a.netns.name,
+        "type",
+        "veth",
+        "peer",
+        "name",
+        b.name,
+        "address",
+        &b.mac.to_string(),
+        "netns",
+        &b.netns.name,
+    ]);
+    for (end, peer) in [(a, b), (b, a)] {
+        end.netns
+            .ip(&format!("addr add {}/24 dev {}", end.addr, end.name));
+        end.netns.ip(&format!("link set {} up", end.name));
+        end.netns.ip(&format!(
+            "neigh replace {} lladdr {} dev {}",
+            peer.addr, peer.mac, end.name
+        ));
+    }
+}
+
+/// A host `a` and a host `b` linked by `va` and `vb`, in 10.77.1.0/24.
+struct TwoHosts {
+    a: Netns,
+    b: Netns,
+}
+
+impl TwoHosts {
+    const A_ADDR: Ipv4Addr = Ipv4Addr::new(10, 77, 1, 1);
+    const B_ADDR: Ipv4Addr = Ipv4Addr::new(10, 77, 1, 2);
+    const A_MAC: MacAddress = MacAddress([0x02, 0, 0, 0, 1, 1]);
+    const B_MAC: MacAddress = MacAddress([0x02, 0, 0, 0, 1, 2]);
+
+    fn new() -> Self {
+        let (a, b) = (Netns::new(), Netns::new());
+        veth_pair(
+            &VethEnd {
+                netns: &a,
+                name: "va",
+                mac: Self::A_MAC,
+                addr: Self::A_ADDR,
+            },
+            &VethEnd {
+                netns: &b,
+                name: "vb",
+                mac: Self::B_MAC,
+                addr: Self::B_ADDR,
+            },
+        );
+        Self { a, b }
+    }
+}
+
+fn bind(addr: impl Into<SocketAddr>) -> UdpSocket {
+    #[allow(clippy::disallowed_methods)]
+    let socket = UdpSocket::bind(addr.into()).unwrap();
+    socket.set_read_timeout(Some(TIMEOUT)).unwrap();
+    socket
+}
+
+fn any_cpu() -> usize {
+    cpu_affinity().unwrap()[0]
+}
+
+fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + TIMEOUT;
+    while !condition() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+#[test]
+#[ignore]
+fn test_tx_end_to_end() {
+    let hosts = TwoHosts::new();
+    let receiver = hosts.b.run(|| bind((TwoHosts::B_ADDR, 8000)));
+
+    hosts.a.run(|| {
+        let mut config = XdpTransportConfig::new(vec![any_cpu()], 9000);
+        config.interface = Some("va".to_owned());
+        let (service, transport, priority_transport) = XdpTransportService::new(config).unwrap();
+        let dest = SocketAddr::from((TwoHosts::B_ADDR, 8000));
+        transport
+            .send_to(Bytes::from_static(b"normal"), &[dest])
+            .unwrap();
+        priority_transport
+            .send_to(Bytes::from_static(b"priority"), &[dest])
+            .unwrap();
+        // the tx loops flush what's queued and exit
+        drop((transport, priority_transport));
+        service.join().unwrap();
+    });
+
+    let mut received = Vec::new();
+    let mut buf = [0u8; 2048];
+    for _ in 0..2 {
+        let (len, from) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(from, SocketAddr::from((TwoHosts::A_ADDR, 9000)));
+        received.push(buf[..len].to_vec());
+    }
+    received.sort();
+    assert_eq!(received, [b"normal".to_vec(), b"priority".to_vec()]);
+}
+
+#[test]
+#[ignore]
+fn test_rx_end_to_end() {
+    let hosts = TwoHosts::new();
+    let sender = hosts.a.run(|| bind((TwoHosts::A_ADDR, 9000)));
+
+    hosts.b.run(|| {
+        let exit = Arc::new(AtomicBool::new(false));
+        let mut config = RxServiceConfig::new(vec![any_cpu()]);
+        config.interface = Some("vb".to_owned());
+        config.flows = vec![RxFlow::port(8000)];
+        let (service, receiver) = RxService::new(config, exit.clone()).unwrap();
+
+        // packets to other ports are passed to the kernel
+        let kernel = bind((TwoHosts::B_ADDR, 8001));
+        sender.send_to(b"xdp", (TwoHosts::B_ADDR, 8000)).unwrap();
+        sender.send_to(b"kernel", (TwoHosts::B_ADDR, 8001)).unwrap();
+
+        let batch = receiver.recv_timeout(TIMEOUT).unwrap();
+        let packet = batch.iter().next().unwrap();
+        assert_eq!(packet.data(..).unwrap(), b"xdp");
+        assert_eq!(
+            packet.meta().socket_addr(),
+            SocketAddr::from((TwoHosts::A_ADDR, 9000))
+        );
+        let mut buf = [0u8; 16];
+        let len = kernel.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"kernel");
+
+        exit.store(true, Ordering::Relaxed);
+        service.join().unwrap();
+    });
+}
+
+#[test]
+#[ignore]
+fn test_program_reattach() {
+    let hosts = TwoHosts::new();
+    hosts.a.run(|| {
+        let dev = NetworkDevice::new("va").unwrap();
+        let attached = || netlink_get_link(dev.if_index()).unwrap().xdp_prog_id;
+        assert_eq!(attached(), None);
+
+        let ebpf = load_xdp_program(&dev).unwrap();
+        let first = xdp_program_id(&ebpf);
+        assert!(first.is_some());
+        assert_eq!(attached(), first);
+
+        // dropping the program detaches it, and it can be attached again
+        drop(ebpf);
+        assert_eq!(attached(), None);
+        let ebpf = load_xdp_program(&dev).unwrap();
+        assert!(xdp_program_id(&ebpf).is_some());
+        assert_eq!(attached(), xdp_program_id(&ebpf));
+        assert_ne!(attached(), first);
+    });
+}
+
+/// A host `a` linked to a host `b` by `va1`/`vb1` in 10.77.1.0/24 and `va2`/`vb2` in
+/// 10.77.2.0/24.
+fn dual_homed() -> (Netns, Netns) {
+    let (a, b) = (Netns::new(), Netns::new());
+    // b accepts packets for the address of one link on the other
+    b.sysctl("net/ipv4/conf/all/rp_filter", "0");
+    b.sysctl("net/ipv4/conf/default/rp_filter", "0");
+    for link in 1..=2 {
+        veth_pair(
+            &VethEnd {
+                netns: &a,
+                name: &format!("va{link}"),
+                mac: MacAddress([0x02, 0, 0, 0, link, 1]),
+                addr: Ipv4Addr::new(10, 77, link, 1),
+            },
+            &VethEnd {
+                netns: &b,
+                name: &format!("vb{link}"),
+                mac: MacAddress([0x02, 0, 0, 0, link, 2]),
+                addr: Ipv4Addr::new(10, 77, link, 2),
+            },
+        );
+    }
+    (a, b)
+}
+
+#[test]
+#[ignore]
+fn test_route_change() {
+    let (a, _b) = dual_homed();
+    a.ip("route add 10.99.0.0/24 via 10.77.1.2");
+    a.run(|| {
+        let dest = Ipv4Addr::new(10, 99, 0, 1).into();
+        let va1 = NetworkDevice::new("va1").unwrap().if_index();
+        let va2 = NetworkDevice::new("va2").unwrap().if_index();
+
+        let monitor = RouteMonitor::new().unwrap();
+        let next_hop = Router::new().unwrap().route(dest).unwrap();
+        assert_eq!(next_hop.if_index, va1);
+        assert_eq!(next_hop.mac_addr, Some(MacAddress([0x02, 0, 0, 0, 1, 2])));
+        assert_eq!(next_hop.src_ip, Some(Ipv4Addr::new(10, 77, 1, 1).into()));
+        assert!(!monitor.changed().unwrap());
+
+        a.ip("route replace 10.99.0.0/24 via 10.77.2.2");
+        assert!(wait_until(|| monitor.changed().unwrap()));
+        let next_hop = Router::new().unwrap().route(dest).unwrap();
+        assert_eq!(next_hop.if_index, va2);
+        assert_eq!(next_hop.mac_addr, Some(MacAddress([0x02, 0, 0, 0, 2, 2])));
+    });
+}
+
+#[test]
+#[ignore]
+fn test_link_failover() {
+    let (a, b) = dual_homed();
+    a.ip("route add default via 10.77.1.2 dev va1 metric 10");
+    a.ip("route add default via 10.77.2.2 dev va2 metric 20");
+    let receiver = b.run(|| bind((Ipv4Addr::UNSPECIFIED, 8000)));
+    let dest = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 77, 1, 2), 8000));
+
+    a.run(|| {
+        let links = ["va1", "va2"]
+            .into_iter()
+            .map(|interface| {
+                let mut config = XdpTransportConfig::new(vec![any_cpu()], 9000);
+                config.interface = Some(interface.to_owned());
+                config
+            })
+            .collect();
+        let (service, transport, priority_transport) =
+            MultiNicTransportService::new(MultiNicConfig::new(links)).unwrap();
+        let va1 = NetworkDevice::new("va1").unwrap().if_index();
+        let va2 = NetworkDevice::new("va2").unwrap().if_index();
+        let mut buf = [0u8; 16];
+
+        assert_eq!(transport.active_if_index(), Some(va1));
+        transport
+            .send_to(Bytes::from_static(b"primary"), &[dest])
+            .unwrap();
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"primary");
+
+        // the backup link takes over, sending through its own gateway
+        a.ip("link set va1 down");
+        assert!(wait_until(|| transport.active_if_index() == Some(va2)));
+        transport
+            .send_to(Bytes::from_static(b"backup"), &[dest])
+            .unwrap();
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"backup");
+
+        // and hands traffic back once the primary link is up again
+        a.ip("link set va1 up");
+        assert!(wait_until(|| transport.active_if_index() == Some(va1)));
+
+        drop((transport, priority_transport));
+        service.join().unwrap();
+    });
+}